@@ -60,7 +60,7 @@ impl FomConfig {
         let cfg = fom::FomConfig{ rois, background_rois, background_activity: bg};
         use geometry::units::mm;
         let size = (mm(size.0), mm(size.1), mm(size.2));
-        FomConfig{ cfg, fov: FOV::new(size, voxels)}
+        FomConfig{ cfg, fov: FOV::new_from_full_widths(size, voxels)}
     }
 
     /// Calculate CRC for a 60x60x60 voxel image
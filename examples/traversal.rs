@@ -0,0 +1,86 @@
+/// This example compares the throughput of the voxel traversal of LORs lying in
+/// the transverse plane of a single-slice (nz = 1) FOV, using the generic
+/// traversal in 3D and in its native 2D instantiation.
+///
+/// To run this example:
+///
+///    cargo run --example traversal --release
+///
+/// If you want to control the number of LORs and voxels:
+///
+///    cargo run --example traversal --release 1000000 200 # 10^6 LORs, 200 x 200 voxels
+
+use petalo::fov::{lor_fov_hit, FOV};
+use petalo::gauss::make_gauss_option;
+use petalo::system_matrix::WeightsAlongLor3;
+use petalo::{Point, Time, LOR, TWOPI};
+use geometry::units::mm;
+
+fn main() {
+
+    let n = Cli::from_args();
+    let fov = FOV::new_from_full_widths((mm(300.0), mm(300.0), mm(1.0)), (n.voxels, n.voxels, 1));
+    let tof = make_gauss_option(None, None);
+
+    // LORs between points spread evenly around a ring: golden-ratio steps
+    let golden = 0.618_034;
+    let traversals: Vec<WeightsAlongLor3> = (0..n.lors)
+        .map(|i| {
+            let a = (i as f32 * golden).fract() * TWOPI;
+            let b = a + (0.25 + 0.5 * (i as f32 * golden * golden).fract()) * TWOPI;
+            let point = |t: f32| Point::new(mm(400.0 * t.cos()), mm(400.0 * t.sin()), mm(0.0));
+            LOR::new(Time::ZERO, Time::ZERO, point(a), point(b))
+        })
+        .filter_map(|lor| lor_fov_hit(&lor, fov))
+        .map(WeightsAlongLor3::from)
+        .collect();
+
+    let (mut indices, mut weights) = (vec![], vec![]);
+    let mut t = Instant::now();
+
+    let mut total_3d = 0.0;
+    for traversal in &traversals {
+        indices.clear(); weights.clear();
+        total_3d += traversal.weights(&mut indices, &mut weights, &tof);
+    }
+    let time_3d = report_time(&mut t, "3D with nz = 1");
+
+    let mut total_2d = 0.0;
+    for traversal in &traversals {
+        indices.clear(); weights.clear();
+        total_2d += traversal.planar().unwrap().weights(&mut indices, &mut weights, &tof);
+    }
+    let time_2d = report_time(&mut t, "2D native    ");
+
+    println!("Total chord lengths: {total_3d} (3D) {total_2d} (2D)");
+    println!("Speedup factor: (3D time) / (2D time) {:?}",
+             time_3d.as_secs_f32() / time_2d.as_secs_f32());
+}
+
+// -------------------- Utility for reporting timings --------------------
+use std::time::Instant;
+
+fn report_time(t: &mut Instant, message: &str) -> std::time::Duration {
+    let elapsed = t.elapsed();
+    println!("{}: {} ms", message, elapsed.as_millis());
+    *t = Instant::now();
+    elapsed
+}
+
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "traversal", about = "Compare 2D-native and 3D traversal of planar LORs")]
+pub struct Cli {
+
+    /// Number of LORs
+    #[structopt(default_value = "1000000")]
+    lors: usize,
+
+    /// Number of voxels along x and y
+    #[structopt(default_value = "200")]
+    voxels: usize,
+
+}
@@ -81,6 +81,7 @@ pub fn kg (x: f32) -> Mass     {    Mass::new::        <kilogram>(x) }
 
 pub fn ratio (x: f32) -> Ratio  {   Ratio::new::<uom::si::ratio::ratio>(x) }
 pub fn radian(x: f32) -> Angle  {   Angle::new::<uom::si::angle::radian>(x) }
+pub fn degree(x: f32) -> Angle  {   Angle::new::<uom::si::angle::degree>(x) }
 pub fn turn  (x: f32) -> Angle  {   Angle::new::<uom::si::angle::revolution>(x) }
 
 // Reverse direction of the above. Rethink nomenclature once the dust has
@@ -92,6 +93,7 @@ pub fn kg_(x: Mass  ) -> f32 { x.get::<kilogram>  () }
 
 pub fn ratio_ (x: Ratio) -> f32 { x.get::<uom::si::ratio::ratio>() }
 pub fn radian_(x: Angle) -> f32 { x.get::<uom::si::angle::radian>() }
+pub fn degree_(x: Angle) -> f32 { x.get::<uom::si::angle::degree>() }
 pub fn turn_  (x: Angle) -> f32 { x.get::<uom::si::angle::revolution>() }
 
 #[macro_export]
@@ -0,0 +1,112 @@
+// ----------------------------------- CLI -----------------------------------
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "bench_projection", about = "Time MLEM iterations with different numbers of threads, and with precomputed system matrix rows")]
+pub struct Cli {
+
+    /// Number of random LORs through the FOV
+    #[structopt(long, short="n", default_value = "1000000")]
+    pub n_lors: usize,
+
+    /// Field Of View full-widths in mm
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<Length>), default_value = "300 mm,300 mm,300 mm")]
+    pub size: (Length, Length, Length),
+
+    /// Field Of View size in number of voxels
+    #[structopt(long, parse(try_from_str = parse_triplet::<usize>), default_value = "151,151,151")]
+    pub nvoxels: (usize, usize, usize),
+
+    /// Iterations timed for each configuration (at least one)
+    #[structopt(short, long, default_value = "3")]
+    pub iterations: NonZeroUsize,
+
+    /// Numbers of threads to compare, the first being the reference [default:
+    /// 1, 2, 4, ... up to one per core]
+    #[structopt(short = "j", long, use_delimiter = true)]
+    pub threads: Vec<usize>,
+
+    /// Also time iterations using system matrix rows precomputed in up to this
+    /// many MB. For example, `-n 1000000 --nvoxels 60,60,60 --system-matrix-mb
+    /// 4096` compares caching with recomputation for a small FOV.
+    #[structopt(long)]
+    pub system_matrix_mb: Option<usize>,
+
+}
+
+use structopt::StructOpt;
+
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::time::Instant;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use petalo::{Length, Point, Time};
+use petalo::fov::FovBuilder;
+use petalo::image::Image;
+use petalo::mlem::{MlemOptions, Reduction};
+use petalo::system_matrix::{RowSource, SystemMatrix, LOR};
+use petalo::utils::{group_digits, parse_triplet};
+use geometry::uom::ConstZero;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    let ((dx, dy, dz), (nx, ny, nz)) = (args.size, args.nvoxels);
+    let fov = FovBuilder::full_widths(dx, dy, dz).voxels(nx, ny, nz).build()?;
+    let lors = random_lors(args.n_lors, (dx, dy, dz));
+    let threads = if args.threads.is_empty() { default_threads() } else { args.threads.clone() };
+    println!("{fov}");
+    println!("{} LORs, {} iterations per configuration\n", group_digits(lors.len()), args.iterations);
+
+    let system_matrix = args.system_matrix_mb.map(|mb| {
+        let start = Instant::now();
+        let matrix = SystemMatrix::new(&lors, fov, None, None, None, mb << 20);
+        println!("Precomputed {} rows, {} MB, in {:.3} s\n",
+                 group_digits(matrix.n_stored()), matrix.bytes() >> 20, start.elapsed().as_secs_f64());
+        matrix
+    });
+    let mut sources: Vec<(&str, Option<&dyn RowSource>)> = vec![("on the fly", None)];
+    if let Some(matrix) = &system_matrix { sources.push(("precomputed", Some(matrix))) }
+
+    println!("threads  reduction      rows         s/iteration  speedup");
+    let mut reference = None;
+    for &n_threads in &threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads).build()?;
+        for reduction in [Reduction::Fast, Reduction::Deterministic] {
+            for &(source, rows) in &sources {
+                let start = Instant::now();
+                pool.install(|| Image::mlem_with(Image::ones(fov), &lors, None, None, None, None, 1, MlemOptions { rows, reduction, ..Default::default() })
+                             .nth(args.iterations.get() - 1));
+                let seconds = start.elapsed().as_secs_f64() / args.iterations.get() as f64;
+                let reference = *reference.get_or_insert(seconds);
+                println!("{n_threads:7}  {:13}  {source:11}  {seconds:11.3}  {:7.2}", format!("{reduction:?}"), reference / seconds);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 1, 2, 4, ... and the number of cores
+fn default_threads() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads: Vec<usize> = std::iter::successors(Some(1), |n| Some(n * 2)).take_while(|&n| n < cores).collect();
+    threads.push(cores);
+    threads
+}
+
+/// `n` LORs between points on a cylinder around the FOV, from a fixed seed so
+/// that every run projects the same LORs
+fn random_lors(n: usize, (dx, dy, dz): (Length, Length, Length)) -> Vec<LOR> {
+    use std::f32::consts::TAU;
+    let mut rng = StdRng::seed_from_u64(0);
+    let radius = if dx > dy { dx } else { dy };
+    let mut point = || {
+        let theta = TAU * rng.gen::<f32>();
+        let z = dz * (rng.gen::<f32>() - 0.5);
+        Point::new(radius * theta.cos(), radius * theta.sin(), z)
+    };
+    (0..n).map(|_| LOR::new(Time::ZERO, Time::ZERO, point(), point())).collect()
+}
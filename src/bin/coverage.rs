@@ -0,0 +1,98 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::{BoundPair, Energyf32, Length};
+use petalo::mlem::CoverageWeight;
+use petalo::sink::ImageFormat;
+use petalo::utils::{parse_bounds, parse_range, parse_triplet};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "coverage", about = "Image of how many LORs traverse each voxel of the FOV")]
+pub struct Cli {
+
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`. Repeat, or use
+    /// a glob pattern such as 'jobs/*.h5', to read several files as one table,
+    /// concatenated in order
+    #[structopt(short = "f", long, required = true, number_of_values = 1)]
+    pub input_file: Vec<String>,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
+
+    /// Which rows of the input file should be loaded
+    #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
+    pub event_range: Option<std::ops::Range<usize>>,
+
+    /// Ignore events with gamma energy/keV outside this range
+    #[structopt(short = "E", long, parse(try_from_str = parse_bounds::<Energyf32>), default_value = "..")]
+    pub ecut: BoundPair<Energyf32>,
+
+    /// Field Of View full-widths
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<Length>), default_value = "300 mm,300 mm,300 mm")]
+    pub size: (Length, Length, Length),
+
+    /// Field Of View size in number of voxels
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<usize>), default_value = "151,151,151")]
+    pub nvoxels: (usize, usize, usize),
+
+    /// What each LOR adds to the voxels it traverses: count (1), or chord (its
+    /// length in mm within the voxel)
+    #[structopt(short, long, default_value = "count")]
+    pub weight: CoverageWeight,
+
+    /// Format of the image: raw, or nifti (`.nii`, recording the voxel size
+    /// and position of the FOV)
+    #[structopt(long, default_value = "raw")]
+    pub format: ImageFormat,
+
+    /// Where to write the image
+    #[structopt(short, long)]
+    pub out: std::path::PathBuf,
+
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use petalo::io;
+use petalo::fov::FovBuilder;
+use petalo::image::Image;
+use petalo::io::hdf5::DEFAULT_LOR_DATASET;
+use petalo::reconstruction::Cuts;
+use petalo::utils::{group_digits, resolve_file_and_dataset};
+use petalo::utils::stats::format_fraction;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+
+    let ((dx, dy, dz), (nx, ny, nz)) = (args.size, args.nvoxels);
+    let fov = FovBuilder::full_widths(dx, dy, dz).voxels(nx, ny, nz).build()?;
+
+    let mut specs = args.input_file.iter();
+    let (first, dataset) = resolve_file_and_dataset(specs.next().unwrap(), args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+    let input_files: Vec<String> = std::iter::once(first)
+        .chain(specs.map(|spec| resolve_file_and_dataset(spec, Some(&dataset), DEFAULT_LOR_DATASET).0))
+        .collect();
+    let Cuts { charge, theta, .. } = Cuts::default();
+    let io_args = io::hdf5::Args {
+        input_files, dataset, event_range: args.event_range.clone(),
+        ecut: args.ecut, qcut: charge, theta_cut: theta,
+        ..Default::default()
+    };
+    let lors = io::hdf5::read_lors(io_args, None)?;
+    println!("Read {} LORs", group_digits(lors.len()));
+
+    let coverage = Image::coverage_image(fov, &lors, args.weight);
+    let reached = coverage.data.iter().filter(|&&c| c > 0.0).count();
+    println!("Coverage: {}", coverage.summary());
+    println!("Voxels traversed by any LOR: {}", format_fraction(reached, coverage.data.len()));
+    if let Some(dir) = args.out.parent() { std::fs::create_dir_all(dir)? }
+    args.format.write(&coverage, &args.out)?;
+    println!("Wrote coverage image to {}", args.out.display());
+    Ok(())
+}
@@ -0,0 +1,100 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::Lengthf32;
+use petalo::utils::{parse_triplet, resolve_file_and_dataset};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "event_display", about = "Show the sensor charges of one event, with the LOR reconstructed from them")]
+pub struct Cli {
+
+    /// MC file with `MC/total_charge`, `MC/waveform` and `MC/sensor_xyz` tables
+    pub mc_file: String,
+
+    /// Event whose sensors are shown
+    #[structopt(short, long)]
+    pub event: u64,
+
+    /// LORs reconstructed from the MC file: `file.h5` or `file.h5:group/dataset`
+    #[structopt(short = "f", long, requires = "lor-row")]
+    pub lor_file: Option<String>,
+
+    /// Row of the LOR reconstructed from the event, in `--lor-file`. LOR tables
+    /// do not record events, so it must be given explicitly.
+    #[structopt(short = "r", long, requires = "lor-file")]
+    pub lor_row: Option<usize>,
+
+    /// Write the event as CSV to this file, rather than showing it
+    #[structopt(long)]
+    pub csv: Option<std::path::PathBuf>,
+
+    /// Field Of View full-widths in mm
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<Lengthf32>), default_value = "300,300,300")]
+    size: (Lengthf32, Lengthf32, Lengthf32),
+
+    /// Field Of View size in number of voxels
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<usize>), default_value = "60,60,60")]
+    nvoxels: (usize, usize, usize),
+
+    /// Length in mm of the tick showing the charge on the brightest sensor
+    #[structopt(long, default_value = "50")]
+    tick: Lengthf32,
+
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+
+use petalo::{Point, Time};
+use petalo::fov::FovBuilder;
+use petalo::io::hdf5::DEFAULT_LOR_DATASET;
+use petalo::sensors::event::{EventDetail, LorRow};
+use petalo::system_matrix::LOR;
+use petalo::visualize::{coloured_lors, colour_scale, Shape};
+use geometry::units::mm;
+use geometry::uom::ConstZero;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+
+    let lor_source = args.lor_file.as_deref().map(|spec| resolve_file_and_dataset(spec, None, DEFAULT_LOR_DATASET));
+    let row = match (&lor_source, args.lor_row) {
+        (Some((file, dataset)), Some(row)) => Some(LorRow { file, dataset, row }),
+        _ => None,
+    };
+    let event = EventDetail::read(&args.mc_file, args.event, row)?;
+    let unplaced = event.sensors.iter().filter(|s| s.position.is_none()).count();
+    println!("Event {}: {} sensors, total charge {}, {} hits",
+             event.event_id, event.sensors.len(), event.total_charge(), event.n_hits());
+    if unplaced > 0 { println!("Warning: {unplaced} sensors are absent from the sensor table, and not shown") }
+    if let Some(lor) = &event.lor { println!("{}", LOR::from(lor)) }
+
+    if let Some(path) = &args.csv {
+        event.write_csv(std::fs::File::create(path)?)?;
+        println!("Wrote event to {}", path.display());
+        return Ok(())
+    }
+
+    // Each sensor is shown as a radial tick, whose length and colour grow with
+    // its charge; the LOR, in yellow, comes first so that its voxels are shown
+    let max_charge = event.sensors.iter().map(|s| s.charge).max().unwrap_or(0).max(1) as f32;
+    let ticks = event.sensors.iter().flat_map(|sensor| {
+        let [x, y, z] = sensor.position?;
+        let fraction = sensor.charge as f32 / max_charge;
+        let r = x.hypot(y).max(f32::EPSILON);
+        let out = args.tick * fraction / r;
+        let (p1, p2) = (Point::new(mm(x), mm(y), mm(z)), Point::new(mm(x + x * out), mm(y + y * out), mm(z)));
+        Some((LOR::new(Time::ZERO, Time::ZERO, p1, p2), colour_scale(fraction, 0.0, 1.0)))
+    });
+    let lors: Vec<(LOR, [f32; 3])> = event.lor.iter().map(|lor| (LOR::from(lor), [1.0, 1.0, 0.0])).chain(ticks).collect();
+    if lors.is_empty() { return Err("Nothing to show: no sensor of the event has a known position".into()) }
+
+    let ((dx, dy, dz), (nx, ny, nz)) = (args.size, args.nvoxels);
+    let fov = FovBuilder::full_widths(mm(dx), mm(dy), mm(dz)).voxels(nx, ny, nz).build()?;
+    coloured_lors(&lors, fov, Shape::Box, None, None);
+    Ok(())
+}
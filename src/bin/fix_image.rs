@@ -0,0 +1,57 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::utils::parse_triplet;
+use petalo::io::raw::{Dtype, Order};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "fix_image", about = "Rewrite legacy raw images in the versioned image format")]
+pub struct Cli {
+
+    /// Legacy image to be converted
+    pub input: PathBuf,
+
+    /// Where to write the converted image
+    pub output: PathBuf,
+
+    /// Number of voxels of a headerless input file. Without this, the input
+    /// is taken to contain its own sizes (the old `Image3D` format)
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<usize>))]
+    pub nvoxels: Option<(usize, usize, usize)>,
+
+    /// Field Of View full-widths of a headerless input file
+    #[structopt(short, long, parse(try_from_str = parse_triplet::<Length>))]
+    pub size: Option<(Length, Length, Length)>,
+
+    /// Type of the values in a headerless input file: f32 or f64
+    #[structopt(long, default_value = "f32")]
+    pub dtype: Dtype,
+
+    /// Fastest-varying axis in a headerless input file: x or z
+    #[structopt(long, default_value = "x")]
+    pub order: Order,
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use std::path::PathBuf;
+use petalo::Length;
+use petalo::fov::FOV;
+use petalo::io::raw::{fix_legacy, Legacy};
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let Cli { input, output, nvoxels, size, dtype, order } = args;
+    let legacy = match (nvoxels, size) {
+        (Some(n), Some(size)) => Legacy::Headerless { fov: FOV::new_from_full_widths(size, n), dtype, order },
+        (None   , None      ) => Legacy::Image3D,
+        _ => return Err("Headerless images need both --nvoxels and --size".into()),
+    };
+    fix_legacy(&input, &output, legacy)?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
@@ -18,32 +18,54 @@ pub struct Cli {
     #[structopt(possible_values = &Phantom::variants(), case_insensitive = true)]
     phantom: Phantom,
 
-    /// Image file to analyse
+    /// Image file to analyse: raw or NIfTI (`.nii`)
     pub input_file: String,
 
+    /// Displace the ROIs to follow the shift of the image relative to this one
+    #[structopt(long)]
+    pub align_to: Option<String>,
+
 }
 
 // --------------------------------------------------------------------------------
 use std::error::Error;
-use petalo::{fom::{InRoiFn, PointValue}, io::raw::Image3D};
+use petalo::fom::{InRoiFn, PointValue};
 use petalo::Intensityf32;
 use petalo::{Length};
 use petalo::image::Image;
+use petalo::registration::{estimate_shift, shift_to_offset};
 use petalo::fom;
 use petalo::fom::{Sphere, ROI, centres_of_slices_closest_to};
 use geometry::units::{mm, mm_};
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
     let args = Cli::from_args();
-    let image = Image3D::read_from_file(&args.input_file)?;
-    let image = Image::from(&image);
+    petalo::error::record_config(&args);
+    let image = Image::from_file(std::path::Path::new(&args.input_file))?;
+
+    let offset = match &args.align_to {
+        Some(reference) => {
+            let reference = Image::from_file(std::path::Path::new(reference))?;
+            if reference.fov.n != image.fov.n {
+                return Err(format!("Cannot align: image has {:?} voxels, reference has {:?}",
+                                   image.fov.n, reference.fov.n).into());
+            }
+            let shift = estimate_shift(&reference, &image);
+            println!("Shift relative to reference / voxels: ({:.2}, {:.2}, {:.2})", shift.0, shift.1, shift.2);
+            shift_to_offset(image.fov, shift)
+        },
+        None => (mm(0.0), mm(0.0), mm(0.0)),
+    };
 
     match args.phantom {
-        Phantom::Nema7    =>    nema7_foms(&image),
-        Phantom::Jaszczak => jaszczak_foms(&image),
+        Phantom::Nema7    =>    nema7_foms(&image, offset),
+        Phantom::Jaszczak => jaszczak_foms(&image, offset),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn sphere_foms(
     image         : &Image,
     sphere_ring_r : Length,
@@ -52,15 +74,20 @@ fn sphere_foms(
     background_zs : &[Length],
     background_xys: &[(Length, Length)],
     background_a  : Intensityf32,
+    (dx, dy, dz)  : (Length, Length, Length),
 ) -> Result<Vec<fom::FOM>, Box<dyn Error>> {
     let z_voxel_size = image.fov.voxel_size[2];
     let z_half_width = image.fov.half_width[2];
 
+    // Follow the displacement of the image
+    let background_zs = background_zs.iter().map(|&z| z + dz).collect::<Vec<_>>();
+    let background_xys = &background_xys.iter().map(|&(x, y)| (x + dx, y + dy)).collect::<Vec<_>>();
+
     // Ensure that the ROIs are z-aligned with some slice
     let background_zs = centres_of_slices_closest_to(
-        background_zs, z_half_width, z_voxel_size
+        &background_zs, z_half_width, z_voxel_size
     );
-    let foreground_z = fom::centre_of_slice_closest_to(z_half_width, z_voxel_size)(sphere_z);
+    let foreground_z = fom::centre_of_slice_closest_to(z_half_width, z_voxel_size)(sphere_z + dz);
 
 
     let mut background_roi_centres = vec![];
@@ -72,6 +99,7 @@ fn sphere_foms(
 
     let spheres: Vec<_> = sphere_spec.iter()
         .map(|&(n, d, a)| sphere(sphere_ring_r, n,d,a))
+        .map(|Sphere { x, y, r, a }| Sphere { x: x + dx, y: y + dy, r, a })
         .collect();
 
     let sphere_rois: Vec<_> = spheres.iter()
@@ -91,7 +119,7 @@ fn sphere_foms(
        .collect())
 }
 
-fn nema7_foms(image: &Image) -> Result<(), Box<dyn Error>> {
+fn nema7_foms(image: &Image, offset: (Length, Length, Length)) -> Result<(), Box<dyn Error>> {
 
     // The 6 hot spheres
     let ring_r = mm(114.4 / 2.0); // displacement of centre of sphere from centre of body
@@ -127,7 +155,7 @@ fn nema7_foms(image: &Image) -> Result<(), Box<dyn Error>> {
     let bg_activity = 1.0;
 
     // Calculate the contrasts and background variabilities
-    let foms = sphere_foms(image, ring_r, sphere_z, &spheres, &bg_zs, &bg_xys, bg_activity)?;
+    let foms = sphere_foms(image, ring_r, sphere_z, &spheres, &bg_zs, &bg_xys, bg_activity, offset)?;
 
     // The background count of the largest sphere (37mm) is also needed later
     // for the Accuracy of Corrections calculation. Create a place to store it.
@@ -141,9 +169,10 @@ fn nema7_foms(image: &Image) -> Result<(), Box<dyn Error>> {
     // --- 7.4.2 ---------------------------------------------------------------------
     let z_voxel_size = image.fov.voxel_size[2];
     let z_half_width = image.fov.half_width[2];
+    let (dx, dy, dz) = offset;
     // ignore slices which lie within 30mm of ends.
-    let hi_limit = mm(70.0         - 30.0);
-    let lo_limit = mm(70.0 - 180.0 + 30.0);
+    let hi_limit = mm(70.0         - 30.0) + dz;
+    let lo_limit = mm(70.0 - 180.0 + 30.0) + dz;
     // Find voxel z-centres nearest to the limits
     let nearest = fom::centre_of_slice_closest_to(z_half_width, z_voxel_size);
     let hi_centre = nearest(hi_limit);
@@ -160,14 +189,14 @@ fn nema7_foms(image: &Image) -> Result<(), Box<dyn Error>> {
     // Annotate each voxel value with its 3D position
     let all_voxels = image.values_with_positions();
     // Ignore voxels which lie outside of the lung insert
-    let lung = ROI::CylinderZ((mm(0.0), mm(0.0)), mm(30.0/2.0));
+    let lung = ROI::CylinderZ((dx, dy), mm(30.0/2.0));
     let filter = lung.contains_fn();
     let lung_voxels: Vec<_> = fom::in_roi(filter, &all_voxels).collect();
 
     // For each z-slice divide mean within ROI, by 37mm background mean
     let bg_37 = bg_37.unwrap();
     let aocs = (lo_index..=hi_index).into_iter()
-        .map(|i| { fom::mean_in_region(ROI::DiscZ((mm(0.0), mm(0.0), pos_of(i)), mm(30.0/2.0)), &lung_voxels) })
+        .map(|i| { fom::mean_in_region(ROI::DiscZ((dx, dy, pos_of(i)), mm(30.0/2.0)), &lung_voxels) })
         .map(|v| 100.0 * v / bg_37)
         .collect::<Vec<_>>();
 
@@ -176,7 +205,7 @@ fn nema7_foms(image: &Image) -> Result<(), Box<dyn Error>> {
 }
 
 
-fn jaszczak_foms(image: &Image) -> Result<(), Box<dyn Error>> {
+fn jaszczak_foms(image: &Image, offset: (Length, Length, Length)) -> Result<(), Box<dyn Error>> {
 
     // The 6 hot spheres
     let ring_r = mm(54.0); // displacement of centre of sphere from centre of body
@@ -205,7 +234,7 @@ fn jaszczak_foms(image: &Image) -> Result<(), Box<dyn Error>> {
     let bg_activity = 1.0;
 
     // Calculate the contrasts and background variabilities
-    let foms = sphere_foms(image, ring_r, sphere_z, &spheres, &bg_zs, &bg_xys, bg_activity)?;
+    let foms = sphere_foms(image, ring_r, sphere_z, &spheres, &bg_zs, &bg_xys, bg_activity, offset)?;
 
     println!("Sphere diameter / mm    CRC %   bg var %    SNR %");
     for &fom::FOM{ r, crc, bg_variability, snr } in foms.iter() {
@@ -34,14 +34,17 @@ use std::error::Error;
 use petalo::Lengthf32;
 use petalo::Length;
 use geometry::units::{mm, mm_};
-use petalo::fov::FOV;
+use petalo::fov::FovBuilder;
 use petalo::image::Image;
 use petalo::io::hdf5::{read_table, Primary};
 type L = Lengthf32;
 use geometry::uom::si::length::millimeter;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
     let args = Cli::from_args();
+    petalo::error::record_config(&args);
     // --- Process input files -------------------------------------------------------
     let Cli{ input_files, nvoxels, out_file, .. } = args.clone();
     let mut all_events: Vec<Primary> = vec![];
@@ -78,7 +81,9 @@ fn main() -> Result<(), Box<dyn Error>> {
         ((2.0 * xmax).ceil::<millimeter>(), (2.0 * ymax).ceil::<millimeter>(), (2.0 * zmax).ceil::<millimeter>())
     };
     // --- Create empty image of appropriate size ------------------------------------
-    let fov = FOV::new(size, nvoxels);
+    let (dx, dy, dz) = size;
+    let (nx, ny, nz) = nvoxels;
+    let fov = FovBuilder::full_widths(dx, dy, dz).voxels(nx, ny, nz).build()?;
     let mut image = Image::empty(fov);
     // --- Calculate how to translate spatial position into image index --------------
     let (xn, yn, zn) = args.nvoxels;
@@ -121,7 +126,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         format!("Wrote image with phisical size {} x {} x {} and {} x {} x {} voxels to {}",
                                                 xe,  ye,  ze,    xn,  yn,  zn,    out_file);
     progress.finish_with_message(message.clone());
-    petalo::io::raw::Image3D::from(&image).write_to_file(out_file)?;
+    image.write_to_raw_file(std::path::Path::new(&out_file))?;
     println!("{}", message);
     Ok(())
 }
@@ -1,6 +1,6 @@
 use structopt::StructOpt;
 use petalo::io;
-use petalo::io::hdf5::Hdf5Lor;
+use petalo::io::hdf5::{write_lors, Compression, Hdf5Lor};
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
@@ -21,6 +21,10 @@ pub struct Cli {
     #[structopt(short, long, default_value = "lors")]
     pub dataset: String,
 
+    /// Compression of the output: none, a gzip level such as 4, or zstd:<level>
+    #[structopt(long, default_value = "none")]
+    pub compress: Compression,
+
     // TODO allow using different group/dataset in output
 }
 
@@ -28,8 +32,11 @@ pub struct Cli {
 type Data = Hdf5Lor; // TODO: add CLI switches for selecting type
 
 
-fn main() -> hdf5::Result<()> {
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> hdf5::Result<()> {
     let args = Cli::from_args();
+    petalo::error::record_config(&args);
     let mut joined = Vec::<Data>::new();
 
     // ----- read data from separate files -------------------------------------------
@@ -45,11 +52,7 @@ fn main() -> hdf5::Result<()> {
     // --- write combined data to single file ----------------------------------------
     let outname = args.outfile;
     println!("Writing data to {}", outname);
-    hdf5::File::create(outname)?
-        .create_group(&args.group)?
-        .new_dataset_builder()
-        .with_data(&joined)
-        .create(args.dataset.as_str())?;
+    write_lors(outname, &format!("{}/{}", args.group, args.dataset), &joined, args.compress)?;
 
     Ok(())
 }
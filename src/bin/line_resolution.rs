@@ -0,0 +1,101 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::Length;
+use petalo::resolution::FitMethod;
+use petalo::utils::{parse_range, resolve_file_and_dataset};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "line_resolution", about = "Intrinsic resolution from LORs of a line source parallel to z, per view angle")]
+pub struct Cli {
+
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`
+    pub input_file: String,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
+
+    /// Which rows of the input file should be loaded
+    #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
+    pub event_range: Option<std::ops::Range<usize>>,
+
+    /// Transverse position of the source: `x,y` in mm
+    #[structopt(short, long, parse(try_from_str = parse_xy), default_value = "0,0")]
+    pub source: (Length, Length),
+
+    /// Number of view angles in [0, 180) degrees
+    #[structopt(long, default_value = "36")]
+    pub n_phi: usize,
+
+    /// Number of bins of the distance from the source
+    #[structopt(long, default_value = "101")]
+    pub n_s: usize,
+
+    /// Largest distance from the source considered in the fits
+    #[structopt(long, default_value = "10 mm")]
+    pub s_max: Length,
+
+    /// Views with fewer LORs are not fitted
+    #[structopt(long, default_value = "100")]
+    pub min_counts: usize,
+
+    /// How to fit the Gaussians: `lsq` or `moments`
+    #[structopt(short, long, default_value = "lsq")]
+    pub method: FitMethod,
+
+    /// CSV file of the fits [default: stdout]
+    #[structopt(short, long)]
+    pub out: Option<String>,
+}
+
+fn parse_xy(s: &str) -> Result<(Length, Length), String> {
+    let v = s.split(',').map(|x| x.trim().parse::<f32>().map_err(|e| format!("`{x}`: {e}"))).collect::<Result<Vec<_>, _>>()?;
+    match v[..] {
+        [x, y] => Ok((mm(x), mm(y))),
+        _ => Err(format!("Expected `x,y`, got `{s}`")),
+    }
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use petalo::io::hdf5::{Hdf5Lor, read_table, DEFAULT_LOR_DATASET};
+use petalo::resolution::LineSourceAnalysis;
+use petalo::system_matrix::LOR;
+use petalo::utils::group_digits;
+use geometry::units::{mm, mm_};
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let (input_file, dataset) = resolve_file_and_dataset(&args.input_file, args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+    let lors = read_table::<Hdf5Lor>(&input_file, &dataset, args.event_range.clone())?
+        .iter()
+        .filter(|l| !l.x1.is_nan() && !l.x2.is_nan())
+        .map(LOR::from)
+        .collect::<Vec<_>>();
+    eprintln!("Read {} LORs from {input_file}", group_digits(lors.len()));
+
+    let (x, y) = args.source;
+    let analysis = LineSourceAnalysis {
+        n_phi: args.n_phi, n_s: args.n_s, s_max: args.s_max, min_counts: args.min_counts, method: args.method,
+        ..LineSourceAnalysis::new(x, y)
+    };
+    let result = analysis.fit(&lors)?;
+    let skipped = result.views.iter().filter(|v| v.fit.is_none()).count();
+    if skipped > 0 { eprintln!("{skipped} of {} views not fitted", result.views.len()) }
+    match result.average {
+        Some(average) => eprintln!("Average FWHM: {:.3} mm, centroid offset: {:.3} mm", mm_(average.fwhm), mm_(average.centroid)),
+        None          => eprintln!("No view had enough LORs to fit"),
+    }
+
+    match &args.out {
+        Some(path) => result.write_csv(std::io::BufWriter::new(std::fs::File::create(path)?))?,
+        None       => result.write_csv(std::io::stdout().lock())?,
+    }
+    Ok(())
+}
@@ -20,6 +20,10 @@ pub struct Cli {
     #[structopt(long, short="d", default_value = "710 mm")]
     pub detector_diameter: Length,
 
+    /// Scanner description (TOML): overrides detector length and diameter
+    #[structopt(long)]
+    pub scanner: Option<PathBuf>,
+
     /// Number of random LORs to use in sensitivity image generation
     #[structopt(long, short="n", default_value = "5000000")]
     pub n_lors: usize,
@@ -28,6 +32,12 @@ pub struct Cli {
     #[structopt(long, default_value = "0.095")]
     pub rho_to_mu: Lengthf32,
 
+    /// Accumulate the image in (r, z) and broadcast it to the voxels: less
+    /// noise, but only valid if the density and the scanner are symmetric
+    /// under rotations about the z-axis (warns when the result suggests not)
+    #[structopt(long)]
+    pub assume_rotational_symmetry: bool,
+
     /// Maximum number of rayon threads
     #[structopt(short = "j", long, default_value = "30")]
     pub n_threads: usize,
@@ -40,16 +50,30 @@ use std::{error::Error, io::Write};
 use std::path::PathBuf;
 
 use petalo::{utils::group_digits, fov::FOV, Lengthf32};
+use petalo::utils::stats::format_millis;
 use petalo::image::Image;
+use petalo::cylindrical::ASYMMETRY_WARNING;
+use petalo::scanner::Scanner;
 
 use petalo::{Length, Time, AreaPerMass};
-use geometry::units::{ratio, kg, mm};
+use geometry::units::{kg, mm};
 use geometry::uom::ConstZero;
 use petalo::system_matrix as sm;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
 
-    let Cli { input, output, detector_length, detector_diameter, n_lors, rho_to_mu, n_threads } = Cli::from_args();
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let Cli { input, output, detector_length, detector_diameter, scanner, n_lors, rho_to_mu, assume_rotational_symmetry, n_threads } = args;
+
+    let (detector_length, detector_diameter) = match scanner {
+        Some(path) => {
+            let scanner = Scanner::load(path)?;
+            (scanner.length(), scanner.diameter())
+        },
+        None => (detector_length, detector_diameter),
+    };
 
     // Interpret rho_to_mu as converting from [rho in g/cm^3] to [mu in cm^-1]
     let rho_to_mu: AreaPerMass = {
@@ -66,7 +90,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut now = Instant::now();
 
     let mut report_time = |message: &str| {
-        println!("{}: {} ms", message, group_digits(now.elapsed().as_millis()));
+        println!("{}: {}", message, format_millis(now.elapsed()));
         now = Instant::now();
     };
 
@@ -83,8 +107,16 @@ fn main() -> Result<(), Box<dyn Error>> {
     pre_report(&format!("Creating sensitivity image, using {} LORs ... ", group_digits(n_lors)))?;
     let lors = find_potential_lors(n_lors, density.fov, detector_length, detector_diameter);
     let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads).build().unwrap();
-    let sensitivity = pool.install(|| Image::sensitivity_image(density, lors, n_lors, rho_to_mu));
-    report_time("done");
+    let sensitivity = if assume_rotational_symmetry {
+        let (sensitivity, asymmetry) = pool.install(|| Image::sensitivity_image_symmetric(density, lors, n_lors, rho_to_mu, false));
+        report_time("done");
+        warn_if_asymmetric(asymmetry);
+        sensitivity
+    } else {
+        let sensitivity = pool.install(|| Image::sensitivity_image(density, lors, n_lors, rho_to_mu));
+        report_time("done");
+        sensitivity
+    };
 
     let outfile = output.or_else(|| Some("sensitivity.raw".into())).unwrap();
     std::fs::create_dir_all(PathBuf::from(&outfile).parent().unwrap())?; // TODO turn into utility with cleaner interface
@@ -93,6 +125,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn warn_if_asymmetric(asymmetry: f32) {
+    if asymmetry > ASYMMETRY_WARNING {
+        eprintln!("Warning: --assume-rotational-symmetry, but the azimuthal sectors of the sensitivity differ by up to {:.0}%: \
+                   the image is the azimuthal average of the sensitivity", 100.0 * asymmetry);
+    }
+}
+
 /// Return a vector (size specified in Cli) of LORs with endpoints on cilinder
 /// with length and diameter specified in Cli and passing through the FOV
 /// specified in Cli.
@@ -103,7 +142,7 @@ fn find_potential_lors(n_lors: usize, fov: FOV, detector_length: Length, detecto
             let p1 = random_point_on_cylinder(l, r);
             let p2 = random_point_on_cylinder(l, r);
             if fov.entry(p1, p2).is_some() {
-                return sm::LOR::new(Time::ZERO, Time::ZERO, p1, p2, ratio(1.0))
+                return sm::LOR::new(Time::ZERO, Time::ZERO, p1, p2)
             }
         }
     };
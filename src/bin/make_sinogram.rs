@@ -0,0 +1,110 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::{BoundPair, Energyf32, Length};
+use petalo::utils::{group_digits, parse_bounds, parse_range, resolve_file_and_dataset};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "make_sinogram", about = "Bin LORs into an (r, phi, z, dz) sinogram, written to HDF5")]
+pub struct Cli {
+
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`. Repeat, or use
+    /// a glob pattern such as 'jobs/*.h5', to read several files as one table,
+    /// concatenated in order
+    #[structopt(short = "f", long, required = true, number_of_values = 1)]
+    pub input_file: Vec<String>,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
+
+    /// Which rows of the input file should be loaded
+    #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
+    pub event_range: Option<std::ops::Range<usize>>,
+
+    /// Ignore events with gamma energy/keV outside this range
+    #[structopt(short = "E", long, parse(try_from_str = parse_bounds::<Energyf32>), default_value = "..")]
+    pub ecut: BoundPair<Energyf32>,
+
+    /// Where to write the sinogram
+    #[structopt(short, long)]
+    pub out: std::path::PathBuf,
+
+    /// Compression of the counts: none, a gzip level such as 4, or zstd:<level>
+    #[structopt(long, default_value = "none")]
+    pub compress: Compression,
+
+    /// Number of bins of distance from the z-axis
+    #[structopt(long, default_value = "100")]
+    pub r_bins: usize,
+
+    /// Largest distance from the z-axis binned
+    #[structopt(long, default_value = "300 mm")]
+    pub r_max: Length,
+
+    /// Number of bins of transverse direction, over a whole turn
+    #[structopt(long, default_value = "200")]
+    pub phi_bins: usize,
+
+    /// Number of bins of the z of the LOR's midpoint
+    #[structopt(long, default_value = "100")]
+    pub z_bins: usize,
+
+    /// Range of z binned
+    #[structopt(long, default_value = "-500 mm", allow_hyphen_values = true)]
+    pub z_min: Length,
+
+    #[structopt(long, default_value = "500 mm", allow_hyphen_values = true)]
+    pub z_max: Length,
+
+    /// Number of bins of the difference in z of the LOR's endpoints
+    #[structopt(long, default_value = "20")]
+    pub dz_bins: usize,
+
+    /// Largest difference in z binned
+    #[structopt(long, default_value = "1000 mm")]
+    pub dz_max: Length,
+
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use petalo::io;
+use petalo::io::hdf5::{Compression, DEFAULT_LOR_DATASET};
+use petalo::reconstruction::Cuts;
+use petalo::sinogram::{Sinogram, SinogramBins};
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+
+    let mut specs = args.input_file.iter();
+    let (first, dataset) = resolve_file_and_dataset(specs.next().unwrap(), args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+    let input_files: Vec<String> = std::iter::once(first)
+        .chain(specs.map(|spec| resolve_file_and_dataset(spec, Some(&dataset), DEFAULT_LOR_DATASET).0))
+        .collect();
+    let Cuts { charge, theta, .. } = Cuts::default();
+    let io_args = io::hdf5::Args {
+        input_files, dataset, event_range: args.event_range.clone(),
+        ecut: args.ecut, qcut: charge, theta_cut: theta,
+        ..Default::default()
+    };
+    let lors = io::hdf5::read_lors(io_args, None)?;
+    println!("Read {} LORs", group_digits(lors.len()));
+
+    let bins = SinogramBins {
+        n_r : args.r_bins  , r_max : args.r_max,
+        n_phi: args.phi_bins,
+        n_z : args.z_bins  , z_min : args.z_min, z_max: args.z_max,
+        n_dz: args.dz_bins , dz_max: args.dz_max,
+    };
+    let sinogram = Sinogram::from_lors(bins, &lors)?;
+    println!("Binned {} LORs, {} beyond the binned range", group_digits(sinogram.total() as usize), group_digits(sinogram.outside));
+    sinogram.write_hdf5(&args.out, args.compress)?;
+    println!("Wrote sinogram to {}", args.out.display());
+    Ok(())
+}
@@ -2,15 +2,22 @@ use structopt::StructOpt;
 use itertools::Itertools;
 use indicatif::{ProgressBar, ProgressStyle};
 use petalo::io;
-use petalo::io::hdf5::{SensorXYZ, Hdf5Lor};
+use petalo::io::hdf5::{write_lors, Compression, SensorXYZ, Hdf5Lor, Qtot, Waveform, DEFAULT_LOR_DATASET};
+use petalo::sensors::{Charge, DuplicateSensors, SensorHit, SensorTables};
+use petalo::sensors::coincidences::{sort_coincidences, CoincidenceCuts};
+use petalo::io::mapped::RawLor;
+use petalo::io::units::{mm_from_file, ns_from_file, ns_to_file, point_to_file};
 use petalo::Energyf32;
 use petalo::{Length, Time, Point, Ratio};
 use geometry::units::mmps::f32::Area;
 use geometry::uom::ConstZero;
 use petalo::utils::group_digits;
+use petalo::utils::stats::{format_fraction, format_percentage};
+use petalo::system_matrix::{DegeneratePolicy, LOR};
+use petalo::error::Context;
 
 // TODO: try to remove the need for these
-use geometry::units::{mm, mm_, ns, ns_, ratio};
+use geometry::units::{mm_, ns, ns_, ratio};
 // The mair problems seems to be that uom types do not implement various third-party traits, such as:
 // + std::iter::Sum
 // + hdf5:H5Type
@@ -24,10 +31,28 @@ pub struct Cli {
     /// HDF5 input files with waveform and charge tables
     pub infiles: Vec<String>,
 
-    /// HDF5 output file for LORs found in input file
+    /// Output file for LORs found in input file: HDF5, or memory-mappable if
+    /// its name ends in `.lors`
     #[structopt(short, long)]
     pub out: String,
 
+    /// Compression of HDF5 output: none, a gzip level such as 4, or zstd:<level>
+    #[structopt(long, default_value = "none")]
+    pub compress: Compression,
+
+    /// LORs with coincident endpoints: drop, error or keep
+    #[structopt(long, default_value = "drop")]
+    pub degenerate: DegeneratePolicy,
+
+    /// Sensor ids appearing more than once in the sensor table: error or keep-first
+    #[structopt(long, default_value = "keep-first")]
+    pub duplicate_sensors: DuplicateSensors,
+
+    /// Ignore events with a larger fraction of their charge on sensors absent
+    /// from the sensor table
+    #[structopt(long, default_value = "0.5")]
+    pub max_orphan_charge: f32,
+
     #[structopt(subcommand)]
     reco: Reco,
 
@@ -68,17 +93,36 @@ enum Reco {
         /// Maximum distance between neighbours in cluster
         #[structopt(short = "d", long, default_value = "100 mm")]
         max_distance: Length,
-    }
+    },
+
+    /// Reconstruct LORs from the charge-weighted centroids and earliest times
+    /// of the sensors on either side of the brightest one
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Coincidences {
+        /// Ignore events with less total charge on either side
+        #[structopt(short, long = "charge-threshold", default_value = "4")]
+        q: u64,
+
+        /// Ignore events whose sides are further apart in time
+        #[structopt(short, long, default_value = "1 ns")]
+        time_window: Time,
+    },
 }
 
-fn main() -> hdf5::Result<()> {
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::from_args();
+    petalo::error::record_config(&args);
 
     // Before starting the potentially long computation, make sure that we can
     // write the result to the requested destination. If the directory where
-    // results will be written does not exist yet, make it. Panic if that's impossible.
-    std::fs::create_dir_all(std::path::PathBuf::from(&args.out).parent().unwrap())
-        .unwrap_or_else(|e| panic!("\n\nCan't write to \n\n   {}\n\n because \n\n   {e}\n\n", args.out));
+    // results will be written does not exist yet, make it.
+    if args.out.ends_with(".lors") && args.compress != Compression::None {
+        return Err("--compress applies to HDF5 output, not to memory-mappable .lors files".into())
+    }
+    let out_dir = std::path::PathBuf::from(&args.out).parent().unwrap().to_owned();
+    std::fs::create_dir_all(&out_dir).context(|| format!("creating output directory '{}'", out_dir.display()))?;
     // --- Progress bar --------------------------------------------------------------
     let files_pb = ProgressBar::new(args.infiles.len() as u64).with_message(args.infiles[0].clone());
     files_pb.set_style(ProgressStyle::default_bar()
@@ -86,7 +130,9 @@ fn main() -> hdf5::Result<()> {
         );
     files_pb.tick();
     // --- Process input files -------------------------------------------------------
-    let xyzs = read_sensor_map(&args.infiles[0])?;
+    let sensors = read_sensors(&args.infiles[0], args.duplicate_sensors)?;
+    let xyzs = make_sensor_position_map(sensors.clone());
+    let max_orphan_charge = args.max_orphan_charge;
     let mut lors: Vec<Hdf5Lor> = vec![];
     let mut n_events = 0;
     let mut failed_files = vec![];
@@ -109,25 +155,34 @@ fn main() -> hdf5::Result<()> {
 
         Reco::Half{q} => Box::new(
             move |infile: &String| -> hdf5::Result<(Vec<Hdf5Lor>, usize)> {
-                let qts = read_qts(infile)?;
+                let qts = read_qts(infile, &sensors, max_orphan_charge)?;
                 let events = group_by(|h| h.event_id, qts.into_iter().filter(|h| h.q >= q));
                 Ok((lors_from(&events, |evs| lor_from_hits(evs, &xyzs)), events.len()))
             }),
 
         Reco::Dbscan { q, min_count, max_distance } => Box::new(
             move |infile: &String| -> hdf5::Result<(Vec<Hdf5Lor>, usize)> {
-                let qts = read_qts(infile)?;
+                let qts = read_qts(infile, &sensors, max_orphan_charge)?;
                 let events = group_by(|h| h.event_id, qts.into_iter().filter(|h| h.q >= q));
                 Ok((lors_from(&events, |evs| lor_from_hits_dbscan(evs, &xyzs, min_count, max_distance)), events.len()))
             }),
+
+        Reco::Coincidences { q, time_window } => Box::new(
+            move |infile: &String| -> hdf5::Result<(Vec<Hdf5Lor>, usize)> {
+                let tables = read_sensor_tables(infile, &sensors)?;
+                let cuts = CoincidenceCuts { charge_threshold: q, time_window, max_orphan_charge };
+                let (lors, counts) = sort_coincidences(&tables, cuts);
+                println!("{}: {}", infile, counts);
+                Ok((lors, counts.events))
+            }),
     };
 
 
     for infile in args.infiles {
         // TODO message doesn't appear until end of iteration
-        files_pb.set_message(format!("{}. Found {} LORs in {} events, so far ({}%).",
+        files_pb.set_message(format!("{}. Found {} LORs in {} events, so far ({}).",
                                      infile.clone(), group_digits(lors.len()), group_digits(n_events),
-                                     if n_events > 0 {100 * lors.len() / n_events} else {0}));
+                                     format_percentage(lors.len(), n_events)));
         if let Ok((new_lors, envlen)) = makelors(&infile) {
             n_events += envlen;
             lors.extend_from_slice(&new_lors);
@@ -136,15 +191,25 @@ fn main() -> hdf5::Result<()> {
 
     }
     files_pb.finish_with_message("<finished processing files>");
-    println!("{} / {} ({}%) events produced LORs", group_digits(lors.len()), group_digits(n_events),
-             100 * lors.len() / n_events);
+    println!("{} events produced LORs", format_fraction(lors.len(), n_events));
+    // --- apply degenerate LOR policy -----------------------------------------------
+    let n_made = lors.len();
+    let mut admitted = Vec::with_capacity(n_made);
+    for lor in lors {
+        if args.degenerate.admit(&LOR::from(&lor)).map_err(hdf5::Error::from)? { admitted.push(lor) }
+    }
+    let lors = admitted;
+    if lors.len() < n_made {
+        println!("Dropped {} degenerate LORs", group_digits(n_made - lors.len()));
+    }
     // --- write lors to hdf5 --------------------------------------------------------
     println!("Writing LORs to {}", args.out);
-    hdf5::File::create(args.out)?
-        .create_group("reco_info")? // TODO rethink all the HDF% group names
-        .new_dataset_builder()
-        .with_data(&lors)
-        .create("lors")?;
+    if args.out.ends_with(".lors") {
+        let raw: Vec<RawLor> = lors.iter().map(RawLor::from).collect();
+        io::mapped::write(&args.out, &raw).map_err(|e| hdf5::Error::from(e.to_string()))?;
+    } else {
+        write_lors(&args.out, DEFAULT_LOR_DATASET, &lors, args.compress)?;
+    }
     // --- Report any files that failed no be read -----------------------------------
     if !failed_files.is_empty() {
         println!("Warning: failed to read the following files:");
@@ -187,10 +252,11 @@ fn lor_from_barycentre_of_vertices(vertices: &[Vertex]) -> Option<Hdf5Lor> {
 
     let nan = f32::NAN; let q1 = nan; let q2 = nan;
 
+    let (x1, y1, z1) = point_to_file(Point::new(x1, y1, z1));
+    let (x2, y2, z2) = point_to_file(Point::new(x2, y2, z2));
     Some(Hdf5Lor {
-        dt: ns_(t2 - t1),
-        x1: mm_(x1), y1: mm_(y1), z1: mm_(z1),
-        x2: mm_(x2), y2: mm_(y2), z2: mm_(z2),
+        dt: ns_to_file(t2 - t1),
+        x1, y1, z1,   x2, y2, z2,
         q1, q2, E1, E2,
     })
 }
@@ -201,13 +267,8 @@ fn lor_from_hits(hits: &[QT], xyzs: &SensorMap) -> Option<Hdf5Lor> {
     let (p1, t1) = cluster_xyzt(&cluster_a, xyzs)?;
     let (p2, t2) = cluster_xyzt(&cluster_b, xyzs)?;
     //println!("{:?} {:?}", xyzt_a, xyzt_b);
-    Some(Hdf5Lor {
-        dt: ns_(t2 - t1),
-        x1: mm_(p1.x), y1: mm_(p1.y), z1: mm_(p1.z),
-        x2: mm_(p2.x), y2: mm_(p2.y), z2: mm_(p2.z),
-        // TODO qs and Es missing
-        q1: f32::NAN, q2: f32::NAN,   E1: f32::NAN, E2: f32::NAN,
-    })
+    // TODO qs and Es missing
+    Some(Hdf5Lor::from(&LOR::new(t1, t2, p1, p2)))
 }
 
 fn xxx(labels: &ndarray::Array1<Option<usize>>) -> usize {
@@ -359,7 +420,7 @@ fn vertex_barycentre(vertices: &[&Vertex]) -> Option<Barycentre> {
     let mut tt = Time::ZERO;
     for &&Vertex { x, y, z, t, pre_KE, post_KE, .. } in vertices {
         let dE = pre_KE - post_KE;
-        let (x, y, z, t) = (mm(x), mm(y), mm(z), ns(t));
+        let (x, y, z, t) = (mm_from_file(x), mm_from_file(y), mm_from_file(z), ns_from_file(t));
         delta_E += dE;
         rr += (x*x + y*y).sqrt() * dE;
         xx += x * dE;
@@ -381,6 +442,8 @@ fn vertex_barycentre(vertices: &[&Vertex]) -> Option<Barycentre> {
 #[cfg(test)]
 mod test_vertex_barycentre {
     use super::*;
+    use petalo::constants::ELECTRON_REST_ENERGY;
+    use geometry::units::mm;
     use float_eq::assert_float_eq;
     use geometry::units::radian;
     use std::f32::consts::PI;
@@ -393,7 +456,7 @@ mod test_vertex_barycentre {
             // dummy values
             z: 23.4, t: 123.0,
             event_id: 0, parent_id: 0, track_id: 0, process_id: 0, volume_id: 0,
-            moved: 0.0, deposited: 0, pre_KE: pre_ke.unwrap_or(511.0), post_KE: 0.0,
+            moved: 0.0, deposited: 0, pre_KE: pre_ke.unwrap_or(ELECTRON_REST_ENERGY), post_KE: 0.0,
         }
     }
 
@@ -441,7 +504,7 @@ mod test_vertex_barycentre {
 
     #[test]
     fn energy_weights_used_correctly() {
-        let energies = vec![511.0, 415.7, 350.0, 479.0, 222.5];
+        let energies = vec![ELECTRON_REST_ENERGY, 415.7, 350.0, 479.0, 222.5];
         let ys       = vec![353.5, 382.0, 367.3, 372.9, 377.0];
         let vertices: Vec<Vertex> = ys.iter().zip(energies.iter())
             .map(|(y, e)| vertex(mm(0.0), mm(*y), Some(*e)))
@@ -490,22 +553,6 @@ pub struct Vertex {
     volume_id: u32,  // different files
 }
 
-#[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
-#[repr(C)]
-pub struct Waveform {
-    pub event_id: u32,
-    pub sensor_id: u32,
-    pub time: f32,
-}
-
-#[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
-#[repr(C)]
-pub struct Qtot {
-    pub event_id: u32,
-    pub sensor_id: u32,
-    pub charge: u32,
-}
-
 // TODO Is there really no simpler way?
 fn array_to_vec<T: Clone>(array: ndarray::Array1<T>) -> Vec<T> {
     let mut vec = vec![];
@@ -517,30 +564,43 @@ fn array_to_vec<T: Clone>(array: ndarray::Array1<T>) -> Vec<T> {
 
 
 
-fn read_sensor_map(filename: &str) -> hdf5::Result<SensorMap> {
-    // TODO: refactor and hide in a function
+/// The sensor table of `filename`, with duplicate ids resolved by `duplicates`
+fn read_sensors(filename: &str, duplicates: DuplicateSensors) -> hdf5::Result<Vec<SensorXYZ>> {
     let array = io::hdf5::read_table::<SensorXYZ>(filename, "MC/sensor_xyz"  , None)?;
-    Ok(make_sensor_position_map(array_to_vec(array)))
+    let tables = SensorTables::new(array_to_vec(array), vec![], vec![], duplicates).map_err(hdf5::Error::from)?;
+    if tables.n_duplicates > 0 { eprintln!("Warning: {}: {}", filename, tables.report()) }
+    Ok(tables.sensors)
 }
 
 fn read_vertices(filename: &str) -> hdf5::Result<Vec<Vertex>> {
     Ok(array_to_vec(io::hdf5::read_table::<Vertex>(filename, "MC/vertices", None)?))
 }
 
-fn read_qts(infile: &str) -> hdf5::Result<Vec<QT>> {
-    // Read charges and waveforms
+/// Charges and waveforms of `infile` on known `sensors`, in events with no
+/// more than `max_orphan_charge` of their charge on unknown sensors
+fn read_qts(infile: &str, sensors: &[SensorXYZ], max_orphan_charge: f32) -> hdf5::Result<Vec<QT>> {
+    Ok(combine_tables(&read_sensor_tables(infile, sensors)?, max_orphan_charge))
+}
+
+/// Charges and waveforms of `infile`, checked against `sensors`
+fn read_sensor_tables(infile: &str, sensors: &[SensorXYZ]) -> hdf5::Result<SensorTables> {
     let qs = io::hdf5::read_table::<Qtot     >(infile, "MC/total_charge", None)?;
     let ts = io::hdf5::read_table::<Waveform >(infile, "MC/waveform"    , None)?;
-    Ok(combine_tables(qs, ts))
+    let qs = qs.iter().map(|&Qtot    { event_id, sensor_id, charge }| Charge    { event_id: event_id as u64, sensor_id: sensor_id as u64, charge: charge as u64 }).collect();
+    let ts = ts.iter().map(|&Waveform{ event_id, sensor_id, time   }| SensorHit { event_id: event_id as u64, sensor_id: sensor_id as u64, time: time as f64 }).collect();
+    let tables = SensorTables::new(sensors.to_vec(), qs, ts, DuplicateSensors::KeepFirst).map_err(hdf5::Error::from)?;
+    if tables.n_orphan_charges + tables.n_orphan_hits > 0 { eprintln!("Warning: {}: {}", infile, tables.report()) }
+    Ok(tables)
 }
 
-fn combine_tables(qs: ndarray::Array1<Qtot>, ts: ndarray::Array1<Waveform>) -> Vec<QT> {
+fn combine_tables(tables: &SensorTables, max_orphan_charge: f32) -> Vec<QT> {
     let mut qts = vec![];
-    let mut titer = ts.iter();
-    for &Qtot{ event_id, sensor_id, charge:q} in qs.iter() {
-        for &Waveform{ event_id: te, sensor_id: ts, time:t} in titer.by_ref() {
+    let mut titer = tables.hits.iter();
+    for &Charge{ event_id, sensor_id, charge:q} in tables.charges.iter() {
+        if !tables.is_complete(event_id, max_orphan_charge) { continue }
+        for &SensorHit{ event_id: te, sensor_id: ts, time:t} in titer.by_ref() {
             if event_id == te && sensor_id == ts {
-                qts.push(QT{ event_id, sensor_id, q, t: ns(t) });
+                qts.push(QT{ event_id: event_id as u32, sensor_id: sensor_id as u32, q: q as u32, t: ns_from_file(t as f32) });
                 break;
             }
         }
@@ -558,7 +618,7 @@ fn group_by<T>(group_by: impl FnMut(&T) -> u32, qts: impl IntoIterator<Item = T>
 
 fn make_sensor_position_map(xyzs: Vec<SensorXYZ>) -> SensorMap {
     xyzs.iter().cloned()
-        .map(|SensorXYZ{sensor_id, x, y, z}| (sensor_id, (mm(x), mm(y), mm(z))))
+        .map(|SensorXYZ{sensor_id, x, y, z}| (sensor_id, (mm_from_file(x), mm_from_file(y), mm_from_file(z))))
         .collect()
 }
 
@@ -2,7 +2,32 @@
 use structopt::StructOpt;
 
 use petalo::{utils::{parse_triplet, parse_range, parse_bounds, parse_maybe_cutoff, CutoffOption,
-                     group_digits}, lorogram::BuildScattergram};
+                     group_digits, Region}, lorogram::{AdaptiveBinning, BuildScattergram}};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensitivityMode {
+    Ones,
+    Analytic,
+    AnalyticAxial,
+    Data,
+}
+
+impl SensitivityMode {
+    fn variants() -> [&'static str; 4] { ["ones", "analytic", "analytic-axial", "data"] }
+}
+
+impl std::str::FromStr for SensitivityMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ones"           => Ok(Self::Ones),
+            "analytic"       => Ok(Self::Analytic),
+            "analytic-axial" => Ok(Self::AnalyticAxial),
+            "data"           => Ok(Self::Data),
+            _ => Err(format!("Unknown sensitivity mode '{s}': expected one of {}", Self::variants().join(", "))),
+        }
+    }
+}
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
@@ -13,10 +38,32 @@ pub struct Cli {
     #[structopt(short, long, default_value = "5")]
     pub iterations: usize,
 
-    /// Number of OSEM subsets to use
+    /// Number of OSEM subsets to use: subset `k` of `n` takes every `n`th LOR,
+    /// starting with the `k`th
     #[structopt(long, default_value = "1")]
     pub subsets: usize,
 
+    /// Accelerate MLEM: `plain`, `power:<α>` (over-relaxation) or
+    /// `nesterov:<α>` (momentum). Costs one extra forward projection per subset.
+    #[structopt(long, default_value = "plain")]
+    pub accel: Acceleration,
+
+    /// Memoize the system matrix rows of up to this many distinct LORs, for
+    /// data in which identical LORs recur (mashed or step-and-shoot scans)
+    #[structopt(long)]
+    pub geometry_cache: Option<usize>,
+
+    /// Round LOR endpoints to this granularity when matching them in
+    /// --geometry-cache. 0: only exact duplicates match
+    #[structopt(long, default_value = "0 mm")]
+    pub geometry_cache_granularity: Length,
+
+    /// Precompute the system matrix rows of the LORs, using up to this many MB,
+    /// rather than recomputing them in every iteration. Rows which do not fit
+    /// are still computed on the fly.
+    #[structopt(long, conflicts_with = "geometry-cache")]
+    pub system_matrix_mb: Option<usize>,
+
     /// Field Of View full-widths in mm
     #[structopt(short, long, parse(try_from_str = parse_triplet::<Length>), default_value = "300 mm,300 mm,300 mm")]
     pub size: (Length, Length, Length),
@@ -25,50 +72,330 @@ pub struct Cli {
     #[structopt(short, long, parse(try_from_str = parse_triplet::<usize>), default_value = "151,151,151")]
     pub nvoxels: (usize, usize, usize),
 
+    /// Move the endpoints of the LORs to where they enter and leave the FOV.
+    /// LORs which miss the FOV are always dropped.
+    #[structopt(long)]
+    pub truncate_lors: bool,
+
     /// TOF time-resolution sigma (eg '200 ps'). TOF ignored if not supplied
     #[structopt(short, long)]
     pub tof: Option<Time>,
 
-    /// TOF cutoff (✕ sigma). to disable: `-k no` [Rust version only]
-    #[structopt(short = "k", default_value = "3", long, parse(try_from_str = parse_maybe_cutoff))]
-    pub cutoff: CutoffOption<Ratio>,
+    /// TOF cutoff (✕ sigma): `3`, or `before,after` the peak. to disable: `-k no`
+    #[structopt(short = "k", long = "tof-cutoff", alias = "cutoff", default_value = "3", parse(try_from_str = parse_maybe_cutoff))]
+    pub cutoff: CutoffOption<TofCutoff>,
+
+    /// Ignore voxels farther than this from the z-axis (eg '380 mm')
+    #[structopt(long)]
+    pub tof_bore_radius: Option<Length>,
+
+    /// Radius of tube-of-response model of LORs (used with --tube-samples)
+    #[structopt(long, default_value = "0 mm")]
+    pub tube_radius: Length,
+
+    /// Number of parallel sub-LORs averaged in tube-of-response model. 1: thin LORs
+    #[structopt(long, default_value = "1")]
+    pub tube_samples: usize,
+
+    /// Divide the weights of each LOR by its chord length through the FOV.
+    /// Also applied to `--sensitivity-mode data`
+    #[structopt(long)]
+    pub normalize_chord: bool,
 
     /// Override automatic generation of image output file name
     #[structopt(short, long)]
     pub out_files: Option<String>,
 
-    /// LORs to read in
-    #[structopt(short = "f", long, default_value = "MC.h5")]
-    pub input_file: String, // TODO replace String with PathBuf here and wherever else appropriate
+    /// Format of the images: raw, or nifti (`.nii`, recording the voxel size
+    /// and position of the FOV)
+    #[structopt(long, default_value = "raw")]
+    pub format: ImageFormat,
+
+    /// Write the voxel-centre coordinates of each image to `<image>.axes.json`
+    #[structopt(long)]
+    pub write_axes: bool,
+
+    /// Also write every image to this HDF5 file, as `images/<iteration>-<subset>`
+    #[structopt(long)]
+    pub hdf5_series: Option<PathBuf>,
+
+    /// Compression of the images in --hdf5-series: none, a gzip level such as
+    /// 4, or zstd:<level>
+    #[structopt(long, default_value = "none")]
+    pub compress: Compression,
+
+    /// Filter the images written out, but not those on which MLEM iterates:
+    /// `gauss:<σ>` (e.g. `gauss:2 mm`), or edge-preserving total-variation
+    /// denoising `tv:lambda=<λ>,iters=<n>` (λ in intensity × mm), to which
+    /// `,verbose` adds a printout of the energy after every TV iteration
+    #[structopt(long)]
+    pub post_filter: Option<PostFilter>,
+
+    /// Estimate the voxel-wise variance of the final image (diagonal Fisher
+    /// information) and write it to `<out-files>variance.raw` (or `.nii`)
+    #[structopt(long)]
+    pub variance_image: bool,
 
-    /// The dataset location inside the input file
-    #[structopt(short, long, default_value = "reco_info/lors")]
-    pub dataset: String,
+    /// Skip writing outputs which `<out-files>manifest.json` records as
+    /// completed by an earlier, interrupted run, and whose contents are
+    /// unchanged. The images are still reconstructed.
+    #[structopt(long)]
+    pub resume_outputs: bool,
+
+    /// LORs to read in: `file.h5`, `file.h5:group/dataset`, or a memory-mapped
+    /// LOR file written by `makelor -o file.lors` (any dataset is ignored).
+    /// Repeat, or use a glob pattern such as 'jobs/*.h5', to read several
+    /// files as one table, concatenated in order
+    #[structopt(short = "f", long, default_value = "MC.h5", number_of_values = 1)]
+    pub input_file: Vec<String>, // TODO replace String with PathBuf here and wherever else appropriate
+
+    /// Instead of reading --input-file, reconstruct this many synthetic LORs
+    /// from a uniform cylinder filling half the FOV in each direction (see
+    /// `testdata::uniform_cylinder_lors`), written to `synthetic_lors.h5`
+    /// beside the images
+    #[cfg(feature = "testing")]
+    #[structopt(long)]
+    pub synthetic: Option<usize>,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
 
     /// Which rows of the input file should be loaded
     #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
     pub event_range: Option<std::ops::Range<usize>>,
 
+    /// Per-LOR multiplicative corrections computed externally: 1D dataset in
+    /// the input file, aligned with the LOR table. May be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub mult_correction_dataset: Vec<String>,
+
+    /// Per-LOR additive corrections computed externally: 1D dataset in the
+    /// input file, aligned with the LOR table. May be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub add_correction_dataset: Vec<String>,
+
     /// Sensitivity image to be used for corrections
     #[structopt(long)]
     pub sensitivity_image: Option<PathBuf>,
 
+    /// Source of sensitivity correction [default: analytic if
+    /// --sensitivity-image is given, ones otherwise]. `analytic-axial` uses the
+    /// linear axial fall-off of the sensitivity of a cylindrical detector of
+    /// --detector-half-length, constant transaxially. `data` estimates the
+    /// sensitivity from the backprojection of the measured LORs themselves:
+    /// this conflates the activity distribution with the scanner sensitivity,
+    /// and is noisy unless the source is extended, roughly uniform and has
+    /// high statistics.
+    #[structopt(long, possible_values = &SensitivityMode::variants(), case_insensitive = true)]
+    pub sensitivity_mode: Option<SensitivityMode>,
+
+    /// Axial half-length of the detector, for `--sensitivity-mode analytic-axial`
+    #[structopt(long)]
+    pub detector_half_length: Option<Length>,
+
+    /// Gaussian sigma with which to smooth the `data` sensitivity image
+    #[structopt(long, default_value = "5 mm")]
+    pub sensitivity_smoothing: Length,
+
+    /// Accumulate the `data` sensitivity image in (r, z) and broadcast it to
+    /// the voxels: less noise, but only valid for sources and scanners which
+    /// are symmetric under rotations about the z-axis, such as uniform
+    /// cylinder normalization scans (warns when the data suggest not)
+    #[structopt(long)]
+    pub assume_rotational_symmetry: bool,
+
+    /// Weight each LOR in the `data` sensitivity image by its normalization
+    /// (multiplicative correction: see --mult-correction-dataset and
+    /// --crystal-interference-bins), as it is weighted in the projections
+    #[structopt(long)]
+    pub weight_sensitivity_by_normalization: bool,
+
+    /// Image from which to start iterating, instead of a uniform one
+    #[structopt(long)]
+    pub initial_image: Option<PathBuf>,
+
+    /// Write the image and the number of iterations done to this HDF5 file
+    /// after every iteration, for --resume
+    #[structopt(long)]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Continue from this checkpoint, up to --iterations in total. Its FOV must
+    /// match --size and --nvoxels
+    #[structopt(long, conflicts_with = "initial-image")]
+    pub resume: Option<PathBuf>,
+
+    /// Only update voxels inside this region, keeping the rest at their values
+    /// in --initial-image: `sphere:x,y,z,r` or `box:x0,y0,z0,x1,y1,z1` (mm)
+    #[structopt(long, requires = "initial-image")]
+    pub focus_roi: Option<Region>,
+
+    /// Reconstruct only one of this many statistically independent replicates
+    /// of the data (Poisson thinning). Select it with --split-index
+    #[structopt(long, requires = "split-index")]
+    pub split: Option<usize>,
+
+    /// Which replicate to reconstruct with --split: 0 to split-1
+    #[structopt(long, requires = "split")]
+    pub split_index: Option<usize>,
+
+    /// Seed for assigning events to --split replicates
+    #[structopt(long, default_value = "0")]
+    pub split_seed: u64,
+
     /// Use true rather than reco LOR data
     #[structopt(long)]
     use_true: bool,
 
-    /// Maximum number of rayon threads
-    #[structopt(short = "j", long, default_value = "4")]
+    /// Check the inputs, outputs and configuration, estimate the time and
+    /// memory needed, from a sample of the LORs, and exit
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Number of LORs to sample in --dry-run
+    #[structopt(long, default_value = "100000")]
+    pub dry_run_sample: usize,
+
+    /// Read input serially, rather than overlapping it with other work
+    #[structopt(long)]
+    pub no_prefetch: bool,
+
+    /// Also write a Chrome trace of the phases of the run (see about://tracing)
+    #[structopt(long)]
+    pub trace_json: Option<PathBuf>,
+
+    /// Write a JSON summary of the run (peak memory of each phase, ...) to this
+    /// file. With --dry-run, the plan and the cost estimate
+    #[structopt(long)]
+    pub summary_json: Option<PathBuf>,
+
+    /// Append the statistics of each image (time, total, maximum, ...) to this
+    /// file as they are calculated: JSON lines if it ends in `.json` or
+    /// `.jsonl`, otherwise CSV
+    #[structopt(long)]
+    pub stats_out: Option<PathBuf>,
+
+    /// Include the data mismatch (nats per LOR) on this many LORs in the
+    /// statistics, and print it after every image
+    #[structopt(long)]
+    pub stats_likelihood: Option<usize>,
+
+    /// Stop before all iterations are done once no voxel changes by more than
+    /// this fraction of its value from one image to the next
+    #[structopt(long)]
+    pub stop_when_delta: Option<f32>,
+
+    /// Do not abort the reconstruction when it appears to diverge
+    #[structopt(long)]
+    pub no_divergence_check: bool,
+
+    /// Abort if the image maximum grows by more than this factor in one iteration
+    #[structopt(long, default_value = "10")]
+    pub divergence_max_growth: f32,
+
+    /// Abort if the total activity grows by more than this factor in one iteration
+    #[structopt(long, default_value = "10")]
+    pub divergence_total_growth: f32,
+
+    /// Abort if the data mismatch (nats per LOR) increases by more than this in one iteration
+    #[structopt(long, default_value = "1")]
+    pub divergence_mismatch_increase: f32,
+
+    /// Number of LORs on which the data mismatch is evaluated
+    #[structopt(long, default_value = "10000")]
+    pub divergence_sample: usize,
+
+    /// Maximum number of rayon threads: 0 for one per core
+    #[structopt(short = "j", long, alias = "threads", default_value = "0")]
     pub num_threads: usize,
 
-    /// Ignore events with gamma energy/keV outside this range
-    #[structopt(short = "E", long, parse(try_from_str = parse_bounds::<Energyf32>), default_value = "..")]
-    pub ecut: BoundPair<Energyf32>,
+    /// How the backprojections of the threads are added up: `fast`, or
+    /// `deterministic`, which gives the same images with any number of threads
+    #[structopt(long, default_value = "fast")]
+    pub reduction: Reduction,
+
+    /// Ignore events with gamma energy/keV outside this range, or outside a
+    /// window around the photopeak found in a sample of the input:
+    /// `auto:<n>sigma[,bins=<n>][,max=<keV>]`, such as `auto:2.5sigma`
+    #[structopt(short = "E", long, default_value = "..")]
+    pub ecut: EnergyCut,
 
     /// Ignore events with detected charge/pes outside this range
     #[structopt(short, long, parse(try_from_str = parse_bounds::<Chargef32>), default_value = "..")]
     pub qcut: BoundPair<Chargef32>,
 
+    /// Instead of --qcut, cut the lowest fraction (e.g. 0.05) of the charges
+    /// of each side separately, ignoring NaN and zero charges
+    #[structopt(long)]
+    pub auto_qcut: Option<f32>,
+
+    /// Ignore LORs whose polar angle from the transverse plane exceeds this (degrees)
+    #[structopt(long)]
+    pub max_theta: Option<f32>,
+
+    /// Ignore LORs whose polar angle from the transverse plane is below this (degrees)
+    #[structopt(long)]
+    pub min_theta: Option<f32>,
+
+    /// LORs with coincident endpoints: drop, error or keep
+    #[structopt(long, default_value = "drop")]
+    pub degenerate: DegeneratePolicy,
+
+    /// Order the endpoints of each LOR deterministically (by z, then azimuth,
+    /// then x), flipping the sign of dt to match
+    #[structopt(long)]
+    pub canonicalize_endpoints: bool,
+
+    /// Columns of the LOR table named differently from the standard ones,
+    /// which are then read one by one: `field=column,...`, such as
+    /// `x1=xr1,E1=e1`
+    #[structopt(long)]
+    pub column_map: Option<ColumnMap>,
+
+    /// Move every LOR as it is read, to align the data with the FOV:
+    /// 'tx,ty,tz,rx,ry,rz', translations in mm and rotations in degrees. Points
+    /// are rotated about the x, then y, then z axis, and then translated
+    #[structopt(long, allow_hyphen_values = true)]
+    pub transform: Option<RigidTransform>,
+
+    /// Correct each time frame for dead time: paralyzable or non-paralyzable
+    #[structopt(long, requires = "dead-time-tau")]
+    pub dead_time_model: Option<DeadTimeModel>,
+
+    /// Dead time of the dead-time model, e.g. '300 ns'
+    #[structopt(long, requires = "dead-time-model")]
+    pub dead_time_tau: Option<Time>,
+
+    /// Singles rates of each time frame (start / s, rate / s^-1), for dead-time correction
+    #[structopt(long, default_value = "reco_info/singles")]
+    pub singles_dataset: String,
+
+    /// Acquisition time (s) of each event, for dead-time correction and scatter
+    /// time windows: 1D dataset in the input file, aligned with the LOR table
+    #[structopt(long, default_value = "reco_info/time")]
+    pub acquisition_time_dataset: String,
+
+    /// Scanner description (TOML), for corrections which depend on the module layout
+    #[structopt(long)]
+    pub scanner: Option<PathBuf>,
+
+    /// LORs of a uniform-source normalization scan: `file.h5` or
+    /// `file.h5:group/dataset`, read with the same cuts as the data
+    #[structopt(long)]
+    pub normalization_scan: Option<String>,
+
+    /// Correct for crystal interference, derived from --normalization-scan in
+    /// this many bins of the LOR angle within each module of --scanner
+    #[structopt(long, requires_all = &["scanner", "normalization-scan"])]
+    pub crystal_interference_bins: Option<usize>,
+
+    /// Gather scatter corrections separately in this many windows of equal
+    /// acquisition time, interpolating between them (each takes the memory of
+    /// a whole scattergram)
+    #[structopt(long)]
+    pub scatter_time_windows: Option<usize>,
+
     /// Apply scatter corrections with   r-axis up to this value
     #[structopt(long)]
     pub scatter_r_max: Option<Length>,
@@ -105,74 +432,361 @@ pub struct Cli {
     #[structopt(long)]
     pub scatter_tof_max: Option<Time>,
 
+    /// Gamma energy (keV) below which a coincidence counts as a scatter in the
+    /// scatter corrections [default: electron rest energy]
+    #[structopt(long)]
+    pub scatter_true_threshold: Option<Energyf32>,
+
+    /// Scatter correction of LORs in scattergram bins without trues [default: 1,
+    /// no correction]
+    #[structopt(long)]
+    pub scatter_empty_bin_value: Option<f32>,
+
+    /// Interpolate scattergram counts linearly between bin centres, rather
+    /// than using those of the bin containing each LOR
+    #[structopt(long)]
+    pub scatter_interpolate: bool,
+
+    /// Merge adjacent scattergram bins until each holds at least this many
+    /// events: `min_count=<n>`. The --scatter-*-bins become the maximum number
+    /// of bins along each axis; phi bins are never merged. Not available with
+    /// --scatter-time-windows.
+    #[structopt(long)]
+    pub scattergram_adaptive: Option<AdaptiveBinning>,
+
+    /// Report the k hottest voxels of the final image, optionally at least
+    /// min_sep mm apart: `k[,min_sep]`. Also written to --summary-json
+    #[structopt(long, parse(try_from_str = parse_hotspots))]
+    pub report_hotspots: Option<(usize, Option<Length>)>,
+
+    /// On Ctrl-C, `finish` the current (sub)iteration and write its image, or
+    /// `abandon` it, keeping the previous one. A second Ctrl-C exits at once
+    #[structopt(long, default_value = "finish")]
+    pub on_cancel: OnCancel,
+
 }
 
 // --------------------------------------------------------------------------------
 
 use std::error::Error;
+use std::ops::Bound::Unbounded;
 use std::path::PathBuf;
-use std::fs::create_dir_all;
+use std::time::Duration;
 
 use petalo::{Energyf32, Chargef32, BoundPair};
-use petalo::{Length, Time, Ratio};
-use petalo::lorogram::Scattergram;
-use petalo::fov::FOV;
-use petalo::image::Image;
+use petalo::{Length, Time};
+use petalo::gauss::TofCutoff;
+use petalo::fov::{FOV, FovBuilder};
+use petalo::image::{Hotspot, Image};
+use petalo::mlem::Reduction;
+use petalo::system_matrix::{DegeneratePolicy, Tube};
 use petalo::io;
-use geometry::units::mm_;
+use petalo::timing;
+use petalo::thinning::Split;
+use petalo::transform::RigidTransform;
+use petalo::divergence::{IterationStats, Thresholds};
+use petalo::sink::{write_output, ImageFormat};
+use petalo::utils::stats::{format_millis, format_percentage};
+use petalo::io::columns::ColumnMap;
+use petalo::io::hdf5::{describe_files, Compression, DeadTimeArgs, ScatterWindowArgs};
+use petalo::deadtime::DeadTimeModel;
+use petalo::acceleration::Acceleration;
+use petalo::post_filter::PostFilter;
+use petalo::ecut::EnergyCut;
+use petalo::cancel::{Cancel, OnCancel, RunStatus};
+use petalo::error::Context;
+use petalo::reconstruction::{self, Cuts, Outputs, Plan, Reconstruction};
+use geometry::units::{degree, mm, ratio};
+
+/// Exit status of a run cancelled with Ctrl-C: 128 + SIGINT, as shells report
+const EXIT_CANCELLED: i32 = 130;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+
+    #[allow(unused_mut)]
+    let mut args = Cli::from_args();
+    petalo::error::record_config(&args);
+    #[cfg(feature = "testing")]
+    if let Some(n) = args.synthetic {
+        args.input_file = vec![write_synthetic_lors(&args, n)?];
+    }
 
+    // Set up progress reporting, timing and memory accounting. The trace is
+    // completed when `telemetry` is dropped, at the end of run
+    let telemetry = timing::init(args.trace_json.as_deref());
 
-fn main() -> Result<(), Box<dyn Error>> {
+    // Set the maximum number of threads used by rayon for parallel iteration
+    match rayon::ThreadPoolBuilder::new().num_threads(args.num_threads).build_global() {
+        Err(e) => println!("{}", e),
+        Ok(_)  => println!("Using up to {} threads.", rayon::current_num_threads()),
+    }
 
-    let args = Cli::from_args();
+    let (reconstruction, plan) = validate_and_plan(&args).context(|| "configuring the reconstruction")?;
+    if args.dry_run { return dry_run(&args, &reconstruction, &plan, &telemetry) }
+    println!("{}", plan.fov);
+    println!("Expecting to need {} MB for {} LORs", group_digits(plan.memory.total() >> 20), group_digits(plan.total_rows));
 
-    // Set up progress reporting and timing
-    use std::time::Instant;
-    let mut now = Instant::now();
+    let cancel = Cancel::new(Default::default()).on_cancel(args.on_cancel);
+    install_interrupt_handler(&cancel)?;
+    let reconstruction = reconstruction.cancellation(cancel);
 
-    let mut report_time = |message: &str| {
-        println!("{}: {} ms", message, group_digits(now.elapsed().as_millis()));
-        now = Instant::now();
+    let print = |_: usize, image: &Image, stats: &IterationStats| -> Result<(), Box<dyn Error>> {
+        println!("Iteration {:02}-{:02}: {}{}", stats.iteration, stats.subset, image.summary(), convergence(stats));
+        Ok(())
+    };
+    let summary = reconstruction.sink(print).run().context(|| format!(
+        "reconstructing {} with {} iterations of {} subsets", describe_files(&args.input_file), args.iterations, args.subsets))?;
+
+    let hotspots = match (args.report_hotspots, &summary.final_image) {
+        (Some((k, min_sep)), Some(image)) => {
+            let hotspots: Vec<Hotspot> = match min_sep {
+                Some(min_sep) => image.top_k_separated(k, min_sep),
+                None          => image.top_k(k),
+            }.into_iter().map(Hotspot::from).collect();
+            println!("rank      voxel          x/mm    y/mm    z/mm      value");
+            for (rank, Hotspot { voxel: [ix, iy, iz], position_mm: [x, y, z], value }) in hotspots.iter().enumerate() {
+                println!("{:4}  {ix:4} {iy:4} {iz:4}  {x:7.1} {y:7.1} {z:7.1}  {value:.4e}", rank + 1);
+            }
+            Some(hotspots)
+        },
+        _ => None,
     };
 
-    report_time("Startup");
+    println!("{}", telemetry.memory.summary());
+    if let Some(path) = &args.summary_json {
+        let mut json = telemetry.summary_json();
+        json["reconstruction"] = serde_json::to_value(&summary)?;
+        if let Some(hotspots) = &hotspots { json["hotspots"] = serde_json::to_value(hotspots)?; }
+        let json = serde_json::to_string_pretty(&json)?;
+        write_output(summary.manifest.as_ref(), path, |tmp| Ok(std::fs::write(tmp, &json)?))
+            .context(|| format!("writing summary '{}'", path.display()))?;
+    }
+    if summary.status == RunStatus::Cancelled {
+        // Complete the trace, which `exit` would otherwise skip
+        drop(telemetry);
+        std::process::exit(EXIT_CANCELLED);
+    }
+    Ok(())
+}
 
-    // Read event data from disk into memory
-    let                      Cli{ input_file, dataset, event_range, use_true, ecut, qcut, .. } = args.clone();
-    let io_args = io::hdf5::Args{ input_file, dataset, event_range, use_true, ecut, qcut };
+/// The change from the previous image and, if calculated, the data mismatch
+fn convergence(stats: &IterationStats) -> String {
+    let mut text = String::new();
+    if let Some(seconds) = stats.seconds { text += &format!("   {}", format_millis(Duration::from_secs_f32(seconds))) }
+    if let Some(change) = stats.max_change { text += &format!("   max change {change:.2e}") }
+    if let Some(mismatch) = stats.mismatch { text += &format!("   mismatch {mismatch:.5}") }
+    text
+}
 
-    let scattergram = build_scattergram(args.clone());
+/// Request cancellation on the first Ctrl-C, and exit immediately on the second
+fn install_interrupt_handler(cancel: &Cancel) -> Result<(), Box<dyn Error>> {
+    let cancel = cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel.is_requested() { std::process::exit(EXIT_CANCELLED) }
+        eprintln!("Cancelling the reconstruction (Ctrl-C again to exit immediately) ...");
+        cancel.request();
+    })?;
+    Ok(())
+}
 
-    // Define field of view extent and voxelization
-    let fov = FOV::new(args.size, args.nvoxels);
+/// Translate the command line into a `Reconstruction`
+fn reconstruction(args: &Cli) -> Result<Reconstruction, Box<dyn Error>> {
+    let mut r = Reconstruction::new()
+        .inputs(&args.input_file)
+        .use_true(args.use_true)
+        .cuts(Cuts {
+            energy: match args.ecut {
+                EnergyCut::Bounds(bounds) => bounds,
+                EnergyCut::Auto(_)        => (Unbounded, Unbounded),
+            },
+            charge: args.qcut,
+            theta: io::hdf5::theta_bounds(args.min_theta.map(degree), args.max_theta.map(degree)),
+        })
+        .degenerate(args.degenerate)
+        .canonicalize_endpoints(args.canonicalize_endpoints)
+        .corrections(args.mult_correction_dataset.clone(), args.add_correction_dataset.clone())
+        .prefetch(!args.no_prefetch)
+        .fov(fov(args)?)
+        .truncate_lors(args.truncate_lors)
+        .tof_cutoff(cutoff(args))
+        .tube(Tube { radius: args.tube_radius, samples: args.tube_samples, normalize_chord: args.normalize_chord })
+        .sensitivity(sensitivity_mode(args)?)
+        .iterations(args.iterations)
+        .subsets(args.subsets)
+        .acceleration(args.accel)
+        .reduction(args.reduction)
+        .outputs(Outputs {
+            pattern: guess_filename(args),
+            format: args.format,
+            write_axes: args.write_axes,
+            hdf5_series: args.hdf5_series.clone(),
+            compression: args.compress,
+            post_filter: args.post_filter,
+            variance_image: args.variance_image,
+            summary_json: args.summary_json.clone(),
+            resume: args.resume_outputs,
+        });
+    if let Some(dataset) = &args.dataset { r = r.dataset(dataset) }
+    if let Some(range) = &args.event_range { r = r.event_range(range.clone()) }
+    if let Some(fraction) = args.auto_qcut { r = r.auto_qcut(fraction) }
+    if let Some(transform) = args.transform { r = r.transform(transform) }
+    if let (Some(k), Some(index)) = (args.split, args.split_index) { r = r.split(Split::new(k, index, args.split_seed)?) }
+    if let (Some(model), Some(tau)) = (args.dead_time_model, args.dead_time_tau) {
+        r = r.dead_time(DeadTimeArgs {
+            time_dataset: args.acquisition_time_dataset.clone(),
+            singles_dataset: args.singles_dataset.clone(),
+            model, tau,
+        });
+    }
+    if let EnergyCut::Auto(auto) = args.ecut { r = r.auto_ecut(auto) }
+    if let Some(columns) = &args.column_map { r = r.column_map(columns.clone()) }
+    if let Some(scatter) = build_scattergram(args) { r = r.scatter(scatter) }
+    if let Some(n_windows) = args.scatter_time_windows {
+        r = r.scatter_time_windows(ScatterWindowArgs { time_dataset: args.acquisition_time_dataset.clone(), n_windows });
+    }
+    if let (Some(n_bins), Some(scanner), Some(scan)) = (args.crystal_interference_bins, &args.scanner, &args.normalization_scan) {
+        r = r.crystal_interference(n_bins, scanner, scan);
+    }
+    if let Some(sigma) = args.tof { r = r.tof(sigma) }
+    if let Some(max_entries) = args.geometry_cache { r = r.geometry_cache(max_entries, args.geometry_cache_granularity) }
+    if let Some(mb) = args.system_matrix_mb { r = r.system_matrix_memory(mb << 20) }
+    if let Some(path) = &args.initial_image { r = r.initial_image(path) }
+    if let Some(path) = &args.checkpoint { r = r.checkpoint(path) }
+    if let Some(path) = &args.resume { r = r.resume_from(path) }
+    if let Some(region) = args.focus_roi { r = r.focus(region) }
+    if let Some(path) = &args.stats_out { r = r.stats_out(path) }
+    if let Some(sample) = args.stats_likelihood { r = r.likelihood_sample(sample) }
+    if let Some(delta) = args.stop_when_delta { r = r.stop_when_delta(delta) }
+    if !args.no_divergence_check {
+        let thresholds = Thresholds {
+            max_growth       : Some(args.divergence_max_growth),
+            total_growth     : Some(args.divergence_total_growth),
+            mismatch_increase: Some(args.divergence_mismatch_increase),
+        };
+        r = r.divergence_check(thresholds, args.divergence_sample);
+    }
+    Ok(r)
+}
 
-    println!("Reading LOR data from disk ...");
-    let measured_lors = io::hdf5::read_lors(io_args, scattergram)?;
-    report_time("Loaded LOR data from disk");
+fn sensitivity_mode(args: &Cli) -> Result<reconstruction::SensitivityMode, String> {
+    use reconstruction::SensitivityMode as Mode;
+    if args.assume_rotational_symmetry && args.sensitivity_mode != Some(SensitivityMode::Data) {
+        return Err("--assume-rotational-symmetry requires --sensitivity-mode data".into())
+    }
+    if args.weight_sensitivity_by_normalization && args.sensitivity_mode != Some(SensitivityMode::Data) {
+        return Err("--weight-sensitivity-by-normalization requires --sensitivity-mode data".into())
+    }
+    let mode = args.sensitivity_mode.unwrap_or(
+        if args.sensitivity_image.is_some() { SensitivityMode::Analytic }
+        else                                { SensitivityMode::Ones     });
+    Ok(match mode {
+        SensitivityMode::Ones     => Mode::Ones,
+        SensitivityMode::Analytic => Mode::Analytic(args.sensitivity_image.clone()
+            .ok_or("--sensitivity-mode analytic requires --sensitivity-image")?),
+        SensitivityMode::AnalyticAxial => Mode::AxialProfile { detector_half_length: args.detector_half_length
+            .ok_or("--sensitivity-mode analytic-axial requires --detector-half-length")? },
+        SensitivityMode::Data     => Mode::Data { smoothing: args.sensitivity_smoothing,
+                                                  assume_rotational_symmetry: args.assume_rotational_symmetry,
+                                                  weight_by_normalization: args.weight_sensitivity_by_normalization },
+    })
+}
 
-    let file_pattern = guess_filename(&args);
+/// Parse `k` or `k,min_sep` (min_sep in mm)
+fn parse_hotspots(s: &str) -> Result<(usize, Option<Length>), String> {
+    let (k, min_sep) = match s.split_once(',') {
+        Some((k, min_sep)) => (k, Some(min_sep)),
+        None               => (s, None),
+    };
+    let k = k.trim().parse::<usize>().map_err(|e| format!("{e} in hotspot count '{k}'"))?;
+    let min_sep = min_sep
+        .map(|m| m.trim().parse::<f32>().map(mm).map_err(|e| format!("{e} in hotspot separation '{m}'")))
+        .transpose()?;
+    Ok((k, min_sep))
+}
 
-    // If the directory where results will be written does not exist yet, make it
-    create_dir_all(PathBuf::from(format!("{:02}00.raw", file_pattern)).parent().unwrap())?;
+/// Write `n` LORs from a uniform cylinder inscribed in the inner half of the
+/// FOV, with 511 keV gammas, to `synthetic_lors.h5` in the directory of the
+/// images. Returns the path.
+#[cfg(feature = "testing")]
+fn write_synthetic_lors(args: &Cli, n: usize) -> Result<String, Box<dyn Error>> {
+    use petalo::constants::ELECTRON_REST_ENERGY;
+    use petalo::io::hdf5::{write_table, Hdf5Lor, DEFAULT_LOR_DATASET};
+    use petalo::testdata::uniform_cylinder_lors;
+    let (dx, dy, dz) = args.size;
+    let lors = uniform_cylinder_lors(n, (if dx < dy { dx } else { dy }) / 4.0, dz / 4.0, 0);
+    let rows: Vec<Hdf5Lor> = lors.iter()
+        .map(|lor| Hdf5Lor { E1: ELECTRON_REST_ENERGY, E2: ELECTRON_REST_ENERGY, ..Hdf5Lor::from(lor) })
+        .collect();
+    let pattern = guess_filename(args);
+    let dir = std::path::Path::new(&pattern).parent().unwrap_or_else(|| std::path::Path::new(""));
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("synthetic_lors.h5").to_string_lossy().into_owned();
+    write_table(&path, DEFAULT_LOR_DATASET, &rows)?;
+    println!("Wrote {} synthetic LORs to {path}", group_digits(n));
+    Ok(path)
+}
 
-    let sensitivity_image: Option<Image> = args.sensitivity_image.as_ref().map(|path| Image::from_raw_file(path)).transpose()?;
-    if let Some(i) = sensitivity_image.as_ref() { assert_image_sizes_match(i, args.nvoxels, args.size) };
-    if sensitivity_image.is_some() { report_time("Loaded sensitivity image"); }
+/// The FOV requested on the command line: `--size` gives full widths
+fn fov(args: &Cli) -> Result<FOV, String> {
+    let ((dx, dy, dz), (nx, ny, nz)) = (args.size, args.nvoxels);
+    FovBuilder::full_widths(dx, dy, dz).voxels(nx, ny, nz).build()
+}
 
-    // Set the maximum number of threads used by rayon for parallel iteration
-    match rayon::ThreadPoolBuilder::new().num_threads(args.num_threads).build_global() {
-        Err(e) => println!("{}", e),
-        Ok(_)  => println!("Using up to {} threads.", args.num_threads),
+/// The TOF cutoff, including any bore-radius clamp
+fn cutoff(args: &Cli) -> Option<TofCutoff> {
+    match args.tof_bore_radius {
+        None         => args.cutoff,
+        Some(radius) => Some(args.cutoff
+            .unwrap_or_else(|| TofCutoff::symmetric(ratio(f32::INFINITY)))
+            .with_bore_radius(radius)),
     }
+}
 
-    for (image, iteration, subset) in (Image::mlem(fov, &measured_lors, args.tof, args.cutoff, sensitivity_image, args.subsets))
-        .take(args.iterations * args.subsets) {
-            report_time(&format!("Iteration {iteration:2}-{subset:02}"));
-            let path = PathBuf::from(format!("{}{iteration:02}-{subset:02}.raw", file_pattern));
-            petalo::io::raw::Image3D::from(&image).write_to_file(&path)?;
-            report_time("                               Wrote raw bin");
-            // TODO: step_by for print every
-        }
+/// The reconstruction configured on the command line, having checked
+/// everything that can be checked before reading the LORs
+fn validate_and_plan(args: &Cli) -> Result<(Reconstruction, Plan), Box<dyn Error>> {
+    let reconstruction = reconstruction(args)?;
+    let plan = reconstruction.plan(rayon::current_num_threads())?;
+    Ok((reconstruction, plan))
+}
+
+/// Summarize the plan, and estimate the cost of the reconstruction by
+/// projecting a sample of the LORs. Both are included in the --summary-json
+fn dry_run(args: &Cli, reconstruction: &Reconstruction, plan: &Plan, telemetry: &timing::Telemetry) -> Result<(), Box<dyn Error>> {
+    let g = group_digits;
+    let mb = |bytes: usize| format!("{} MB", g(bytes >> 20));
+    println!("Dry run: all checks passed");
+    for (file, rows) in &plan.inputs { println!("    input                   : {file} ({} rows)", g(*rows)) }
+    println!("    rows to read            : {} of {} bytes", g(plan.total_rows), plan.row_bytes);
+    println!("    FOV                     : {}", plan.fov);
+    if let Some(dir) = &plan.output_dir { println!("    output directory        : {}", dir.display()) }
+    let memory = &plan.memory;
+    println!("    memory for LORs         : {}", mb(memory.lors));
+    println!("    memory for reading      : {}", mb(memory.read_buffer));
+    println!("    memory for images       : {}", mb(memory.images + memory.sensitivity));
+    println!("    memory for {:3} threads  : {}", rayon::current_num_threads(), mb(memory.thread_buffers));
+    println!("    memory in total         : {}", mb(memory.total()));
+
+    let report = reconstruction.estimate_cost(args.dry_run_sample, rayon::current_num_threads())?;
+    let (sample, estimate) = (&report.sample, &report.estimate);
+    println!("Dry run: projected {} of {} rows", g(sample.n_lors), g(report.total_rows));
+    println!("    LORs hitting FOV        : {}", format_percentage(sample.n_hits, sample.n_lors));
+    println!("    voxels per LOR          : {:.1}", estimate.voxels_per_lor);
+    println!("    LORs after cuts         : {}", g(estimate.n_events));
+    println!("    time per iteration      : {:.1} s", estimate.iteration_time.as_secs_f64());
+    println!("    time for {:2} x {:2} subsets: {:.1} s", args.iterations, args.subsets,
+             estimate.iteration_time.as_secs_f64() * args.iterations as f64);
+
+    if let Some(path) = &args.summary_json {
+        let mut json = telemetry.summary_json();
+        json["plan"] = serde_json::to_value(plan)?;
+        json["cost"] = serde_json::to_value(&report)?;
+        let json = serde_json::to_string_pretty(&json)?;
+        write_output(None, path, |tmp| Ok(std::fs::write(tmp, &json)?))
+            .context(|| format!("writing summary '{}'", path.display()))?;
+    }
     Ok(())
 }
 
@@ -182,37 +796,18 @@ fn guess_filename(args: &Cli) -> String {
     } else {
         let (nx, ny, nz) = args.nvoxels;
         let tof = args.tof.map_or(String::from("OFF"), |x| format!("{:.0?}", x));
-        format!("data/out/mlem/{nx}_{ny}_{nz}_tof_{tof}",
+        let split = match (args.split, args.split_index) {
+            (Some(k), Some(i)) => format!("_split_{i}_of_{k}"),
+            _ => String::new(),
+        };
+        format!("data/out/mlem/{nx}_{ny}_{nz}_tof_{tof}{split}",
                 nx=nx, ny=ny, nz=nz, tof=tof)
     }
 }
 
 
-type FovSize = (Length, Length, Length);
-type NVoxels = (usize , usize , usize );
-
-/// Panic if the image size does not match the specified values
-fn assert_image_sizes_match(image: &Image, nvoxels: NVoxels, fov_size: FovSize) {
-    use float_eq::float_eq;
-    let size = image.fov.half_width;
-    let (idx, idy, idz) = (size[0]*2.0, size[1]*2.0, size[2]*2.0);
-    let [inx, iny, inz] = image.fov.n;
-    let (enx, eny, enz) = nvoxels;
-    let (edx, edy, edz) = fov_size;
-    // Unwrap uom, to make float_eq! work
-    let ids = [mm_(idx), mm_(idy), mm_(idz)];
-    let eds = [mm_(edx), mm_(edy), mm_(edz)];
-    if ! ((enx, eny, enz) == (inx, iny, inz) && float_eq!(eds, ids, ulps_all <= 1)) {
-        // TODO enable use of density images with different
-        // pixelizations as long as they cover the whole FOV.
-        println!("Mismatch sensitivity image and output image size:");
-        println!("Sensitivity image: {:3} x {:3} x {:3} pixels, {:3} x {:3} x {:3} mm", inx,iny,inz, mm_(idx),mm_(idy),mm_(idz));
-        println!("     Output image: {:3} x {:3} x {:3} pixels, {:3} x {:3} x {:3} mm", enx,eny,enz, mm_(edx),mm_(edy),mm_(edz));
-        panic!("For now, the sensitivity image must match the dimensions of the output image exactly.");
-    }
-}
-
-fn build_scattergram(args: Cli) -> Option<Scattergram> {
+/// The scatter configuration, if any scatter axis is requested
+fn build_scattergram(args: &Cli) -> Option<BuildScattergram> {
     let mut builder = BuildScattergram::new();
     if let Some(n) = args.scatter_phi_bins { builder = builder.phi_bins(n) };
     if let Some(n) = args.scatter_r_bins   { builder = builder.  r_bins(n) };
@@ -222,6 +817,10 @@ fn build_scattergram(args: Cli) -> Option<Scattergram> {
     if let Some(r) = args.scatter_r_max    { builder = builder. r_max  (r) };
     if let Some(z) = args.scatter_dz_max   { builder = builder.dz_max  (z) };
     if let Some(t) = args.scatter_tof_max  { builder = builder.dt_max  (t) };
+    if let Some(e) = args.scatter_true_threshold { builder = builder.true_threshold(e) };
+    if let Some(v) = args.scatter_empty_bin_value { builder = builder.empty_bin_value(ratio(v)) };
+    builder = builder.interpolate(args.scatter_interpolate);
+    if let Some(a) = args.scattergram_adaptive { builder = builder.adaptive(a) };
     if let Some(l) = args.scatter_z_length { builder = builder.z_length(l) };
-    builder.build()
+    builder.clone().build().is_some().then(|| builder)
 }
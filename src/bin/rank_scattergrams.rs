@@ -0,0 +1,71 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::utils::{parse_range, group_digits, resolve_file_and_dataset};
+use petalo::lorogram::ScattergramConfig;
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "rank_scattergrams", about = "Rank scattergram configurations by cross-validation")]
+pub struct Cli {
+
+    /// Scattergram configurations to compare, e.g. 'r:20:30,phi:15'
+    #[structopt(required = true)]
+    pub configs: Vec<ScattergramConfig>,
+
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`
+    #[structopt(short = "f", long)]
+    pub input_file: String,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
+
+    /// Which rows of the input file should be loaded
+    #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
+    pub event_range: Option<std::ops::Range<usize>>,
+
+    /// Seed for the random train/test split
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+
+    /// Gamma energy (keV) below which a coincidence counts as a scatter
+    /// [default: electron rest energy]
+    #[structopt(long)]
+    pub true_threshold: Option<f32>,
+
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use petalo::io::hdf5::{Hdf5Lor, read_table, DEFAULT_LOR_DATASET};
+use petalo::lorogram::{classify_energies, Prompt};
+use petalo::constants::ELECTRON_REST_ENERGY;
+use petalo::lorogram::cross_validation::rank_scattergram_configs;
+use petalo::system_matrix::{LorWithMeta, LOR};
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let (input_file, dataset) = resolve_file_and_dataset(&args.input_file, args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+
+    let threshold = args.true_threshold.unwrap_or(ELECTRON_REST_ENERGY);
+    let lors: Vec<(Prompt, LOR)> = read_table::<Hdf5Lor>(&input_file, &dataset, args.event_range.clone())?
+        .iter()
+        .filter(|Hdf5Lor { x1, x2, .. }| !x1.is_nan() && !x2.is_nan())
+        .map(LorWithMeta::from)
+        .map(|LorWithMeta { lor, e1, e2, .. }| (classify_energies(e1, e2, threshold), lor))
+        .collect();
+    println!("Read {} classified LORs", group_digits(lors.len()));
+
+    let ranked = rank_scattergram_configs(&args.configs, &lors, args.seed);
+
+    println!("rank   NLL/LOR   configuration");
+    for (rank, (config, score)) in ranked.iter().enumerate() {
+        println!("{:4}  {:8.5}   {}", rank + 1, score.nll, config);
+    }
+    Ok(())
+}
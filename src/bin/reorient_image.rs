@@ -0,0 +1,77 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::orientation::Orientation;
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "reorient_image", about = "Write an image in the orientation of a reference image, without resampling")]
+pub struct Cli {
+
+    /// Image to be reoriented, in the versioned or legacy raw format
+    pub input: PathBuf,
+
+    /// Where to write the reoriented image: `.mha` (MetaImage) or `.nii` (NIfTI)
+    pub output: PathBuf,
+
+    /// MetaImage (`.mha`, `.mhd`) or NIfTI (`.nii`) image whose orientation
+    /// should be adopted
+    #[structopt(short, long, conflicts_with = "orientation")]
+    pub reference: Option<PathBuf>,
+
+    /// Orientation to adopt, as the signed world axes of the array axes, such
+    /// as `-y,x,z` [default: x,y,z]
+    #[structopt(long)]
+    pub orientation: Option<Orientation>,
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use petalo::fov::FOV;
+use petalo::io;
+use petalo::orientation::Frame;
+use geometry::units::mm_;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let Cli { input, output, reference, orientation } = args;
+    let image = io::raw::read_image(&input)?;
+    let reference = reference.map(|path| read_header(&path)).transpose()?;
+    let target = match reference {
+        Some((_, frame)) => frame.orientation,
+        None             => orientation.unwrap_or_default(),
+    };
+    let image = image.reorient(target);
+    if let Some((fov, _)) = reference { warn_about_spacing(&image.fov, &fov) }
+    match extension(&output) {
+        "mha"         => io::metaimage::write(&image, &output)?,
+        "nii"         => io::nifti    ::write(&image, &output)?,
+        other => return Err(format!("Cannot write images with extension '{other}': use .mha or .nii").into()),
+    }
+    println!("Wrote {} in orientation {target}", output.display());
+    Ok(())
+}
+
+fn extension(path: &Path) -> &str { path.extension().and_then(|e| e.to_str()).unwrap_or("") }
+
+fn read_header(path: &Path) -> Result<(FOV, Frame), Box<dyn Error>> {
+    match extension(path) {
+        "mha" | "mhd" => io::metaimage::read_header(path),
+        "nii"         => io::nifti    ::read_header(path),
+        other => Err(format!("Cannot read reference images with extension '{other}': use .mha, .mhd or .nii").into()),
+    }
+}
+
+/// Voxels are not resampled, so the images only overlay voxel by voxel if their
+/// spacings agree
+fn warn_about_spacing(image: &FOV, reference: &FOV) {
+    let spacing = |fov: &FOV| [0, 1, 2].map(|a| mm_(fov.voxel_size[a]));
+    let (mine, theirs) = (spacing(image), spacing(reference));
+    if mine.iter().zip(theirs).any(|(a, b)| (a - b).abs() > 1e-3 * b.abs()) {
+        eprintln!("Warning: voxel spacing {mine:?} mm differs from that of the reference, {theirs:?} mm");
+    }
+}
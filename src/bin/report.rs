@@ -0,0 +1,57 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "report", about = "HTML report comparing reconstructed images")]
+pub struct Cli {
+
+    /// ROI definitions (TOML) with which to calculate FOMs
+    #[structopt(short, long)]
+    pub rois: PathBuf,
+
+    /// Where to write the report
+    #[structopt(short, long, default_value = "report.html")]
+    pub out: PathBuf,
+
+    /// Title of the report
+    #[structopt(short, long, default_value = "Reconstruction comparison")]
+    pub title: String,
+
+    /// Displace each run's ROIs to follow its shift relative to this image
+    #[structopt(long)]
+    pub align_to: Option<PathBuf>,
+
+    /// Images to compare: file names or glob patterns, e.g. 'sweep/*/05-01.raw'
+    #[structopt(required = true)]
+    pub runs: Vec<String>,
+
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use std::path::PathBuf;
+use petalo::fom::FomConfig;
+use petalo::image::Image;
+use petalo::report::report_aligned;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let config = FomConfig::load(&args.rois)?;
+
+    let mut paths = vec![];
+    for pattern in &args.runs {
+        let matches = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+        // Non-matching patterns are kept, so that they appear in the report as missing runs
+        if matches.is_empty() { paths.push(PathBuf::from(pattern)) } else { paths.extend(matches) }
+    }
+    println!("Comparing {} runs", paths.len());
+
+    let reference = args.align_to.as_ref().map(Image::from_raw_file).transpose()?;
+    std::fs::write(&args.out, report_aligned(&args.title, &paths, &config, reference.as_ref()))?;
+    println!("Wrote {}", args.out.display());
+    Ok(())
+}
@@ -1,11 +1,15 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
-use petalo::utils::parse_range;
-use petalo::io::hdf5::{Hdf5Lor, read_table};
-use petalo::lorogram::{axis_z, axis_dz, axis_phi, axis_r, fill_scattergram, mk_lor};
-use ndhistogram::ndhistogram;
+use petalo::utils::{parse_range, resolve_file_and_dataset};
+use petalo::io::hdf5::{Hdf5Lor, expand_input_files, read_concatenated, with_meta, DEFAULT_LOR_DATASET};
+use petalo::lorogram::{axis_z, axis_dz, axis_phi, axis_r, axis_e, classify_energies, par_fill_scattergram_with, mk_lor,
+                       EnergyOf, Lorogram, Prompt, Scattergram};
+use petalo::constants::ELECTRON_REST_ENERGY;
+use petalo::system_matrix::LorWithMeta;
+use petalo::{Energyf32, Time};
+use ndhistogram::{ndhistogram, Histogram};
 use std::f32::consts::PI;
-use geometry::units::{mm, ratio_};
+use geometry::units::{mm, ps_, ratio_};
 
 
 #[derive(StructOpt, Debug, Clone)]
@@ -13,101 +17,172 @@ use geometry::units::{mm, ratio_};
 #[structopt(name = "show_logogram", about = "Interactive testing of logograms")]
 pub struct Cli {
 
-    #[structopt(short = "f", long)]
-    pub input_file: PathBuf,
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`. Repeat, or use
+    /// a glob pattern such as 'jobs/*.h5', to read several files as one table,
+    /// concatenated in order
+    #[structopt(short = "f", long, required = true, number_of_values = 1)]
+    pub input_file: Vec<PathBuf>,
 
-    /// The dataset location inside the input file
-    #[structopt(short, long, default_value = "reco_info/lors")]
-    pub dataset: String,
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
 
     /// Which rows of the input file should be loaded
     #[structopt(short, long, parse(try_from_str = parse_range::<usize>))]
     pub event_range: Option<std::ops::Range<usize>>,
 
+    /// Count coincidences whose time difference exceeds this as randoms
+    #[structopt(long)]
+    pub randoms_dt: Option<Time>,
+
+    /// Energy whose influence is shown: `min` (of the two gammas) or `sum`
+    #[structopt(long, default_value = "min")]
+    pub energy_of: EnergyOf,
+
+}
+
+/// Fill a scattergram with `lors`, counting as randoms those whose time
+/// difference exceeds `randoms_dt`
+fn fill_scattergram(
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram> + Sync),
+    lors: impl IntoIterator<Item = LorWithMeta>,
+    randoms_dt: Option<Time>,
+) -> Scattergram {
+    let lors: Vec<_> = lors.into_iter().collect();
+    par_fill_scattergram_with(make_empty_lorogram, &lors, |meta| classify(meta, randoms_dt))
+}
+
+/// Randoms by time difference, then trues or scatters by energy
+fn classify(&LorWithMeta { lor, e1, e2, .. }: &LorWithMeta, randoms_dt: Option<Time>) -> Prompt {
+    match randoms_dt {
+        Some(max) if ps_(lor.dt).abs() > ps_(max) => Prompt::Random,
+        _ => classify_energies(e1, e2, ELECTRON_REST_ENERGY),
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Print the trues, scatters and randoms in `nbins` bins of the energy `of`
+/// each coincidence in `lors`, between `low` and `high` keV
+fn energy_dependence(lors: &[Hdf5Lor], of: EnergyOf, (low, high): (Energyf32, Energyf32), nbins: usize, randoms_dt: Option<Time>) {
+    let make = || ndhistogram!(axis_e(nbins, low, high, of); usize);
+    let mut histograms = [make(), make(), make()];
+    for meta in with_meta(lors) {
+        let k = match classify(&meta, randoms_dt) { Prompt::True => 0, Prompt::Scatter => 1, Prompt::Random => 2 };
+        histograms[k].fill(&meta);
+    }
+    let step = (high - low) / nbins as f32;
+    println!("   E/keV     (s+r)/t + 1   trues   scatters  randoms");
+    for i in 0..nbins {
+        let e = low + (i as f32 + 0.5) * step;
+        // Index 0 is the underflow bin
+        let counts: Vec<usize> = histograms.iter().map(|h| *h.value_at_index(i + 1).unwrap_or(&0)).collect();
+        let (t, s, n_r) = (counts[0], counts[1], counts[2]);
+        // As `Scattergram::triplet`, without trues
+        let v = if t > 0 { (s + n_r + t) as f32 / t as f32 } else { 1.0 };
+        println!("{e:7.1}   {v:10.2}    {t:8}  {s:8}  {n_r:8}");
+    }
+}
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Cli::from_args();
+    petalo::error::record_config(&args);
 
-    let (nbins_z, nbins_dz, nbins_r, nbins_phi) = (10, 10, 10, 10);
+    let (nbins_z, nbins_dz, nbins_r, nbins_phi, nbins_e) = (10, 10, 10, 10, 10);
     let (l, dz_max, r_max) = (200.0, 1000.0, 120.0);
     let l0 = -l / 2.0;
     let step_z  =      l / nbins_z  as f32;
     let step_r  =  r_max / nbins_r  as f32;
     let step_dz = dz_max / nbins_dz as f32;
-    let infile  = args.input_file.into_os_string().into_string().unwrap();
+    let mut specs = args.input_file.iter().map(|spec| spec.to_string_lossy().into_owned());
+    let (first, dataset) = resolve_file_and_dataset(&specs.next().unwrap(), args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+    let files: Vec<String> = std::iter::once(first)
+        .chain(specs.map(|spec| resolve_file_and_dataset(&spec, Some(&dataset), DEFAULT_LOR_DATASET).0))
+        .collect();
+    let infiles = expand_input_files(&files)?;
 
     {
         println!("===== z dependence ======================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
-        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_z(nbins_z, mm(-l/2.0), mm(l/2.0)); usize)), lors);
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
+        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_z(nbins_z, mm(-l/2.0), mm(l/2.0)); usize)), with_meta(&lors), args.randoms_dt);
 
-        println!("     z       (s/t) + 1     trues   scatters");
+        println!("     z       (s+r)/t + 1   trues   scatters  randoms");
         for i in 0..nbins_z {
             let z = l0 + (i as f32 + 0.5) * step_z;
             let p = (0.0, 0.0, z as f32);
-            let (v, t, s) = sgram.triplet(&mk_lor((p, p)));
+            let (v, t, s, n_r) = sgram.triplet(&mk_lor((p, p)));
             let v = ratio_(v);
-            println!("{z:7.1}   {v:10.2}    {t:8}  {s:8}");
+            println!("{z:7.1}   {v:10.2}    {t:8}  {s:8}  {n_r:8}");
         }
     }
     {
         println!("===== phi dependence ====================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
-        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_phi(nbins_phi); usize)), lors);
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
+        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_phi(nbins_phi); usize)), with_meta(&lors), args.randoms_dt);
 
-        println!("   phi       (s/t) + 1     trues   scatters");
+        println!("   phi       (s+r)/t + 1   trues   scatters  randoms");
         for i in 0..nbins_phi {
             let phi = PI * ((i as f32 + 0.5) / nbins_phi as f32);
             let x = phi.cos();
             let y = phi.sin();
             let p1 = (x, 0.0, 0.0);
             let p2 = (0.0, y, 0.0);
-            let (v, t, s) = sgram.triplet(&mk_lor((p1, p2)));
+            let (v, t, s, n_r) = sgram.triplet(&mk_lor((p1, p2)));
             let v = ratio_(v);
             let phi_in_degrees = phi * 180.0 / PI;
-            println!("{phi_in_degrees:7.1}   {v:10.2}    {t:8}  {s:8}");
+            println!("{phi_in_degrees:7.1}   {v:10.2}    {t:8}  {s:8}  {n_r:8}");
         }
     }
     {
         println!("===== r dependence ====================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
-        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_r(nbins_r, mm(r_max)); usize)), lors);
-        println!("     r       (s/t) + 1     trues   scatters");
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
+        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_r(nbins_r, mm(r_max)); usize)), with_meta(&lors), args.randoms_dt);
+        println!("     r       (s+r)/t + 1   trues   scatters  randoms");
         for i in 0..nbins_r {
             let r = (i as f32 + 0.5) * step_r;
             let p1 = (r,  100.0, 0.0);
             let p2 = (r, -100.0, 0.0);
-            let (v, t, s) = sgram.triplet(&mk_lor((p1, p2)));
+            let (v, t, s, n_r) = sgram.triplet(&mk_lor((p1, p2)));
             let v = ratio_(v);
-            println!("{r:7.1}   {v:10.2}    {t:8}  {s:8}");
+            println!("{r:7.1}   {v:10.2}    {t:8}  {s:8}  {n_r:8}");
         }
     }
     {
         println!("===== obliqueness ====================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
-        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_dz(nbins_dz, mm(dz_max)); usize)), lors);
-        println!("     dz      (s/t) + 1     trues   scatters");
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
+        let sgram = fill_scattergram(&|| Box::new(ndhistogram!(axis_dz(nbins_dz, mm(dz_max)); usize)), with_meta(&lors), args.randoms_dt);
+        println!("     dz      (s+r)/t + 1   trues   scatters  randoms");
         for i in 0..nbins_dz {
             let dz = (i as f32 + 0.5) * step_dz;
             let p1 = (0.0, 0.0,  dz/2.0);
             let p2 = (0.0, 0.0, -dz/2.0);
-            let (v, t, s) = sgram.triplet(&mk_lor((p1, p2)));
+            let (v, t, s, n_r) = sgram.triplet(&mk_lor((p1, p2)));
             let v = ratio_(v);
-            println!("{dz:7.1}   {v:10.2}    {t:8}  {s:8}");
+            println!("{dz:7.1}   {v:10.2}    {t:8}  {s:8}  {n_r:8}");
         }
     }
+    {
+        println!("===== energy dependence =================================");
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
+        let bounds = match args.energy_of {
+            EnergyOf::Min => (300.0,  600.0),
+            EnergyOf::Sum => (600.0, 1200.0),
+        };
+        energy_dependence(&lors, args.energy_of, bounds, nbins_e, args.randoms_dt);
+    }
     {
         println!("===== z and dz ====================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
         let sgram = fill_scattergram(
             &|| Box::new(
                 ndhistogram!(axis_z (nbins_z , mm(-l/2.0), mm(l/2.0)),
                              axis_dz(nbins_dz, mm(dz_max));
                              usize)
             ),
-            lors
+            with_meta(&lors),
+            args.randoms_dt,
         );
         print!("      dz =");
         for j in 0..nbins_dz {
@@ -131,14 +206,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     {
         println!("===== z and r =====================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
         let sgram = fill_scattergram(
             &|| Box::new(
                 ndhistogram!(axis_z(nbins_z, mm(-l/2.0), mm(l/2.0)),
                              axis_r(nbins_r, mm(r_max));
                              usize)
             ),
-            lors
+            with_meta(&lors),
+            args.randoms_dt,
         );
         print!("       r =");
         for j in 0..nbins_r {
@@ -164,7 +240,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("======================================================================");
         println!("===== Using z-dz-r scattergram =======================================");
         println!("======================================================================");
-        let lors = read_table::<Hdf5Lor>(&infile, &args.dataset, args.event_range.clone())?;
+        let lors = read_concatenated::<Hdf5Lor>(&infiles, &dataset, args.event_range.clone())?;
         let sgram = fill_scattergram(
             &|| Box::new(
                 ndhistogram!(axis_z  (nbins_z  , mm(-l/2.0), mm(l/2.0)),
@@ -172,7 +248,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                              axis_r  (nbins_r  , mm(r_max));
                              usize)
             ),
-            lors
+            with_meta(&lors),
+            args.randoms_dt,
         );
         println!("----- r and z ---------------------------------------------------");
         for k in 0..nbins_dz {
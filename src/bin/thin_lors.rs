@@ -0,0 +1,57 @@
+// ----------------------------------- CLI -----------------------------------
+use structopt::StructOpt;
+
+use petalo::utils::{group_digits, resolve_file_and_dataset};
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "thin_lors", about = "Split LORs into statistically independent replicates (Poisson thinning)")]
+pub struct Cli {
+
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`
+    pub input_file: String,
+
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `file.h5:group/dataset`. Also used in
+    /// the output files
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
+
+    /// Number of replicates
+    #[structopt(short)]
+    pub k: usize,
+
+    /// Seed for assigning events to replicates
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+
+    /// Replicate i is written to `<out-prefix><i>.h5`
+    #[structopt(short, long)]
+    pub out_prefix: String,
+
+    /// Compression of the replicates: none, a gzip level such as 4, or zstd:<level>
+    #[structopt(long, default_value = "none")]
+    pub compress: Compression,
+}
+
+// --------------------------------------------------------------------------------
+use std::error::Error;
+use petalo::io::hdf5::{Compression, Hdf5Lor, read_table, write_lors, DEFAULT_LOR_DATASET};
+use petalo::thinning::thin_lors;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    if args.k == 0 { return Err("Need at least one replicate".into()) }
+    let (input_file, dataset) = resolve_file_and_dataset(&args.input_file, args.dataset.as_deref(), DEFAULT_LOR_DATASET);
+    let lors = read_table::<Hdf5Lor>(&input_file, &dataset, None)?.to_vec();
+    println!("Read {} LORs from {input_file}", group_digits(lors.len()));
+    for (i, replicate) in thin_lors(&lors, args.k, args.seed).iter().enumerate() {
+        let out_file = format!("{}{i}.h5", args.out_prefix);
+        write_lors(&out_file, &dataset, replicate, args.compress)?;
+        println!("Wrote {:>12} LORs to {out_file}", group_digits(replicate.len()));
+    }
+    Ok(())
+}
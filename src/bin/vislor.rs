@@ -2,44 +2,113 @@ use std::error::Error;
 use structopt::StructOpt;
 
 use petalo::Lengthf32;
-use petalo::{Time, Ratio};
-use petalo::{system_matrix::LOR, fov::FOV};
-use petalo::visualize::{lor_weights, Shape};
-
-use petalo::utils::{parse_triplet, parse_lor, parse_maybe_cutoff, parse_bounds, CutoffOption};
+use petalo::Time;
+use petalo::gauss::TofCutoff;
+use petalo::{system_matrix::LOR, fov::FovBuilder};
+use petalo::visualize::{browse_lors, coloured_lors, colour_scale, EventBrowser, Shape};
+use petalo::lorogram::ScattergramConfig;
+
+use petalo::utils::{parse_triplet, parse_lor, parse_maybe_cutoff, CutoffOption,
+                    resolve_file_and_dataset};
 use petalo::io;
+use petalo::io::hdf5::DEFAULT_LOR_DATASET;
+
+use geometry::units::{mm, ratio_};
 
-use geometry::units::mm;
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn run() -> Result<(), Box<dyn Error>> {
 
     let args = Cli::from_args();
+    petalo::error::record_config(&args);
 
     let (dx, dy, dz) = args.size;
-    let size = (mm(dx), mm(dy), mm(dz));
-    let nvox = args.nvoxels;
-
-    let fov = FOV::new(size, nvox);
-    println!("fov: {fov:?}");
+    let (nx, ny, nz) = args.nvoxels;
+    let fov = FovBuilder::full_widths(mm(dx), mm(dy), mm(dz)).voxels(nx, ny, nz).build()?;
+    println!("{fov}");
 
     // TODO: reading LOR from file overrides CLI lor: make them mutually
     // exclusive.
-    let lor = if let Some(input_file) = args.clone().input_file {
-        let event_range = args.event..args.event+1;
+    let file_args = args.clone().input_file.map(|input_file| {
         let                      Cli{ dataset, use_true, .. } = args.clone();
-        let io_args = io::hdf5::Args{ dataset, use_true, input_file,
-                                      ecut: parse_bounds("..").unwrap(), qcut: parse_bounds("..").unwrap(),
-                                      event_range: Some(event_range) };
-        petalo::io::hdf5::read_lors(io_args, None)?[0]
+        let (input_file, dataset) = resolve_file_and_dataset(&input_file, dataset.as_deref(), DEFAULT_LOR_DATASET);
+        io::hdf5::Args{ dataset, use_true, input_files: vec![input_file], ..Default::default() }
+    });
+    if args.browse {
+        let file_args = file_args.ok_or("--browse requires --input-file")?;
+        return browse(file_args, &args, fov)
+    }
+
+    // Each LOR with its row in the file, as rows may have been cut
+    let (rows, lors): (Vec<_>, Vec<_>) = if let Some(file_args) = file_args.clone() {
+        let event_range = args.event..args.event + args.n_lors.max(1);
+        io::hdf5::read_lors_with_rows(io::hdf5::Args { event_range: Some(event_range), ..file_args })?
+            .into_iter()
+            .map(|(row, meta)| (row, meta.lor))
+            .unzip()
     } else {
-        args.lor
+        (vec![args.event], vec![args.lor])
+    };
+    if lors.is_empty() { return Err("No LORs to display".into()) }
+
+    let colours = match &args.scattergram {
+        None => vec![[1.0, 1.0, 0.0]; lors.len()],
+        Some(source) => {
+            let file_args = file_args.ok_or("--scattergram requires --input-file, from which to fill it")?;
+            let scattergram = scattergram_config(source)?.build()?.ok_or("Scattergram has no axes")?;
+            let scattergram = io::hdf5::read_scattergram(file_args, scattergram)?;
+            let corrections = lors.iter().map(|lor| scattergram.describe(lor)).collect::<Vec<_>>();
+            for (row, correction) in rows.iter().zip(&corrections) {
+                println!("LOR {row:6}: {correction}");
+            }
+            let fractions = corrections.iter().map(|c| ratio_(c.fraction)).collect::<Vec<_>>();
+            let lo = fractions.iter().copied().fold(f32::INFINITY, f32::min);
+            let hi = fractions.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            println!("Colour scale: blue = {lo:.4} ... red = {hi:.4}");
+            fractions.into_iter().map(|f| colour_scale(f, lo, hi)).collect()
+        }
     };
 
-    println!("{}", lor);
-    lor_weights(lor, fov, args.shape, args.cutoff, args.tof);
+    println!("{}", lors[0]);
+    let coloured = lors.into_iter().zip(colours).collect::<Vec<_>>();
+    coloured_lors(&coloured, fov, args.shape, args.cutoff, args.tof);
     Ok(())
 }
 
+/// Step through the events in the input file one at a time, holding
+/// `--browse-window` of them in memory
+fn browse(file_args: io::hdf5::Args, args: &Cli, fov: petalo::fov::FOV) -> Result<(), Box<dyn Error>> {
+    let n_events = io::hdf5::concatenated_len(&file_args.files()?, &file_args.dataset)?;
+    println!("{n_events} events in {}. Press `n` / `p` for the next / previous one", file_args.describe_input());
+    let load = move |range: std::ops::Range<usize>| -> Result<Vec<LOR>, Box<dyn Error>> {
+        let n = range.len();
+        let rows = io::hdf5::sample_rows(&io::hdf5::Args { event_range: Some(range), ..file_args.clone() }, n)?;
+        Ok(rows.iter().map(LOR::from).collect())
+    };
+    let mut browser = EventBrowser::new(load, n_events, args.browse_window, args.event)?;
+    let first = browser.current();
+    println!("Event {}: {first}", browser.index());
+    browse_lors(first, fov, args.shape.clone(), args.cutoff, args.tof, |step| {
+        match browser.step(step) {
+            Ok(Some(lor)) => { println!("Event {}: {lor}", browser.index()); Some(lor) },
+            Ok(None)      => { println!("No more events in this direction"); None },
+            Err(e)        => { eprintln!("Failed to load events: {e}"); None },
+        }
+    });
+    Ok(())
+}
+
+/// The contents of `source`, if it is a file, otherwise `source` itself, as a
+/// scattergram specification
+fn scattergram_config(source: &str) -> Result<ScattergramConfig, String> {
+    let spec = if std::path::Path::new(source).is_file() {
+        std::fs::read_to_string(source).map_err(|e| format!("{source}: {e}"))?
+    } else {
+        source.to_string()
+    };
+    spec.trim().parse()
+}
+
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
@@ -50,22 +119,23 @@ pub struct Cli {
     #[structopt(short, long)]
     tof: Option<Time>,
 
-    /// TOF cutoff (✕ sigma). to disable: `-k no`
-    #[structopt(short = "k", default_value = "3", long, parse(try_from_str = parse_maybe_cutoff))]
-    cutoff: CutoffOption<Ratio>,
+    /// TOF cutoff (✕ sigma): `3`, or `before,after` the peak. to disable: `-k no`
+    #[structopt(short = "k", long = "tof-cutoff", alias = "cutoff", default_value = "3", parse(try_from_str = parse_maybe_cutoff))]
+    cutoff: CutoffOption<TofCutoff>,
 
     /// How to represent voxels. BOX is better for viewing the geometric
     /// weights; BALL is better for viewing TOF weights.
     #[structopt(possible_values = &Shape::variants(), case_insensitive = true, default_value = "box")]
     shape: Shape,
 
-    /// LORs to read in
+    /// LORs to read in: `file.h5` or `file.h5:group/dataset`
     #[structopt(short = "f", long)]
     pub input_file: Option<String>,
 
-    /// The dataset location inside the input file
-    #[structopt(short, long, default_value = "reco_info/lors")]
-    pub dataset: String,
+    /// The dataset location inside the input file [default: reco_info/lors].
+    /// Overrides any dataset given in `-f file.h5:group/dataset`
+    #[structopt(short, long)]
+    pub dataset: Option<String>,
 
     /// Event number (in <file>) to be displayed
     #[structopt(short, long, default_value = "0")]
@@ -79,12 +149,33 @@ pub struct Cli {
     #[structopt(short, long, parse(try_from_str = parse_triplet::<usize>), default_value = "151,151,151")]
     nvoxels: (usize, usize, usize),
 
-    /// LOR to visualize: 't1 t2   x1 y1 z1   x2 y2 z2' (t: ps, xyz: mm)
-    #[structopt(short, long, parse(try_from_str = parse_lor), default_value = "0 300  -100 20 -90  100 60 10")]
+    /// LOR to visualize: 't1 t2   x1 y1 z1   x2 y2 z2' or 'dt=<dt>   x1 y1 z1
+    /// x2 y2 z2', each value optionally with a unit (t: ps or ns [default],
+    /// xyz: mm [default] or cm), e.g. 'dt=200ps  -10cm 2cm -9cm  10cm 6cm 1cm'
+    #[structopt(short, long, parse(try_from_str = parse_lor), default_value = "dt=300ps  -100 20 -90  100 60 10")]
     lor: LOR,
 
     /// Use true rather than reco LOR data from file
     #[structopt(long)]
     use_true: bool,
 
+    /// Number of consecutive LORs (starting at <event>) to display
+    #[structopt(long, default_value = "1")]
+    n_lors: usize,
+
+    /// Fill a scattergram from <file>, and report, and colour LORs by, the
+    /// correction each would receive. Either an axis specification such as
+    /// `r:20:30,phi:15,z:10:-100:100`, or a file containing one
+    #[structopt(long)]
+    scattergram: Option<String>,
+
+    /// Step through the events in <file>, starting at <event>, with the `n`
+    /// and `p` keys
+    #[structopt(long, conflicts_with_all = &["n-lors", "scattergram"])]
+    browse: bool,
+
+    /// Number of consecutive events to hold in memory while browsing
+    #[structopt(long, default_value = "100")]
+    browse_window: usize,
+
 }
@@ -0,0 +1,79 @@
+// ----------------------------------- CLI -----------------------------------
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+#[structopt(name = "volrender", about = "Volume-render a reconstructed image")]
+pub struct Cli {
+
+    /// Image file to render
+    pub input_file: PathBuf,
+
+    /// Write rendering to this PNG file, rather than displaying it in a window
+    #[structopt(short, long)]
+    pub out: Option<PathBuf>,
+
+    /// Camera angle around the z-axis, measured from the x-axis
+    #[structopt(short, long, default_value = "0.5 rad")]
+    pub azimuth: Angle,
+
+    /// Camera angle above the x-y plane
+    #[structopt(short, long, default_value = "0.35 rad")]
+    pub elevation: Angle,
+
+    /// Width and height of the rendering in pixels
+    #[structopt(short, long, parse(try_from_str = parse_pair::<u32>), default_value = "512,512")]
+    pub pixels: (u32, u32),
+
+    /// Transfer function control points 'value:r,g,b,a ...', with values
+    /// relative to the image maximum
+    #[structopt(short, long, default_value = "0:0,0,0,0  1:1,1,1,0.2")]
+    pub transfer: TransferFunction,
+
+}
+
+use structopt::StructOpt;
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use petalo::{Angle, Point};
+use petalo::image::Image;
+use petalo::visualize::{volume_render, display_rendering, write_png, CameraParams, TransferFunction};
+use geometry::units::mm;
+
+fn main() -> std::process::ExitCode { petalo::error::main(run) }
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = Cli::from_args();
+    petalo::error::record_config(&args);
+    let image = Image::from_raw_file(&args.input_file)?;
+
+    // Make the view wide enough to contain the whole FOV from any direction
+    let w = image.fov.half_width;
+    let width = 2.0 * (w.x * w.x + w.y * w.y + w.z * w.z).sqrt();
+
+    let camera = CameraParams {
+        look_at: Point::new(mm(0.0), mm(0.0), mm(0.0)),
+        azimuth: args.azimuth,
+        elevation: args.elevation,
+        width,
+    };
+    let rendering = volume_render(&image, camera, &args.transfer, args.pixels);
+
+    if let Some(out) = args.out {
+        write_png(&rendering, &out)?;
+        println!("Wrote rendering to {:?}", out);
+    } else {
+        display_rendering(&rendering)?;
+    }
+    Ok(())
+}
+
+fn parse_pair<T: std::str::FromStr>(s: &str) -> Result<(T, T), String>
+where
+    <T as std::str::FromStr>::Err: std::fmt::Display,
+{
+    let v = s.split(',').collect::<Vec<_>>();
+    if v.len() != 2 { return Err(format!("Expected two comma-separated values, got '{s}'")) }
+    let parse = |x: &str| x.trim().parse().map_err(|e| format!("'{x}' in '{s}': {e}"));
+    Ok((parse(v[0])?, parse(v[1])?))
+}
@@ -0,0 +1,79 @@
+//! Cooperative cancellation of long-running reconstructions.
+//!
+//! A `Cancel` wraps a flag which may be set from anywhere (a signal handler, a
+//! service's request handler, a sink). `Image::mlem_with` checks it
+//! before each (sub)iteration, and stops producing images once it is set. What
+//! happens to the (sub)iteration in progress depends on `OnCancel`: it is
+//! either completed, and its image produced, or abandoned between chunks of
+//! `CANCEL_CHECK_CHUNK` LORs, in which case the last image produced is the
+//! most recent complete one.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+/// Number of LORs projected between checks of the flag, when abandoning
+pub const CANCEL_CHECK_CHUNK: usize = 10_000;
+
+/// What to do with the (sub)iteration in progress when cancellation is requested
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnCancel {
+    /// Complete it, and produce its image
+    Finish,
+    /// Stop projecting LORs, and discard it
+    Abandon,
+}
+
+impl Default for OnCancel {
+    fn default() -> Self { Self::Finish }
+}
+
+impl std::str::FromStr for OnCancel {
+    type Err = String;
+    /// `finish` or `abandon`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "finish"  => Ok(Self::Finish),
+            "abandon" => Ok(Self::Abandon),
+            _ => Err(format!("Expected finish or abandon, got '{s}'")),
+        }
+    }
+}
+
+/// Shared flag requesting cancellation, and what to do when it is set
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+    flag: Arc<AtomicBool>,
+    on_cancel: OnCancel,
+}
+
+impl Cancel {
+    /// Cancellation requested by setting `flag`, which the caller may share
+    /// with whatever decides to cancel
+    pub fn new(flag: Arc<AtomicBool>) -> Self { Self { flag, on_cancel: OnCancel::Finish } }
+
+    pub fn on_cancel(self, on_cancel: OnCancel) -> Self { Self { on_cancel, ..self } }
+
+    /// The flag which requests cancellation when set
+    pub fn flag(&self) -> Arc<AtomicBool> { self.flag.clone() }
+
+    pub fn request(&self) { self.flag.store(true, Ordering::SeqCst) }
+
+    pub fn is_requested(&self) -> bool { self.flag.load(Ordering::Relaxed) }
+
+    /// Whether the (sub)iteration in progress should stop projecting LORs
+    pub(crate) fn abandon_now(&self) -> bool { self.on_cancel == OnCancel::Abandon && self.is_requested() }
+}
+
+/// How a reconstruction ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    /// All requested images were produced
+    Completed,
+    /// Cancellation was requested before the last image was produced
+    Cancelled,
+    /// The images stopped changing before the last one was produced
+    Converged,
+}
@@ -0,0 +1,122 @@
+//! Checkpoints from which long reconstructions can be resumed.
+//!
+//! A checkpoint is an HDF5 file holding the image after some number of
+//! complete iterations:
+//!
+//! + `image`: the voxel values, indexed by (z, y, x)
+//! + `full_width_mm`, `n_voxels`: the FOV of the image, (x, y, z)
+//! + `iterations`: the number of iterations which produced it
+//!
+//! It is written under a temporary name and renamed into place, so a run which
+//! dies while writing it leaves the previous checkpoint intact.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::divergence::IterationStats;
+use crate::error::Context;
+use crate::fov::{FovRecord, FOV, FOV_SCHEMA_VERSION};
+use crate::image::Image;
+use crate::sink::{IterationSink, Manifest};
+use geometry::units::mm_;
+
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub image: Image,
+    /// Number of complete iterations which produced `image`
+    pub iterations: usize,
+}
+
+impl Checkpoint {
+    /// Write `image`, produced by `iterations` complete iterations, to `path`
+    pub fn write(path: &Path, image: &Image, iterations: usize) -> Result<(), Box<dyn Error>> {
+        let tmp = Manifest::temp_path(path);
+        {
+            let file = hdf5::File::create(&tmp)?;
+            let h = image.fov.half_width;
+            let full_width = [h.x, h.y, h.z].map(|h| mm_(h) * 2.0);
+            let [nx, ny, nz] = image.fov.n;
+            file.new_dataset_builder().with_data(&full_width).create("full_width_mm")?;
+            file.new_dataset_builder().with_data(&[nx, ny, nz].map(|n| n as u64)).create("n_voxels")?;
+            file.new_dataset_builder().with_data(&[iterations as u64]).create("iterations")?;
+            let data = ndarray::Array3::from_shape_vec((nz, ny, nx), image.data.clone())?;
+            file.new_dataset_builder().with_data(&data).create("image")?;
+        }
+        std::fs::rename(&tmp, path).context(|| format!("writing checkpoint '{}'", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::read_unchecked(path).context(|| format!("reading checkpoint '{}'", path.display()))
+    }
+
+    fn read_unchecked(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = hdf5::File::open(path)?;
+        let full_width = file.dataset("full_width_mm")?.read_raw::<f32>()?;
+        let n_voxels   = file.dataset("n_voxels"     )?.read_raw::<u64>()?;
+        let iterations = file.dataset("iterations"   )?.read_raw::<u64>()?;
+        let data       = file.dataset("image"        )?.read_raw::<f32>()?;
+        let (dx, dy, dz, nx, ny, nz, iterations) = match (&full_width[..], &n_voxels[..], &iterations[..]) {
+            (&[dx, dy, dz], &[nx, ny, nz], &[iterations]) => (dx, dy, dz, nx, ny, nz, iterations),
+            _ => return Err("Malformed FOV or iteration count".into()),
+        };
+        let [nx, ny, nz] = [nx, ny, nz].map(|n| n as usize);
+        if data.len() != nx * ny * nz {
+            return Err(format!("Image has {} voxels, but the FOV has {nx} x {ny} x {nz}", data.len()).into())
+        }
+        let fov = FOV::try_from(FovRecord { version: FOV_SCHEMA_VERSION, full_width_mm: [dx, dy, dz].map(f64::from), n_voxels: [nx, ny, nz] })?;
+        Ok(Self { image: Image::new(fov, data), iterations: iterations as usize })
+    }
+}
+
+/// Writes a checkpoint after the last subset of every iteration, replacing
+/// the previous one
+pub struct CheckpointSink {
+    pub path: PathBuf,
+    pub subsets: usize,
+}
+
+impl IterationSink for CheckpointSink {
+    fn on_iteration(&mut self, _: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        if stats.subset != self.subsets { return Ok(()) }
+        Checkpoint::write(&self.path, image, stats.iteration)
+    }
+}
+
+#[cfg(test)]
+mod test_checkpoint {
+    use super::*;
+    use geometry::units::mm;
+
+    #[test]
+    fn checkpoints_are_read_back() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("checkpoint.h5");
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(20.0), mm(10.0)), (3, 2, 1));
+        let image = Image::new(fov, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        Checkpoint::write(&path, &image, 7)?;
+        assert!(!Manifest::temp_path(&path).exists());
+
+        let Checkpoint { image: back, iterations } = Checkpoint::read(&path)?;
+        assert_eq!(iterations, 7);
+        assert_eq!(back.data, image.data);
+        assert_eq!(back.fov.n, fov.n);
+        assert_eq!(back.fov.half_width, fov.half_width);
+        Ok(())
+    }
+
+    #[test]
+    fn only_complete_iterations_are_checkpointed() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("checkpoint.h5");
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (1, 1, 1));
+        let mut sink = CheckpointSink { path: path.clone(), subsets: 2 };
+        let stats = |iteration, subset| IterationStats { subset, ..IterationStats::of(&Image::ones(fov), iteration, 1, None) };
+        sink.on_iteration(1, &Image::new(fov, vec![1.0]), &stats(1, 1))?;
+        assert!(!path.exists());
+        sink.on_iteration(2, &Image::new(fov, vec![2.0]), &stats(1, 2))?;
+        sink.on_iteration(3, &Image::new(fov, vec![3.0]), &stats(2, 1))?;
+        let checkpoint = Checkpoint::read(&path)?;
+        assert_eq!((checkpoint.iterations, checkpoint.image.data), (1, vec![2.0]));
+        Ok(())
+    }
+}
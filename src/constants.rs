@@ -0,0 +1,40 @@
+//! Physical constants, in the units used throughout the crate: mm, ps and keV.
+
+use crate::{Energyf32, Time, Velocity};
+use geometry::in_base_unit;
+
+/// Speed of light in vacuum. Stored in the base units of `Velocity`: mm/ps.
+pub const C: Velocity = in_base_unit!(0.299_792_458);
+
+/// Electron rest energy (keV): the energy of each photon of a positron
+/// annihilation at rest. Also the default threshold below which scattergrams
+/// count a gamma as scattered.
+pub const ELECTRON_REST_ENERGY: Energyf32 = 510.998_950;
+
+/// Half-life of fluorine-18: 109.771 minutes. Stored in the base units of
+/// `Time`: ps.
+pub const F18_HALF_LIFE: Time = in_base_unit!(6.586_26e15);
+
+#[cfg(test)]
+mod test_constants {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::{m_s, mm_, ns, ns_};
+
+    #[test]
+    fn c_in_consistent_units() {
+        assert_float_eq!(mm_(C * ns(1.0)), 299.792_458, rmax <= 1e-6);
+        assert_float_eq!(C.value, m_s(299_792_458.0).value, rmax <= 1e-6);
+    }
+
+    #[test]
+    fn f18_half_life_in_minutes() {
+        assert_float_eq!(ns_(F18_HALF_LIFE) / 60e9, 109.771, rmax <= 1e-6);
+    }
+
+    #[test]
+    fn electron_rest_energy_matches_codata() {
+        // m_e c^2 = 0.510 998 950 MeV
+        assert_float_eq!(ELECTRON_REST_ENERGY / 1000.0, 0.510_998_95, rmax <= 1e-7);
+    }
+}
@@ -0,0 +1,191 @@
+//! Estimate the cost of a reconstruction by projecting a sample of its LORs.
+
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Serializer};
+
+use crate::{Intensityf32, Time};
+use crate::gauss::TofCutoff;
+use crate::fov::FOV;
+use crate::gauss::make_gauss_option;
+use crate::image::Image;
+use crate::mlem::system_matrix_row;
+use crate::system_matrix::{LOR, Tube};
+
+/// Measurements made on a sample of LORs
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ProjectionSample {
+    /// Number of LORs in the sample
+    pub n_lors: usize,
+    /// Number of LORs which hit the FOV
+    pub n_hits: usize,
+    /// Total number of (LOR, voxel) system matrix elements
+    pub voxel_visits: usize,
+    /// Wall-clock time of one MLEM iteration over the sample
+    #[serde(rename = "elapsed_seconds", serialize_with = "seconds")]
+    pub elapsed: Duration,
+}
+
+impl ProjectionSample {
+    pub fn voxels_per_lor(&self) -> f64 { self.voxel_visits as f64 / self.n_lors.max(1) as f64 }
+}
+
+/// Number of LORs hitting the FOV, and total number of voxels they cross
+pub fn count_voxel_visits(lors: &[LOR], fov: FOV, sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>) -> (usize, usize) {
+    let tof = make_gauss_option(sigma, cutoff);
+    let (mut indices, mut weights) = (vec![], vec![]);
+    let (mut hits, mut visits) = (0, 0);
+    for lor in lors {
+        if system_matrix_row(lor, fov, &tof, tube, &mut indices, &mut weights) {
+            hits += 1;
+            visits += indices.len();
+        }
+    }
+    (hits, visits)
+}
+
+/// Count voxel visits in `lors`, and time one MLEM iteration over them, using
+/// the current rayon thread pool
+pub fn sample_projection(lors: &[LOR], fov: FOV, sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>) -> ProjectionSample {
+    let (n_hits, voxel_visits) = count_voxel_visits(lors, fov, sigma, cutoff, tube);
+    let start = Instant::now();
+    let _ = Image::mlem(fov, lors, sigma, cutoff, tube, None, 1).next();
+    ProjectionSample { n_lors: lors.len(), n_hits, voxel_visits, elapsed: start.elapsed() }
+}
+
+/// Extrapolated cost of reconstructing a whole dataset
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct CostEstimate {
+    pub n_events: usize,
+    pub voxels_per_lor: f64,
+    #[serde(rename = "iteration_seconds", serialize_with = "seconds")]
+    pub iteration_time: Duration,
+    pub memory_bytes: usize,
+}
+
+/// Memory needed to reconstruct a dataset, in bytes, by what it holds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryEstimate {
+    /// The LORs, after cuts
+    pub lors: usize,
+    /// The chunks of rows being read (two when prefetching: see
+    /// `io::prefetch`), both as stored in the file (whose columns may be `f32`
+    /// or `f64`) and as converted for use
+    pub read_buffer: usize,
+    /// The current image, its copy handed out by the MLEM iterator, and the
+    /// summed backprojection
+    pub images: usize,
+    /// The sensitivity image
+    pub sensitivity: usize,
+    /// One backprojection buffer per thread
+    pub thread_buffers: usize,
+}
+
+impl MemoryEstimate {
+    /// Memory needed to iterate over `n_events` LORs in `fov`, with
+    /// `n_threads` threads, not counting the reading of the LORs
+    pub fn new(n_events: usize, fov: FOV, n_threads: usize) -> Self {
+        let [nx, ny, nz] = fov.n;
+        let image_bytes = nx * ny * nz * std::mem::size_of::<Intensityf32>();
+        Self {
+            lors: n_events * std::mem::size_of::<LOR>(),
+            read_buffer: 0,
+            images: 3 * image_bytes,
+            sensitivity: image_bytes,
+            thread_buffers: n_threads * image_bytes,
+        }
+    }
+
+    /// Also count a buffer of `n_rows` rows being read, of `row_bytes` each
+    pub fn reading(self, n_rows: usize, row_bytes: usize) -> Self {
+        Self { read_buffer: n_rows * row_bytes, ..self }
+    }
+
+    pub fn total(&self) -> usize { self.lors + self.read_buffer + self.images + self.sensitivity + self.thread_buffers }
+}
+
+/// `duration` in (fractional) seconds, as in the JSON summaries
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Scale the measurements made on `sample` up to `n_events` LORs.
+///
+/// Time is assumed to be proportional to the number of LORs. Memory is that of
+/// `MemoryEstimate::new`.
+pub fn extrapolate(sample: &ProjectionSample, n_events: usize, fov: FOV, n_threads: usize) -> CostEstimate {
+    let scale = n_events as f64 / sample.n_lors.max(1) as f64;
+    CostEstimate {
+        n_events,
+        voxels_per_lor: sample.voxels_per_lor(),
+        iteration_time: sample.elapsed.mul_f64(scale),
+        memory_bytes: MemoryEstimate::new(n_events, fov, n_threads).total(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Point;
+    use geometry::units::{mm, ns};
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (10, 10, 10)) }
+
+    fn lor(p1: (f32, f32, f32), p2: (f32, f32, f32)) -> LOR {
+        let p = |(x, y, z)| Point::new(mm(x), mm(y), mm(z));
+        LOR::new(ns(0.0), ns(0.0), p(p1), p(p2))
+    }
+
+    #[test]
+    fn voxel_visits_match_direct_count() {
+        let lors = [
+            lor((-50.0,   0.5,   0.5), (50.0,  0.5,  0.5)), // along x: 10 voxels
+            lor((  1.5, -50.0,  -2.5), ( 1.5, 50.0, -2.5)), // along y: 10 voxels
+            lor((  3.5,   3.5, -50.0), ( 3.5,  3.5, 50.0)), // along z: 10 voxels
+            lor((-50.0,   0.5, -50.5), (50.0,  0.5, 49.5)), // z = x - 0.5: counted below
+            lor((-50.0,  20.0,   0.0), (50.0, 20.0,  0.0)), // misses the FOV
+        ];
+        // Voxels crossed by the diagonal, found by walking along it in small steps
+        let mut diagonal = std::collections::HashSet::new();
+        for step in 0..=100_000 {
+            let x = -5.0 + 10.0 * step as f32 / 100_000.0;
+            let z = x - 0.5;
+            if (-5.0..5.0).contains(&x) && (-5.0..5.0).contains(&z) {
+                diagonal.insert(((x + 5.0).floor() as i32, (z + 5.0).floor() as i32));
+            }
+        }
+        let (hits, visits) = count_voxel_visits(&lors, fov(), None, None, None);
+        assert_eq!(hits, 4);
+        assert_eq!(visits, 30 + diagonal.len());
+    }
+
+    #[test]
+    fn extrapolation_scales_with_events() {
+        let sample = ProjectionSample { n_lors: 1000, n_hits: 900, voxel_visits: 50_000, elapsed: Duration::from_secs(2) };
+        let estimate = extrapolate(&sample, 1_000_000, fov(), 4);
+        assert_eq!(estimate.voxels_per_lor, 50.0);
+        assert_eq!(estimate.iteration_time, Duration::from_secs(2000));
+        let lors = 1_000_000 * std::mem::size_of::<LOR>();
+        let images = (4 + 4) * 1000 * 4;
+        assert_eq!(estimate.memory_bytes, lors + images);
+    }
+
+    #[test]
+    fn memory_grows_with_threads_and_read_buffer() {
+        let image = 1000 * 4;
+        let base = MemoryEstimate::new(100, fov(), 1);
+        assert_eq!(base.total(), 100 * std::mem::size_of::<LOR>() + 5 * image);
+        assert_eq!(MemoryEstimate::new(100, fov(), 8).total() - base.total(), 7 * image);
+        // Rows stored as f64 take twice the buffer of rows stored as f32
+        let (f32_rows, f64_rows) = (base.reading(10, 11 * 4), base.reading(10, 11 * 8));
+        assert_eq!(f64_rows.total() - f32_rows.total(), 10 * 11 * 4);
+    }
+
+    #[test]
+    fn durations_are_serialized_in_seconds() {
+        let sample = ProjectionSample { n_lors: 10, n_hits: 5, voxel_visits: 50, elapsed: Duration::from_millis(1500) };
+        let json = serde_json::to_value(extrapolate(&sample, 100, fov(), 1)).unwrap();
+        assert_eq!(json["iteration_seconds"], 15.0);
+        assert_eq!(serde_json::to_value(sample).unwrap()["elapsed_seconds"], 1.5);
+    }
+}
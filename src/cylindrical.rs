@@ -0,0 +1,196 @@
+//! Images accumulated in cylindrical coordinates, for rotationally symmetric
+//! problems.
+//!
+//! When the inputs are symmetric under rotations about the z-axis (uniform
+//! cylinder normalization scans, analytic sensitivity of a cylindrical scanner
+//! with symmetric attenuation), so is the result. Accumulating it in (r, z)
+//! bins rather than in voxels, and broadcasting each bin back to the voxels
+//! whose centres it contains, averages away the noise of the voxels in each
+//! bin, and shrinks the buffers of parallel backprojections.
+//!
+//! Each (r, z) bin is split into `SECTORS` azimuthal sectors, only to check the
+//! assumption: see `asymmetry`. For asymmetric inputs, the broadcast image is
+//! the azimuthal average of the true result, not an estimate of it.
+
+use std::f32::consts::TAU;
+
+use crate::{Length, Lengthf32, Point};
+use crate::fov::FOV;
+use crate::image::Image;
+use geometry::units::{mm, mm_};
+
+/// Number of azimuthal sectors into which each (r, z) bin is split
+pub const SECTORS: usize = 8;
+
+/// `asymmetry` above which the assumption of rotational symmetry should be
+/// considered violated
+pub const ASYMMETRY_WARNING: f32 = 0.1;
+
+/// Sums of the values of the voxels whose centres lie in each of `nr` radial
+/// bins of width `dr` around the z-axis, by `nz` axial bins of width `dz`
+/// centred on `z = 0`
+#[derive(Clone, Debug)]
+pub struct CylindricalImage {
+    pub nr: usize,
+    pub nz: usize,
+    pub dr: Length,
+    pub dz: Length,
+    /// Sector varying fastest, then r, then z
+    sums: Vec<f32>,
+}
+
+impl CylindricalImage {
+
+    pub fn new(nr: usize, nz: usize, dr: Length, dz: Length) -> Result<Self, String> {
+        if nr == 0 || nz == 0 {
+            return Err(format!("Cylindrical image needs at least one bin in r and z, got {nr} and {nz}"))
+        }
+        if !(mm_(dr) > 0.0 && mm_(dz) > 0.0) {
+            return Err(format!("Cylindrical image bin widths must be positive, got {} mm and {} mm", mm_(dr), mm_(dz)))
+        }
+        Ok(Self { nr, nz, dr, dz, sums: vec![0.0; SECTORS * nr * nz] })
+    }
+
+    /// Empty image with one axial bin per voxel slice of `fov`, and radial bins
+    /// as wide as its smaller transverse voxel size, reaching all its voxels
+    pub fn covering(fov: FOV) -> Self {
+        let (s, h) = (fov.voxel_size, fov.half_width);
+        let dr = mm_(s.x).min(mm_(s.y));
+        let r_max = (mm_(h.x) - mm_(s.x) / 2.0).hypot(mm_(h.y) - mm_(s.y) / 2.0);
+        let nr = (r_max / dr).floor() as usize + 1;
+        Self::new(nr, fov.n[2], mm(dr), s.z).unwrap()
+    }
+
+    /// Sum of the voxels of `image` in each bin
+    pub fn from_image(image: &Image) -> Self {
+        let mut cylindrical = Self::covering(image.fov);
+        let bins = cylindrical.voxel_bins(image.fov);
+        for (bin, value) in bins.into_iter().zip(&image.data) {
+            if let Some(bin) = bin { cylindrical.sums[bin] += value }
+        }
+        cylindrical
+    }
+
+    /// Index of the bin and sector containing `p`, if any
+    fn bin(&self, p: Point) -> Option<usize> {
+        let (x, y) = (mm_(p.x), mm_(p.y));
+        let r = x.hypot(y) / mm_(self.dr);
+        let z = mm_(p.z) / mm_(self.dz) + self.nz as f32 / 2.0;
+        if r >= self.nr as f32 || !(0.0..self.nz as f32).contains(&z) { return None }
+        let turns = (y.atan2(x) / TAU).rem_euclid(1.0);
+        let sector = ((turns * SECTORS as f32) as usize).min(SECTORS - 1);
+        Some(sector + SECTORS * (r as usize + self.nr * z as usize))
+    }
+
+    /// The bin and sector of each voxel of `fov`, by the position of its centre
+    pub fn voxel_bins(&self, fov: FOV) -> Vec<Option<usize>> {
+        (0..fov.n.iter().product()).map(|i| self.bin(fov.voxel_centre1(i))).collect()
+    }
+
+    /// Add `projection` times the `weights` of the voxels at `indices` to their
+    /// `bins` (see `voxel_bins`)
+    pub fn back_project(&mut self, bins: &[Option<usize>], weights: &[Lengthf32], indices: &[usize], projection: Lengthf32) {
+        for (&i, &w) in indices.iter().zip(weights) {
+            if let Some(bin) = bins[i] { self.sums[bin] += w * projection }
+        }
+    }
+
+    /// Sum of two images with the same bins, such as those accumulated by
+    /// different threads
+    pub fn merged(mut self, other: Self) -> Self {
+        assert_eq!((self.nr, self.nz), (other.nr, other.nz), "Cylindrical images have different bins");
+        for (a, b) in self.sums.iter_mut().zip(other.sums) { *a += b }
+        self
+    }
+
+    pub fn scale(&mut self, factor: f32) {
+        for s in &mut self.sums { *s *= factor }
+    }
+
+    /// Image of `fov` in which each voxel has the mean value of the voxels of
+    /// `fov` in its (r, z) bin. Voxels outside all bins are 0.
+    pub fn broadcast(&self, fov: FOV) -> Image {
+        let bins = self.voxel_bins(fov);
+        let rz = |bin: usize| bin / SECTORS;
+        let mut totals = vec![(0.0, 0); self.nr * self.nz];
+        for &bin in bins.iter().flatten() { totals[rz(bin)].1 += 1 }
+        for (bin, sum) in self.sums.iter().enumerate() { totals[rz(bin)].0 += sum }
+        let data = bins.into_iter()
+            .map(|bin| bin.map_or(0.0, |bin| { let (sum, n) = totals[rz(bin)]; sum / n as f32 }))
+            .collect();
+        Image::new(fov, data)
+    }
+
+    /// Largest relative deviation, over the azimuthal sectors, of the sum
+    /// accumulated in the sector from that expected if the value in each (r, z)
+    /// bin were independent of azimuth, given the voxels of `fov` in each
+    /// sector of each bin. Near 0 for symmetric inputs, up to noise and the
+    /// variation of the value across the width of the bins; of order 1 when
+    /// parts of the image are missing.
+    pub fn asymmetry(&self, fov: FOV) -> f32 {
+        let mut voxels = vec![0; self.sums.len()];
+        for bin in self.voxel_bins(fov).into_iter().flatten() { voxels[bin] += 1 }
+        let (mut actual, mut expected) = ([0.0; SECTORS], [0.0; SECTORS]);
+        for (sums, voxels) in self.sums.chunks(SECTORS).zip(voxels.chunks(SECTORS)) {
+            let n: usize = voxels.iter().sum();
+            if n == 0 { continue }
+            let mean = sums.iter().sum::<f32>() / n as f32;
+            for s in 0..SECTORS {
+                actual  [s] += sums[s];
+                expected[s] += mean * voxels[s] as f32;
+            }
+        }
+        actual.iter().zip(&expected)
+            .filter(|&(_, &e)| e > 0.0)
+            .map(|(a, e)| (a / e - 1.0).abs())
+            .fold(0.0, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod test_cylindrical {
+    use super::*;
+    use float_eq::assert_float_eq;
+
+    /// 4 mm voxels, 5 by 5 transversely: the voxel centres are at radii 0, 4,
+    /// 5.7, 8, 8.9 and 11.3 mm
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(20.0), mm(20.0), mm(8.0)), (5, 5, 2)) }
+
+    #[test]
+    fn invalid_bins_are_rejected() {
+        assert!(CylindricalImage::new(0, 1, mm(1.0), mm(1.0)).is_err());
+        assert!(CylindricalImage::new(1, 1, mm(0.0), mm(1.0)).is_err());
+        assert!(CylindricalImage::new(1, 1, mm(1.0), mm(f32::NAN)).is_err());
+    }
+
+    #[test]
+    fn covering_bins_hold_every_voxel() {
+        let c = CylindricalImage::covering(fov());
+        assert_eq!((c.nr, c.nz), (3, 2));
+        assert!(c.voxel_bins(fov()).iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn broadcast_averages_over_each_ring() {
+        // Values equal to the x index: the average of each ring is 2
+        let fov = fov();
+        let data = (0..50).map(|i| (i % 5) as f32).collect();
+        let image = Image::new(fov, data);
+        let c = CylindricalImage::from_image(&image);
+        assert_float_eq!(c.broadcast(fov).data, vec![2.0; 50], abs_all <= 1e-6);
+        assert!(c.asymmetry(fov) > ASYMMETRY_WARNING, "{}", c.asymmetry(fov));
+    }
+
+    #[test]
+    fn symmetric_images_are_reproduced() {
+        let fov = fov();
+        let data = (0..50).map(|i| {
+            let p = fov.voxel_centre1(i);
+            100.0 - mm_(p.x).hypot(mm_(p.y)).floor() / 4.0 + mm_(p.z)
+        }).collect();
+        let image = Image::new(fov, data);
+        let c = CylindricalImage::from_image(&image);
+        assert_float_eq!(c.broadcast(fov).data, image.data, abs_all <= 1e-4);
+        assert_float_eq!(c.asymmetry(fov), 0.0, abs <= 1e-6);
+    }
+}
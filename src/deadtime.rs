@@ -0,0 +1,178 @@
+//! Dead-time corrections from the singles rates of each time frame.
+//!
+//! A detector with dead time `tau` records a rate `m` of singles when the true
+//! rate is `n`:
+//!
+//! + non-paralyzable: `m = n / (1 + n tau)`
+//! + paralyzable:     `m = n exp(-n tau)`
+//!
+//! The correction factor of a frame is `n / m`, obtained by inverting the model
+//! for the frame's measured singles rate. The LORs in a frame were detected
+//! with probability `m / n` relative to a detector without dead time, so that
+//! is the factor applied to their multiplicative corrections.
+
+use crate::Time;
+use geometry::units::ns_;
+
+/// Row of the singles-rate table: frames start at `start` and end at the start
+/// of the next row. The last frame never ends.
+#[cfg_attr(feature = "hdf5", derive(hdf5::H5Type))]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+pub struct SinglesRate {
+    /// Start of the frame, in seconds of acquisition time
+    pub start: f32,
+    /// Singles per second, as measured during the frame
+    pub rate: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeadTimeModel {
+    Paralyzable,
+    NonParalyzable,
+}
+
+impl std::str::FromStr for DeadTimeModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(|c| c == '-' || c == '_', "").as_str() {
+            "paralyzable"    => Ok(Self::Paralyzable),
+            "nonparalyzable" => Ok(Self::NonParalyzable),
+            _ => Err(format!("Unknown dead-time model '{s}': use paralyzable or non-paralyzable")),
+        }
+    }
+}
+
+impl DeadTimeModel {
+    /// True rate / measured rate, for a measured `rate` (per second) and dead
+    /// time `tau`. Error if no true rate could give rise to `rate`.
+    pub fn correction_factor(self, rate: f32, tau: Time) -> Result<f64, String> {
+        let x = rate as f64 * ns_(tau) as f64 * 1e-9; // measured counts per dead time
+        if !(x >= 0.0 && x.is_finite()) { return Err(format!("Invalid singles rate {rate} /s")) }
+        if x == 0.0 { return Ok(1.0) }
+        // True counts per dead time, `y`
+        let y = match self {
+            Self::NonParalyzable => {
+                if x >= 1.0 {
+                    return Err(format!("Singles rate {rate} /s is unattainable with non-paralyzable dead time {tau:?}"))
+                }
+                x / (1.0 - x)
+            },
+            Self::Paralyzable => {
+                if x > (-1.0_f64).exp() {
+                    return Err(format!("Singles rate {rate} /s is unattainable with paralyzable dead time {tau:?}"))
+                }
+                // Newton's method on the lower branch of `y exp(-y) = x`.
+                // Starting below the root of this concave, increasing function,
+                // the iterates increase monotonically towards it.
+                let mut y = x;
+                for _ in 0..100 {
+                    let step = (y * (-y).exp() - x) / ((1.0 - y) * (-y).exp());
+                    if !step.is_finite() { break }
+                    y -= step;
+                    if step.abs() <= 1e-12 * y { break }
+                }
+                y
+            },
+        };
+        Ok(y / x)
+    }
+}
+
+/// Dead-time correction factors of consecutive frames
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadTimeCorrection {
+    starts: Vec<f32>,
+    factors: Vec<f64>,
+}
+
+impl DeadTimeCorrection {
+    /// Factors of the frames in `singles`, which must be ordered by start time
+    pub fn new(singles: &[SinglesRate], model: DeadTimeModel, tau: Time) -> Result<Self, String> {
+        if singles.is_empty() { return Err("Singles-rate table is empty".into()) }
+        if singles.windows(2).any(|w| w[0].start.partial_cmp(&w[1].start) != Some(std::cmp::Ordering::Less)) {
+            return Err("Singles-rate frames are not in increasing order of start time".into())
+        }
+        let factors = singles.iter()
+            .map(|&SinglesRate { rate, .. }| model.correction_factor(rate, tau))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { starts: singles.iter().map(|s| s.start).collect(), factors })
+    }
+
+    /// Correction factor (true / measured) of the frame containing acquisition
+    /// `time` (seconds). `None` if `time` precedes the first frame or is NaN.
+    pub fn factor_at(&self, time: f32) -> Option<f64> {
+        if time.is_nan() || time < self.starts[0] { return None }
+        let frame = self.starts.partition_point(|&start| start <= time) - 1;
+        Some(self.factors[frame])
+    }
+
+    /// Factor by which to scale the multiplicative correction of a LOR detected
+    /// at `time`: the fraction of events which survive dead time
+    pub fn live_fraction_at(&self, time: f32) -> Option<f32> {
+        self.factor_at(time).map(|f| (1.0 / f) as f32)
+    }
+}
+
+#[cfg(test)]
+mod test_dead_time {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::ns;
+    use rstest::rstest;
+
+    // tau = 1 us, so that measured rates of 1e5 /s give x = m tau = 0.1
+    #[rstest(/**/ model                       , rate , expected,
+             case(DeadTimeModel::NonParalyzable, 0.0  , 1.0),
+             case(DeadTimeModel::NonParalyzable, 1e5  , 1.0 / 0.9),
+             case(DeadTimeModel::NonParalyzable, 5e5  , 2.0),
+             case(DeadTimeModel::Paralyzable   , 0.0  , 1.0),
+             // n tau = 0.5 gives m tau = 0.5 exp(-0.5)
+             case(DeadTimeModel::Paralyzable   , 0.5 * (-0.5_f32).exp() * 1e6, (0.5_f64).exp()),
+             // Close to the peak of the paralyzable curve, at n tau = 1
+             case(DeadTimeModel::Paralyzable   , 0.9 * (-0.9_f32).exp() * 1e6, (0.9_f64).exp()),
+    )]
+    fn analytic_factors(model: DeadTimeModel, rate: f32, expected: f64) {
+        let factor = model.correction_factor(rate, ns(1000.0)).unwrap();
+        assert_float_eq!(factor, expected, rmax <= 1e-5);
+    }
+
+    #[test]
+    fn paralyzable_inverts_model() {
+        let tau = ns(200.0);
+        for n in [1e3, 1e5, 1e6, 3e6] {
+            let m = n * (-n * 200e-9_f64).exp();
+            let factor = DeadTimeModel::Paralyzable.correction_factor(m as f32, tau).unwrap();
+            assert_float_eq!(factor * m, n, rmax <= 1e-5);
+        }
+    }
+
+    #[rstest(/**/ model, rate,
+             case(DeadTimeModel::NonParalyzable, 1e6),
+             case(DeadTimeModel::Paralyzable   , 4e5),
+             case(DeadTimeModel::Paralyzable   , -1.0),
+             case(DeadTimeModel::Paralyzable   , f32::NAN),
+    )]
+    fn unattainable_rates(model: DeadTimeModel, rate: f32) {
+        assert!(model.correction_factor(rate, ns(1000.0)).is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let singles = [
+            SinglesRate { start:  0.0, rate: 1e5 },
+            SinglesRate { start: 10.0, rate: 5e5 },
+            SinglesRate { start: 20.0, rate: 0.0 },
+        ];
+        let correction = DeadTimeCorrection::new(&singles, DeadTimeModel::NonParalyzable, ns(1000.0)).unwrap();
+        assert_eq!(correction.factor_at(-1.0), None);
+        assert_eq!(correction.factor_at(f32::NAN), None);
+        assert_float_eq!(correction.factor_at( 0.0).unwrap(), 1.0 / 0.9, rmax <= 1e-6);
+        assert_float_eq!(correction.factor_at( 9.9).unwrap(), 1.0 / 0.9, rmax <= 1e-6);
+        assert_float_eq!(correction.factor_at(10.0).unwrap(), 2.0      , rmax <= 1e-6);
+        assert_float_eq!(correction.factor_at(1e6 ).unwrap(), 1.0      , rmax <= 1e-6);
+        assert_float_eq!(correction.live_fraction_at(15.0).unwrap(), 0.5, rmax <= 1e-6);
+        let unordered = [singles[1], singles[0]];
+        assert!(DeadTimeCorrection::new(&unordered, DeadTimeModel::Paralyzable, ns(1000.0)).is_err());
+    }
+}
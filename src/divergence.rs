@@ -0,0 +1,364 @@
+//! Detection of diverging reconstructions.
+//!
+//! Bad inputs (wrong units, broken normalization) can make MLEM diverge, with
+//! the image maximum exploding by orders of magnitude per iteration. The
+//! [`DivergenceDetector`] tracks a few statistics of each image produced, and
+//! reports a [`Divergence`] when they change implausibly fast. The [`Monitor`]
+//! also computes the data-mismatch statistic and, when divergence is detected,
+//! writes the last good image and a diagnostics dump.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{Lengthf32, Time};
+use crate::gauss::TofCutoff;
+use crate::image::Image;
+use crate::mlem::forward_projections;
+use crate::system_matrix::{LOR, Tube};
+use geometry::units::{mm_, ps_};
+
+/// Limits beyond which an iteration is considered to have diverged. Each limit
+/// can be disabled with `None`. Non-finite voxels are always reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Largest allowed factor by which the image maximum grows in one iteration
+    pub max_growth: Option<f32>,
+    /// Largest allowed factor by which the total activity grows in one iteration
+    pub total_growth: Option<f32>,
+    /// Largest allowed increase of the data mismatch (see `mismatch`) in one iteration
+    pub mismatch_increase: Option<f32>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self { max_growth: Some(10.0), total_growth: Some(10.0), mismatch_increase: Some(1.0) }
+    }
+}
+
+/// Summary of the image produced by one iteration (or subset)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct IterationStats {
+    pub iteration: usize,
+    pub subset: usize,
+    pub max: f32,
+    pub total: f32,
+    pub non_finite: usize,
+    pub mismatch: Option<f32>,
+    /// Wall-clock time taken to compute the image
+    pub seconds: Option<f32>,
+    /// Largest relative change of any voxel from the previous image (see
+    /// `max_relative_change`)
+    pub max_change: Option<f32>,
+}
+
+impl IterationStats {
+    pub fn of(image: &Image, iteration: usize, subset: usize, mismatch: Option<f32>) -> Self {
+        let (mut max, mut total, mut non_finite) = (f32::NEG_INFINITY, 0.0, 0);
+        for &v in &image.data {
+            if v.is_finite() { max = max.max(v); total += v }
+            else             { non_finite += 1 }
+        }
+        Self { iteration, subset, max, total, non_finite, mismatch, seconds: None, max_change: None }
+    }
+}
+
+/// Largest relative change `|current - previous| / previous` of any voxel.
+/// Voxels which were not positive in `previous`, and non-finite changes, are
+/// ignored: MLEM cannot revive a voxel once it is zero.
+pub fn max_relative_change(previous: &[f32], current: &[f32]) -> f32 {
+    previous.iter().zip(current)
+        .filter(|(&p, _)| p > 0.0)
+        .map(|(&p, &c)| ((c - p) / p).abs())
+        .filter(|change| change.is_finite())
+        .fold(0.0, f32::max)
+}
+
+/// How far MLEM has converged, after one iteration (or subset)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Convergence {
+    pub iteration: usize,
+    pub subset: usize,
+    /// Poisson log-likelihood of the data, up to a constant (see
+    /// `acceleration::log_likelihood`)
+    pub log_likelihood: f64,
+    /// See `max_relative_change`
+    pub max_change: f32,
+}
+
+/// Negative mean log-likelihood of the events, given the forward
+/// `projections` into their LORs of an image whose voxels sum to `total`.
+/// Normalizing by `total` makes this independent of the overall scale of the
+/// image. LORs missing the FOV are ignored.
+pub fn mismatch(projections: &[Option<Lengthf32>], total: f32) -> f32 {
+    let (n, sum_ln) = projections.iter().flatten()
+        .fold((0, 0.0_f64), |(n, s), &p| (n + 1, s + (p as f64).ln()));
+    if n == 0 { return 0.0 }
+    ((total as f64).ln() - sum_ln / n as f64) as f32
+}
+
+// --------------------------------------------------------------------------------
+/// Why a reconstruction was judged to have diverged
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub reason: String,
+    pub stats: IterationStats,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reconstruction diverged in iteration {}, subset {}: {}",
+               self.stats.iteration, self.stats.subset, self.reason)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+#[derive(Clone, Debug, Default)]
+pub struct DivergenceDetector {
+    thresholds: Thresholds,
+    history: Vec<IterationStats>,
+}
+
+impl DivergenceDetector {
+    pub fn new(thresholds: Thresholds) -> Self { Self { thresholds, history: vec![] } }
+
+    /// Statistics of all the iterations checked so far, including any which diverged
+    pub fn history(&self) -> &[IterationStats] { &self.history }
+
+    /// Record `stats`, and compare them to those of the previous iteration
+    pub fn check(&mut self, stats: IterationStats) -> Result<(), Divergence> {
+        let previous = self.history.last().copied();
+        self.history.push(stats);
+        let diverged = |reason: String| Err(Divergence { reason, stats });
+
+        if stats.non_finite > 0 {
+            return diverged(format!("{} voxels are NaN or infinite", stats.non_finite))
+        }
+        if let Some(m) = stats.mismatch {
+            if !m.is_finite() { return diverged(format!("data mismatch is {m}")) }
+        }
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+        let growth = |now: f32, before: f32| if before > 0.0 { now / before } else { 1.0 };
+        if let Some(limit) = self.thresholds.max_growth {
+            let g = growth(stats.max, previous.max);
+            if g > limit { return diverged(format!("image maximum grew by a factor of {g:.3e} (limit {limit})")) }
+        }
+        if let Some(limit) = self.thresholds.total_growth {
+            let g = growth(stats.total, previous.total);
+            if g > limit { return diverged(format!("total activity grew by a factor of {g:.3e} (limit {limit})")) }
+        }
+        if let (Some(limit), Some(now), Some(before)) = (self.thresholds.mismatch_increase, stats.mismatch, previous.mismatch) {
+            if now - before > limit {
+                return diverged(format!("data mismatch increased from {before:.4} to {now:.4} (limit +{limit})"))
+            }
+        }
+        Ok(())
+    }
+}
+
+// --------------------------------------------------------------------------------
+/// A LOR whose forward projection was among the largest
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HotLor {
+    pub index: usize,
+    pub p1: [f32; 3],
+    pub p2: [f32; 3],
+    pub dt_ps: f32,
+    pub projection: f32,
+}
+
+/// What is written when divergence is detected
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostics {
+    pub reason: String,
+    pub history: Vec<IterationStats>,
+    pub largest_projections: Vec<HotLor>,
+}
+
+/// The `k` LORs with the largest forward projections. NaNs count as largest.
+pub fn largest_projections(projections: &[Option<Lengthf32>], lors: &[LOR], k: usize) -> Vec<HotLor> {
+    let key = |p: f32| if p.is_nan() { f32::INFINITY } else { p };
+    let mut hit: Vec<(usize, f32)> = projections.iter().enumerate()
+        .filter_map(|(i, p)| p.map(|p| (i, p)))
+        .collect();
+    hit.sort_by(|a, b| key(b.1).partial_cmp(&key(a.1)).unwrap().then(a.0.cmp(&b.0)));
+    hit.into_iter().take(k)
+        .map(|(index, projection)| {
+            let LOR { p1, p2, dt, .. } = lors[index];
+            HotLor {
+                index, projection, dt_ps: ps_(dt),
+                p1: [mm_(p1.x), mm_(p1.y), mm_(p1.z)],
+                p2: [mm_(p2.x), mm_(p2.y), mm_(p2.z)],
+            }
+        })
+        .collect()
+}
+
+/// Checks the images produced by MLEM as they arrive. Keeps a copy of the last
+/// image which passed the checks, to be written out if a later one diverges.
+pub struct Monitor<'a> {
+    detector: DivergenceDetector,
+    /// LORs on which the data mismatch is evaluated
+    sample: &'a [LOR],
+    sigma: Option<Time>,
+    cutoff: Option<TofCutoff>,
+    tube: Option<Tube>,
+    /// Prefix of the files written on divergence
+    output: String,
+    last_good: Option<Image>,
+}
+
+impl<'a> Monitor<'a> {
+    /// Files are written to `{output}last-good.raw` and `{output}divergence.json`
+    pub fn new(thresholds: Thresholds, sample: &'a [LOR], sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>, output: &str) -> Self {
+        Self { detector: DivergenceDetector::new(thresholds), sample, sigma, cutoff, tube, output: output.into(), last_good: None }
+    }
+
+    pub fn last_good_path  (&self) -> PathBuf { PathBuf::from(format!("{}last-good.raw"  , self.output)) }
+    pub fn diagnostics_path(&self) -> PathBuf { PathBuf::from(format!("{}divergence.json", self.output)) }
+
+    /// Check `image`. On divergence, write the last good image (if any) and the
+    /// diagnostics, before returning the reason.
+    pub fn observe(&mut self, image: &Image, iteration: usize, subset: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let projections = forward_projections(image, self.sample, self.sigma, self.cutoff, self.tube);
+        let stats = IterationStats::of(image, iteration, subset, None);
+        let stats = IterationStats { mismatch: Some(mismatch(&projections, stats.total)), ..stats };
+        match self.detector.check(stats) {
+            Ok(()) => {
+                self.last_good = Some(image.clone());
+                Ok(())
+            }
+            Err(divergence) => {
+                self.dump(&divergence, &projections)?;
+                Err(divergence.into())
+            }
+        }
+    }
+
+    fn dump(&self, divergence: &Divergence, projections: &[Option<Lengthf32>]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(image) = &self.last_good {
+            image.write_to_raw_file(&self.last_good_path())?;
+        }
+        let diagnostics = Diagnostics {
+            reason: divergence.to_string(),
+            history: self.detector.history().to_vec(),
+            largest_projections: largest_projections(projections, self.sample, 10),
+        };
+        write_json(&self.diagnostics_path(), &diagnostics)
+    }
+}
+
+fn write_json(path: &Path, diagnostics: &Diagnostics) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, serde_json::to_string_pretty(diagnostics)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Point;
+    use crate::fov::FOV;
+    use crate::system_matrix::Corrections;
+    use geometry::units::{mm, ns};
+
+    fn stats(iteration: usize, max: f32, total: f32, mismatch: Option<f32>) -> IterationStats {
+        IterationStats { iteration, subset: 1, max, total, non_finite: 0, mismatch, seconds: None, max_change: None }
+    }
+
+    #[test]
+    fn thresholds_apply_between_consecutive_iterations() {
+        let mut detector = DivergenceDetector::new(Thresholds::default());
+        // No previous iteration: any growth is acceptable
+        assert!(detector.check(stats(1, 1e6, 1e9, Some(3.0))).is_ok());
+        assert!(detector.check(stats(2, 5e6, 2e9, Some(2.5))).is_ok());
+        let err = detector.check(stats(3, 1e8, 2e9, Some(2.4))).unwrap_err();
+        assert!(err.reason.contains("maximum"), "{err}");
+        assert_eq!(detector.history().len(), 3);
+
+        let mut detector = DivergenceDetector::new(Thresholds::default());
+        detector.check(stats(1, 1.0, 1.0, Some(2.0))).unwrap();
+        assert!(detector.check(stats(2, 1.0, 1.0, Some(3.5))).unwrap_err().reason.contains("mismatch"));
+
+        let disabled = Thresholds { max_growth: None, total_growth: None, mismatch_increase: None };
+        let mut detector = DivergenceDetector::new(disabled);
+        detector.check(stats(1, 1.0, 1.0, Some(2.0))).unwrap();
+        assert!(detector.check(stats(2, 1e9, 1e9, Some(9.0))).is_ok());
+    }
+
+    #[test]
+    fn mismatch_is_scale_invariant() {
+        let projections = [Some(2.0), None, Some(8.0)];
+        let scaled = [Some(20.0), None, Some(80.0)];
+        let m = mismatch(&projections, 10.0);
+        assert!((m - mismatch(&scaled, 100.0)).abs() < 1e-6);
+        assert!((m - (10.0_f32.ln() - 4.0_f32.ln())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn max_relative_change_ignores_empty_voxels() {
+        assert_eq!(max_relative_change(&[1.0, 2.0, 4.0], &[1.5, 1.0, 4.0]), 0.5);
+        assert_eq!(max_relative_change(&[0.0, 2.0], &[3.0, 2.5]), 0.25);
+        assert_eq!(max_relative_change(&[1.0], &[1.0]), 0.0);
+    }
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(60.0), mm(60.0), mm(60.0)), (6, 6, 6)) }
+
+    /// LORs through the FOV in many directions
+    fn lors() -> Vec<LOR> {
+        let mut lors = vec![];
+        for i in 0..20 {
+            let a = i as f32 * std::f32::consts::PI / 20.0;
+            for &(dy, z) in &[(-12.0, -10.0), (0.0, 0.0), (12.0, 10.0), (6.0, -20.0)] {
+                let (c, s) = (a.cos() * 100.0, a.sin() * 100.0);
+                let p1 = Point::new(mm( c - s * dy / 100.0), mm( s + c * dy / 100.0), mm(z));
+                let p2 = Point::new(mm(-c - s * dy / 100.0), mm(-s + c * dy / 100.0), mm(-z));
+                lors.push(LOR::new(ns(0.0), ns(0.0), p1, p2));
+            }
+        }
+        lors
+    }
+
+    fn run(lors: &[LOR], output: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut monitor = Monitor::new(Thresholds::default(), lors, None, None, None, output);
+        for (image, iteration, subset) in Image::mlem(fov(), lors, None, None, None, None, 1).take(5) {
+            monitor.observe(&image, iteration, subset)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn healthy_run_never_triggers() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let output = format!("{}/", dir.path().display());
+        run(&lors(), &output)?;
+        assert!(!dir.path().join("divergence.json").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn nan_lor_aborts_with_diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let output = format!("{}/", dir.path().display());
+        let mut lors = lors();
+        let bad = Corrections { additive: f32::NAN, ..Corrections::NONE };
+        lors[7] = lors[7].with_corrections(bad);
+
+        let err = run(&lors, &output).unwrap_err();
+        assert!(err.to_string().contains("iteration 1"), "{err}");
+        assert!(err.to_string().contains("NaN"), "{err}");
+
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join("divergence.json"))?)?;
+        assert_eq!(json["history"].as_array().unwrap().len(), 1);
+        let hottest = &json["largest_projections"];
+        assert_eq!(hottest.as_array().unwrap().len(), 10);
+        assert!(hottest.as_array().unwrap().iter().any(|lor| lor["index"] == 7));
+        // Divergence in the first iteration leaves no good image to write
+        assert!(!dir.path().join("last-good.raw").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+//! Energy windows derived from the energy spectra themselves.
+//!
+//! `E1` and `E2` are histogrammed separately and together. The photopeak is
+//! taken to be the highest bin of each spectrum, after smoothing over three
+//! bins, which assumes that the photopeak rises above the Compton continuum.
+//! A Gaussian is fitted to the bins around it which hold at least half as many
+//! counts as the peak, by weighted least squares on the logarithms of their
+//! counts, and the width of the bins is subtracted in quadrature (Sheppard's
+//! correction). The window extends `n_sigma` standard deviations either side
+//! of the mean of the fit to both spectra together, as `ecut` applies to both
+//! energies alike.
+
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use crate::{BoundPair, Energyf32};
+use crate::utils::parse_bounds;
+
+/// `--ecut`: fixed bounds, such as `434..588`, or a window found automatically,
+/// such as `auto:2.5sigma`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnergyCut {
+    Bounds(BoundPair<Energyf32>),
+    Auto(AutoEcut),
+}
+
+impl FromStr for EnergyCut {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().starts_with("auto") { return s.parse().map(Self::Auto) }
+        if !s.contains("..") { return Err(format!("Expected <min>..<max> or auto:<n>sigma, got '{s}'")) }
+        parse_bounds(s).map(Self::Bounds).map_err(|e| format!("Invalid energy bounds '{s}': {e}"))
+    }
+}
+
+/// Settings of the automatic energy window:
+/// `auto:<n>sigma[,bins=<n>][,max=<keV>]`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoEcut {
+    /// Half-width of the window, in standard deviations of the photopeak
+    pub n_sigma: f32,
+    /// Bins of the spectra, which span `[0, max)` keV
+    pub bins: usize,
+    pub max: Energyf32,
+}
+
+impl AutoEcut {
+    pub const DEFAULT_BINS: usize = 200;
+    pub const DEFAULT_MAX: Energyf32 = 1000.0;
+
+    pub fn new(n_sigma: f32) -> Self { Self { n_sigma, bins: Self::DEFAULT_BINS, max: Self::DEFAULT_MAX } }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.n_sigma.is_finite() && self.n_sigma > 0.0) { return Err(format!("The energy window must be a positive number of σ, got {}", self.n_sigma)) }
+        if self.bins < 3 { return Err(format!("Energy spectra need at least 3 bins, got {}", self.bins)) }
+        if !(self.max.is_finite() && self.max > 0.0) { return Err(format!("Energy spectra must end above 0 keV, got {}", self.max)) }
+        Ok(())
+    }
+}
+
+impl FromStr for AutoEcut {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Expected auto:<n>sigma[,bins=<n>][,max=<keV>], got '{s}'");
+        let parameters = s.trim().strip_prefix("auto:").ok_or_else(usage)?;
+        let mut parameters = parameters.split(',').map(str::trim);
+        let n_sigma = parameters.next()
+            .and_then(|n| n.strip_suffix("sigma"))
+            .ok_or_else(usage)?;
+        let n_sigma = n_sigma.trim().parse::<f32>().map_err(|e| format!("Invalid number of σ '{n_sigma}': {e}"))?;
+        let mut auto = Self::new(n_sigma);
+        for parameter in parameters {
+            match parameter.split_once('=') {
+                Some(("bins", v)) => auto.bins = v.parse().map_err(|e| format!("Invalid bins '{v}': {e}"))?,
+                Some(("max" , v)) => auto.max  = v.parse().map_err(|e| format!("Invalid max '{v}': {e}"))?,
+                _ => return Err(format!("Unknown energy window parameter '{parameter}': use bins=<n> or max=<keV>")),
+            }
+        }
+        auto.validate()?;
+        Ok(auto)
+    }
+}
+
+/// Counts of energies in equal bins spanning `[0, max)` keV
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnergyHistogram {
+    pub max: Energyf32,
+    pub counts: Vec<usize>,
+    /// Energies outside `[0, max)`, NaNs included
+    pub n_outside: usize,
+}
+
+impl EnergyHistogram {
+    pub fn new(energies: impl IntoIterator<Item = Energyf32>, bins: usize, max: Energyf32) -> Self {
+        let mut histogram = Self { max, counts: vec![0; bins], n_outside: 0 };
+        for e in energies {
+            // NaN fails the comparison, and so lands outside
+            if (0.0..max).contains(&e) {
+                histogram.counts[((e / max * bins as f32) as usize).min(bins - 1)] += 1;
+            } else {
+                histogram.n_outside += 1;
+            }
+        }
+        histogram
+    }
+
+    pub fn bin_width(&self) -> Energyf32 { self.max / self.counts.len() as f32 }
+
+    pub fn bin_centre(&self, i: usize) -> Energyf32 { (i as f32 + 0.5) * self.bin_width() }
+
+    /// Energies in the histogram, not counting those outside it
+    pub fn n_inside(&self) -> usize { self.counts.iter().sum() }
+
+    /// Both histograms together; they must have the same bins
+    pub fn combined(&self, other: &Self) -> Self {
+        assert_eq!((self.max, self.counts.len()), (other.max, other.counts.len()), "Energy histograms have different bins");
+        let counts = self.counts.iter().zip(&other.counts).map(|(a, b)| a + b).collect();
+        Self { max: self.max, counts, n_outside: self.n_outside + other.n_outside }
+    }
+
+    /// Mean counts over each bin and its neighbours
+    fn smoothed(&self) -> Vec<f64> {
+        let n = self.counts.len();
+        (0..n).map(|i| {
+            let window = &self.counts[i.saturating_sub(1)..(i + 2).min(n)];
+            window.iter().sum::<usize>() as f64 / window.len() as f64
+        }).collect()
+    }
+
+    /// Bin of the highest peak, after smoothing over three bins
+    pub fn peak(&self) -> Option<usize> {
+        let smoothed = self.smoothed();
+        let peak = (0..smoothed.len()).rev().max_by(|&a, &b| smoothed[a].total_cmp(&smoothed[b]))?;
+        if smoothed[peak] > 0.0 { Some(peak) } else { None }
+    }
+
+    /// Gaussian fitted to the bins around the `peak` which hold at least half
+    /// as many counts as it does
+    pub fn fit_photopeak(&self) -> Result<PhotopeakFit, String> {
+        let peak = self.peak().ok_or("No energies in the spectrum")?;
+        let half = self.smoothed()[peak] / 2.0;
+        let n = self.counts.len();
+        let mut low = peak;
+        while low > 0 && self.counts[low - 1] as f64 >= half { low -= 1 }
+        let mut high = peak;
+        while high + 1 < n && self.counts[high + 1] as f64 >= half { high += 1 }
+        if low == 0 || high == n - 1 {
+            return Err(format!("Photopeak at {} keV reaches the end of the spectrum: change its max", self.bin_centre(peak)))
+        }
+        // Narrow peaks are fitted to the neighbouring bins as well
+        if high - low < 2 {
+            low -= 1;
+            high += 1;
+        }
+
+        // ln(count) = a + b u + c u², with u in bins from the peak, weighted by
+        // the counts, as the variance of ln(count) is 1 / count
+        let points: Vec<(f64, f64, f64)> = (low..=high)
+            .filter(|&i| self.counts[i] > 0)
+            .map(|i| (i as f64 - peak as f64, (self.counts[i] as f64).ln(), self.counts[i] as f64))
+            .collect();
+        if points.len() < 3 { return Err(format!("Photopeak at {} keV is too narrow to fit: use more bins", self.bin_centre(peak))) }
+        let [_, b, c] = weighted_parabola(&points).ok_or("Degenerate photopeak fit")?;
+        if c >= 0.0 { return Err(format!("No photopeak at {} keV: the spectrum does not fall away from it", self.bin_centre(peak))) }
+        let w = self.bin_width() as f64;
+        let variance = w * w * (-0.5 / c - 1.0 / 12.0);
+        if variance <= 0.0 { return Err(format!("Photopeak at {} keV is narrower than a bin: use more bins", self.bin_centre(peak))) }
+        let mean = self.bin_centre(peak) as f64 - w * b / (2.0 * c);
+        if !(self.bin_centre(low) as f64..=self.bin_centre(high) as f64).contains(&mean) {
+            return Err(format!("Photopeak fit near {} keV puts its mean outside the fitted bins", self.bin_centre(peak)))
+        }
+        Ok(PhotopeakFit {
+            mean: mean as Energyf32,
+            sigma: variance.sqrt() as Energyf32,
+            n_fitted: points.len(),
+            n_energies: self.n_inside(),
+            n_outside: self.n_outside,
+        })
+    }
+}
+
+/// Coefficients `[a, b, c]` of `a + b x + c x²` minimizing the squared
+/// deviations from the `(x, y, weight)` `points`
+fn weighted_parabola(points: &[(f64, f64, f64)]) -> Option<[f64; 3]> {
+    // Normal equations: sums of w xᵏ for k in 0..=4 and of w xᵏ y for k in 0..=2
+    let mut s = [0.0; 5];
+    let mut t = [0.0; 3];
+    for &(x, y, w) in points {
+        let mut xk = w;
+        for k in 0..5 {
+            s[k] += xk;
+            if k < 3 { t[k] += xk * y }
+            xk *= x;
+        }
+    }
+    let m = [[s[0], s[1], s[2]], [s[1], s[2], s[3]], [s[2], s[3], s[4]]];
+    let det = |m: &[[f64; 3]; 3]| m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+                                 - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+                                 + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let d = det(&m);
+    if d.abs() < 1e-12 { return None }
+    // Cramer's rule
+    let solve = |column: usize| {
+        let mut mc = m;
+        for row in 0..3 { mc[row][column] = t[row] }
+        det(&mc) / d
+    };
+    Some([solve(0), solve(1), solve(2)])
+}
+
+/// Gaussian fitted to the photopeak of a spectrum
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhotopeakFit {
+    pub mean: Energyf32,
+    pub sigma: Energyf32,
+    /// Bins to which the Gaussian was fitted
+    pub n_fitted: usize,
+    /// Energies in the spectrum, and outside it
+    pub n_energies: usize,
+    pub n_outside: usize,
+}
+
+/// Energy window `mean ± n_sigma σ` around the photopeak of both energies
+/// together, and the photopeaks of each
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EcutSuggestion {
+    pub n_sigma: f32,
+    pub e1: PhotopeakFit,
+    pub e2: PhotopeakFit,
+    pub both: PhotopeakFit,
+}
+
+impl EcutSuggestion {
+    pub fn lower(&self) -> Energyf32 { self.both.mean - self.n_sigma * self.both.sigma }
+    pub fn upper(&self) -> Energyf32 { self.both.mean + self.n_sigma * self.both.sigma }
+
+    /// Keep energies within the window, inclusive
+    pub fn bounds(&self) -> BoundPair<Energyf32> { (Bound::Included(self.lower()), Bound::Included(self.upper())) }
+}
+
+impl fmt::Display for EcutSuggestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let peak = |f: &mut fmt::Formatter, name: &str, fit: &PhotopeakFit| writeln!(
+            f, "    {name:4} photopeak at {:7.1} keV, σ {:5.1} keV   ({} energies, {} outside the spectrum; fitted to {} bins)",
+            fit.mean, fit.sigma, fit.n_energies, fit.n_outside, fit.n_fitted);
+        writeln!(f, "Energy window {:.1}σ either side of the photopeak: {:.1}..={:.1} keV", self.n_sigma, self.lower(), self.upper())?;
+        peak(f, "E1", &self.e1)?;
+        peak(f, "E2", &self.e2)?;
+        peak(f, "both", &self.both)
+    }
+}
+
+/// Window around the photopeak of the spectra of `e1` and `e2` together
+pub fn suggest_ecut(e1: &EnergyHistogram, e2: &EnergyHistogram, n_sigma: f32) -> Result<EcutSuggestion, String> {
+    Ok(EcutSuggestion {
+        n_sigma,
+        e1  : e1.fit_photopeak().map_err(|e| format!("E1: {e}"))?,
+        e2  : e2.fit_photopeak().map_err(|e| format!("E2: {e}"))?,
+        both: e1.combined(e2).fit_photopeak()?,
+    })
+}
+
+#[cfg(test)]
+mod test_ecut {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use rstest::rstest;
+
+    /// `n` energies in a photopeak of `mean` and `sigma`, on a Compton
+    /// continuum of another `n` energies rising linearly up to its edge at
+    /// 340 keV
+    fn spectrum(n: usize, mean: f32, sigma: f32, seed: u64) -> Vec<Energyf32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut energies = Vec::with_capacity(2 * n);
+        for _ in 0..n {
+            let (u, v): (f32, f32) = (1.0 - rng.gen::<f32>(), rng.gen());
+            energies.push(mean + sigma * (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos());
+            energies.push(340.0 * rng.gen::<f32>().sqrt());
+        }
+        energies
+    }
+
+    #[rstest(/**/ mean , sigma, bins,
+             case(511.0, 20.0 , 200),
+             case(511.0, 12.0 , 200),   // about 2 bins per σ
+             case(480.0, 35.0 , 400),   // off the nominal peak, finer bins
+    )]
+    fn photopeak_on_compton_continuum_is_recovered(mean: f32, sigma: f32, bins: usize) -> Result<(), String> {
+        let e1 = EnergyHistogram::new(spectrum(200_000, mean, sigma, 1), bins, 1000.0);
+        let e2 = EnergyHistogram::new(spectrum(200_000, mean, sigma, 2), bins, 1000.0);
+        let suggestion = suggest_ecut(&e1, &e2, 2.5)?;
+        for fit in [suggestion.e1, suggestion.e2, suggestion.both] {
+            assert_float_eq!(fit.mean , mean , rmax <= 0.01);
+            assert_float_eq!(fit.sigma, sigma, rmax <= 0.03);
+        }
+        assert_eq!(suggestion.both.n_energies, 800_000);
+        assert_float_eq!(suggestion.lower(), mean - 2.5 * sigma, abs <= 2.0);
+        assert_float_eq!(suggestion.upper(), mean + 2.5 * sigma, abs <= 2.0);
+        assert_eq!(suggestion.bounds(), (Bound::Included(suggestion.lower()), Bound::Included(suggestion.upper())));
+        Ok(())
+    }
+
+    #[test]
+    fn energies_outside_the_spectrum_are_counted() {
+        let histogram = EnergyHistogram::new([-1.0, 0.0, 4.9, 5.0, 999.9, 1000.0, f32::NAN], 200, 1000.0);
+        assert_eq!((histogram.counts[0], histogram.counts[1], histogram.counts[199]), (2, 1, 1));
+        assert_eq!((histogram.n_inside(), histogram.n_outside), (4, 3));
+    }
+
+    #[test]
+    fn spectra_without_a_photopeak_are_rejected() {
+        assert!(EnergyHistogram::new([], 200, 1000.0).fit_photopeak().is_err());
+        // Rising to the end of the spectrum
+        let rising = (0..1000).flat_map(|i| std::iter::repeat(i as f32).take(i / 10));
+        assert!(EnergyHistogram::new(rising, 100, 1000.0).fit_photopeak().is_err());
+        // All in one bin
+        assert!(EnergyHistogram::new([511.0; 100], 200, 1000.0).fit_photopeak().is_err());
+    }
+
+    #[rstest(/**/ text                          , expected,
+             case("auto:2.5sigma"              , Ok(EnergyCut::Auto(AutoEcut::new(2.5)))),
+             case("auto:3sigma,bins=100,max=800", Ok(EnergyCut::Auto(AutoEcut { n_sigma: 3.0, bins: 100, max: 800.0 }))),
+             case("434..588"                   , Ok(EnergyCut::Bounds((Bound::Included(434.0), Bound::Excluded(588.0))))),
+             case(".."                         , Ok(EnergyCut::Bounds((Bound::Unbounded, Bound::Unbounded)))),
+             case("auto:2.5"                   , Err(())),
+             case("auto:-1sigma"               , Err(())),
+             case("auto:2sigma,bins=2"         , Err(())),
+             case("auto:2sigma,width=5"        , Err(())),
+             case("auto"                       , Err(())),
+             case("434"                        , Err(())),
+    )]
+    fn parse(text: &str, expected: Result<EnergyCut, ()>) {
+        assert_eq!(text.parse::<EnergyCut>().map_err(|_| ()), expected);
+    }
+}
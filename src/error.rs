@@ -0,0 +1,261 @@
+//! Errors reported by the executables, classified by whose problem they are.
+//!
+//! Most of the library reports errors as `Box<dyn Error>`, built from strings
+//! describing what was wrong with the request, or from the errors of I/O and
+//! HDF5. `classify` sorts them into `ErrorClass`es: problems with the input or
+//! the command line, problems with the environment (permissions, full disks),
+//! and bugs in petalo. Panics are always bugs. Code which knows better than
+//! these rules says so with `user_error`, `environment_error` or
+//! `internal_error`.
+//!
+//! `Context::context` wraps an error in a description of the operation which
+//! failed (file, dataset, parameter values). `main` runs the body of an
+//! executable, and reports any error or panic with its class and the chain of
+//! contexts, exiting with the class's code. For bugs, it also writes a crash
+//! report (resolved configuration, versions, error chain) to a file, to be
+//! attached to an issue.
+
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::io::ErrorKind;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Mutex;
+
+/// Whose problem an error is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Bad paths, options or combinations thereof, malformed input files
+    UserInput,
+    /// The system refused: permissions, full disks, resource limits
+    Environment,
+    /// A bug in petalo
+    Internal,
+}
+
+impl ErrorClass {
+    /// Process exit code: `EX_USAGE`, `EX_IOERR` and `EX_SOFTWARE` from `sysexits.h`
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::UserInput   => 64,
+            Self::Environment => 74,
+            Self::Internal    => 70,
+        }
+    }
+}
+
+impl Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::UserInput   => "user input error",
+            Self::Environment => "environment error",
+            Self::Internal    => "internal error",
+        })
+    }
+}
+
+/// An error whose class is known where it arises
+#[derive(Debug)]
+pub struct Classified {
+    pub class: ErrorClass,
+    message: String,
+}
+
+impl Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.message) }
+}
+
+impl Error for Classified {}
+
+fn classified(class: ErrorClass, message: impl Into<String>) -> Box<dyn Error> {
+    Box::new(Classified { class, message: message.into() })
+}
+
+pub fn user_error       (message: impl Into<String>) -> Box<dyn Error> { classified(ErrorClass::UserInput  , message) }
+pub fn environment_error(message: impl Into<String>) -> Box<dyn Error> { classified(ErrorClass::Environment, message) }
+pub fn internal_error   (message: impl Into<String>) -> Box<dyn Error> { classified(ErrorClass::Internal   , message) }
+
+/// An error, and the operation during which it happened
+#[derive(Debug)]
+pub struct WithContext {
+    context: String,
+    source: Box<dyn Error>,
+}
+
+impl Display for WithContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.context) }
+}
+
+impl Error for WithContext {
+    fn source(&self) -> Option<&(dyn Error + 'static)> { Some(self.source.as_ref()) }
+}
+
+/// Describe the operation which produced an error
+pub trait Context<T> {
+    /// Wrap any error in the description returned by `context`, such as
+    /// `format!("reading LORs from '{file}'")`
+    fn context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, Box<dyn Error>>;
+}
+
+impl<T, E: Into<Box<dyn Error>>> Context<T> for Result<T, E> {
+    fn context<C: Display>(self, context: impl FnOnce() -> C) -> Result<T, Box<dyn Error>> {
+        self.map_err(|e| Box::new(WithContext { context: context().to_string(), source: e.into() }) as Box<dyn Error>)
+    }
+}
+
+/// `error` and its sources, outermost first
+pub fn chain<'a>(error: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    std::iter::successors(Some(error), |&e| e.source())
+}
+
+/// The class of the outermost error in the chain whose class is known. Errors
+/// of unknown type (mostly descriptions of invalid requests) are user errors.
+pub fn classify(error: &(dyn Error + 'static)) -> ErrorClass {
+    chain(error)
+        .find_map(|e| {
+            if let Some(c) = e.downcast_ref::<Classified>() { return Some(c.class) }
+            e.downcast_ref::<std::io::Error>().map(|e| io_class(e.kind()))
+        })
+        .unwrap_or(ErrorClass::UserInput)
+}
+
+fn io_class(kind: ErrorKind) -> ErrorClass {
+    use ErrorKind::*;
+    match kind {
+        NotFound | AlreadyExists | InvalidInput | InvalidData | UnexpectedEof => ErrorClass::UserInput,
+        _ => ErrorClass::Environment,
+    }
+}
+
+/// Debug representation of the configuration of the running executable, for
+/// crash reports
+static CONFIG: Mutex<Option<String>> = Mutex::new(None);
+
+/// Message and location, and backtrace, of the first panic
+static PANIC: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+/// In debug builds, `record_config` panics if this variable is set: exercises
+/// the reporting of internal errors in tests
+pub const INJECT_PANIC_VAR: &str = "PETALO_INJECT_PANIC";
+
+/// Include `config` (usually the parsed command line) in crash reports
+pub fn record_config(config: &impl Debug) {
+    *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = Some(format!("{config:#?}"));
+    if cfg!(debug_assertions) && std::env::var_os(INJECT_PANIC_VAR).is_some() {
+        panic!("Panic injected through {INJECT_PANIC_VAR}")
+    }
+}
+
+/// Run the body of an executable, reporting any error or panic on stderr, as
+/// described in the module documentation
+pub fn main<E: Into<Box<dyn Error>>>(run: impl FnOnce() -> Result<(), E>) -> ExitCode {
+    panic::set_hook(Box::new(|info| {
+        let mut first = PANIC.lock().unwrap_or_else(|e| e.into_inner());
+        if first.is_none() {
+            *first = Some((info.to_string(), std::backtrace::Backtrace::force_capture().to_string()));
+        }
+    }));
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| run().map_err(Into::into)));
+    let program = program_name();
+    let (error, backtrace) = match outcome {
+        Ok(Ok(())) => return ExitCode::SUCCESS,
+        Ok(Err(error)) => (error, None),
+        Err(_) => {
+            let (panic, backtrace) = PANIC.lock().unwrap_or_else(|e| e.into_inner()).take()
+                .unwrap_or_else(|| ("panic".into(), String::new()));
+            (internal_error(panic), Some(backtrace))
+        },
+    };
+    let class = classify(error.as_ref());
+    eprint!("{}", Report { program: &program, class, error: error.as_ref() });
+    if class == ErrorClass::Internal {
+        match write_crash_report(&program, error.as_ref(), backtrace) {
+            Ok(path) => eprintln!("This is a bug in petalo. Please report it, attaching\n\n    {}\n", path.display()),
+            Err(e)   => eprintln!("This is a bug in petalo. Please report it (the crash report could not be written: {e})"),
+        }
+    }
+    ExitCode::from(class.exit_code())
+}
+
+/// The message printed on failure: class, contexts (outermost first) and cause
+pub struct Report<'a> {
+    pub program: &'a str,
+    pub class: ErrorClass,
+    pub error: &'a (dyn Error + 'static),
+}
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.program, self.class)?;
+        let chain: Vec<_> = chain(self.error).collect();
+        let (cause, contexts) = chain.split_last().unwrap();
+        for context in contexts { writeln!(f, "  while {context}")?; }
+        writeln!(f, "  cause: {cause}")?;
+        match self.class {
+            ErrorClass::UserInput   => writeln!(f, "Check the files and options named above; `{} --help` describes the options.", self.program),
+            ErrorClass::Environment => writeln!(f, "Check permissions, free disk space and resource limits."),
+            ErrorClass::Internal    => Ok(()),
+        }
+    }
+}
+
+fn program_name() -> String {
+    std::env::args_os().next()
+        .and_then(|arg0| PathBuf::from(arg0).file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "petalo".into())
+}
+
+/// Write the configuration, versions, error chain and any backtrace of a panic
+/// to a JSON file in the temporary directory, returning its path
+fn write_crash_report(program: &str, error: &(dyn Error + 'static), backtrace: Option<String>) -> std::io::Result<PathBuf> {
+    let config = CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let report = serde_json::json!({
+        "program": program,
+        "version": env!("CARGO_PKG_VERSION"),
+        "target": format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        "args": std::env::args().collect::<Vec<_>>(),
+        "config": config,
+        "errors": chain(error).map(|e| e.to_string()).collect::<Vec<_>>(),
+        "backtrace": backtrace,
+    });
+    let path = std::env::temp_dir().join(format!("{program}-crash-{}.json", std::process::id()));
+    std::fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+
+    fn io(kind: ErrorKind) -> Box<dyn Error> { Box::new(std::io::Error::new(kind, "io")) }
+
+    #[test]
+    fn classification_follows_the_chain() {
+        let bare: Box<dyn Error> = "Need at least one subset".into();
+        assert_eq!(classify(bare.as_ref()), ErrorClass::UserInput);
+        let missing: Result<(), _> = Err(io(ErrorKind::NotFound));
+        assert_eq!(classify(missing.context(|| "reading 'x.h5'").unwrap_err().as_ref()), ErrorClass::UserInput);
+        let denied: Result<(), _> = Err(io(ErrorKind::PermissionDenied));
+        assert_eq!(classify(denied.context(|| "writing 'out'").unwrap_err().as_ref()), ErrorClass::Environment);
+        // Explicit classes beat those inferred from the sources
+        let bug: Result<(), _> = Err(io(ErrorKind::NotFound));
+        let bug = bug.context(|| "a").map_err(|e| internal_error(e.to_string()));
+        assert_eq!(classify(bug.unwrap_err().as_ref()), ErrorClass::Internal);
+    }
+
+    #[test]
+    fn report_lists_contexts_outermost_first() {
+        let e: Result<(), _> = Err("not an HDF5 file");
+        let e = e.context(|| "reading LORs from 'x.h5', dataset 'reco_info/lors'")
+                 .context(|| "reconstructing with 5 iterations")
+                 .unwrap_err();
+        let report = Report { program: "mlem", class: classify(e.as_ref()), error: e.as_ref() }.to_string();
+        assert_eq!(report.lines().take(4).collect::<Vec<_>>(), [
+            "mlem: user input error",
+            "  while reconstructing with 5 iterations",
+            "  while reading LORs from 'x.h5', dataset 'reco_info/lors'",
+            "  cause: not an HDF5 file",
+        ]);
+    }
+}
@@ -4,7 +4,6 @@ use ncollide3d as nc;
 
 pub use geometry::uom::si::Quantity;
 pub use geometry::uom::typenum::{Z0, N1};
-use geometry::in_base_unit;
 
 pub type Lengthf32  = f32;
 pub use geometry::Length;
@@ -41,6 +40,6 @@ pub use crate::index::{BoxDim_u, Index1_u, Index3_u, Index3Weightf32, LengthI, L
 
 pub type BoundPair<T> = (std::ops::Bound<T>, std::ops::Bound<T>);
 
-pub const C: Velocity = in_base_unit!(299_792_458.0);
+pub use crate::constants::C;
 
 pub use geometry::AreaPerMass;
@@ -1,19 +1,31 @@
 use crate::io::raw;
-use crate::{Intensityf32, Ratiof32};
+use crate::{Intensityf32, Ratiof32, Lengthf32, Index1_u};
 use crate::{Length, Point};
-use geometry::units::ratio_;
+use crate::index::index3_to_1;
+use geometry::units::{mm, mm_, ratio_};
 use crate::image::{Image, ImageData};
 use crate::fov::FOV;
 
 type BoxErr<T> = Result<T, Box<dyn std::error::Error>>;
 
+/// Load an image in the versioned format, or a legacy headerless one filling `fov`
 pub fn load_image(filename: &std::path::Path, fov: FOV) -> BoxErr<Image> {
+    if raw::has_header(filename)? { return Ok(raw::read_versioned(filename)?) }
     let data = raw::read(filename)?.collect::<Result<_,_>>()?;
     Ok(Image::new(fov, data)) // TODO: Upgrade Image::new from panic to Result
 }
 
 
-#[derive(Clone, Debug)]
+/// Region of interest. Basic shapes can be combined with `Union`,
+/// `Intersection` and `Difference`.
+///
+/// Parses from a compact expression (lengths in mm), where `&` binds more
+/// tightly than `|` and `-`:
+///
+/// ```text
+/// sphere(0,57.2,0,5) | box(-5,-5,-5,5,5,5) - cylinderz(0,0,2)
+/// ```
+#[derive(Clone, Debug, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum ROI {
     Sphere((Length, Length, Length), Length),
@@ -21,13 +33,72 @@ pub enum ROI {
     CylinderY((Length, Length), Length),
     CylinderZ((Length, Length), Length),
     DiscZ((Length, Length, Length), Length),
+    /// Opposite corners: (min, max)
+    Box((Length, Length, Length), (Length, Length, Length)),
+    Union(Vec<ROI>),
+    Intersection(Vec<ROI>),
+    Difference(Box<ROI>, Box<ROI>),
 }
 
 pub type InRoiFn = Box<dyn Fn(Point) -> bool>;
 
+/// Axis-aligned box enclosing an ROI, in mm. Infinite in unbounded directions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: [Lengthf32; 3],
+    pub max: [Lengthf32; 3],
+}
+
+impl Bounds {
+    const EVERYWHERE: Self = Self { min: [f32::NEG_INFINITY; 3], max: [f32::INFINITY; 3] };
+    const NOWHERE   : Self = Self { min: [f32::INFINITY; 3], max: [f32::NEG_INFINITY; 3] };
+
+    fn around(centre: [Lengthf32; 3], half_widths: [Lengthf32; 3]) -> Self {
+        let [x, y, z] = centre;
+        let [dx, dy, dz] = half_widths;
+        Self { min: [x - dx, y - dy, z - dz], max: [x + dx, y + dy, z + dz] }
+    }
+
+    fn hull(self, other: Self) -> Self {
+        let f = |i: usize| (self.min[i].min(other.min[i]), self.max[i].max(other.max[i]));
+        let ((x0, x1), (y0, y1), (z0, z1)) = (f(0), f(1), f(2));
+        Self { min: [x0, y0, z0], max: [x1, y1, z1] }
+    }
+
+    fn overlap(self, other: Self) -> Self {
+        let f = |i: usize| (self.min[i].max(other.min[i]), self.max[i].min(other.max[i]));
+        let ((x0, x1), (y0, y1), (z0, z1)) = (f(0), f(1), f(2));
+        Self { min: [x0, y0, z0], max: [x1, y1, z1] }
+    }
+
+    pub fn contains(&self, p: Point) -> bool {
+        let p = [mm_(p.x), mm_(p.y), mm_(p.z)];
+        (0..3).all(|i| self.min[i] <= p[i] && p[i] <= self.max[i])
+    }
+
+    /// Indices (along `axis` of `fov`) of the voxels whose centres might lie
+    /// within the bounds. Errs on the side of inclusion.
+    fn voxel_range(&self, fov: FOV, axis: usize) -> std::ops::Range<usize> {
+        let (half_width, voxel_size) = (mm_(fov.half_width[axis]), mm_(fov.voxel_size[axis]));
+        let index = |x: Lengthf32| (x + half_width) / voxel_size - 0.5;
+        let first = (index(self.min[axis]).floor() - 1.0).max(0.0);
+        let last  = (index(self.max[axis]).ceil()  + 1.0).min(fov.n[axis] as f32 - 1.0);
+        if last < first { return 0..0 }
+        first as usize..last as usize + 1
+    }
+}
+
 impl ROI {
 
+    /// Predicate testing whether a point lies inside the ROI. Sub-regions are
+    /// pruned by their bounding boxes before testing their exact shape.
     pub fn contains_fn(&self) -> InRoiFn {
+        let bounds = self.bounds();
+        let exact = self.exact_contains_fn();
+        Box::new(move |p: Point| bounds.contains(p) && exact(p))
+    }
+
+    fn exact_contains_fn(&self) -> InRoiFn {
         match *self {
             ROI::Sphere((cx, cy, cz), radius) => Box::new(move |p: Point| {
                 let (x,y,z) = (p.x - cx, p.y - cy, p.z - cz);
@@ -53,9 +124,66 @@ impl ROI {
                 let (x, y) = (p.x - cx, p.y - cy);
                 z == p.z && x*x + y*y < radius*radius
             }),
+
+            ROI::Box((x0, y0, z0), (x1, y1, z1)) => Box::new(move |p: Point| {
+                x0 <= p.x && p.x <= x1 &&
+                y0 <= p.y && p.y <= y1 &&
+                z0 <= p.z && p.z <= z1
+            }),
+
+            ROI::Union(ref rois) => {
+                let parts: Vec<_> = rois.iter().map(ROI::contains_fn).collect();
+                Box::new(move |p: Point| parts.iter().any(|inside| inside(p)))
+            }
+
+            ROI::Intersection(ref rois) => {
+                let parts: Vec<_> = rois.iter().map(ROI::contains_fn).collect();
+                Box::new(move |p: Point| parts.iter().all(|inside| inside(p)))
+            }
+
+            ROI::Difference(ref keep, ref remove) => {
+                let (keep, remove) = (keep.contains_fn(), remove.contains_fn());
+                Box::new(move |p: Point| keep(p) && !remove(p))
+            }
+        }
+    }
+
+    /// Box enclosing all points of the ROI
+    pub fn bounds(&self) -> Bounds {
+        let inf = f32::INFINITY;
+        match *self {
+            ROI::Sphere   ((x, y, z), r) => Bounds::around([mm_(x), mm_(y), mm_(z)], [mm_(r); 3]),
+            ROI::CylinderX((y, z)   , r) => Bounds::around([0.0, mm_(y), mm_(z)], [inf, mm_(r), mm_(r)]),
+            ROI::CylinderY((x, z)   , r) => Bounds::around([mm_(x), 0.0, mm_(z)], [mm_(r), inf, mm_(r)]),
+            ROI::CylinderZ((x, y)   , r) => Bounds::around([mm_(x), mm_(y), 0.0], [mm_(r), mm_(r), inf]),
+            ROI::DiscZ    ((x, y, z), r) => Bounds::around([mm_(x), mm_(y), mm_(z)], [mm_(r), mm_(r), 0.0]),
+            ROI::Box((x0, y0, z0), (x1, y1, z1)) => Bounds { min: [mm_(x0), mm_(y0), mm_(z0)], max: [mm_(x1), mm_(y1), mm_(z1)] },
+            ROI::Union       (ref rois) => rois.iter().map(ROI::bounds).fold(Bounds::NOWHERE   , Bounds::hull),
+            ROI::Intersection(ref rois) => rois.iter().map(ROI::bounds).fold(Bounds::EVERYWHERE, Bounds::overlap),
+            ROI::Difference(ref keep, _) => keep.bounds(),
+        }
+    }
+
+    /// 1D indices of the voxels of `fov` whose centres lie inside the ROI, in
+    /// increasing order. Only voxels within the ROI's bounding box are tested.
+    pub fn voxel_indices(&self, fov: FOV) -> Vec<Index1_u> {
+        let contains = self.contains_fn();
+        let bounds = self.bounds();
+        let (xs, ys, zs) = (bounds.voxel_range(fov, 0), bounds.voxel_range(fov, 1), bounds.voxel_range(fov, 2));
+        let mut out = vec![];
+        for iz in zs {
+            for iy in ys.clone() {
+                for ix in xs.clone() {
+                    let i = index3_to_1([ix, iy, iz], fov.n);
+                    if contains(fov.voxel_centre1(i)) { out.push(i) }
+                }
+            }
         }
+        out
     }
 
+    /// Characteristic radius: that of round basic shapes, or the half-diagonal
+    /// of the bounding box of others
     pub fn r(&self) -> Length {
         match *self {
             ROI::Sphere   (_,r) => r,
@@ -63,9 +191,189 @@ impl ROI {
             ROI::CylinderY(_,r) => r,
             ROI::CylinderZ(_,r) => r,
             ROI::DiscZ    (_,r) => r,
+            _ => {
+                let Bounds { min, max } = self.bounds();
+                let d = |i: usize| max[i] - min[i];
+                mm((d(0)*d(0) + d(1)*d(1) + d(2)*d(2)).sqrt() / 2.0)
+            }
+        }
+    }
+
+    /// The same region, displaced by `offset`. `DiscZ` regions only contain
+    /// voxels whose centres lie exactly at their z, so should be displaced
+    /// along z by whole slices.
+    pub fn translated(&self, offset: (Length, Length, Length)) -> Self {
+        let (dx, dy, dz) = offset;
+        match self {
+            ROI::Sphere   ((x, y, z), r) => ROI::Sphere   ((*x + dx, *y + dy, *z + dz), *r),
+            ROI::CylinderX((y, z)   , r) => ROI::CylinderX((*y + dy, *z + dz)         , *r),
+            ROI::CylinderY((x, z)   , r) => ROI::CylinderY((*x + dx, *z + dz)         , *r),
+            ROI::CylinderZ((x, y)   , r) => ROI::CylinderZ((*x + dx, *y + dy)         , *r),
+            ROI::DiscZ    ((x, y, z), r) => ROI::DiscZ    ((*x + dx, *y + dy, *z + dz), *r),
+            ROI::Box((x0, y0, z0), (x1, y1, z1)) => ROI::Box((*x0 + dx, *y0 + dy, *z0 + dz), (*x1 + dx, *y1 + dy, *z1 + dz)),
+            ROI::Union       (rois) => ROI::Union       (rois.iter().map(|roi| roi.translated(offset)).collect()),
+            ROI::Intersection(rois) => ROI::Intersection(rois.iter().map(|roi| roi.translated(offset)).collect()),
+            ROI::Difference(keep, remove) => ROI::Difference(Box::new(keep.translated(offset)), Box::new(remove.translated(offset))),
+        }
+    }
+
+    /// Box with corners `a` and `b`, in either order
+    pub fn cuboid(a: (Length, Length, Length), b: (Length, Length, Length)) -> Self {
+        let lo = |a: Length, b: Length| if a < b { a } else { b };
+        let hi = |a: Length, b: Length| if a < b { b } else { a };
+        ROI::Box((lo(a.0, b.0), lo(a.1, b.1), lo(a.2, b.2)),
+                 (hi(a.0, b.0), hi(a.1, b.1), hi(a.2, b.2)))
+    }
+
+}
+
+impl std::fmt::Display for ROI {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Parenthesize operands which would otherwise be parsed differently
+        let loose  = |roi: &ROI| matches!(roi, ROI::Union(_) | ROI::Difference(..));
+        let nested = |roi: &ROI| loose(roi) || matches!(roi, ROI::Intersection(_));
+        let operand = |f: &mut std::fmt::Formatter<'_>, roi: &ROI, wrap: bool| {
+            if wrap { write!(f, "({roi})") } else { write!(f, "{roi}") }
+        };
+        let join = |f: &mut std::fmt::Formatter<'_>, rois: &[ROI], op: &str, wrap: &dyn Fn(&ROI) -> bool| {
+            for (n, roi) in rois.iter().enumerate() {
+                if n > 0 { write!(f, " {op} ")? }
+                operand(f, roi, wrap(roi))?;
+            }
+            Ok(())
+        };
+        let v = |l: &Length| mm_(*l);
+        match self {
+            ROI::Sphere   ((x, y, z), r) => write!(f, "sphere({},{},{},{})", v(x), v(y), v(z), v(r)),
+            ROI::CylinderX((y, z)   , r) => write!(f, "cylinderx({},{},{})", v(y), v(z), v(r)),
+            ROI::CylinderY((x, z)   , r) => write!(f, "cylindery({},{},{})", v(x), v(z), v(r)),
+            ROI::CylinderZ((x, y)   , r) => write!(f, "cylinderz({},{},{})", v(x), v(y), v(r)),
+            ROI::DiscZ    ((x, y, z), r) => write!(f, "discz({},{},{},{})" , v(x), v(y), v(z), v(r)),
+            ROI::Box((x0, y0, z0), (x1, y1, z1)) =>
+                write!(f, "box({},{},{},{},{},{})", v(x0), v(y0), v(z0), v(x1), v(y1), v(z1)),
+            ROI::Union       (rois) => join(f, rois, "|", &loose),
+            ROI::Intersection(rois) => join(f, rois, "&", &nested),
+            ROI::Difference(keep, remove) => {
+                operand(f, keep, loose(keep))?;
+                write!(f, " - ")?;
+                operand(f, remove, loose(remove))
+            }
         }
     }
+}
 
+impl std::str::FromStr for ROI {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = RoiParser { text: s, pos: 0 };
+        let roi = parser.expression()?;
+        parser.skip_whitespace();
+        if parser.pos < s.len() { return Err(parser.error("unexpected input")) }
+        Ok(roi)
+    }
+}
+
+/// Recursive descent parser for ROI expressions
+struct RoiParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl RoiParser<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("{what} at position {} in ROI '{}'", self.pos, self.text)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) { return Err(self.error(&format!("expected '{c}'"))) }
+        self.pos += c.len_utf8();
+        Ok(())
+    }
+
+    /// Terms separated by `|` or `-`, left-associative
+    fn expression(&mut self) -> Result<ROI, String> {
+        let mut roi = self.term()?;
+        let mut chained = false; // whether `roi` is a union built by this loop
+        loop {
+            match self.peek() {
+                Some('|') => {
+                    self.pos += 1;
+                    let rhs = self.term()?;
+                    roi = match roi {
+                        ROI::Union(mut rois) if chained => { rois.push(rhs); ROI::Union(rois) }
+                        roi => ROI::Union(vec![roi, rhs]),
+                    };
+                    chained = true;
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    roi = ROI::Difference(Box::new(roi), Box::new(self.term()?));
+                    chained = false;
+                }
+                _ => return Ok(roi),
+            }
+        }
+    }
+
+    /// Factors separated by `&`
+    fn term(&mut self) -> Result<ROI, String> {
+        let mut rois = vec![self.factor()?];
+        while self.peek() == Some('&') {
+            self.pos += 1;
+            rois.push(self.factor()?);
+        }
+        Ok(if rois.len() == 1 { rois.pop().unwrap() } else { ROI::Intersection(rois) })
+    }
+
+    /// Parenthesized expression or basic shape
+    fn factor(&mut self) -> Result<ROI, String> {
+        if self.peek() == Some('(') {
+            self.pos += 1;
+            let roi = self.expression()?;
+            self.expect(')')?;
+            return Ok(roi)
+        }
+        let rest = &self.text[self.pos..];
+        let name_len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        if name_len == 0 { return Err(self.error("expected shape or '('")) }
+        let name = rest[..name_len].to_ascii_lowercase();
+        self.pos += name_len;
+        self.expect('(')?;
+        let close = self.text[self.pos..].find(')').ok_or_else(|| self.error("unclosed '('"))?;
+        let n = self.text[self.pos..self.pos + close].split(',')
+            .map(|n| n.trim().parse::<Lengthf32>().map_err(|e| self.error(&format!("{e}"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let roi = basic_roi(&name, &n).map_err(|e| self.error(&e))?;
+        self.pos += close + 1;
+        Ok(roi)
+    }
+}
+
+/// Basic shape called `name`, with parameters `n` in mm
+fn basic_roi(name: &str, n: &[Lengthf32]) -> Result<ROI, String> {
+    Ok(match (name, n) {
+        ("sphere"   , &[x, y, z, r]) => ROI::Sphere   ((mm(x), mm(y), mm(z)), mm(r)),
+        ("cylinderx", &[y, z   , r]) => ROI::CylinderX((mm(y), mm(z))       , mm(r)),
+        ("cylindery", &[x, z   , r]) => ROI::CylinderY((mm(x), mm(z))       , mm(r)),
+        ("cylinderz", &[x, y   , r]) => ROI::CylinderZ((mm(x), mm(y))       , mm(r)),
+        ("discz"    , &[x, y, z, r]) => ROI::DiscZ    ((mm(x), mm(y), mm(z)), mm(r)),
+        ("box", &[x0, y0, z0, x1, y1, z1]) => ROI::cuboid((mm(x0), mm(y0), mm(z0)), (mm(x1), mm(y1), mm(z1))),
+        ("sphere" | "discz", _)                       => return Err(format!("{name} needs 4 values: x,y,z,r")),
+        ("cylinderx" | "cylindery" | "cylinderz", _) => return Err(format!("{name} needs 3 values: 2 centre coordinates and r")),
+        ("box", _)                                    => return Err("box needs 6 values: x0,y0,z0,x1,y1,z1".into()),
+        (other, _)                                    => return Err(format!("unknown shape '{other}'")),
+    })
 }
 
 /// A 3D point with an associated value. Used to represent voxels
@@ -75,13 +383,9 @@ pub type PointValue = (Point, Intensityf32);
 impl Image {
 
     pub fn values_inside_roi(&self, roi: ROI) -> ImageData {
-        let mut out = vec![];
-        let roi_contains = roi.contains_fn();
-        for (index, value) in self.data.iter().copied().enumerate() {
-            let p = self.fov.voxel_centre1(index);
-            if roi_contains(p) { out.push(value) }
-        }
-        out
+        roi.voxel_indices(self.fov).into_iter()
+            .map(|index| self.data[index])
+            .collect()
     }
 
     pub fn values_with_positions(&self) -> Vec<PointValue> {
@@ -158,7 +462,7 @@ mod test_in_roi {
         let centre = (mm(centre.0), mm(centre.1), mm(centre.2));
         let r = mm(r);
         let data = vec![1.0; n*n*n];
-        let fov = FOV::new((l,l,l), (n,n,n));
+        let fov = FOV::new_from_full_widths((l,l,l), (n,n,n));
         let image = Image::new(fov, data);
         let inside = image.values_inside_roi(ROI::Sphere(centre, r));
         println!("{} {}", inside.len(), expected_len);
@@ -182,7 +486,7 @@ mod test_in_roi {
         let centre = (mm(centre.0), mm(centre.1));
         let r = mm(r);
         let data = vec![1.0; n*n*n];
-        let fov = FOV::new((l,l,l), (n,n,n));
+        let fov = FOV::new_from_full_widths((l,l,l), (n,n,n));
         let image = Image::new(fov, data);
         let inside = image.values_inside_roi(ROI::CylinderZ(centre, r));
         println!("{} {}", inside.len(), expected_len);
@@ -223,7 +527,7 @@ mod test_in_roi {
     fn which_voxels(roi: ROI, expected: usize) {
         let data = vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0];
         let (l, n) = (4.0, 2);
-        let fov = FOV::new((mm(l),mm(l),mm(l)), (n,n,n));
+        let fov = FOV::new_from_full_widths((mm(l),mm(l),mm(l)), (n,n,n));
         let image = Image::new(fov, data);
         let pattern = image.values_inside_roi(roi)
             .iter().sum::<Intensityf32>()
@@ -234,6 +538,125 @@ mod test_in_roi {
     }
 }
 
+#[cfg(test)]
+mod test_roi_algebra {
+    use super::*;
+    use rstest::rstest;
+
+    fn p(x: f32, y: f32, z: f32) -> Point { Point::new(mm(x), mm(y), mm(z)) }
+    fn roi(s: &str) -> ROI { s.parse().unwrap() }
+
+    #[rstest(/**/ point           , expected,
+             case(( 0.0, 0.0, 0.0), false), // inside the hole
+             case(( 4.0, 0.0, 0.0), true ),
+             case(( 0.0, 3.0, 8.0), true ),
+             case(( 0.0, 0.0, 8.0), false), // hole runs along the whole z-axis
+             case(( 0.0, 0.0,11.0), false), // outside the sphere
+    )]
+    fn difference_membership(point: (f32, f32, f32), expected: bool) {
+        let inside = roi("sphere(0,0,0,10) - cylinderz(0,0,2)").contains_fn();
+        let (x, y, z) = point;
+        assert_eq!(inside(p(x, y, z)), expected);
+    }
+
+    #[rstest(/**/ point             , expected,
+             case((  0.0,  0.0, 0.0), true ),
+             case(( 19.0, 19.0, 0.0), true ),
+             case(( 10.0, 10.0, 0.0), false),
+             case((-19.0, 19.0, 0.0), false),
+    )]
+    fn union_membership(point: (f32, f32, f32), expected: bool) {
+        let inside = roi("sphere(0,0,0,5) | box(15,15,-1,20,20,1)").contains_fn();
+        let (x, y, z) = point;
+        assert_eq!(inside(p(x, y, z)), expected);
+    }
+
+    #[test]
+    fn inclusion_exclusion() {
+        let fov = FOV::new_from_full_widths((mm(40.0), mm(40.0), mm(40.0)), (20, 20, 20));
+        let (a, b) = (roi("sphere(-3,2,1,9)"), roi("box(-2,-10,-4,12,5,8) - cylinderx(0,0,1.5)"));
+        let count = |r: ROI| r.voxel_indices(fov).len();
+        let union        = ROI::Union       (vec![a.clone(), b.clone()]);
+        let intersection = ROI::Intersection(vec![a.clone(), b.clone()]);
+        let (n_a, n_b, n_ab) = (count(a), count(b), count(intersection));
+        assert!(n_ab > 0 && n_ab < n_a.min(n_b));
+        assert_eq!(count(union), n_a + n_b - n_ab);
+    }
+
+    #[rstest(/**/ expr,
+             case("sphere(0,57.2,0,5)"),
+             case("box(-1,-2,-3,4,5,6) - discz(0,0,0,1.5)"),
+             case("sphere(1,2,3,4) | cylinderx(0,0,1) | cylindery(0,0,1) & cylinderz(0,0,2)"),
+             case("(sphere(0,0,0,5) | sphere(9,0,0,5)) & box(-20,-20,-1,20,20,1)"),
+             case("sphere(0,0,0,9) - (sphere(0,0,0,5) - sphere(0,0,0,2))"),
+             case("sphere(0,0,0,1) | (sphere(0,0,0,2) | sphere(0,0,0,3))"),
+    )]
+    fn parse_round_trip(expr: &str) {
+        let parsed = roi(expr);
+        assert_eq!(roi(&parsed.to_string()), parsed);
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        let (a, b, c) = (roi("sphere(0,0,0,1)"), roi("sphere(0,0,0,2)"), roi("sphere(0,0,0,3)"));
+        let diff = |x: &ROI, y: &ROI| ROI::Difference(Box::new(x.clone()), Box::new(y.clone()));
+        let and  = ROI::Intersection(vec![b.clone(), c.clone()]);
+        assert_eq!(roi("sphere(0,0,0,1) - sphere(0,0,0,2) - sphere(0,0,0,3)"), diff(&diff(&a, &b), &c));
+        assert_eq!(roi("sphere(0,0,0,1) | sphere(0,0,0,2) & sphere(0,0,0,3)"), ROI::Union(vec![a, and]));
+    }
+
+    #[test]
+    fn translation_moves_every_component() {
+        let offset = (mm(1.0), mm(-2.0), mm(4.0));
+        let moved = roi("(sphere(0,0,0,5) | box(1,2,3,4,5,6)) - cylinderz(1,1,1) & discz(0,0,2,3)").translated(offset);
+        assert_eq!(moved, roi("(sphere(1,-2,4,5) | box(2,0,7,5,3,10)) - cylinderz(2,-1,1) & discz(1,-2,6,3)"));
+        let config = FomConfig::new(vec![(roi("cylinderx(0,0,2)"), 4.0)], vec![roi("cylindery(0,0,2)")], 1.0).translated(offset);
+        assert_eq!(config.rois[0].0, roi("cylinderx(-2,4,2)"));
+        assert_eq!(config.background_rois[0], roi("cylindery(1,4,2)"));
+    }
+
+    #[rstest(/**/ expr,
+             case("sphere(0,0,0)"),
+             case("cube(0,0,0,1)"),
+             case("sphere(0,0,0,1) |"),
+             case("(sphere(0,0,0,1)"),
+             case("sphere(0,0,0,1) sphere(0,0,0,2)"),
+    )]
+    fn parse_errors(expr: &str) {
+        assert!(expr.parse::<ROI>().is_err());
+    }
+
+    #[test]
+    fn pruned_sweep_matches_brute_force() {
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(20.0), mm(10.0)), (15, 10, 5));
+        let r = roi("(discz(3,3,0,4) | box(-12,-9,-5,-6,0,5)) - sphere(-9,-4,0,2)");
+        let inside = r.contains_fn();
+        let brute: Vec<_> = (0..15*10*5).filter(|&i| inside(fov.voxel_centre1(i))).collect();
+        assert!(!brute.is_empty());
+        assert_eq!(r.voxel_indices(fov), brute);
+    }
+
+    #[test]
+    fn combinations_in_toml() {
+        let config: FomConfig = r#"
+            background_activity = 1.0
+            [[roi]]
+            shape    = "union"
+            of       = [{ shape = "sphere", centre = [0.0, 0.0, 0.0], r = 5.0 },
+                        { shape = "box", min = [6.0, 6.0, 6.0], max = [1.0, 1.0, 1.0] }]
+            activity = 4.0
+            [[background]]
+            shape = "difference"
+            from  = { shape = "cylinderz", centre = [0.0, 0.0], r = 10.0 }
+            minus = { shape = "expr", expr = "sphere(0,0,0,5)" }
+        "#.parse().unwrap();
+        assert_eq!(config.rois[0].0, roi("sphere(0,0,0,5) | box(1,1,1,6,6,6)"));
+        assert_eq!(config.background_rois[0], roi("cylinderz(0,0,10) - sphere(0,0,0,5)"));
+        let bad = "background_activity = 1.0\n[[background]]\nshape = \"expr\"\nexpr = \"cube(1)\"\n";
+        assert!(bad.parse::<FomConfig>().is_err());
+    }
+}
+
 // TODO stop reinventing this wheel
 pub fn mean(data: &[Intensityf32]) -> Option<Intensityf32> {
     data.iter().cloned().reduce(|a, b| a+b).map(|s| s / data.len() as Intensityf32)
@@ -301,6 +724,110 @@ impl FomConfig {
     pub fn new(rois: Vec<(ROI, Intensityf32)>, background_rois: Vec<ROI>, background_activity: Intensityf32) -> Self {
         Self{ rois, background_rois, background_activity }
     }
+
+    /// Read ROI definitions from a TOML file. Lengths are in mm:
+    ///
+    /// ```toml
+    /// background_activity = 1.0
+    ///
+    /// [[roi]]
+    /// shape    = "sphere"
+    /// centre   = [0.0, 57.2, 0.0]
+    /// r        = 5.0
+    /// activity = 4.0
+    ///
+    /// [[background]]
+    /// shape  = "cylinderz"
+    /// centre = [0.0, -80.0]
+    /// r      = 10.0
+    ///
+    /// [[background]]
+    /// shape = "difference"
+    /// from  = { shape = "box", min = [-90.0, 60.0, -5.0], max = [90.0, 80.0, 5.0] }
+    /// minus = { shape = "expr", expr = "sphere(0,70,0,5) | sphere(50,70,0,5)" }
+    /// ```
+    ///
+    /// Shapes `union` and `intersection` take a list `of` ROIs; `expr` takes
+    /// the compact syntax parsed by `ROI`'s `FromStr`.
+    pub fn load(path: impl AsRef<std::path::Path>) -> BoxErr<Self> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    /// The same configuration, with all ROIs displaced by `offset`
+    pub fn translated(&self, offset: (Length, Length, Length)) -> Self {
+        Self {
+            rois: self.rois.iter().map(|(roi, activity)| (roi.translated(offset), *activity)).collect(),
+            background_rois: self.background_rois.iter().map(|roi| roi.translated(offset)).collect(),
+            background_activity: self.background_activity,
+        }
+    }
+}
+
+impl std::str::FromStr for FomConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec: FomConfigSpec = toml::from_str(s).map_err(|e| format!("Invalid ROI definition: {e}"))?;
+        if spec.background.is_empty() { return Err("No background ROIs defined".into()) }
+        Ok(Self {
+            rois: spec.roi.into_iter()
+                .map(|ActiveRoiSpec { roi, activity }| Ok((roi.try_into()?, activity)))
+                .collect::<Result<_, String>>()?,
+            background_rois: spec.background.into_iter().map(ROI::try_from).collect::<Result<_, _>>()?,
+            background_activity: spec.background_activity,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct FomConfigSpec {
+    background_activity: Intensityf32,
+    #[serde(default)]
+    roi: Vec<ActiveRoiSpec>,
+    #[serde(default)]
+    background: Vec<RoiSpec>,
+}
+
+#[derive(serde::Deserialize)]
+struct ActiveRoiSpec {
+    #[serde(flatten)]
+    roi: RoiSpec,
+    activity: Intensityf32,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+enum RoiSpec {
+    Sphere    { centre: [f32; 3], r: f32 },
+    CylinderX { centre: [f32; 2], r: f32 },
+    CylinderY { centre: [f32; 2], r: f32 },
+    CylinderZ { centre: [f32; 2], r: f32 },
+    DiscZ     { centre: [f32; 3], r: f32 },
+    Box       { min: [f32; 3], max: [f32; 3] },
+    Union        { of: Vec<RoiSpec> },
+    Intersection { of: Vec<RoiSpec> },
+    Difference   { from: Box<RoiSpec>, minus: Box<RoiSpec> },
+    Expr         { expr: String },
+}
+
+impl TryFrom<RoiSpec> for ROI {
+    type Error = String;
+
+    fn try_from(spec: RoiSpec) -> Result<Self, Self::Error> {
+        let all = |specs: Vec<RoiSpec>| specs.into_iter().map(ROI::try_from).collect::<Result<Vec<_>, _>>();
+        Ok(match spec {
+            RoiSpec::Sphere    { centre: [x, y, z], r } => ROI::Sphere   ((mm(x), mm(y), mm(z)), mm(r)),
+            RoiSpec::CylinderX { centre: [y, z]   , r } => ROI::CylinderX((mm(y), mm(z))       , mm(r)),
+            RoiSpec::CylinderY { centre: [x, z]   , r } => ROI::CylinderY((mm(x), mm(z))       , mm(r)),
+            RoiSpec::CylinderZ { centre: [x, y]   , r } => ROI::CylinderZ((mm(x), mm(y))       , mm(r)),
+            RoiSpec::DiscZ     { centre: [x, y, z], r } => ROI::DiscZ    ((mm(x), mm(y), mm(z)), mm(r)),
+            RoiSpec::Box { min: [x0, y0, z0], max: [x1, y1, z1] } => ROI::cuboid((mm(x0), mm(y0), mm(z0)), (mm(x1), mm(y1), mm(z1))),
+            RoiSpec::Union        { of } => ROI::Union       (all(of)?),
+            RoiSpec::Intersection { of } => ROI::Intersection(all(of)?),
+            RoiSpec::Difference { from, minus } => ROI::Difference(Box::new((*from).try_into()?), Box::new((*minus).try_into()?)),
+            RoiSpec::Expr { expr } => expr.parse()?,
+        })
+    }
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -1,14 +1,17 @@
 /// The size and granularity of the Field of View (FOV) in which images should
 /// be reconstructed
 
+use serde::{Deserialize, Serialize};
+
 use crate::{Lengthf32, Pointf32};
 use crate::{Length, Point, Vector, LOR, find_tof_peak, find_entry_point, voxel_size, first_boundaries};
 use crate::index::{BoxDim_u, Index3_u, Index1_u, index1_to_3, index3_to_1};
-use geometry::units::mm_;
+use geometry::units::{mm, mm_};
 use geometry::RatioPoint;
 use geometry::uom::ConstZero;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(into = "FovRecord", try_from = "FovRecord")]
 pub struct FOV {
     pub half_width: Vector,
     pub n: BoxDim_u,
@@ -17,15 +20,32 @@ pub struct FOV {
 
 impl FOV {
 
-    pub fn new(
+    #[deprecated(note = "ambiguous about full vs half widths: use `FovBuilder` or `FOV::new_from_full_widths`")]
+    pub fn new(full_size: (Length, Length, Length), n: (usize, usize, usize)) -> Self {
+        Self::new_from_full_widths(full_size, n)
+    }
+
+    /// FOV spanning `-full_size/2` to `full_size/2` along each axis, without
+    /// validation (see `FovBuilder`)
+    pub fn new_from_full_widths(
         full_size: (Length, Length, Length),
         (nx, ny, nz): (usize, usize, usize)
     ) -> Self {
         let (dx, dy, dz) = full_size;
-        let half_width = Vector::new(dx/2.0, dy/2.0, dz/2.0);
+        Self::new_from_half_widths((dx/2.0, dy/2.0, dz/2.0), (nx, ny, nz))
+    }
+
+    /// FOV spanning `-half_size` to `half_size` along each axis, without
+    /// validation (see `FovBuilder`)
+    pub fn new_from_half_widths(
+        half_size: (Length, Length, Length),
+        (nx, ny, nz): (usize, usize, usize)
+    ) -> Self {
+        let (hx, hy, hz) = half_size;
+        let half_width = Vector::new(hx, hy, hz);
         let n = [nx, ny, nz];
         let voxel_size = Self::voxel_size(n, half_width);
-            Self { half_width, n, voxel_size }
+        Self { half_width, n, voxel_size }
     }
 
     fn voxel_size(n: BoxDim_u, half_width: Vector) -> Vector {
@@ -50,8 +70,41 @@ impl FOV {
         self.voxel_centre(index1_to_3(i, self.n))
     }
 
+    /// World coordinates of the voxel centres along `axis` (0, 1, 2 for x, y, z)
+    pub fn axis_coordinates(&self, axis: usize) -> Vec<Length> {
+        let (s, h) = (self.voxel_size[axis], self.half_width[axis]);
+        (0..self.n[axis]).map(|i| (i as Lengthf32 + 0.5) * s - h).collect()
+    }
+
+    /// Position of `p` expressed in voxel units, with the origin at the
+    /// lowest corner of the FOV: voxel `i` spans `[i, i+1)` along each axis.
+    /// `None` if `p` lies outside the FOV.
+    pub fn voxel_coordinates(&self, p: Point) -> Option<[Lengthf32; 3]> {
+        let coord = |d: usize| mm_(p[d] + self.half_width[d]) / mm_(self.voxel_size[d]);
+        let coords = [coord(0), coord(1), coord(2)];
+        let outside = coords.iter().zip(self.n).any(|(&c, n)| c < 0.0 || c > n as Lengthf32);
+        if outside { None } else { Some(coords) }
+    }
+
+    /// The FOV grown by `margin` on every side, with the same number of voxels
+    pub fn expanded_by(&self, margin: Length) -> Self {
+        let h = self.half_width;
+        Self::new_from_half_widths((h.x + margin, h.y + margin, h.z + margin), (self.n[0], self.n[1], self.n[2]))
+    }
+
+    /// Whether `p` lies inside the FOV, or on its surface
+    pub fn contains(&self, p: Point) -> bool {
+        (0..3).all(|d| p[d].abs() <= self.half_width[d])
+    }
+
+    /// Where the LOR from `p1` towards `p2` enters the FOV: `p1` itself, if it
+    /// lies inside. `None` if the segment between them misses the FOV.
     pub fn entry(&self, p1: Point, p2: Point) -> Option<Point> {
 
+        // The ray cast below only finds the boundary, so a LOR which starts
+        // inside would appear to enter where it leaves
+        if self.contains(p1) { return Some(p1) }
+
         use ncollide3d::query::RayCast;
         use ncollide3d::shape::Cuboid;
 
@@ -60,6 +113,7 @@ impl FOV {
 
         let lor_direction = (p2 - p1).normalize();
         let lor_length    = (p2 - p1).norm();
+        if lor_length == Length::ZERO { return None }
         let lor: Ray = Ray::new(p1.into(), lor_direction.into());
         let iso: Isometry = Isometry::identity();
         Cuboid::new(self.half_width.into())
@@ -70,6 +124,215 @@ impl FOV {
 
 }
 
+/// `FOV 300×300×200 mm, 151×151×101 voxels of 1.99 mm`: dimensions which are
+/// the same along every axis are shown once
+impl std::fmt::Display for FOV {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [wx, wy, wz] = [self.half_width.x, self.half_width.y, self.half_width.z].map(|h| compact(mm_(h) * 2.0));
+        let [vx, vy, vz] = [self.voxel_size.x, self.voxel_size.y, self.voxel_size.z].map(|v| compact(mm_(v)));
+        let [nx, ny, nz] = self.n;
+        let voxels = if nx == ny && ny == nz { format!("{nx}³") } else { format!("{nx}×{ny}×{nz}") };
+        let voxel_size = if vx == vy && vy == vz { vx } else { format!("{vx}×{vy}×{vz}") };
+        write!(f, "FOV {wx}×{wy}×{wz} mm, {voxels} voxels of {voxel_size} mm")
+    }
+}
+
+/// `x` to two decimal places, without trailing zeros
+pub(crate) fn compact(x: f32) -> String {
+    let s = format!("{x:.2}");
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Construction of a validated `FOV`, stating explicitly whether its sizes are
+/// full widths or half widths:
+///
+///     FovBuilder::full_widths(mm(300.0), mm(300.0), mm(200.0)).voxels(151, 151, 101).build()
+#[derive(Clone, Copy, Debug)]
+pub struct FovBuilder {
+    half_width: (Length, Length, Length),
+    n: (usize, usize, usize),
+}
+
+impl FovBuilder {
+    /// FOV spanning `-dx/2` to `dx/2` in x, etc.
+    pub fn full_widths(dx: Length, dy: Length, dz: Length) -> Self {
+        Self::half_widths(dx / 2.0, dy / 2.0, dz / 2.0)
+    }
+
+    /// FOV spanning `-hx` to `hx` in x, etc.
+    pub fn half_widths(hx: Length, hy: Length, hz: Length) -> Self {
+        Self { half_width: (hx, hy, hz), n: (1, 1, 1) }
+    }
+
+    /// Number of voxels along each axis [default: 1]
+    pub fn voxels(self, nx: usize, ny: usize, nz: usize) -> Self { Self { n: (nx, ny, nz), ..self } }
+
+    pub fn build(self) -> Result<FOV, String> {
+        let (hx, hy, hz) = self.half_width;
+        for (axis, h) in [("x", hx), ("y", hy), ("z", hz)] {
+            let width = 2.0 * mm_(h);
+            if !(width.is_finite() && width > 0.0) {
+                return Err(format!("FOV full width in {axis} must be positive and finite, got {width} mm"))
+            }
+        }
+        let (nx, ny, nz) = self.n;
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(format!("FOV must have at least one voxel along each axis, got {nx} x {ny} x {nz}"))
+        }
+        Ok(FOV::new_from_half_widths(self.half_width, self.n))
+    }
+}
+
+/// Version of `FovRecord` written by this build
+pub const FOV_SCHEMA_VERSION: u32 = 1;
+
+fn fov_schema_version() -> u32 { FOV_SCHEMA_VERSION }
+
+/// How `FOV` is (de)serialized: plain numbers, independent of its internal
+/// representation. Widths are `f64`, so that files written by builds of either
+/// precision read back to within rounding. Unknown fields are ignored, and a
+/// missing `version` is taken to be 1, so that files from newer and older
+/// versions remain readable.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FovRecord {
+    #[serde(default = "fov_schema_version")]
+    pub version: u32,
+    pub full_width_mm: [f64; 3],
+    pub n_voxels: [usize; 3],
+}
+
+impl From<FOV> for FovRecord {
+    fn from(fov: FOV) -> Self {
+        let h = fov.half_width;
+        Self {
+            version: FOV_SCHEMA_VERSION,
+            full_width_mm: [h.x, h.y, h.z].map(|h| 2.0 * mm_(h) as f64),
+            n_voxels: fov.n,
+        }
+    }
+}
+
+/// Validated as by `FovBuilder`
+impl TryFrom<FovRecord> for FOV {
+    type Error = String;
+    fn try_from(FovRecord { full_width_mm: [dx, dy, dz], n_voxels: [nx, ny, nz], .. }: FovRecord) -> Result<Self, Self::Error> {
+        FovBuilder::full_widths(mm(dx as f32), mm(dy as f32), mm(dz as f32)).voxels(nx, ny, nz).build()
+    }
+}
+
+/// Half widths (mm), voxel counts and voxel sizes (mm) of `fov`, for
+/// comparing FOVs in tests
+#[cfg(test)]
+fn fields(fov: FOV) -> ([f32; 3], [usize; 3], [f32; 3]) {
+    let v = |v: Vector| [mm_(v.x), mm_(v.y), mm_(v.z)];
+    (v(fov.half_width), fov.n, v(fov.voxel_size))
+}
+
+#[cfg(test)]
+mod test_fov_serde {
+    use super::*;
+    use float_eq::assert_float_eq;
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(300.0), mm(300.0), mm(200.0)), (151, 151, 101)) }
+
+    #[test]
+    fn json_is_plain_numbers_in_mm() -> Result<(), serde_json::Error> {
+        let json = serde_json::to_value(fov())?;
+        assert_eq!(json, serde_json::json!({"version": 1, "full_width_mm": [300.0, 300.0, 200.0], "n_voxels": [151, 151, 101]}));
+        let back: FOV = serde_json::from_value(json)?;
+        assert_eq!(fields(back), fields(fov()));
+        Ok(())
+    }
+
+    #[test]
+    fn roundtrip_through_bincode() -> Result<(), bincode::Error> {
+        let back: FOV = bincode::deserialize(&bincode::serialize(&fov())?)?;
+        assert_eq!(fields(back), fields(fov()));
+        Ok(())
+    }
+
+    #[test]
+    fn widths_written_in_f64_are_read_within_rounding() -> Result<(), serde_json::Error> {
+        // 0.1 mm voxels, as computed in double precision
+        let widths = [0.1_f64 * 3.0, 0.1 * 7.0, 0.1 * 11.0];
+        let text = serde_json::json!({"version": 1, "full_width_mm": widths, "n_voxels": [3, 7, 11]});
+        let fov: FOV = serde_json::from_value(text)?;
+        let (half_width, _, voxel_size) = fields(fov);
+        assert_float_eq!(half_width, [0.15, 0.35, 0.55], rmax <= [1e-6; 3]);
+        assert_float_eq!(voxel_size, [0.1; 3], rmax <= [1e-6; 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn old_and_new_versions_are_readable() -> Result<(), serde_json::Error> {
+        let without_version = r#"{"full_width_mm": [300, 300, 200], "n_voxels": [151, 151, 101]}"#;
+        assert_eq!(fields(serde_json::from_str(without_version)?), fields(fov()));
+        let with_unknown_fields = r#"{"version": 2, "full_width_mm": [300, 300, 200], "n_voxels": [151, 151, 101], "origin_mm": [0, 0, 5]}"#;
+        assert_eq!(fields(serde_json::from_str(with_unknown_fields)?), fields(fov()));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_fovs_are_rejected() {
+        let parse = |text: &str| serde_json::from_str::<FOV>(text).map_err(|e| e.to_string());
+        assert!(parse(r#"{"full_width_mm": [0, 300, 200], "n_voxels": [151, 151, 101]}"#).unwrap_err().contains("in x"));
+        assert!(parse(r#"{"full_width_mm": [300, 300, 200], "n_voxels": [151, 0, 101]}"#).unwrap_err().contains("voxel"));
+        assert!(parse(r#"{"full_width_mm": [300, 300, 200]}"#).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_fov_display {
+    use super::*;
+    use geometry::units::mm;
+    use rstest::rstest;
+
+    #[rstest(/**/ full                 , n              , expected,
+             case((300.0, 300.0, 300.0), (151, 151, 151), "FOV 300×300×300 mm, 151³ voxels of 1.99 mm"),
+             case((300.0, 300.0, 200.0), (151, 151, 101), "FOV 300×300×200 mm, 151×151×101 voxels of 1.99×1.99×1.98 mm"),
+             case(( 60.0,  60.0, 180.0), (  6,   6,  18), "FOV 60×60×180 mm, 6×6×18 voxels of 10 mm"),
+             case((  2.5,   1.0,   1.0), (  2,   1,   1), "FOV 2.5×1×1 mm, 2×1×1 voxels of 1.25×1×1 mm"),
+    )]
+    fn display(full: (f32, f32, f32), n: (usize, usize, usize), expected: &str) {
+        let (dx, dy, dz) = full;
+        assert_eq!(FOV::new_from_full_widths((mm(dx), mm(dy), mm(dz)), n).to_string(), expected);
+    }
+}
+
+#[cfg(test)]
+mod test_fov_builder {
+    use super::*;
+    use geometry::units::mm;
+    use rstest::rstest;
+
+    #[rstest(/**/ full           , n,
+             case((300.0, 300.0, 200.0), (151, 151, 101)),
+             case((  3.0,   1.0,   1.0), (  3,   1,   1)),
+             case(( 60.0,  60.0, 180.0), (  6,   6,  12)),
+    )]
+    fn full_and_half_widths_agree(full: (f32, f32, f32), n: (usize, usize, usize)) {
+        let (dx, dy, dz) = full;
+        let (nx, ny, nz) = n;
+        let from_full = FovBuilder::full_widths(mm(dx), mm(dy), mm(dz)).voxels(nx, ny, nz).build().unwrap();
+        let from_half = FovBuilder::half_widths(mm(dx / 2.0), mm(dy / 2.0), mm(dz / 2.0)).voxels(nx, ny, nz).build().unwrap();
+        assert_eq!(fields(from_full), fields(from_half));
+        assert_eq!(fields(from_full), fields(FOV::new_from_full_widths((mm(dx), mm(dy), mm(dz)), n)));
+        assert_eq!(fields(from_full), fields(FOV::new_from_half_widths((mm(dx / 2.0), mm(dy / 2.0), mm(dz / 2.0)), n)));
+        // The full width really is the full width
+        assert_eq!(mm_(from_full.half_width.x) * 2.0, dx);
+    }
+
+    #[test]
+    fn invalid_geometry_is_rejected() {
+        let full = |x: f32| FovBuilder::full_widths(mm(x), mm(10.0), mm(10.0));
+        assert!(full(0.0).build().unwrap_err().contains("in x"));
+        assert!(full(-5.0).build().is_err());
+        assert!(full(f32::NAN).build().is_err());
+        assert!(full(10.0).voxels(2, 0, 2).build().unwrap_err().contains("voxel"));
+        assert!(full(10.0).build().is_ok());
+    }
+}
+
 #[cfg(test)]
 mod test_fov {
     use super::*;
@@ -88,11 +351,42 @@ mod test_fov {
              case([1,1,1], [ 1.0,  1.0,  1.0]),
     )]
     fn test_voxel_centre(index: Index3_u, expected_position: [f32; 3]) {
-        let fov = FOV::new((mm(4.0), mm(4.0), mm(4.0)), (2,2,2));
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(4.0), mm(4.0)), (2,2,2));
         let c = fov.voxel_centre(index);
         let c = [mm_(c.x), mm_(c.y), mm_(c.z)];
         assert_float_eq!(c, expected_position, ulps <= [1, 1, 1]);
     }
+
+    #[test]
+    fn lors_starting_inside_enter_where_they_start() {
+        let fov = FOV::new_from_full_widths((mm(20.0), mm(20.0), mm(20.0)), (2, 2, 2));
+        let p = |x, y, z| Point::new(mm(x), mm(y), mm(z));
+        let (inside, outside, beyond) = (p(1.0, 2.0, 3.0), p(-30.0, 2.0, 3.0), p(-40.0, 2.0, 3.0));
+        assert!(fov.contains(inside) && !fov.contains(outside));
+        assert_eq!(fov.entry(inside, outside), Some(inside));
+        assert_eq!(fov.entry(inside, inside ), Some(inside));
+        let entry = fov.entry(outside, inside).unwrap();
+        assert_float_eq!([mm_(entry.x), mm_(entry.y), mm_(entry.z)], [-10.0, 2.0, 3.0], abs <= [1e-5; 3]);
+        assert_eq!(fov.entry(beyond, outside), None);
+        assert_eq!(fov.entry(outside, outside), None);
+    }
+
+    #[test]
+    fn axis_coordinates_are_voxel_centres() {
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(8.0), mm(50.0)), (15, 4, 25));
+        for axis in 0..3 {
+            let coords: Vec<f32> = fov.axis_coordinates(axis).into_iter().map(mm_).collect();
+            assert_eq!(coords.len(), fov.n[axis]);
+            let edge = mm_(fov.half_width[axis] - fov.voxel_size[axis] / 2.0);
+            assert_float_eq!(coords[0]              , -edge, abs <= 1e-5);
+            assert_float_eq!(coords[fov.n[axis] - 1],  edge, abs <= 1e-5);
+            for (i, &c) in coords.iter().enumerate() {
+                let mut index = [0; 3];
+                index[axis] = i;
+                assert_eq!(c, mm_(fov.voxel_centre(index)[axis]));
+            }
+        }
+    }
 }
 
 #[inline(always)]
@@ -204,6 +498,10 @@ pub struct FovHit {
 
     /// Distance to the peak of the TOF gaussian.
     pub tof_peak     : Length,
+
+    /// Distance from the entry point to the end of the LOR. Traversal stops
+    /// here, if the LOR ends inside the FOV.
+    pub end          : Length,
 }
 
 /// Figure out if the LOR hits the FOV at all. If it does, calculate values
@@ -227,6 +525,9 @@ pub fn lor_fov_hit(lor: &LOR, fov: FOV) -> Option<FovHit> {
     // How far the entry point is from the TOF peak
     let tof_peak = find_tof_peak(entry_point, p1, p2, lor.dt);
 
+    // How far the LOR extends beyond the entry point
+    let end = (p2 - entry_point).norm();
+
     // Express entry point in voxel coordinates: floor(position) = index of voxel.
     let entry_point: RatioPoint = find_entry_point(entry_point, fov);
 
@@ -247,5 +548,100 @@ pub fn lor_fov_hit(lor: &LOR, fov: FOV) -> Option<FovHit> {
 
     // Return the values needed by `system_matrix_elements`
     let tof_peak = tof_peak;
-    Some(FovHit { next_boundary, voxel_size, index, delta_index, remaining, tof_peak } )
+    Some(FovHit { next_boundary, voxel_size, index, delta_index, remaining, tof_peak, end } )
+}
+
+/// What `clip_lors_to_fov` did to the LORs it was given
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ClipStats {
+    pub lors: usize,
+    /// Missing the FOV entirely
+    pub dropped: usize,
+    /// Whose endpoints were moved to where they enter and leave the FOV
+    pub truncated: usize,
+}
+
+impl std::fmt::Display for ClipStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use crate::utils::{group_digits, stats::format_fraction};
+        write!(f, "dropped {} LORs which miss the FOV", format_fraction(self.dropped, self.lors))?;
+        if self.truncated > 0 { write!(f, ", truncated {} to the FOV", group_digits(self.truncated))? }
+        Ok(())
+    }
+}
+
+/// Remove the LORs which miss `fov`, and so contribute nothing to the
+/// reconstruction. If `truncate`, move the endpoints of the rest to where they
+/// enter and leave `fov`, adjusting `dt` so that the TOF peak stays in place:
+/// the system matrix elements of a LOR do not change.
+pub fn clip_lors_to_fov(lors: Vec<LOR>, fov: &FOV, truncate: bool) -> (Vec<LOR>, ClipStats) {
+    let mut stats = ClipStats { lors: lors.len(), ..ClipStats::default() };
+    let clipped: Vec<LOR> = lors.into_iter()
+        .filter_map(|lor| {
+            let entry = fov.entry(lor.p1, lor.p2)?;
+            if !truncate { return Some(lor) }
+            let exit = fov.entry(lor.p2, lor.p1)?;
+            // A LOR grazing an edge would be left without a direction
+            if entry == exit || (entry == lor.p1 && exit == lor.p2) { return Some(lor) }
+            stats.truncated += 1;
+            Some(truncated(lor, entry, exit))
+        })
+        .collect();
+    stats.dropped = stats.lors - clipped.len();
+    (clipped, stats)
+}
+
+/// `lor` between `entry` and `exit`, which lie on it. The TOF peak lies
+/// `(L/2 - c dt/2)` from `p1`, where `L` is the length of the LOR, so it stays
+/// in place if `dt` grows by `(d1 - d2)/c`, where `d1` and `d2` are the lengths
+/// cut from the `p1` and `p2` ends.
+fn truncated(lor: LOR, entry: Point, exit: Point) -> LOR {
+    let d1 = (entry - lor.p1).norm();
+    let d2 = (lor.p2 - exit).norm();
+    LOR { p1: entry, p2: exit, dt: lor.dt + (d1 - d2) / crate::C, ..lor }
+}
+
+#[cfg(test)]
+mod test_clip {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::{mm, ps};
+    use std::collections::BTreeMap;
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(100.0), mm(100.0), mm(100.0)), (10, 10, 10)) }
+
+    fn lor(dt: f32, (x1, y1, z1): (f32, f32, f32), (x2, y2, z2): (f32, f32, f32)) -> LOR {
+        LOR { dt: ps(dt), ..LOR::new(ps(0.0), ps(0.0), Point::new(mm(x1), mm(y1), mm(z1)), Point::new(mm(x2), mm(y2), mm(z2))) }
+    }
+
+    fn weights(lor: &LOR) -> BTreeMap<Index3_u, f32> {
+        lor.active_voxels(&fov(), None, Some(ps(100.0))).into_iter().collect()
+    }
+
+    #[test]
+    fn lors_beyond_the_axial_extent_are_dropped() {
+        let through = lor(0.0, (-200.0, 0.0,   0.0), (200.0, 0.0,   0.0));
+        let beyond  = lor(0.0, (-200.0, 0.0, 500.0), (200.0, 0.0, 500.0));
+        let (kept, stats) = clip_lors_to_fov(vec![through, beyond, through], &fov(), false);
+        assert_eq!(kept.iter().map(|lor| lor.p1).collect::<Vec<_>>(), vec![through.p1; 2]);
+        assert_eq!(stats, ClipStats { lors: 3, dropped: 1, truncated: 0 });
+        assert_eq!(stats.to_string(), "dropped 1 of 3 (33.3%) LORs which miss the FOV");
+    }
+
+    #[test]
+    fn truncation_preserves_weights() {
+        let original = lor(300.0, (-200.0, -30.0, -50.0), (200.0, 40.0, 60.0));
+        let (clipped, stats) = clip_lors_to_fov(vec![original], &fov(), true);
+        assert_eq!(stats, ClipStats { lors: 1, dropped: 0, truncated: 1 });
+        let clipped = clipped[0];
+        // The new endpoints lie on the faces at x = ±50 mm
+        assert_float_eq!([mm_(clipped.p1.x), mm_(clipped.p2.x)], [-50.0, 50.0], abs <= [1e-4; 2]);
+
+        let (before, after) = (weights(&original), weights(&clipped));
+        assert!(!before.is_empty());
+        assert_eq!(before.keys().collect::<Vec<_>>(), after.keys().collect::<Vec<_>>());
+        for (voxel, w) in before {
+            assert_float_eq!(after[&voxel], w, rmax <= 1e-3, "voxel {voxel:?}");
+        }
+    }
 }
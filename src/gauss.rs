@@ -1,16 +1,61 @@
 use crate::{Angle, Length, PerLength, Ratio, Time, TWOPI, C};
 
 use geometry::uom::ConstZero; // num_traits::Zero;
-use geometry::units::{mm, ratio_};
+use geometry::units::{mm, ratio, ratio_};
+
+/// Truncation of the TOF kernel. The kernel is zero outside `(-negative,
+/// positive)` sigma from the TOF peak, where positive distances point from
+/// `p1` towards `p2` of the LOR. Either limit may be negative, truncating the
+/// kernel before it reaches the peak on that side.
+///
+/// Asymmetric cutoffs therefore depend on the order of the endpoints, which is
+/// only meaningful if the LORs were read with canonical endpoints (see
+/// `system_matrix::endpoint_order`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TofCutoff {
+    pub negative: Ratio,
+    pub positive: Ratio,
+    /// Ignore voxels whose centres lie farther than this from the z-axis,
+    /// where no annihilations can occur
+    pub bore_radius: Option<Length>,
+}
+
+impl TofCutoff {
+    pub fn symmetric(k: Ratio) -> Self { Self { negative: k, positive: k, bore_radius: None } }
+
+    pub fn asymmetric(negative: Ratio, positive: Ratio) -> Self { Self { negative, positive, bore_radius: None } }
+
+    pub fn with_bore_radius(self, radius: Length) -> Self { Self { bore_radius: Some(radius), ..self } }
+}
+
+/// `k` for a symmetric cutoff, or `negative,positive`, in units of sigma
+impl std::str::FromStr for TofCutoff {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |k: &str| k.trim().parse::<f32>().map_err(|e| format!("{e} in TOF cutoff '{s}'"));
+        let cutoff = match s.split_once(',') {
+            None                       => Self::symmetric(ratio(parse(s)?)),
+            Some((negative, positive)) => Self::asymmetric(ratio(parse(negative)?), ratio(parse(positive)?)),
+        };
+        let width = ratio_(cutoff.negative + cutoff.positive);
+        if width.is_nan() || width <= 0.0 {
+            return Err(format!("TOF cutoff '{s}' leaves no part of the kernel"))
+        }
+        Ok(cutoff)
+    }
+}
 
 // How would you make this generic over Length -> T ?
-fn make_gauss(sigma: Length, cutoff: Option<Ratio>) -> impl Fn(Length) -> PerLength {
+fn make_gauss(sigma: Length, cutoff: Option<TofCutoff>) -> impl Fn(Length) -> PerLength {
     let two_pi: Angle = TWOPI;
     let root_two_pi: Ratio = two_pi.sqrt();
     let peak_height: PerLength = 1.0 / (sigma * root_two_pi);
-    let cutoff: Length = cutoff.map_or(mm(std::f32::INFINITY), |width| width * sigma);
+    let (below, above): (Length, Length) = match cutoff {
+        Some(TofCutoff { negative, positive, .. }) => (-(negative * sigma), positive * sigma),
+        None => (mm(-std::f32::INFINITY), mm(std::f32::INFINITY)),
+    };
     move |dx: Length| -> PerLength {
-        if dx.abs() < cutoff {
+        if below < dx && dx < above {
             let y: Ratio = dx / sigma;
             let z: Ratio = y * y;
             peak_height * ratio_(-0.5 * z).exp()
@@ -20,6 +65,77 @@ fn make_gauss(sigma: Length, cutoff: Option<Ratio>) -> impl Fn(Length) -> PerLen
     }
 }
 
-pub fn make_gauss_option(sigma: Option<Time>, cutoff: Option<Ratio>) -> Option<impl Fn(Length) -> PerLength> {
+pub fn make_gauss_option(sigma: Option<Time>, cutoff: Option<TofCutoff>) -> Option<impl Fn(Length) -> PerLength> {
     sigma.map(|sigma| make_gauss(sigma * C, cutoff))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geometry::units::{mm_, ps};
+    use rstest::rstest;
+
+    /// The kernel as it was before asymmetric cutoffs
+    fn symmetric_reference(sigma: Length, cutoff: Option<Ratio>) -> impl Fn(Length) -> PerLength {
+        let two_pi: Angle = TWOPI;
+        let root_two_pi: Ratio = two_pi.sqrt();
+        let peak_height: PerLength = 1.0 / (sigma * root_two_pi);
+        let cutoff: Length = cutoff.map_or(mm(std::f32::INFINITY), |width| width * sigma);
+        move |dx: Length| -> PerLength {
+            if dx.abs() < cutoff {
+                let y: Ratio = dx / sigma;
+                let z: Ratio = y * y;
+                peak_height * ratio_(-0.5 * z).exp()
+            } else {
+                PerLength::ZERO
+            }
+        }
+    }
+
+    #[rstest(k, case(None), case(Some(1.0)), case(Some(3.0)), case(Some(0.25)))]
+    fn symmetric_cutoff_is_unchanged(k: Option<f32>) {
+        let sigma = mm(20.0);
+        let new = make_gauss(sigma, k.map(|k| TofCutoff::symmetric(ratio(k))));
+        let old = symmetric_reference(sigma, k.map(ratio));
+        for i in -200..=200 {
+            let dx = mm(i as f32 * 0.5);
+            assert_eq!(new(dx), old(dx), "dx = {} mm", mm_(dx));
+        }
+    }
+
+    #[test]
+    fn asymmetric_cutoff_zeroes_the_correct_sides() {
+        let sigma = mm(10.0);
+        // 1 sigma before the peak, 3 sigma after it
+        let gauss = make_gauss(sigma, Some(TofCutoff::asymmetric(ratio(1.0), ratio(3.0))));
+        let nonzero = |dx: f32| gauss(mm(dx)) > PerLength::ZERO;
+        assert!( nonzero(  0.0));
+        assert!( nonzero( -9.9));
+        assert!(!nonzero(-10.1));
+        assert!( nonzero( 29.9));
+        assert!(!nonzero( 30.1));
+        // Negative: the kernel stops 1 sigma before the peak on the positive side
+        let gauss = make_gauss(sigma, Some(TofCutoff::asymmetric(ratio(3.0), ratio(-1.0))));
+        assert!(gauss(mm(-15.0)) > PerLength::ZERO);
+        assert_eq!(gauss(mm(-5.0)), PerLength::ZERO);
+        assert_eq!(gauss(mm( 0.0)), PerLength::ZERO);
+    }
+
+    #[test]
+    fn parse_cutoffs() {
+        assert_eq!("3".parse::<TofCutoff>(), Ok(TofCutoff::symmetric(ratio(3.0))));
+        assert_eq!("3,5".parse::<TofCutoff>(), Ok(TofCutoff::asymmetric(ratio(3.0), ratio(5.0))));
+        assert_eq!(" 2 , -1 ".parse::<TofCutoff>(), Ok(TofCutoff::asymmetric(ratio(2.0), ratio(-1.0))));
+        assert!("1,-1".parse::<TofCutoff>().is_err());
+        assert!("x".parse::<TofCutoff>().is_err());
+    }
+
+    #[test]
+    fn make_gauss_option_uses_speed_of_light() {
+        let gauss = make_gauss_option(Some(ps(100.0)), Some(TofCutoff::symmetric(ratio(1.0)))).unwrap();
+        // sigma = c * 100 ps ~ 30 mm
+        assert!(gauss(mm( 29.0)) > PerLength::ZERO);
+        assert_eq!(gauss(mm( 31.0)), PerLength::ZERO);
+        assert!(make_gauss_option(None, None).is_none());
+    }
+}
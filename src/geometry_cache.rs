@@ -0,0 +1,298 @@
+//! Memoization of system matrix rows, for data in which the same geometric LOR
+//! recurs many times: mashed sinograms, or step-and-shoot calibration scans.
+//! List-mode data rarely repeats, so the cache is off unless requested.
+//!
+//! LORs are keyed by their endpoint coordinates, rounded to a configurable
+//! granularity, and by their exact dt. With a granularity of zero only exact
+//! duplicates share an entry; otherwise every LOR in the same cell gets the row
+//! of the first one to be projected, which is an approximation. A cache is only
+//! valid for the FOV, TOF and tube settings with which it was filled, so each
+//! reconstruction should have its own.
+//!
+//! The cache is split into shards, each behind its own lock and bounded by an
+//! LRU policy, so that the threads of the parallel projector rarely contend.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use serde::Serialize;
+
+use crate::{memory, Index1_u, Length, Lengthf32};
+use crate::system_matrix::{RowSource, LOR};
+use crate::utils::group_digits;
+use geometry::units::{mm_, ns_};
+
+/// Upper limit on the number of shards
+const MAX_SHARDS: usize = 64;
+
+/// Quantized endpoints (x1 y1 z1 x2 y2 z2) and dt
+type Key = [i64; 7];
+
+/// The voxel indices and weights of a row, or `None` for LORs which miss the FOV
+type Row = Option<(Box<[Index1_u]>, Box<[Lengthf32]>)>;
+
+pub struct GeometryCache {
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    /// Cell size (mm) of the endpoint quantization; 0 for exact matching
+    granularity: f32,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+}
+
+/// Entries, and the order in which they were last used
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Key, (Row, u64)>,
+    by_use: BTreeMap<u64, Key>,
+    clock: u64,
+    capacity: usize,
+}
+
+/// Hit and miss counts of a `GeometryCache`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 { self.hits as f64 / (self.hits + self.misses).max(1) as f64 }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = group_digits;
+        write!(f, "{} hits, {} misses ({:.1}% hit rate), {} entries, {} evicted",
+               g(self.hits), g(self.misses), 100.0 * self.hit_rate(), g(self.entries), g(self.evictions))
+    }
+}
+
+impl GeometryCache {
+    /// A cache of (about) `max_entries` rows, with LOR endpoints rounded to
+    /// cells of `granularity`
+    pub fn new(max_entries: usize, granularity: Length) -> Result<Self, String> {
+        Self::with_shards(max_entries, granularity, MAX_SHARDS.min(max_entries))
+    }
+
+    fn with_shards(max_entries: usize, granularity: Length, n_shards: usize) -> Result<Self, String> {
+        if max_entries == 0 { return Err("The geometry cache needs room for at least one entry".into()) }
+        let granularity = mm_(granularity);
+        if !(granularity >= 0.0 && granularity.is_finite()) {
+            return Err(format!("Invalid geometry cache granularity: {granularity} mm"))
+        }
+        let capacity = (max_entries + n_shards - 1) / n_shards;
+        let shards = (0..n_shards).map(|_| Mutex::new(Shard { capacity, ..Shard::default() })).collect();
+        Ok(Self { shards, hasher: RandomState::new(), granularity,
+                  hits: AtomicUsize::new(0), misses: AtomicUsize::new(0), evictions: AtomicUsize::new(0) })
+    }
+
+    fn key(&self, lor: &LOR, with_dt: bool) -> Key {
+        let quantize = |x: f32| {
+            // -0.0 and 0.0 are the same coordinate
+            if self.granularity == 0.0 { (x + 0.0).to_bits() as i64 }
+            else                       { (x / self.granularity).round() as i64 }
+        };
+        let q = |c: Length| quantize(mm_(c));
+        let (p1, p2) = (lor.p1, lor.p2);
+        let dt = if with_dt { (ns_(lor.dt) + 0.0).to_bits() as i64 } else { 0 };
+        [q(p1.x), q(p1.y), q(p1.z), q(p2.x), q(p2.y), q(p2.z), dt]
+    }
+
+    fn shard(&self, key: &Key) -> &Mutex<Shard> {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Replace the contents of `indices` and `weights` with the row of `lor`,
+    /// from the cache if possible, otherwise from `compute`, which has the
+    /// signature and meaning of `mlem::system_matrix_row`. `with_dt` must be
+    /// set when the row depends on dt, as it does with TOF.
+    pub fn row(&self, lor: &LOR, with_dt: bool,
+               indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+               compute: impl FnOnce(&mut Vec<Index1_u>, &mut Vec<Lengthf32>) -> bool,
+    ) -> bool {
+        let key = self.key(lor, with_dt);
+        let shard = self.shard(&key);
+        if let Some(hit) = shard.lock().unwrap().get(&key, indices, weights) {
+            self.hits.fetch_add(1, Relaxed);
+            return hit
+        }
+        // Computed without holding the lock: another thread may be computing
+        // the same row, in which case both count as misses
+        self.misses.fetch_add(1, Relaxed);
+        let hit = compute(indices, weights);
+        let row = hit.then(|| (indices.as_slice().into(), weights.as_slice().into()));
+        if shard.lock().unwrap().insert(key, row) { self.evictions.fetch_add(1, Relaxed); }
+        hit
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            entries: self.shards.iter().map(|shard| shard.lock().unwrap().entries.len()).sum(),
+        }
+    }
+}
+
+impl RowSource for GeometryCache {
+    fn row(&self, _: usize, lor: &LOR, with_dt: bool,
+           indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+           compute: &mut dyn FnMut(&mut Vec<Index1_u>, &mut Vec<Lengthf32>) -> bool,
+    ) -> bool {
+        GeometryCache::row(self, lor, with_dt, indices, weights, compute)
+    }
+}
+
+impl Drop for GeometryCache {
+    fn drop(&mut self) {
+        let bytes = self.shards.iter_mut()
+            .map(|shard| shard.get_mut().map_or(0, |s| s.entries.values().map(|(row, _)| row_size(row)).sum::<usize>()))
+            .sum();
+        memory::freed("geometry_cache", bytes);
+    }
+}
+
+fn row_size(row: &Row) -> usize {
+    row.as_ref().map_or(0, |(i, w)| memory::size_of_slice(i) + memory::size_of_slice(w))
+}
+
+impl Shard {
+    /// Copy the row of `key`, if present, into `indices` and `weights`, and
+    /// mark it as the most recently used
+    fn get(&mut self, key: &Key, indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>) -> Option<bool> {
+        self.clock += 1;
+        let (row, used) = self.entries.get_mut(key)?;
+        self.by_use.remove(used);
+        *used = self.clock;
+        self.by_use.insert(self.clock, *key);
+        indices.clear();
+        weights.clear();
+        Some(match row {
+            Some((i, w)) => { indices.extend_from_slice(i); weights.extend_from_slice(w); true },
+            None         => false,
+        })
+    }
+
+    /// Add `row`, evicting the least recently used entry if full. Returns
+    /// whether an entry was evicted.
+    fn insert(&mut self, key: Key, row: Row) -> bool {
+        self.clock += 1;
+        memory::allocated("geometry_cache", row_size(&row));
+        if let Some((old, used)) = self.entries.insert(key, (row, self.clock)) {
+            // Another thread got here first
+            self.by_use.remove(&used);
+            memory::freed("geometry_cache", row_size(&old));
+        }
+        self.by_use.insert(self.clock, key);
+        if self.entries.len() <= self.capacity { return false }
+        let used = *self.by_use.keys().next().unwrap();
+        let oldest = self.by_use.remove(&used).unwrap();
+        let (evicted, _) = self.entries.remove(&oldest).unwrap();
+        memory::freed("geometry_cache", row_size(&evicted));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test_geometry_cache {
+    use super::*;
+    use crate::fov::FOV;
+    use crate::gauss::make_gauss_option;
+    use crate::image::Image;
+    use crate::mlem::{system_matrix_row, MlemOptions};
+    use crate::Point;
+    use crate::Time;
+    use geometry::units::mm;
+    use float_eq::assert_float_eq;
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(100.0), mm(100.0), mm(10.0)), (20, 20, 2)) }
+
+    fn lor(y: f32) -> LOR {
+        LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-200.0), mm(y), mm(0.0)), Point::new(mm(200.0), mm(-y), mm(1.0)))
+    }
+
+    /// Passes above the FOV
+    fn miss() -> LOR {
+        LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-200.0), mm(0.0), mm(50.0)), Point::new(mm(200.0), mm(0.0), mm(50.0)))
+    }
+
+    /// Look up every one of `lors` in `cache`, returning the rows
+    fn rows(cache: &GeometryCache, lors: &[LOR]) -> Vec<(Vec<Index1_u>, Vec<Lengthf32>)> {
+        let notof = make_gauss_option(None, None);
+        lors.iter().map(|lor| {
+            let (mut indices, mut weights) = (vec![], vec![]);
+            cache.row(lor, false, &mut indices, &mut weights,
+                      |indices, weights| system_matrix_row(lor, fov(), &notof, None, indices, weights));
+            (indices, weights)
+        }).collect()
+    }
+
+    #[test]
+    fn duplicates_hit_and_match_uncached_rows() {
+        // 11 distinct LORs, 20 times each, one of them missing the FOV
+        let mut distinct: Vec<LOR> = (0..10).map(|i| lor(-90.0 + 20.0 * i as f32)).collect();
+        distinct.push(miss());
+        let lors: Vec<LOR> = (0..20).flat_map(|_| distinct.iter().copied()).collect();
+        let cache = GeometryCache::new(100, mm(0.0)).unwrap();
+        let cached = rows(&cache, &lors);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries, stats.evictions), (209, 11, 11, 0));
+        assert_float_eq!(stats.hit_rate(), 0.95, abs <= 1e-9);
+
+        let notof = make_gauss_option(None, None);
+        for (lor, (indices, weights)) in lors.iter().zip(cached) {
+            let (mut i, mut w) = (vec![], vec![]);
+            system_matrix_row(lor, fov(), &notof, None, &mut i, &mut w);
+            assert_eq!((indices, weights), (i, w));
+        }
+    }
+
+    #[test]
+    fn zero_granularity_only_matches_exact_duplicates() {
+        let lors = [lor(10.0), lor(10.001), lor(10.0)];
+        let exact = GeometryCache::new(100, mm(0.0)).unwrap();
+        rows(&exact, &lors);
+        assert_eq!((exact.stats().hits, exact.stats().misses), (1, 2));
+
+        let coarse = GeometryCache::new(100, mm(0.1)).unwrap();
+        rows(&coarse, &lors);
+        assert_eq!((coarse.stats().hits, coarse.stats().misses), (2, 1));
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let cache = GeometryCache::with_shards(2, mm(0.0), 1).unwrap();
+        let (a, b, c) = (lor(1.0), lor(2.0), lor(3.0));
+        // a is used more recently than b, so c displaces b
+        rows(&cache, &[a, b, a, c, a, b]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.entries), (2, 4, 2, 2));
+    }
+
+    #[test]
+    fn cached_reconstruction_matches_uncached() {
+        let distinct: Vec<LOR> = (0..9).map(|i| lor(-40.0 + 10.0 * i as f32)).collect();
+        let lors: Vec<LOR> = (0..50).flat_map(|i| distinct.iter().copied().cycle().skip(i).take(7)).collect();
+        let cache = GeometryCache::new(1000, mm(0.0)).unwrap();
+        let n = 3;
+        let plain  = Image::mlem(fov(), &lors, None, None, None, None, 1).nth(n - 1).unwrap().0;
+        let options = MlemOptions { rows: Some(&cache), ..Default::default() };
+        let cached = Image::mlem_with(Image::ones(fov()), &lors, None, None, None, None, 1, options).nth(n - 1).unwrap().image;
+        assert_float_eq!(plain.data, cached.data, rmax_all <= 1e-6);
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, n * lors.len());
+        // Only the first iteration can miss, at most once per thread for each LOR
+        assert!(stats.misses <= distinct.len() * rayon::current_num_threads(), "{stats}");
+    }
+}
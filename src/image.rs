@@ -1,6 +1,10 @@
-use crate::{Intensityf32, Index1_u, Index3_u};
+use serde::{Deserialize, Serialize};
+
+use crate::{Intensityf32, Index1_u, Index3_u, Lengthf32, Length, Point};
+use geometry::units::{mm_, ratio_};
 use crate::fov::FOV;
-use crate::index::index3_to_1;
+use crate::index::{index1_to_3, index3_to_1};
+use crate::orientation::Frame;
 pub type ImageData = Vec<Intensityf32>;
 
 
@@ -8,6 +12,34 @@ pub type ImageData = Vec<Intensityf32>;
 pub struct Image {
     pub fov: FOV,
     pub data: ImageData,
+    /// Placement of the voxel array in world coordinates, used by writers of
+    /// formats with affine metadata (see `orientation`)
+    pub frame: Frame,
+}
+
+/// Version of `ImageHeader` written by this build
+pub const IMAGE_HEADER_VERSION: u32 = 1;
+
+fn image_header_version() -> u32 { IMAGE_HEADER_VERSION }
+
+/// Description of an image without its voxel values, for metadata and cache
+/// files. As with `FovRecord`, unknown fields are ignored and missing optional
+/// ones are defaulted, so that headers written by other versions are readable.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ImageHeader {
+    #[serde(default = "image_header_version")]
+    pub version: u32,
+    pub fov: FOV,
+    /// Number of complete iterations which produced the image, if it is a
+    /// reconstruction
+    #[serde(default)]
+    pub iterations: Option<usize>,
+}
+
+impl ImageHeader {
+    pub fn new(fov: FOV, iterations: Option<usize>) -> Self {
+        Self { version: IMAGE_HEADER_VERSION, fov, iterations }
+    }
 }
 
 
@@ -38,3 +70,450 @@ impl core::ops::Index<Index3_u> for Image {
     }
 }
 
+impl Image {
+    /// One line for logs: `151×151×151 voxels: min 0.000e0, max 1.250e1, mean 1.020e0, 0 NaN`.
+    /// NaNs are excluded from the extrema and the mean.
+    pub fn summary(&self) -> String {
+        let [nx, ny, nz] = self.fov.n;
+        let (mut min, mut max, mut sum, mut n, mut nans) = (f32::INFINITY, f32::NEG_INFINITY, 0.0_f64, 0_usize, 0_usize);
+        for &v in &self.data {
+            if v.is_nan() { nans += 1; continue }
+            min = min.min(v);
+            max = max.max(v);
+            sum += v as f64;
+            n += 1;
+        }
+        if n == 0 { min = f32::NAN; max = f32::NAN; }
+        let mean = if n == 0 { f64::NAN } else { sum / n as f64 };
+        format!("{nx}×{ny}×{nz} voxels: min {min:.3e}, max {max:.3e}, mean {mean:.3e}, {nans} NaN")
+    }
+}
+
+impl Image {
+    /// Value at arbitrary position `p`, trilinearly interpolated between the
+    /// centres of the surrounding voxels. Positions between the outermost voxel
+    /// centres and the edge of the FOV take the value of the outermost
+    /// voxels. `None` if `p` lies outside the FOV.
+    pub fn trilinear(&self, p: Point) -> Option<Intensityf32> {
+        let c = self.fov.voxel_coordinates(p)?;
+        // Lower and upper neighbouring voxel indices, and fractional distance
+        // from the lower one, along a single axis
+        let neighbours = |u: Lengthf32, n: usize| -> (usize, usize, f32) {
+            let u = (u - 0.5).max(0.0).min((n - 1) as f32);
+            let i0 = u.floor() as usize;
+            let i1 = (i0 + 1).min(n - 1);
+            (i0, i1, u - i0 as f32)
+        };
+        let [nx, ny, nz] = self.fov.n;
+        let (x0, x1, fx) = neighbours(c[0], nx);
+        let (y0, y1, fy) = neighbours(c[1], ny);
+        let (z0, z1, fz) = neighbours(c[2], nz);
+        let lerp = |a: f32, b: f32, f: f32| a + (b - a) * f;
+        let v = |x, y, z| self[[x, y, z]];
+        let c00 = lerp(v(x0, y0, z0), v(x1, y0, z0), fx);
+        let c10 = lerp(v(x0, y1, z0), v(x1, y1, z0), fx);
+        let c01 = lerp(v(x0, y0, z1), v(x1, y0, z1), fx);
+        let c11 = lerp(v(x0, y1, z1), v(x1, y1, z1), fx);
+        let c0 = lerp(c00, c10, fy);
+        let c1 = lerp(c01, c11, fy);
+        Some(lerp(c0, c1, fz))
+    }
+}
+
+impl Image {
+    /// Separable Gaussian smoothing with standard deviation `sigma` along each
+    /// axis. The kernel is truncated at 3 sigma, and renormalized where it
+    /// overlaps the edges of the FOV, so that uniform images are unchanged.
+    #[allow(clippy::needless_range_loop)]
+    pub fn gaussian_smoothed(&self, sigma: Length) -> Self {
+        let mut data = self.data.clone();
+        let [nx, ny, _] = self.fov.n;
+        let strides = [1, nx, nx * ny];
+        for d in 0..3 {
+            let s = ratio_(sigma / self.fov.voxel_size[d]);
+            if s <= 0.0 { continue }
+            let radius = (3.0 * s).ceil() as isize;
+            let kernel: Vec<f32> = (-radius..=radius)
+                .map(|k| (-0.5 * (k as f32 / s).powi(2)).exp())
+                .collect();
+            let (n, stride) = (self.fov.n[d] as isize, strides[d] as isize);
+            let mut smoothed = vec![0.0; data.len()];
+            for (i, out) in smoothed.iter_mut().enumerate() {
+                let position = (i as isize / stride) % n;
+                let (mut sum, mut norm) = (0.0, 0.0);
+                for (k, w) in (-radius..=radius).zip(&kernel) {
+                    if !(0..n).contains(&(position + k)) { continue }
+                    sum  += w * data[(i as isize + k * stride) as usize];
+                    norm += w;
+                }
+                *out = sum / norm;
+            }
+            data = smoothed;
+        }
+        Self::new(self.fov, data)
+    }
+}
+
+impl Image {
+    /// Total-variation denoising: the image `u` which minimizes
+    /// `½ Σ (u - self)² + lambda Σ |∇u|`, found with `iterations` of
+    /// Chambolle's projection algorithm. `∇` is the forward-difference gradient
+    /// per mm, so that edges across small voxels cost as much as those across
+    /// large ones, and `lambda` has units of intensity × mm. Noise in uniform
+    /// regions is smoothed away, while edges between them are preserved.
+    /// `lambda = 0` leaves the image unchanged.
+    pub fn tv_denoise(&self, lambda: f32, iterations: usize) -> Self {
+        self.tv_denoise_monitored(lambda, iterations, None)
+    }
+
+    /// As `tv_denoise`, passing the iteration number and the energy being
+    /// minimized by the current estimate to `monitor` after every iteration
+    #[allow(clippy::needless_range_loop)]
+    pub fn tv_denoise_monitored(&self, lambda: f32, iterations: usize, mut monitor: Option<&mut dyn FnMut(usize, f64)>) -> Self {
+        if lambda <= 0.0 { return self.clone() }
+        let grid = Grid::of(&self.fov);
+        let f = &self.data;
+        // The dual variable: one vector field component per axis
+        let mut p = [(); 3].map(|_| vec![0.0_f32; f.len()]);
+        // Step which guarantees convergence: 1 / |div|²
+        let tau = 1.0 / (4.0 * grid.inv_h.iter().map(|h| h * h).sum::<f32>());
+        let denoised = |p: &[Vec<f32>; 3]| -> Vec<f32> {
+            f.iter().enumerate().map(|(i, &f)| f - lambda * grid.div(p, i)).collect()
+        };
+        for iteration in 1..=iterations {
+            let w: Vec<f32> = f.iter().enumerate().map(|(i, &f)| grid.div(&p, i) - f / lambda).collect();
+            for i in 0..f.len() {
+                let g = grid.gradient(&w, i);
+                let norm = g.iter().map(|g| g * g).sum::<f32>().sqrt();
+                for d in 0..3 { p[d][i] = (p[d][i] + tau * g[d]) / (1.0 + tau * norm) }
+            }
+            if let Some(monitor) = monitor.as_mut() {
+                let u = denoised(&p);
+                let fidelity: f64 = u.iter().zip(f).map(|(&u, &f)| 0.5 * ((u - f) as f64).powi(2)).sum();
+                let tv: f64 = (0..u.len()).map(|i| grid.gradient(&u, i).iter().map(|g| g * g).sum::<f32>().sqrt() as f64).sum();
+                monitor(iteration, fidelity + lambda as f64 * tv);
+            }
+        }
+        Self::new(self.fov, denoised(&p))
+    }
+}
+
+/// Finite differences over the voxels of a FOV, as used by `tv_denoise`
+struct Grid {
+    n: [usize; 3],
+    strides: [usize; 3],
+    /// Reciprocals of the voxel sizes, in mm⁻¹
+    inv_h: [f32; 3],
+}
+
+impl Grid {
+    fn of(fov: &FOV) -> Self {
+        let [nx, ny, _] = fov.n;
+        Self { n: fov.n, strides: [1, nx, nx * ny], inv_h: [0, 1, 2].map(|d| 1.0 / mm_(fov.voxel_size[d])) }
+    }
+
+    /// Position of voxel `i` along axis `d`
+    fn position(&self, i: usize, d: usize) -> usize { (i / self.strides[d]) % self.n[d] }
+
+    /// Forward differences of `u` at voxel `i`, zero across the far edges
+    #[allow(clippy::needless_range_loop)]
+    fn gradient(&self, u: &[f32], i: usize) -> [f32; 3] {
+        let mut g = [0.0; 3];
+        for d in 0..3 {
+            if self.position(i, d) + 1 < self.n[d] { g[d] = (u[i + self.strides[d]] - u[i]) * self.inv_h[d] }
+        }
+        g
+    }
+
+    /// Divergence of `p` at voxel `i`: minus the adjoint of `gradient`
+    fn div(&self, p: &[Vec<f32>; 3], i: usize) -> f32 {
+        (0..3).map(|d| {
+            let position = self.position(i, d);
+            let here  = if position + 1 < self.n[d] { p[d][i] } else { 0.0 };
+            let there = if position > 0 { p[d][i - self.strides[d]] } else { 0.0 };
+            (here - there) * self.inv_h[d]
+        }).sum()
+    }
+}
+
+/// A voxel selected by `Image::top_k` or `Image::top_k_separated`, as
+/// reported in JSON summaries
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Hotspot {
+    pub voxel: Index3_u,
+    /// Centre of the voxel
+    pub position_mm: [Lengthf32; 3],
+    pub value: Intensityf32,
+}
+
+impl From<(Index3_u, Point, Intensityf32)> for Hotspot {
+    fn from((voxel, p, value): (Index3_u, Point, Intensityf32)) -> Self {
+        Self { voxel, position_mm: [mm_(p.x), mm_(p.y), mm_(p.z)], value }
+    }
+}
+
+/// Voxel value ordered by `total_cmp`, for use in `BinaryHeap`
+#[derive(Clone, Copy, PartialEq)]
+struct Ranked(Intensityf32, Index1_u);
+
+impl Eq for Ranked {}
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+impl Image {
+    /// The `k` hottest voxels, in descending order of value.
+    pub fn top_k(&self, k: usize) -> Vec<(Index3_u, Point, Intensityf32)> {
+        self.largest(k).into_iter()
+            .map(|Ranked(v, i)| (index1_to_3(i, self.fov.n), self.fov.voxel_centre1(i), v))
+            .collect()
+    }
+
+    /// Like `top_k`, but each selected voxel is at least `min_separation` from
+    /// all hotter selected voxels: voxels are considered in descending order,
+    /// and rejected if they lie too close to one that was already accepted.
+    ///
+    /// Only as many candidates as could possibly be needed are kept in memory:
+    /// each accepted voxel can suppress at most the voxels within
+    /// `min_separation` of it.
+    pub fn top_k_separated(&self, k: usize, min_separation: Length) -> Vec<(Index3_u, Point, Intensityf32)> {
+        let per_peak: usize = [0, 1, 2]
+            .map(|d| 2 * ratio_(min_separation / self.fov.voxel_size[d]).floor() as usize + 1)
+            .iter().product();
+        let mut accepted: Vec<(Index3_u, Point, Intensityf32)> = vec![];
+        for Ranked(v, i) in self.largest(k.saturating_mul(per_peak)) {
+            if accepted.len() == k { break }
+            let p = self.fov.voxel_centre1(i);
+            if accepted.iter().all(|&(_, q, _)| (p - q).norm() >= min_separation) {
+                accepted.push((index1_to_3(i, self.fov.n), p, v));
+            }
+        }
+        accepted
+    }
+
+    /// The `n` largest (non-NaN) voxels in descending order, found with a
+    /// min-heap holding at most `n` elements
+    fn largest(&self, n: usize) -> Vec<Ranked> {
+        use std::{cmp::Reverse, collections::BinaryHeap};
+        let n = n.min(self.data.len());
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        for (i, &v) in self.data.iter().enumerate() {
+            if v.is_nan() || n == 0 { continue }
+            if heap.len() < n {
+                heap.push(Reverse(Ranked(v, i)));
+            } else if heap.peek().unwrap().0 < Ranked(v, i) {
+                heap.pop();
+                heap.push(Reverse(Ranked(v, i)));
+            }
+        }
+        heap.into_sorted_vec().into_iter().map(|Reverse(r)| r).collect()
+    }
+}
+
+#[cfg(test)]
+mod test_summary {
+    use super::*;
+    use geometry::units::mm;
+
+    #[test]
+    fn extrema_mean_and_nans() {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(2.0), mm(2.0)), (4, 2, 1));
+        let image = Image::new(fov, vec![1.0, f32::NAN, -2.5, 12.5, f32::NAN, 0.0, 3.0, 1.0]);
+        assert_eq!(image.summary(), "4×2×1 voxels: min -2.500e0, max 1.250e1, mean 2.500e0, 2 NaN");
+        let nans = Image::new(fov, vec![f32::NAN; 8]);
+        assert_eq!(nans.summary(), "4×2×1 voxels: min NaN, max NaN, mean NaN, 8 NaN");
+    }
+}
+
+#[cfg(test)]
+mod test_image_header {
+    use super::*;
+    use geometry::units::mm;
+
+    fn header() -> ImageHeader {
+        ImageHeader::new(FOV::new_from_full_widths((mm(30.0), mm(20.0), mm(10.0)), (3, 2, 1)), Some(7))
+    }
+
+    fn fields(h: ImageHeader) -> (u32, [f32; 3], [usize; 3], Option<usize>) {
+        let w = h.fov.half_width;
+        (h.version, [w.x, w.y, w.z].map(mm_), h.fov.n, h.iterations)
+    }
+
+    #[test]
+    fn roundtrip_through_json_and_bincode() -> Result<(), Box<dyn std::error::Error>> {
+        let json: ImageHeader = serde_json::from_str(&serde_json::to_string(&header())?)?;
+        assert_eq!(fields(json), fields(header()));
+        let binary: ImageHeader = bincode::deserialize(&bincode::serialize(&header())?)?;
+        assert_eq!(fields(binary), fields(header()));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_and_unknown_fields_are_tolerated() -> Result<(), Box<dyn std::error::Error>> {
+        let text = r#"{"fov": {"full_width_mm": [30, 20, 10], "n_voxels": [3, 2, 1]}, "units": "Bq/ml"}"#;
+        let header: ImageHeader = serde_json::from_str(text)?;
+        assert_eq!(fields(header), (IMAGE_HEADER_VERSION, [15.0, 10.0, 5.0], [3, 2, 1], None));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_top_k {
+    use super::*;
+    use geometry::units::mm;
+
+    #[test]
+    fn plain_top_k_returns_largest_in_descending_order() {
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (10, 10, 10));
+        // Distinct values in scrambled order
+        let data: Vec<f32> = (0..1000).map(|i| ((i * 379) % 1000) as f32).collect();
+        let image = Image::new(fov, data.clone());
+        let top = image.top_k(5);
+        let values: Vec<f32> = top.iter().map(|&(_, _, v)| v).collect();
+        assert_eq!(values, vec![999.0, 998.0, 997.0, 996.0, 995.0]);
+        for (index, _, value) in top {
+            assert_eq!(image[index], value);
+        }
+        assert_eq!(image.top_k(0).len(), 0);
+        assert_eq!(image.top_k(2000).len(), 1000);
+    }
+
+    #[test]
+    fn separated_top_k_finds_blob_centres() {
+        let fov = FOV::new_from_full_widths((mm(40.0), mm(40.0), mm(40.0)), (20, 20, 20));
+        let mut image = Image::empty(fov);
+        let centres = [[4, 5, 6], [14, 4, 10], [9, 15, 15]];
+        let heights = [10.0, 8.0, 6.0];
+        for i in 0..image.data.len() {
+            let p = fov.voxel_centre1(i);
+            image[i] = centres.iter().zip(heights).map(|(&c, h)| {
+                let r = ratio_((p - fov.voxel_centre(c)).norm() / mm(4.0));
+                h * (-0.5 * r * r).exp()
+            }).sum();
+        }
+        // Without separation, all hottest voxels surround the highest peak
+        let unseparated = image.top_k(3);
+        assert!(unseparated.iter().all(|&(_, p, _)| (p - fov.voxel_centre(centres[0])).norm() < mm(3.0)));
+
+        let found = image.top_k_separated(3, mm(10.0));
+        assert_eq!(found.len(), 3);
+        for ((index, _, _), expected) in found.iter().zip(centres) {
+            for (&i, e) in index.iter().zip(expected) {
+                assert!((i as isize - e as isize).abs() <= 1, "{index:?} vs {expected:?}");
+            }
+        }
+    }
+    #[test]
+    fn hotspots_serialize_voxel_position_and_value() {
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (10, 10, 10));
+        let mut image = Image::empty(fov);
+        image[[2, 3, 4]] = 5.0;
+        let hotspot = Hotspot::from(image.top_k(1)[0]);
+        let json = serde_json::to_value(hotspot).unwrap();
+        assert_eq!(json["voxel"], serde_json::json!([2, 3, 4]));
+        assert_eq!(json["position_mm"], serde_json::json!([-2.5, -1.5, -0.5]));
+        assert_eq!(json["value"], 5.0);
+    }
+}
+
+#[cfg(test)]
+mod test_smoothing {
+    use super::*;
+    use geometry::units::mm;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn uniform_image_unchanged() {
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(12.0), mm(14.0)), (5, 6, 7));
+        let image = Image::new(fov, vec![3.0; 5 * 6 * 7]);
+        let smoothed = image.gaussian_smoothed(mm(3.0));
+        assert_float_eq!(smoothed.data, image.data, ulps_all <= 2);
+    }
+
+    #[test]
+    fn point_spreads_symmetrically_and_conserves_total() {
+        let fov = FOV::new_from_full_widths((mm(9.0), mm(9.0), mm(9.0)), (9, 9, 9));
+        let mut image = Image::empty(fov);
+        image[[4, 4, 4]] = 1.0;
+        let smoothed = image.gaussian_smoothed(mm(1.0));
+        assert_float_eq!(smoothed[[3, 4, 4]], smoothed[[5, 4, 4]], ulps <= 1);
+        assert_float_eq!(smoothed[[4, 3, 4]], smoothed[[4, 4, 5]], ulps <= 1);
+        assert!(smoothed[[4, 4, 4]] < 1.0);
+        assert_float_eq!(smoothed.data.iter().sum::<f32>(), 1.0, abs <= 1e-5);
+    }
+}
+
+#[cfg(test)]
+mod test_tv_denoise {
+    use super::*;
+    use geometry::units::mm;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// A 12 x 12 x 4 mm block of intensity 3 in a background of 1, in voxels
+    /// twice as long in y as in x and z, and the same with roughly Gaussian
+    /// noise of standard deviation 0.3 added
+    fn phantom() -> (Image, Image) {
+        let fov = FOV::new_from_full_widths((mm(24.0), mm(24.0), mm(6.0)), (24, 12, 6));
+        let mut truth = Image::new(fov, vec![1.0; 24 * 12 * 6]);
+        for x in 6..18 { for y in 3..9 { for z in 1..5 { truth[[x, y, z]] = 3.0 } } }
+        let mut rng = StdRng::seed_from_u64(1);
+        // Sum of 12 uniforms: unit variance
+        let mut noise = || (0..12).map(|_| rng.gen::<f32>()).sum::<f32>() - 6.0;
+        let noisy = Image::new(fov, truth.data.iter().map(|&v| v + 0.3 * noise()).collect());
+        (truth, noisy)
+    }
+
+    fn rmse(a: &Image, b: &Image) -> f32 {
+        (a.data.iter().zip(&b.data).map(|(a, b)| (a - b).powi(2)).sum::<f32>() / a.data.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn denoising_reduces_error() {
+        let (truth, noisy) = phantom();
+        let denoised = noisy.tv_denoise(0.3, 100);
+        let (before, after) = (rmse(&noisy, &truth), rmse(&denoised, &truth));
+        assert!(after < 0.6 * before, "RMSE {before} -> {after}");
+        // The edges survive: the block is still much brighter than the background
+        assert!(denoised[[12, 6, 3]] - denoised[[1, 1, 3]] > 1.5);
+    }
+
+    #[test]
+    fn zero_lambda_changes_nothing() {
+        let (_, noisy) = phantom();
+        assert_eq!(noisy.tv_denoise(0.0, 50).data, noisy.data);
+    }
+
+    #[test]
+    fn energy_falls() {
+        let (_, noisy) = phantom();
+        let mut energies = vec![];
+        noisy.tv_denoise_monitored(0.3, 50, Some(&mut |_, energy| energies.push(energy)));
+        assert_eq!(energies.len(), 50);
+        assert!(energies[49] < energies[0], "{} -> {}", energies[0], energies[49]);
+    }
+}
+
+#[cfg(test)]
+mod test_trilinear {
+    use super::*;
+    use geometry::units::mm;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn voxel_centres_and_midpoints() {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(2.0), mm(2.0)), (2, 1, 1));
+        let image = Image::new(fov, vec![1.0, 3.0]);
+        let at = |x| image.trilinear(Point::new(mm(x), mm(0.0), mm(0.0)));
+        assert_float_eq!(at(-1.0).unwrap(), 1.0, ulps <= 1); // centre of first voxel
+        assert_float_eq!(at( 1.0).unwrap(), 3.0, ulps <= 1); // centre of second voxel
+        assert_float_eq!(at( 0.0).unwrap(), 2.0, ulps <= 1); // half-way between them
+        assert_float_eq!(at(-1.9).unwrap(), 1.0, ulps <= 1); // beyond outermost centre
+        assert_eq!(at(2.1), None);                          // outside FOV
+    }
+}
@@ -10,6 +10,17 @@ pub type Index3Weightf32 = (Index3_u, Weightf32);
 pub type LengthI = geometry::uom::si::i32  ::Length;
 pub type LengthU = geometry::uom::si::usize::Length;
 
+/// Compact display of a 3D index: `(12, 5, 40)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisplayIndex3(pub Index3_u);
+
+impl std::fmt::Display for DisplayIndex3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let [x, y, z] = self.0;
+        write!(f, "({x}, {y}, {z})")
+    }
+}
+
 
 // --------------------------------------------------------------------------------
 //                  Conversion between 1d and 3d indices
@@ -38,6 +49,45 @@ where
     [x,y,z]
 }
 
+// --------------------------------------------------------------------------------
+//          Conversion between flat indices and ndarray Array3 indices
+//
+// Flat indices, as produced by LOR traversal and used to index `Image::data`,
+// have x varying fastest. A standard-layout (row-major) `Array3` viewing the
+// same data must therefore have shape `[nz, ny, nx]`, and is indexed with
+// `[iz, iy, ix]`: z is its slowest-varying axis and x its fastest.
+
+/// Shape of a standard-layout `Array3` sharing its data with a flat image of
+/// `[nx, ny, nz]` voxels: `[nz, ny, nx]`
+pub fn array3_shape([nx, ny, nz]: BoxDim_u) -> [usize; 3] { [nz, ny, nx] }
+
+/// `Array3` index `[iz, iy, ix]` of flat index `i` (x fastest) in an image of
+/// `[nx, ny, nz]` voxels
+pub fn index_to_array3(i: Index1_u, n: BoxDim_u) -> [usize; 3] {
+    let [ix, iy, iz] = index1_to_3(i, n);
+    [iz, iy, ix]
+}
+
+/// Flat index (x fastest) of `Array3` index `[iz, iy, ix]` in an image of
+/// `[nx, ny, nz]` voxels
+pub fn array3_to_index([iz, iy, ix]: [usize; 3], n: BoxDim_u) -> Index1_u {
+    index3_to_1([ix, iy, iz], n)
+}
+
+/// `i`, checked in debug builds to lie within an image of `n` voxels
+#[inline]
+pub fn checked_index(i: Index1_u, n: BoxDim_u) -> Index1_u {
+    debug_assert!(i < n[0] * n[1] * n[2], "Voxel index {i} out of bounds for image of {n:?} voxels");
+    i
+}
+
+/// Check, in debug builds only, that all `indices` lie within an image of `n` voxels
+#[inline]
+pub fn debug_assert_in_bounds(indices: &[Index1_u], n: BoxDim_u) {
+    if cfg!(debug_assertions) {
+        for &i in indices { checked_index(i, n); }
+    }
+}
 
 #[cfg(test)]
 mod test_index_conversion {
@@ -88,5 +138,112 @@ mod test_index_conversion {
             assert_eq!(back, index)
         }
 
+        #[test]
+        fn array3_roundtrip((size, index) in size_and_in_range_index()) {
+            let there = index_to_array3(index, size);
+            assert_eq!(array3_to_index(there, size), index);
+            let shape = array3_shape(size);
+            assert!(there.iter().zip(shape).all(|(&i, n)| i < n));
+        }
+
+    }
+
+    #[test]
+    fn array3_view_agrees_with_flat_index() {
+        let n = [4, 3, 2];
+        let data = (0..24).collect::<Vec<usize>>();
+        let view = ndarray::ArrayView3::from_shape(array3_shape(n), &data).unwrap();
+        for i in 0..24 {
+            assert_eq!(view[index_to_array3(i, n)], i);
+            let [ix, iy, iz] = index1_to_3(i, n);
+            assert_eq!(view[[iz, iy, ix]], data[index3_to_1([ix, iy, iz], n)]);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of bounds")]
+    fn out_of_bounds_index_is_caught() {
+        debug_assert_in_bounds(&[0, 5, 24], [4, 3, 2]);
+    }
+}
+
+#[cfg(test)]
+mod test_traversal_to_image {
+    use super::*;
+    use crate::{Length, PerLength, Point, Vector};
+    use crate::fov::FOV;
+    use crate::image::Image;
+    use crate::mlem::system_matrix_row;
+    use crate::system_matrix::{LOR, Tube};
+    use geometry::units::{mm, mm_, ns};
+    use ndarray::ArrayViewMut3;
+    use proptest::prelude::*;
+
+    proptest! {
+        /// A LOR through a known point marks the voxel containing that point,
+        /// whichever way the flat traversal index is mapped onto an Array3.
+        #[test]
+        fn traversal_marks_voxel_containing_point(
+            n in [1..20_usize, 1..20_usize, 1..20_usize],
+            size in [10.0..300.0_f32, 10.0..300.0_f32, 10.0..300.0_f32],
+            f in [0.01..0.99_f32, 0.01..0.99_f32, 0.01..0.99_f32],
+            theta in 0.1..3.0_f32,
+            phi in 0.0..6.28_f32,
+        ) {
+            let fov = FOV::new_from_full_widths((mm(size[0]), mm(size[1]), mm(size[2])), (n[0], n[1], n[2]));
+            let p = Point::new(mm(size[0] * (f[0] - 0.5)), mm(size[1] * (f[1] - 0.5)), mm(size[2] * (f[2] - 0.5)));
+            let d = Vector::new(mm(theta.sin() * phi.cos()), mm(theta.sin() * phi.sin()), mm(theta.cos())) * 1000.0;
+            let lor = LOR::new(ns(0.0), ns(0.0), p + d * -1.0, p + d);
+
+            let (mut indices, mut weights) = (vec![], vec![]);
+            let no_tof: Option<fn(Length) -> PerLength> = None;
+            prop_assert!(system_matrix_row(&lor, fov, &no_tof, None, &mut indices, &mut weights));
+            debug_assert_in_bounds(&indices, fov.n);
+
+            // Tolerance for points lying on voxel boundaries
+            let contains = |i: Index1_u| {
+                let c = fov.voxel_centre1(i);
+                (0..3).all(|d| mm_((c[d] - p[d]).abs()) <= mm_(fov.voxel_size[d]) / 2.0 + 1e-3)
+            };
+            let hit = indices.iter().copied().find(|&i| contains(i));
+            prop_assert!(hit.is_some(), "No traversed voxel contains {p:?}");
+
+            // Mark the voxel through an Array3 view of the image data
+            let mut image = Image::empty(fov);
+            {
+                let mut view = ArrayViewMut3::from_shape(array3_shape(fov.n), &mut image.data).unwrap();
+                view[index_to_array3(hit.unwrap(), fov.n)] = 1.0;
+            }
+            let marked = image.data.iter().position(|&v| v == 1.0).unwrap();
+            prop_assert!(contains(marked));
+
+            let view = ndarray::ArrayView3::from_shape(array3_shape(fov.n), &image.data).unwrap();
+            let (array_index, _) = view.indexed_iter().find(|(_, &v)| v == 1.0).unwrap();
+            let [iz, iy, ix] = [array_index.0, array_index.1, array_index.2];
+            prop_assert_eq!(array3_to_index([iz, iy, ix], fov.n), marked);
+        }
+
+        /// With chord normalization, every LOR hitting the FOV has total weight 1
+        #[test]
+        fn normalized_chord_weights_sum_to_one(
+            n in [1..20_usize, 1..20_usize, 1..20_usize],
+            size in [10.0..300.0_f32, 10.0..300.0_f32, 10.0..300.0_f32],
+            f in [0.01..0.99_f32, 0.01..0.99_f32, 0.01..0.99_f32],
+            theta in 0.1..3.0_f32,
+            phi in 0.0..6.28_f32,
+        ) {
+            let fov = FOV::new_from_full_widths((mm(size[0]), mm(size[1]), mm(size[2])), (n[0], n[1], n[2]));
+            let p = Point::new(mm(size[0] * (f[0] - 0.5)), mm(size[1] * (f[1] - 0.5)), mm(size[2] * (f[2] - 0.5)));
+            let d = Vector::new(mm(theta.sin() * phi.cos()), mm(theta.sin() * phi.sin()), mm(theta.cos())) * 1000.0;
+            let lor = LOR::new(ns(0.0), ns(0.0), p + d * -1.0, p + d);
+
+            let (mut indices, mut weights) = (vec![], vec![]);
+            let no_tof: Option<fn(Length) -> PerLength> = None;
+            let tube = Tube { radius: mm(0.0), samples: 1, normalize_chord: true };
+            prop_assert!(system_matrix_row(&lor, fov, &no_tof, Some(tube), &mut indices, &mut weights));
+            let total: f64 = weights.iter().map(|&w| w as f64).sum();
+            prop_assert!((total - 1.0).abs() < 1e-6, "total weight {total}");
+        }
     }
 }
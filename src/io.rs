@@ -1,2 +1,11 @@
+#[cfg(feature = "hdf5")]
 pub mod hdf5;
 pub mod raw;
+pub mod metaimage;
+pub mod nifti;
+#[cfg(feature = "hdf5")]
+pub mod mapped;
+#[cfg(feature = "hdf5")]
+pub mod columns;
+pub mod prefetch;
+pub mod units;
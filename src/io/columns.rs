@@ -0,0 +1,248 @@
+//! Reading LOR tables whose columns are named differently from the fields of
+//! `Hdf5Lor`, such as `xr1` for `x1` or `e1` for `E1`.
+//!
+//! Without a `ColumnMap`, LOR tables are read in one go as the compound type
+//! `Hdf5Lor`, which requires the columns to have exactly its names. With one,
+//! they are read in one go as `LorColumns`: the compound type of `Hdf5Lor` with
+//! each member renamed to the column to which it is mapped. This relies on HDF5
+//! converting compound types member by member, matched by name, converting
+//! each column to `f32` if necessary.
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use hdf5::H5Type;
+use hdf5::types::TypeDescriptor;
+use hdf5_sys::h5::hsize_t;
+use hdf5_sys::h5d::{H5Dget_space, H5Dread};
+use hdf5_sys::h5p::H5P_DEFAULT;
+use hdf5_sys::h5s::{H5Sclose, H5Screate_simple, H5Sselect_hyperslab, H5S_seloper_t::H5S_SELECT_SET};
+
+use crate::io::hdf5::Hdf5Lor;
+
+/// Fields of `Hdf5Lor`, in order
+pub const LOR_FIELDS: [&str; 11] = ["dt", "x1", "y1", "z1", "x2", "y2", "z2", "q1", "q2", "E1", "E2"];
+
+/// Columns holding the fields of `Hdf5Lor` which are named differently in the
+/// file: `x1=xr1,E1=e1`. Fields which are not mapped are read from the column
+/// of the same name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnMap {
+    /// Field of `Hdf5Lor`, and the column which holds it
+    renamed: Vec<(&'static str, String)>,
+}
+
+impl ColumnMap {
+    /// The column holding `field`
+    pub fn column<'a>(&'a self, field: &'a str) -> &'a str {
+        self.renamed.iter().find(|(f, _)| *f == field).map_or(field, |(_, column)| column)
+    }
+}
+
+impl FromStr for ColumnMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut renamed: Vec<(&'static str, String)> = vec![];
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (field, column) = entry.split_once('=')
+                .ok_or_else(|| format!("Expected <field>=<column> in column map, got '{entry}'"))?;
+            let (field, column) = (field.trim(), column.trim());
+            let field = *LOR_FIELDS.iter().find(|&&f| f == field)
+                .ok_or_else(|| format!("Unknown LOR field '{field}' in column map entry '{entry}': expected one of {}", LOR_FIELDS.join(", ")))?;
+            if column.is_empty() { return Err(format!("No column given for LOR field '{field}' in column map")) }
+            if renamed.iter().any(|(f, _)| *f == field) { return Err(format!("LOR field '{field}' is mapped more than once in column map")) }
+            renamed.push((field, column.into()));
+        }
+        if renamed.is_empty() { return Err("Empty column map: expected <field>=<column>,...".into()) }
+        Ok(Self { renamed })
+    }
+}
+
+/// Names of the columns of the compound `table`
+pub fn column_names(table: &hdf5::Dataset) -> hdf5::Result<Vec<String>> {
+    match table.dtype()?.to_descriptor()? {
+        TypeDescriptor::Compound(compound) => Ok(compound.fields.into_iter().map(|field| field.name).collect()),
+        other => Err(format!("LOR table has no columns: its type is {other:?}").into()),
+    }
+}
+
+/// Check that the LOR `table` has the column in `columns` of every field of
+/// `Hdf5Lor`. Every missing column is reported, alongside those available.
+pub fn check_lor_columns(table: &hdf5::Dataset, columns: &ColumnMap) -> hdf5::Result<()> {
+    let available = column_names(table)?;
+    let missing: Vec<String> = LOR_FIELDS.iter()
+        .filter(|field| !available.iter().any(|column| column == columns.column(field)))
+        .map(|field| match columns.column(field) {
+            column if column == *field => format!("'{field}'"),
+            column                     => format!("'{column}' (mapped from {field})"),
+        })
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("LOR table has no column {}. Its columns are {}: map the LOR fields to them with a column map, such as x1=xr1",
+                           missing.join(", "), available.join(", ")).into())
+    }
+    Ok(())
+}
+
+/// Reads LOR tables through a `ColumnMap`, as the compound type of `Hdf5Lor`
+/// with each member renamed to the column which holds it
+#[derive(Clone, Debug)]
+pub struct LorColumns {
+    columns: ColumnMap,
+    /// Layout of `Hdf5Lor`, with the member names of the file
+    descriptor: TypeDescriptor,
+}
+
+impl LorColumns {
+    pub fn new(columns: ColumnMap) -> Self {
+        let mut descriptor = Hdf5Lor::type_descriptor();
+        if let TypeDescriptor::Compound(compound) = &mut descriptor {
+            for field in &mut compound.fields { field.name = columns.column(&field.name).into() }
+        }
+        Self { columns, descriptor }
+    }
+
+    /// Check that `table` has every column to be read (see `check_lor_columns`)
+    pub fn check(&self, table: &hdf5::Dataset) -> hdf5::Result<()> { check_lor_columns(table, &self.columns) }
+
+    /// Rows `range` of the LOR `table`, read in one go
+    pub fn read(&self, table: &hdf5::Dataset, range: Range<usize>) -> hdf5::Result<Vec<Hdf5Lor>> {
+        if range.is_empty() { return Ok(vec![]) }
+        let memtype = hdf5::Datatype::from_descriptor(&self.descriptor)?;
+        let (start, count) = ([range.start as hsize_t], [range.len() as hsize_t]);
+        let mut rows = Vec::<Hdf5Lor>::with_capacity(range.len());
+        // SAFETY: `memtype` has exactly the layout of `Hdf5Lor`, only its member
+        // names differ, and `rows` has room for the `count` rows selected. The
+        // library lock is held throughout, as by every call in `hdf5`.
+        let status = hdf5::sync::sync(|| unsafe {
+            let file_space = H5Dget_space(table.id());
+            let mem_space = H5Screate_simple(1, count.as_ptr(), std::ptr::null());
+            let status = if file_space < 0 || mem_space < 0 { -1 } else {
+                match H5Sselect_hyperslab(file_space, H5S_SELECT_SET, start.as_ptr(), std::ptr::null(), count.as_ptr(), std::ptr::null()) {
+                    failed if failed < 0 => failed,
+                    _ => H5Dread(table.id(), memtype.id(), mem_space, file_space, H5P_DEFAULT, rows.as_mut_ptr().cast()),
+                }
+            };
+            if file_space >= 0 { H5Sclose(file_space); }
+            if  mem_space >= 0 { H5Sclose( mem_space); }
+            status
+        });
+        if status < 0 { return Err(format!("Failed to read rows {range:?} through the column map").into()) }
+        // SAFETY: `H5Dread` succeeded, so every row selected has been written
+        unsafe { rows.set_len(range.len()) }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod test_columns {
+    use super::*;
+    use crate::io::hdf5::{fixtures, read_lors_with_meta, read_table, sample_rows, write_table, Args, ConcatenatedChunks, DEFAULT_LOR_DATASET};
+    use crate::io::prefetch::ChunkReader;
+    use rstest::rstest;
+    use std::error::Error;
+
+    /// LOR table of another production: positions `xr1`, ..., lowercase
+    /// energies, and an extra column
+    #[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
+    #[repr(C)]
+    struct OtherLor {
+        event_id: u32,
+        dt: f32,
+        xr1: f32, yr1: f32, zr1: f32,
+        xr2: f32, yr2: f32, zr2: f32,
+        q1: f32, q2: f32,
+        e1: f64, e2: f64,
+    }
+
+    fn other(i: usize) -> OtherLor {
+        let i = i as f32;
+        OtherLor { event_id: i as u32, dt: 0.01 * i,
+                   xr1: -300.0, yr1: i, zr1: 2.0 * i,
+                   xr2:  300.0, yr2: -i, zr2: 3.0 * i,
+                   q1: 1000.0 + i, q2: 2000.0 + i, e1: 511.0 + i as f64, e2: 400.0 - i as f64 }
+    }
+
+    const RENAMED: &str = "x1=xr1, y1=yr1, z1=zr1, x2=xr2, y2=yr2, z2=zr2, E1=e1, E2=e2";
+
+    fn write_fixture(dir: &std::path::Path, n: usize) -> hdf5::Result<String> {
+        let path = dir.join("other.h5").to_str().unwrap().to_string();
+        write_table(&path, DEFAULT_LOR_DATASET, &(0..n).map(other).collect::<Vec<_>>())?;
+        Ok(path)
+    }
+
+    fn args(path: &str, column_map: Option<&str>) -> Args {
+        Args { column_map: column_map.map(|map| map.parse().unwrap()), ..fixtures::args(path) }
+    }
+
+    #[test]
+    fn nonstandard_columns_are_read_through_a_map() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_fixture(dir.path(), 30)?;
+
+        let rows = sample_rows(&args(&path, Some(RENAMED)), 30)?;
+        assert_eq!(rows.len(), 30);
+        for (i, row) in rows.iter().enumerate() {
+            let o = other(i);
+            assert_eq!(row, &Hdf5Lor { dt: o.dt, x1: o.xr1, y1: o.yr1, z1: o.zr1, x2: o.xr2, y2: o.yr2, z2: o.zr2,
+                                       q1: o.q1, q2: o.q2, E1: o.e1 as f32, E2: o.e2 as f32 });
+        }
+
+        // The whole pipeline reads through the map, in chunks
+        let lors = read_lors_with_meta(Args { event_range: Some(5..25), ..args(&path, Some(RENAMED)) })?;
+        assert_eq!(lors.len(), 20);
+        assert_eq!(lors[0].e1, 516.0);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_columns_are_reported() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_fixture(dir.path(), 3)?;
+
+        // Without a map, the compound read fails
+        assert!(read_table::<Hdf5Lor>(&path, DEFAULT_LOR_DATASET, None).is_err());
+
+        let err = sample_rows(&args(&path, Some("x1=xr1,E1=energy1")), 3).unwrap_err().to_string();
+        for expected in ["'energy1' (mapped from E1)", "'y1'", "'E2'", "xr1, yr1"] {
+            assert!(err.contains(expected), "{expected} not in: {err}");
+        }
+        assert!(!err.contains("'x1'"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn columns_are_checked_once_on_opening() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_fixture(dir.path(), 30)?;
+        let open = |map: &str| ConcatenatedChunks::<Hdf5Lor>::new(&[path.clone()], DEFAULT_LOR_DATASET, None, 7)?
+            .with_columns(Some(map.parse().unwrap()));
+
+        assert!(open("x1=xr1").is_err());
+        let mut reader = open(RENAMED)?;
+        let mut n = 0;
+        while let Some(chunk) = reader.next_chunk() { n += chunk?.len() }
+        assert_eq!(n, 30);
+        Ok(())
+    }
+
+    #[rstest(/**/ text               , expected,
+             case("x1=xr1"          , Ok(vec![("x1", "xr1")])),
+             case(" E1 = e1 ,E2=e2 ", Ok(vec![("E1", "e1"), ("E2", "e2")])),
+             case("e1=E1"           , Err("Unknown LOR field 'e1'")),
+             case("x1"              , Err("Expected <field>=<column>")),
+             case("x1="             , Err("No column given for LOR field 'x1'")),
+             case("x1=a,x1=b"       , Err("LOR field 'x1' is mapped more than once")),
+             case(""                , Err("Empty column map")),
+    )]
+    fn parse(text: &str, expected: Result<Vec<(&str, &str)>, &str>) {
+        match (text.parse::<ColumnMap>(), expected) {
+            (Ok(map), Ok(expected)) => {
+                for (field, column) in expected { assert_eq!(map.column(field), column) }
+                assert_eq!(map.column("dt"), "dt");
+            }
+            (Err(e), Err(expected)) => assert!(e.starts_with(expected), "{e}"),
+            (actual, expected) => panic!("Expected {expected:?}, got {actual:?}"),
+        }
+    }
+}
@@ -2,131 +2,945 @@
 
 use std::error::Error;
 use std::ops::RangeBounds;
-use crate::lorogram::{Scattergram, Prompt};
+use crate::lorogram::{classify_energies, Scattergram, Prompt, WindowedScattergram, theta};
 
 #[derive(Clone)]
 pub struct Args {
-    pub input_file: String,
+    /// Files whose LOR tables are read as one, concatenated in this order:
+    /// paths or glob patterns (see `expand_input_files`)
+    pub input_files: Vec<String>,
     pub dataset: String,
     pub event_range: Option<std::ops::Range<usize>>,
     pub use_true: bool,
     pub ecut: BoundPair<Energyf32>,
     pub qcut: BoundPair<crate::Chargef32>,
+    /// Cut on q2, if it differs from that on q1, which is then `qcut`
+    pub q2cut: Option<BoundPair<crate::Chargef32>>,
+    /// Keep only LORs whose polar angle from the transverse plane is in this range
+    pub theta_cut: BoundPair<Angle>,
+    /// Keep only the events in this replicate of the dataset
+    pub split: Option<Split>,
+    /// 1D datasets in the input files, aligned with the LOR table, whose values
+    /// multiply each LOR's multiplicative correction
+    pub mult_corrections: Vec<String>,
+    /// As `mult_corrections`, but added to each LOR's additive correction
+    pub add_corrections: Vec<String>,
+    /// What to do with LORs whose endpoints coincide
+    pub degenerate: DegeneratePolicy,
+    /// Correct each LOR for the dead time of the frame in which it was detected
+    pub dead_time: Option<DeadTimeArgs>,
+    /// Put the endpoints of each LOR in the order given by
+    /// `system_matrix::endpoint_order`, on which downstream code may then rely
+    pub canonicalize_endpoints: bool,
+    /// Applied to both endpoints of every LOR as it is read, before any cuts
+    pub transform: Option<RigidTransform>,
+    /// Gather the scattergram separately in consecutive windows of acquisition
+    /// time, and interpolate each LOR's scatter correction in time between them
+    pub scatter_windows: Option<ScatterWindowArgs>,
+    /// Columns of the LOR table named differently from the fields of
+    /// `Hdf5Lor`, which are then read column by column (see `io::columns`)
+    pub column_map: Option<ColumnMap>,
 }
 
+/// Every row of `DEFAULT_LOR_DATASET`, without cuts, corrections or
+/// transformations: give the `input_files` and override what differs
+impl Default for Args {
+    fn default() -> Self {
+        use std::ops::Bound::Unbounded;
+        Self {
+            input_files: vec![], dataset: DEFAULT_LOR_DATASET.into(), event_range: None, use_true: false,
+            ecut: (Unbounded, Unbounded), qcut: (Unbounded, Unbounded), q2cut: None, theta_cut: (Unbounded, Unbounded),
+            split: None, mult_corrections: vec![], add_corrections: vec![], degenerate: DegeneratePolicy::default(),
+            dead_time: None, canonicalize_endpoints: false, transform: None, scatter_windows: None, column_map: None,
+        }
+    }
+}
+
+impl Args {
+    /// The input files, with glob patterns expanded
+    pub fn files(&self) -> Result<Vec<String>, String> { expand_input_files(&self.input_files) }
+
+    /// The input, if it is a memory-mapped LOR file (see `io::mapped`). Such
+    /// files cannot be concatenated with others.
+    fn mapped_file(&self) -> Result<Option<String>, String> {
+        let files = self.files()?;
+        match files.as_slice() {
+            [file] if is_mapped_file(file) && self.column_map.is_some() => Err(format!(
+                "Column maps apply only to HDF5 LOR tables, not to the memory-mapped LOR file '{file}'")),
+            [file] if is_mapped_file(file) => Ok(Some(file.clone())),
+            _ if files.iter().any(is_mapped_file) => Err(format!(
+                "Memory-mapped LOR files cannot be combined with other input files: {}", describe_files(&files))),
+            _ => Ok(None),
+        }
+    }
+
+    /// The input files, for messages
+    pub fn describe_input(&self) -> String { describe_files(&self.input_files) }
+}
+
+/// `'file'` for a single file, otherwise the number of files and the first and
+/// last of them
+pub fn describe_files(files: &[String]) -> String {
+    match files {
+        []                => "no files".into(),
+        [file]            => format!("'{file}'"),
+        [first, .., last] => format!("{} files, '{first}' to '{last}'", files.len()),
+    }
+}
+
+/// Where to find the acquisition times for time-windowed scattergrams, and how
+/// many windows to use
+#[derive(Clone, Debug)]
+pub struct ScatterWindowArgs {
+    /// 1D dataset in the input files, aligned with the LOR table: acquisition time
+    /// (s) of each event
+    pub time_dataset: String,
+    pub n_windows: usize,
+}
+
+/// Where to find the data needed for dead-time corrections, and how to model
+/// the dead time
+#[derive(Clone, Debug)]
+pub struct DeadTimeArgs {
+    /// 1D dataset in the input files, aligned with the LOR table: acquisition time
+    /// (s) of each event
+    pub time_dataset: String,
+    /// Table of `SinglesRate`s in the input files
+    pub singles_dataset: String,
+    pub model: DeadTimeModel,
+    pub tau: Time,
+}
+
+use std::os::raw::c_int;
 use ndarray::{s, Array1};
+use hdf5::filters::Filter;
 
-use crate::{Chargef32, Energyf32, BoundPair};
-use crate::Point;
-use crate::system_matrix::LOR;
+use crate::{Angle, Chargef32, Energyf32, BoundPair, Time};
+use crate::ecut::EnergyHistogram;
+use crate::deadtime::{DeadTimeCorrection, DeadTimeModel, SinglesRate};
+use crate::system_matrix::{canonical, endpoint_order, Corrections, DegeneratePolicy, LorWithMeta, LOR};
+use crate::io::columns::{check_lor_columns, ColumnMap, LorColumns};
+use crate::io::prefetch::{chunks, ChunkReader, ChunkResult};
+use crate::io::mapped::{is_mapped_file, MappedChunks, MappedLors};
+use crate::thinning::Split;
+use crate::transform::RigidTransform;
+use crate::memory;
+use crate::utils::stats::format_percentage;
+use tracing::info_span;
+use geometry::units::ratio_;
 
-use geometry::units::{mm, ns, ratio};
+use crate::io::units::{ns_from_file, ns_to_file, point_from_file, point_to_file};
 
-pub fn read_table<T: hdf5::H5Type>(filename: &str, dataset: &str, range: Option<std::ops::Range<usize>>) -> hdf5::Result<Array1<T>> {
+mod writer;
+pub use writer::*;
+
+/// Dataset used by default when reading LORs
+pub const DEFAULT_LOR_DATASET: &str = "reco_info/lors";
+
+/// Number of LORs read in each chunk, when reading in chunks
+pub const LOR_CHUNK_SIZE: usize = 1_000_000;
+
+fn open_table(filename: &str, dataset: &str) -> hdf5::Result<hdf5::Dataset> {
+    if !std::path::Path::new(filename).is_file() {
+        return Err(format!("File not found: '{filename}'").into())
+    }
     let file = ::hdf5::File::open(filename)?;
-    let table = file.dataset(dataset)?;
-    let data = if let Some(range) = range {
-        table.read_slice_1d::<T,_>(s![range])?
-    } else {
-        table.read_slice_1d::<T,_>(s![..])?
+    let table = file.dataset(dataset).map_err(|_| -> hdf5::Error {
+        let available = list_datasets(&file).unwrap_or_default();
+        format!("Dataset '{dataset}' not found in '{filename}'. Available datasets:\n  {}",
+                available.join("\n  ")).into()
+    })?;
+    for filter in table.filters() {
+        check_filter_available(filter.id(), filename, dataset)?;
+    }
+    Ok(table)
+}
+
+/// Fail with an explanation if the HDF5 filter `id`, needed to read `dataset`,
+/// cannot decode data in this HDF5 installation
+fn check_filter_available(id: c_int, filename: &str, dataset: &str) -> hdf5::Result<()> {
+    if Filter::get_info(id).decode_enabled { return Ok(()) }
+    Err(format!(
+        "Dataset '{dataset}' in '{filename}' is compressed with HDF5 filter {}, which is not available.\n\
+         If the filter is provided by a plugin, set HDF5_PLUGIN_PATH to the directory containing it.",
+        filter_name(id)).into())
+}
+
+/// Human-readable name of HDF5 filter `id`
+fn filter_name(id: c_int) -> String {
+    let name = match id {
+        1     => "deflate (gzip)",
+        2     => "shuffle",
+        3     => "fletcher32",
+        4     => "szip",
+        5     => "nbit",
+        6     => "scaleoffset",
+        32000 => "lzf",
+        32001 => "blosc",
+        32004 => "lz4",
+        32008 => "bitshuffle",
+        32015 => "zstd",
+        _     => return format!("with id {id}"),
+    };
+    format!("'{name}' (id {id})")
+}
+
+/// Number of rows in each chunk of `table`, if it is chunked and filtered
+/// (typically compressed)
+fn filtered_chunk_rows(table: &hdf5::Dataset) -> Option<usize> {
+    if table.filters().is_empty() { return None }
+    table.chunk().and_then(|chunk| chunk.first().copied()).filter(|&rows| rows > 0)
+}
+
+/// Log the compression settings of `table`, which explain slow reads
+fn report_compression(table: &hdf5::Dataset, dataset: &str) {
+    let filters = table.filters();
+    if filters.is_empty() { return }
+    let filters = filters.iter()
+        .map(|filter| match filter {
+            Filter::Deflate(level) => format!("gzip level {level}"),
+            other                  => filter_name(other.id()),
+        })
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let chunk = match table.chunk() {
+        Some(chunk) => format!(", chunks of {chunk:?} rows"),
+        None        => String::new(),
     };
-    Ok(data)
+    tracing::info!("Dataset '{dataset}' is filtered: {filters}{chunk}");
+}
+
+/// `range` extended outwards to the nearest multiples of `chunk`, within `len`
+fn align_to_chunks(range: std::ops::Range<usize>, chunk: usize, len: usize) -> std::ops::Range<usize> {
+    let start = range.start / chunk * chunk;
+    let end = ((range.end + chunk - 1) / chunk * chunk).min(len);
+    start..end
+}
+
+/// Number of rows in `dataset`
+pub fn table_len(filename: &str, dataset: &str) -> hdf5::Result<usize> {
+    if is_mapped_file(filename) { return Ok(MappedLors::open(filename)?.len()) }
+    Ok(open_table(filename, dataset)?.size())
+}
+
+/// Number of rows of the LOR table `dataset` in `filename`, and the bytes per
+/// row in which they are stored, having checked that the table has a column
+/// for every field of `Hdf5Lor` (through `columns`, if given)
+pub fn inspect_lor_table(filename: &str, dataset: &str, columns: Option<&ColumnMap>) -> hdf5::Result<(usize, usize)> {
+    if is_mapped_file(filename) { return Ok((MappedLors::open(filename)?.len(), crate::io::mapped::RECORD_SIZE)) }
+    let table = open_table(filename, dataset)?;
+    check_lor_columns(&table, columns.unwrap_or(&ColumnMap::default()))?;
+    Ok((table.size(), table.dtype()?.size()))
+}
+
+/// Up to `n` rows of the LOR table described by `args`, from the start of its
+/// `event_range`, before any cuts: for checking what the input contains
+pub fn sample_rows(args: &Args, n: usize) -> Result<Vec<Hdf5Lor>, Box<dyn Error>> {
+    let start = args.event_range.as_ref().map_or(0, |range| range.start);
+    let limit = args.event_range.as_ref().map_or(n, |range| n.min(range.len()));
+    if let Some(file) = args.mapped_file()? {
+        return Ok(MappedLors::open(&file)?.hdf5_lors().skip(start).take(limit).collect())
+    }
+    let files = args.files()?;
+    let end = (start + limit).min(concatenated_len(&files, &args.dataset)?);
+    let mut reader = ConcatenatedChunks::new(&files, &args.dataset, Some(start..end.max(start)), LOR_CHUNK_SIZE)?
+        .with_columns(args.column_map.clone())?;
+    let mut rows = vec![];
+    while let Some(chunk) = reader.next_chunk() { rows.extend(chunk?) }
+    Ok(rows)
+}
+
+/// The charges `q1` and `q2` of the rows in the `event_range` of the LOR table
+/// described by `args`, before any cuts: for deriving charge cuts (see `qcut`)
+pub fn read_charges(args: &Args) -> Result<(Vec<crate::Chargef32>, Vec<crate::Chargef32>), Box<dyn Error>> {
+    let files = args.describe_input();
+    let _span = info_span!("read_charges", files = files.as_str()).entered();
+    let (mut q1, mut q2) = (vec![], vec![]);
+    let mut add = |chunk: Vec<Hdf5Lor>| for lor in chunk { q1.push(lor.q1); q2.push(lor.q2) };
+    match args.mapped_file()? {
+        Some(file) => for chunk in chunks(open_mapped_lors(file, args)()?, true) { add(chunk?) },
+        None       => for chunk in chunks(open_lor_table        (args)()?, true) { add(chunk?) },
+    }
+    Ok((q1, q2))
+}
+
+/// Spectra of the energies `E1` and `E2` of `rows`, in `bins` spanning `[0,
+/// max)` keV: for deriving energy cuts (see `ecut`)
+pub fn energy_histograms(rows: &[Hdf5Lor], bins: usize, max: Energyf32) -> (EnergyHistogram, EnergyHistogram) {
+    (EnergyHistogram::new(rows.iter().map(|row| row.E1), bins, max),
+     EnergyHistogram::new(rows.iter().map(|row| row.E2), bins, max))
+}
+
+pub fn read_table<T: hdf5::H5Type>(filename: &str, dataset: &str, range: Option<std::ops::Range<usize>>) -> hdf5::Result<Array1<T>> {
+    let table = open_table(filename, dataset)?;
+    report_compression(&table, dataset);
+    read_rows(&table, range)
+}
+
+/// As `read_table`, in consecutive slices of about `chunk_size` rows (see
+/// `TableChunks`), each read only when the iterator reaches it
+pub fn read_table_chunked<T: hdf5::H5Type + Send + 'static>(filename: &str, dataset: &str, range: Option<std::ops::Range<usize>>, chunk_size: usize)
+    -> hdf5::Result<impl Iterator<Item = hdf5::Result<Array1<T>>>>
+{
+    let reader = TableChunks::<T>::new(filename, dataset, range, chunk_size)?;
+    Ok(chunks(reader, false).map(|chunk| chunk.map(Array1::from).map_err(hdf5::Error::from)))
+}
+
+/// `patterns`, in order, with each glob pattern (containing `*`, `?` or `[`)
+/// replaced by the files it matches, in lexical order
+pub fn expand_input_files(patterns: &[String]) -> Result<Vec<String>, String> {
+    let mut files = vec![];
+    for pattern in patterns {
+        if !pattern.contains(&['*', '?', '['][..]) {
+            files.push(pattern.clone());
+            continue
+        }
+        let matches = glob::glob(pattern).map_err(|e| format!("Invalid input file pattern '{pattern}': {e}"))?
+            .map(|path| path.map(|path| path.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Expanding input file pattern '{pattern}': {e}"))?;
+        if matches.is_empty() { return Err(format!("No input files match '{pattern}'")) }
+        files.extend(matches);
+    }
+    if files.is_empty() { return Err("No input files given".into()) }
+    Ok(files)
+}
+
+/// Number of rows in the concatenation of `dataset` in each of `files`
+pub fn concatenated_len(files: &[String], dataset: &str) -> hdf5::Result<usize> {
+    files.iter().map(|file| table_len(file, dataset)).sum()
+}
+
+/// As `read_table`, for the concatenation of `dataset` in each of `files`,
+/// reading the files which overlap `range` one at a time
+pub fn read_concatenated<T: hdf5::H5Type + Send + 'static>(files: &[String], dataset: &str, range: Option<std::ops::Range<usize>>)
+    -> hdf5::Result<Vec<T>>
+{
+    let mut reader = ConcatenatedChunks::<T>::new(files, dataset, range, LOR_CHUNK_SIZE)?;
+    let mut rows = vec![];
+    while let Some(chunk) = reader.next_chunk() { rows.extend(chunk?) }
+    Ok(rows)
+}
+
+/// Total number of rows of `dataset`, which must have as many rows as the LOR
+/// table `lors` in each of `files`. `what` describes `dataset` in errors.
+fn aligned_len(files: &[String], dataset: &str, lors: &str, what: &str) -> Result<usize, Box<dyn Error>> {
+    let mut total = 0;
+    for file in files {
+        let (len, n_rows) = (table_len(file, dataset)?, table_len(file, lors)?);
+        if len != n_rows {
+            return Err(format!("{what} dataset '{dataset}' has {len} rows, but LOR table '{lors}' has {n_rows}, in '{file}'").into())
+        }
+        total += len;
+    }
+    Ok(total)
+}
+
+/// Read `range` of `table`. Reads of filtered tables are extended to whole
+/// chunks and trimmed, so that no chunk is decompressed only partially.
+pub(crate) fn read_rows<T: hdf5::H5Type>(table: &hdf5::Dataset, range: Option<std::ops::Range<usize>>) -> hdf5::Result<Array1<T>> {
+    let range = match range {
+        Some(range) => range,
+        None        => return table.read_slice_1d::<T,_>(s![..]),
+    };
+    match filtered_chunk_rows(table) {
+        Some(chunk) if range.start < range.end && range.end <= table.size() => {
+            let aligned = align_to_chunks(range.clone(), chunk, table.size());
+            let data = table.read_slice_1d::<T,_>(s![aligned.clone()])?;
+            Ok(data.slice_move(s![range.start - aligned.start .. range.end - aligned.start]))
+        }
+        _ => table.read_slice_1d::<T,_>(s![range]),
+    }
+}
+
+/// Full paths of all datasets in `group`, recursively
+pub fn list_datasets(group: &hdf5::Group) -> hdf5::Result<Vec<String>> {
+    fn visit(group: &hdf5::Group, prefix: &str, found: &mut Vec<String>) -> hdf5::Result<()> {
+        for name in group.member_names()? {
+            let path = format!("{prefix}{name}");
+            if let Ok(subgroup) = group.group(&name) {
+                visit(&subgroup, &format!("{path}/"), found)?;
+            } else if group.dataset(&name).is_ok() {
+                found.push(path);
+            }
+        }
+        Ok(())
+    }
+    let mut found = vec![];
+    visit(group, "", &mut found)?;
+    Ok(found)
+}
+
+/// Reads consecutive slices of `chunk_size` rows of an HDF5 table. With
+/// compressed tables, slices end on the table's chunk boundaries.
+pub struct TableChunks<T> {
+    filename: String,
+    dataset: String,
+    next: usize,
+    end: usize,
+    chunk_size: usize,
+    /// Rows per chunk of the table's storage, if compressed
+    storage_chunk: Option<usize>,
+    read: RowReader<T>,
+}
+
+/// Reads a range of rows of a table
+type RowReader<T> = std::sync::Arc<dyn Fn(&hdf5::Dataset, std::ops::Range<usize>) -> hdf5::Result<Vec<T>> + Send + Sync>;
+
+/// Reads rows as a whole, with the compound type `T`
+fn compound_rows<T: hdf5::H5Type + 'static>() -> RowReader<T> {
+    std::sync::Arc::new(|table, range| read_rows::<T>(table, Some(range)).map(|array| array.to_vec()))
+}
+
+impl<T: hdf5::H5Type + 'static> TableChunks<T> {
+    pub fn new(filename: &str, dataset: &str, range: Option<std::ops::Range<usize>>, chunk_size: usize) -> hdf5::Result<Self> {
+        let table = open_table(filename, dataset)?;
+        report_compression(&table, dataset);
+        let range = match range {
+            Some(range) if range.end > table.size() => return Err(format!(
+                "Rows {range:?} requested, but '{dataset}' has {} rows in '{filename}'", table.size()).into()),
+            Some(range) => range,
+            None        => 0..table.size(),
+        };
+        let storage_chunk = filtered_chunk_rows(&table);
+        // Whole number of storage chunks per slice
+        let chunk_size = match storage_chunk {
+            Some(rows) => (chunk_size.max(1) + rows - 1) / rows * rows,
+            None       => chunk_size.max(1),
+        };
+        Ok(Self { filename: filename.into(), dataset: dataset.into(),
+                  next: range.start, end: range.end, chunk_size, storage_chunk,
+                  read: compound_rows() })
+    }
+
+    /// Read the rows with `read`, rather than as a whole
+    fn with_reader(mut self, read: RowReader<T>) -> Self { self.read = read; self }
+}
+
+impl<T: hdf5::H5Type + Send + 'static> ChunkReader for TableChunks<T> {
+    type Item = T;
+
+    fn next_chunk(&mut self) -> Option<ChunkResult<T>> {
+        if self.next >= self.end { return None }
+        let hi = match self.storage_chunk {
+            Some(rows) => (self.next + self.chunk_size) / rows * rows,
+            None       =>  self.next + self.chunk_size,
+        }.min(self.end);
+        let (filename, dataset) = (&self.filename, &self.dataset);
+        let chunk = open_table(filename, dataset)
+            .map_err(|e| e.to_string())
+            .and_then(|table| (self.read)(&table, self.next..hi)
+                      .map_err(|e| format!("Reading rows {}..{hi} of '{dataset}' in '{filename}': {e}", self.next)));
+        self.next = hi;
+        Some(chunk)
+    }
+}
+
+/// Reads consecutive slices of the concatenation of an HDF5 table in several
+/// files, as `TableChunks`, opening the next file only once the previous one
+/// has been read. Slices do not straddle files.
+pub struct ConcatenatedChunks<T> {
+    dataset: String,
+    /// The files overlapping the range to be read, and the rows of each
+    parts: std::collections::VecDeque<(String, std::ops::Range<usize>)>,
+    chunk_size: usize,
+    current: Option<TableChunks<T>>,
+    read: RowReader<T>,
+}
+
+impl<T: hdf5::H5Type + 'static> ConcatenatedChunks<T> {
+    /// Rows `range` of the concatenation of `dataset` in each of `files`, or
+    /// all of them
+    pub fn new(files: &[String], dataset: &str, range: Option<std::ops::Range<usize>>, chunk_size: usize) -> hdf5::Result<Self> {
+        let mut parts = std::collections::VecDeque::new();
+        let mut offset = 0;
+        for file in files {
+            let len = open_table(file, dataset)?.size();
+            let (start, end) = match &range {
+                Some(range) => (range.start.clamp(offset, offset + len), range.end.clamp(offset, offset + len)),
+                None        => (offset, offset + len),
+            };
+            if start < end { parts.push_back((file.clone(), start - offset..end - offset)) }
+            offset += len;
+        }
+        if let Some(range) = range.filter(|range| range.end > offset) {
+            return Err(format!("Rows {range:?} requested, but '{dataset}' has {offset} rows in {}", describe_files(files)).into())
+        }
+        Ok(Self { dataset: dataset.into(), parts, chunk_size, current: None, read: compound_rows() })
+    }
+}
+
+impl ConcatenatedChunks<Hdf5Lor> {
+    /// Read the LORs through `columns`, if given, having checked once that
+    /// every file has the columns to which they map
+    pub fn with_columns(mut self, columns: Option<ColumnMap>) -> hdf5::Result<Self> {
+        if let Some(columns) = columns {
+            let columns = LorColumns::new(columns);
+            for (file, _) in &self.parts { columns.check(&open_table(file, &self.dataset)?)? }
+            self.read = std::sync::Arc::new(move |table, range| columns.read(table, range));
+        }
+        Ok(self)
+    }
 }
 
+impl<T: hdf5::H5Type + Send + 'static> ChunkReader for ConcatenatedChunks<T> {
+    type Item = T;
+
+    fn next_chunk(&mut self) -> Option<ChunkResult<T>> {
+        loop {
+            if let Some(chunk) = self.current.as_mut().and_then(TableChunks::next_chunk) { return Some(chunk) }
+            let (file, range) = self.parts.pop_front()?;
+            match TableChunks::new(&file, &self.dataset, Some(range), self.chunk_size) {
+                Ok(table) => self.current = Some(table.with_reader(self.read.clone())),
+                Err(e)    => return Some(Err(e.to_string())),
+            }
+        }
+    }
+}
 
 /// Fill `scattergram`, with spatial distribution of scatters probabilities
 /// gathered from `lors`
 fn fill_scattergram(scattergram: &mut Option<Scattergram>, lors: &[Hdf5Lor]) {
     if let Some(ref mut scattergram) = scattergram.as_mut() {
-        for h5lor @&Hdf5Lor { x1, x2, E1, E2, .. } in lors {
-            if x1.is_nan() || x2.is_nan() { continue }
-            let prompt = if E1.min(E2) < 510.0 { Prompt::Scatter } else { Prompt::True };
-            scattergram.fill(prompt, &LOR::from(h5lor));
+        let _span = info_span!("scattergram_fill", n_lors = lors.len()).entered();
+        for meta in lors.iter().map(LorWithMeta::from) {
+            if let Some(prompt) = classify(&meta, scattergram.true_threshold()) { scattergram.fill(prompt, &meta.lor) }
         }
     }
 }
 
-/// Read HDF5 LORs from file, potentially filtering according to event, energy
-/// and charge ranges
-fn read_hdf5_lors(
-    input_file: &str, dataset: &str,
-    event_range: Option<std::ops::Range<usize>>,
-    qcut: BoundPair<Chargef32>, ecut: BoundPair<Energyf32>
-) -> Result<(Vec<Hdf5Lor>, usize), Box<dyn Error>> {
-    let mut cut = 0;
-    // Read LOR data from disk
-    let hdf5_lors: Vec<Hdf5Lor> = {
-        read_table::<Hdf5Lor>(input_file, dataset, event_range)?
-            .iter().cloned()
-            .filter(|Hdf5Lor{E1, E2, q1, q2, ..}| {
-                let eok = ecut.contains(E1) && ecut.contains(E2);
-                let qok = qcut.contains(q1) && qcut.contains(q2);
-                if eok && qok { true }
-                else { cut += 1; false }
-            })
-            .collect()
-    };
-    Ok((hdf5_lors, cut))
+/// `lors` with their energies and charges, for `lorogram::fill_scattergram`
+pub fn with_meta<'a>(lors: impl IntoIterator<Item = &'a Hdf5Lor> + 'a) -> impl Iterator<Item = LorWithMeta> + 'a {
+    lors.into_iter().map(LorWithMeta::from)
 }
 
-#[allow(nonstandard_style)]
-pub fn read_lors(args: Args, mut scattergram: Option<Scattergram>) -> Result<Vec<LOR>, Box<dyn Error>> {
-    // Read LORs from file,
-    let (hdf5_lors, cut) = read_hdf5_lors(&args.input_file, &args.dataset,
-                                          args.event_range.clone(),
-                                          args.qcut, args.ecut)?;
+/// Whether `meta` counts as a true or a scatter in scattergrams, given the
+/// energy `threshold` of trues. `None` for events without positions.
+fn classify(&LorWithMeta { lor, e1, e2, .. }: &LorWithMeta, threshold: Energyf32) -> Option<Prompt> {
+    if lor.p1.x.is_nan() || lor.p2.x.is_nan() { return None }
+    Some(classify_energies(e1, e2, threshold))
+}
+
+/// Acceptance-angle cut: LORs with polar angle (from the transverse plane)
+/// within `[min, max]`
+pub fn theta_bounds(min: Option<Angle>, max: Option<Angle>) -> BoundPair<Angle> {
+    use std::ops::Bound::{Included, Unbounded};
+    (min.map_or(Unbounded, Included), max.map_or(Unbounded, Included))
+}
+
+/// Whether `lor` survives the acceptance-angle cut `theta_cut`
+pub fn passes_theta_cut(lor: &LOR, theta_cut: &BoundPair<Angle>) -> bool { theta_cut.contains(&theta(lor)) }
 
-    // Use LORs to gather statistics about spatial distribution of scatter probability
-    fill_scattergram(&mut scattergram, &hdf5_lors);
+/// Numbers of events rejected by each cut
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CutCounts {
+    /// Energy and charge
+    pub eq: usize,
+    /// Acceptance angle
+    pub theta: usize,
+    /// Coincident endpoints (see `DegeneratePolicy`)
+    pub degenerate: usize,
+}
+
+impl CutCounts {
+    pub fn total(&self) -> usize { self.eq + self.theta + self.degenerate }
+}
 
-    let hdf5lor_to_lor: Box<dyn Fn(Hdf5Lor) -> LOR> = if let Some(scattergram) = scattergram.as_ref() {
+/// The single pass over the file shared by all readers of LORs: the rows
+/// produced by the reader returned by `open` are filtered according to the
+/// event, energy, charge and acceptance-angle ranges and the degenerate LOR
+/// policy in `args`, the accepted
+/// ones are used to fill `scattergram` (if any) chunk by chunk, and are
+/// collected. If `prefetch` is true, the next chunk is read while the current
+/// one is being processed. If `rows` is given, the positions in the whole
+/// table of the accepted rows are appended to it.
+fn read_and_classify<R, O>(
+    open: O,
+    args: &Args,
+    scattergram: &mut Option<Scattergram>,
+    prefetch: bool,
+    mut rows: Option<&mut Vec<usize>>,
+) -> Result<(Vec<Hdf5Lor>, CutCounts), Box<dyn Error>>
+where
+    R: ChunkReader<Item = Hdf5Lor>,
+    O: FnOnce() -> hdf5::Result<R>,
+{
+    let files = args.describe_input();
+    let _span = info_span!("read_hdf5", files = files.as_str(), dataset = args.dataset.as_str(), prefetch).entered();
+    let Args { ecut, qcut, q2cut, theta_cut, split, degenerate, canonicalize_endpoints, transform, .. } = args.clone();
+    if let Some(scattergram) = scattergram.as_ref() {
+        memory::allocated("scattergram", scattergram.size_in_bytes());
+    }
+    let mut cut = CutCounts::default();
+    // First degenerate LOR rejected by `DegeneratePolicy::Error`
+    let mut failure = None;
+    let mut hdf5_lors = vec![];
+    // Position of the next event in the whole dataset, for splitting
+    let mut event = args.event_range.as_ref().map_or(0, |r| r.start);
+    // Read LOR data from disk
+    for chunk in chunks(open()?, prefetch) {
+        let chunk = chunk?;
+        let accepted = {
+            let _span = info_span!("filter", n_lors = chunk.len()).entered();
+            let first = event;
+            event += chunk.len();
+            chunk
+                .into_iter()
+                .enumerate()
+                .map(|(i, lor)| {
+                    let lor = match &transform { Some(t) => lor.transformed(t), None => lor };
+                    (first + i, if canonicalize_endpoints { lor.canonicalized() } else { lor })
+                })
+                .filter(|(row, _)| match split { Some(split) => split.keeps(*row), None => true })
+                .filter(|(_, Hdf5Lor{E1, E2, q1, q2, ..})| {
+                    let eok = ecut.contains(E1) && ecut.contains(E2);
+                    let qok = qcut.contains(q1) && q2cut.unwrap_or(qcut).contains(q2);
+                    if eok && qok { true }
+                    else { cut.eq += 1; false }
+                })
+                .filter(|(row, h5lor)| {
+                    match degenerate.admit(&LOR::from(h5lor)) {
+                        Ok(keep) => { if !keep { cut.degenerate += 1 } keep }
+                        Err(e) => { if failure.is_none() { failure = Some(format!("row {row}: {e}")) } false }
+                    }
+                })
+                .filter(|(_, h5lor)| {
+                    if passes_theta_cut(&LOR::from(h5lor), &theta_cut) { true }
+                    else { cut.theta += 1; false }
+                })
+                .map(|(row, lor)| {
+                    if let Some(rows) = rows.as_mut() { rows.push(row) }
+                    lor
+                })
+                .collect::<Vec<_>>()
+        };
+        if let Some(failure) = failure.take() { return Err(failure.into()) }
+        // Use LORs to gather statistics about spatial distribution of scatter probability
+        fill_scattergram(scattergram, &accepted);
+        hdf5_lors.extend(accepted);
+    }
+    Ok((hdf5_lors, cut))
+}
+
+/// Convert raw data (Hdf5Lors) to LORs used by MLEM, with the scatter
+/// corrections given by `scattergram`
+fn to_lors(hdf5_lors: Vec<Hdf5Lor>, scattergram: Option<&Scattergram>) -> Vec<LOR> {
+    let hdf5lor_to_lor: Box<dyn Fn(Hdf5Lor) -> LOR> = if let Some(scattergram) = scattergram {
         Box::new(|hdf5_lor: Hdf5Lor| {
             let mut lor: LOR = hdf5_lor.into();
-            lor.additive_correction = scattergram.value(&lor);
+            lor.corrections.scatter = scattergram.value(&lor);
             lor
         })
     } else { Box::new(LOR::from) };
 
-    // Convert raw data (Hdf5Lors) to LORs used by MLEM
-    let lors: Vec<_> = hdf5_lors
+    info_span!("convert").in_scope(|| hdf5_lors
         .into_iter()
         .map(hdf5lor_to_lor)
-        .collect();
+        .collect())
+}
+
+fn open_lor_table(args: &Args) -> impl FnOnce() -> hdf5::Result<ConcatenatedChunks<Hdf5Lor>> + '_ {
+    move || Ok(ConcatenatedChunks::new(&args.files()?, &args.dataset, args.event_range.clone(), LOR_CHUNK_SIZE)?
+        .with_columns(args.column_map.clone())?)
+}
+
+/// As `open_lor_table`, for the memory-mapped LOR `file` (see `io::mapped`),
+/// which is used in place of HDF5 tables wherever it is the input
+fn open_mapped_lors(file: String, args: &Args) -> impl FnOnce() -> hdf5::Result<MappedChunks> + '_ {
+    move || MappedChunks::new(&file, args.event_range.clone(), LOR_CHUNK_SIZE).map_err(hdf5::Error::from)
+}
+
+pub fn read_lors(args: Args, scattergram: Option<Scattergram>) -> Result<Vec<LOR>, Box<dyn Error>> {
+    read_lors_prefetching(args, scattergram, true)
+}
+
+/// As `read_lors`, with control over whether reading of the next chunk of the
+/// file overlaps with processing of the current one
+pub fn read_lors_prefetching(args: Args, scattergram: Option<Scattergram>, prefetch: bool) -> Result<Vec<LOR>, Box<dyn Error>> {
+    Ok(read_lors_and_scattergram(args, scattergram, prefetch)?.0)
+}
+
+/// As `read_lors`, keeping the energies and charges of the LORs, which carry
+/// any external corrections but no scatter corrections
+pub fn read_lors_with_meta(args: Args) -> Result<Vec<LorWithMeta>, Box<dyn Error>> {
+    Ok(read_lors_with_rows(args)?.into_iter().map(|(_, meta)| meta).collect())
+}
+
+/// As `read_lors_with_meta`, pairing each LOR with its position in the whole
+/// table, which is not its position in the result when rows have been cut
+pub fn read_lors_with_rows(args: Args) -> Result<Vec<(usize, LorWithMeta)>, Box<dyn Error>> {
+    match args.mapped_file()? {
+        Some(file) => lors_with_rows_with(open_mapped_lors(file, &args), &args),
+        None       => lors_with_rows_with(open_lor_table        (&args), &args),
+    }
+}
+
+fn lors_with_rows_with<R, O>(open: O, args: &Args) -> Result<Vec<(usize, LorWithMeta)>, Box<dyn Error>>
+where
+    R: ChunkReader<Item = Hdf5Lor>,
+    O: FnOnce() -> hdf5::Result<R>,
+{
+    let _span = info_span!("read_lors_with_meta").entered();
+    let external = ExternalCorrections::read(args)?;
+    let mut rows = vec![];
+    let (hdf5_lors, _) = read_and_classify(open, args, &mut None, true, Some(&mut rows))?;
+    let mut lors: Vec<LorWithMeta> = info_span!("convert").in_scope(|| hdf5_lors.iter().map(LorWithMeta::from).collect());
+    if let Some(external) = &external {
+        for (meta, &row) in lors.iter_mut().zip(&rows) { external.apply(&mut meta.lor, row) }
+    }
+    Ok(rows.into_iter().zip(lors).collect())
+}
+
+/// Read LORs and fill `scattergram` in a single pass over the file. The LORs
+/// carry the scatter corrections of the completed scattergram, which is
+/// returned alongside them.
+pub fn read_lors_and_scattergram(args: Args, scattergram: Option<Scattergram>, prefetch: bool)
+    -> Result<(Vec<LOR>, Option<Scattergram>), Box<dyn Error>>
+{
+    match args.mapped_file()? {
+        Some(file) => lors_and_scattergram_with(open_mapped_lors(file, &args), &args, scattergram, prefetch),
+        None       => lors_and_scattergram_with(open_lor_table        (&args), &args, scattergram, prefetch),
+    }
+}
+
+fn lors_and_scattergram_with<R, O>(open: O, args: &Args, mut scattergram: Option<Scattergram>, prefetch: bool)
+    -> Result<(Vec<LOR>, Option<Scattergram>), Box<dyn Error>>
+where
+    R: ChunkReader<Item = Hdf5Lor>,
+    O: FnOnce() -> hdf5::Result<R>,
+{
+    let _span = info_span!("read_lors").entered();
+    let external = ExternalCorrections::read(args)?;
+    // Windowed scattergrams are filled after reading, once the times are known
+    let windows = match (&args.scatter_windows, scattergram.take()) {
+        (Some(_), Some(binning)) if binning.is_adaptive() =>
+            return Err("Adaptive scattergram binning cannot be combined with scatter windows".into()),
+        (Some(windows), Some(binning)) => Some((windows, binning)),
+        (_, binning) => { scattergram = binning; None },
+    };
+    // Original rows of the accepted LORs, to look up their external corrections
+    // and acquisition times
+    let mut rows = vec![];
+    let record_rows = if external.is_some() || windows.is_some() { Some(&mut rows) } else { None };
+    let (hdf5_lors, cut) = read_and_classify(open, args, &mut scattergram, prefetch, record_rows)?;
+
+    let hdf5_bytes = memory::size_of_slice(&hdf5_lors);
+    memory::allocated("hdf5_lors", hdf5_bytes);
+    let mut lors = if let Some((windows, binning)) = windows {
+        let (lors, merged) = windowed_lors(args, windows, binning, hdf5_lors, &rows)?;
+        scattergram = Some(merged);
+        lors
+    } else {
+        if let Some(scattergram) = &mut scattergram { adapt_scattergram(scattergram)? }
+        to_lors(hdf5_lors, scattergram.as_ref())
+    };
+    memory::freed("hdf5_lors", hdf5_bytes);
+    memory::allocated("lors", memory::size_of_slice(&lors));
+    if let Some(external) = &external {
+        for (lor, &row) in lors.iter_mut().zip(&rows) { external.apply(lor, row) }
+    }
+
+    if let Some(occupancy) = scattergram.as_ref().map(Scattergram::validate) {
+        if occupancy.n_without_trues > 0 {
+            let empty = ratio_(scattergram.as_ref().unwrap().empty_bin_value());
+            tracing::warn!("{occupancy}: LORs in bins without trues get scatter correction {empty}");
+        }
+    }
+    if let Some(scattergram) = &scattergram {
+        tracing::info!("{}", scattergram.occupancy_report(Some(&occupancy_sample(&lors))));
+    }
 
     let used = lors.len();
-    let used_pct = 100 * used / (used + cut);
     use crate::utils::group_digits as g;
-    println!("Using {} LORs (cut {}    kept {}%)",
-               g(used),      g(cut),   used_pct);
-    Ok(lors)
+    tracing::info!("Using {} LORs (cut {}: energy/charge {}, theta {}, degenerate {}    kept {})",
+                     g(used), g(cut.total()), g(cut.eq), g(cut.theta), g(cut.degenerate),
+                     format_percentage(used, used + cut.total()));
+    if let Some(external) = &external {
+        if external.invalid > 0 {
+            tracing::warn!("{} invalid external correction values replaced by the identity", g(external.invalid));
+        }
+    }
+    Ok((lors, scattergram))
 }
 
+/// Convert `hdf5_lors`, which came from `rows` of the LOR table, to LORs with
+/// scatter corrections from a `WindowedScattergram` binned like `binning`,
+/// whose windows span the acquisition times of the events in `args`' range.
+/// Returns the LORs and the windows merged into a single scattergram.
+fn windowed_lors(args: &Args, windows: &ScatterWindowArgs, binning: Scattergram, hdf5_lors: Vec<Hdf5Lor>, rows: &[usize])
+    -> Result<(Vec<LOR>, Scattergram), Box<dyn Error>>
+{
+    let _span = info_span!("scattergram_windows", n_windows = windows.n_windows).entered();
+    let ScatterWindowArgs { time_dataset, n_windows } = windows;
+    let files = args.files()?;
+    let n_rows = aligned_len(&files, time_dataset, &args.dataset, "Acquisition time")?;
+    let range = args.event_range.clone().unwrap_or(0..n_rows);
+    let start_row = range.start;
+    let times = read_concatenated::<f32>(&files, time_dataset, Some(range))?;
+    let (start, end) = times.iter().filter(|t| t.is_finite())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &t| (lo.min(t), hi.max(t)));
+    let mut sgram = WindowedScattergram::new(binning, start, end, *n_windows)
+        .map_err(|e| format!("Scatter windows from '{time_dataset}': {e}"))?;
+    memory::allocated("scattergram_windows", sgram.size_in_bytes());
+    let time_of = |row: usize| times[row - start_row];
+    for (meta, &row) in hdf5_lors.iter().map(LorWithMeta::from).zip(rows) {
+        if let Some(prompt) = classify(&meta, sgram.true_threshold()) { sgram.fill(prompt, &meta.lor, time_of(row)) }
+    }
+    let lors = hdf5_lors.into_iter().zip(rows)
+        .map(|(h5lor, &row)| {
+            let mut lor = LOR::from(h5lor);
+            lor.corrections.scatter = sgram.value(&lor, time_of(row));
+            lor
+        })
+        .collect();
+    memory::freed("scattergram_windows", sgram.size_in_bytes());
+    Ok((lors, sgram.merged()))
+}
 
+/// Fill `scattergram` from the LORs selected by `args`, without keeping them
+pub fn read_scattergram(args: Args, scattergram: Scattergram) -> Result<Scattergram, Box<dyn Error>> {
+    match args.mapped_file()? {
+        Some(file) => scattergram_with(open_mapped_lors(file, &args), &args, scattergram),
+        None       => scattergram_with(open_lor_table        (&args), &args, scattergram),
+    }
+}
 
-// --------------------------------------------------------------------------------
-#[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
-#[repr(C)]
-pub struct SensorXYZ {
-    pub sensor_id: u32,
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+fn scattergram_with<R, O>(open: O, args: &Args, scattergram: Scattergram) -> Result<Scattergram, Box<dyn Error>>
+where
+    R: ChunkReader<Item = Hdf5Lor>,
+    O: FnOnce() -> hdf5::Result<R>,
+{
+    let mut scattergram = Some(scattergram);
+    read_and_classify(open, args, &mut scattergram, true, None)?;
+    let mut scattergram = scattergram.unwrap();
+    adapt_scattergram(&mut scattergram)?;
+    tracing::info!("{}", scattergram.occupancy_report(None));
+    Ok(scattergram)
+}
+
+/// Merge the bins of a filled `scattergram` with adaptive binning, reporting
+/// the chosen axes
+fn adapt_scattergram(scattergram: &mut Scattergram) -> Result<(), Box<dyn Error>> {
+    if !scattergram.is_adaptive() { return Ok(()) }
+    let short = scattergram.adapt_binning()?;
+    if let Some(axes) = scattergram.adapted_axes() {
+        let axes = axes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        tracing::info!("Adaptive scattergram axes: {}", axes.join(", "));
+    }
+    if short > 0 {
+        tracing::warn!("{short} adaptive scattergram bins remain below the minimum count: too few events");
+    }
+    Ok(())
+}
+
+/// Maximum number of LORs in the usage samples of `occupancy_sample`
+pub const OCCUPANCY_SAMPLE: usize = 100_000;
+
+/// Up to `OCCUPANCY_SAMPLE` of `lors`, evenly spread through them, with which
+/// to weight `Scattergram::occupancy_report` by usage
+pub fn occupancy_sample(lors: &[LOR]) -> Vec<LOR> {
+    let step = (lors.len() / OCCUPANCY_SAMPLE).max(1);
+    lors.iter().step_by(step).take(OCCUPANCY_SAMPLE).copied().collect()
+}
+
+/// Per-row corrections computed by external tools, read from 1D datasets
+/// aligned with the LOR table. Several multiplicative datasets are combined by
+/// multiplication, several additive ones by addition. Dead-time corrections
+/// are combined with the multiplicative ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExternalCorrections {
+    /// Row of the LOR table corresponding to the first element of each column
+    start: usize,
+    multiplicative: Option<Vec<f32>>,
+    additive: Option<Vec<f32>>,
+    /// Number of values which were NaN (or otherwise invalid) and were replaced
+    /// by the identity
+    pub invalid: usize,
+}
+
+impl ExternalCorrections {
+    /// The corrections named in `args`, covering its event range. `None` if
+    /// there are none.
+    pub fn read(args: &Args) -> Result<Option<Self>, Box<dyn Error>> {
+        if args.mult_corrections.is_empty() && args.add_corrections.is_empty() && args.dead_time.is_none() { return Ok(None) }
+        let files = args.files()?;
+        let n_rows = concatenated_len(&files, &args.dataset)?;
+        let range = args.event_range.clone().unwrap_or(0..n_rows);
+        let mut corrections = Self { start: range.start, ..Self::default() };
+
+        let mut combine = |datasets: &[String], identity: f32, valid: fn(f32) -> bool, op: fn(f32, f32) -> f32|
+                           -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+            let mut combined: Option<Vec<f32>> = None;
+            for dataset in datasets {
+                aligned_len(&files, dataset, &args.dataset, "Correction")?;
+                let column = read_concatenated::<f32>(&files, dataset, Some(range.clone()))?;
+                let column = column.iter().map(|&v| {
+                    if valid(v) { v } else { corrections.invalid += 1; identity }
+                });
+                combined = Some(match combined {
+                    None      => column.collect(),
+                    Some(acc) => acc.into_iter().zip(column).map(|(a, v)| op(a, v)).collect(),
+                });
+            }
+            Ok(combined)
+        };
+        let mut multiplicative = combine(&args.mult_corrections, 1.0, |v| v.is_finite() && v > 0.0, |a, b| a * b)?;
+        let additive           = combine(&args. add_corrections, 0.0, |v| v.is_finite() && v >= 0.0, |a, b| a + b)?;
+
+        if let Some(DeadTimeArgs { time_dataset, singles_dataset, model, tau }) = &args.dead_time {
+            let singles = read_concatenated::<SinglesRate>(&files, singles_dataset, None)?;
+            let dead_time = DeadTimeCorrection::new(&singles, *model, *tau)
+                .map_err(|e| format!("Dead time from '{singles_dataset}': {e}"))?;
+            aligned_len(&files, time_dataset, &args.dataset, "Acquisition time")?;
+            let times = read_concatenated::<f32>(&files, time_dataset, Some(range))?;
+            let live = times.iter().map(|&t| {
+                dead_time.live_fraction_at(t).unwrap_or_else(|| { corrections.invalid += 1; 1.0 })
+            });
+            multiplicative = Some(match multiplicative {
+                None      => live.collect(),
+                Some(acc) => acc.into_iter().zip(live).map(|(a, l)| a * l).collect(),
+            });
+        }
+        corrections.multiplicative = multiplicative;
+        corrections.additive = additive;
+        Ok(Some(corrections))
+    }
+
+    /// Compose the corrections of the LOR table's `row` with those of `lor`
+    pub fn apply(&self, lor: &mut LOR, row: usize) {
+        let i = row - self.start;
+        if let Some(m) = &self.multiplicative { lor.corrections.multiplicative *= m[i] }
+        if let Some(a) = &self.additive       { lor.corrections.additive       += a[i] }
+    }
+}
+
+/// Write `data` to a new file, in `dataset`, which may include groups:
+/// `group/subgroup/dataset`
+pub fn write_table<T: hdf5::H5Type>(filename: &str, dataset: &str, data: &[T]) -> hdf5::Result<()> {
+    Writer::create(filename)?.write(dataset, ndarray::ArrayView1::from(data))?;
+    Ok(())
 }
 
+// --------------------------------------------------------------------------------
+pub use crate::sensors::{Charge, SensorHit, SensorXYZ};
+
+/// Row of the `MC/waveform` table of MC files: the time of one sensor's signal
 #[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
 #[repr(C)]
-pub struct Charge {
-    pub event_id: u64,
-    pub sensor_id: u64,
-    pub charge: u64,
+pub struct Waveform {
+    pub event_id: u32,
+    pub sensor_id: u32,
+    pub time: f32,
 }
 
+/// Row of the `MC/total_charge` table of MC files: the charge collected by one
+/// sensor in one event
 #[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
 #[repr(C)]
-pub struct SensorHit {
-    pub event_id: u64,
-    pub sensor_id: u64,
-    pub time: f64,
+pub struct Qtot {
+    pub event_id: u32,
+    pub sensor_id: u32,
+    pub charge: u32,
 }
 
 // The LOR used by mlem contains fields (the points) with types (ncollide Point)
@@ -157,10 +971,10 @@ impl From<Hdf5Lor> for LOR {
     fn from(lor: Hdf5Lor) -> Self {
         let Hdf5Lor{dt, x1, y1, z1, x2, y2, z2, ..} = lor;
         Self {
-            dt: ns(dt),
-            p1: Point::new(mm(x1), mm(y1), mm(z1)),
-            p2: Point::new(mm(x2), mm(y2), mm(z2)),
-            additive_correction: ratio(1.0)
+            dt: ns_from_file(dt),
+            p1: point_from_file(x1, y1, z1),
+            p2: point_from_file(x2, y2, z2),
+            corrections: Corrections::NONE,
         }
     }
 }
@@ -169,14 +983,60 @@ impl From<&Hdf5Lor> for LOR {
     fn from(lor: &Hdf5Lor) -> Self {
         let &Hdf5Lor{dt, x1, y1, z1, x2, y2, z2, ..} = lor;
         Self {
-            dt: ns(dt),
-            p1: Point::new(mm(x1), mm(y1), mm(z1)),
-            p2: Point::new(mm(x2), mm(y2), mm(z2)),
-            additive_correction: ratio(1.0)
+            dt: ns_from_file(dt),
+            p1: point_from_file(x1, y1, z1),
+            p2: point_from_file(x2, y2, z2),
+            corrections: Corrections::NONE,
         }
     }
 }
 
+impl Hdf5Lor {
+    /// The same coincidence with its endpoints, charges and energies exchanged,
+    /// and the sign of `dt` flipped to match
+    pub fn swapped(self) -> Self {
+        let Self { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1, E2 } = self;
+        Self { dt: -dt, x1: x2, y1: y2, z1: z2, x2: x1, y2: y1, z2: z1, q1: q2, q2: q1, E1: E2, E2: E1 }
+    }
+
+    /// The same coincidence, with its endpoints in canonical order (see
+    /// `system_matrix::endpoint_order`)
+    pub fn canonicalized(self) -> Self {
+        let order = endpoint_order((self.x1, self.y1, self.z1), (self.x2, self.y2, self.z2));
+        if canonical(order, self.dt) { self } else { self.swapped() }
+    }
+
+    /// The same coincidence, with both endpoints moved by `transform`
+    pub fn transformed(self, transform: &RigidTransform) -> Self {
+        let (x1, y1, z1) = point_to_file(transform.apply(point_from_file(self.x1, self.y1, self.z1)));
+        let (x2, y2, z2) = point_to_file(transform.apply(point_from_file(self.x2, self.y2, self.z2)));
+        Self { x1, y1, z1, x2, y2, z2, ..self }
+    }
+}
+
+impl From<&Hdf5Lor> for LorWithMeta {
+    fn from(h5lor: &Hdf5Lor) -> Self {
+        let &Hdf5Lor { q1, q2, E1, E2, .. } = h5lor;
+        Self { lor: LOR::from(h5lor), e1: E1, e2: E2, q1, q2 }
+    }
+}
+
+impl From<&LorWithMeta> for Hdf5Lor {
+    fn from(meta: &LorWithMeta) -> Self {
+        Self { q1: meta.q1, q2: meta.q2, E1: meta.e1, E2: meta.e2, ..Self::from(&meta.lor) }
+    }
+}
+
+/// Charges and energies are not known, and are set to NaN
+impl From<&LOR> for Hdf5Lor {
+    fn from(lor: &LOR) -> Self {
+        let (x1, y1, z1) = point_to_file(lor.p1);
+        let (x2, y2, z2) = point_to_file(lor.p2);
+        let nan = f32::NAN;
+        Self { dt: ns_to_file(lor.dt), x1, y1, z1, x2, y2, z2, q1: nan, q2: nan, E1: nan, E2: nan }
+    }
+}
+
 // --------------------------------------------------------------------------------
 #[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
 #[repr(C)]
@@ -189,3 +1049,761 @@ pub struct Primary {
     pub vy: f32,
     pub vz: f32,
 }
+
+/// LOR tables and arguments shared by the tests of reading LORs
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+
+    /// A true coincidence across the x axis, whose endpoints move with `i`
+    pub fn hdf5_lor(i: usize) -> Hdf5Lor {
+        let f = i as f32;
+        Hdf5Lor { dt: 0.0, x1: -300.0, y1: f, z1: f, x2: 300.0, y2: -f, z2: f,
+                  q1: 1000.0, q2: 1000.0, E1: ELECTRON_REST_ENERGY, E2: ELECTRON_REST_ENERGY }
+    }
+
+    /// Every row of the LOR table in `path`
+    pub fn args(path: &str) -> Args { Args { input_files: vec![path.into()], ..Args::default() } }
+
+    /// Write `rows` as the LOR table of the file `name` in `dir`, whose path is
+    /// returned
+    pub fn write_lors(dir: &std::path::Path, name: &str, rows: &[Hdf5Lor]) -> hdf5::Result<String> {
+        let path = dir.join(name).to_str().unwrap().to_string();
+        write_table(&path, DEFAULT_LOR_DATASET, rows)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test_read_table {
+    use super::*;
+
+    fn primary(event_id: u32) -> Primary {
+        Primary { event_id, x: 0.0, y: 0.0, z: 0.0, vx: 0.0, vy: 0.0, vz: 0.0 }
+    }
+
+    #[test]
+    fn missing_file_and_missing_dataset_are_distinguished() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("primaries.h5");
+        let path = path.to_str().unwrap();
+        {
+            let file = hdf5::File::create(path)?;
+            file.create_group("MC")?
+                .new_dataset_builder()
+                .with_data(&[primary(1), primary(2)])
+                .create("primaries")?;
+            file.create_group("reco_info")?;
+        }
+
+        let read = read_table::<Primary>(path, "MC/primaries", None)?;
+        assert_eq!(read.len(), 2);
+
+        let missing_file = dir.path().join("nope.h5");
+        let err = read_table::<Primary>(missing_file.to_str().unwrap(), "MC/primaries", None).unwrap_err();
+        assert!(err.to_string().contains("File not found"), "{err}");
+
+        let err = read_table::<Primary>(path, "reco_info/lors", None).unwrap_err().to_string();
+        assert!(err.contains("Dataset 'reco_info/lors' not found"), "{err}");
+        assert!(err.contains("MC/primaries"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn write_table_creates_nested_groups() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("written.h5");
+        let path = path.to_str().unwrap();
+        let primaries: Vec<Primary> = (0..4).map(primary).collect();
+        write_table(path, "a/b/primaries", &primaries)?;
+        assert_eq!(read_table::<Primary>(path, "a/b/primaries", None)?.to_vec(), primaries);
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_reading_with_and_without_prefetching() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("primaries.h5");
+        let path = path.to_str().unwrap();
+        let primaries: Vec<Primary> = (0..10).map(primary).collect();
+        hdf5::File::create(path)?
+            .create_group("MC")?
+            .new_dataset_builder()
+            .with_data(&primaries)
+            .create("primaries")?;
+
+        let read = |range: Option<std::ops::Range<usize>>, prefetch| -> Result<Vec<Primary>, Box<dyn Error>> {
+            let reader = TableChunks::<Primary>::new(path, "MC/primaries", range, 3)?;
+            let mut all = vec![];
+            for chunk in chunks(reader, prefetch) { all.extend(chunk?) }
+            Ok(all)
+        };
+        assert_eq!(read(None, false)?, primaries);
+        assert_eq!(read(None, true )?, primaries);
+        assert_eq!(read(Some(2..9), true)?, primaries[2..9].to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_tables_read_correctly_in_aligned_chunks() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("compressed.h5");
+        let path = path.to_str().unwrap();
+        let primaries: Vec<Primary> = (0..45).map(primary).collect();
+        hdf5::File::create(path)?
+            .create_group("MC")?
+            .new_dataset_builder()
+            .chunk(10)
+            .shuffle()
+            .deflate(6)
+            .with_data(&primaries)
+            .create("primaries")?;
+        let table = open_table(path, "MC/primaries")?;
+        assert_eq!(filtered_chunk_rows(&table), Some(10));
+
+        // Single reads, including ranges which straddle chunk boundaries
+        assert_eq!(read_table::<Primary>(path, "MC/primaries", None)?.to_vec(), primaries);
+        for range in [0..1, 9..11, 12..38, 44..45] {
+            assert_eq!(read_table::<Primary>(path, "MC/primaries", Some(range.clone()))?.to_vec(), primaries[range].to_vec());
+        }
+
+        // Slices starting mid-chunk end on chunk boundaries
+        let range = 3..41;
+        let slices: Vec<Vec<Primary>> = chunks(TableChunks::<Primary>::new(path, "MC/primaries", Some(range.clone()), 15)?, false)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(slices.iter().map(Vec::len).collect::<Vec<_>>(), [17, 20, 1]);
+        assert_eq!(slices.concat(), primaries[range].to_vec());
+        Ok(())
+    }
+
+    /// Layout of `reco_info/table` in `src/io/test.h5`
+    #[derive(hdf5::H5Type, Clone, PartialEq, Debug)]
+    #[repr(C)]
+    struct RecoRow {
+        event_id: f64, true_energy: f64,
+        true_r1: f64, true_phi1: f64, true_z1: f64, true_t1: f64,
+        true_r2: f64, true_phi2: f64, true_z2: f64, true_t2: f64,
+        phot_like1: f64, phot_like2: f64,
+        reco_r1: f64, reco_phi1: f64, reco_z1: f64, reco_t1: f64,
+        reco_r2: f64, reco_phi2: f64, reco_z2: f64, reco_t2: f64,
+        not_sel: f64,
+    }
+
+    #[test]
+    fn chunked_read_matches_one_shot_read() -> Result<(), Box<dyn Error>> {
+        let (path, dataset) = ("src/io/test.h5", "reco_info/table");
+        let all = read_table::<RecoRow>(path, dataset, None)?.to_vec();
+        assert!(all.len() > 3);
+        for range in [None, Some(0..all.len()), Some(1..all.len() - 2), Some(2..3), Some(4..4)] {
+            let mut chunked = vec![];
+            for chunk in read_table_chunked::<RecoRow>(path, dataset, range.clone(), 3)? {
+                let chunk = chunk?;
+                assert!(chunk.len() <= 3);
+                chunked.extend(chunk.to_vec());
+            }
+            let expected = range.map_or(all.clone(), |range| all[range].to_vec());
+            assert_eq!(chunked, expected);
+        }
+        assert!(read_table_chunked::<RecoRow>(path, dataset, Some(0..all.len() + 1), 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_filter_is_named() {
+        // 511 is reserved for testing, and never registered
+        let err = check_filter_available(511, "run.h5", "reco_info/lors").unwrap_err().to_string();
+        assert!(err.contains("reco_info/lors"), "{err}");
+        assert!(err.contains("511"), "{err}");
+        assert!(err.contains("HDF5_PLUGIN_PATH"), "{err}");
+        assert!(check_filter_available(2, "run.h5", "reco_info/lors").is_ok()); // shuffle is built in
+    }
+}
+
+#[cfg(test)]
+mod test_theta_cut {
+    use super::*;
+    use crate::lorogram::mk_lor;
+    use geometry::units::degree;
+    use rstest::rstest;
+
+    fn swapped(lor: LOR) -> LOR { LOR { p1: lor.p2, p2: lor.p1, ..lor } }
+
+    #[rstest(max, case(0.5), case(10.0), case(45.0), case(89.0))]
+    fn transverse_lors_pass_any_max_theta(max: f32) {
+        let cut = theta_bounds(None, Some(degree(max)));
+        let lor = mk_lor(((-300.0, 20.0, 17.0), (300.0, -40.0, 17.0)));
+        assert!(passes_theta_cut(&lor, &cut));
+    }
+
+    #[test]
+    fn nearly_axial_lors_fail_45_degrees() {
+        let cut = theta_bounds(None, Some(degree(45.0)));
+        let lor = mk_lor(((1.0, 0.0, -500.0), (-1.0, 0.0, 500.0)));
+        assert!(!passes_theta_cut(&lor, &cut));
+        assert!( passes_theta_cut(&lor, &theta_bounds(Some(degree(45.0)), None)));
+    }
+
+    #[rstest(/**/ min  , max  ,
+             case(None , Some(30.0)),
+             case(Some(20.0), None),
+             case(Some(10.0), Some(60.0)),
+    )]
+    fn endpoint_order_does_not_matter(min: Option<f32>, max: Option<f32>) {
+        let cut = theta_bounds(min.map(degree), max.map(degree));
+        for &(dx, dz) in &[(300.0, 0.0), (300.0, 100.0), (100.0, 300.0), (200.0, -200.0), (0.0, 500.0)] {
+            let lor = mk_lor(((-dx, 10.0, -dz), (dx, -10.0, dz)));
+            assert_eq!(passes_theta_cut(&lor, &cut), passes_theta_cut(&swapped(lor), &cut), "dx={dx} dz={dz}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_single_pass {
+    use super::*;
+    use super::fixtures::{args, write_lors};
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use std::cell::Cell;
+    use crate::lorogram::BuildScattergram;
+    use geometry::units::mm;
+    use crate::utils::parse_bounds;
+
+    fn hdf5_lor(i: usize) -> Hdf5Lor {
+        Hdf5Lor {
+            dt: 0.01 * i as f32,
+            // Every third LOR is a scatter, every seventh is cut
+            E1: if i % 3 == 0 { 450.0 } else { ELECTRON_REST_ENERGY },
+            E2: if i % 7 == 0 { 100.0 } else { ELECTRON_REST_ENERGY },
+            ..fixtures::hdf5_lor(i)
+        }
+    }
+
+    fn scattergram() -> Scattergram {
+        BuildScattergram::new().phi_bins(6).z_bins(5).z_length(mm(200.0)).r_bins(4).r_max(mm(200.0)).build().unwrap()
+    }
+
+    #[test]
+    fn one_pass_matches_two_passes() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_lors(dir.path(), "lors.h5", &(0..200).map(hdf5_lor).collect::<Vec<_>>())?;
+        let args = Args { ecut: parse_bounds("400..").unwrap(), ..args(&path) };
+
+        // Counts how many times the LOR table is opened for a pass
+        let opens = Cell::new(0);
+        let counting_open = || { opens.set(opens.get() + 1); open_lor_table(&args)() };
+
+        let (lors, combined) = lors_and_scattergram_with(counting_open, &args, Some(scattergram()), false)?;
+        let combined = combined.unwrap();
+        assert_eq!(opens.get(), 1);
+
+        // The old approach: one pass for the scattergram, another for the LORs
+        let separate = scattergram_with(counting_open, &args, scattergram())?;
+        let (rows, _) = read_and_classify(counting_open, &args, &mut None, false, None)?;
+        let two_pass = to_lors(rows, Some(&separate));
+        assert_eq!(opens.get(), 3);
+
+        assert!(!lors.is_empty());
+        assert_eq!(lors.len(), two_pass.len());
+        for (a, b) in lors.iter().zip(&two_pass) {
+            assert_eq!((a.p1, a.p2, a.dt), (b.p1, b.p2, b.dt));
+            assert_eq!(a.corrections, b.corrections);
+            assert_eq!(combined.counts(a), separate.counts(a));
+        }
+        for lor in (0..200).step_by(5).map(hdf5_lor).map(LOR::from) {
+            assert_eq!(combined.counts(&lor), separate.counts(&lor));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size_does_not_change_the_result() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_lors(dir.path(), "lors.h5", &(0..200).map(hdf5_lor).collect::<Vec<_>>())?;
+        let args = Args { event_range: Some(13..187), ecut: parse_bounds("400..").unwrap(), ..args(&path) };
+        let (one_shot, whole) = lors_and_scattergram_with(open_lor_table(&args), &args, Some(scattergram()), false)?;
+        let small_chunks = || TableChunks::new(&path, &args.dataset, args.event_range.clone(), 7);
+        let (chunked, sliced) = lors_and_scattergram_with(small_chunks, &args, Some(scattergram()), true)?;
+        assert!(!one_shot.is_empty());
+        assert_eq!(chunked.len(), one_shot.len());
+        for (a, b) in chunked.iter().zip(&one_shot) {
+            assert_eq!((a.p1, a.p2, a.dt, a.corrections), (b.p1, b.p2, b.dt, b.corrections));
+        }
+        let (whole, sliced) = (whole.unwrap(), sliced.unwrap());
+        for lor in (0..200).map(hdf5_lor).map(LOR::from) {
+            assert_eq!(whole.counts(&lor), sliced.counts(&lor));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_external_corrections {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use crate::utils::parse_bounds;
+    use geometry::units::{mm_, ratio_};
+
+    fn hdf5_lor(i: usize) -> Hdf5Lor {
+        // Every seventh is cut
+        Hdf5Lor { E2: if i % 7 == 0 { 100.0 } else { ELECTRON_REST_ENERGY }, ..fixtures::hdf5_lor(i) }
+    }
+
+    fn mult_a(i: usize) -> f32 { if i == 13 { f32::NAN } else { 1.0 + i as f32 / 100.0 } }
+    fn add   (i: usize) -> f32 { 0.1 * i as f32 }
+
+    /// LOR table and correction columns in one file
+    fn write_file(path: &str, n: usize) -> Result<(), Box<dyn Error>> {
+        let file = hdf5::File::create(path)?;
+        let rows: Vec<Hdf5Lor> = (0..n).map(hdf5_lor).collect();
+        file.create_group("reco_info")?.new_dataset_builder().with_data(&rows).create("lors")?;
+        let corrections = file.create_group("corrections")?;
+        let column = |f: fn(usize) -> f32| (0..n).map(f).collect::<Vec<_>>();
+        corrections.new_dataset_builder().with_data(&column(mult_a)).create("mult_a")?;
+        corrections.new_dataset_builder().with_data(&vec![2.0_f32; n]).create("mult_b")?;
+        corrections.new_dataset_builder().with_data(&column(add)).create("add")?;
+        corrections.new_dataset_builder().with_data(&vec![1.0_f32; n + 1]).create("too_long")?;
+        Ok(())
+    }
+
+    fn args(path: &str, event_range: Option<std::ops::Range<usize>>, ecut: &str) -> Args {
+        Args {
+            event_range, ecut: parse_bounds(ecut).unwrap(),
+            mult_corrections: vec!["corrections/mult_a".into(), "corrections/mult_b".into()],
+            add_corrections: vec!["corrections/add".into()],
+            ..fixtures::args(path)
+        }
+    }
+
+    #[test]
+    fn corrections_follow_original_rows() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path, 50)?;
+
+        for (event_range, ecut) in [(None, ".."), (Some(10..40), ".."), (Some(10..40), "400..")] {
+            let args = args(path, event_range.clone(), ecut);
+            let lors = read_lors_prefetching(args.clone(), None, false)?;
+            let range = event_range.unwrap_or(0..50);
+            let expected_rows: Vec<usize> = range.clone().filter(|i| ecut == ".." || i % 7 != 0).collect();
+            assert_eq!(lors.len(), expected_rows.len());
+            for (lor, row) in lors.iter().zip(expected_rows) {
+                assert_eq!(mm_(lor.p1.y), row as f32);
+                let m = if row == 13 { 1.0 } else { mult_a(row) };
+                assert_eq!(ratio_(lor.corrections.multiplicative), m * 2.0, "row {row}");
+                assert_eq!(lor.corrections.additive, add(row), "row {row}");
+            }
+            let invalid = ExternalCorrections::read(&args)?.unwrap().invalid;
+            assert_eq!(invalid, usize::from(range.contains(&13)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn misaligned_columns_are_rejected() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path, 20)?;
+        let args = Args { add_corrections: vec!["corrections/too_long".into()], ..args(path, None, "..") };
+        let err = read_lors(args, None).unwrap_err().to_string();
+        assert!(err.contains("has 21 rows"), "{err}");
+        let args = Args { mult_corrections: vec![], add_corrections: vec![], ..args(path, None, "..") };
+        assert!(ExternalCorrections::read(&args)?.is_none());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_dead_time_corrections {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::{ns, ratio_};
+
+    /// 40 LORs, two per second, in frames starting at 0 s and 10 s, with
+    /// non-paralyzable live fractions of 0.9 and 0.5 for a dead time of 1 us
+    fn write_file(path: &str) -> Result<(), Box<dyn Error>> {
+        let file = hdf5::File::create(path)?;
+        let rows: Vec<Hdf5Lor> = (0..40).map(fixtures::hdf5_lor).collect();
+        let reco = file.create_group("reco_info")?;
+        reco.new_dataset_builder().with_data(&rows).create("lors")?;
+        let times: Vec<f32> = (0..40).map(|i| if i == 25 { f32::NAN } else { i as f32 * 0.5 }).collect();
+        reco.new_dataset_builder().with_data(&times).create("time")?;
+        let singles = [SinglesRate { start: 0.0, rate: 1e5 }, SinglesRate { start: 10.0, rate: 5e5 }];
+        reco.new_dataset_builder().with_data(&singles).create("singles")?;
+        file.create_group("corrections")?.new_dataset_builder().with_data(&vec![2.0_f32; 40]).create("mult")?;
+        Ok(())
+    }
+
+    fn args(path: &str, mult_corrections: Vec<String>) -> Args {
+        Args {
+            event_range: Some(10..30), mult_corrections,
+            dead_time: Some(DeadTimeArgs {
+                time_dataset: "reco_info/time".into(), singles_dataset: "reco_info/singles".into(),
+                model: DeadTimeModel::NonParalyzable, tau: ns(1000.0),
+            }),
+            ..fixtures::args(path)
+        }
+    }
+
+    #[test]
+    fn frames_weight_their_lors() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path)?;
+
+        for (mult_corrections, scale) in [(vec![], 1.0), (vec!["corrections/mult".to_string()], 2.0)] {
+            let args = args(path, mult_corrections);
+            let lors = read_lors(args.clone(), None)?;
+            assert_eq!(lors.len(), 20);
+            for (lor, row) in lors.iter().zip(10..) {
+                let live = match row { 25 => 1.0, 0..=19 => 0.9, _ => 0.5 };
+                assert_float_eq!(ratio_(lor.corrections.multiplicative), scale * live, rmax <= 1e-6, "row {row}");
+            }
+            assert_eq!(ExternalCorrections::read(&args)?.unwrap().invalid, 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_time_column_is_an_error() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path)?;
+        let mut args = args(path, vec![]);
+        args.dead_time.as_mut().unwrap().time_dataset = "corrections/missing".into();
+        assert!(read_lors(args.clone(), None).is_err());
+        args.dead_time.as_mut().unwrap().tau = ns(1e4);
+        args.dead_time.as_mut().unwrap().time_dataset = "reco_info/time".into();
+        let err = read_lors(args, None).unwrap_err().to_string();
+        assert!(err.contains("unattainable"), "{err}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_degenerate {
+    use super::*;
+    use super::fixtures::write_lors;
+    use geometry::units::mm_;
+
+    fn hdf5_lor(i: usize) -> Hdf5Lor {
+        let lor = fixtures::hdf5_lor(i);
+        // Rows 3 and 7 have coincident endpoints
+        if i == 3 || i == 7 { Hdf5Lor { x2: lor.x1, y2: lor.y1, z2: lor.z1, ..lor } } else { lor }
+    }
+
+    #[test]
+    fn read_lors_respects_policy() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_lors(dir.path(), "lors.h5", &(0..10).map(hdf5_lor).collect::<Vec<_>>())?;
+        let args = |degenerate| Args { degenerate, ..fixtures::args(&path) };
+        let read = |degenerate| read_and_classify(open_lor_table(&args(degenerate)), &args(degenerate), &mut None, false, None);
+
+        let (lors, cut) = read(DegeneratePolicy::Drop)?;
+        assert_eq!((lors.len(), cut.degenerate, cut.total()), (8, 2, 2));
+        let (lors, cut) = read(DegeneratePolicy::Keep)?;
+        assert_eq!((lors.len(), cut.total()), (10, 0));
+        let err = read(DegeneratePolicy::Error).unwrap_err().to_string();
+        assert!(err.contains("row 3"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_rows_do_not_shift_the_positions_of_the_rest() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = write_lors(dir.path(), "lors.h5", &(0..10).map(hdf5_lor).collect::<Vec<_>>())?;
+        let lors = read_lors_with_rows(Args { event_range: Some(2..9), ..fixtures::args(&path) })?;
+        let rows = lors.iter().map(|(row, _)| *row).collect::<Vec<_>>();
+        assert_eq!(rows, vec![2, 4, 5, 6, 8]);
+        for (row, meta) in &lors { assert_eq!(mm_(meta.lor.p1.y), *row as f32) }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_mapped_input {
+    use super::*;
+    use crate::io::mapped::{self, RawLor};
+    use crate::utils::parse_bounds;
+
+    #[test]
+    fn mapped_files_are_read_like_hdf5_tables() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let lor = |i: usize| Hdf5Lor { E1: 400.0 + 10.0 * i as f32, ..fixtures::hdf5_lor(i) };
+        let hdf5_lors: Vec<_> = (0..20).map(lor).collect();
+        let h5   = dir.path().join("lors.h5")  .to_str().unwrap().to_string();
+        let lors = dir.path().join("lors.lors").to_str().unwrap().to_string();
+        write_table(&h5, "reco_info/lors", &hdf5_lors)?;
+        mapped::write(&lors, &hdf5_lors.iter().map(RawLor::from).collect::<Vec<_>>())?;
+        assert_eq!(table_len(&lors, "ignored")?, 20);
+
+        let args = |input_file: &str| Args { event_range: Some(2..18), ecut: parse_bounds("450..").unwrap(), ..fixtures::args(input_file) };
+        let from_h5     = read_lors(args(&h5), None)?;
+        let from_mapped = read_lors(args(&lors), None)?;
+        // Rows 5..18 pass the energy cut
+        assert_eq!(from_mapped.len(), 13);
+        assert_eq!(from_mapped.len(), from_h5.len());
+        for (a, b) in from_mapped.iter().zip(&from_h5) {
+            assert_eq!((a.p1, a.p2, a.dt), (b.p1, b.p2, b.dt));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_canonical_endpoints {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use crate::image::Image;
+    use crate::testing::AnalyticSystem;
+
+    fn args(input_file: &str) -> Args {
+        Args { canonicalize_endpoints: true, ..fixtures::args(input_file) }
+    }
+
+    #[test]
+    fn swapped_endpoints_are_read_identically() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let rows: Vec<Hdf5Lor> = system.measured_lors().iter().enumerate()
+            .map(|(i, lor)| Hdf5Lor { dt: 0.01 * i as f32 - 0.1, q1: 1000.0, q2: 900.0, E1: ELECTRON_REST_ENERGY, E2: 480.0, ..Hdf5Lor::from(lor) })
+            .collect();
+        // The same events, with every other one's endpoints swapped
+        let shuffled: Vec<Hdf5Lor> = rows.iter().cloned().enumerate()
+            .map(|(i, row)| if i % 2 == 0 { row.swapped() } else { row })
+            .collect();
+        let original = dir.path().join("original.h5");
+        let swapped  = dir.path().join("swapped.h5");
+        let (original, swapped) = (original.to_str().unwrap(), swapped.to_str().unwrap());
+        write_table(original, "reco_info/lors", &rows)?;
+        write_table(swapped , "reco_info/lors", &shuffled)?;
+
+        let a = read_lors(args(original), None)?;
+        let b = read_lors(args(swapped ), None)?;
+        assert_eq!(a.len(), rows.len());
+        for (a, b) in a.iter().zip(&b) {
+            assert_eq!((a.p1, a.p2, a.dt), (b.p1, b.p2, b.dt));
+            assert!(a.is_canonical());
+        }
+
+        let reconstruct = |lors: &[LOR]| -> Vec<Image> {
+            Image::mlem(system.fov, lors, None, None, None, Some(system.sensitivity_image()), 1)
+                .take(10).map(|(image, _, _)| image).collect()
+        };
+        for (a, b) in reconstruct(&a).iter().zip(reconstruct(&b)) {
+            assert_eq!(a.data, b.data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn charges_and_energies_follow_their_endpoints() {
+        let row = Hdf5Lor { dt: 0.2, x1: 10.0, y1: 0.0, z1: 50.0, x2: -10.0, y2: 0.0, z2: -50.0,
+                            q1: 1.0, q2: 2.0, E1: 3.0, E2: 4.0 };
+        let canonical = row.clone().canonicalized();
+        assert_eq!(canonical, Hdf5Lor { dt: -0.2, x1: -10.0, y1: 0.0, z1: -50.0, x2: 10.0, y2: 0.0, z2: 50.0,
+                                        q1: 2.0, q2: 1.0, E1: 4.0, E2: 3.0 });
+        assert_eq!(canonical.clone().canonicalized(), canonical);
+        assert_eq!(row.clone().swapped().swapped(), row);
+    }
+}
+
+#[cfg(test)]
+mod test_scatter_windows {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use crate::lorogram::{axis_phi, axis_z};
+    use float_eq::assert_float_eq;
+    use geometry::units::{mm, ratio_};
+    use ndhistogram::ndhistogram;
+
+    /// 400 events in a single scattergram bin, 0.5 s apart: scatter fraction
+    /// 20% during the first half of the acquisition, 50% during the second
+    fn write_file(path: &str) -> Result<(), Box<dyn Error>> {
+        let rows: Vec<Hdf5Lor> = (0..400).map(|i| {
+            let scatter = if i < 200 { i % 5 == 0 } else { i % 2 == 0 };
+            Hdf5Lor { y1: 10.0, z1: 20.0, y2: -10.0, z2: 30.0, E1: if scatter { 450.0 } else { ELECTRON_REST_ENERGY },
+                      ..fixtures::hdf5_lor(0) }
+        }).collect();
+        let times: Vec<f32> = (0..400).map(|i| i as f32 * 0.5).collect();
+        let file = hdf5::File::create(path)?;
+        let reco = file.create_group("reco_info")?;
+        reco.new_dataset_builder().with_data(&rows).create("lors")?;
+        reco.new_dataset_builder().with_data(&times).create("time")?;
+        Ok(())
+    }
+
+    fn scattergram() -> Scattergram {
+        Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)), axis_phi(4); usize)))
+    }
+
+    fn args(path: &str, n_windows: Option<usize>) -> Args {
+        Args {
+            scatter_windows: n_windows.map(|n_windows| ScatterWindowArgs { time_dataset: "reco_info/time".into(), n_windows }),
+            ..fixtures::args(path)
+        }
+    }
+
+    #[test]
+    fn corrections_follow_the_acquisition_time() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path)?;
+
+        let (lors, merged) = read_lors_and_scattergram(args(path, Some(2)), Some(scattergram()), true)?;
+        assert_eq!(lors.len(), 400);
+        // Before the centre of the first window and after that of the second
+        assert_float_eq!(ratio_(lors[ 99].corrections.scatter), 1.0 / 0.8, rmax <= 1e-6);
+        assert_float_eq!(ratio_(lors[300].corrections.scatter), 1.0 / 0.5, rmax <= 1e-6);
+        let merged = merged.unwrap();
+        assert_eq!(merged.counts(&lors[0]), (260, 140));
+
+        // The merged windows are the scattergram of the whole acquisition
+        let (lors, single) = read_lors_and_scattergram(args(path, None), Some(scattergram()), true)?;
+        assert_eq!(single.unwrap().counts(&lors[0]), (260, 140));
+        assert_float_eq!(ratio_(lors[99].corrections.scatter), 400.0 / 260.0, rmax <= 1e-6);
+        Ok(())
+    }
+
+    #[test]
+    fn mismatched_time_column_is_an_error() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("lors.h5");
+        let path = path.to_str().unwrap();
+        write_file(path)?;
+        let mut args = args(path, Some(2));
+        args.event_range = Some(0..100);
+        assert!(read_lors(args.clone(), Some(scattergram())).is_ok());
+        args.scatter_windows.as_mut().unwrap().time_dataset = "reco_info/missing".into();
+        assert!(read_lors(args, Some(scattergram())).is_err());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_concatenated_input {
+    use super::*;
+    use super::fixtures::{hdf5_lor, write_lors};
+
+    fn args(input_files: Vec<String>, event_range: Option<std::ops::Range<usize>>) -> Args {
+        Args { input_files, event_range, ..Args::default() }
+    }
+
+    /// Rows 0..10, 10..15 and 15..30 of a single table, in three files
+    fn write_parts(dir: &std::path::Path) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut files = vec![];
+        for (n, rows) in [0..10, 10..15, 15..30].into_iter().enumerate() {
+            files.push(write_lors(dir, &format!("job{n}.h5"), &rows.map(hdf5_lor).collect::<Vec<_>>())?);
+        }
+        Ok(files)
+    }
+
+    #[test]
+    fn event_range_spans_files() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let files = write_parts(dir.path())?;
+        assert_eq!(concatenated_len(&files, "reco_info/lors")?, 30);
+
+        let rows = read_concatenated::<Hdf5Lor>(&files, "reco_info/lors", Some(8..17))?;
+        assert_eq!(rows.iter().map(|row| row.y1 as usize).collect::<Vec<_>>(), (8..17).collect::<Vec<_>>());
+
+        let lors = read_lors(args(files.clone(), Some(8..17)), None)?;
+        assert_eq!(lors.len(), 9);
+        assert_eq!(read_lors(args(files, None), None)?.len(), 30);
+        Ok(())
+    }
+
+    #[test]
+    fn glob_patterns_expand_in_lexical_order() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let files = write_parts(dir.path())?;
+        let pattern = dir.path().join("job*.h5").to_str().unwrap().to_string();
+        assert_eq!(expand_input_files(&[pattern])?, files);
+        let missing = dir.path().join("nothing*.h5").to_str().unwrap().to_string();
+        assert!(expand_input_files(&[missing]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn errors_name_the_offending_file() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut files = write_parts(dir.path())?;
+        let broken = dir.path().join("broken.h5").to_str().unwrap().to_string();
+        hdf5::File::create(&broken)?.create_group("reco_info")?;
+        files.insert(1, broken.clone());
+        let error = read_lors(args(files, None), None).unwrap_err().to_string();
+        assert!(error.contains(&broken), "{error}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_transform {
+    use super::*;
+    use super::fixtures::write_lors;
+
+    #[test]
+    fn lors_are_moved_as_they_are_read() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let rows: Vec<Hdf5Lor> = (0..20).map(|i| Hdf5Lor { dt: 0.01 * i as f32, ..fixtures::hdf5_lor(i) }).collect();
+        let path = write_lors(dir.path(), "lors.h5", &rows)?;
+        let transform: RigidTransform = "10,-20,30,5,0,-15".parse()?;
+        let args = |transform| Args { transform, ..fixtures::args(&path) };
+        let original = read_lors(args(None), None)?;
+        let moved    = read_lors(args(Some(transform)), None)?;
+        assert_eq!(moved.len(), original.len());
+        for (original, moved) in original.iter().zip(&moved) {
+            let expected = transform.apply_lor(original);
+            assert_eq!((moved.dt, moved.corrections), (original.dt, original.corrections));
+            assert!((moved.p1 - expected.p1).norm() < geometry::units::mm(1e-3));
+            assert!((moved.p2 - expected.p2).norm() < geometry::units::mm(1e-3));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_lor_with_meta {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use crate::utils::parse_bounds;
+
+    fn hdf5_lor(i: usize) -> Hdf5Lor {
+        let f = i as f32;
+        Hdf5Lor { dt: 0.1 * f, q1: 1000.0 + f, q2: 2000.0 - f, E1: ELECTRON_REST_ENERGY - f, ..fixtures::hdf5_lor(i) }
+    }
+
+    #[test]
+    fn conversions_keep_the_geometry_and_the_metadata() {
+        let h5lor = hdf5_lor(3);
+        let meta = LorWithMeta::from(&h5lor);
+        // The geometry is borrowed, not converted
+        assert!(std::ptr::eq(meta.as_ref(), &meta.lor));
+        assert_eq!((meta.e1, meta.e2, meta.q1, meta.q2), (h5lor.E1, h5lor.E2, h5lor.q1, h5lor.q2));
+        assert_eq!(Hdf5Lor::from(&meta), h5lor);
+        let (direct, moved) = (LOR::from(&h5lor), LOR::from(meta));
+        assert_eq!((moved.p1, moved.p2, moved.dt, moved.corrections), (direct.p1, direct.p2, direct.dt, direct.corrections));
+    }
+
+    #[test]
+    fn both_readers_yield_the_same_lors() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let rows: Vec<Hdf5Lor> = (0..20).map(hdf5_lor).collect();
+        let path = fixtures::write_lors(dir.path(), "lors.h5", &rows)?;
+        // Drops the rows with E1 below 500 keV
+        let args = || Args { ecut: parse_bounds("500..").unwrap(), ..fixtures::args(&path) };
+        let lors = read_lors(args(), None)?;
+        let metas = read_lors_with_meta(args())?;
+        assert_eq!(lors.len(), 11);
+        assert_eq!(metas.len(), lors.len());
+        for ((lor, meta), row) in lors.iter().zip(&metas).zip(&rows) {
+            assert_eq!((meta.lor.p1, meta.lor.p2, meta.lor.dt), (lor.p1, lor.p2, lor.dt));
+            assert_eq!((meta.e1, meta.q2), (row.E1, row.q2));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Writing HDF5 datasets in chunks, optionally compressed, either all at once
+//! or by appending rows along the first axis as they are produced.
+//!
+//! Without compression or an explicit chunk shape, datasets written all at
+//! once are contiguous, as those written by `write_table`.
+
+use std::error::Error;
+use std::marker::PhantomData;
+use std::os::raw::{c_int, c_uint};
+use std::path::Path;
+use std::str::FromStr;
+
+use hdf5::H5Type;
+use hdf5::filters::Filter;
+use ndarray::{s, Array4, ArrayView, ArrayView1, Dimension};
+
+use super::Hdf5Lor;
+use crate::fov::FOV;
+use crate::image::Image;
+use geometry::units::mm_;
+
+/// Id of the zstd filter, provided by an HDF5 plugin
+const ZSTD_FILTER: c_int = 32015;
+
+/// Number of elements in each chunk, when the chunk shape is not given
+pub const DEFAULT_CHUNK_ELEMENTS: usize = 1 << 16;
+
+/// Compression of the datasets written by a `Writer`: `none`, a gzip level
+/// `0`-`9` (`4` or `gzip:4`), or a zstd level `1`-`22` (`zstd:3`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip(u8),
+    Zstd(u8),
+}
+
+impl Default for Compression {
+    fn default() -> Self { Self::None }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "none" { return Ok(Self::None) }
+        let (method, level) = s.split_once(':').unwrap_or(("gzip", s));
+        let level = level.trim().parse::<u8>()
+            .map_err(|e| format!("{e} in compression level '{level}': expected none, <level>, gzip:<level> or zstd:<level>"))?;
+        match (method.trim(), level) {
+            ("gzip", 0)       => Ok(Self::None),
+            ("gzip", 1..=9)   => Ok(Self::Gzip(level)),
+            ("zstd", 1..=22)  => Ok(Self::Zstd(level)),
+            ("gzip", _)       => Err(format!("gzip compression level must be between 0 and 9, not {level}")),
+            ("zstd", _)       => Err(format!("zstd compression level must be between 1 and 22, not {level}")),
+            (other, _)        => Err(format!("Unknown compression '{other}': expected gzip or zstd")),
+        }
+    }
+}
+
+impl Compression {
+    /// Fail with an explanation if this HDF5 installation cannot compress
+    /// with this method
+    fn check_available(&self) -> hdf5::Result<()> {
+        let (id, name) = match self {
+            Self::None    => return Ok(()),
+            Self::Gzip(_) => (1, "gzip"),
+            Self::Zstd(_) => (ZSTD_FILTER, "zstd"),
+        };
+        if Filter::get_info(id).encode_enabled { return Ok(()) }
+        Err(format!(
+            "{name} compression is not available in this HDF5 installation.\n\
+             If the filter is provided by a plugin, set HDF5_PLUGIN_PATH to the directory containing it.").into())
+    }
+}
+
+/// Writes datasets to an HDF5 file, chunked and compressed as configured
+pub struct Writer {
+    file: hdf5::File,
+    compression: Compression,
+    chunk: Option<Vec<usize>>,
+}
+
+impl Writer {
+    /// Create a new file at `path`, replacing any existing one
+    pub fn create(path: impl AsRef<Path>) -> hdf5::Result<Self> { Ok(Self::wrap(hdf5::File::create(path)?)) }
+
+    /// Add datasets to the existing file at `path`
+    pub fn append(path: impl AsRef<Path>) -> hdf5::Result<Self> { Ok(Self::wrap(hdf5::File::append(path)?)) }
+
+    fn wrap(file: hdf5::File) -> Self { Self { file, compression: Compression::None, chunk: None } }
+
+    pub fn compression(self, compression: Compression) -> Self { Self { compression, ..self } }
+
+    /// Shape of the chunks of the datasets written from now on, whose rank it
+    /// must match [default: the whole of every axis but the first, and enough
+    /// of the first for `DEFAULT_CHUNK_ELEMENTS`]
+    pub fn chunk(self, shape: &[usize]) -> Self { Self { chunk: Some(shape.to_vec()), ..self } }
+
+    pub fn file(&self) -> &hdf5::File { &self.file }
+
+    /// Write `data` to `dataset`, which may include groups:
+    /// `group/subgroup/dataset`
+    pub fn write<'d, T: H5Type, D: Dimension>(&self, dataset: &str, data: ArrayView<'d, T, D>) -> hdf5::Result<hdf5::Dataset> {
+        let (group, name) = self.parent(dataset)?;
+        let builder = group.new_dataset_builder();
+        if self.compression == Compression::None && self.chunk.is_none() {
+            return builder.with_data(data).create(name)
+        }
+        let (&rows, trailing) = data.shape().split_first().unwrap_or((&1, &[]));
+        let chunk = self.chunk_shape(trailing, Some(rows))?;
+        self.configured(builder, chunk)?.with_data(data).create(name)
+    }
+
+    /// An empty `dataset` of rows of `T`, to which rows are added with
+    /// `Appender::append`
+    pub fn appendable<T: H5Type>(&self, dataset: &str) -> hdf5::Result<Appender<T>> {
+        let (group, name) = self.parent(dataset)?;
+        let chunk = self.chunk_shape(&[], None)?;
+        let dataset = self.configured(group.new_dataset_builder(), chunk)?
+            .empty::<T>()
+            .shape(hdf5::SimpleExtents::resizable([0]))
+            .create(name)?;
+        Ok(Appender { dataset, len: 0, rows: PhantomData })
+    }
+
+    /// The group holding `dataset`, created along with any missing groups
+    /// above it, and the name of `dataset` within it
+    fn parent<'a>(&self, dataset: &'a str) -> hdf5::Result<(hdf5::Group, &'a str)> {
+        let (groups, name) = dataset.rsplit_once('/').unwrap_or(("", dataset));
+        let mut group = self.file.group("/")?;
+        for g in groups.split('/').filter(|g| !g.is_empty()) {
+            group = if group.link_exists(g) { group.group(g)? } else { group.create_group(g)? };
+        }
+        Ok((group, name))
+    }
+
+    /// Chunks of a dataset of `rows` (unlimited if `None`) along the first
+    /// axis, and `trailing` along the others
+    fn chunk_shape(&self, trailing: &[usize], rows: Option<usize>) -> hdf5::Result<Vec<usize>> {
+        if let Some(chunk) = &self.chunk {
+            if chunk.len() != trailing.len() + 1 || chunk.contains(&0) {
+                return Err(format!("Chunk shape {chunk:?} does not suit a dataset with {} axes", trailing.len() + 1).into())
+            }
+            return Ok(chunk.clone())
+        }
+        let row = trailing.iter().map(|&n| n.max(1)).product::<usize>();
+        let first = (DEFAULT_CHUNK_ELEMENTS / row).min(rows.unwrap_or(usize::MAX)).max(1);
+        Ok(std::iter::once(first).chain(trailing.iter().map(|&n| n.max(1))).collect())
+    }
+
+    fn configured(&self, builder: hdf5::DatasetBuilder, chunk: Vec<usize>) -> hdf5::Result<hdf5::DatasetBuilder> {
+        self.compression.check_available()?;
+        let builder = builder.chunk(chunk);
+        Ok(match self.compression {
+            Compression::None        => builder,
+            Compression::Gzip(level) => builder.shuffle().deflate(level),
+            Compression::Zstd(level) => builder.shuffle().add_filter(ZSTD_FILTER, &[c_uint::from(level)]),
+        })
+    }
+}
+
+/// A dataset to which rows are appended: see `Writer::appendable`
+pub struct Appender<T> {
+    dataset: hdf5::Dataset,
+    len: usize,
+    rows: PhantomData<T>,
+}
+
+impl<T: H5Type> Appender<T> {
+    /// Write `rows` after those written so far
+    pub fn append(&mut self, rows: &[T]) -> hdf5::Result<()> {
+        if rows.is_empty() { return Ok(()) }
+        let end = self.len + rows.len();
+        self.dataset.resize(end)?;
+        self.dataset.write_slice(rows, s![self.len..end])?;
+        self.len = end;
+        Ok(())
+    }
+
+    /// Number of rows written so far
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    pub fn dataset(&self) -> &hdf5::Dataset { &self.dataset }
+}
+
+/// Write `lors` to `dataset` in a new file at `path`, compressed with
+/// `compression`
+pub fn write_lors(path: impl AsRef<Path>, dataset: &str, lors: &[Hdf5Lor], compression: Compression) -> hdf5::Result<()> {
+    Writer::create(path)?.compression(compression).write(dataset, ArrayView1::from(lors))?;
+    Ok(())
+}
+
+/// Write `images`, which must share a FOV, to a new file at `path`: a stack of
+/// images indexed by (image, z, y, x) in the dataset `images`, whose
+/// attributes `full_width_mm` and `n_voxels` give the FOV in (x, y, z) order.
+/// One image per chunk, unless the images are smaller than
+/// `DEFAULT_CHUNK_ELEMENTS`.
+pub fn write_images_hdf5(images: &[Image], path: impl AsRef<Path>, compression: Compression) -> Result<(), Box<dyn Error>> {
+    let fov = match images.first() {
+        Some(image) => image.fov,
+        None        => return Err("No images to write".into()),
+    };
+    let full_width = |fov: FOV| { let h = fov.half_width; [h.x, h.y, h.z].map(|h| mm_(h) * 2.0) };
+    if let Some(i) = images.iter().position(|image| image.fov.n != fov.n || full_width(image.fov) != full_width(fov)) {
+        return Err(format!("Image {i} does not share the FOV of the first: {} vs {fov}", images[i].fov).into())
+    }
+    let [nx, ny, nz] = fov.n;
+    let data: Vec<f32> = images.iter().flat_map(|image| image.data.iter().copied()).collect();
+    let stack = Array4::from_shape_vec((images.len(), nz, ny, nx), data)?;
+    let dataset = Writer::create(path)?.compression(compression).write("images", stack.view())?;
+    dataset.new_attr_builder().with_data(&full_width(fov)).create("full_width_mm")?;
+    dataset.new_attr_builder().with_data(&[nx, ny, nz].map(|n| n as u64)).create("n_voxels")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_writer {
+    use super::*;
+    use crate::io::hdf5::read_table;
+    use geometry::units::mm;
+    use rstest::rstest;
+
+    fn lor(i: usize) -> Hdf5Lor {
+        let i = i as f32;
+        Hdf5Lor { dt: 0.0, x1: -300.0, y1: i % 7.0, z1: 0.0, x2: 300.0, y2: -(i % 7.0), z2: 0.0, q1: 1000.0, q2: 1000.0, E1: 511.0, E2: 511.0 }
+    }
+
+    #[test]
+    fn compressed_lors_reread_and_are_smaller() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let lors: Vec<Hdf5Lor> = (0..100_000).map(lor).collect();
+        let size = |name: &str, compression| -> Result<u64, Box<dyn Error>> {
+            let path = dir.path().join(name);
+            write_lors(&path, "reco_info/lors", &lors, compression)?;
+            assert_eq!(read_table::<Hdf5Lor>(path.to_str().unwrap(), "reco_info/lors", None)?.to_vec(), lors);
+            Ok(std::fs::metadata(&path)?.len())
+        };
+        let plain = size("plain.h5", Compression::None)?;
+        let gzip  = size("gzip.h5" , Compression::Gzip(4))?;
+        assert!(gzip * 4 < plain, "gzip {gzip} vs plain {plain} bytes");
+        Ok(())
+    }
+
+    #[test]
+    fn rows_are_appended_in_chunks() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("appended.h5");
+        let lors: Vec<Hdf5Lor> = (0..2500).map(lor).collect();
+        {
+            let mut out = Writer::create(&path)?.compression(Compression::Gzip(1)).chunk(&[1000]).appendable::<Hdf5Lor>("a/b/lors")?;
+            for chunk in lors.chunks(700) { out.append(chunk)? }
+            out.append(&[])?;
+            assert_eq!(out.len(), 2500);
+            assert_eq!(out.dataset().chunk(), Some(vec![1000]));
+        }
+        assert_eq!(read_table::<Hdf5Lor>(path.to_str().unwrap(), "a/b/lors", None)?.to_vec(), lors);
+        Ok(())
+    }
+
+    #[test]
+    fn image_stack_keeps_data_and_fov() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let fov = FOV::new_from_full_widths((mm(20.0), mm(30.0), mm(40.0)), (2, 3, 4));
+        let images: Vec<Image> = (0..3)
+            .map(|i| Image::new(fov, (0..24).map(|v| (10 * i + v) as f32).collect()))
+            .collect();
+        for compression in [Compression::None, Compression::Gzip(6)] {
+            let path = dir.path().join("stack.h5");
+            write_images_hdf5(&images, &path, compression)?;
+            let file = hdf5::File::open(&path)?;
+            let dataset = file.dataset("images")?;
+            assert_eq!(dataset.shape(), vec![3, 4, 3, 2]);
+            let stack = dataset.read_raw::<f32>()?;
+            assert_eq!(stack, images.iter().flat_map(|image| image.data.clone()).collect::<Vec<_>>());
+            assert_eq!(dataset.attr("full_width_mm")?.read_raw::<f32>()?, vec![20.0, 30.0, 40.0]);
+            assert_eq!(dataset.attr("n_voxels")?.read_raw::<u64>()?, vec![2, 3, 4]);
+        }
+
+        let other = Image::new(FOV::new_from_full_widths((mm(20.0), mm(30.0), mm(40.0)), (2, 3, 2)), vec![0.0; 12]);
+        let err = write_images_hdf5(&[images[0].clone(), other], dir.path().join("mixed.h5"), Compression::None).unwrap_err();
+        assert!(err.to_string().starts_with("Image 1 does not share the FOV"), "{err}");
+        Ok(())
+    }
+
+    #[rstest(/**/ text     , expected,
+             case("none"   , Ok(Compression::None)),
+             case("0"      , Ok(Compression::None)),
+             case("4"      , Ok(Compression::Gzip(4))),
+             case("gzip:9" , Ok(Compression::Gzip(9))),
+             case("zstd:3" , Ok(Compression::Zstd(3))),
+             case("10"     , Err(())),
+             case("zstd:0" , Err(())),
+             case("lz4:1"  , Err(())),
+             case("fast"   , Err(())),
+    )]
+    fn parse(text: &str, expected: Result<Compression, ()>) {
+        assert_eq!(text.parse::<Compression>().map_err(|_| ()), expected);
+    }
+}
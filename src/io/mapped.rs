@@ -0,0 +1,356 @@
+//! Memory-mapped LOR files: a fixed header followed by fixed-size records
+//!
+//! The records are exposed as a zero-copy slice of `RawLor`, so that very large
+//! files can be iterated without loading them into RAM, and concurrent
+//! reconstructions on one node share the page cache.
+//!
+//! Layout, all little-endian:
+//!
+//! ```text
+//!  0  magic        8 bytes  "PETLORS\0"
+//!  8  version      u32
+//! 12  record size  u32      bytes per record (44)
+//! 16  n records    u64
+//! 24  reserved     8 bytes  zero
+//! 32  records      n × record size
+//! ```
+//!
+//! Each record is the 11 `f32`s of `RawLor`, in declaration order.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::io::hdf5::Hdf5Lor;
+use crate::io::prefetch::{ChunkReader, ChunkResult};
+use crate::system_matrix::LOR;
+
+pub const MAGIC: [u8; 8] = *b"PETLORS\0";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 32;
+pub const RECORD_SIZE: usize = std::mem::size_of::<RawLor>();
+
+/// On-disk LOR record, with the same fields as `Hdf5Lor`
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[repr(C)]
+#[allow(nonstandard_style)]
+pub struct RawLor {
+    pub dt: f32,
+    pub x1: f32,
+    pub y1: f32,
+    pub z1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    pub z2: f32,
+    pub q1: f32,
+    pub q2: f32,
+    pub E1: f32,
+    pub E2: f32,
+}
+
+impl RawLor {
+    fn fields(&self) -> [f32; 11] {
+        let &Self { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 } = self;
+        [dt, x1, y1, z1, x2, y2, z2, q1, q2, e1, e2]
+    }
+
+    #[cfg(any(test, target_endian = "big"))]
+    fn from_fields([dt, x1, y1, z1, x2, y2, z2, q1, q2, e1, e2]: [f32; 11]) -> Self {
+        Self { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 }
+    }
+
+    /// The on-disk representation of this record
+    pub fn to_le_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        for (out, field) in bytes.chunks_exact_mut(4).zip(self.fields()) {
+            out.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decode a record from its on-disk representation, on any host
+    #[cfg(any(test, target_endian = "big"))]
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        let mut fields = [0.0; 11];
+        for (field, b) in fields.iter_mut().zip(bytes.chunks_exact(4)) {
+            *field = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        Self::from_fields(fields)
+    }
+}
+
+impl From<&Hdf5Lor> for RawLor {
+    fn from(lor: &Hdf5Lor) -> Self {
+        let &Hdf5Lor { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 } = lor;
+        Self { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 }
+    }
+}
+
+impl From<&RawLor> for Hdf5Lor {
+    fn from(lor: &RawLor) -> Self {
+        let &RawLor { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 } = lor;
+        Self { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1: e1, E2: e2 }
+    }
+}
+
+impl From<&RawLor> for LOR {
+    fn from(lor: &RawLor) -> Self { LOR::from(Hdf5Lor::from(lor)) }
+}
+
+/// Write `lors` to `path` in the memory-mappable format
+pub fn write(path: impl AsRef<Path>, lors: &[RawLor]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(RECORD_SIZE as u32).to_le_bytes())?;
+    out.write_all(&(lors.len() as u64).to_le_bytes())?;
+    out.write_all(&[0; 8])?;
+    for lor in lors { out.write_all(&lor.to_le_bytes())? }
+    out.flush()
+}
+
+/// Whether `path` starts with the magic bytes of a memory-mappable LOR file
+pub fn is_mapped_file(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0; 8];
+    File::open(path).and_then(|mut f| f.read_exact(&mut magic)).is_ok() && magic == MAGIC
+}
+
+/// The records of a memory-mapped LOR file
+pub struct MappedLors {
+    storage: Storage,
+    len: usize,
+}
+
+enum Storage {
+    Mapped(Mmap),
+    /// Records converted to host byte order
+    #[cfg(target_endian = "big")]
+    Converted(Vec<RawLor>),
+}
+
+impl MappedLors {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("Cannot open '{}': {e}", path.display()))?;
+        // SAFETY: the map is read-only; modifying the file while it is mapped
+        // is not supported
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| format!("Cannot map '{}': {e}", path.display()))?;
+        let len = validate(&map, path)?;
+        // Checked before any slice of records is formed
+        let address = map.as_ptr() as usize + HEADER_SIZE;
+        if address % std::mem::align_of::<RawLor>() != 0 {
+            return Err(format!("LOR records in '{}' are misaligned (address {address:#x})", path.display()))
+        }
+        let storage = Storage::Mapped(map);
+        #[cfg(target_endian = "big")]
+        let storage = match storage {
+            Storage::Mapped(map) => Storage::Converted(
+                map[HEADER_SIZE..].chunks_exact(RECORD_SIZE).map(RawLor::from_le_bytes).collect()),
+            converted => converted,
+        };
+        Ok(Self { storage, len })
+    }
+
+    /// Iterate over the records as `LOR`s
+    pub fn lors(&self) -> impl Iterator<Item = LOR> + '_ { self.iter().map(LOR::from) }
+
+    /// Iterate over the records as `Hdf5Lor`s, which retain charges and energies
+    pub fn hdf5_lors(&self) -> impl Iterator<Item = Hdf5Lor> + '_ { self.iter().map(Hdf5Lor::from) }
+}
+
+/// Check the header of a mapped file, returning the number of records
+fn validate(bytes: &[u8], path: &Path) -> Result<usize, String> {
+    let path = path.display();
+    if bytes.len() < HEADER_SIZE {
+        return Err(format!("'{path}' is too short for a LOR file header: {} < {HEADER_SIZE} bytes", bytes.len()))
+    }
+    if bytes[..8] != MAGIC {
+        return Err(format!("'{path}' is not a memory-mappable LOR file (bad magic)"))
+    }
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i+1], bytes[i+2], bytes[i+3]]);
+    let (version, record_size) = (u32_at(8), u32_at(12) as usize);
+    if version != VERSION {
+        return Err(format!("'{path}' has LOR file version {version}, expected {VERSION}"))
+    }
+    if record_size != RECORD_SIZE {
+        return Err(format!("'{path}' has {record_size}-byte LOR records, expected {RECORD_SIZE}"))
+    }
+    let mut n = [0; 8];
+    n.copy_from_slice(&bytes[16..24]);
+    let n = u64::from_le_bytes(n) as usize;
+    let actual = bytes.len();
+    match n.checked_mul(RECORD_SIZE).and_then(|r| r.checked_add(HEADER_SIZE)) {
+        Some(expected) if expected == actual => Ok(n),
+        Some(expected) => {
+            let problem = if expected > actual { "truncated" } else { "too long" };
+            Err(format!("'{path}' is {problem}: header promises {n} LORs ({expected} bytes), but file has {actual} bytes"))
+        }
+        None => Err(format!("'{path}' header promises an impossible number of LORs: {n}")),
+    }
+}
+
+impl std::ops::Deref for MappedLors {
+    type Target = [RawLor];
+
+    fn deref(&self) -> &[RawLor] {
+        match &self.storage {
+            // SAFETY: `open` checked that the file holds `len` records of
+            // `RECORD_SIZE` bytes after the header, and that they are aligned.
+            // On little-endian hosts the on-disk bytes are the in-memory
+            // representation of `RawLor`, whose fields are all `f32`.
+            Storage::Mapped(map) => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(HEADER_SIZE) as *const RawLor, self.len)
+            },
+            #[cfg(target_endian = "big")]
+            Storage::Converted(lors) => lors,
+        }
+    }
+}
+
+/// Consecutive chunks of a memory-mapped LOR file, as `Hdf5Lor`s, for use
+/// wherever LORs are read from HDF5 tables
+pub struct MappedChunks {
+    lors: MappedLors,
+    next: usize,
+    end: usize,
+    chunk_size: usize,
+}
+
+impl MappedChunks {
+    pub fn new(path: impl AsRef<Path>, range: Option<Range<usize>>, chunk_size: usize) -> Result<Self, String> {
+        let lors = MappedLors::open(&path)?;
+        let Range { start, end } = match range {
+            Some(range) if range.end > lors.len() => return Err(format!(
+                "Rows {range:?} requested, but '{}' has {} rows", path.as_ref().display(), lors.len())),
+            Some(range) => range,
+            None        => 0..lors.len(),
+        };
+        Ok(Self { lors, next: start, end, chunk_size: chunk_size.max(1) })
+    }
+}
+
+impl ChunkReader for MappedChunks {
+    type Item = Hdf5Lor;
+
+    fn next_chunk(&mut self) -> Option<ChunkResult<Hdf5Lor>> {
+        if self.next >= self.end { return None }
+        let hi = (self.next + self.chunk_size).min(self.end);
+        let chunk = self.lors[self.next..hi].iter().map(Hdf5Lor::from).collect();
+        self.next = hi;
+        Some(Ok(chunk))
+    }
+}
+
+#[cfg(test)]
+mod test_mapped {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use tempfile::tempdir;
+
+    fn example(n: usize) -> Vec<RawLor> {
+        (0..n).map(|i| {
+            let f = i as f32;
+            RawLor { dt: 0.1 * f, x1: -f, y1: 2.0 * f, z1: 3.0, x2: f, y2: -2.0 * f, z2: -3.0,
+                     q1: 1000.0 + f, q2: 2000.0, E1: ELECTRON_REST_ENERGY, E2: 500.0 - f }
+        }).collect()
+    }
+
+    #[test]
+    fn write_then_map_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("roundtrip.lors");
+        let original = example(1000);
+        write(&path, &original)?;
+        assert!(is_mapped_file(&path));
+        let mapped = MappedLors::open(&path)?;
+        assert_eq!(&mapped[..], &original[..]);
+        assert_eq!(mapped.hdf5_lors().map(|l| RawLor::from(&l)).collect::<Vec<_>>(), original);
+        // The mapped records agree with a byte-by-byte decoding of the file
+        let bytes = std::fs::read(&path)?;
+        let decoded: Vec<_> = bytes[HEADER_SIZE..].chunks_exact(RECORD_SIZE).map(RawLor::from_le_bytes).collect();
+        assert_eq!(decoded, original);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_file_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("empty.lors");
+        write(&path, &[])?;
+        assert!(MappedLors::open(&path)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("truncated.lors");
+        write(&path, &example(10))?;
+        let full = HEADER_SIZE + 10 * RECORD_SIZE;
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len((full - 5) as u64)?;
+        drop(file);
+        let error = MappedLors::open(&path).err().unwrap();
+        assert!(error.contains("truncated"), "{error}");
+        assert!(error.contains(&format!("{full} bytes")), "{error}");
+        assert!(error.contains(&format!("{} bytes", full - 5)), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn other_files_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("not.lors");
+        std::fs::write(&path, vec![0; HEADER_SIZE + RECORD_SIZE])?;
+        assert!(!is_mapped_file(&path));
+        assert!(MappedLors::open(&path).err().unwrap().contains("bad magic"));
+        std::fs::write(&path, b"PETLORS")?;
+        assert!(MappedLors::open(&path).err().unwrap().contains("too short"));
+        Ok(())
+    }
+
+    #[test]
+    fn mapped_slice_is_aligned() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(RECORD_SIZE, 44);
+        assert_eq!(HEADER_SIZE % std::mem::align_of::<RawLor>(), 0);
+        let dir = tempdir()?;
+        let path = dir.path().join("aligned.lors");
+        write(&path, &example(3))?;
+        let mapped = MappedLors::open(&path)?;
+        assert_eq!(mapped.as_ptr() as usize % std::mem::align_of::<RawLor>(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_cover_requested_range() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("chunks.lors");
+        let original = example(25);
+        write(&path, &original)?;
+        let mut chunks = MappedChunks::new(&path, Some(3..22), 7)?;
+        let mut sizes = vec![];
+        let mut read = vec![];
+        while let Some(chunk) = chunks.next_chunk() {
+            let chunk = chunk?;
+            sizes.push(chunk.len());
+            read.extend(chunk.iter().map(RawLor::from));
+        }
+        assert_eq!(sizes, vec![7, 7, 5]);
+        assert_eq!(read, original[3..22]);
+        Ok(())
+    }
+
+    #[test]
+    fn range_beyond_the_end_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("short.lors");
+        write(&path, &example(10))?;
+        let error = MappedChunks::new(&path, Some(5..12), 4).err().unwrap();
+        assert!(error.contains("5..12 requested") && error.contains("has 10 rows"), "{error}");
+        assert!(MappedChunks::new(&path, Some(5..10), 4).is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! MetaImage (`.mha`) images, with the header and voxels in one file.
+//!
+//! `TransformMatrix` lists the direction of each array axis in turn, and
+//! `Offset` is the centre of the first voxel (see `orientation`). World
+//! coordinates are written as they are, in mm: no conversion to the LPS
+//! convention of medical imaging is attempted.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::fov::FOV;
+use crate::image::Image;
+use crate::orientation::{Frame, Orientation};
+use crate::io::units::{mm_from_file, mm_to_file};
+use crate::Point;
+
+/// Write `image` as little-endian `f32`s, x varying fastest, after a header
+/// describing its `frame`
+pub fn write(image: &Image, path: impl AsRef<Path>) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(header(image).as_bytes())?;
+    for v in &image.data { file.write_all(&v.to_le_bytes())? }
+    file.flush()
+}
+
+fn header(image: &Image) -> String {
+    let join = |values: &[f32]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" ");
+    let direction: Vec<f32> = image.frame.orientation.direction().concat();
+    let origin = image.frame.origin;
+    let spacing = [0, 1, 2].map(|a| mm_to_file(image.fov.voxel_size[a]));
+    let [nx, ny, nz] = image.fov.n;
+    format!("ObjectType = Image\n\
+             NDims = 3\n\
+             BinaryData = True\n\
+             BinaryDataByteOrderMSB = False\n\
+             CompressedData = False\n\
+             TransformMatrix = {}\n\
+             Offset = {}\n\
+             CenterOfRotation = 0 0 0\n\
+             ElementSpacing = {}\n\
+             DimSize = {nx} {ny} {nz}\n\
+             ElementType = MET_FLOAT\n\
+             ElementDataFile = LOCAL\n",
+            join(&direction), join(&[origin.x, origin.y, origin.z].map(mm_to_file)), join(&spacing))
+}
+
+/// The voxel grid and frame described by the header of the MetaImage file
+/// (`.mha` or `.mhd`) at `path`
+pub fn read_header(path: impl AsRef<Path>) -> Result<(FOV, Frame), Box<dyn Error>> {
+    let mut fields = std::collections::HashMap::new();
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = vec![];
+    // The header is text, but may be followed by binary data
+    while reader.by_ref().take(4096).read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line).into_owned();
+        line.clear();
+        let (key, value) = text.split_once('=').ok_or_else(|| format!("Malformed MetaImage header line: {}", text.trim()))?;
+        let (key, value) = (key.trim().to_string(), value.trim().to_string());
+        let last = key == "ElementDataFile";
+        fields.insert(key, value);
+        if last { break }
+    }
+    let numbers = |keys: &[&str], default: Option<Vec<f32>>| -> Result<Vec<f32>, String> {
+        match keys.iter().find_map(|k| fields.get(*k)) {
+            Some(value) => value.split_whitespace()
+                .map(|v| v.parse::<f32>().map_err(|e| format!("Bad number '{v}' in MetaImage {}: {e}", keys[0])))
+                .collect(),
+            None => default.ok_or_else(|| format!("MetaImage header lacks {}", keys[0])),
+        }
+    };
+    let triple = |v: Vec<f32>, key: &str| -> Result<[f32; 3], String> {
+        v.try_into().map_err(|v: Vec<f32>| format!("MetaImage {key} should have 3 values, found {}", v.len()))
+    };
+    let n       = triple(numbers(&["DimSize"], None)?, "DimSize")?;
+    let spacing = triple(numbers(&["ElementSpacing", "ElementSize"], Some(vec![1.0; 3]))?, "ElementSpacing")?;
+    let offset  = triple(numbers(&["Offset", "Origin", "Position"], Some(vec![0.0; 3]))?, "Offset")?;
+    let matrix  = numbers(&["TransformMatrix", "Rotation", "Orientation"], Some(Orientation::IDENTITY.direction().concat()))?;
+    if matrix.len() != 9 { return Err(format!("MetaImage TransformMatrix should have 9 values, found {}", matrix.len()).into()) }
+    let direction = [0, 1, 2].map(|a| [matrix[3*a], matrix[3*a + 1], matrix[3*a + 2]]);
+
+    let n = n.map(|n| n as usize);
+    let width = |a: usize| mm_from_file(spacing[a] * n[a] as f32);
+    let fov = FOV::new_from_full_widths((width(0), width(1), width(2)), (n[0], n[1], n[2]));
+    let origin = Point::new(mm_from_file(offset[0]), mm_from_file(offset[1]), mm_from_file(offset[2]));
+    Ok((fov, Frame { orientation: Orientation::from_direction(direction)?, origin }))
+}
+
+#[cfg(test)]
+mod test_metaimage {
+    use super::*;
+    use geometry::units::{mm, mm_};
+    use float_eq::assert_float_eq;
+
+    fn image() -> Image {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(9.0), mm(20.0)), (2, 3, 4));
+        Image::new(fov, (0..24).map(|i| i as f32).collect())
+    }
+
+    #[test]
+    fn direction_matrix_matches_orientation() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.mha");
+        let rotated = image().reorient("-y,x,z".parse()?);
+        write(&rotated, &path)?;
+        let bytes = std::fs::read(&path)?;
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(text.contains("TransformMatrix = 0 -1 0 1 0 0 0 0 1\n"), "{text}");
+        assert!(text.contains("DimSize = 3 2 4\n"), "{text}");
+        assert!(text.contains("ElementSpacing = 3 2 5\n"), "{text}");
+
+        let (fov, frame) = read_header(&path)?;
+        assert_eq!(fov.n, rotated.fov.n);
+        assert_eq!(frame.orientation, rotated.frame.orientation);
+        let xyz = |p: Point| [mm_(p.x), mm_(p.y), mm_(p.z)];
+        assert_float_eq!(xyz(frame.origin), xyz(rotated.frame.origin), abs_all <= 1e-5);
+
+        // The voxels follow the header
+        let data_start = bytes.len() - 24 * 4;
+        let value = |k: usize| f32::from_le_bytes(bytes[data_start + 4*k..data_start + 4*k + 4].try_into().unwrap());
+        assert_eq!((0..24).map(value).collect::<Vec<_>>(), rotated.data);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_fields_take_defaults() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("reference.mhd");
+        std::fs::write(&path, "ObjectType = Image\nNDims = 3\nDimSize = 4 4 2\nElementType = MET_SHORT\nElementDataFile = ct.raw\n")?;
+        let (fov, frame) = read_header(&path)?;
+        assert_eq!(fov.n, [4, 4, 2]);
+        assert_eq!(frame.orientation, Orientation::IDENTITY);
+        assert_float_eq!(mm_(fov.voxel_size[0]), 1.0, ulps <= 1);
+        Ok(())
+    }
+}
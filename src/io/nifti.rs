@@ -0,0 +1,190 @@
+//! Single-file NIfTI-1 (`.nii`) images.
+//!
+//! The orientation and origin of the image are written as the sform, which maps
+//! voxel indices to the world coordinates (mm) of voxel centres, so
+//! `srow_x[a]` is the x-component of the step along array axis `a`. World
+//! coordinates are written as they are: no conversion to the RAS convention of
+//! NIfTI is attempted.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::fov::FOV;
+use crate::image::Image;
+use crate::orientation::{Frame, Orientation};
+use crate::io::units::{mm_from_file, mm_to_file};
+use crate::Point;
+
+const HEADER_LEN: usize = 348;
+/// Header, followed by an empty extension flag
+const VOX_OFFSET: usize = HEADER_LEN + 4;
+const DT_FLOAT32: i16 = 16;
+const NIFTI_XFORM_SCANNER_ANAT: i16 = 1;
+const NIFTI_UNITS_MM: u8 = 2;
+
+/// Write `image` as little-endian `f32`s, x varying fastest, with its `frame`
+/// in the sform
+pub fn write(image: &Image, path: impl AsRef<Path>) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header(image))?;
+    for v in &image.data { file.write_all(&v.to_le_bytes())? }
+    file.flush()
+}
+
+fn header(image: &Image) -> Vec<u8> {
+    let mut h = vec![0_u8; VOX_OFFSET];
+    let mut put = |offset: usize, bytes: &[u8]| h[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0, &(HEADER_LEN as i32).to_le_bytes());
+    let [nx, ny, nz] = image.fov.n;
+    for (k, d) in [3, nx, ny, nz, 1, 1, 1, 1].into_iter().enumerate() { put(40 + 2*k, &(d as i16).to_le_bytes()) }
+    put(70, &DT_FLOAT32.to_le_bytes());
+    put(72, &32_i16.to_le_bytes());
+    let spacing = [0, 1, 2].map(|a| mm_to_file(image.fov.voxel_size[a]));
+    for (k, p) in [1.0, spacing[0], spacing[1], spacing[2], 0.0, 0.0, 0.0, 0.0].into_iter().enumerate() {
+        put(76 + 4*k, &p.to_le_bytes())
+    }
+    put(108, &(VOX_OFFSET as f32).to_le_bytes());
+    put(112, &1.0_f32.to_le_bytes()); // scl_slope
+    put(123, &[NIFTI_UNITS_MM]);
+    put(254, &NIFTI_XFORM_SCANNER_ANAT.to_le_bytes());
+    let direction = image.frame.orientation.direction();
+    let o = image.frame.origin;
+    let origin = [o.x, o.y, o.z].map(mm_to_file);
+    for w in 0..3 {
+        let row = [direction[0][w] * spacing[0], direction[1][w] * spacing[1], direction[2][w] * spacing[2], origin[w]];
+        for (k, v) in row.into_iter().enumerate() { put(280 + 16*w + 4*k, &v.to_le_bytes()) }
+    }
+    put(344, b"n+1\0");
+    h
+}
+
+/// Read a `float32` NIfTI-1 image, such as those written by `write`
+pub fn read(path: impl AsRef<Path>) -> Result<Image, Box<dyn Error>> {
+    let (fov, frame) = read_header(&path)?;
+    let mut bytes = vec![];
+    File::open(&path)?.read_to_end(&mut bytes)?;
+    let i16_at = |i: usize| i16::from_le_bytes(bytes[i..i+2].try_into().unwrap());
+    if i16_at(70) != DT_FLOAT32 { return Err(format!("Expected float32 NIfTI data, found datatype {}", i16_at(70)).into()) }
+    let offset = f32::from_le_bytes(bytes[108..112].try_into().unwrap()) as usize;
+    let n: usize = fov.n.iter().product();
+    let data = bytes.get(offset..).filter(|data| data.len() == 4 * n)
+        .ok_or_else(|| format!("Expected {} bytes of voxel data for {:?} voxels, found {}", 4 * n, fov.n, bytes.len().saturating_sub(offset)))?
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect();
+    Ok(Image::new(fov, data).with_frame(frame))
+}
+
+/// The voxel grid and frame described by the sform of the NIfTI-1 file at `path`
+pub fn read_header(path: impl AsRef<Path>) -> Result<(FOV, Frame), Box<dyn Error>> {
+    let mut h = [0_u8; HEADER_LEN];
+    File::open(path)?.read_exact(&mut h)?;
+    let i16_at = |i: usize| i16::from_le_bytes(h[i..i+2].try_into().unwrap());
+    let f32_at = |i: usize| f32::from_le_bytes(h[i..i+4].try_into().unwrap());
+    if i32::from_le_bytes(h[0..4].try_into().unwrap()) != HEADER_LEN as i32 {
+        return Err("Not a little-endian NIfTI-1 file".into())
+    }
+    if &h[344..347] == b"ni1" {
+        return Err("Two-file (.hdr/.img) NIfTI-1 images are not supported: convert to a single .nii file".into())
+    }
+    if &h[344..347] != b"n+1" { return Err("Missing NIfTI-1 magic".into()) }
+    if i16_at(40) < 3 { return Err(format!("Expected a 3D NIfTI image, found {} dimensions", i16_at(40)).into()) }
+    if i16_at(254) <= 0 { return Err("NIfTI file has no sform: cannot determine its orientation".into()) }
+
+    let n = [1, 2, 3].map(|k| i16_at(40 + 2*k) as usize);
+    let srow = [0, 1, 2].map(|w| [0, 1, 2, 3].map(|k| f32_at(280 + 16*w + 4*k)));
+    let spacing = [0, 1, 2].map(|a| (srow[0][a].powi(2) + srow[1][a].powi(2) + srow[2][a].powi(2)).sqrt());
+    if spacing.iter().any(|&s| s <= 0.0 || s.is_nan()) { return Err(format!("Degenerate NIfTI sform {srow:?}").into()) }
+    let direction = [0, 1, 2].map(|a| [0, 1, 2].map(|w| srow[w][a] / spacing[a]));
+
+    let width = |a: usize| mm_from_file(spacing[a] * n[a] as f32);
+    let fov = FOV::new_from_full_widths((width(0), width(1), width(2)), (n[0], n[1], n[2]));
+    let origin = Point::new(mm_from_file(srow[0][3]), mm_from_file(srow[1][3]), mm_from_file(srow[2][3]));
+    Ok((fov, Frame { orientation: Orientation::from_direction(direction)?, origin }))
+}
+
+#[cfg(test)]
+mod test_nifti {
+    use super::*;
+    use geometry::units::{mm, mm_};
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    fn image() -> Image {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(9.0), mm(20.0)), (2, 3, 4));
+        Image::new(fov, (0..24).map(|i| i as f32).collect())
+    }
+
+    #[rstest]
+    #[case("x,y,z")]
+    #[case("-y,x,z")]
+    #[case("z,-x,-y")]
+    fn sform_matches_orientation(#[case] target: Orientation) -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.nii");
+        let reoriented = image().reorient(target);
+        write(&reoriented, &path)?;
+        let bytes = std::fs::read(&path)?;
+        assert_eq!(bytes.len(), VOX_OFFSET + 24 * 4);
+
+        let (fov, frame) = read_header(&path)?;
+        assert_eq!(fov.n, reoriented.fov.n);
+        assert_eq!(frame.orientation, target);
+        let xyz = |p: Point| [mm_(p.x), mm_(p.y), mm_(p.z)];
+        assert_float_eq!(xyz(frame.origin), xyz(reoriented.frame.origin), abs_all <= 1e-5);
+        for a in 0..3 {
+            assert_float_eq!(mm_(fov.voxel_size[a]), mm_(reoriented.fov.voxel_size[a]), rmax <= 1e-6);
+        }
+
+        // The sform takes the last voxel to its world position
+        let last = [0, 1, 2].map(|a| reoriented.fov.n[a] - 1);
+        let f32_at = |i: usize| f32::from_le_bytes(bytes[i..i+4].try_into().unwrap());
+        let world = [0, 1, 2].map(|w| (0..3).map(|a| f32_at(280 + 16*w + 4*a) * last[a] as f32).sum::<f32>() + f32_at(280 + 16*w + 12));
+        assert_float_eq!(world, xyz(reoriented.world(last)), abs_all <= 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn images_are_read_back() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.nii");
+        let reoriented = image().reorient("-y,x,z".parse()?);
+        write(&reoriented, &path)?;
+        let back = read(&path)?;
+        assert_eq!(back.fov.n, reoriented.fov.n);
+        assert_eq!(back.data, reoriented.data);
+        assert_eq!(back.frame.orientation, reoriented.frame.orientation);
+        assert_eq!(Image::from_file(&path)?.data, reoriented.data);
+
+        // Truncated voxel data
+        let bytes = std::fs::read(&path)?;
+        std::fs::write(&path, &bytes[..bytes.len() - 4])?;
+        assert!(read(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn files_without_sform_are_rejected() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.nii");
+        let mut bytes = header(&image());
+        bytes[254..256].copy_from_slice(&0_i16.to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+        assert!(read_header(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn two_file_images_are_rejected() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.hdr");
+        let mut bytes = header(&image());
+        bytes[344..348].copy_from_slice(b"ni1\0");
+        std::fs::write(&path, &bytes)?;
+        let err = read_header(&path).unwrap_err().to_string();
+        assert!(err.contains("Two-file"), "{err}");
+        Ok(())
+    }
+}
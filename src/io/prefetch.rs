@@ -0,0 +1,156 @@
+//! Overlap reading of data with their processing.
+//!
+//! A [`ChunkReader`] produces data in consecutive chunks. [`chunks`] turns it
+//! into an iterator over those chunks which, when prefetching, reads the next
+//! chunk on a background thread while the current one is being processed. The
+//! chunks are handed over through a rendezvous channel, which holds none, so
+//! at most two chunks (the one being processed and the one being read or
+//! waiting to be handed over) are held in memory at any time.
+
+use std::sync::{Arc, Mutex, mpsc::{sync_channel, Receiver}};
+
+pub type ChunkResult<T> = Result<Vec<T>, String>;
+
+/// Source of data which is read in consecutive chunks
+pub trait ChunkReader: Send + 'static {
+    type Item: Send + 'static;
+    /// The next chunk, or `None` once all data have been read
+    fn next_chunk(&mut self) -> Option<ChunkResult<Self::Item>>;
+}
+
+/// Iterator over the chunks produced by a `ChunkReader`
+pub enum Chunks<R: ChunkReader> {
+    Serial(R),
+    Prefetched { receiver: Receiver<ChunkResult<R::Item>>, reader: Arc<Mutex<Option<R>>> },
+    Failed,
+}
+
+/// Iterate over the chunks produced by `reader`, reading ahead on a background
+/// thread if `prefetch` is true. Falls back to serial reading if the thread
+/// cannot be started, or stops sending chunks before the reader is exhausted.
+pub fn chunks<R: ChunkReader>(reader: R, prefetch: bool) -> Chunks<R> {
+    if !prefetch { return Chunks::Serial(reader) }
+    let (sender, receiver) = sync_channel(0);
+    // Shared, so that it can be recovered if the prefetching thread fails
+    let reader = Arc::new(Mutex::new(Some(reader)));
+    let thread_reader = Arc::clone(&reader);
+    let spawned = std::thread::Builder::new()
+        .name("prefetch".into())
+        .spawn(move || loop {
+            let chunk = match thread_reader.lock().unwrap().as_mut().and_then(R::next_chunk) {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            // Send fails if the consumer is no longer interested
+            if sender.send(chunk).is_err() { break }
+        });
+    match spawned {
+        Ok(_) => Chunks::Prefetched { receiver, reader },
+        Err(e) => {
+            tracing::warn!("Could not start prefetching thread ({e}): reading serially");
+            Chunks::recover(reader)
+        }
+    }
+}
+
+impl<R: ChunkReader> Chunks<R> {
+    fn recover(reader: Arc<Mutex<Option<R>>>) -> Self {
+        match reader.lock().map(|mut r| r.take()) {
+            Ok(Some(reader)) => Chunks::Serial(reader),
+            _                => Chunks::Failed,
+        }
+    }
+}
+
+impl<R: ChunkReader> Iterator for Chunks<R> {
+    type Item = ChunkResult<R::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Chunks::Serial(reader) => reader.next_chunk(),
+            Chunks::Prefetched { receiver, reader } => match receiver.recv() {
+                Ok(chunk) => Some(chunk),
+                // The channel closes when the reader is exhausted, or when the
+                // prefetching thread died: in the latter case, carry on serially
+                Err(_) => {
+                    *self = Chunks::recover(Arc::clone(reader));
+                    match self {
+                        Chunks::Failed => Some(Err("Prefetching thread failed".into())),
+                        _              => self.next(),
+                    }
+                }
+            },
+            Chunks::Failed => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    /// Produces `n_chunks` chunks holding their own index, reporting each index
+    /// through `read` as soon as the chunk has been read
+    struct Reporting { next: usize, n_chunks: usize, read: Sender<usize> }
+
+    impl ChunkReader for Reporting {
+        type Item = usize;
+        fn next_chunk(&mut self) -> Option<ChunkResult<usize>> {
+            if self.next >= self.n_chunks { return None }
+            let _ = self.read.send(self.next);
+            self.next += 1;
+            Some(Ok(vec![self.next - 1]))
+        }
+    }
+
+    fn reporting(n_chunks: usize) -> (Reporting, Receiver<usize>) {
+        let (read, reported) = channel();
+        (Reporting { next: 0, n_chunks, read }, reported)
+    }
+
+    #[test]
+    fn prefetching_does_not_change_results() {
+        let serial    : Vec<_> = chunks(reporting(6).0, false).collect();
+        let prefetched: Vec<_> = chunks(reporting(6).0, true ).collect();
+        assert_eq!(serial, (0..6).map(|i| Ok(vec![i])).collect::<Vec<_>>());
+        assert_eq!(serial, prefetched);
+    }
+
+    #[test]
+    fn prefetching_overlaps_reading_with_processing() {
+        // Generous: only reached if the next chunk is never read ahead
+        let timeout = Duration::from_secs(10);
+
+        // The next chunk is read while the current one is being processed
+        let (reader, reported) = reporting(3);
+        let mut prefetched = chunks(reader, true);
+        assert_eq!(prefetched.next(), Some(Ok(vec![0])));
+        assert_eq!(reported.recv_timeout(timeout), Ok(0));
+        assert_eq!(reported.recv_timeout(timeout), Ok(1));
+        // ... but no further: it waits to be handed over
+        assert!(reported.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(prefetched.collect::<Vec<_>>(), [Ok(vec![1]), Ok(vec![2])]);
+
+        // Serially, nothing is read until it is asked for
+        let (reader, reported) = reporting(3);
+        let mut serial = chunks(reader, false);
+        assert_eq!(serial.next(), Some(Ok(vec![0])));
+        assert_eq!(reported.try_iter().collect::<Vec<_>>(), [0]);
+        assert_eq!(serial.next(), Some(Ok(vec![1])));
+        assert_eq!(reported.try_iter().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        struct Broken;
+        impl ChunkReader for Broken {
+            type Item = u8;
+            fn next_chunk(&mut self) -> Option<ChunkResult<u8>> { Some(Err("broken".into())) }
+        }
+        for prefetch in [false, true] {
+            assert_eq!(chunks(Broken, prefetch).next(), Some(Err("broken".into())));
+        }
+    }
+}
@@ -3,7 +3,7 @@
 use std::fs::File;
 use std::io::{Write, Read, BufWriter, BufReader};
 
-use geometry::units::{mm, mm_};
+use crate::io::units::{mm_from_file, mm_to_file};
 
 pub fn write(data: impl Iterator<Item = f32>, path: &std::path::Path) -> std::io::Result<()> {
     let file = File::create(path)?;
@@ -108,7 +108,7 @@ impl From<&MLEMImage> for Image3D {
         let n = image.fov.n;
         let pixels = [n[0] as u16, n[1] as u16, n[2] as u16];
         let l = image.fov.half_width;
-        let mm = [mm_(l[0]*2.0), mm_(l[1]*2.0), mm_(l[2]*2.0)];
+        let mm = [mm_to_file(l[0]*2.0), mm_to_file(l[1]*2.0), mm_to_file(l[2]*2.0)];
         let data = image.data.clone();
         Self { pixels, mm, data }
     }
@@ -119,10 +119,10 @@ impl From<&Image3D> for MLEMImage {
         let [px, py, pz] = image.pixels;
         let n = (px as usize, py as usize, pz as usize);
         let [wx, wy, wz] = image.mm;
-        let half_width = (mm(wx), mm(wy), mm(wz));
-        let fov = crate::fov::FOV::new(half_width, n);
+        let full_width = (mm_from_file(wx), mm_from_file(wy), mm_from_file(wz));
+        let fov = crate::fov::FOV::new_from_full_widths(full_width, n);
         let data = image.data.clone();
-        Self { fov, data }
+        Self::new(fov, data)
     }
 }
 
@@ -193,6 +193,349 @@ mod test_with_metadata {
 
 }
 
+// ----- Versioned image format ----------------------------------------------------------
+//
+// A fixed little-endian header, followed by the voxel values:
+//
+// | bytes | content                                                        |
+// |-------|----------------------------------------------------------------|
+// | 16    | `MAGIC`                                                        |
+// | 2     | version (u16)                                                  |
+// | 4     | header length in bytes, including the magic (u32)             |
+// | 1     | dtype: 0 = f32, 1 = f64                                        |
+// | 1     | order: 0 = x varies fastest, 1 = z varies fastest              |
+// | 12    | number of voxels in x, y, z (u32 each)                         |
+// | 12    | full size of the FOV in x, y, z, in mm (f32 each)              |
+//
+// Later versions may only append fields to the header, so readers skip to the
+// stated header length, and can load files written by newer versions.
+//
+// Files which do not start with `MAGIC` are legacy files: either `Image3D`s,
+// or bare sequences of values, whose FOV must be supplied by the user.
+
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use crate::fov::FOV;
+use crate::index::{index1_to_3, index3_to_1};
+
+/// Long enough not to be confused with the start of a legacy file
+pub const MAGIC: &[u8; 16] = b"\x89PETALO-IMAGE\r\n\x1a";
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16 + 2 + 4 + 1 + 1 + 12 + 12;
+
+/// Type of the values stored in an image file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dtype { F32, F64 }
+
+/// Flattening order of the voxels in an image file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Used by `Image` in memory
+    XFastest,
+    ZFastest,
+}
+
+impl std::str::FromStr for Dtype {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Dtype::F32),
+            "f64" => Ok(Dtype::F64),
+            _     => Err(format!("Unknown dtype '{s}': use f32 or f64")),
+        }
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x" => Ok(Order::XFastest),
+            "z" => Ok(Order::ZFastest),
+            _   => Err(format!("Unknown order '{s}': use x or z (the fastest-varying axis)")),
+        }
+    }
+}
+
+fn invalid(message: String) -> Error { Error::new(ErrorKind::InvalidData, message) }
+
+/// Position in a file with flattening `order`, of voxel `i` of an `Image`
+fn file_index(i: usize, [nx, ny, nz]: [usize; 3], order: Order) -> usize {
+    match order {
+        Order::XFastest => i,
+        Order::ZFastest => {
+            let [ix, iy, iz] = index1_to_3(i, [nx, ny, nz]);
+            index3_to_1([iz, iy, ix], [nz, ny, nx])
+        }
+    }
+}
+
+fn encode(data: &[f32], n: [usize; 3], dtype: Dtype, order: Order) -> Vec<u8> {
+    let mut in_file_order = vec![0.0; data.len()];
+    for (i, &value) in data.iter().enumerate() {
+        in_file_order[file_index(i, n, order)] = value;
+    }
+    match dtype {
+        Dtype::F32 => in_file_order.iter().flat_map(|v|         v .to_le_bytes()).collect(),
+        Dtype::F64 => in_file_order.iter().flat_map(|v| (*v as f64).to_le_bytes()).collect(),
+    }
+}
+
+fn decode(bytes: &[u8], n: [usize; 3], dtype: Dtype, order: Order) -> std::io::Result<Vec<f32>> {
+    let len = n[0] * n[1] * n[2];
+    let size = match dtype { Dtype::F32 => 4, Dtype::F64 => 8 };
+    if bytes.len() != len * size {
+        return Err(invalid(format!("Expected {} bytes of voxel data for {n:?} {dtype:?} voxels, found {}",
+                                   len * size, bytes.len())))
+    }
+    let in_file_order: Vec<f32> = match dtype {
+        Dtype::F32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect(),
+        Dtype::F64 => bytes.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32).collect(),
+    };
+    Ok((0..len).map(|i| in_file_order[file_index(i, n, order)]).collect())
+}
+
+/// Write `image` in the versioned format
+pub fn write_versioned(image: &MLEMImage, path: impl AsRef<Path>, dtype: Dtype, order: Order) -> std::io::Result<()> {
+    let n = image.fov.n;
+    let size = image.fov.half_width * 2.0;
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(HEADER_LEN as u32).to_le_bytes());
+    bytes.push(match dtype { Dtype::F32 => 0, Dtype::F64 => 1 });
+    bytes.push(match order { Order::XFastest => 0, Order::ZFastest => 1 });
+    for n in n                                    { bytes.extend_from_slice(&(n as u32).to_le_bytes()) }
+    for l in [size.x, size.y, size.z].map(mm_to_file) { bytes.extend_from_slice(&l.to_le_bytes()) }
+    bytes.extend(encode(&image.data, n, dtype, order));
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&bytes)?;
+    file.flush()
+}
+
+/// Whether the file at `path` starts with `MAGIC`
+pub fn has_header(path: impl AsRef<Path>) -> std::io::Result<bool> {
+    let mut start = [0; 16];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut start) {
+        Ok(()) => Ok(&start == MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read an image in the versioned format
+pub fn read_versioned(path: impl AsRef<Path>) -> std::io::Result<MLEMImage> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < HEADER_LEN || &bytes[..16] != MAGIC {
+        return Err(invalid("Not a versioned petalo image".into()))
+    }
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i+4].try_into().unwrap());
+    let f32_at = |i: usize| f32::from_le_bytes(bytes[i..i+4].try_into().unwrap());
+    let header_len = u32_at(18) as usize;
+    if header_len < HEADER_LEN || header_len > bytes.len() {
+        return Err(invalid(format!("Corrupt image header length: {header_len}")))
+    }
+    let dtype = match bytes[22] { 0 => Dtype::F32, 1 => Dtype::F64, d => return Err(invalid(format!("Unknown image dtype {d}"))) };
+    let order = match bytes[23] { 0 => Order::XFastest, 1 => Order::ZFastest, o => return Err(invalid(format!("Unknown image order {o}"))) };
+    let n = [u32_at(24) as usize, u32_at(28) as usize, u32_at(32) as usize];
+    let size = (mm_from_file(f32_at(36)), mm_from_file(f32_at(40)), mm_from_file(f32_at(44)));
+    let data = decode(&bytes[header_len..], n, dtype, order)?;
+    Ok(MLEMImage::new(FOV::new_from_full_widths(size, (n[0], n[1], n[2])), data))
+}
+
+/// Read a headerless file of little-endian values, which fill `fov`
+pub fn read_headerless(path: impl AsRef<Path>, fov: FOV, dtype: Dtype, order: Order) -> std::io::Result<MLEMImage> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(MLEMImage::new(fov, decode(&bytes, fov.n, dtype, order)?))
+}
+
+/// Read an image in the versioned format or, failing that, as a legacy `Image3D`
+pub fn read_image(path: impl AsRef<Path>) -> std::io::Result<MLEMImage> {
+    if has_header(&path)? { read_versioned(path) }
+    else                  { Ok((&Image3D::read_from_file(path)?).into()) }
+}
+
+/// Where `write_axes` puts the voxel-centre coordinates of the image in `path`
+pub fn axes_path(path: impl AsRef<Path>) -> std::path::PathBuf { path.as_ref().with_extension("axes.json") }
+
+/// Write the world coordinates (mm) of the voxel centres along each axis of
+/// `fov`, and `fov` itself (see `FovRecord`), as JSON:
+/// `{"unit": "mm", "x": [...], "y": [...], "z": [...], "fov": {...}}`
+pub fn write_axes(fov: FOV, path: impl AsRef<Path>) -> std::io::Result<()> {
+    let axis = |a: usize| fov.axis_coordinates(a).into_iter().map(mm_to_file).collect::<Vec<_>>();
+    let axes = serde_json::json!({ "unit": "mm", "x": axis(0), "y": axis(1), "z": axis(2), "fov": fov });
+    std::fs::write(path, serde_json::to_string_pretty(&axes)?)
+}
+
+/// How a legacy image file was written
+#[derive(Clone, Copy, Debug)]
+pub enum Legacy {
+    /// Matrix and physical sizes in a big-endian header (see `Image3D`)
+    Image3D,
+    /// Bare values, without any information about the FOV
+    Headerless { fov: FOV, dtype: Dtype, order: Order },
+}
+
+/// Rewrite the legacy image in `input` into the versioned format, in `output`
+pub fn fix_legacy(input: impl AsRef<Path>, output: impl AsRef<Path>, legacy: Legacy) -> std::io::Result<()> {
+    if has_header(&input)? {
+        return Err(invalid(format!("{} already has a versioned header", input.as_ref().display())))
+    }
+    let image = match legacy {
+        Legacy::Image3D                         => (&Image3D::read_from_file(input)?).into(),
+        Legacy::Headerless { fov, dtype, order } => read_headerless(input, fov, dtype, order)?,
+    };
+    write_versioned(&image, output, Dtype::F32, Order::XFastest)
+}
+
+#[cfg(test)]
+mod test_versioned {
+    use super::*;
+    use rstest::rstest;
+    use geometry::units::mm;
+
+    fn image() -> MLEMImage {
+        let fov = FOV::new_from_full_widths((mm(2.0), mm(6.0), mm(12.0)), (2, 3, 4));
+        MLEMImage::new(fov, (0..24).map(|i| i as f32 * 1.5 - 7.0).collect())
+    }
+
+    fn assert_same(a: &MLEMImage, b: &MLEMImage) {
+        assert_eq!(a.fov.n, b.fov.n);
+        assert_eq!(a.fov.half_width, b.fov.half_width);
+        assert_eq!(a.data, b.data);
+    }
+
+    #[rstest(dtype, order,
+             case(Dtype::F32, Order::XFastest),
+             case(Dtype::F32, Order::ZFastest),
+             case(Dtype::F64, Order::XFastest),
+             case(Dtype::F64, Order::ZFastest),
+    )]
+    fn roundtrip(dtype: Dtype, order: Order) -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.raw");
+        write_versioned(&image(), &path, dtype, order)?;
+        assert!(has_header(&path)?);
+        assert_same(&read_image(&path)?, &image());
+        Ok(())
+    }
+
+    #[test]
+    fn z_fastest_order_is_transposed() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.raw");
+        write_versioned(&image(), &path, Dtype::F32, Order::ZFastest)?;
+        let bytes = std::fs::read(&path)?;
+        let value = |k: usize| f32::from_le_bytes(bytes[HEADER_LEN + 4*k..HEADER_LEN + 4*k + 4].try_into().unwrap());
+        // Second value in file is voxel (0,0,1), which is at 0 + (0 + 1*3)*2 = 6 in memory
+        assert_eq!(value(1), image().data[6]);
+        // Fifth value in file is voxel (0,1,0), which is at 2 in memory
+        assert_eq!(value(4), image().data[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_file_resembling_magic_is_not_headered() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("legacy.raw");
+        // A headerless file which starts with the first 12 bytes of the magic
+        let mut bytes = MAGIC[..12].to_vec();
+        bytes.resize(24 * 4, 0);
+        std::fs::write(&path, &bytes)?;
+        assert!(!has_header(&path)?);
+        let fov = image().fov;
+        let legacy = read_headerless(&path, fov, Dtype::F32, Order::XFastest)?;
+        assert_eq!(legacy.data.len(), 24);
+        // Files shorter than the magic are not headered either
+        std::fs::write(&path, &MAGIC[..10])?;
+        assert!(!has_header(&path)?);
+        Ok(())
+    }
+
+    #[rstest(dtype, order,
+             case(Dtype::F32, Order::XFastest),
+             case(Dtype::F64, Order::ZFastest),
+    )]
+    fn fix_headerless(dtype: Dtype, order: Order) -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (legacy, fixed) = (dir.path().join("legacy.raw"), dir.path().join("fixed.raw"));
+        std::fs::write(&legacy, encode(&image().data, image().fov.n, dtype, order))?;
+        fix_legacy(&legacy, &fixed, Legacy::Headerless { fov: image().fov, dtype, order })?;
+        assert_same(&read_image(&fixed)?, &image());
+        Ok(())
+    }
+
+    #[test]
+    fn fix_image3d() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (legacy, fixed) = (dir.path().join("legacy.raw"), dir.path().join("fixed.raw"));
+        Image3D::from(&image()).write_to_file(&legacy)?;
+        // Legacy Image3Ds are still read transparently ...
+        assert_same(&read_image(&legacy)?, &image());
+        // ... and can be upgraded
+        fix_legacy(&legacy, &fixed, Legacy::Image3D)?;
+        assert!(has_header(&fixed)?);
+        assert_same(&read_image(&fixed)?, &image());
+        // Already-fixed files are left alone
+        assert!(fix_legacy(&fixed, &legacy, Legacy::Image3D).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn newer_versions_with_longer_headers_are_readable() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.raw");
+        write_versioned(&image(), &path, Dtype::F32, Order::XFastest)?;
+        let mut bytes = std::fs::read(&path)?;
+        bytes[16..18].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        bytes[18..22].copy_from_slice(&(HEADER_LEN as u32 + 8).to_le_bytes());
+        let data = bytes.split_off(HEADER_LEN);
+        bytes.extend([0xAB; 8]);
+        bytes.extend(data);
+        std::fs::write(&path, &bytes)?;
+        assert_same(&read_image(&path)?, &image());
+        Ok(())
+    }
+
+    #[test]
+    fn axes_are_written_alongside_image() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = axes_path(dir.path().join("image.raw"));
+        assert_eq!(path, dir.path().join("image.axes.json"));
+        write_axes(image().fov, &path)?;
+        let axes: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(axes["unit"], "mm");
+        assert_eq!(axes["x"], serde_json::json!([-0.5, 0.5]));
+        assert_eq!(axes["y"], serde_json::json!([-2.0, 0.0, 2.0]));
+        assert_eq!(axes["z"], serde_json::json!([-4.5, -1.5, 1.5, 4.5]));
+        let fov: FOV = serde_json::from_value(axes["fov"].clone())?;
+        assert_eq!((fov.n, fov.half_width), (image().fov.n, image().fov.half_width));
+        Ok(())
+    }
+
+    #[test]
+    fn headerless_images_are_read_with_the_given_fov() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.bin");
+        image().write_raw(&path)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 24 * 4);
+        assert_same(&MLEMImage::from_raw_file_with_fov(&path, image().fov)?, &image());
+
+        // f64 values are recognized by the size of the file
+        std::fs::write(&path, encode(&image().data, image().fov.n, Dtype::F64, Order::XFastest))?;
+        assert_same(&MLEMImage::from_raw_file_with_fov(&path, image().fov)?, &image());
+
+        // Too few or too many values for the FOV
+        let other = FOV::new_from_full_widths((mm(2.0), mm(6.0), mm(12.0)), (2, 3, 5));
+        let err = MLEMImage::from_raw_file_with_fov(&path, other).unwrap_err().to_string();
+        assert!(err.contains("Expected 120 bytes"), "{err}");
+        Ok(())
+    }
+}
+
 // ----- Proofs of concept ---------------------------------------------------------------
 #[cfg(test)]
 mod test_br_enum {
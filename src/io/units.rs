@@ -0,0 +1,88 @@
+//! Conversions between the unit-less numbers stored in files and `uom`
+//! quantities.
+//!
+//! All petalo files store lengths as `f32` mm and times as `f32` ns. Readers
+//! and writers should cross that boundary only through these functions, so
+//! that the units of the files are stated in exactly one place.
+//!
+//! Lengths round-trip bit-exactly, because mm is also the base unit of
+//! `Length`. The base unit of `Time` is ps, and not every `f32` number of ns
+//! has a distinct `f32` number of ps, so a time read from a file and written
+//! back may differ from the original by one unit in the last place.
+
+use crate::{Length, Point, Time};
+use geometry::units::{mm, mm_, ps, ps_};
+
+// Scaling in f64 is exact, leaving a single rounding to f32
+const PS_PER_NS: f64 = 1000.0;
+
+/// Length stored in a file
+pub fn mm_from_file(x: f32) -> Length { mm(x) }
+
+/// Time stored in a file
+pub fn ns_from_file(t: f32) -> Time { ps((t as f64 * PS_PER_NS) as f32) }
+
+/// Length, as stored in a file
+pub fn mm_to_file(x: Length) -> f32 { mm_(x) }
+
+/// Time, as stored in a file
+pub fn ns_to_file(t: Time) -> f32 { (ps_(t) as f64 / PS_PER_NS) as f32 }
+
+/// Point whose coordinates are stored in a file
+pub fn point_from_file(x: f32, y: f32, z: f32) -> Point {
+    Point::new(mm_from_file(x), mm_from_file(y), mm_from_file(z))
+}
+
+/// Coordinates of a point, as stored in a file
+pub fn point_to_file(p: Point) -> (f32, f32, f32) {
+    (mm_to_file(p.x), mm_to_file(p.y), mm_to_file(p.z))
+}
+
+#[cfg(test)]
+mod test_round_trip {
+    use super::*;
+    #[cfg(feature = "hdf5")] use crate::io::hdf5::Hdf5Lor;
+    #[cfg(feature = "hdf5")] use crate::system_matrix::LOR;
+    use proptest::prelude::*;
+
+    /// Any finite `f32`
+    fn finite() -> impl Strategy<Value = f32> { any::<f32>().prop_filter("finite", |x| x.is_finite()) }
+
+    /// Finite `f32`s which remain finite when expressed in ps
+    fn finite_ns() -> impl Strategy<Value = f32> { finite().prop_filter("finite in ps", |t| (t * 1000.0).is_finite()) }
+
+    fn ulps_apart(a: f32, b: f32) -> u32 { (a.to_bits() as i64 - b.to_bits() as i64).unsigned_abs() as u32 }
+
+    proptest! {
+        #[test]
+        fn lengths_are_bit_exact(x in finite()) {
+            prop_assert_eq!(mm_to_file(mm_from_file(x)).to_bits(), x.to_bits());
+        }
+
+        #[test]
+        fn times_are_within_one_ulp(t in finite_ns()) {
+            prop_assert!(ulps_apart(ns_to_file(ns_from_file(t)), t) <= 1);
+        }
+
+        #[cfg(feature = "hdf5")]
+        #[test]
+        fn file_lor_file(
+            dt in finite_ns(),
+            x1 in finite(), y1 in finite(), z1 in finite(),
+            x2 in finite(), y2 in finite(), z2 in finite(),
+        ) {
+            let nan = f32::NAN;
+            let original = Hdf5Lor { dt, x1, y1, z1, x2, y2, z2, q1: nan, q2: nan, E1: nan, E2: nan };
+            let written = Hdf5Lor::from(&LOR::from(&original));
+            let bits = |l: &Hdf5Lor| [l.x1, l.y1, l.z1, l.x2, l.y2, l.z2].map(f32::to_bits);
+            prop_assert_eq!(bits(&written), bits(&original));
+            prop_assert!(ulps_apart(written.dt, dt) <= 1);
+        }
+    }
+
+    #[test]
+    fn ns_in_file_are_1000_ps() {
+        assert_eq!(ps_(ns_from_file(1.5)), 1500.0);
+        assert_eq!(ns_to_file(ps(250.0)), 0.25);
+    }
+}
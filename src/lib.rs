@@ -2,6 +2,7 @@ mod exports;
 pub use exports::*;
 
 pub mod system_matrix;
+#[cfg(feature = "vis")]
 pub mod visualize;
 pub mod io;
 pub mod utils;
@@ -10,5 +11,41 @@ pub mod gauss;
 pub mod fom;
 pub mod lorogram;
 pub mod image;
+pub mod cylindrical;
 pub mod index;
 pub mod fov;
+pub mod scanner;
+pub mod sensors;
+pub mod report;
+pub mod cost;
+pub mod timing;
+pub mod memory;
+pub mod divergence;
+pub mod thinning;
+pub mod registration;
+pub mod deadtime;
+pub mod sink;
+pub mod acceleration;
+pub mod constants;
+pub mod normalization;
+pub mod geometry_cache;
+pub mod resolution;
+pub mod orientation;
+pub mod transform;
+pub mod robust;
+pub mod cancel;
+pub mod qcut;
+pub mod ecut;
+pub mod sinogram;
+pub mod post_filter;
+pub mod residuals;
+pub mod error;
+#[cfg(feature = "hdf5")]
+pub mod reconstruction;
+#[cfg(feature = "hdf5")]
+pub mod checkpoint;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "testing"))]
+pub mod testdata;
@@ -3,24 +3,79 @@ pub mod axis;
 mod build_scattergram;
 pub use build_scattergram::*;
 
-use ndhistogram::{axis::{Axis, Uniform}, Histogram};
-use axis::Cyclic;
-use crate::io::hdf5::Hdf5Lor;
-use crate::system_matrix::LOR;
+mod config;
+pub use config::*;
+
+mod rings;
+pub use rings::*;
+
+mod windowed;
+pub use windowed::*;
+
+mod smoothing;
+pub use smoothing::*;
+
+mod interpolation;
+pub use interpolation::*;
+
+mod occupancy;
+pub use occupancy::*;
+
+mod adaptive;
+pub use adaptive::*;
+
+pub mod cross_validation;
+
+use ndhistogram::{axis::{Axis, Uniform, Variable}, Histogram};
+use axis::{Cyclic, AxisError, HalfOpen, try_uniform, try_variable};
+use crate::system_matrix::{Corrections, LorWithMeta, LOR};
 use std::f32::consts::TAU;
 
-use crate::Lengthf32;
+use crate::{Anglef32, Energyf32, Lengthf32};
+use crate::constants::ELECTRON_REST_ENERGY;
+use crate::scanner::Scanner;
+use std::fmt::Debug;
 use crate::{Angle, Length, Point, Time, Ratio};
-use geometry::units::{mm, mm_, ps_, ratio, radian_, turn};
+use geometry::units::{mm, mm_, ps_, ratio, ratio_, radian_, turn};
 use geometry::uom::ConstZero;
 
 
 /// Distinguish between true, scatter and random prompt signals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Prompt { True, Scatter, Random }
 
+/// Classify a coincidence by the energies (keV) of its gammas: a scatter if
+/// either is below `threshold`
+#[allow(nonstandard_style)]
+pub fn classify_energies(E1: Energyf32, E2: Energyf32, threshold: Energyf32) -> Prompt {
+    if E1.min(E2) < threshold { Prompt::Scatter } else { Prompt::True }
+}
+
 pub struct Scattergram {
     trues  : Box<dyn Lorogram>,
     scatters:Box<dyn Lorogram>,
+    randoms: Box<dyn Lorogram>,
+    /// Gamma energy (keV) below which a coincidence counts as a scatter
+    true_threshold: Energyf32,
+    /// `value` of bins without trues
+    empty_bin_value: Ratio,
+    /// Counts from which `value` is computed, if `smooth`ed
+    smoothed: Option<SmoothedCounts>,
+    /// Interpolate counts between bin centres, rather than using those of the
+    /// bin containing the LOR
+    interpolate: bool,
+    /// Binning to be adapted to the statistics, and the specifications of the
+    /// provisional axes, until `adapt_binning` is applied
+    adaptive: Option<(AdaptiveBinning, Vec<AxisSpec>)>,
+    /// Axes chosen by `adapt_binning`
+    adapted_axes: Option<Vec<AdaptedAxis>>,
+}
+
+/// Trues, scatters and randoms in each bin, after smoothing
+struct SmoothedCounts {
+    trues: Vec<f32>,
+    scatters: Vec<f32>,
+    randoms: Vec<f32>,
 }
 
 impl Scattergram {
@@ -28,36 +83,492 @@ impl Scattergram {
     pub fn new(make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>)) -> Self {
         let trues    = make_empty_lorogram();
         let scatters = make_empty_lorogram();
-        Self { trues, scatters }
+        let randoms  = make_empty_lorogram();
+        Self { trues, scatters, randoms, true_threshold: ELECTRON_REST_ENERGY, empty_bin_value: ratio(1.0), smoothed: None, interpolate: false,
+               adaptive: None, adapted_axes: None }
+    }
+
+    /// Count coincidences with either gamma below `threshold` (keV) as scatters,
+    /// rather than those below the electron rest energy
+    pub fn with_true_threshold(mut self, threshold: Energyf32) -> Self {
+        self.true_threshold = threshold;
+        self
+    }
+
+    pub fn true_threshold(&self) -> Energyf32 { self.true_threshold }
+
+    /// Use `value` as the correction of LORs in bins without trues, rather
+    /// than 1 (no correction)
+    pub fn with_empty_bin_value(mut self, value: Ratio) -> Self {
+        self.empty_bin_value = value;
+        self
+    }
+
+    pub fn empty_bin_value(&self) -> Ratio { self.empty_bin_value }
+
+    /// Compute `value` from counts interpolated linearly between the centres
+    /// of the bins around the LOR (see `interpolation`), rather than from those
+    /// of the bin containing it
+    pub fn with_interpolation(mut self, interpolate: bool) -> Self {
+        self.interpolate = interpolate;
+        self
+    }
+
+    pub fn interpolates(&self) -> bool { self.interpolate }
+
+    /// Classify a coincidence by the energies (keV) of its gammas
+    #[allow(nonstandard_style)]
+    pub fn classify(&self, E1: Energyf32, E2: Energyf32) -> Prompt { classify_energies(E1, E2, self.true_threshold) }
+
+    /// As `new`, but fail if the lorograms' axes, whose kinds are listed in
+    /// `kinds`, contain duplicates (unless `allow_duplicates`). Warns about
+    /// redundant combinations of axes.
+    pub fn with_axes(
+        kinds: &[AxisKind],
+        allow_duplicates: bool,
+        make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>),
+    ) -> Result<Self, String> {
+        for warning in check_axis_kinds(kinds, allow_duplicates)? {
+            tracing::warn!("{warning}");
+        }
+        Ok(Self::new(make_empty_lorogram))
     }
 
     pub fn fill(&mut self, kind: Prompt, lor: &LOR) {
+        self.smoothed = None;
         match kind {
             Prompt::True    => self.trues.   fill(lor),
             Prompt::Scatter => self.scatters.fill(lor),
-            Prompt::Random  => panic!("Not expecting any random events yet."),
+            Prompt::Random  => self.randoms. fill(lor),
         }
     }
 
-    /// Multiplicative contribution of scatters to trues, in nearby LORs.
+    /// Multiplicative contribution of scatters and randoms to trues, in nearby
+    /// LORs.
     ///
-    /// `(scatters + trues) / trues`
+    /// `(scatters + randoms + trues) / trues`, or `empty_bin_value` in bins
+    /// without trues. Uses the smoothed counts, if `smooth`ed, interpolated
+    /// between bins, if `with_interpolation`.
     pub fn value(&self, lor: &LOR) -> Ratio {
-        let trues = self.trues.value(lor);
-        ratio(if trues > 0 {
-            let scatters: f32 = self.scatters.value(lor) as f32;
-            let trues = trues as f32;
-            (scatters + trues) / trues
-        } else { f32::MAX })
+        let (trues, scatters, randoms) = self.bin_counts(lor);
+        scatter_ratio(trues, scatters + randoms, self.empty_bin_value)
+    }
+
+    /// Compute `value`s from the counts convolved with a Gaussian of standard
+    /// deviation `kernel_width` bins along each axis, wrapping around cyclic
+    /// ones (see `gaussian_smooth`). The raw `counts` remain available. Any
+    /// counts added afterwards discard the smoothing, so smooth once filling is
+    /// complete.
+    pub fn smooth(&mut self, kernel_width: f32) -> Result<(), String> {
+        if !(kernel_width >= 0.0 && kernel_width.is_finite()) {
+            return Err(format!("Smoothing kernel width must be a non-negative number of bins, got {kernel_width}"))
+        }
+        let layout = self.trues.layout();
+        let smoothed = |lorogram: &dyn Lorogram| {
+            let mut values: Vec<f32> = (0..lorogram.n_bins()).map(|i| lorogram.value_at(i) as f32).collect();
+            gaussian_smooth(&mut values, &layout, kernel_width);
+            values
+        };
+        self.smoothed = Some(SmoothedCounts {
+            trues   : smoothed(self.trues   .as_ref()),
+            scatters: smoothed(self.scatters.as_ref()),
+            randoms : smoothed(self.randoms .as_ref()),
+        });
+        Ok(())
+    }
+
+    /// Trues, scatters and randoms in the bin containing `lor`, smoothed if
+    /// `smooth`ed, and interpolated if `with_interpolation`
+    fn bin_counts(&self, lor: &LOR) -> (f32, f32, f32) {
+        let at = |i: usize| match &self.smoothed {
+            Some(s) => (s.trues[i], s.scatters[i], s.randoms[i]),
+            None    => (self.trues.value_at(i) as f32, self.scatters.value_at(i) as f32, self.randoms.value_at(i) as f32),
+        };
+        let positions = if self.interpolate { self.trues.positions(lor) } else { None };
+        match positions {
+            Some(positions) => interpolation_weights(&self.trues.layout(), &positions).into_iter()
+                .fold((0.0, 0.0, 0.0), |(t, s, r), (i, w)| {
+                    let (ti, si, ri) = at(i);
+                    (t + w * ti, s + w * si, r + w * ri)
+                }),
+            None => self.index(lor).map_or((0.0, 0.0, 0.0), at),
+        }
+    }
+
+    /// Count the bins without events, and those without trues, whose LORs get
+    /// `empty_bin_value`. Many such bins suggest that the binning is too fine
+    /// for the statistics.
+    pub fn validate(&self) -> BinOccupancy {
+        let mut occupancy = BinOccupancy { n_bins: self.n_bins(), n_empty: 0, n_without_trues: 0 };
+        for i in 0..occupancy.n_bins {
+            if self.trues.value_at(i) > 0 { continue }
+            occupancy.n_without_trues += 1;
+            if self.scatters.value_at(i) == 0 && self.randoms.value_at(i) == 0 { occupancy.n_empty += 1 }
+        }
+        occupancy
+    }
+
+    /// Number of bins, including overflow bins, of each of the trues, scatters
+    /// and randoms histograms
+    pub fn n_bins(&self) -> usize { self.trues.n_bins() }
+
+    /// Position of the bin containing `lor` among all `n_bins`
+    pub fn index(&self, lor: &LOR) -> Option<usize> { self.trues.index(lor) }
+
+    /// Add `count` events of `kind` to the bin at position `index`
+    pub fn add_at(&mut self, kind: Prompt, index: usize, count: usize) {
+        self.smoothed = None;
+        match kind {
+            Prompt::True    => self.trues.   add_at(index, count),
+            Prompt::Scatter => self.scatters.add_at(index, count),
+            Prompt::Random  => self.randoms. add_at(index, count),
+        }
+    }
+
+    /// Add the counts of `other`, which must have identical bins and true
+    /// threshold, to those of `self`
+    pub fn merge(&mut self, other: &Scattergram) -> Result<(), String> {
+        if self.true_threshold != other.true_threshold {
+            return Err(format!("Cannot merge scattergrams with true thresholds {} and {} keV", self.true_threshold, other.true_threshold))
+        }
+        // Checking one lorogram checks all: each scattergram's are made alike
+        self.trues   .merge(other.trues   .as_ref())?;
+        self.smoothed = None;
+        self.scatters.merge(other.scatters.as_ref())?;
+        self.randoms .merge(other.randoms .as_ref())
+    }
+
+    /// Memory occupied by the bin contents
+    pub fn size_in_bytes(&self) -> usize {
+        (self.trues.n_bins() + self.scatters.n_bins() + self.randoms.n_bins()) * std::mem::size_of::<usize>()
+    }
+
+    /// Number of trues and scatters in the bin containing `lor`
+    pub fn counts(&self, lor: &LOR) -> (usize, usize) {
+        (self.trues.value(lor), self.scatters.value(lor))
+    }
+
+    /// Number of randoms in the bin containing `lor`
+    pub fn randoms(&self, lor: &LOR) -> usize { self.randoms.value(lor) }
+
+    /// Everything the scattergram knows about `lor`: its correction, the
+    /// counts on which it is based, and the bin from which they come
+    pub fn describe(&self, lor: &LOR) -> LorCorrection {
+        let (fraction, trues, scatters, randoms) = self.triplet(lor);
+        LorCorrection { fraction, trues, scatters, randoms, bin: self.trues.bin(lor) }
+    }
+
+    /// The correction `value` of `lor`, followed by the trues, scatters and
+    /// randoms in its bin (smoothed and interpolated, as `value`)
+    pub fn triplet(&self, lor: &LOR) -> (Ratio, f32, f32, f32) {
+        let (trues, scatters, randoms) = self.bin_counts(lor);
+        (self.value(lor), trues, scatters, randoms)
+    }
+}
+
+/// `(scatters + trues) / trues`, or `empty` without trues
+pub(crate) fn scatter_value(trues: usize, scatters: usize, empty: Ratio) -> Ratio {
+    scatter_ratio(trues as f32, scatters as f32, empty)
+}
+
+/// As `scatter_value`, for counts which need not be whole
+fn scatter_ratio(trues: f32, scatters: f32, empty: Ratio) -> Ratio {
+    if trues > 0.0 { ratio((scatters + trues) / trues) } else { empty }
+}
+
+/// Bins of a `Scattergram` lacking statistics, as counted by `validate`.
+/// Overflow bins are included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BinOccupancy {
+    pub n_bins: usize,
+    /// Bins without events of any kind
+    pub n_empty: usize,
+    /// Bins without trues, including the empty ones
+    pub n_without_trues: usize,
+}
+
+impl std::fmt::Display for BinOccupancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let percent = |n: usize| 100.0 * n as f32 / self.n_bins.max(1) as f32;
+        write!(f, "{} of {} scattergram bins ({:.1}%) have no trues, {} ({:.1}%) no events at all",
+               self.n_without_trues, self.n_bins, percent(self.n_without_trues), self.n_empty, percent(self.n_empty))
     }
+}
+
+/// Scatter correction of a single LOR, as reported by `Scattergram::describe`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LorCorrection {
+    pub fraction: Ratio,
+    pub trues: f32,
+    pub scatters: f32,
+    pub randoms: f32,
+    /// Intervals of the bin along each axis; `None` if the LOR cannot be binned
+    pub bin: Option<String>,
+}
 
-    pub fn triplet(&self, lor: &LOR) -> (Ratio, f32, f32) {
-        let trues = self.trues.value(lor);
-        if trues > 0 {
-            let scatters: f32 = self.scatters.value(lor) as f32;
-            let trues = trues as f32;
-            (ratio((scatters + trues) / trues), trues, scatters)
-        } else { (ratio(1.0), 0.0, self.scatters.value(lor) as f32) }
+impl std::fmt::Display for LorCorrection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "fraction {:8.4}   trues {:8}   scatters {:8}   randoms {:8}   bin {}",
+               ratio_(self.fraction), self.trues, self.scatters, self.randoms,
+               self.bin.as_deref().unwrap_or("none"))
+    }
+}
+
+#[cfg(test)]
+mod test_scattergram_axes {
+    use super::*;
+    use ndhistogram::ndhistogram;
+
+    fn z () -> LorAxU { axis_z (10, mm(-100.0), mm(100.0)) }
+    fn dz() -> LorAxU { axis_dz(10, mm(1000.0)) }
+
+    #[test]
+    fn duplicate_kinds_are_rejected() {
+        let result = Scattergram::with_axes(&[AxisKind::Z, AxisKind::Z], false, &|| Box::new(ndhistogram!(z(), z(); usize)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn distinct_kinds_are_accepted() {
+        let result = Scattergram::with_axes(&[AxisKind::Z, AxisKind::Dz], false, &|| Box::new(ndhistogram!(z(), dz(); usize)));
+        assert!(result.is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test_bin_edges {
+    use super::*;
+    use ndhistogram::{ndhistogram, Histogram};
+    use rstest::rstest;
+
+    /// LOR along x at height `y`, from `x` to `-x`, with endpoints at `z1`, `z2`
+    fn along_x(x: f32, y: f32, z1: f32, z2: f32) -> LOR { mk_lor(((x, y, z1), (-x, y, z2))) }
+
+    // Edges of axis_dz(4, 100 mm) are 0, 25, 50, 75, 100 mm
+    #[rstest]
+    #[case(  0.0, 1)]
+    #[case( -0.0, 1)]
+    #[case( 25.0, 2)]
+    #[case( 50.0, 3)]
+    #[case( 75.0, 4)]
+    #[case(100.0, 5)] // overflow
+    fn dz_on_edges(#[case] z: f32, #[case] expected: usize) {
+        let axis = axis_dz(4, mm(100.0));
+        assert_eq!(axis.index(&along_x(100.0, 0.0, 0.0, z)), Some(expected));
+        assert_eq!(axis.index(&along_x(100.0, 0.0, z, 0.0)), Some(expected));
+    }
+
+    // Edges of axis_r(4, 100 mm) are 0, 25, 50, 75, 100 mm
+    #[rstest]
+    #[case(  0.0, 1)]
+    #[case( -0.0, 1)]
+    #[case( 25.0, 2)]
+    #[case(-50.0, 3)]
+    #[case( 75.0, 4)]
+    #[case(100.0, 5)] // overflow
+    fn r_on_edges(#[case] y: f32, #[case] expected: usize) {
+        let axis = axis_r(4, mm(100.0));
+        assert_eq!(axis.index(&along_x( 300.0, y, 0.0, 0.0)), Some(expected));
+        assert_eq!(axis.index(&along_x(-300.0, y, 0.0, 0.0)), Some(expected));
+    }
+
+    // Edges of axis_phi(4) are 0, ¼, ½ and ¾ turns; 1 turn wraps around to 0
+    #[rstest]
+    #[case(mk_lor(((-100.0,  10.0, 0.0), ( 100.0,  10.0, 0.0))), 0)] // 0
+    #[case(mk_lor(((-10.0, -100.0, 0.0), (-10.0,  100.0, 0.0))), 1)] // ¼
+    #[case(mk_lor(((-100.0, -10.0, 0.0), ( 100.0, -10.0, 0.0))), 2)] // ½
+    #[case(mk_lor((( 10.0, -100.0, 0.0), ( 10.0,  100.0, 0.0))), 3)] // ¾
+    #[case(mk_lor((( 100.0,  0.0, 0.0), (-100.0, -0.0, 0.0))), 0)]  // through the axis, dy = -0
+    #[case(mk_lor(((-100.0, -0.0, 0.0), ( 100.0,  0.0, 0.0))), 0)]  // through the axis, dy = +0
+    fn phi_on_edges(#[case] lor: LOR, #[case] expected: usize) {
+        let axis = axis_phi(4);
+        let reversed = LOR { p1: lor.p2, p2: lor.p1, ..lor };
+        assert_eq!(axis.index(&lor), Some(expected));
+        assert_eq!(axis.index(&reversed), Some(expected));
+        for lor in [lor, reversed] {
+            let phi = radian_(phi(&lor));
+            assert!((0.0..TAU).contains(&phi) && phi.is_sign_positive(), "phi = {phi}");
+        }
+    }
+
+    #[test]
+    fn every_fill_lands_in_a_bin() {
+        let mut h = ndhistogram!(axis_dz(4, mm(100.0)), axis_phi(4); usize);
+        let edges = [-0.0, 0.0, 25.0, 50.0, 75.0, 100.0, 150.0];
+        let mut n = 0;
+        for &z in &edges {
+            for &(x, y) in &[(100.0, 10.0), (-100.0, 10.0), (100.0, -10.0), (100.0, 0.0), (100.0, -0.0)] {
+                Lorogram::fill(&mut h, &along_x(x, y, 0.0, z));
+                n += 1;
+            }
+        }
+        assert_eq!(h.values().sum::<usize>(), n);
+    }
+}
+
+#[cfg(test)]
+mod test_true_threshold {
+    use super::*;
+
+    #[test]
+    fn defaults_to_electron_rest_energy() {
+        let sgram = BuildScattergram::new().phi_bins(4).build().unwrap();
+        assert_eq!(sgram.true_threshold(), ELECTRON_REST_ENERGY);
+        assert_eq!(sgram.classify(ELECTRON_REST_ENERGY, ELECTRON_REST_ENERGY), Prompt::True);
+        assert_eq!(sgram.classify(ELECTRON_REST_ENERGY, 510.0), Prompt::Scatter);
+    }
+
+    #[test]
+    fn is_configurable() {
+        let sgram = BuildScattergram::new().phi_bins(4).true_threshold(450.0).build().unwrap();
+        assert_eq!(sgram.classify(460.0, 500.0), Prompt::True);
+        assert_eq!(sgram.classify(440.0, 500.0), Prompt::Scatter);
+    }
+}
+
+#[cfg(test)]
+mod test_randoms {
+    use super::*;
+    use ndhistogram::ndhistogram;
+
+    fn scattergram() -> Scattergram { Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)); usize))) }
+
+    #[test]
+    fn randoms_add_to_the_correction() {
+        let mut sgram = scattergram();
+        let lor = mk_lor(((-300.0, 0.0, 10.0), (300.0, 0.0, 10.0)));
+        for _ in 0..4 { sgram.fill(Prompt::True   , &lor) }
+        for _ in 0..2 { sgram.fill(Prompt::Scatter, &lor) }
+        for _ in 0..1 { sgram.fill(Prompt::Random , &lor) }
+        assert_eq!(sgram.counts(&lor), (4, 2));
+        assert_eq!(sgram.randoms(&lor), 1);
+        assert_eq!(ratio_(sgram.value(&lor)), 7.0 / 4.0);
+        let (fraction, trues, scatters, randoms) = sgram.triplet(&lor);
+        assert_eq!((ratio_(fraction), trues, scatters, randoms), (7.0 / 4.0, 4.0, 2.0, 1.0));
+        assert_eq!(sgram.describe(&lor).randoms, 1.0);
+        // Other bins are unaffected
+        let elsewhere = mk_lor(((-300.0, 0.0, -90.0), (300.0, 0.0, -90.0)));
+        assert_eq!(sgram.randoms(&elsewhere), 0);
+    }
+
+    #[test]
+    fn randoms_can_be_added_at_an_index() {
+        let mut sgram = scattergram();
+        let lor = mk_lor(((-300.0, 0.0, 60.0), (300.0, 0.0, 60.0)));
+        let index = sgram.index(&lor).unwrap();
+        sgram.add_at(Prompt::Random, index, 3);
+        assert_eq!(sgram.randoms(&lor), 3);
+    }
+
+    #[test]
+    fn classifier_identifies_randoms() {
+        let lors = (0..10).map(|i| LorWithMeta::with_energies(mk_lor(((-300.0, 0.0, 10.0), (300.0, 0.0, 10.0))), if i < 3 { 400.0 } else { 511.0 }, 511.0));
+        let sgram = fill_scattergram_with(
+            &|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)); usize)),
+            lors,
+            |&LorWithMeta { e1, e2, .. }| if e1 < 450.0 { Prompt::Random } else { classify_energies(e1, e2, ELECTRON_REST_ENERGY) },
+        );
+        let lor = mk_lor(((-300.0, 0.0, 10.0), (300.0, 0.0, 10.0)));
+        assert_eq!(sgram.counts(&lor), (7, 0));
+        assert_eq!(sgram.randoms(&lor), 3);
+    }
+}
+
+#[cfg(test)]
+mod test_empty_bins {
+    use super::*;
+    use ndhistogram::ndhistogram;
+
+    fn scattergram() -> Scattergram { Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)); usize))) }
+
+    fn lor_at(z: Lengthf32) -> LOR { mk_lor(((-300.0, 0.0, z), (300.0, 0.0, z))) }
+
+    #[test]
+    fn bins_without_trues_get_no_correction_by_default() {
+        let mut sgram = scattergram();
+        for _ in 0..3 { sgram.fill(Prompt::Scatter, &lor_at(60.0)) }
+        for lor in [lor_at(60.0), lor_at(-60.0)] {
+            assert_eq!(ratio_(sgram.value(&lor)), 1.0);
+            assert_eq!(ratio_(sgram.triplet(&lor).0), 1.0);
+        }
+    }
+
+    #[test]
+    fn empty_bin_value_is_configurable() {
+        let sgram = BuildScattergram::new().z_bins(4).empty_bin_value(ratio(1.5)).build().unwrap();
+        assert_eq!(ratio_(sgram.empty_bin_value()), 1.5);
+        assert_eq!(ratio_(sgram.value(&lor_at(60.0))), 1.5);
+
+        let mut windows = WindowedScattergram::new(sgram, 0.0, 10.0, 2).unwrap();
+        windows.fill(Prompt::Scatter, &lor_at(60.0), 1.0);
+        assert_eq!(ratio_(windows.value_in_window(0, &lor_at(60.0))), 1.5);
+        assert_eq!(ratio_(windows.value(&lor_at(60.0), 5.0)), 1.5);
+    }
+
+    #[test]
+    fn validate_counts_bins_lacking_statistics() {
+        let mut sgram = scattergram();
+        let n_bins = sgram.n_bins();
+        assert_eq!(sgram.validate(), BinOccupancy { n_bins, n_empty: n_bins, n_without_trues: n_bins });
+        sgram.fill(Prompt::True   , &lor_at( 60.0));
+        sgram.fill(Prompt::Scatter, &lor_at( 60.0));
+        sgram.fill(Prompt::Random , &lor_at(-60.0));
+        assert_eq!(sgram.validate(), BinOccupancy { n_bins, n_empty: n_bins - 2, n_without_trues: n_bins - 1 });
+    }
+}
+
+#[cfg(test)]
+mod test_parallel_fill {
+    use super::*;
+    use ndhistogram::ndhistogram;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn make() -> Box<dyn Lorogram> {
+        Box::new(ndhistogram!(axis_z(5, mm(-100.0), mm(100.0)), axis_phi(6), axis_r(4, mm(120.0)); usize))
+    }
+
+    fn lors(n: usize) -> Vec<LorWithMeta> {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut coordinate = |max: f32| rng.gen_range(-max..max);
+        (0..n).map(|_| {
+            let lor = mk_lor(((coordinate(300.0), coordinate(300.0), coordinate(150.0)),
+                              (coordinate(300.0), coordinate(300.0), coordinate(150.0))));
+            LorWithMeta::with_energies(lor, 400.0 + coordinate(120.0), 511.0)
+        }).collect()
+    }
+
+    fn counts(lorogram: &dyn Lorogram) -> Vec<usize> { (0..lorogram.n_bins()).map(|i| lorogram.value_at(i)).collect() }
+
+    #[test]
+    fn parallel_fill_matches_serial_fill() {
+        let lors = lors(3 * PARALLEL_FILL_CHUNK + 123);
+        let serial   =     fill_scattergram(&make, lors.iter().copied());
+        let parallel = par_fill_scattergram(&make, &lors);
+        assert_eq!(parallel.n_bins(), serial.n_bins());
+        assert_eq!(counts(parallel.trues   .as_ref()), counts(serial.trues   .as_ref()));
+        assert_eq!(counts(parallel.scatters.as_ref()), counts(serial.scatters.as_ref()));
+        assert_eq!(counts(parallel.randoms .as_ref()), counts(serial.randoms .as_ref()));
+        let total = |s: &Scattergram| (0..s.n_bins()).map(|i| s.trues.value_at(i) + s.scatters.value_at(i)).sum::<usize>();
+        assert_eq!(total(&parallel), lors.len());
+    }
+
+    #[test]
+    fn empty_input_gives_empty_scattergram() {
+        let sgram = par_fill_scattergram(&make, &[]);
+        assert_eq!((0..sgram.n_bins()).map(|i| sgram.trues.value_at(i)).sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn merging_different_axes_fails() {
+        let mut a = Scattergram::new(&make);
+        let b = Scattergram::new(&|| Box::new(ndhistogram!(axis_z(5, mm(-100.0), mm(100.0)), axis_phi(6), axis_r(4, mm(100.0)); usize)));
+        let c = Scattergram::new(&|| Box::new(ndhistogram!(axis_z(5, mm(-100.0), mm(100.0)); usize)));
+        assert!(a.merge(&b).is_err());
+        assert!(a.merge(&c).is_err());
+        let d = Scattergram::new(&make).with_true_threshold(450.0);
+        assert!(a.merge(&d).is_err());
+        assert!(a.merge(&Scattergram::new(&make)).is_ok());
     }
 }
 // --------------------------------------------------------------------------------
@@ -66,19 +577,20 @@ where
     A: Axis,
 {
     axis: A,
-    map: Box<dyn Fn(&T) -> A::Coordinate>,
+    map: Box<dyn Fn(&T) -> A::Coordinate + Send + Sync>,
 }
 
+/// Bins follow the conventions of `axis::HalfOpen`
 impl<T,A> Axis for MappedAxis<T,A>
 where
-    A: Axis,
+    A: HalfOpen,
 {
     type Coordinate = T;
 
     type BinInterval = A::BinInterval;
 
     fn index(&self, coordinate: &Self::Coordinate) -> Option<usize> {
-        self.axis.index(&(self.map)(coordinate))
+        self.axis.half_open_index(&(self.map)(coordinate))
     }
 
     fn num_bins(&self) -> usize {
@@ -92,19 +604,45 @@ where
 // --------------------------------------------------------------------------------
 pub type LorAxU = MappedAxis<LOR, Uniform<Lengthf32>>;
 pub type LorAxC = MappedAxis<LOR, Cyclic <Lengthf32>>;
+pub type LorAxV = MappedAxis<LOR, Variable<Lengthf32>>;
+
+// All the LOR coordinates binned by scattergrams are independent of the order
+// of the endpoints, so scattergrams do not need canonical endpoints.
+//
+// None of them is ever -0.0, so they cannot differ in sign from the same
+// coordinate computed elsewhere: values exactly on a bin edge are assigned as
+// described in `axis`.
 
 fn z_of_midpoint(LOR {p1, p2, ..}: &LOR) -> Length { (p1.z + p2.z) / 2.0 }
 
 fn delta_z(LOR{p1, p2, ..}: &LOR) -> Length { (p1.z - p2.z).abs() }
 
+/// Polar angle of the LOR, measured from the transverse plane: 0 for
+/// transverse LORs, a quarter turn for axial ones. Independent of the order of
+/// the endpoints.
+pub fn theta(lor: &LOR) -> Angle {
+    let LOR{ p1, p2, .. } = lor;
+    let dx = p2.x - p1.x;
+    let dy = p2.y - p1.y;
+    delta_z(lor).atan2((dx*dx + dy*dy).sqrt())
+}
+
+/// Distance of closest approach to the z-axis. LORs without transverse extent
+/// (axial or degenerate ones) lie at the transverse distance of their endpoints.
 fn distance_from_z_axis(LOR{ p1, p2, .. }: &LOR) -> Length {
     let dx = p2.x - p1.x;
     let dy = p2.y - p1.y;
     let x1 = p1.x;
     let y1 = p1.y;
-    (dx * y1 - dy * x1).abs() / (dx*dx + dy*dy).sqrt()
+    let transverse = (dx*dx + dy*dy).sqrt();
+    if transverse > mm(0.0) { (dx * y1 - dy * x1).abs() / transverse }
+    else                    { (x1*x1 + y1*y1).sqrt() }
 }
 
+/// Transverse direction of the LOR, distinguishing the sides of the z-axis.
+/// Zero for LORs without transverse extent. LORs through the z-axis have no
+/// side, and get the direction in `[0, ½)` turns. Independent of the order of
+/// the endpoints.
 fn phi(LOR{ p1, p2, .. }: &LOR) -> Angle {
     // TODO this repeats the work done in distance_from_z_axis. Can this be
     // optimized out, once we settle on a less flexible scattergram?
@@ -112,47 +650,357 @@ fn phi(LOR{ p1, p2, .. }: &LOR) -> Angle {
     let dy = p2.y - p1.y;
     let x1 = p1.x;
     let y1 = p1.y;
-    let r = (dx * y1 - dy * x1) / (dx*dx + dy*dy).sqrt();
+    let transverse = (dx*dx + dy*dy).sqrt();
+    if transverse <= mm(0.0) { return turn(0.0) }
+    let r = (dx * y1 - dy * x1) / transverse;
     let phi = phi_of_x_y(dx, dy);
-    if r < mm(0.0) { phi + turn(0.5) }
-    else           { phi             }
-
+    let phi = if      r < mm(0.0)      { phi + turn(0.5) }
+              else if r > mm(0.0)      { phi             }
+              else if phi < turn(0.0)  { phi + turn(0.5) }
+              else if phi >= turn(0.5) { phi - turn(0.5) }
+              else                     { phi             };
+    // Bring the result into [0, 1) turns. atan2 gives -½ turn rather than ½
+    // turn, and -0 rather than 0, when dy is -0: `+ 0` turns -0 into +0.
+    let phi = if phi < turn(0.0) { phi + turn(1.0) } else { phi };
+    if phi >= turn(1.0) { phi - turn(1.0) } else { phi + turn(0.0) }
 }
 
 fn phi_of_x_y(x: Length, y: Length) -> Angle { y.atan2(x) }
 
-pub fn axis_z(nbins: usize, min: Length, max: Length) -> LorAxU {
-    LorAxU {
-        axis: Uniform::new(nbins, mm_(min), mm_(max)),
+pub fn axis_z(nbins: usize, min: Length, max: Length) -> LorAxU { try_axis_z(nbins, min, max).unwrap_or_else(|e| panic!("{e}")) }
+pub fn axis_dz(nbins: usize, max: Length)           -> LorAxU { try_axis_dz(nbins, max)     .unwrap_or_else(|e| panic!("{e}")) }
+pub fn axis_r(nbins: usize, max: Length)            -> LorAxU { try_axis_r(nbins, max)      .unwrap_or_else(|e| panic!("{e}")) }
+pub fn axis_phi(nbins: usize)                       -> LorAxC { try_axis_phi(nbins)         .unwrap_or_else(|e| panic!("{e}")) }
+pub fn axis_t(nbins: usize, max: Time)              -> LorAxU { try_axis_t(nbins, max)      .unwrap_or_else(|e| panic!("{e}")) }
+
+pub fn try_axis_z(nbins: usize, min: Length, max: Length) -> Result<LorAxU, AxisError> {
+    Ok(LorAxU {
+        axis: try_uniform(nbins, mm_(min), mm_(max))?,
         map: Box::new(|z| mm_(z_of_midpoint(z))),
-    }
+    })
 }
 
-pub fn axis_dz(nbins: usize, max: Length) -> LorAxU {
-    LorAxU {
-        axis: Uniform::new(nbins, 0.0, mm_(max)),
+pub fn try_axis_dz(nbins: usize, max: Length) -> Result<LorAxU, AxisError> {
+    Ok(LorAxU {
+        axis: try_uniform(nbins, 0.0, mm_(max))?,
         map: Box::new(|x| mm_(delta_z(x))),
-    }
+    })
 }
 
-pub fn axis_r(nbins: usize, max: Length) -> LorAxU {
-    LorAxU {
-        axis: Uniform::new(nbins, 0.0, mm_(max)),
+pub fn try_axis_r(nbins: usize, max: Length) -> Result<LorAxU, AxisError> {
+    Ok(LorAxU {
+        axis: try_uniform(nbins, 0.0, mm_(max))?,
         map: Box::new(|x| mm_(distance_from_z_axis(x))),
-    }
+    })
 }
 
-pub fn axis_phi(nbins: usize) -> LorAxC {
-    LorAxC {
-        axis: Cyclic::new(nbins, 0.0, TAU),
+pub fn try_axis_phi(nbins: usize) -> Result<LorAxC, AxisError> {
+    Ok(LorAxC {
+        axis: Cyclic::try_new(nbins, 0.0, TAU)?,
         map: Box::new(|x| radian_(phi(x))),
-    }
+    })
+}
+
+/// `phi` of `lor` modulo the azimuthal pitch of the modules of `scanner`, in
+/// radians in `[0, pitch)`: the transverse direction of the LOR relative to the
+/// module pattern, which is the same for LORs related by a rotation through a
+/// whole number of modules.
+pub fn phi_within_module(lor: &LOR, scanner: &Scanner) -> Anglef32 {
+    wrap_within(radian_(phi(lor)), radian_(scanner.module_pitch()))
+}
+
+/// `angle` modulo `pitch`, in `[0, pitch)`
+fn wrap_within(angle: Anglef32, pitch: Anglef32) -> Anglef32 {
+    let wrapped = angle.rem_euclid(pitch);
+    // rem_euclid may round up to `pitch` for tiny negative angles
+    if wrapped >= pitch { 0.0 } else { wrapped }
+}
+
+pub fn axis_phi_within_module(nbins: usize, scanner: &Scanner) -> LorAxC {
+    try_axis_phi_within_module(nbins, scanner).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Axis binning `phi_within_module`, for corrections of patterns which repeat
+/// with every module, such as crystal interference
+pub fn try_axis_phi_within_module(nbins: usize, scanner: &Scanner) -> Result<LorAxC, AxisError> {
+    let pitch = radian_(scanner.module_pitch());
+    Ok(LorAxC {
+        axis: Cyclic::try_new(nbins, 0.0, pitch)?,
+        map: Box::new(move |x| wrap_within(radian_(phi(x)), pitch)),
+    })
 }
 
-pub fn axis_t(nbins: usize, max: Time) -> LorAxU {
-    LorAxU {
-        axis: Uniform::new(nbins, ps_(-max), ps_(max)),
+pub fn try_axis_t(nbins: usize, max: Time) -> Result<LorAxU, AxisError> {
+    Ok(LorAxU {
+        axis: try_uniform(nbins, ps_(-max), ps_(max))?,
         map: Box::new(|z| mm_(z_of_midpoint(z))),
+    })
+}
+
+/// Axis of the same quantity as the uniform axis of `kind`, but with bins
+/// between `edges`, in the units of that axis. The cyclic `phi` cannot have
+/// variable bins.
+pub fn try_axis_variable(kind: AxisKind, edges: Vec<Lengthf32>) -> Result<LorAxV, AxisError> {
+    let map: Box<dyn Fn(&LOR) -> Lengthf32 + Send + Sync> = match kind {
+        // As `try_axis_t`, T bins the axial midpoint (see `REDUNDANT_AXES`)
+        AxisKind::R               => Box::new(|x| mm_(distance_from_z_axis(x))),
+        AxisKind::Z | AxisKind::T => Box::new(|x| mm_(z_of_midpoint(x))),
+        AxisKind::Dz              => Box::new(|x| mm_(delta_z(x))),
+        AxisKind::Phi             => return Err(AxisError::Cyclic),
+    };
+    Ok(LorAxV { axis: try_variable(edges)?, map })
+}
+
+/// Axis binning an energy of a `LorWithMeta`. Energies are not binned by
+/// `Lorogram`s, whose coordinate is the bare `LOR`: fill `ndhistogram`s of
+/// these axes directly.
+pub type LorEAxU = MappedAxis<LorWithMeta, Uniform<Energyf32>>;
+
+/// Which energy of a coincidence `axis_e` bins
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnergyOf {
+    /// The lower of the two gamma energies, as compared to the true threshold
+    Min,
+    /// The total energy deposited by both gammas
+    Sum,
+}
+
+impl EnergyOf {
+    #[allow(nonstandard_style)]
+    pub fn of(self, E1: Energyf32, E2: Energyf32) -> Energyf32 {
+        match self {
+            Self::Min => E1.min(E2),
+            Self::Sum => E1 + E2,
+        }
+    }
+}
+
+impl std::str::FromStr for EnergyOf {
+    type Err = String;
+    /// `min` or `sum`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(Self::Min),
+            "sum" => Ok(Self::Sum),
+            _ => Err(format!("Expected min or sum, got '{s}'")),
+        }
+    }
+}
+
+pub fn axis_e(nbins: usize, low: Energyf32, high: Energyf32, of: EnergyOf) -> LorEAxU {
+    try_axis_e(nbins, low, high, of).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Axis binning the energy (keV) `of` a coincidence uniformly in `[low, high)`
+pub fn try_axis_e(nbins: usize, low: Energyf32, high: Energyf32, of: EnergyOf) -> Result<LorEAxU, AxisError> {
+    Ok(LorEAxU {
+        axis: try_uniform(nbins, low, high)?,
+        map: Box::new(move |&LorWithMeta { e1, e2, .. }| of.of(e1, e2)),
+    })
+}
+
+#[cfg(test)]
+mod test_energy_axis {
+    use super::*;
+    use ndhistogram::ndhistogram;
+
+    #[test]
+    fn bins_min_or_sum_of_energies() {
+        let lor = mk_lor(((-300.0, 0.0, 0.0), (300.0, 0.0, 0.0)));
+        let mut min = ndhistogram!(axis_e(4, 400.0, 600.0, EnergyOf::Min); usize);
+        let mut sum = ndhistogram!(axis_e(4, 800.0, 1200.0, EnergyOf::Sum); usize);
+        for (e1, e2) in [(511.0, 430.0), (511.0, 511.0), (300.0, 700.0)] {
+            let event = LorWithMeta::with_energies(lor, e1, e2);
+            min.fill(&event);
+            sum.fill(&event);
+        }
+        // Indices 0 and 5 are the underflow and overflow bins
+        assert_eq!((0..6).map(|i| *min.value_at_index(i).unwrap()).collect::<Vec<_>>(), vec![1, 1, 0, 1, 0, 0]);
+        assert_eq!((0..6).map(|i| *sum.value_at_index(i).unwrap()).collect::<Vec<_>>(), vec![0, 0, 1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_bounds_are_rejected() {
+        assert!(try_axis_e(4, 600.0, 400.0, EnergyOf::Min).is_err());
+        assert!(try_axis_e(0, 400.0, 600.0, EnergyOf::Sum).is_err());
+        assert_eq!("sum".parse(), Ok(EnergyOf::Sum));
+        assert!("max".parse::<EnergyOf>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_describe {
+    use super::*;
+    use ndhistogram::ndhistogram;
+
+    #[test]
+    fn description_matches_triplet() {
+        let mut sgram = Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)), axis_phi(4); usize)));
+        let lors: Vec<LOR> = (0..20)
+            .map(|i| i as f32)
+            .map(|i| mk_lor(((-300.0, 10.0 * i, 9.0 * i - 90.0), (300.0, -5.0 * i, 9.0 * i - 80.0))))
+            .collect();
+        for (i, lor) in lors.iter().enumerate() {
+            sgram.fill(if i % 3 == 0 { Prompt::Scatter } else { Prompt::True }, lor);
+        }
+        for lor in &lors {
+            let description = sgram.describe(lor);
+            let (fraction, trues, scatters, randoms) = sgram.triplet(lor);
+            assert_eq!((description.fraction, description.trues, description.scatters, description.randoms),
+                       (fraction, trues, scatters, randoms));
+            assert!(description.bin.is_some());
+        }
+        let outside = mk_lor(((-300.0, 0.0, 500.0), (300.0, 0.0, 500.0)));
+        let bin = sgram.describe(&outside).bin.unwrap();
+        assert!(bin.contains("Overflow"), "{bin}");
+    }
+}
+
+#[cfg(test)]
+mod test_degenerate_lors {
+    use super::*;
+    use crate::system_matrix::DegeneratePolicy;
+    use ndhistogram::ndhistogram;
+
+    #[test]
+    fn degenerate_and_axial_lors_have_finite_coordinates() {
+        let degenerate = mk_lor(((30.0, 40.0, 7.0), (30.0, 40.0, 7.0)));
+        let axial      = mk_lor(((30.0, 40.0, 7.0), (30.0, 40.0, -7.0)));
+        for lor in [degenerate, axial] {
+            assert_eq!(mm_(distance_from_z_axis(&lor)), 50.0);
+            assert_eq!(radian_(phi(&lor)), 0.0);
+        }
+    }
+
+    #[test]
+    fn filling_with_degenerate_lor_under_keep() {
+        let mut sgram = Scattergram::new(&|| Box::new(ndhistogram!(
+            axis_r(4, mm(100.0)), axis_phi(4), axis_z(4, mm(-100.0), mm(100.0)), axis_dz(4, mm(200.0)); usize)));
+        let degenerate = mk_lor(((30.0, 40.0, 7.0), (30.0, 40.0, 7.0)));
+        assert_eq!(DegeneratePolicy::Keep.admit(&degenerate), Ok(true));
+        sgram.fill(Prompt::True   , &degenerate);
+        sgram.fill(Prompt::Scatter, &degenerate);
+        let (fraction, trues, scatters, randoms) = sgram.triplet(&degenerate);
+        assert_eq!((ratio_(fraction), trues, scatters, randoms), (2.0, 1.0, 1.0, 0.0));
+        assert!(!sgram.describe(&degenerate).bin.unwrap().contains("NaN"));
+    }
+}
+
+#[cfg(test)]
+mod test_endpoint_order {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::{ns, radian};
+    use proptest::prelude::*;
+
+    /// `a - b`, reduced to `[-½, ½)` turns
+    fn angle_difference(a: Angle, b: Angle) -> f32 {
+        (radian_(a - b) + TAU / 2.0).rem_euclid(TAU) - TAU / 2.0
+    }
+
+    proptest! {
+        #[test]
+        fn mappings_ignore_endpoint_order(
+            x1 in -400.0..(400.0 as f32), y1 in -400.0..(400.0 as f32), z1 in -200.0..(200.0 as f32),
+            x2 in -400.0..(400.0 as f32), y2 in -400.0..(400.0 as f32), z2 in -200.0..(200.0 as f32),
+        ) {
+            let lor = mk_lor(((x1, y1, z1), (x2, y2, z2)));
+            let swapped = lor.swapped();
+            prop_assert_eq!(z_of_midpoint(&lor), z_of_midpoint(&swapped));
+            prop_assert_eq!(delta_z(&lor), delta_z(&swapped));
+            prop_assert_eq!(theta(&lor), theta(&swapped));
+            assert_float_eq!(mm_(distance_from_z_axis(&lor)), mm_(distance_from_z_axis(&swapped)), abs <= 1e-2);
+            // Near the axis, rounding can put the two orders on different sides of it
+            if mm_(distance_from_z_axis(&lor)) > 1e-2 {
+                assert_float_eq!(angle_difference(phi(&lor), phi(&swapped)), 0.0, abs <= 1e-4);
+            }
+            let t = axis_t(10, ns(1.0));
+            prop_assert_eq!(t.index(&lor), t.index(&swapped));
+        }
+    }
+
+    #[test]
+    fn phi_through_axis() {
+        for (x, y) in [(100.0, 0.0), (0.0, 100.0), (-100.0, 30.0), (-70.0, -70.0), (50.0, -120.0)] {
+            let lor = mk_lor(((x, y, 10.0), (-x, -y, -20.0)));
+            let (a, b) = (phi(&lor), phi(&lor.swapped()));
+            assert_float_eq!(angle_difference(a, b), 0.0, abs <= 1e-6);
+            assert!(a >= radian(0.0) && a < turn(0.5), "{x} {y}: {a:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_phi_within_module {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    fn scanner(azimuthal: usize) -> Scanner {
+        format!("radius = 350.0\nlength = 1000.0\n[modules]\nazimuthal = {azimuthal}\naxial = 1").parse().unwrap()
+    }
+
+    /// LOR through the point at `distance` from the z-axis in direction `phi`
+    /// (turns), travelling perpendicularly to it
+    fn lor_at(phi: f32, distance: f32) -> LOR {
+        let (s, c) = (phi * TAU).sin_cos();
+        let (x, y) = (distance * c, distance * s);
+        mk_lor(((x - 300.0 * s, y + 300.0 * c, 0.0), (x + 300.0 * s, y - 300.0 * c, 0.0)))
+    }
+
+    #[rstest(/**/ angle , pitch, expected,
+             case(  0.25,  1.0 , 0.25),
+             case(  1.25,  1.0 , 0.25),
+             case( -0.25,  1.0 , 0.75),
+             case(  1.0 ,  1.0 , 0.0 ),
+             case(-1e-9 ,  1.0 , 0.0 ),
+             case( -2.0 ,  1.0 , 0.0 ),
+    )]
+    fn wraps(angle: f32, pitch: f32, expected: f32) {
+        let wrapped = wrap_within(angle, pitch);
+        assert!((0.0..pitch).contains(&wrapped), "{wrapped}");
+        assert_float_eq!(wrapped, expected, abs <= 1e-6);
+    }
+
+    #[test]
+    fn rotating_by_a_module_changes_nothing() {
+        let scanner = scanner(8);
+        let pitch = 1.0 / 8.0;
+        for phi in [0.01, 0.06, 0.124, 0.3, 0.77] {
+            let a = phi_within_module(&lor_at(phi, 40.0), &scanner);
+            for k in 1..8 {
+                let b = phi_within_module(&lor_at(phi + k as f32 * pitch, 40.0), &scanner);
+                assert_float_eq!(a, b, abs <= 1e-4);
+            }
+            assert!(a >= 0.0 && a < TAU * pitch);
+        }
+    }
+
+    #[test]
+    fn bins_wrap_at_module_boundaries() {
+        use ndhistogram::ndhistogram;
+        let scanner = scanner(8);
+        let pitch = 1.0 / 8.0;
+        let mut h = ndhistogram!(axis_phi_within_module(4, &scanner); usize);
+        // Just before and after the boundary between modules 2 and 3, and at
+        // the start of module 0
+        let before = lor_at(3.0 * pitch - 1e-3, 40.0);
+        let after  = lor_at(3.0 * pitch + 1e-3, 40.0);
+        let start  = lor_at(1e-3, 40.0);
+        let last   = lor_at(-1e-3, 40.0);
+        for lor in [&before, &after, &start, &last] { Lorogram::fill(&mut h, lor) }
+        assert_eq!(Lorogram::value(&h, &start), 2);
+        assert_eq!(Lorogram::value(&h, &before), 2);
+        assert_eq!(Lorogram::index(&h, &after), Lorogram::index(&h, &start));
+        assert_eq!(Lorogram::index(&h, &last), Lorogram::index(&h, &before));
+        assert_ne!(Lorogram::index(&h, &start), Lorogram::index(&h, &before));
+    }
+
+    #[test]
+    fn no_modules_is_plain_phi() {
+        let scanner: Scanner = "radius = 350.0\nlength = 1000.0".parse().unwrap();
+        let lor = lor_at(0.7, 40.0);
+        assert_float_eq!(phi_within_module(&lor, &scanner), radian_(phi(&lor)).rem_euclid(TAU), abs <= 1e-6);
     }
 }
 
@@ -201,72 +1049,248 @@ mod test_mapped_axes {
 
 }
 // --------------------------------------------------------------------------------
-pub trait Lorogram {
+/// `Send`, so that lorograms can be filled on separate threads and `merge`d
+pub trait Lorogram: Send {
     fn fill (&mut self, lor: &LOR);
     fn value(&    self, lor: &LOR) -> usize;
+    /// Total number of bins, including overflow bins
+    fn n_bins(&self) -> usize;
+    /// The intervals, along each axis, of the bin containing `lor`
+    fn bin(&self, lor: &LOR) -> Option<String>;
+    /// Position of the bin containing `lor` among all `n_bins`
+    fn index(&self, lor: &LOR) -> Option<usize>;
+    /// Add `count` to the bin at position `index`
+    fn add_at(&mut self, index: usize, count: usize);
+    /// Count in the bin at position `index`
+    fn value_at(&self, index: usize) -> usize;
+    /// The intervals, along each axis, of the bin at position `index`
+    fn bin_at(&self, index: usize) -> Option<String>;
+    /// Arrangement of the bins along each axis. The first axis varies fastest
+    /// with `index`.
+    fn layout(&self) -> Vec<AxisLayout>;
+    /// Position of `lor` along each axis, in units of bins (see
+    /// `HalfOpen::bin_position`)
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>>;
+
+    /// Count interpolated linearly between the centres of the bins around
+    /// `lor` (see `interpolation`), or the raw `value` if `lor` cannot be placed
+    fn value_interpolated(&self, lor: &LOR) -> f32 {
+        match self.positions(lor) {
+            Some(positions) => interpolation_weights(&self.layout(), &positions).into_iter()
+                .map(|(i, w)| w * self.value_at(i) as f32)
+                .sum(),
+            None => self.value(lor) as f32,
+        }
+    }
+
+    /// Add the counts of `other` to those of `self`. Fails, leaving `self`
+    /// unchanged, unless both have identical bins.
+    fn merge(&mut self, other: &dyn Lorogram) -> Result<(), String> {
+        if self.n_bins() != other.n_bins() {
+            return Err(format!("Cannot merge lorograms with {} and {} bins", self.n_bins(), other.n_bins()))
+        }
+        if let Some(i) = (0..self.n_bins()).find(|&i| self.bin_at(i) != other.bin_at(i)) {
+            return Err(format!("Cannot merge lorograms with different axes: bin {i} is {:?} in one and {:?} in the other",
+                               self.bin_at(i), other.bin_at(i)))
+        }
+        for i in 0..other.n_bins() {
+            let count = other.value_at(i);
+            if count > 0 { self.add_at(i, count) }
+        }
+        Ok(())
+    }
 }
 
 impl<X> Lorogram for ndhistogram::Hist1D<X, usize>
 where
-    X: Axis<Coordinate = LOR>,
+    X: Axis<Coordinate = LOR> + BinLayout + Send,
+    X::BinInterval: Debug,
 {
     fn fill (&mut self, lor: &LOR)          {  Histogram::fill (self, lor) }
     fn value(&    self, lor: &LOR) -> usize { *Histogram::value(self, lor).unwrap_or(&0) }
+    fn n_bins(&self)               -> usize {  Histogram::axes(self).num_bins() }
+    fn bin   (&    self, lor: &LOR) -> Option<String> {
+        let axes = Histogram::axes(self);
+        axes.index(lor).and_then(|i| axes.bin(i)).map(|b| format!("{b:?}"))
+    }
+    fn index (&    self, lor: &LOR) -> Option<usize> { Histogram::axes(self).index(lor) }
+    fn add_at(&mut self, index: usize, count: usize) {
+        if let Some(value) = Histogram::value_at_index_mut(self, index) { *value += count }
+    }
+    fn value_at(&self, index: usize) -> usize { *Histogram::value_at_index(self, index).unwrap_or(&0) }
+    fn bin_at  (&self, index: usize) -> Option<String> { Histogram::axes(self).bin(index).map(|b| format!("{b:?}")) }
+    fn layout  (&self) -> Vec<AxisLayout> { let axes = Histogram::axes(self); vec![axes.0.layout()] }
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>> { let axes = Histogram::axes(self); Some(vec![axes.0.position(lor)?]) }
 }
 
 impl<X, Y> Lorogram for ndhistogram::Hist2D<X, Y, usize>
 where
-    X: Axis<Coordinate = LOR>,
-    Y: Axis<Coordinate = LOR>,
+    X: Axis<Coordinate = LOR> + BinLayout + Send,
+    Y: Axis<Coordinate = LOR> + BinLayout + Send,
+    X::BinInterval: Debug,
+    Y::BinInterval: Debug,
 {
     fn fill (&mut self, lor: &LOR)          {  Histogram::fill (self, &(*lor, *lor)) }
     fn value(&    self, lor: &LOR) -> usize { *Histogram::value(self, &(*lor, *lor)).unwrap_or(&0) }
+    fn n_bins(&self)               -> usize {  Histogram::axes(self).num_bins() }
+    fn bin   (&    self, lor: &LOR) -> Option<String> {
+        let axes = Histogram::axes(self);
+        axes.index(&(*lor, *lor)).and_then(|i| axes.bin(i)).map(|b| format!("{b:?}"))
+    }
+    fn index (&    self, lor: &LOR) -> Option<usize> { Histogram::axes(self).index(&(*lor, *lor)) }
+    fn add_at(&mut self, index: usize, count: usize) {
+        if let Some(value) = Histogram::value_at_index_mut(self, index) { *value += count }
+    }
+    fn value_at(&self, index: usize) -> usize { *Histogram::value_at_index(self, index).unwrap_or(&0) }
+    fn bin_at  (&self, index: usize) -> Option<String> { Histogram::axes(self).bin(index).map(|b| format!("{b:?}")) }
+    fn layout  (&self) -> Vec<AxisLayout> { let axes = Histogram::axes(self); vec![axes.0.layout(), axes.1.layout()] }
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>> { let axes = Histogram::axes(self); Some(vec![axes.0.position(lor)?, axes.1.position(lor)?]) }
 }
 
 impl<X, Y, Z> Lorogram for ndhistogram::Hist3D<X, Y, Z, usize>
 where
-    X: Axis<Coordinate = LOR>,
-    Y: Axis<Coordinate = LOR>,
-    Z: Axis<Coordinate = LOR>,
+    X: Axis<Coordinate = LOR> + BinLayout + Send,
+    Y: Axis<Coordinate = LOR> + BinLayout + Send,
+    Z: Axis<Coordinate = LOR> + BinLayout + Send,
+    X::BinInterval: Debug,
+    Y::BinInterval: Debug,
+    Z::BinInterval: Debug,
 {
     fn fill (&mut self, lor: &LOR)          {  Histogram::fill (self, &(*lor, *lor, *lor)) }
     fn value(&    self, lor: &LOR) -> usize { *Histogram::value(self, &(*lor, *lor, *lor)).unwrap_or(&0) }
+    fn n_bins(&self)               -> usize {  Histogram::axes(self).num_bins() }
+    fn bin   (&    self, lor: &LOR) -> Option<String> {
+        let axes = Histogram::axes(self);
+        axes.index(&(*lor, *lor, *lor)).and_then(|i| axes.bin(i)).map(|b| format!("{b:?}"))
+    }
+    fn index (&    self, lor: &LOR) -> Option<usize> { Histogram::axes(self).index(&(*lor, *lor, *lor)) }
+    fn add_at(&mut self, index: usize, count: usize) {
+        if let Some(value) = Histogram::value_at_index_mut(self, index) { *value += count }
+    }
+    fn value_at(&self, index: usize) -> usize { *Histogram::value_at_index(self, index).unwrap_or(&0) }
+    fn bin_at  (&self, index: usize) -> Option<String> { Histogram::axes(self).bin(index).map(|b| format!("{b:?}")) }
+    fn layout  (&self) -> Vec<AxisLayout> { let axes = Histogram::axes(self); vec![axes.0.layout(), axes.1.layout(), axes.2.layout()] }
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>> { let axes = Histogram::axes(self); Some(vec![axes.0.position(lor)?, axes.1.position(lor)?, axes.2.position(lor)?]) }
 }
 
 impl<X, Y, Z, T> Lorogram for ndhistogram::HistND<(X, Y, Z, T), usize>
 where
-    X: Axis<Coordinate = LOR>,
-    Y: Axis<Coordinate = LOR>,
-    Z: Axis<Coordinate = LOR>,
-    T: Axis<Coordinate = LOR>,
+    X: Axis<Coordinate = LOR> + BinLayout + Send,
+    Y: Axis<Coordinate = LOR> + BinLayout + Send,
+    Z: Axis<Coordinate = LOR> + BinLayout + Send,
+    T: Axis<Coordinate = LOR> + BinLayout + Send,
+    X::BinInterval: Debug,
+    Y::BinInterval: Debug,
+    Z::BinInterval: Debug,
+    T::BinInterval: Debug,
 {
     fn fill (&mut self, lor: &LOR)          {  Histogram::fill (self, &(*lor, *lor, *lor, *lor)) }
     fn value(&    self, lor: &LOR) -> usize { *Histogram::value(self, &(*lor, *lor, *lor, *lor)).unwrap_or(&0) }
+    fn n_bins(&self)               -> usize {  Histogram::axes(self).num_bins() }
+    fn bin   (&    self, lor: &LOR) -> Option<String> {
+        let axes = Histogram::axes(self);
+        axes.index(&(*lor, *lor, *lor, *lor)).and_then(|i| axes.bin(i)).map(|b| format!("{b:?}"))
+    }
+    fn index (&    self, lor: &LOR) -> Option<usize> { Histogram::axes(self).index(&(*lor, *lor, *lor, *lor)) }
+    fn add_at(&mut self, index: usize, count: usize) {
+        if let Some(value) = Histogram::value_at_index_mut(self, index) { *value += count }
+    }
+    fn value_at(&self, index: usize) -> usize { *Histogram::value_at_index(self, index).unwrap_or(&0) }
+    fn bin_at  (&self, index: usize) -> Option<String> { Histogram::axes(self).bin(index).map(|b| format!("{b:?}")) }
+    fn layout  (&self) -> Vec<AxisLayout> { let axes = Histogram::axes(self); vec![axes.0.layout(), axes.1.layout(), axes.2.layout(), axes.3.layout()] }
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>> { let axes = Histogram::axes(self); Some(vec![axes.0.position(lor)?, axes.1.position(lor)?, axes.2.position(lor)?, axes.3.position(lor)?]) }
 }
 
 impl<X, Y, Z, T, U> Lorogram for ndhistogram::HistND<(X, Y, Z, T, U), usize>
 where
-    X: Axis<Coordinate = LOR>,
-    Y: Axis<Coordinate = LOR>,
-    Z: Axis<Coordinate = LOR>,
-    T: Axis<Coordinate = LOR>,
-    U: Axis<Coordinate = LOR>,
+    X: Axis<Coordinate = LOR> + BinLayout + Send,
+    Y: Axis<Coordinate = LOR> + BinLayout + Send,
+    Z: Axis<Coordinate = LOR> + BinLayout + Send,
+    T: Axis<Coordinate = LOR> + BinLayout + Send,
+    U: Axis<Coordinate = LOR> + BinLayout + Send,
+    X::BinInterval: Debug,
+    Y::BinInterval: Debug,
+    Z::BinInterval: Debug,
+    T::BinInterval: Debug,
+    U::BinInterval: Debug,
 {
     fn fill (&mut self, lor: &LOR)          {  Histogram::fill (self, &(*lor, *lor, *lor, *lor, *lor)) }
     fn value(&    self, lor: &LOR) -> usize { *Histogram::value(self, &(*lor, *lor, *lor, *lor, *lor)).unwrap_or(&0) }
+    fn n_bins(&self)               -> usize {  Histogram::axes(self).num_bins() }
+    fn bin   (&    self, lor: &LOR) -> Option<String> {
+        let axes = Histogram::axes(self);
+        axes.index(&(*lor, *lor, *lor, *lor, *lor)).and_then(|i| axes.bin(i)).map(|b| format!("{b:?}"))
+    }
+    fn index (&    self, lor: &LOR) -> Option<usize> { Histogram::axes(self).index(&(*lor, *lor, *lor, *lor, *lor)) }
+    fn add_at(&mut self, index: usize, count: usize) {
+        if let Some(value) = Histogram::value_at_index_mut(self, index) { *value += count }
+    }
+    fn value_at(&self, index: usize) -> usize { *Histogram::value_at_index(self, index).unwrap_or(&0) }
+    fn bin_at  (&self, index: usize) -> Option<String> { Histogram::axes(self).bin(index).map(|b| format!("{b:?}")) }
+    fn layout  (&self) -> Vec<AxisLayout> { let axes = Histogram::axes(self); vec![axes.0.layout(), axes.1.layout(), axes.2.layout(), axes.3.layout(), axes.4.layout()] }
+    fn positions(&self, lor: &LOR) -> Option<Vec<f32>> { let axes = Histogram::axes(self); Some(vec![axes.0.position(lor)?, axes.1.position(lor)?, axes.2.position(lor)?, axes.3.position(lor)?, axes.4.position(lor)?]) }
 }
 
-pub fn fill_scattergram(make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>), lors: ndarray::Array1<Hdf5Lor>) ->  Scattergram {
+/// Fill a scattergram with `lors`, classified by the energies of their gammas
+pub fn fill_scattergram(
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>),
+    lors: impl IntoIterator<Item = LorWithMeta>,
+) -> Scattergram {
+    fill_scattergram_with(make_empty_lorogram, lors, classify_by_energy)
+}
+
+/// Fill a scattergram with `lors`, classified by `classify`, which is given
+/// each LOR with its energies and charges. Allows randoms to be identified.
+pub fn fill_scattergram_with(
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>),
+    lors: impl IntoIterator<Item = LorWithMeta>,
+    classify: impl Fn(&LorWithMeta) -> Prompt,
+) -> Scattergram {
     let mut sgram = Scattergram::new(make_empty_lorogram);
-    for h5lor @Hdf5Lor { x1, x2, E1, E2, .. } in lors {
-        if x1.is_nan() || x2.is_nan() { continue }
-        let prompt = if E1.min(E2) < 511.0 { Prompt::Scatter } else { Prompt::True };
-        sgram.fill(prompt, &LOR::from(h5lor));
+    for meta in lors {
+        let lor = &meta.lor;
+        if lor.p1.x.is_nan() || lor.p2.x.is_nan() { continue }
+        sgram.fill(classify(&meta), lor);
     }
     sgram
 }
 
+/// Trues and scatters by the energies of their gammas, as compared to the
+/// electron rest energy
+fn classify_by_energy(&LorWithMeta { e1, e2, .. }: &LorWithMeta) -> Prompt {
+    classify_energies(e1, e2, ELECTRON_REST_ENERGY)
+}
+
+/// Number of LORs filled into each per-thread scattergram by `par_fill_scattergram`
+const PARALLEL_FILL_CHUNK: usize = 100_000;
+
+/// As `fill_scattergram`, but filling scattergrams from chunks of `lors` in
+/// parallel, and merging them
+pub fn par_fill_scattergram(
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram> + Sync),
+    lors: &[LorWithMeta],
+) -> Scattergram {
+    par_fill_scattergram_with(make_empty_lorogram, lors, classify_by_energy)
+}
+
+/// As `fill_scattergram_with`, but filling scattergrams from chunks of `lors`
+/// in parallel, and merging them
+pub fn par_fill_scattergram_with(
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram> + Sync),
+    lors: &[LorWithMeta],
+    classify: impl Fn(&LorWithMeta) -> Prompt + Sync,
+) -> Scattergram {
+    use rayon::prelude::*;
+    lors.par_chunks(PARALLEL_FILL_CHUNK)
+        .map(|chunk| fill_scattergram_with(make_empty_lorogram, chunk.iter().copied(), &classify))
+        .reduce_with(|mut a, b| {
+            a.merge(&b).expect("Scattergrams made by the same factory should have identical bins");
+            a
+        })
+        .unwrap_or_else(|| Scattergram::new(make_empty_lorogram))
+}
+
 pub fn mk_lor(((x1,y1,z1), (x2,y2,z2)): ((f32, f32, f32), (f32, f32, f32))) -> LOR {
     let (x1, y1, z1, x2, y2, z2) = (mm(x1), mm(y1), mm(z1), mm(x2), mm(y2), mm(z2));
-    LOR { p1: Point::new(x1,y1,z1), p2: Point::new(x2,y2,z2), dt: Time::ZERO, additive_correction: ratio(1.0) }
+    LOR { p1: Point::new(x1,y1,z1), p2: Point::new(x2,y2,z2), dt: Time::ZERO, corrections: Corrections::NONE }
 }
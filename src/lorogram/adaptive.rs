@@ -0,0 +1,343 @@
+//! Scattergram binning adapted to the statistics of the data.
+//!
+//! The scattergram is first filled with fine, uniform, provisional bins. Then,
+//! along each non-cyclic axis in turn, runs of adjacent bins are merged, from
+//! the lowest upwards, until every bin holds at least `min_count` events
+//! (trues, scatters and randoms together) in each bin of the axes merged so
+//! far. A run at the top of an axis which falls short joins the run below it.
+//! The merged bins share the edges of the provisional ones, so their counts
+//! are carried over exactly, without filling them again.
+//!
+//! Cyclic axes (`phi`) keep their provisional bins, and the underflow and
+//! overflow bins are neither merged nor required to meet `min_count`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::{try_axis_variable, AxisKind, AxisLayout, AxisSpec, LorAxis, Lorogram, Scattergram, lorogram_with_axes};
+use crate::Lengthf32;
+use geometry::units::{mm_, ps_};
+
+/// Target of adaptive binning: `min_count=<n>`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdaptiveBinning {
+    /// Events below which a bin is merged with its neighbours
+    pub min_count: usize,
+}
+
+impl FromStr for AdaptiveBinning {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once('=') {
+            Some(("min_count", n)) => {
+                let min_count = n.trim().parse::<usize>().map_err(|e| format!("Invalid min_count '{n}': {e}"))?;
+                if min_count == 0 { return Err("min_count must be at least 1".into()) }
+                Ok(Self { min_count })
+            }
+            _ => Err(format!("Expected min_count=<n>, got '{s}'")),
+        }
+    }
+}
+
+/// An axis of an adapted scattergram
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdaptedAxis {
+    /// A cyclic axis, whose bins are never merged
+    Uniform(AxisSpec),
+    /// Bins between `edges`, in the units of the provisional axis (mm or ps)
+    Variable { kind: AxisKind, edges: Vec<Lengthf32> },
+}
+
+impl AdaptedAxis {
+    /// The axis of `spec` with the provisional regular bins merged into the
+    /// `groups` of each
+    fn merged(spec: &AxisSpec, groups: &[usize]) -> Self {
+        let edges = match uniform_edges(spec) {
+            Some(edges) => edges,
+            None => return Self::Uniform(*spec),
+        };
+        let mut merged = vec![edges[0]];
+        merged.extend((1..groups.len()).filter(|&i| groups[i] != groups[i - 1]).map(|i| edges[i]));
+        merged.push(edges[groups.len()]);
+        Self::Variable { kind: spec.kind(), edges: merged }
+    }
+
+    pub fn axis(&self) -> LorAxis {
+        match self {
+            Self::Uniform(spec) => spec.axis(),
+            Self::Variable { kind, edges } => LorAxis::V(try_axis_variable(*kind, edges.clone())
+                .unwrap_or_else(|e| panic!("{e} in adapted axis '{self}'"))),
+        }
+    }
+}
+
+/// `r:[0,2.5,6,100]` for variable axes; as `AxisSpec` for uniform ones
+impl fmt::Display for AdaptedAxis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Uniform(spec) => write!(f, "{spec}"),
+            Self::Variable { kind, edges } => {
+                let edges = edges.iter().map(ToString::to_string).collect::<Vec<_>>();
+                write!(f, "{kind}:[{}]", edges.join(","))
+            }
+        }
+    }
+}
+
+/// The edges of the regular bins of the non-cyclic `spec`, computed as by
+/// `axis::half_open_index`, so that they match those of the provisional axis
+/// exactly
+fn uniform_edges(spec: &AxisSpec) -> Option<Vec<Lengthf32>> {
+    let (bins, low, high) = match *spec {
+        AxisSpec::Phi { .. }            => return None,
+        AxisSpec::R   { bins, max }      => (bins, 0.0, mm_(max)),
+        AxisSpec::Z   { bins, min, max } => (bins, mm_(min), mm_(max)),
+        AxisSpec::Dz  { bins, max }      => (bins, 0.0, mm_(max)),
+        AxisSpec::T   { bins, max }      => (bins, ps_(-max), ps_(max)),
+    };
+    let step = (high - low) / bins as Lengthf32;
+    Some((0..=bins).map(|i| if i == bins { high } else { low + i as Lengthf32 * step }).collect())
+}
+
+/// Regular bins along each axis
+fn regular_bins(layout: &[AxisLayout]) -> Vec<usize> {
+    layout.iter().map(|a| if a.cyclic { a.n_bins } else { a.n_bins - 2 }).collect()
+}
+
+/// Coordinates of the bin at `index` along each axis (the first varying
+/// fastest), counting regular bins from 0; `None` for underflow and overflow
+/// bins
+fn regular_coordinates(layout: &[AxisLayout], mut index: usize) -> Option<Vec<usize>> {
+    layout.iter()
+        .map(|a| {
+            let i = index % a.n_bins;
+            index /= a.n_bins;
+            if a.cyclic { Some(i) } else if i == 0 || i == a.n_bins - 1 { None } else { Some(i - 1) }
+        })
+        .collect()
+}
+
+/// For each axis, the merged bin into which each of its regular provisional
+/// bins goes, given the `totals` of every provisional bin
+fn merge_groups(layout: &[AxisLayout], totals: &[usize], min_count: usize) -> Vec<Vec<usize>> {
+    let regular = regular_bins(layout);
+    let mut groups: Vec<Vec<usize>> = regular.iter().map(|&n| (0..n).collect()).collect();
+    let cells: Vec<(Vec<usize>, usize)> = totals.iter().enumerate()
+        .filter_map(|(i, &count)| Some((regular_coordinates(layout, i)?, count)))
+        .collect();
+    let n_merged = |groups: &[usize]| groups.last().map_or(0, |&g| g + 1);
+    for axis in (0..layout.len()).filter(|&a| !layout[a].cyclic) {
+        // Counts in each provisional slice across `axis`, by the merged bins
+        // of the other axes
+        let others: Vec<usize> = (0..layout.len()).filter(|&a| a != axis).collect();
+        let n_others: usize = others.iter().map(|&a| n_merged(&groups[a])).product();
+        let mut slices = vec![vec![0; n_others]; regular[axis]];
+        for (coordinates, count) in &cells {
+            let other = others.iter().fold(0, |key, &a| key * n_merged(&groups[a]) + groups[a][coordinates[a]]);
+            slices[coordinates[axis]][other] += count;
+        }
+        groups[axis] = merge_slices(&slices, min_count);
+    }
+    groups
+}
+
+/// Group consecutive `slices` so that each element of the sum of each group is
+/// at least `min_count`; a short last group joins the one before it
+fn merge_slices(slices: &[Vec<usize>], min_count: usize) -> Vec<usize> {
+    let mut groups = vec![0; slices.len()];
+    let mut current = 0;
+    let mut sum = vec![0; slices.first().map_or(0, Vec::len)];
+    for (i, slice) in slices.iter().enumerate() {
+        groups[i] = current;
+        for (s, &c) in sum.iter_mut().zip(slice) { *s += c }
+        if i + 1 < slices.len() && sum.iter().all(|&s| s >= min_count) {
+            current += 1;
+            sum.iter_mut().for_each(|s| *s = 0);
+        }
+    }
+    if current > 0 && sum.iter().any(|&s| s < min_count) {
+        for g in groups.iter_mut().filter(|g| **g == current) { *g = current - 1 }
+    }
+    groups
+}
+
+/// Index, in a lorogram with `merged` layout, of the bin into which the bin at
+/// `index` of one with `layout` goes
+fn merged_index(layout: &[AxisLayout], merged: &[AxisLayout], groups: &[Vec<usize>], mut index: usize) -> usize {
+    let (mut result, mut stride) = (0, 1);
+    for ((a, m), groups) in layout.iter().zip(merged).zip(groups) {
+        let i = index % a.n_bins;
+        index /= a.n_bins;
+        let j = if a.cyclic            { groups[i] }
+                else if i == 0         { 0 }
+                else if i == a.n_bins - 1 { m.n_bins - 1 }
+                else                   { groups[i - 1] + 1 };
+        result += j * stride;
+        stride *= m.n_bins;
+    }
+    result
+}
+
+impl Scattergram {
+    /// Adapt the bins to the statistics once filled (see `adapt_binning`).
+    /// `axes` must describe those of the lorograms, in order.
+    pub fn with_adaptive_binning(mut self, binning: AdaptiveBinning, axes: Vec<AxisSpec>) -> Self {
+        self.adaptive = Some((binning, axes));
+        self
+    }
+
+    pub fn is_adaptive(&self) -> bool { self.adaptive.is_some() }
+
+    /// The axes chosen by `adapt_binning`, if it has been applied
+    pub fn adapted_axes(&self) -> Option<&[AdaptedAxis]> { self.adapted_axes.as_deref() }
+
+    /// Merge the provisional bins of a scattergram `with_adaptive_binning`, as
+    /// described in `adaptive`, and discard any smoothing. The resulting edges
+    /// are reported by `adapted_axes`, and the bins of individual LORs by
+    /// `describe`. Returns the number of regular bins which still fall short
+    /// of `min_count`, because all their neighbours have been merged. Does
+    /// nothing to scattergrams without adaptive binning.
+    pub fn adapt_binning(&mut self) -> Result<usize, String> {
+        let (binning, specs) = match self.adaptive.take() {
+            Some(adaptive) => adaptive,
+            None => return Ok(0),
+        };
+        let layout = self.trues.layout();
+        if layout.len() != specs.len() {
+            return Err(format!("Adaptive binning of {} axes given for a scattergram with {}", specs.len(), layout.len()))
+        }
+        let totals: Vec<usize> = (0..self.n_bins())
+            .map(|i| self.trues.value_at(i) + self.scatters.value_at(i) + self.randoms.value_at(i))
+            .collect();
+        let groups = merge_groups(&layout, &totals, binning.min_count);
+        let adapted: Vec<AdaptedAxis> = specs.iter().zip(&groups).map(|(spec, groups)| AdaptedAxis::merged(spec, groups)).collect();
+
+        let make = || lorogram_with_axes(adapted.iter().map(AdaptedAxis::axis).collect());
+        let merged = make().layout();
+        let rebin = |from: &dyn Lorogram| {
+            let mut to = make();
+            for i in 0..from.n_bins() {
+                let count = from.value_at(i);
+                if count > 0 { to.add_at(merged_index(&layout, &merged, &groups, i), count) }
+            }
+            to
+        };
+        self.trues    = rebin(self.trues   .as_ref());
+        self.scatters = rebin(self.scatters.as_ref());
+        self.randoms  = rebin(self.randoms .as_ref());
+        self.smoothed = None;
+        self.adapted_axes = Some(adapted);
+
+        let short = (0..self.n_bins())
+            .filter(|&i| regular_coordinates(&merged, i).is_some())
+            .filter(|&i| self.trues.value_at(i) + self.scatters.value_at(i) + self.randoms.value_at(i) < binning.min_count)
+            .count();
+        Ok(short)
+    }
+}
+
+#[cfg(test)]
+mod test_adaptive {
+    use super::*;
+    use super::super::*;
+    use geometry::units::mm;
+    use rstest::rstest;
+
+    fn lor_at_radius(r: Lengthf32) -> LOR { mk_lor(((-300.0, r, 0.0), (300.0, r, 0.0))) }
+
+    /// Radial distribution falling as `exp(-r / 10 mm)`, in 50 provisional
+    /// bins up to 100 mm
+    fn falling() -> Scattergram {
+        let n = 20_000;
+        let mut sgram = BuildScattergram::new().r_bins(50).r_max(mm(100.0))
+            .adaptive(AdaptiveBinning { min_count: 500 })
+            .build().unwrap();
+        for i in 0..n {
+            let u = (i as f32 + 0.5) / n as f32;
+            sgram.fill(Prompt::True, &lor_at_radius(-10.0 * (1.0 - u).ln()));
+        }
+        sgram
+    }
+
+    fn totals(sgram: &Scattergram) -> Vec<usize> {
+        (0..sgram.n_bins()).map(|i| sgram.trues.value_at(i) + sgram.scatters.value_at(i) + sgram.randoms.value_at(i)).collect()
+    }
+
+    #[test]
+    fn falling_radial_distribution_gets_wider_outer_bins() {
+        let mut sgram = falling();
+        let before: usize = totals(&sgram).iter().sum();
+        assert_eq!(sgram.adapt_binning(), Ok(0));
+
+        let edges = match sgram.adapted_axes() {
+            Some([AdaptedAxis::Variable { kind: AxisKind::R, edges }]) => edges.clone(),
+            other => panic!("Expected a variable r axis, got {other:?}"),
+        };
+        assert_eq!((edges[0], edges[edges.len() - 1]), (0.0, 100.0));
+        let widths: Vec<f32> = edges.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(widths.len() > 3 && widths.len() < 50, "{edges:?}");
+        assert!(widths[widths.len() - 1] > 5.0 * widths[0], "{edges:?}");
+
+        let counts = totals(&sgram);
+        assert_eq!(counts.len(), widths.len() + 2);
+        assert!(counts[1..counts.len() - 1].iter().all(|&c| c >= 500), "{counts:?}");
+        assert_eq!(counts.iter().sum::<usize>(), before);
+        // LORs are looked up in the merged bins
+        assert_eq!(sgram.index(&lor_at_radius(0.0)), Some(1));
+        assert_eq!(sgram.counts(&lor_at_radius(0.0)).0, counts[1]);
+        assert!(sgram.describe(&lor_at_radius(0.0)).bin.is_some());
+    }
+
+    #[test]
+    fn cyclic_axes_are_kept_and_others_merged_to_meet_the_target_everywhere() {
+        // 4 phi bins by 10 z bins, with 30 events in each z bin of each phi bin
+        let axes = vec![AxisSpec::Phi { bins: 4 }, AxisSpec::Z { bins: 10, min: mm(-100.0), max: mm(100.0) }];
+        let config = ScattergramConfig::new(axes.clone(), false).unwrap();
+        let mut sgram = Scattergram::new(&|| config.lorogram()).with_adaptive_binning(AdaptiveBinning { min_count: 100 }, axes.clone());
+        for phi in [0.3_f32, 1.9, 3.5, 5.1] {
+            // Passing 10 mm from the axis, so that phi covers a whole turn
+            let (c, s) = (phi.cos(), phi.sin());
+            let (x0, y0) = (-10.0 * s, 10.0 * c);
+            for z in (0..10).map(|i| -90.0 + 20.0 * i as f32) {
+                let lor = mk_lor(((x0 - 300.0 * c, y0 - 300.0 * s, z), (x0 + 300.0 * c, y0 + 300.0 * s, z)));
+                for _ in 0..30 { sgram.fill(Prompt::Scatter, &lor) }
+            }
+        }
+        assert_eq!(sgram.adapt_binning(), Ok(0));
+        let adapted = sgram.adapted_axes().unwrap();
+        assert_eq!(adapted[0], AdaptedAxis::Uniform(axes[0]));
+        // Runs of 4 z bins (120 events) and a last run of 2 joined to the one before
+        assert_eq!(adapted[1].to_string(), "z:[-100,-20,100]");
+        assert!(totals(&sgram).iter().all(|&c| c == 0 || c >= 100));
+    }
+
+    #[test]
+    fn without_enough_events_bins_are_reported_short() {
+        let mut sgram = BuildScattergram::new().r_bins(5).r_max(mm(100.0))
+            .adaptive(AdaptiveBinning { min_count: 500 })
+            .build().unwrap();
+        for _ in 0..10 { sgram.fill(Prompt::True, &lor_at_radius(50.0)) }
+        assert_eq!(sgram.adapt_binning(), Ok(1));
+        assert_eq!(sgram.adapted_axes().unwrap()[0].to_string(), "r:[0,100]");
+    }
+
+    #[test]
+    fn scattergrams_without_adaptive_binning_are_untouched() {
+        let mut sgram = BuildScattergram::new().r_bins(5).r_max(mm(100.0)).build().unwrap();
+        assert_eq!(sgram.adapt_binning(), Ok(0));
+        assert_eq!(sgram.adapted_axes(), None);
+        assert_eq!(sgram.n_bins(), 7);
+    }
+
+    #[rstest(/**/ text              , expected,
+             case("min_count=500"   , Ok(AdaptiveBinning { min_count: 500 })),
+             case(" min_count = 20 ", Ok(AdaptiveBinning { min_count: 20 })),
+             case("min_count=0"     , Err(())),
+             case("min_count=many"  , Err(())),
+             case("500"             , Err(())),
+             case("max_bins=5"      , Err(())),
+    )]
+    fn parse(text: &str, expected: Result<AdaptiveBinning, ()>) {
+        assert_eq!(text.parse::<AdaptiveBinning>().map_err(|_| ()), expected);
+    }
+}
@@ -1,4 +1,32 @@
-use ndhistogram::axis::{Axis, BinInterval, Uniform};
+//! Axes for lorograms.
+//!
+//! All lorogram axes assign values to bins in the same way, so that counts can
+//! be reproduced exactly by other analyses:
+//!
+//! + Bins are half-open, `[low edge, high edge)`: a value exactly on an edge
+//!   between two bins belongs to the bin above it. The edges are those
+//!   reported by `Axis::bin`.
+//!
+//! + On `Uniform` axes, values below the lowest edge go to the underflow bin,
+//!   and values at or above the highest edge (including the highest edge
+//!   itself) to the overflow bin.
+//!
+//! + `Variable` axes follow the same rules as `Uniform` ones, with their own
+//!   edges.
+//!
+//! + On `Cyclic` axes, values are wrapped into `[low, high)`: the highest edge
+//!   is the same point as the lowest, and belongs to bin 0.
+//!
+//! + `NaN` belongs to the underflow bin of `Uniform` axes, as in `ndhistogram`,
+//!   and to no bin of `Cyclic` axes, which have no underflow bin. Infinities
+//!   also belong to no bin of `Cyclic` axes.
+//!
+//! `ndhistogram`'s own `Uniform::index` finds bins by division, which can put
+//! values exactly on an edge in the bin below it: `half_open_index` implements
+//! the convention, and `HalfOpen` exposes it for the axes wrapped by
+//! `MappedAxis`.
+
+use ndhistogram::axis::{Axis, BinInterval, Uniform, Variable};
 use std::fmt::Debug; // TODO Display
 
 use num_traits::{Float, Num, NumCast, NumOps};
@@ -44,6 +72,156 @@ pub struct Cyclic<T = f64> {
     axis: Uniform<T>,
 }
 
+/// Reasons for rejecting the parameters of an axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisError {
+    NoBins,
+    NonFiniteBounds,
+    ZeroWidth,
+    ReversedBounds,
+    NonPositiveStep,
+    UnsortedEdges,
+    Cyclic,
+}
+
+impl std::fmt::Display for AxisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            AxisError::NoBins          => "axis must have at least one bin",
+            AxisError::NonFiniteBounds => "axis bounds must be finite",
+            AxisError::ZeroWidth       => "axis low and high bounds must differ",
+            AxisError::ReversedBounds  => "axis low bound must be below its high bound",
+            AxisError::NonPositiveStep => "axis step size must be strictly positive",
+            AxisError::UnsortedEdges   => "axis edges must be strictly increasing",
+            AxisError::Cyclic          => "cyclic axes cannot have variable bins",
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for AxisError {}
+
+fn is_finite<T: NumCast>(x: T) -> bool { x.to_f64().map_or(false, f64::is_finite) }
+
+/// Check the parameters of an axis with `nbins` bins in `[low, high)`
+pub fn check_bounds<T: PartialOrd + NumCast + Copy>(nbins: usize, low: T, high: T) -> Result<(), AxisError> {
+    if nbins == 0                       { return Err(AxisError::NoBins) }
+    if !is_finite(low) || !is_finite(high) { return Err(AxisError::NonFiniteBounds) }
+    if low == high                      { return Err(AxisError::ZeroWidth) }
+    if low > high                       { return Err(AxisError::ReversedBounds) }
+    Ok(())
+}
+
+/// `Uniform::new`, with the parameters checked by `check_bounds` rather than
+/// causing a panic or a nonsensical axis
+pub fn try_uniform<T: Float>(nbins: usize, low: T, high: T) -> Result<Uniform<T>, AxisError> {
+    check_bounds(nbins, low, high)?;
+    Ok(Uniform::new(nbins, low, high))
+}
+
+/// `Variable::new`, with the `edges` checked to be finite and strictly
+/// increasing, rather than being sorted or causing a panic
+pub fn try_variable<T: Float>(edges: Vec<T>) -> Result<Variable<T>, AxisError> {
+    if edges.len() < 2 { return Err(AxisError::NoBins) }
+    if !edges.iter().all(|e| e.is_finite()) { return Err(AxisError::NonFiniteBounds) }
+    if edges.windows(2).any(|w| w[0] >= w[1]) { return Err(AxisError::UnsortedEdges) }
+    Ok(Variable::new(edges))
+}
+
+/// Index of the bin containing `x`, in an axis with `nbins` bins in `[low,
+/// high)` plus the underflow and overflow bins (indices `0` and `nbins + 1`),
+/// following the conventions in the module documentation. The edges are
+/// `low + i * step`, as in `Uniform::bin`, except the highest, which is `high`.
+pub fn half_open_index<T>(nbins: usize, low: T, high: T, x: T) -> Option<usize>
+where
+    T: PartialOrd + NumCast + NumOps + Copy,
+{
+    if x.partial_cmp(&x).is_none() { return Some(0) } // NaN
+    if x <  low  { return Some(0) }
+    if x >= high { return Some(nbins + 1) }
+    let step = (high - low) / T::from(nbins)?;
+    let edge = |i: usize| -> Option<T> { if i == nbins { Some(high) } else { Some(low + T::from(i)? * step) } };
+    // The quotient may be off by one through rounding: correct it against the edges
+    let mut i = ((x - low) / step).to_usize().unwrap_or(0).min(nbins - 1);
+    while i > 0             && x <  edge(i    )? { i -= 1 }
+    while i + 1 < nbins     && x >= edge(i + 1)? { i += 1 }
+    Some(i + 1)
+}
+
+/// Axes whose bins follow the conventions in the module documentation
+pub trait HalfOpen: Axis {
+    fn half_open_index(&self, x: &Self::Coordinate) -> Option<usize>;
+    /// Whether the bins wrap around, rather than being flanked by underflow
+    /// and overflow bins
+    fn is_cyclic(&self) -> bool { false }
+    /// Position of `x` in units of bins, with the centre of the bin at `index`
+    /// at `index`, for interpolation. Not wrapped around cyclic axes. `None`
+    /// if `x` cannot be placed.
+    fn bin_position(&self, x: &Self::Coordinate) -> Option<f32>;
+}
+
+impl<T> HalfOpen for Uniform<T>
+where
+    T: PartialOrd + NumCast + NumOps + Copy,
+    Uniform<T>: Axis<Coordinate = T>,
+{
+    fn half_open_index(&self, x: &T) -> Option<usize> {
+        half_open_index(self.num_bins() - 2, *self.low(), *self.high(), *x)
+    }
+    fn bin_position(&self, x: &T) -> Option<f32> {
+        let (x, low, high) = (x.to_f64()?, self.low().to_f64()?, self.high().to_f64()?);
+        if x.is_nan() { return None }
+        // The underflow bin precedes the first regular one
+        Some((0.5 + (self.num_bins() - 2) as f64 * (x - low) / (high - low)) as f32)
+    }
+}
+
+/// Edge `i` of `axis`, counting up from its lowest edge
+fn variable_edge<T: PartialOrd + Copy>(axis: &Variable<T>, i: usize) -> T {
+    if i == 0 { return *axis.low() }
+    match axis.bin(i) {
+        Some(BinInterval::Bin { end, .. }) => end,
+        _ => *axis.high(),
+    }
+}
+
+impl<T> HalfOpen for Variable<T>
+where
+    T: PartialOrd + NumCast + Copy,
+    Variable<T>: Axis<Coordinate = T, BinInterval = BinInterval<T>>,
+{
+    /// The number of edges at or below `x`: none for `NaN`
+    fn half_open_index(&self, x: &T) -> Option<usize> {
+        let (mut lo, mut hi) = (0, self.num_bins() - 1);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if variable_edge(self, mid) <= *x { lo = mid + 1 } else { hi = mid }
+        }
+        Some(lo)
+    }
+    /// Beyond the axis, the outermost bins are extended
+    fn bin_position(&self, x: &T) -> Option<f32> {
+        let nbins = self.num_bins() - 2;
+        let k = self.half_open_index(x)?.saturating_sub(1).min(nbins - 1);
+        let (x, low, high) = (x.to_f64()?, variable_edge(self, k).to_f64()?, variable_edge(self, k + 1).to_f64()?);
+        if x.is_nan() { return None }
+        Some((k as f64 + 0.5 + (x - low) / (high - low)) as f32)
+    }
+}
+
+impl<T> HalfOpen for Cyclic<T>
+where
+    T: PartialOrd + NumCast + NumOps + Copy,
+{
+    fn half_open_index(&self, x: &T) -> Option<usize> { self.index(x) }
+    fn is_cyclic(&self) -> bool { true }
+    fn bin_position(&self, x: &T) -> Option<f32> {
+        let (x, low, high) = (x.to_f64()?, self.low().to_f64()?, self.high().to_f64()?);
+        if !x.is_finite() { return None }
+        Some((self.num_bins() as f64 * (x - low) / (high - low) - 0.5) as f32)
+    }
+}
+
 impl<T> Cyclic<T>
 where
     T: PartialOrd + Num + NumCast + NumOps + Copy,
@@ -53,32 +231,39 @@ where
     /// Only implemented for [Float]. Use [Cyclic::with_step_size] for integers.
     ///
     /// # Panics
-    /// Panics if `nbins == 0` or `low == high`.
+    /// Panics if the parameters are rejected by [Cyclic::try_new].
     pub fn new(nbins: usize, low: T, high: T) -> Self
     where
         T: Float,
     {
-        Self {
-            axis: Uniform::new(nbins, low, high),
-        }
-        // if nbins == 0  { panic!("Need more than zero bins on axis") }
-        // if low == high { panic!("Axis range must be non-zero") }
-        // let (low, high) = if low < high { (low, high) } else { (high, low) };
-        // let step = (high - low) / T::from(nbins).unwrap();
-        // Self { nbins, low, high, step }
+        Self::try_new(nbins, low, high).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Create a wrap-around axis with `nbins` uniformly-spaced bins in the
+    /// range `[low, high)`, failing if there are no bins, or the bounds are
+    /// not finite, equal or reversed.
+    pub fn try_new(nbins: usize, low: T, high: T) -> Result<Self, AxisError>
+    where
+        T: Float,
+    {
+        Ok(Self { axis: try_uniform(nbins, low, high)? })
     }
 
     /// Create a wrap-around axis with `nbins` uniformly-spaced bins in the range `[low, low+num*step)`.
     /// # Panics
-    /// Panics if `nbins == 0` or `step <= 0`.
+    /// Panics if the parameters are rejected by [Cyclic::try_with_step_size].
     pub fn with_step_size(nbins: usize, low: T, step: T) -> Self {
-        Self {
-            axis: Uniform::with_step_size(nbins, low, step),
-        }
-        // let high = T::from(nbins).expect("Failed to convert nbins to coordinate type") * step + low;
-        // if nbins == 0        { panic!("Need more than zero bins on axis") }
-        // if step <= T::zero() { panic!("Step size must be strictly positive") }
-        // Self { nbins, low, high, step }
+        Self::try_with_step_size(nbins, low, step).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Create a wrap-around axis with `nbins` uniformly-spaced bins in the
+    /// range `[low, low+num*step)`, failing if there are no bins, `low` or
+    /// `step` are not finite, or `step` is not positive.
+    pub fn try_with_step_size(nbins: usize, low: T, step: T) -> Result<Self, AxisError> {
+        if nbins == 0                          { return Err(AxisError::NoBins) }
+        if !is_finite(low) || !is_finite(step) { return Err(AxisError::NonFiniteBounds) }
+        if step <= T::zero()                   { return Err(AxisError::NonPositiveStep) }
+        Ok(Self { axis: Uniform::with_step_size(nbins, low, step) })
     }
 }
 
@@ -100,6 +285,7 @@ impl<T: PartialOrd + NumCast + NumOps + Copy> Axis for Cyclic<T> {
 
     // TODO optimize by using division instead of looping?
     fn index(&self, coordinate: &Self::Coordinate) -> Option<usize> {
+        if !is_finite(*coordinate) { return None }
         let (mut x, hi, lo) = (*coordinate, *self.axis.high(), *self.axis.low());
         let range = hi - lo;
         while x >= hi {
@@ -108,7 +294,9 @@ impl<T: PartialOrd + NumCast + NumOps + Copy> Axis for Cyclic<T> {
         while x < lo {
             x = x + range
         }
-        self.axis.index(&x).map(|n| n - 1)
+        // Wrapping up from just below `lo` may round to `hi`, which is `lo`
+        if x >= hi { return Some(0) }
+        half_open_index(self.num_bins(), lo, hi, x).map(|n| n - 1)
     }
 
     fn num_bins(&self) -> usize {
@@ -168,6 +356,99 @@ mod test {
     }
 }
 
+#[cfg(test)]
+mod test_edges {
+    use super::*;
+    use ndhistogram::{ndhistogram, Histogram};
+    use rstest::rstest;
+
+    /// Nearest `f32` above `x`
+    fn up(x: f32) -> f32 {
+        if      x == 0.0 { f32::from_bits(1) }
+        else if x >  0.0 { f32::from_bits(x.to_bits() + 1) }
+        else             { f32::from_bits(x.to_bits() - 1) }
+    }
+
+    /// Nearest `f32` below `x`
+    fn down(x: f32) -> f32 { -up(-x) }
+
+    /// Low edges of the bins of `axis`, as reported by `Axis::bin`, and its
+    /// high edge
+    fn edges<A: Axis<BinInterval = BinInterval<f32>>>(axis: &A, indices: std::ops::Range<usize>) -> Vec<f32> {
+        let mut edges = indices.clone().map(|i| match axis.bin(i) {
+            Some(BinInterval::Bin { start, .. }) => start,
+            other => panic!("No bin {i}: {other:?}"),
+        }).collect::<Vec<_>>();
+        edges.push(match axis.bin(indices.end - 1) { Some(BinInterval::Bin { end, .. }) => end, _ => unreachable!() });
+        edges
+    }
+
+    // Bounds and bin counts whose edges are not exactly representable
+    #[rstest]
+    #[case( 3,  0.0,  1.0)]
+    #[case(10,  0.0,  1.0)]
+    #[case(10, -0.3,  0.7)]
+    #[case( 7,  0.0, std::f32::consts::TAU)]
+    #[case(20,  0.0, 1000.0)]
+    fn uniform_edges_belong_to_the_bin_above(#[case] n: usize, #[case] low: f32, #[case] high: f32) {
+        let axis = Uniform::new(n, low, high);
+        let edges = edges(&axis, 1..n + 1);
+        for (i, edge) in edges[..n].iter().enumerate() {
+            assert_eq!(axis.half_open_index(edge), Some(i + 1), "edge {i} at {edge}");
+        }
+        assert_eq!(axis.half_open_index(&high), Some(n + 1));
+        assert_eq!(axis.half_open_index(&f32::NAN), Some(0));
+        // Just inside the extreme bins
+        assert_eq!(axis.half_open_index(&up(low)), Some(1));
+        assert_eq!(axis.half_open_index(&down(high)), Some(n));
+        assert_eq!(axis.half_open_index(&down(low)), Some(0));
+    }
+
+    #[rstest]
+    #[case( 3,  0.0,  1.0)]
+    #[case(10,  0.0,  1.0)]
+    #[case(10, -0.3,  0.7)]
+    #[case( 7,  0.0, std::f32::consts::TAU)]
+    #[case( 4,  0.0, 360.0)]
+    fn cyclic_edges_belong_to_the_bin_above(#[case] n: usize, #[case] low: f32, #[case] high: f32) {
+        let axis = Cyclic::new(n, low, high);
+        let edges = edges(&axis, 0..n);
+        for (i, edge) in edges[..n].iter().enumerate() {
+            assert_eq!(axis.index(edge), Some(i), "edge {i} at {edge}");
+        }
+        // The top edge wraps around to the bottom one
+        assert_eq!(axis.index(&high), Some(0));
+        assert_eq!(axis.index(&down(low)), Some(n - 1));
+        for x in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] { assert_eq!(axis.index(&x), None) }
+    }
+
+    #[test]
+    fn cyclic_wrap_rounding_onto_the_top_edge_is_bin_0() {
+        // -1e-9 + TAU rounds to TAU in f32
+        let axis = Cyclic::new(8, 0.0, std::f32::consts::TAU);
+        assert_eq!(axis.index(&-1e-9), Some(0));
+    }
+
+    #[test]
+    fn no_fills_are_lost_at_edges() {
+        let (n, low, high) = (10, -0.3_f32, 0.7_f32);
+        let step = (high - low) / n as f32;
+        // Every edge, and the values either side of it
+        let values = (0..n).map(|i| low + i as f32 * step).chain([high])
+            .flat_map(|x| [down(x), x, up(x)])
+            .collect::<Vec<_>>();
+        let mut cyclic = ndhistogram!(Cyclic::new(n, low, high); usize);
+        for x in &values { cyclic.fill(x) }
+        assert_eq!(cyclic.values().sum::<usize>(), values.len());
+        let axis = Uniform::new(n, low, high);
+        let mut counts = vec![0; n + 2];
+        for x in &values { counts[axis.half_open_index(x).unwrap()] += 1 }
+        assert_eq!(counts.iter().sum::<usize>(), values.len());
+        // The top edge and the value above it overflow
+        assert_eq!(counts[n + 1], 2);
+    }
+}
+
 #[cfg(test)]
 mod test_histogram {
     use super::*;
@@ -206,3 +487,94 @@ mod test_histogram {
         assert_eq!(hist.value(&(45.0 - 360.0)), Some(&1));
     }
 }
+
+#[cfg(test)]
+mod test_try_constructors {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(/**/ nbins,  low         ,  high        , expected,
+             case(0    ,  0.0         ,  1.0         , AxisError::NoBins),
+             case(4    ,  f32::NAN    ,  1.0         , AxisError::NonFiniteBounds),
+             case(4    ,  0.0         ,  f32::INFINITY, AxisError::NonFiniteBounds),
+             case(4    ,  1.0         ,  1.0         , AxisError::ZeroWidth),
+             case(4    ,  2.0         ,  1.0         , AxisError::ReversedBounds),
+    )]
+    fn invalid_bounds(nbins: usize, low: f32, high: f32, expected: AxisError) {
+        assert_eq!(Cyclic::try_new(nbins, low, high), Err(expected));
+        assert_eq!(try_uniform(nbins, low, high), Err(expected));
+    }
+
+    #[rstest(/**/ nbins,  low       ,  step      , expected,
+             case(0    ,  0.0       ,  1.0       , AxisError::NoBins),
+             case(4    ,  f32::NAN  ,  1.0       , AxisError::NonFiniteBounds),
+             case(4    ,  0.0       ,  f32::NAN  , AxisError::NonFiniteBounds),
+             case(4    ,  0.0       ,  0.0       , AxisError::NonPositiveStep),
+             case(4    ,  0.0       , -1.0       , AxisError::NonPositiveStep),
+    )]
+    fn invalid_steps(nbins: usize, low: f32, step: f32, expected: AxisError) {
+        assert_eq!(Cyclic::try_with_step_size(nbins, low, step), Err(expected));
+    }
+
+    #[test]
+    fn invalid_integer_step() {
+        assert_eq!(Cyclic::try_with_step_size(24, 0, -1), Err(AxisError::NonPositiveStep));
+    }
+
+    #[test]
+    fn valid_parameters_match_panicking_constructors() {
+        assert_eq!(Cyclic::try_new(10, -1.5, 2.5), Ok(Cyclic::new(10, -1.5, 2.5)));
+        assert_eq!(Cyclic::try_with_step_size(24, 0, 1), Ok(Cyclic::with_step_size(24, 0, 1)));
+        assert_eq!(try_uniform(7, 0.0, 3.0), Ok(Uniform::new(7, 0.0, 3.0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one bin")]
+    fn panicking_constructor_delegates() {
+        Cyclic::new(0, 0.0, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod test_variable {
+    use super::*;
+    use rstest::rstest;
+
+    fn axis() -> Variable<f32> { try_variable(vec![0.0, 1.0, 3.0, 7.0]).unwrap() }
+
+    #[rstest(/**/ x            , expected,
+             case(-0.5         , 0),
+             case( 0.0         , 1),   // lowest edge
+             case( 0.99        , 1),
+             case( 1.0         , 2),   // edge between bins belongs to the one above
+             case( 6.99        , 3),
+             case( 7.0         , 4),   // highest edge is overflow
+             case( f32::NAN    , 0),
+             case( f32::INFINITY, 4),
+    )]
+    fn half_open(x: f32, expected: usize) {
+        assert_eq!(axis().half_open_index(&x), Some(expected));
+    }
+
+    #[rstest(/**/ x   , expected,
+             case(0.5 , 1.0),   // centres of the bins are at their indices
+             case(2.0 , 2.0),
+             case(5.0 , 3.0),
+             case(3.0 , 2.5),
+             case(-0.5, 0.0),   // the first bin extended below the axis
+             case(9.0 , 4.0),   // and the last above it
+    )]
+    fn positions(x: f32, expected: f32) {
+        assert_eq!(axis().bin_position(&x), Some(expected));
+    }
+
+    #[rstest(/**/ edges                , expected,
+             case(vec![1.0]           , AxisError::NoBins),
+             case(vec![0.0, f32::NAN] , AxisError::NonFiniteBounds),
+             case(vec![0.0, 2.0, 1.0] , AxisError::UnsortedEdges),
+             case(vec![0.0, 1.0, 1.0] , AxisError::UnsortedEdges),
+    )]
+    fn invalid_edges_are_rejected(edges: Vec<f32>, expected: AxisError) {
+        assert_eq!(try_variable(edges).unwrap_err(), expected);
+    }
+}
@@ -1,19 +1,24 @@
-use crate::{Length, Time};
-use crate::lorogram::{Scattergram, axis_r, axis_phi, axis_z, axis_dz, axis_t};
+use crate::{Energyf32, Length, Ratio, Time};
+use crate::constants::ELECTRON_REST_ENERGY;
+use crate::lorogram::{AdaptiveBinning, AxisSpec, Scattergram, axis_r, axis_phi, axis_z, axis_dz, axis_t};
 use ndhistogram::ndhistogram;
-use geometry::units::{mm, ps};
+use geometry::units::{mm, ps, ratio};
 
 
+#[derive(Clone)]
 pub struct BuildScattergram {
     phi_bins: Option<usize>,
     r_bins  : Option<usize>, r_max   : Option<Length>,
     z_bins  : Option<usize>, z_length: Option<Length>,
     dz_bins : Option<usize>, dz_max  : Option<Length>,
     dt_bins : Option<usize>, dt_max  : Option<Time>,
+    true_threshold: Energyf32,
+    empty_bin_value: Ratio,
+    interpolate: bool,
+    adaptive: Option<AdaptiveBinning>,
 //
 // NOTE: Fine-grained bins seem to give bad reconstructed images: perhaps too
-// low statistics. If this is the case, then interpolation in Scattergram::value
-// could be important.
+// low statistics. If this is the case, then `interpolate` may help.
 }
 
 const DEFAULT_NUMBER_OF_BINS: usize = 30;
@@ -34,6 +39,10 @@ impl BuildScattergram {
             z_bins  : None, z_length: None,
             dz_bins : None, dz_max  : None,
             dt_bins : None, dt_max  : None,
+            true_threshold: ELECTRON_REST_ENERGY,
+            empty_bin_value: ratio(1.0),
+            interpolate: false,
+            adaptive: None,
         }
     }
 
@@ -87,7 +96,63 @@ impl BuildScattergram {
         self
     }
 
+    /// Gamma energy (keV) below which a coincidence counts as a scatter
+    pub fn true_threshold(mut self, threshold: Energyf32) -> Self {
+        self.true_threshold = threshold;
+        self
+    }
+
+    /// Correction of LORs in bins without trues (see `Scattergram::value`)
+    pub fn empty_bin_value(mut self, value: Ratio) -> Self {
+        self.empty_bin_value = value;
+        self
+    }
+
+    /// Interpolate counts between bin centres (see `Scattergram::with_interpolation`)
+    pub fn interpolate(mut self, interpolate: bool) -> Self {
+        self.interpolate = interpolate;
+        self
+    }
+
+    /// Merge bins once filled, to reach `binning.min_count` (see
+    /// `Scattergram::adapt_binning`). The numbers of bins given for each axis
+    /// become the maximum.
+    pub fn adaptive(mut self, binning: AdaptiveBinning) -> Self {
+        self.adaptive = Some(binning);
+        self
+    }
+
+    pub fn is_adaptive(&self) -> bool { self.adaptive.is_some() }
+
+    /// Specifications of the axes which `build` gives the scattergram, in order
+    pub fn axis_specs(&self) -> Vec<AxisSpec> {
+        let phi = self.phi_bins.map(|bins| AxisSpec::Phi { bins });
+        let r   = self.  r_bins.map(|bins| AxisSpec::R  { bins, max: self.r_max.unwrap() });
+        let z   = self.  z_bins.map(|bins| {
+            let half = self.z_length.unwrap() / 2.0;
+            AxisSpec::Z { bins, min: -half, max: half }
+        });
+        let dz  = self. dz_bins.map(|bins| AxisSpec::Dz { bins, max: self.dz_max.unwrap() });
+        let dt  = self. dt_bins.map(|bins| AxisSpec::T  { bins, max: self.dt_max.unwrap() });
+        [dt, r, z, phi, dz].into_iter().flatten().collect()
+    }
+
     pub fn build(self) -> Option<Scattergram> {
+        let (threshold, empty, interpolate) = (self.true_threshold, self.empty_bin_value, self.interpolate);
+        let adaptive = self.adaptive.map(|binning| (binning, self.axis_specs()));
+        self.build_axes().map(|sgram| {
+            let sgram = sgram
+                .with_true_threshold(threshold)
+                .with_empty_bin_value(empty)
+                .with_interpolation(interpolate);
+            match adaptive {
+                Some((binning, axes)) => sgram.with_adaptive_binning(binning, axes),
+                None => sgram,
+            }
+        })
+    }
+
+    fn build_axes(self) -> Option<Scattergram> {
         let phi = self.phi_bins;
         let r   = self.  r_bins.map(|n_bins| (n_bins, self. r_max  .unwrap()));
         let z   = self.  z_bins.map(|n_bins| (n_bins, self.z_length.unwrap()));
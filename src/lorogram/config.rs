@@ -0,0 +1,335 @@
+//! Textual specification of Scattergram axes.
+//!
+//! A configuration is a comma-separated list of axes, each written as
+//! `kind:bins[:parameters]`:
+//!
+//! | kind  | parameters  | units |
+//! |-------|-------------|-------|
+//! | `phi` |             |       |
+//! | `r`   | `max`       | mm    |
+//! | `z`   | `min:max`   | mm    |
+//! | `dz`  | `max`       | mm    |
+//! | `t`   | `max`       | ps    |
+//!
+//! For example `"r:20:30,phi:15,z:10:-100:100"`.
+//!
+//! Each kind of axis may appear at most once: a repeated kind almost always
+//! indicates a copy-paste error, and produces a degenerate scattergram. Pairs
+//! of different kinds which carry largely the same information are listed in
+//! [`REDUNDANT_AXES`], and provoke a warning rather than an error.
+
+use std::fmt;
+use std::str::FromStr;
+
+use ndhistogram::axis::{Axis, BinInterval};
+use ndhistogram::ndhistogram;
+
+use crate::{Length, Lengthf32, Time};
+use crate::system_matrix::LOR;
+use crate::lorogram::{Lorogram, LorAxU, LorAxC, LorAxV, Scattergram, try_axis_r, try_axis_phi, try_axis_z, try_axis_dz, try_axis_t};
+use crate::lorogram::axis::AxisError;
+use geometry::units::{mm, mm_, ps, ps_};
+
+/// The quantity binned by a Scattergram axis, irrespective of its binning
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisKind { Phi, R, Z, Dz, T }
+
+impl fmt::Display for AxisKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AxisKind::Phi => "phi",
+            AxisKind::R   => "r",
+            AxisKind::Z   => "z",
+            AxisKind::Dz  => "dz",
+            AxisKind::T   => "t",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Advisory list of pairs of axis kinds which are highly redundant when used
+/// together, with the reason.
+pub const REDUNDANT_AXES: &[(AxisKind, AxisKind, &str)] = &[
+    (AxisKind::Z, AxisKind::T, "axis_t currently bins the axial midpoint of the LOR, just like axis_z"),
+];
+
+/// Check that no kind of axis appears more than once in `kinds`, unless
+/// `allow_duplicates`. Returns warnings about any pairs in `REDUNDANT_AXES`.
+pub fn check_axis_kinds(kinds: &[AxisKind], allow_duplicates: bool) -> Result<Vec<String>, String> {
+    let mut warnings = vec![];
+    for (i, a) in kinds.iter().enumerate() {
+        for (j, b) in kinds.iter().enumerate().skip(i + 1) {
+            if a == b && !allow_duplicates {
+                return Err(format!("Scattergram axis kind '{a}' appears more than once: at positions {} and {}", i + 1, j + 1))
+            }
+            for (x, y, reason) in REDUNDANT_AXES {
+                if (a, b) == (x, y) || (a, b) == (y, x) {
+                    warnings.push(format!("Scattergram axes '{a}' (position {}) and '{b}' (position {}) are redundant: {reason}", i + 1, j + 1));
+                }
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// Specification of a single Scattergram axis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AxisSpec {
+    Phi { bins: usize },
+    R   { bins: usize, max: Length },
+    Z   { bins: usize, min: Length, max: Length },
+    Dz  { bins: usize, max: Length },
+    T   { bins: usize, max: Time },
+}
+
+impl AxisSpec {
+    pub fn kind(&self) -> AxisKind {
+        match self {
+            AxisSpec::Phi { .. } => AxisKind::Phi,
+            AxisSpec::R   { .. } => AxisKind::R,
+            AxisSpec::Z   { .. } => AxisKind::Z,
+            AxisSpec::Dz  { .. } => AxisKind::Dz,
+            AxisSpec::T   { .. } => AxisKind::T,
+        }
+    }
+
+    pub fn axis(&self) -> LorAxis { self.try_axis().unwrap_or_else(|e| panic!("{e} in axis '{self}'")) }
+
+    pub fn try_axis(&self) -> Result<LorAxis, AxisError> {
+        Ok(match *self {
+            AxisSpec::Phi { bins           } => LorAxis::C(try_axis_phi(bins)?),
+            AxisSpec::R   { bins, max      } => LorAxis::U(try_axis_r  (bins, max)?),
+            AxisSpec::Z   { bins, min, max } => LorAxis::U(try_axis_z  (bins, min, max)?),
+            AxisSpec::Dz  { bins, max      } => LorAxis::U(try_axis_dz (bins, max)?),
+            AxisSpec::T   { bins, max      } => LorAxis::U(try_axis_t  (bins, max)?),
+        })
+    }
+}
+
+impl fmt::Display for AxisSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AxisSpec::Phi { bins           } => write!(f, "phi:{bins}"),
+            AxisSpec::R   { bins, max      } => write!(f, "r:{bins}:{}", mm_(max)),
+            AxisSpec::Z   { bins, min, max } => write!(f, "z:{bins}:{}:{}", mm_(min), mm_(max)),
+            AxisSpec::Dz  { bins, max      } => write!(f, "dz:{bins}:{}", mm_(max)),
+            AxisSpec::T   { bins, max      } => write!(f, "t:{bins}:{}", ps_(max)),
+        }
+    }
+}
+
+impl FromStr for AxisSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.trim().split(':').collect::<Vec<_>>();
+        let kind = fields[0];
+        let number = |n: usize| -> Result<f32, String> {
+            fields.get(n)
+                .ok_or_else(|| format!("Missing parameter {n} in axis '{s}'"))?
+                .parse::<f32>()
+                .map_err(|e| format!("{e} in axis '{s}'"))
+        };
+        let bins = fields.get(1)
+            .ok_or_else(|| format!("Missing number of bins in axis '{s}'"))?
+            .parse::<usize>()
+            .map_err(|e| format!("{e} in axis '{s}'"))?;
+        let expected_fields = match kind { "phi" => 2, "r" | "dz" | "t" => 3, "z" => 4, _ => 0 };
+        if expected_fields == 0 { return Err(format!("Unknown axis kind '{kind}' in '{s}'")) }
+        if fields.len() != expected_fields {
+            return Err(format!("Axis '{s}' should have {} parameters", expected_fields - 1))
+        }
+        let spec = match kind {
+            "phi" => AxisSpec::Phi { bins },
+            "r"   => AxisSpec::R   { bins, max: mm(number(2)?) },
+            "z"   => AxisSpec::Z   { bins, min: mm(number(2)?), max: mm(number(3)?) },
+            "dz"  => AxisSpec::Dz  { bins, max: mm(number(2)?) },
+            "t"   => AxisSpec::T   { bins, max: ps(number(2)?) },
+            _     => unreachable!(),
+        };
+        spec.try_axis().map_err(|e| format!("{e} in axis '{s}'"))?;
+        Ok(spec)
+    }
+}
+
+/// The axes with which a Scattergram should be built
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScattergramConfig {
+    pub axes: Vec<AxisSpec>,
+    /// Permit the same kind of axis to appear more than once
+    pub allow_duplicate_axes: bool,
+}
+
+impl ScattergramConfig {
+    /// The maximum number of axes supported by `Lorogram`
+    pub const MAX_AXES: usize = 5;
+
+    pub fn new(axes: Vec<AxisSpec>, allow_duplicate_axes: bool) -> Result<Self, String> {
+        if axes.len() > Self::MAX_AXES {
+            return Err(format!("At most {} axes are supported, got {}", Self::MAX_AXES, axes.len()))
+        }
+        for axis in &axes {
+            axis.try_axis().map_err(|e| format!("{e} in axis '{axis}'"))?;
+        }
+        check_axis_kinds(&Self::kinds(&axes), allow_duplicate_axes)?;
+        Ok(Self { axes, allow_duplicate_axes })
+    }
+
+    fn kinds(axes: &[AxisSpec]) -> Vec<AxisKind> { axes.iter().map(AxisSpec::kind).collect() }
+
+    /// An empty lorogram with the configured axes
+    pub fn lorogram(&self) -> Box<dyn Lorogram> {
+        lorogram_with_axes(self.axes.iter().map(AxisSpec::axis).collect())
+    }
+
+    /// `None` if no axes were specified
+    pub fn build(&self) -> Result<Option<Scattergram>, String> {
+        if self.axes.is_empty() { return Ok(None) }
+        let kinds = Self::kinds(&self.axes);
+        Scattergram::with_axes(&kinds, self.allow_duplicate_axes, &|| self.lorogram()).map(Some)
+    }
+}
+
+/// An empty lorogram with `axes`, of which there must be between 1 and
+/// `ScattergramConfig::MAX_AXES`
+pub fn lorogram_with_axes(axes: Vec<LorAxis>) -> Box<dyn Lorogram> {
+    let n = axes.len();
+    let mut a = axes.into_iter();
+    let mut next = || a.next().unwrap();
+    match n {
+        1 => Box::new(ndhistogram!(next();                                 usize)),
+        2 => Box::new(ndhistogram!(next(), next();                         usize)),
+        3 => Box::new(ndhistogram!(next(), next(), next();                 usize)),
+        4 => Box::new(ndhistogram!(next(), next(), next(), next();         usize)),
+        5 => Box::new(ndhistogram!(next(), next(), next(), next(), next(); usize)),
+        n => panic!("Scattergram must have between 1 and {} axes, got {n}", ScattergramConfig::MAX_AXES),
+    }
+}
+
+impl fmt::Display for ScattergramConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let axes = self.axes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        write!(f, "{}", axes.join(","))
+    }
+}
+
+impl FromStr for ScattergramConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let axes = s.split(',')
+            .filter(|a| !a.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<AxisSpec>, _>>()?;
+        Self::new(axes, false)
+    }
+}
+
+// --------------------------------------------------------------------------------
+/// Any kind of LOR axis, so that axes chosen at runtime can be combined in a
+/// single histogram type.
+pub enum LorAxis {
+    U(LorAxU),
+    C(LorAxC),
+    V(LorAxV),
+}
+
+impl Axis for LorAxis {
+    type Coordinate = LOR;
+    type BinInterval = BinInterval<Lengthf32>;
+
+    fn index(&self, coordinate: &Self::Coordinate) -> Option<usize> {
+        match self {
+            LorAxis::U(a) => a.index(coordinate),
+            LorAxis::C(a) => a.index(coordinate),
+            LorAxis::V(a) => a.index(coordinate),
+        }
+    }
+
+    fn num_bins(&self) -> usize {
+        match self {
+            LorAxis::U(a) => a.num_bins(),
+            LorAxis::C(a) => a.num_bins(),
+            LorAxis::V(a) => a.num_bins(),
+        }
+    }
+
+    fn bin(&self, index: usize) -> Option<Self::BinInterval> {
+        match self {
+            LorAxis::U(a) => a.bin(index),
+            LorAxis::C(a) => a.bin(index),
+            LorAxis::V(a) => a.bin(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(/**/ spec,
+             case("phi:10"),
+             case("r:20:30"),
+             case("z:10:-100:100"),
+             case("dz:10:1000"),
+             case("t:10:1700"),
+             case("r:20:30,phi:15,z:10:-100:100"),
+    )]
+    fn roundtrip(spec: &str) {
+        let config: ScattergramConfig = spec.parse().unwrap();
+        assert_eq!(config.to_string(), spec);
+        assert!(config.build().unwrap().is_some());
+    }
+
+    #[rstest(/**/ spec,
+             case("x:10"),
+             case("r:10"),
+             case("phi:0"),
+             case("z:10:-100"),
+             case("r:ten:30"),
+             case("phi:1,phi:1,phi:1,phi:1,phi:1,phi:1"),
+             case("phi:1,r:2:3,phi:4"),
+    )]
+    fn rejects(spec: &str) {
+        assert!(spec.parse::<ScattergramConfig>().is_err());
+    }
+
+    #[rstest(/**/ spec          , expected,
+             case("phi:0"       , AxisError::NoBins),
+             case("r:10:0"      , AxisError::ZeroWidth),
+             case("r:10:-5"     , AxisError::ReversedBounds),
+             case("z:10:100:-100", AxisError::ReversedBounds),
+             case("z:10:NaN:100", AxisError::NonFiniteBounds),
+             case("dz:10:inf"   , AxisError::NonFiniteBounds),
+             case("t:10:0"      , AxisError::ZeroWidth),
+    )]
+    fn invalid_binning_is_reported(spec: &str, expected: AxisError) {
+        let error = spec.parse::<ScattergramConfig>().unwrap_err();
+        assert_eq!(error, format!("{expected} in axis '{spec}'"));
+    }
+
+    #[test]
+    fn duplicate_axes_are_named_with_positions() {
+        let error = "z:10:-100:100,z:20:-50:50".parse::<ScattergramConfig>().unwrap_err();
+        assert_eq!(error, "Scattergram axis kind 'z' appears more than once: at positions 1 and 2");
+    }
+
+    #[test]
+    fn duplicate_axes_can_be_allowed() {
+        let axes = vec!["z:10:-100:100".parse().unwrap(), "z:20:-50:50".parse().unwrap()];
+        assert!(ScattergramConfig::new(axes.clone(), false).is_err());
+        let config = ScattergramConfig::new(axes, true).unwrap();
+        assert!(config.build().unwrap().is_some());
+    }
+
+    #[test]
+    fn redundant_axes_warn_but_construct() {
+        use AxisKind::*;
+        let warnings = check_axis_kinds(&[R, Z, Phi, T], false).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'z' (position 2) and 't' (position 4)"), "{}", warnings[0]);
+        let config: ScattergramConfig = "r:2:3,z:10:-100:100,phi:4,t:10:1700".parse().unwrap();
+        assert!(config.build().unwrap().is_some());
+    }
+}
@@ -0,0 +1,139 @@
+//! Compare Scattergram configurations by how well they predict whether unseen
+//! LORs are trues or scatters.
+//!
+//! The classified LORs are split randomly into training and test halves. The
+//! Scattergram is filled with the training half, and each LOR in the test half
+//! is scored by the negative log-likelihood of its actual classification, under
+//! the Bernoulli model whose scatter probability is the scatter fraction of the
+//! bin into which the LOR falls. A small prior keeps empty bins finite. Lower
+//! scores are better.
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::system_matrix::LOR;
+use crate::lorogram::{Prompt, ScattergramConfig};
+
+/// Pseudo-counts added to both trues and scatters in every bin
+pub const PRIOR: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Score {
+    /// Mean negative log-likelihood per test LOR (nats)
+    pub nll: f64,
+    pub n_train: usize,
+    pub n_test: usize,
+}
+
+pub fn evaluate_scattergram_config(config: &ScattergramConfig, lors: &[(Prompt, LOR)], seed: u64) -> Score {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (mut train, mut test) = (vec![], vec![]);
+    for pair in lors {
+        if rng.gen::<bool>() { train.push(pair) } else { test.push(pair) }
+    }
+
+    let mut sgram = config.build()
+        .unwrap_or_else(|e| panic!("{e}"))
+        .expect("Scattergram configuration has no axes");
+    for &(prompt, lor) in &train {
+        sgram.fill(*prompt, lor);
+    }
+
+    let mut total = 0.0;
+    for &(prompt, lor) in &test {
+        let (trues, scatters) = sgram.counts(lor);
+        let p_scatter = (scatters as f64 + PRIOR) / ((trues + scatters) as f64 + 2.0 * PRIOR);
+        let p = match prompt {
+            Prompt::Scatter => p_scatter,
+            _               => 1.0 - p_scatter,
+        };
+        total -= p.ln();
+    }
+    let n_test = test.len();
+    Score { nll: total / n_test.max(1) as f64, n_train: train.len(), n_test }
+}
+
+/// Score each configuration and return them sorted from best to worst
+pub fn rank_scattergram_configs(configs: &[ScattergramConfig], lors: &[(Prompt, LOR)], seed: u64) -> Vec<(ScattergramConfig, Score)> {
+    let mut ranked: Vec<_> = configs.iter()
+        .map(|config| (config.clone(), evaluate_scattergram_config(config, lors, seed)))
+        .collect();
+    ranked.sort_by(|(_, a), (_, b)| a.nll.total_cmp(&b.nll));
+    ranked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Point;
+    use geometry::units::{mm, ns};
+    use std::f32::consts::TAU;
+
+    /// LORs whose scatter probability is 0.1 inside r = 50 mm and 0.6 outside,
+    /// independent of all other LOR properties
+    fn r_dependent_sample(n: usize) -> Vec<(Prompt, LOR)> {
+        let mut rng = StdRng::seed_from_u64(1234);
+        (0..n).map(|_| {
+            let r   = rng.gen_range(0.0..100.0_f32);
+            let phi = rng.gen_range(0.0..TAU);
+            let z   = rng.gen_range(-100.0..100.0_f32);
+            let dz  = rng.gen_range(-300.0..300.0_f32);
+            let (c, s) = (phi.cos(), phi.sin());
+            // Point of closest approach to z-axis, and direction perpendicular to it
+            let (x0, y0) = (r * c, r * s);
+            let (dx, dy) = (-s * 400.0, c * 400.0);
+            let p1 = Point::new(mm(x0 + dx), mm(y0 + dy), mm(z + dz));
+            let p2 = Point::new(mm(x0 - dx), mm(y0 - dy), mm(z - dz));
+            let p_scatter = if r < 50.0 { 0.1 } else { 0.6 };
+            let prompt = if rng.gen_bool(p_scatter) { Prompt::Scatter } else { Prompt::True };
+            (prompt, LOR::new(ns(0.0), ns(0.0), p1, p2))
+        }).collect()
+    }
+
+    fn score(spec: &str, lors: &[(Prompt, LOR)]) -> f64 {
+        evaluate_scattergram_config(&spec.parse().unwrap(), lors, 42).nll
+    }
+
+    #[test]
+    fn axes_carrying_the_dependence_score_better() {
+        let lors = r_dependent_sample(20_000);
+        let with_r    = score("r:2:100"             , &lors);
+        let with_r_z  = score("r:2:100,z:4:-100:100", &lors);
+        let with_phi  = score("phi:8"               , &lors);
+        let with_z    = score("z:10:-100:100"       , &lors);
+        let with_dz   = score("dz:10:600"           , &lors);
+        for without_r in [with_phi, with_z, with_dz] {
+            assert!(with_r   < without_r, "{with_r} !< {without_r}");
+            assert!(with_r_z < without_r, "{with_r_z} !< {without_r}");
+        }
+    }
+
+    /// Entropy (nats) of a Bernoulli variable with probability `p`
+    fn entropy(p: f64) -> f64 { -p * p.ln() - (1.0 - p) * (1.0 - p).ln() }
+
+    #[test]
+    fn finer_r_binning_stops_helping() {
+        let lors = r_dependent_sample(20_000);
+        // The best achievable score: the entropy of the true scatter model
+        let ideal = 0.5 * (entropy(0.1) + entropy(0.6));
+        let none = score("phi:1", &lors);
+        assert!(none > ideal + 0.1, "none {none} vs ideal {ideal}");
+        // All these binnings resolve the step at r = 50 mm
+        let [coarse, finer @ ..] = ["r:2:100", "r:4:100", "r:10:100", "r:50:100"].map(|spec| score(spec, &lors));
+        for s in [coarse].iter().chain(&finer) {
+            assert!((s - ideal).abs() < 0.02, "{s} vs ideal {ideal}");
+        }
+        // Bins finer than the dependence only add statistical noise
+        for fine in finer {
+            assert!(fine > coarse - 0.002, "fine {fine} vs coarse {coarse}");
+        }
+    }
+
+    #[test]
+    fn ranking_is_sorted() {
+        let lors = r_dependent_sample(2_000);
+        let configs: [ScattergramConfig; 3] = ["phi:4", "r:2:100", "z:5:-100:100"].map(|s| s.parse().unwrap());
+        let ranked = rank_scattergram_configs(&configs, &lors, 7);
+        assert_eq!(ranked[0].0.to_string(), "r:2:100");
+        assert!(ranked.windows(2).all(|w| w[0].1.nll <= w[1].1.nll));
+    }
+}
@@ -0,0 +1,128 @@
+//! Linear interpolation between the centres of lorogram bins, to avoid the
+//! staircase which the counts of single bins make of corrections varying
+//! smoothly with the LOR coordinates.
+//!
+//! Positions along an axis are measured in bins, with the centre of the bin at
+//! `index` at `index` (see `HalfOpen::bin_position`). Cyclic axes wrap
+//! around. On other axes, positions beyond the centres of the outermost regular
+//! bins are clamped to them: the underflow and overflow bins are never used.
+
+use super::AxisLayout;
+
+/// The bins, as positions among all the bins of `axes` with the first axis
+/// varying fastest, and weights which interpolate linearly between their
+/// centres at `positions` along each axis
+pub fn interpolation_weights(axes: &[AxisLayout], positions: &[f32]) -> Vec<(usize, f32)> {
+    let mut corners = vec![(0, 1.0)];
+    let mut stride = 1;
+    for (&AxisLayout { n_bins, cyclic }, &position) in axes.iter().zip(positions) {
+        let (below, above, f) = neighbours(n_bins, cyclic, position);
+        corners = corners.into_iter()
+            .flat_map(|(i, w)| [(i + below * stride, w * (1.0 - f)), (i + above * stride, w * f)])
+            .collect();
+        stride *= n_bins;
+    }
+    corners
+}
+
+/// The bins whose centres bracket `position`, and how far `position` is from
+/// the first towards the second
+fn neighbours(n_bins: usize, cyclic: bool, position: f32) -> (usize, usize, f32) {
+    if cyclic {
+        let below = position.floor();
+        let i = (below as isize).rem_euclid(n_bins as isize) as usize;
+        (i, (i + 1) % n_bins, position - below)
+    } else {
+        // The regular bins are 1 to n_bins - 2
+        let (first, last) = (1.0, (n_bins - 2) as f32);
+        let position = position.clamp(first, last);
+        let below = position.floor().min(last - 1.0).max(first);
+        let i = below as usize;
+        (i, (i + 1).min(n_bins - 2), position - below)
+    }
+}
+
+#[cfg(test)]
+mod test_interpolation {
+    use super::*;
+    use super::super::*;
+    use float_eq::assert_float_eq;
+    use ndhistogram::ndhistogram;
+
+    /// LOR along x at height `z`
+    fn at_z(z: f32) -> LOR { mk_lor(((-300.0, 0.0, z), (300.0, 0.0, z))) }
+
+    /// LOR with `phi` (in turns), passing the z-axis on its right at `z`
+    fn at_phi_z(phi: f32, z: f32) -> LOR {
+        let (s, c) = (phi * TAU).sin_cos();
+        let (d, l) = (10.0, 300.0);
+        mk_lor(((-l * c - d * s, -l * s + d * c, z), (l * c - d * s, l * s + d * c, z)))
+    }
+
+    /// Lorogram with `counts` in the regular bins of `axis_z(4, -100 mm, 100
+    /// mm)`, whose centres are at -75, -25, 25 and 75 mm
+    fn z_lorogram(counts: [usize; 4]) -> Box<dyn Lorogram> {
+        let mut h: Box<dyn Lorogram> = Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)); usize));
+        for (bin, count) in counts.into_iter().enumerate() { h.add_at(bin + 1, count) }
+        h
+    }
+
+    #[test]
+    fn bin_centres_reproduce_raw_counts() {
+        let h = z_lorogram([10, 20, 40, 80]);
+        for z in [-75.0, -25.0, 25.0, 75.0] {
+            assert_float_eq!(h.value_interpolated(&at_z(z)), h.value(&at_z(z)) as f32, abs <= 1e-4);
+        }
+    }
+
+    #[test]
+    fn halfway_between_centres_is_the_average() {
+        let h = z_lorogram([10, 20, 40, 80]);
+        assert_float_eq!(h.value_interpolated(&at_z(-50.0)), 15.0, abs <= 1e-4);
+        assert_float_eq!(h.value_interpolated(&at_z(  0.0)), 30.0, abs <= 1e-4);
+        assert_float_eq!(h.value_interpolated(&at_z( 37.5)), 50.0, abs <= 1e-4);
+    }
+
+    #[test]
+    fn uniform_axes_are_clamped_to_the_outermost_centres() {
+        let h = z_lorogram([10, 20, 40, 80]);
+        assert_float_eq!(h.value_interpolated(&at_z(-90.0)), 10.0, abs <= 1e-4);
+        assert_float_eq!(h.value_interpolated(&at_z( 99.0)), 80.0, abs <= 1e-4);
+        assert_float_eq!(h.value_interpolated(&at_z(500.0)), 80.0, abs <= 1e-4);
+    }
+
+    #[test]
+    fn cyclic_axes_wrap_around() {
+        // Centres of axis_phi(4) are at 1/8, 3/8, 5/8 and 7/8 of a turn
+        let mut h: Box<dyn Lorogram> = Box::new(ndhistogram!(axis_phi(4); usize));
+        for (bin, count) in [10, 20, 40, 80].into_iter().enumerate() { h.add_at(bin, count) }
+        assert_float_eq!(h.value_interpolated(&at_phi_z(0.375, 0.0)), 20.0, abs <= 1e-3);
+        assert_float_eq!(h.value_interpolated(&at_phi_z(0.25 , 0.0)), 15.0, abs <= 1e-3);
+        // Halfway between the last centre and the first, across phi = 0
+        assert_float_eq!(h.value_interpolated(&at_phi_z(0.0  , 0.0)), 45.0, abs <= 1e-3);
+    }
+
+    #[test]
+    fn bilinear_midpoint_is_the_average_of_four_bins() {
+        let mut h: Box<dyn Lorogram> = Box::new(ndhistogram!(axis_phi(4), axis_z(4, mm(-100.0), mm(100.0)); usize));
+        // Bins (phi 3, z 2), (phi 0, z 2), (phi 3, z 3), (phi 0, z 3): phi varies fastest
+        for (index, count) in [(3 + 4 * 2, 10), (4 * 2, 20), (3 + 4 * 3, 30), (4 * 3, 40)] { h.add_at(index, count) }
+        assert_float_eq!(h.value_interpolated(&at_phi_z(0.0, 0.0)), 25.0, abs <= 1e-3);
+    }
+
+    #[test]
+    fn scattergram_can_interpolate() {
+        let mut sgram = BuildScattergram::new().z_bins(4).z_length(mm(200.0)).interpolate(true).build().unwrap();
+        assert!(sgram.interpolates());
+        for (z, trues, scatters) in [(-25.0, 100, 100), (25.0, 100, 300)] {
+            for _ in 0..trues    { sgram.fill(Prompt::True   , &at_z(z)) }
+            for _ in 0..scatters { sgram.fill(Prompt::Scatter, &at_z(z)) }
+        }
+        assert_float_eq!(ratio_(sgram.value(&at_z(-25.0))), 2.0, abs <= 1e-4);
+        assert_float_eq!(ratio_(sgram.value(&at_z( 25.0))), 4.0, abs <= 1e-4);
+        // Halfway, 100 trues and 200 scatters
+        assert_float_eq!(ratio_(sgram.value(&at_z(0.0))), 3.0, abs <= 1e-4);
+        let sgram = sgram.with_interpolation(false);
+        assert_float_eq!(ratio_(sgram.value(&at_z(0.0))), 4.0, abs <= 1e-4);
+    }
+}
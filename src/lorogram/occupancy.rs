@@ -0,0 +1,154 @@
+//! Statistical health of a filled `Scattergram`: how the events are spread
+//! over its bins, and how much of the data would be corrected from bins with
+//! too few events to be trusted.
+
+use serde::Serialize;
+
+use super::Scattergram;
+use crate::system_matrix::LOR;
+
+/// Bins with fewer events than this (trues, scatters and randoms together)
+/// have low statistics
+pub const LOW_STATISTICS: usize = 10;
+
+/// Occupancy of the bins of a `Scattergram`, by their total counts, as
+/// computed by `Scattergram::occupancy_report`. Overflow bins are included.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OccupancyReport {
+    pub n_bins: usize,
+    /// Bins without events
+    pub n_empty: usize,
+    /// Bins with 1 to 9 events
+    pub n_below_10: usize,
+    /// Bins with 10 to 99 events
+    pub n_below_100: usize,
+    /// Bins with 100 events or more
+    pub n_at_least_100: usize,
+    /// Median of the events in the bins with any
+    pub median_occupied: f32,
+    /// Number of LORs in the usage sample, if any
+    pub usage_sample: Option<usize>,
+    /// Fraction of the LORs of the usage sample which lie in bins with fewer
+    /// than `LOW_STATISTICS` events (or in no bin); without a sample, the
+    /// fraction of the bins with fewer than `LOW_STATISTICS` events
+    pub low_statistics_fraction: f32,
+}
+
+impl Scattergram {
+    /// Summarize the occupancy of the bins. With a `usage_sample` of the LORs
+    /// to be corrected, bins are weighted by how often those LORs hit them,
+    /// rather than all equally.
+    pub fn occupancy_report(&self, usage_sample: Option<&[LOR]>) -> OccupancyReport {
+        let totals: Vec<usize> = (0..self.n_bins())
+            .map(|i| self.trues.value_at(i) + self.scatters.value_at(i) + self.randoms.value_at(i))
+            .collect();
+        let count = |range: std::ops::Range<usize>| totals.iter().filter(|t| range.contains(t)).count();
+        let low = |t: usize| t < LOW_STATISTICS;
+
+        let mut occupied: Vec<usize> = totals.iter().copied().filter(|&t| t > 0).collect();
+        occupied.sort_unstable();
+        let median_occupied = match occupied.len() {
+            0 => 0.0,
+            n if n % 2 == 1 => occupied[n / 2] as f32,
+            n => (occupied[n / 2 - 1] + occupied[n / 2]) as f32 / 2.0,
+        };
+
+        let fraction = |n: usize, of: usize| n as f32 / of.max(1) as f32;
+        let low_statistics_fraction = match usage_sample {
+            Some(lors) => fraction(lors.iter().filter(|lor| self.index(lor).map_or(true, |i| low(totals[i]))).count(), lors.len()),
+            None       => fraction(totals.iter().filter(|&&t| low(t)).count(), totals.len()),
+        };
+
+        OccupancyReport {
+            n_bins: totals.len(),
+            n_empty       : count(0..1),
+            n_below_10    : count(1..10),
+            n_below_100   : count(10..100),
+            n_at_least_100: count(100..usize::MAX),
+            median_occupied,
+            usage_sample: usage_sample.map(<[LOR]>::len),
+            low_statistics_fraction,
+        }
+    }
+}
+
+impl std::fmt::Display for OccupancyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let percent = |n: usize| 100.0 * n as f32 / self.n_bins.max(1) as f32;
+        let counts = [self.n_empty, self.n_below_10, self.n_below_100, self.n_at_least_100];
+        writeln!(f, "Scattergram occupancy of {} bins, by events per bin:", self.n_bins)?;
+        writeln!(f, "  {:>10} {:>10} {:>10} {:>10}", "0", "1-9", "10-99", ">=100")?;
+        writeln!(f, "  {:>10} {:>10} {:>10} {:>10}", counts[0], counts[1], counts[2], counts[3])?;
+        writeln!(f, "  {:>9.1}% {:>9.1}% {:>9.1}% {:>9.1}%", percent(counts[0]), percent(counts[1]), percent(counts[2]), percent(counts[3]))?;
+        writeln!(f, "  median events per occupied bin: {}", self.median_occupied)?;
+        let low = 100.0 * self.low_statistics_fraction;
+        match self.usage_sample {
+            Some(n) => write!(f, "  {low:.1}% of {n} sampled LORs are corrected from bins with fewer than {LOW_STATISTICS} events"),
+            None    => write!(f, "  {low:.1}% of bins have fewer than {LOW_STATISTICS} events"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_occupancy {
+    use super::*;
+    use super::super::*;
+    use float_eq::assert_float_eq;
+    use ndhistogram::ndhistogram;
+
+    fn lor_at(z: Lengthf32) -> LOR { mk_lor(((-300.0, 0.0, z), (300.0, 0.0, z))) }
+
+    /// 4 regular bins centred on z = -75, -25, 25 and 75 mm, holding 0, 5, 50
+    /// and 500 events; the underflow and overflow bins are empty
+    fn scattergram() -> Scattergram {
+        let mut sgram = Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)); usize)));
+        for (z, trues, scatters, randoms) in [(-25.0, 3, 2, 0), (25.0, 40, 9, 1), (75.0, 400, 90, 10)] {
+            let i = sgram.index(&lor_at(z)).unwrap();
+            sgram.add_at(Prompt::True   , i, trues);
+            sgram.add_at(Prompt::Scatter, i, scatters);
+            sgram.add_at(Prompt::Random , i, randoms);
+        }
+        sgram
+    }
+
+    #[test]
+    fn bins_are_counted_by_total_events() {
+        let report = scattergram().occupancy_report(None);
+        assert_eq!((report.n_bins, report.n_empty, report.n_below_10, report.n_below_100, report.n_at_least_100),
+                   (6, 3, 1, 1, 1));
+        assert_eq!(report.median_occupied, 50.0);
+        assert_eq!(report.usage_sample, None);
+        // Empty bins and the bin with 5 events
+        assert_float_eq!(report.low_statistics_fraction, 4.0 / 6.0, abs <= 1e-6);
+    }
+
+    #[test]
+    fn median_of_even_number_of_occupied_bins_is_the_midpoint() {
+        let mut sgram = scattergram();
+        sgram.add_at(Prompt::True, sgram.index(&lor_at(-75.0)).unwrap(), 20);
+        assert_eq!(sgram.occupancy_report(None).median_occupied, 35.0);
+    }
+
+    #[test]
+    fn usage_sample_weights_bins_by_use() {
+        let sgram = scattergram();
+        let in_empty_bin: Vec<LOR> = (0..10).map(|_| lor_at(-75.0)).collect();
+        let report = sgram.occupancy_report(Some(&in_empty_bin));
+        assert_eq!(report.usage_sample, Some(10));
+        assert_eq!(report.low_statistics_fraction, 1.0);
+
+        let in_full_bins: Vec<LOR> = (0..10).map(|i| lor_at(if i < 8 { 75.0 } else { -25.0 })).collect();
+        assert_float_eq!(sgram.occupancy_report(Some(&in_full_bins)).low_statistics_fraction, 0.2, abs <= 1e-6);
+        // Bin occupancy does not depend on the sample
+        assert_eq!(sgram.occupancy_report(Some(&in_full_bins)).n_empty, 3);
+    }
+
+    #[test]
+    fn display_is_a_compact_table() {
+        let report = scattergram().occupancy_report(None).to_string();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[2].split_whitespace().collect::<Vec<_>>(), ["3", "1", "1", "1"]);
+        assert!(lines[5].contains("66.7% of bins have fewer than 10 events"), "{report}");
+    }
+}
@@ -0,0 +1,238 @@
+//! Organization of LORs by virtual rings, as in conventional PET pipelines.
+//!
+//! The axial extent of the scanner is divided into `n_rings` virtual rings of
+//! width `ring_spacing`, with the centre of ring 0 at `z0`. An endpoint belongs
+//! to the ring whose centre is nearest to it; endpoints exactly half-way
+//! between two rings are assigned to the ring with the higher index (the one
+//! further along `+z`). Endpoints beyond the outer edges of the first and last
+//! rings belong to no ring.
+//!
+//! LORs have no intrinsic direction, so their endpoints are put in a canonical
+//! order before the ring difference is calculated: the second endpoint is the
+//! one reached by travelling in the direction of the LOR's view angle, which
+//! lies in `[0, π)`.
+
+use std::f32::consts::PI;
+
+use ndarray::Array3;
+use ndhistogram::axis::Uniform;
+
+use crate::Length;
+use crate::system_matrix::LOR;
+use crate::lorogram::LorAxU;
+use geometry::units::{mm_, ratio_};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RingGeometry {
+    pub n_rings: usize,
+    pub ring_spacing: Length,
+    /// Axial position of the centre of ring 0
+    pub z0: Length,
+}
+
+impl RingGeometry {
+    /// Ring containing axial position `z`, if any
+    pub fn ring_of_z(&self, z: Length) -> Option<usize> {
+        let position = ratio_((z - self.z0) / self.ring_spacing);
+        let ring = (position + 0.5).floor();
+        if ring >= 0.0 && ring < self.n_rings as f32 { Some(ring as usize) } else { None }
+    }
+
+    /// Rings of the endpoints of `lor`, in canonical order
+    pub fn rings(&self, lor: &LOR) -> Option<(usize, usize)> {
+        let (z1, z2) = canonical_z(lor);
+        Some((self.ring_of_z(z1)?, self.ring_of_z(z2)?))
+    }
+
+    /// Signed difference between the rings of the canonically-ordered endpoints
+    pub fn ring_difference(&self, lor: &LOR) -> Option<isize> {
+        self.rings(lor).map(|(r1, r2)| r2 as isize - r1 as isize)
+    }
+
+    /// Segment containing `lor`, when `span` (which must be odd) ring
+    /// differences are combined into each segment.
+    ///
+    /// Segment 0 contains ring differences in `[-(span-1)/2, (span-1)/2]`,
+    /// segment `±1` the next `span` ring differences on either side, and so on.
+    pub fn segment(&self, lor: &LOR, span: usize) -> Option<isize> {
+        self.ring_difference(lor).map(|rd| segment_of_ring_difference(rd, span))
+    }
+
+    /// Largest segment number reachable with this geometry
+    pub fn max_segment(&self, span: usize) -> usize {
+        segment_of_ring_difference(self.n_rings as isize - 1, span) as usize
+    }
+
+    /// LOR counts indexed by `[segment, view, axial position]`.
+    ///
+    /// Segment `s` is stored at index `s + max_segment`. Views divide `[0, π)`
+    /// into `n_views` equal bins. The axial position is the sum of the two
+    /// ring indices, i.e. the LOR's midpoint in units of half a ring spacing,
+    /// giving `2 * n_rings - 1` positions. LORs with an endpoint outside the
+    /// rings are ignored.
+    pub fn sinogram_counts(&self, lors: &[LOR], n_views: usize, span: usize) -> Array3<usize> {
+        let max_segment = self.max_segment(span);
+        let mut counts = Array3::zeros((2 * max_segment + 1, n_views, 2 * self.n_rings - 1));
+        for lor in lors {
+            if let Some((r1, r2)) = self.rings(lor) {
+                let segment = segment_of_ring_difference(r2 as isize - r1 as isize, span);
+                let segment = (segment + max_segment as isize) as usize;
+                counts[[segment, view(lor, n_views), r1 + r2]] += 1;
+            }
+        }
+        counts
+    }
+}
+
+fn segment_of_ring_difference(rd: isize, span: usize) -> isize {
+    let (span, half) = (span as isize, span as isize / 2);
+    rd.signum() * ((rd.abs() + half) / span)
+}
+
+/// Angle of the LOR's projection onto the XY plane, in `[0, π)`
+pub fn view_angle(LOR { p1, p2, .. }: &LOR) -> f32 {
+    let (dx, dy) = (mm_(p2.x - p1.x), mm_(p2.y - p1.y));
+    let angle = dy.atan2(dx);
+    if angle < 0.0 { angle + PI } else if angle >= PI { angle - PI } else { angle }
+}
+
+/// Signed distance of the LOR's projection onto the XY plane from the z-axis:
+/// `y cos φ - x sin φ` for any point on it, where `φ` is its `view_angle`.
+/// Together with `view_angle`, the LOR's position in the sinogram.
+pub fn sinogram_s(lor: &LOR) -> Length {
+    let phi = view_angle(lor);
+    lor.p1.y * phi.cos() - lor.p1.x * phi.sin()
+}
+
+fn view(lor: &LOR, n_views: usize) -> usize {
+    ((view_angle(lor) / PI * n_views as f32) as usize).min(n_views - 1)
+}
+
+/// Axial positions of the endpoints, ordered along the view direction
+fn canonical_z(LOR { p1, p2, .. }: &LOR) -> (Length, Length) {
+    let (dx, dy) = (mm_(p2.x - p1.x), mm_(p2.y - p1.y));
+    let forward = dy > 0.0 || (dy == 0.0 && dx > 0.0);
+    if forward { (p1.z, p2.z) } else { (p2.z, p1.z) }
+}
+
+/// Axis binning LORs by signed ring difference, with one bin per possible
+/// difference. LORs with endpoints outside the rings fall in the overflow bin.
+pub fn axis_ring_difference(geom: RingGeometry) -> LorAxU {
+    let max = geom.n_rings as f32 - 0.5;
+    LorAxU {
+        axis: Uniform::new(2 * geom.n_rings - 1, -max, max),
+        map: Box::new(move |lor| geom.ring_difference(lor).map_or(f32::INFINITY, |rd| rd as f32)),
+    }
+}
+
+#[cfg(test)]
+mod test_rings {
+    use super::*;
+    use crate::lorogram::{mk_lor, Lorogram};
+    use float_eq::assert_float_eq;
+    use geometry::units::mm;
+    use ndhistogram::{axis::Axis, ndhistogram};
+    use rstest::rstest;
+
+    /// 5 rings 4 mm apart, centred on z = 0
+    fn geometry() -> RingGeometry {
+        RingGeometry { n_rings: 5, ring_spacing: mm(4.0), z0: mm(-8.0) }
+    }
+
+    #[rstest(/**/ z   , expected,
+             case(-8.0, Some(0)),
+             case(-4.0, Some(1)),
+             case( 0.0, Some(2)),
+             case( 4.0, Some(3)),
+             case( 8.0, Some(4)),
+             // Half-way between rings: the higher ring wins
+             case(-6.0, Some(1)),
+             case( 2.0, Some(3)),
+             case( 6.0, Some(4)),
+             // Just either side of half-way
+             case( 1.9, Some(2)),
+             case( 2.1, Some(3)),
+             // Outer edges of the scanner
+             case(-10.0, Some(0)),
+             case(-10.1, None),
+             case(  9.9, Some(4)),
+             case( 10.0, None),
+    )]
+    fn ring_of_z(z: f32, expected: Option<usize>) {
+        assert_eq!(geometry().ring_of_z(mm(z)), expected);
+    }
+
+    #[test]
+    fn ring_difference_does_not_depend_on_endpoint_order() {
+        let geom = geometry();
+        let a = (-300.0, -100.0, -8.0);
+        let b = ( 300.0,  200.0,  4.0);
+        assert_eq!(geom.rings          (&mk_lor((a, b))), Some((0, 3)));
+        assert_eq!(geom.rings          (&mk_lor((b, a))), Some((0, 3)));
+        assert_eq!(geom.ring_difference(&mk_lor((a, b))), Some(3));
+        assert_eq!(geom.ring_difference(&mk_lor((b, a))), Some(3));
+    }
+
+    #[rstest(/**/ rd, span, expected,
+             case( 0,    1,  0), case( 3, 1,  3), case(-2, 1, -2),
+             case( 1,    3,  0), case(-1, 3,  0), case( 2, 3,  1),
+             case( 4,    3,  1), case( 5, 3,  2), case(-4, 3, -1),
+    )]
+    fn segments(rd: isize, span: usize, expected: isize) {
+        assert_eq!(segment_of_ring_difference(rd, span), expected);
+    }
+
+    #[rstest]
+    #[case((-300.0,  20.0), ( 300.0, 20.0),  20.0)]
+    #[case(( 300.0,  20.0), (-300.0, 20.0),  20.0)]
+    #[case((   5.0, -300.0), (  5.0, 300.0), -5.0)]
+    #[case((   5.0,  300.0), (  5.0,-300.0), -5.0)]
+    #[case((-100.0, -100.0), (100.0, 100.0),  0.0)]
+    fn sinogram_s_is_independent_of_endpoint_order(#[case] p1: (f32, f32), #[case] p2: (f32, f32), #[case] expected: f32) {
+        let lor = mk_lor(((p1.0, p1.1, 0.0), (p2.0, p2.1, 10.0)));
+        assert_float_eq!(mm_(sinogram_s(&lor)), expected, abs <= 1e-4);
+    }
+
+    #[test]
+    fn ring_difference_axis() {
+        let geom = geometry();
+        let axis = axis_ring_difference(geom);
+        // 9 ring differences, plus underflow and overflow
+        assert_eq!(axis.num_bins(), 9 + 2);
+        let mut h = ndhistogram!(axis; usize);
+        let lor = |z1, z2| mk_lor(((-300.0, 0.0, z1), (300.0, 0.0, z2)));
+        Lorogram::fill(&mut h, &lor(-8.0, 8.0));
+        Lorogram::fill(&mut h, &lor(-4.0, 0.0));
+        Lorogram::fill(&mut h, &lor(-4.1, 0.1));
+        assert_eq!(Lorogram::value(&h, &lor(-8.0, 8.0)), 1);
+        assert_eq!(Lorogram::value(&h, &lor( 0.0, 4.0)), 2);
+        assert_eq!(Lorogram::value(&h, &lor( 4.0, 0.0)), 0);
+    }
+
+    #[test]
+    fn sinogram_of_single_ring_difference() {
+        let geom = geometry();
+        let (n_views, span) = (6, 1);
+        // Ring difference +2 at various angles and axial positions
+        let lors = (0..60).map(|i| {
+            let angle = i as f32 * 0.1;
+            let (x, y) = (300.0 * angle.cos(), 300.0 * angle.sin());
+            let r1 = (i % 3) as f32;
+            let (z1, z2) = (-8.0 + 4.0 * r1, -8.0 + 4.0 * (r1 + 2.0));
+            // Put the lower-ring endpoint first along the view direction
+            if angle.sin() > 0.0 || (angle.sin() == 0.0 && angle.cos() > 0.0) { mk_lor(((-x, -y, z1), (x, y, z2))) }
+            else                                                            { mk_lor(((x, y, z1), (-x, -y, z2))) }
+        }).collect::<Vec<_>>();
+        let counts = geom.sinogram_counts(&lors, n_views, span);
+        let max_segment = geom.max_segment(span);
+        assert_eq!(counts.dim(), (2 * max_segment + 1, n_views, 9));
+        assert_eq!(counts.sum(), lors.len());
+        let expected_segment = max_segment + 2;
+        for ((segment, _, _), &n) in counts.indexed_iter() {
+            if segment != expected_segment { assert_eq!(n, 0) }
+        }
+        // Ring pairs (0,2), (1,3), (2,4) have axial positions 2, 4, 6
+        let axial_totals = counts.sum_axis(ndarray::Axis(0)).sum_axis(ndarray::Axis(0));
+        assert_eq!(axial_totals.to_vec(), vec![0, 0, 20, 0, 20, 0, 20, 0, 0]);
+    }
+}
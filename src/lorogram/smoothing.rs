@@ -0,0 +1,157 @@
+//! Smoothing of the bin counts of lorograms, to tame the noise of binnings
+//! which are fine for the available statistics.
+//!
+//! Counts are convolved with a Gaussian along each axis in turn. Cyclic axes
+//! wrap around; on other axes the kernel is truncated at the underflow and
+//! overflow bins, which are neither smoothed nor used as neighbours, and
+//! renormalized over the bins it covers.
+
+use super::{LorAxis, MappedAxis};
+use crate::system_matrix::LOR;
+use super::axis::HalfOpen;
+use ndhistogram::axis::Axis;
+
+/// Arrangement of the bins along one axis of a lorogram
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AxisLayout {
+    /// Number of bins, including any underflow and overflow bins
+    pub n_bins: usize,
+    /// Whether the bins wrap around, rather than being flanked by an
+    /// underflow and an overflow bin
+    pub cyclic: bool,
+}
+
+/// Axes which can describe the arrangement of their bins
+pub trait BinLayout: Axis {
+    fn layout(&self) -> AxisLayout;
+    /// Position of `x` in units of bins: see `HalfOpen::bin_position`
+    fn position(&self, x: &Self::Coordinate) -> Option<f32>;
+}
+
+impl<T, A: HalfOpen> BinLayout for MappedAxis<T, A> {
+    fn layout(&self) -> AxisLayout { AxisLayout { n_bins: self.axis.num_bins(), cyclic: self.axis.is_cyclic() } }
+    fn position(&self, x: &T) -> Option<f32> { self.axis.bin_position(&(self.map)(x)) }
+}
+
+impl BinLayout for LorAxis {
+    fn layout(&self) -> AxisLayout {
+        match self {
+            LorAxis::U(a) => a.layout(),
+            LorAxis::C(a) => a.layout(),
+            LorAxis::V(a) => a.layout(),
+        }
+    }
+    fn position(&self, x: &LOR) -> Option<f32> {
+        match self {
+            LorAxis::U(a) => a.position(x),
+            LorAxis::C(a) => a.position(x),
+            LorAxis::V(a) => a.position(x),
+        }
+    }
+}
+
+/// Convolve `values`, the bins of `axes` with the first axis varying fastest,
+/// with a Gaussian of standard deviation `sigma` bins along each axis
+pub fn gaussian_smooth(values: &mut [f32], axes: &[AxisLayout], sigma: f32) {
+    assert_eq!(values.len(), axes.iter().map(|a| a.n_bins).product::<usize>(), "Values do not match the axes");
+    if sigma == 0.0 { return }
+    let reach = (3.0 * sigma).ceil() as isize;
+    let weights: Vec<f32> = (0..=reach).map(|k| (-0.5 * (k as f32 / sigma).powi(2)).exp()).collect();
+    let weight = |k: isize| weights[k.unsigned_abs()];
+
+    let mut stride = 1;
+    let mut line = vec![];
+    for &AxisLayout { n_bins: n, cyclic } in axes {
+        let regular = if cyclic { 0..n } else { 1..n.saturating_sub(1) };
+        for outer in 0..values.len() / (stride * n) {
+            for inner in 0..stride {
+                let at = |i: usize| outer * stride * n + inner + i * stride;
+                line.clear();
+                line.extend((0..n).map(|i| values[at(i)]));
+                for i in regular.clone() {
+                    let (mut sum, mut norm) = (0.0, 0.0);
+                    for k in -reach..=reach {
+                        let j = i as isize + k;
+                        let j = if cyclic { j.rem_euclid(n as isize) as usize }
+                                else if j >= 0 && regular.contains(&(j as usize)) { j as usize }
+                                else { continue };
+                        sum  += weight(k) * line[j];
+                        norm += weight(k);
+                    }
+                    values[at(i)] = sum / norm;
+                }
+            }
+        }
+        stride *= n;
+    }
+}
+
+#[cfg(test)]
+mod test_smoothing {
+    use super::*;
+    use super::super::*;
+    use float_eq::assert_float_eq;
+
+    /// Normalized weight of the Gaussian of width `sigma` bins, at `k` bins
+    /// from its centre
+    fn expected(sigma: f32, k: isize) -> f32 {
+        let w = |k: isize| (-0.5 * (k as f32 / sigma).powi(2)).exp();
+        let reach = (3.0 * sigma).ceil() as isize;
+        w(k) / (-reach..=reach).map(w).sum::<f32>()
+    }
+
+    #[test]
+    fn delta_spreads_as_a_gaussian_wrapping_around_cyclic_axes() {
+        // 8 cyclic bins, by 8 bins with underflow and overflow
+        let axes = [AxisLayout { n_bins: 8, cyclic: true }, AxisLayout { n_bins: 10, cyclic: false }];
+        let mut values = vec![0.0; 80];
+        let at = |i: usize, j: usize| i + 8 * j;
+        values[at(0, 4)] = 100.0;
+        gaussian_smooth(&mut values, &axes, 1.0);
+
+        // Along the cyclic axis, bin 0 spreads to bins 7, 6 and 5 as much as to 1, 2 and 3
+        for k in 1..=3 {
+            assert_float_eq!(values[at(k, 4)], values[at(8 - k, 4)], rmax <= 1e-6);
+            assert_float_eq!(values[at(k, 4)], 100.0 * expected(1.0, k as isize) * expected(1.0, 0), rmax <= 1e-5);
+        }
+        // Along the other, the kernel is truncated before the first regular bin
+        // (1), and renormalized over the bins it covers (1 to 4)
+        let e = |k| expected(1.0, k);
+        assert_float_eq!(values[at(0, 1)], 100.0 * e(0) * e(3) / (e(0) + e(1) + e(2) + e(3)), rmax <= 1e-5);
+        // Nothing leaks into the underflow and overflow bins
+        for i in 0..8 { assert_eq!((values[at(i, 0)], values[at(i, 9)]), (0.0, 0.0)); }
+    }
+
+    /// LOR with phi at the centre of `bin`: along that direction, passing the
+    /// z-axis on its right
+    fn lor_in_phi_bin(bin: usize, n_bins: usize) -> LOR {
+        let angle = (bin as f32 + 0.5) * TAU / n_bins as f32;
+        let (s, c) = angle.sin_cos();
+        let (d, l) = (10.0, 300.0);
+        mk_lor(((-l * c - d * s, -l * s + d * c, 0.0), (l * c - d * s, l * s + d * c, 0.0)))
+    }
+
+    #[test]
+    fn scattergram_delta_spreads_across_the_phi_boundary() {
+        let mut sgram = BuildScattergram::new().phi_bins(8).build().unwrap();
+        let lor = |bin| lor_in_phi_bin(bin, 8);
+        assert_eq!(sgram.index(&lor(0)), Some(0));
+        for _ in 0..100 { sgram.fill(Prompt::True   , &lor(0)) }
+        for _ in 0..100 { sgram.fill(Prompt::Scatter, &lor(4)) }
+        sgram.smooth(1.0).unwrap();
+
+        let trues = |bin| sgram.triplet(&lor(bin)).1;
+        assert_float_eq!(trues(1), trues(7), rmax <= 1e-6);
+        assert_float_eq!(trues(0), 100.0 * expected(1.0, 0), rmax <= 1e-5);
+        assert_float_eq!(trues(1), 100.0 * expected(1.0, 1), rmax <= 1e-5);
+        assert_float_eq!(trues(5), 100.0 * expected(1.0, 3), rmax <= 1e-5);
+        assert_float_eq!((0..8).map(trues).sum::<f32>(), 100.0, rmax <= 1e-5);
+        // Corrections come from the smoothed counts; the raw counts remain
+        let (t, s) = (trues(3), sgram.triplet(&lor(3)).2);
+        assert_float_eq!(ratio_(sgram.value(&lor(3))), (t + s) / t, rmax <= 1e-6);
+        assert_eq!(sgram.counts(&lor(0)), (100, 0));
+        // Further filling discards the smoothing
+        sgram.fill(Prompt::True, &lor(0));
+        assert_eq!(sgram.triplet(&lor(0)).1, 101.0);
+    }
+}
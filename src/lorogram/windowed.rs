@@ -0,0 +1,195 @@
+//! Scattergrams of consecutive time windows of an acquisition, for data whose
+//! scatter conditions change during the run.
+//!
+//! The windows share the binning of a single `Scattergram`, so the counts take
+//! `K` times the memory of one scattergram: reduce the axis binning when
+//! increasing the number of windows.
+
+use crate::lorogram::{scatter_value, Prompt, Scattergram};
+use crate::system_matrix::LOR;
+use crate::{Energyf32, Ratio};
+
+pub struct WindowedScattergram {
+    /// Defines the bins; its own counts are not used until `merged`
+    binning: Scattergram,
+    /// Boundaries of the windows, in seconds of acquisition time
+    edges: Vec<f32>,
+    /// Counts of each window, window after window
+    trues: Vec<usize>,
+    scatters: Vec<usize>,
+    randoms: Vec<usize>,
+}
+
+impl WindowedScattergram {
+    /// `n_windows` windows of equal duration covering `[start, end]` (seconds),
+    /// each binned like `binning`, which must be empty
+    pub fn new(binning: Scattergram, start: f32, end: f32, n_windows: usize) -> Result<Self, String> {
+        if n_windows == 0 { return Err("Need at least one scatter time window".into()) }
+        if !(start.is_finite() && end.is_finite() && start < end) {
+            return Err(format!("Invalid acquisition time range for scatter windows: {start} to {end} s"))
+        }
+        let width = (end - start) / n_windows as f32;
+        let mut edges: Vec<f32> = (0..n_windows).map(|k| start + k as f32 * width).collect();
+        edges.push(end);
+        let n = n_windows * binning.n_bins();
+        Ok(Self { binning, edges, trues: vec![0; n], scatters: vec![0; n], randoms: vec![0; n] })
+    }
+
+    pub fn n_windows(&self) -> usize { self.edges.len() - 1 }
+
+    /// Window containing `time`. Times outside the acquisition belong to the
+    /// nearest window.
+    pub fn window_of(&self, time: f32) -> usize {
+        let inner = &self.edges[1..self.edges.len() - 1];
+        inner.partition_point(|&edge| edge <= time)
+    }
+
+    /// Start and end (seconds) of `window`
+    pub fn window_limits(&self, window: usize) -> (f32, f32) { (self.edges[window], self.edges[window + 1]) }
+
+    fn position(&self, window: usize, lor: &LOR) -> Option<usize> {
+        self.binning.index(lor).map(|i| window * self.binning.n_bins() + i)
+    }
+
+    pub fn fill(&mut self, kind: Prompt, lor: &LOR, time: f32) {
+        if let Some(i) = self.position(self.window_of(time), lor) {
+            match kind {
+                Prompt::True    => self.trues   [i] += 1,
+                Prompt::Scatter => self.scatters[i] += 1,
+                Prompt::Random  => self.randoms [i] += 1,
+            }
+        }
+    }
+
+    /// Number of trues and scatters in the bin of `window` containing `lor`
+    pub fn counts(&self, window: usize, lor: &LOR) -> (usize, usize) {
+        self.position(window, lor).map_or((0, 0), |i| (self.trues[i], self.scatters[i]))
+    }
+
+    /// Number of randoms in the bin of `window` containing `lor`
+    pub fn randoms(&self, window: usize, lor: &LOR) -> usize {
+        self.position(window, lor).map_or(0, |i| self.randoms[i])
+    }
+
+    /// As `Scattergram::value`, using only the events of `window`
+    pub fn value_in_window(&self, window: usize, lor: &LOR) -> Ratio {
+        let (trues, scatters) = self.counts(window, lor);
+        scatter_value(trues, scatters + self.randoms(window, lor), self.binning.empty_bin_value())
+    }
+
+    /// As `Scattergram::value`, interpolated linearly in time between the
+    /// centres of the windows on either side of `time`. Beyond the centres of
+    /// the first and last windows, and next to windows without trues in the
+    /// bin of `lor`, the value of a single window is used.
+    pub fn value(&self, lor: &LOR, time: f32) -> Ratio {
+        let centre = |k: usize| { let (a, b) = self.window_limits(k); (a + b) / 2.0 };
+        let last = self.n_windows() - 1;
+        let window = self.window_of(time);
+        let (before, after) = if time < centre(window) { (window.saturating_sub(1), window) }
+                              else                     { (window, (window + 1).min(last)) };
+        if before == after || self.counts(after, lor).0 == 0 { return self.value_in_window(before, lor) }
+        if self.counts(before, lor).0 == 0 { return self.value_in_window(after, lor) }
+        let w = ((time - centre(before)) / (centre(after) - centre(before))).clamp(0.0, 1.0);
+        self.value_in_window(before, lor) * (1.0 - w) + self.value_in_window(after, lor) * w
+    }
+
+    /// Gamma energy (keV) below which a coincidence counts as a scatter
+    pub fn true_threshold(&self) -> Energyf32 { self.binning.true_threshold() }
+
+    /// Memory occupied by the counts of all windows
+    pub fn size_in_bytes(&self) -> usize {
+        (self.trues.len() + self.scatters.len() + self.randoms.len()) * std::mem::size_of::<usize>()
+    }
+
+    /// Collapse the windows into a single scattergram of the whole acquisition
+    pub fn merged(self) -> Scattergram {
+        let Self { mut binning, trues, scatters, randoms, .. } = self;
+        let n_bins = binning.n_bins();
+        for (kind, counts) in [(Prompt::True, trues), (Prompt::Scatter, scatters), (Prompt::Random, randoms)] {
+            for (i, &count) in counts.iter().enumerate() {
+                if count > 0 { binning.add_at(kind, i % n_bins, count) }
+            }
+        }
+        binning
+    }
+}
+
+#[cfg(test)]
+mod test_windowed_scattergram {
+    use super::*;
+    use crate::lorogram::{axis_phi, axis_z, mk_lor};
+    use float_eq::assert_float_eq;
+    use geometry::units::{mm, ratio_};
+    use ndhistogram::ndhistogram;
+
+    fn binning() -> Scattergram {
+        Scattergram::new(&|| Box::new(ndhistogram!(axis_z(4, mm(-100.0), mm(100.0)), axis_phi(4); usize)))
+    }
+
+    /// 100 s of events in a single bin: scatter fraction 20% during the first
+    /// half, 50% during the second
+    fn fill(sgram: &mut WindowedScattergram, lor: &LOR) {
+        for i in 0..1000 {
+            let time = i as f32 / 10.0;
+            let scatter = if i < 500 { i % 5 == 0 } else { i % 2 == 0 };
+            sgram.fill(if scatter { Prompt::Scatter } else { Prompt::True }, lor, time);
+        }
+    }
+
+    #[test]
+    fn windows_follow_changing_scatter_fraction() {
+        let lor = mk_lor(((-300.0, 10.0, 20.0), (300.0, -10.0, 30.0)));
+        let mut sgram = WindowedScattergram::new(binning(), 0.0, 100.0, 2).unwrap();
+        fill(&mut sgram, &lor);
+        // (scatters + trues) / trues
+        let (first, second) = (1.0 / 0.8, 1.0 / 0.5);
+        assert_float_eq!(ratio_(sgram.value_in_window(0, &lor)), first , rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value_in_window(1, &lor)), second, rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value(&lor,  25.0)), first , rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value(&lor,  75.0)), second, rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value(&lor,  50.0)), (first + second) / 2.0, rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value(&lor, -10.0)), first , rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value(&lor, 200.0)), second, rmax <= 1e-6);
+
+        let merged = sgram.merged();
+        assert_eq!(merged.counts(&lor), (650, 350));
+    }
+
+    #[test]
+    fn single_window_matches_plain_scattergram() {
+        let lor = mk_lor(((-300.0, 10.0, 20.0), (300.0, -10.0, 30.0)));
+        let mut windowed = WindowedScattergram::new(binning(), 0.0, 100.0, 1).unwrap();
+        fill(&mut windowed, &lor);
+        assert_eq!(windowed.size_in_bytes(), binning().size_in_bytes());
+        let merged = windowed.merged();
+        assert_eq!(merged.counts(&lor), (650, 350));
+        assert_float_eq!(ratio_(merged.value(&lor)), 1000.0 / 650.0, rmax <= 1e-6);
+    }
+
+    #[test]
+    fn randoms_are_counted_and_merged() {
+        let lor = mk_lor(((-300.0, 10.0, 20.0), (300.0, -10.0, 30.0)));
+        let mut sgram = WindowedScattergram::new(binning(), 0.0, 100.0, 2).unwrap();
+        fill(&mut sgram, &lor);
+        for i in 0..100 { sgram.fill(Prompt::Random, &lor, if i < 80 { 10.0 } else { 90.0 }) }
+        assert_eq!((sgram.randoms(0, &lor), sgram.randoms(1, &lor)), (80, 20));
+        // (scatters + randoms + trues) / trues
+        assert_float_eq!(ratio_(sgram.value_in_window(0, &lor)), (500.0 + 80.0) / 400.0, rmax <= 1e-6);
+        assert_float_eq!(ratio_(sgram.value_in_window(1, &lor)), (500.0 + 20.0) / 250.0, rmax <= 1e-6);
+
+        let merged = sgram.merged();
+        assert_eq!(merged.counts(&lor), (650, 350));
+        assert_eq!(merged.randoms(&lor), 100);
+    }
+
+    #[test]
+    fn windows() {
+        let sgram = WindowedScattergram::new(binning(), 10.0, 50.0, 4).unwrap();
+        assert_eq!(sgram.n_windows(), 4);
+        assert_eq!(sgram.window_limits(1), (20.0, 30.0));
+        let windows: Vec<_> = [0.0, 10.0, 19.9, 20.0, 45.0, 50.0, 99.0].iter().map(|&t| sgram.window_of(t)).collect();
+        assert_eq!(windows, [0, 0, 0, 1, 3, 3, 3]);
+        assert!(WindowedScattergram::new(binning(), 10.0, 50.0, 0).is_err());
+        assert!(WindowedScattergram::new(binning(), 50.0, 10.0, 2).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Accounting of the memory used by the phases of a reconstruction.
+//!
+//! Library code reports the major allocations it controls (LORs, images,
+//! scattergrams, projection buffers) with [`allocated`] and [`freed`], which
+//! emit `tracing` events. The [`MemoryAccounting`] layer collects these in a
+//! [`Registry`], keeps track of the largest registered total seen during each
+//! span, and samples the resident set size of the process whenever a span
+//! closes. The result is summarized by [`MemorySummary`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tracing::{field::{Field, Visit}, span, Event, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::utils::group_digits;
+
+/// Target of the `tracing` events which report allocations
+pub const TARGET: &str = "petalo::memory";
+
+/// Report that `bytes` bytes were allocated for `component`
+pub fn allocated(component: &'static str, bytes: usize) {
+    tracing::trace!(target: TARGET, component, bytes, freed = false);
+}
+
+/// Report that `bytes` bytes previously reported for `component` were released
+pub fn freed(component: &'static str, bytes: usize) {
+    tracing::trace!(target: TARGET, component, bytes, freed = true);
+}
+
+/// Memory occupied by the elements of `items`
+pub fn size_of_slice<T>(items: &[T]) -> usize { std::mem::size_of_val(items) }
+
+// --------------------------------------------------------------------------------
+/// Bytes currently registered by each component, and the largest values they
+/// reached
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registry {
+    current: BTreeMap<String, usize>,
+    peak: BTreeMap<String, usize>,
+    peak_total: usize,
+}
+
+impl Registry {
+    pub fn allocate(&mut self, component: &str, bytes: usize) {
+        let current = self.current.entry(component.into()).or_insert(0);
+        *current += bytes;
+        let current = *current;
+        let peak = self.peak.entry(component.into()).or_insert(0);
+        *peak = (*peak).max(current);
+        self.peak_total = self.peak_total.max(self.total());
+    }
+
+    /// Releasing more than was allocated leaves the component at 0
+    pub fn free(&mut self, component: &str, bytes: usize) {
+        if let Some(current) = self.current.get_mut(component) {
+            *current = current.saturating_sub(bytes);
+        }
+    }
+
+    pub fn current(&self, component: &str) -> usize { self.current.get(component).copied().unwrap_or(0) }
+    pub fn peak   (&self, component: &str) -> usize { self.peak   .get(component).copied().unwrap_or(0) }
+
+    /// Bytes currently registered by all components
+    pub fn total(&self) -> usize { self.current.values().sum() }
+
+    /// Largest value reached by `total`
+    pub fn peak_total(&self) -> usize { self.peak_total }
+}
+
+// --------------------------------------------------------------------------------
+/// Resident set size of this process, in bytes
+#[cfg(target_os = "linux")]
+pub fn rss() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 { return None }
+    Some(pages * page_size as usize)
+}
+
+/// Resident set size of this process: not available on this platform
+#[cfg(not(target_os = "linux"))]
+pub fn rss() -> Option<usize> { None }
+
+// --------------------------------------------------------------------------------
+/// Memory used during all the spans with the same name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PhaseMemory {
+    /// Largest total of registered allocations while the phase was running
+    pub peak_registered: usize,
+    /// Largest resident set size sampled at the end of the phase
+    pub peak_rss: Option<usize>,
+}
+
+/// Everything that was learned about memory use during a run
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MemorySummary {
+    /// Peak bytes registered by each component
+    pub components: BTreeMap<String, usize>,
+    pub phases: BTreeMap<String, PhaseMemory>,
+    pub peak_registered: usize,
+    pub peak_rss: Option<usize>,
+}
+
+impl fmt::Display for MemorySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mb = |bytes: usize| group_digits(bytes / (1 << 20));
+        let mb_or_na = |bytes: Option<usize>| bytes.map_or("n/a".into(), |b| format!("{} MB", mb(b)));
+        writeln!(f, "Memory (peak registered / RSS at end of phase):")?;
+        for (name, phase) in &self.phases {
+            writeln!(f, "    {name:24} {:>8} MB  {:>11}", mb(phase.peak_registered), mb_or_na(phase.peak_rss))?;
+        }
+        writeln!(f, "  Components (peak):")?;
+        for (name, bytes) in &self.components {
+            writeln!(f, "    {name:24} {:>8} MB", mb(*bytes))?;
+        }
+        writeln!(f, "  Peak registered: {} MB", mb(self.peak_registered))?;
+        write!  (f, "  Peak RSS       : {}", mb_or_na(self.peak_rss))
+    }
+}
+
+// --------------------------------------------------------------------------------
+#[derive(Default)]
+struct State {
+    registry: Registry,
+    phases: BTreeMap<String, PhaseMemory>,
+    peak_rss: Option<usize>,
+}
+
+/// `tracing` layer collecting the allocations reported with [`allocated`] and
+/// [`freed`], and sampling RSS whenever a span closes. Clones share their
+/// state, so one can be installed while another is kept for the summary.
+#[derive(Clone, Default)]
+pub struct MemoryAccounting(Arc<Mutex<State>>);
+
+/// Largest registered total seen while a span was open
+struct SpanPeak(usize);
+
+impl MemoryAccounting {
+    pub fn new() -> Self { Self::default() }
+
+    /// Summary of everything recorded so far, including a final RSS sample
+    pub fn summary(&self) -> MemorySummary {
+        let mut state = self.0.lock().unwrap();
+        state.peak_rss = max_option(state.peak_rss, rss());
+        MemorySummary {
+            components: state.registry.peak.clone(),
+            phases: state.phases.clone(),
+            peak_registered: state.registry.peak_total(),
+            peak_rss: state.peak_rss,
+        }
+    }
+}
+
+fn max_option(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl<S> Layer<S> for MemoryAccounting
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let total = self.0.lock().unwrap().registry.total();
+            span.extensions_mut().insert(SpanPeak(total));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != TARGET { return }
+        let mut report = AllocationVisitor::default();
+        event.record(&mut report);
+        let component = match report.component {
+            Some(component) => component,
+            None => return,
+        };
+        let total = {
+            let mut state = self.0.lock().unwrap();
+            if report.freed { state.registry.free    (&component, report.bytes) }
+            else            { state.registry.allocate(&component, report.bytes) }
+            state.registry.total()
+        };
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(SpanPeak(peak)) = span.extensions_mut().get_mut::<SpanPeak>() {
+                    *peak = (*peak).max(total);
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let peak_registered = span.extensions().get::<SpanPeak>().map_or(0, |p| p.0);
+            let sample = rss();
+            let mut state = self.0.lock().unwrap();
+            state.peak_rss = max_option(state.peak_rss, sample);
+            let phase = state.phases.entry(span.name().into()).or_default();
+            phase.peak_registered = phase.peak_registered.max(peak_registered);
+            phase.peak_rss = max_option(phase.peak_rss, sample);
+        }
+    }
+}
+
+#[derive(Default)]
+struct AllocationVisitor {
+    component: Option<String>,
+    bytes: usize,
+    freed: bool,
+}
+
+impl Visit for AllocationVisitor {
+    fn record_str (&mut self, field: &Field, value: &str) { if field.name() == "component" { self.component = Some(value.into()) } }
+    fn record_u64 (&mut self, field: &Field, value: u64 ) { if field.name() == "bytes"     { self.bytes = value as usize } }
+    fn record_bool(&mut self, field: &Field, value: bool) { if field.name() == "freed"     { self.freed = value } }
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn registry_arithmetic_is_exact() {
+        let mut registry = Registry::default();
+        registry.allocate("lors", 3 * 40);
+        registry.allocate("image", 8 * 8 * 8 * 4);
+        registry.allocate("lors", 2 * 40);
+        assert_eq!(registry.current("lors"), 200);
+        assert_eq!(registry.total(), 200 + 2048);
+        registry.free("lors", 120);
+        assert_eq!(registry.current("lors"), 80);
+        assert_eq!(registry.peak("lors"), 200);
+        assert_eq!(registry.total(), 80 + 2048);
+        assert_eq!(registry.peak_total(), 200 + 2048);
+        registry.free("image", 10_000);
+        assert_eq!(registry.current("image"), 0);
+        assert_eq!(registry.current("unknown"), 0);
+    }
+
+    #[test]
+    fn size_of_slice_counts_elements() {
+        assert_eq!(size_of_slice(&[0_f32; 10]), 40);
+        assert_eq!(size_of_slice::<u64>(&[]), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rss_is_plausible() {
+        let before = rss().unwrap();
+        assert!(before > 0);
+        // Touch every page, so that it becomes resident
+        let big = vec![1_u8; 64 << 20];
+        let during = rss().unwrap();
+        assert!(during >= big.len(), "{during} bytes resident while holding {}", big.len());
+        assert!(during < 1 << 40);
+        drop(big);
+    }
+
+    fn run_phases() -> MemorySummary {
+        let accounting = MemoryAccounting::new();
+        let subscriber = tracing_subscriber::registry().with(accounting.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("outer").entered();
+            allocated("lors", 1000);
+            {
+                let _inner = tracing::info_span!("inner").entered();
+                allocated("image", 500);
+                freed("image", 500);
+            }
+            freed("lors", 1000);
+            drop(outer);
+            let _after = tracing::info_span!("after").entered();
+        });
+        accounting.summary()
+    }
+
+    #[test]
+    fn phases_record_peaks_of_registered_allocations() {
+        let summary = run_phases();
+        assert_eq!(summary.phases["outer"].peak_registered, 1500);
+        assert_eq!(summary.phases["inner"].peak_registered, 1500);
+        assert_eq!(summary.phases["after"].peak_registered, 0);
+        assert_eq!(summary.components["lors"], 1000);
+        assert_eq!(summary.components["image"], 500);
+        assert_eq!(summary.peak_registered, 1500);
+    }
+
+    #[test]
+    fn summary_json_contains_components() {
+        let json = serde_json::to_value(run_phases()).unwrap();
+        assert_eq!(json["components"]["lors"], 1000);
+        assert_eq!(json["components"]["image"], 500);
+        assert_eq!(json["phases"]["inner"]["peak_registered"], 1500);
+        assert!(json.get("peak_rss").is_some());
+    }
+}
@@ -2,60 +2,225 @@ use std::path::Path;
 use ndarray::azip;
 
 use rayon::prelude::*;
+use tracing::info_span;
 
-use crate::{io, Lengthf32, Index1_u, Intensityf32};
-use crate::{Length, PerLength, Ratio, Time, AreaPerMass};
-use crate::{fov::{lor_fov_hit, FovHit}, system_matrix::{system_matrix_elements, LOR}};
+use crate::{io, memory, Lengthf32, Index1_u, Intensityf32};
+use crate::{Length, PerLength, Time, AreaPerMass};
+use crate::{fov::{lor_fov_hit, FovHit}, system_matrix::{system_matrix_elements, OnTheFly, Corrections, RowSource, LOR, Tube}};
 use crate::fov::FOV;
-use crate::gauss::make_gauss_option;
+use crate::acceleration::{log_likelihood, Acceleration, Accelerator};
+use crate::divergence::{max_relative_change, Convergence};
+use crate::cancel::{Cancel, CANCEL_CHECK_CHUNK};
+use crate::gauss::{make_gauss_option, TofCutoff};
 use geometry::units::{ratio_, mm, kg};
+use geometry::uom::ConstZero;
 
 use crate::image::{Image, ImageData};
+use crate::cylindrical::CylindricalImage;
+use crate::orientation::Frame;
+use crate::index::{checked_index, debug_assert_in_bounds};
+
+/// How the backprojections of the LORs, computed in parallel, are added up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reduction {
+    /// Each thread adds up its own share of the LORs, and the threads' sums
+    /// are added. How the LORs are shared out, and so the rounding of the
+    /// result, depends on the number of threads and on their timing.
+    Fast,
+    /// The LORs are added up in blocks of `DETERMINISTIC_BLOCK`, and the
+    /// blocks' sums are added in order: the result is the same, bit for bit,
+    /// with any number of threads, at the cost of clearing and adding one
+    /// image-sized buffer per block
+    Deterministic,
+}
+
+impl Default for Reduction {
+    fn default() -> Self { Self::Fast }
+}
+
+impl std::str::FromStr for Reduction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast"          => Ok(Self::Fast),
+            "deterministic" => Ok(Self::Deterministic),
+            _ => Err(format!("Unknown reduction '{s}': use fast or deterministic")),
+        }
+    }
+}
+
+/// What each LOR adds to the voxels it traverses, in `Image::coverage_image`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageWeight {
+    /// 1: the image counts the LORs through each voxel
+    Count,
+    /// The length, in mm, of the LOR's chord through the voxel
+    Chord,
+}
+
+impl std::str::FromStr for CoverageWeight {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "count" => Ok(Self::Count),
+            "chord" => Ok(Self::Chord),
+            _ => Err(format!("Unknown coverage weight '{s}': use count or chord")),
+        }
+    }
+}
+
+/// Number of LORs added up serially by `Reduction::Deterministic`
+pub const DETERMINISTIC_BLOCK: usize = 1 << 14;
+
+/// Optional behaviour of `Image::mlem_with`, beyond that of plain `mlem`,
+/// which is what the `Default` gives
+#[derive(Clone, Default)]
+pub struct MlemOptions<'a> {
+    /// Only the voxels for which it is `true` are updated. The remaining voxels
+    /// keep their initial values, but still contribute to the forward
+    /// projections.
+    pub focus: Option<Vec<bool>>,
+    /// Extrapolation of the updates
+    pub acceleration: Acceleration,
+    /// Source of the system matrix rows of the projector: a `GeometryCache` or
+    /// a `SystemMatrix`, which must not have been made with other LORs, FOV,
+    /// TOF or tube settings
+    pub rows: Option<&'a dyn RowSource>,
+    /// No further images are produced once it is requested (see `cancel`)
+    pub cancel: Option<Cancel>,
+    /// How the backprojections are added up
+    pub reduction: Reduction,
+    /// Pair every image with its `Convergence`. The log-likelihood is that of
+    /// all the measured LORs, which costs one more forward projection per image.
+    pub convergence: bool,
+}
+
+/// One image produced by `Image::mlem_with`, after `subset` of `iteration`
+pub struct MlemStep {
+    pub image: Image,
+    pub iteration: usize,
+    pub subset: usize,
+    /// Only if requested in `MlemOptions`
+    pub convergence: Option<Convergence>,
+}
 
 impl Image {
 
     pub fn mlem<'a>(fov: FOV,
                     measured_lors: &'a [LOR],
                     sigma        :     Option<Time>,
-                    cutoff       :     Option<Ratio>,
+                    cutoff       :     Option<TofCutoff>,
+                    tube         :     Option<Tube>,
                     sensitivity  :     Option<Self>,
                     n_subsets    :     usize,
     ) -> impl Iterator<Item = (Image, usize, usize)> + '_ {
-
         // Start off with a uniform image
-        let mut image = Self::ones(fov);
+        Self::mlem_with(Self::ones(fov), measured_lors, sigma, cutoff, tube, sensitivity, n_subsets, MlemOptions::default())
+            .map(|MlemStep { image, iteration, subset, .. }| (image, iteration, subset))
+    }
+
+    /// Ordered-subset EM: `mlem` without TOF, with `measured_lors` split into
+    /// `n_subsets` interleaved subsets, each of which updates the image in
+    /// turn. With one subset, this is exactly `mlem`.
+    pub fn osem(fov: FOV, measured_lors: &[LOR], sensitivity: Option<Self>, n_subsets: usize) -> impl Iterator<Item = (Image, usize, usize)> + '_ {
+        Self::mlem(fov, measured_lors, None, None, None, sensitivity, n_subsets)
+    }
+
+    /// As `mlem`, but starting from `initial`, with the behaviour chosen in
+    /// `options`
+    #[allow(clippy::too_many_arguments)]
+    pub fn mlem_with<'a>(initial: Self,
+                         measured_lors: &'a [LOR],
+                         sigma        :     Option<Time>,
+                         cutoff       :     Option<TofCutoff>,
+                         tube         :     Option<Tube>,
+                         sensitivity  :     Option<Self>,
+                         n_subsets    :     usize,
+                         options      :     MlemOptions<'a>,
+    ) -> impl Iterator<Item = MlemStep> + 'a {
+        let MlemOptions { focus, acceleration, rows, cancel, reduction, convergence } = options;
+
+        let mut image = initial;
+        let fov = image.fov;
 
         let sensitivity = sensitivity.or_else(|| Some(Self::ones(fov))).unwrap();
+        // The current image, the sensitivity image and the copy handed out in each iteration
+        memory::allocated("image", 3 * memory::size_of_slice(&image.data));
 
-        let len = measured_lors.len();
-        let set_size = len / n_subsets; // TODO: remainder LORs ignored
         let (mut iteration, mut subset) = (1, 1);
+        let mut accelerator = Accelerator::new(acceleration, measured_lors, sigma, cutoff, tube);
+        if accelerator.is_active() {
+            // Copies of the current and previous images
+            memory::allocated("acceleration", 2 * memory::size_of_slice(&image.data));
+        }
+        // The previous image, against which to measure the convergence
+        let mut previous = convergence.then(|| image.data.clone());
 
         // Return an iterator which generates an infinite sequence of images,
         // each one made by performing one MLEM iteration on the previous one
         std::iter::from_fn(move || {
-            let lo = (subset - 1) * set_size;
-            let hi = lo + set_size;
+            if cancel.as_ref().map_or(false, Cancel::is_requested) { return None }
             let (old_iteration, old_subset) = (iteration, subset);
             subset += 1;
             if subset > n_subsets {
                 subset = 1;
                 iteration += 1;
             }
-            image.one_iteration(&measured_lors[lo..hi], &sensitivity.data, sigma, cutoff);
-            Some((image.clone(), old_iteration, old_subset)) // TODO see if we can sensibly avoid cloning
+            {
+                let _span = info_span!("mlem_iteration", iteration = old_iteration, subset = old_subset).entered();
+                let mut complete = true;
+                accelerator.step(&mut image, &sensitivity, |image| {
+                    complete &= image.one_iteration(measured_lors, (old_subset - 1, n_subsets), &sensitivity.data, sigma, cutoff, tube, focus.as_deref(), rows, cancel.as_ref(), reduction)
+                });
+                // The image of an abandoned (sub)iteration is incomplete
+                if !complete { return None }
+            }
+            let convergence = previous.as_mut().map(|previous| {
+                let log_likelihood = log_likelihood(&image, measured_lors, &sensitivity, sigma, cutoff, tube);
+                let max_change = max_relative_change(previous, &image.data);
+                previous.clone_from(&image.data);
+                Convergence { iteration: old_iteration, subset: old_subset, log_likelihood, max_change }
+            });
+            // TODO see if we can sensibly avoid cloning
+            Some(MlemStep { image: image.clone(), iteration: old_iteration, subset: old_subset, convergence })
         })
     }
 
     pub fn from_raw_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok((&crate::io::raw::Image3D::read_from_file(path)?).into())
+        Ok(io::raw::read_image(path)?)
     }
 
     pub fn write_to_raw_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        io::raw::Image3D::from(self).write_to_file(path)?;
+        let _span = info_span!("write_image", path = ?path).entered();
+        io::raw::write_versioned(self, path, io::raw::Dtype::F32, io::raw::Order::XFastest)?;
         Ok(())
     }
 
+    /// Read a headerless file of little-endian `f32`s or `f64`s (told apart by
+    /// the size of the file), x varying fastest, which must fill `fov` exactly
+    pub fn from_raw_file_with_fov(path: &Path, fov: FOV) -> Result<Self, Box<dyn std::error::Error>> {
+        let n_bytes = std::fs::metadata(path)?.len() as usize;
+        let n_voxels: usize = fov.n.iter().product();
+        let dtype = if n_bytes == 8 * n_voxels { io::raw::Dtype::F64 } else { io::raw::Dtype::F32 };
+        io::raw::read_headerless(path, fov, dtype, io::raw::Order::XFastest)
+            .map_err(|e| format!("Reading '{}' as a {fov} image: {e}", path.display()).into())
+    }
+
+    /// Write the voxel values alone, as little-endian `f32`s, x varying
+    /// fastest: read them back with `from_raw_file_with_fov`
+    pub fn write_raw(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(io::raw::write(self.data.iter().copied(), path)?)
+    }
+
+    /// Read an image in any format which can be recognized without being told
+    /// its FOV: NIfTI-1 (`.nii`), or either raw format (see `from_raw_file`)
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("nii") => io::nifti::read(path),
+            _           => Self::from_raw_file(path),
+        }
+    }
+
     // Too much copy-paste code reuse from project_one_lor. This is because the
     // latter (and the functions it uses) was heavily optimized, at the cost of
     // ease of reuse.
@@ -64,19 +229,14 @@ impl Image {
     /// Create sensitivity image by backprojecting LORs. In theory this should
     /// use *all* possible LORs. In practice use a representative sample.
     pub fn sensitivity_image(density: Self, lors: impl ParallelIterator<Item = LOR>, n_lors: usize, rho_to_mu: AreaPerMass) -> Self {
-        // Convert from [density in kg/m^3] to [mu in mm^-1]
-        let rho_to_mu: f32 = ratio_({
-            let kg = kg(1.0);
-            let  m = mm(1000.0);
-            let rho_unit = kg / (m * m * m);
-            let  mu_unit = 1.0 / mm(1.0);
-            rho_to_mu / (mu_unit / rho_unit)
-        });
-        let mut attenuation = density;
-        for voxel in &mut attenuation.data {
-            *voxel *= rho_to_mu;
-        }
-        let attenuation = &attenuation;
+        Self::sensitivity_image_normalized(density, lors, n_lors, rho_to_mu, false)
+    }
+
+    /// As `sensitivity_image`, optionally dividing the weights of each LOR by
+    /// its chord length through the FOV (see `Tube::normalize_chord`)
+    pub fn sensitivity_image_normalized(density: Self, lors: impl ParallelIterator<Item = LOR>, n_lors: usize, rho_to_mu: AreaPerMass, normalize_chord: bool) -> Self {
+        let _span = info_span!("sensitivity_image", n_lors).entered();
+        let attenuation = &attenuation_map(density, rho_to_mu);
 
         // TOF should not be used as LOR attenuation is independent of decay point
         let notof = make_gauss_option(None, None);
@@ -90,7 +250,7 @@ impl Image {
 
         // -------- Project all LORs forwards and backwards ---------------------
         let fold_result = lors
-            .fold(initial_thread_state, sensitivity_one_lor);
+            .fold(initial_thread_state, |state, lor| sensitivity_one_lor(state, lor, normalize_chord));
 
         // -------- extract relevant information (backprojection) ---------------
         let mut backprojection = fold_result
@@ -107,12 +267,172 @@ impl Image {
         Self::new(attenuation.fov, backprojection)
     }
 
-    fn one_iteration(&mut self, measured_lors: &[LOR], sensitivity: &[Intensityf32], sigma: Option<Time>, cutoff: Option<Ratio>) {
+    /// As `sensitivity_image_normalized`, assuming that the sensitivity is
+    /// symmetric under rotations about the z-axis: the LORs are backprojected
+    /// into the `CylindricalImage` covering the FOV, which is broadcast back
+    /// to the voxels.
+    ///
+    /// Also returns the `CylindricalImage::asymmetry` of the backprojection.
+    /// Above `cylindrical::ASYMMETRY_WARNING` the assumption does not hold
+    /// (asymmetric density or LOR sample), and the result is the azimuthal
+    /// average of the sensitivity, rather than an estimate of it.
+    pub fn sensitivity_image_symmetric(density: Self, lors: impl ParallelIterator<Item = LOR>, n_lors: usize, rho_to_mu: AreaPerMass, normalize_chord: bool) -> (Self, f32) {
+        let _span = info_span!("sensitivity_image_symmetric", n_lors).entered();
+        let attenuation = &attenuation_map(density, rho_to_mu);
+        let fov = attenuation.fov;
+        let notof = make_gauss_option(None, None);
+        let empty = CylindricalImage::covering(fov);
+        let bins = &empty.voxel_bins(fov);
+
+        let mut cylindrical = lors
+            .fold(|| (empty.clone(), vec![], vec![]), |(mut cylindrical, mut weights, mut indices), lor| {
+                if let Some(factor) = sensitivity_row(&lor, attenuation, &notof, normalize_chord, &mut weights, &mut indices) {
+                    cylindrical.back_project(bins, &weights, &indices, factor * ratio_(lor.corrections.multiplicative));
+                }
+                (cylindrical, weights, indices)
+            })
+            .map(|state| state.0)
+            .reduce(|| empty.clone(), CylindricalImage::merged);
+
+        cylindrical.scale(1.0 / n_lors as f32);
+        (cylindrical.broadcast(fov), cylindrical.asymmetry(fov))
+    }
+
+    /// Sensitivity correction estimated from the measured LORs themselves,
+    /// rather than from LORs sampled analytically from an idealized detector.
+    ///
+    /// All `lors` are backprojected without attenuation or TOF, the result is smoothed with a Gaussian of width `smoothing`, and the
+    /// reciprocal is taken, normalized to a mean of 1 over the voxels which
+    /// were reached by any LOR. Voxels reached by no LOR get 0. The result is
+    /// suitable for passing as the `sensitivity` argument of `mlem`.
+    ///
+    /// The backprojection of measured data reflects the activity distribution
+    /// as well as the scanner acceptance, so this is only a reasonable estimate
+    /// for extended, roughly uniform sources with good statistics.
+    ///
+    /// With `normalize_chord`, the weights of each LOR are divided by its chord
+    /// length, matching a projector using `Tube::normalize_chord`. With
+    /// `weighted`, each LOR is backprojected with its multiplicative correction
+    /// (normalization), which scales its system matrix elements in `mlem`;
+    /// otherwise with unit weight.
+    pub fn data_sensitivity_image(fov: FOV, lors: &[LOR], smoothing: Option<Length>, normalize_chord: bool, weighted: bool) -> Self {
+        let _span = info_span!("data_sensitivity", n_lors = lors.len()).entered();
+        let lors_par = lors.par_iter().map(|&lor| if weighted { lor } else { unweighted(lor) });
+        let backprojection = Self::sensitivity_image_normalized(Self::empty(fov), lors_par, lors.len(), AreaPerMass::ZERO, normalize_chord);
+        backprojection.sensitivity_correction(smoothing)
+    }
+
+    /// As `data_sensitivity_image`, backprojecting as `sensitivity_image_symmetric`,
+    /// whose asymmetry is also returned: for uniform cylinder normalization scans
+    pub fn data_sensitivity_image_symmetric(fov: FOV, lors: &[LOR], smoothing: Option<Length>, normalize_chord: bool, weighted: bool) -> (Self, f32) {
+        let _span = info_span!("data_sensitivity_symmetric", n_lors = lors.len()).entered();
+        let lors_par = lors.par_iter().map(|&lor| if weighted { lor } else { unweighted(lor) });
+        let (backprojection, asymmetry) = Self::sensitivity_image_symmetric(Self::empty(fov), lors_par, lors.len(), AreaPerMass::ZERO, normalize_chord);
+        (backprojection.sensitivity_correction(smoothing), asymmetry)
+    }
+
+    /// How well `lors` cover each voxel of `fov`: each LOR adds `weight` to
+    /// every voxel it traverses, without TOF, attenuation or corrections. A
+    /// cheap proxy for the sensitivity image, and a diagnostic for voxels which
+    /// few LORs reach, such as those at the axial ends of the FOV.
+    pub fn coverage_image(fov: FOV, lors: &[LOR], weight: CoverageWeight) -> Self {
+        let _span = info_span!("coverage_image", n_lors = lors.len()).entered();
+        let notof = make_gauss_option(None, None);
+        let coverage = lors.par_iter()
+            .fold(|| projection_buffers(fov), |(mut coverage, mut weights, mut indices), lor| {
+                if system_matrix_row(lor, fov, &notof, None, &mut indices, &mut weights) {
+                    for (&w, &j) in weights.iter().zip(&indices) {
+                        if w <= 0.0 { continue }
+                        coverage[j] += match weight {
+                            CoverageWeight::Count => 1.0,
+                            CoverageWeight::Chord => w,
+                        };
+                    }
+                }
+                (coverage, weights, indices)
+            })
+            .map(|(coverage, _, _)| coverage)
+            .reduce(|| zeros_buffer(fov), elementwise_add);
+        Self::new(fov, coverage)
+    }
+
+    /// Smoothed reciprocal of this backprojection: see `data_sensitivity_image`
+    fn sensitivity_correction(self, smoothing: Option<Length>) -> Self {
+        let backprojection = match smoothing {
+            Some(sigma) => self.gaussian_smoothed(sigma),
+            None        => self,
+        };
+        let reached = backprojection.data.iter().filter(|&&b| b > 0.0);
+        let (n, total) = reached.fold((0, 0.0), |(n, t), b| (n + 1, t + b));
+        let mean = if n > 0 { total / n as f32 } else { 1.0 };
+        let mut correction = backprojection;
+        for v in correction.data.iter_mut() {
+            *v = if *v > 0.0 { mean / *v } else { 0.0 };
+        }
+        correction
+    }
+
+    /// Voxel-wise variance of this image, from the diagonal of the Fisher
+    /// information of the `lors` from which it was reconstructed:
+    ///
+    /// `var_j ≈ 1 / Σ_e (m_e a_ej / ȳ_e)²`
+    ///
+    /// where `e` runs over the measured events, `a_ej` are their system matrix
+    /// elements, `m_e` their multiplicative and scatter corrections, and `ȳ_e`
+    /// the forward projection of this image into them. For data consistent
+    /// with the image, this is the expected information `Σ_i a_ij² / ȳ_i`.
+    ///
+    /// Neglecting the off-diagonal terms underestimates the variance of voxels
+    /// which are strongly correlated with their neighbours, and the estimate
+    /// describes the maximum-likelihood solution, not an MLEM image stopped
+    /// well before convergence: treat it as a guide to relative uncertainty.
+    ///
+    /// Events with no positive expectation carry no information and are
+    /// skipped. Voxels without information, or with zero `sensitivity`, get 0.
+    pub fn variance_estimate(&self, lors: &[LOR], sensitivity: Option<&Image>,
+                             sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>) -> Self {
+        let _span = info_span!("variance_estimate", n_lors = lors.len()).entered();
+        let tof = make_gauss_option(sigma, cutoff);
+        let bore = cutoff.and_then(|c| c.bore_radius);
+        let information = lors.par_iter()
+            .fold(|| (zeros_buffer(self.fov), vec![], vec![]), |(mut information, mut indices, mut weights), lor| {
+                let hit = system_matrix_row(lor, self.fov, &tof, tube, &mut indices, &mut weights);
+                if !hit || indices.iter().any(|&j| j >= information.len()) { return (information, indices, weights) }
+                if let Some(radius) = bore { clamp_to_bore(&mut indices, &mut weights, self.fov, radius) }
+                let expected = lor.corrections.forward(forward_project(&weights, &indices, self));
+                if expected > 0.0 {
+                    let m = ratio_(lor.corrections.multiplicative * lor.corrections.scatter);
+                    for (&w, &j) in weights.iter().zip(&indices) {
+                        information[j] += (m * w / expected).powi(2);
+                    }
+                }
+                (information, indices, weights)
+            })
+            .map(|(information, _, _)| information)
+            .reduce(|| zeros_buffer(self.fov), elementwise_add);
+        let insensitive = |j: usize| matches!(sensitivity, Some(s) if s.data[j] <= 0.0);
+        let variance = information.iter().enumerate()
+            .map(|(j, &f)| if f > 0.0 && !insensitive(j) { 1.0 / f } else { 0.0 })
+            .collect();
+        Self::new(self.fov, variance)
+    }
+
+    /// Update the image with one (sub)iteration over `measured_lors`. Returns
+    /// `false`, leaving the image untouched, if `cancel` abandoned it.
+    ///
+    /// `(k, n)` selects the subset: every `n`th LOR, starting with the `k`th
+    /// (counting from 0). As each subset sees only about `1/n` of the data,
+    /// its update is scaled by `n` to keep the image at the scale of the full
+    /// dataset.
+    #[allow(clippy::too_many_arguments)]
+    fn one_iteration(&mut self, measured_lors: &[LOR], (k, n): (usize, usize), sensitivity: &[Intensityf32], sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>, focus: Option<&[bool]>, rows: Option<&dyn RowSource>, cancel: Option<&Cancel>, reduction: Reduction) -> bool {
 
         // -------- Prepare state required by serial/parallel fold --------------
 
         // TOF adjustment to apply to the weights
         let tof: Option<_> = make_gauss_option(sigma, cutoff);
+        let bore = cutoff.and_then(|c| c.bore_radius);
+        let rows = rows.unwrap_or(&OnTheFly);
 
         // Closure preparing the state needed by `fold`: will be called by
         // `fold` at the start of every thread that is launched.
@@ -123,25 +443,73 @@ impl Image {
         };
 
         // -------- Project all LORs forwards and backwards ---------------------
-        let fold_result = measured_lors
-            .par_iter()
-            .fold(initial_thread_state, project_one_lor);
-
-        // -------- extract relevant information (backprojection) ---------------
-        let backprojection = fold_result
-            // Keep only the backprojection (ignore weights and indices)
-            .map(|tuple| tuple.0)
-            // Sum the backprojections calculated on each thread
-            .reduce(|| zeros_buffer(self.fov), elementwise_add);
+        // (Forward and back projections are interleaved LOR by LOR, so they
+        // share a single span.)
+        let projection_span = info_span!("projection", n_lors = measured_lors.len() / n).entered();
+        let buffers = rayon::current_num_threads() * memory::size_of_slice(&self.data);
+        memory::allocated("projection_buffers", buffers);
+        let abandon = || cancel.map_or(false, Cancel::abandon_now);
+        // Chunks are multiples of `n` long, so that every chunk starts at the
+        // beginning of a round of subsets. Each comes with the position of its
+        // first LOR in `measured_lors`, which identifies the LORs to `rows`.
+        let chunk_len = CANCEL_CHECK_CHUNK * n;
+        let project_chunk = |state, (start, chunk): (usize, &[LOR])| {
+            if abandon() { return state }
+            chunk.iter().enumerate().skip(k).step_by(n)
+                .fold(state, |state, (i, lor)| project_one_lor(state, start + i, lor, tube, bore, rows))
+        };
+        let mut backprojection = match reduction {
+            Reduction::Fast => measured_lors
+                .par_chunks(chunk_len)
+                .enumerate()
+                .map(|(c, chunk)| (c * chunk_len, chunk))
+                .fold(initial_thread_state, &project_chunk)
+                // Keep only the backprojection (ignore weights and indices)
+                .map(|tuple| tuple.0)
+                // Sum the backprojections calculated on each thread
+                .reduce(|| zeros_buffer(self.fov), elementwise_add),
+            Reduction::Deterministic => {
+                // As many blocks at a time as there are threads, keeping the
+                // memory used to that of `Fast`
+                let block_len = DETERMINISTIC_BLOCK * n;
+                let blocks: Vec<&[LOR]> = measured_lors.chunks(block_len).collect();
+                let wave_len = rayon::current_num_threads();
+                let mut total = zeros_buffer(self.fov);
+                for (w, wave) in blocks.chunks(wave_len).enumerate() {
+                    let first_block = w * wave_len;
+                    let sums: Vec<ImageData> = wave.par_iter().enumerate()
+                        .map(|(b, block)| block.chunks(chunk_len).enumerate()
+                             .map(|(c, chunk)| ((first_block + b) * block_len + c * chunk_len, chunk))
+                             .fold(initial_thread_state(), &project_chunk).0)
+                        .collect();
+                    for sum in sums {
+                        total.iter_mut().zip(&sum).for_each(|(t, s)| *t += s);
+                    }
+                }
+                total
+            },
+        };
+        if n > 1 {
+            let scale = n as Lengthf32;
+            backprojection.iter_mut().for_each(|b| *b *= scale);
+        }
+        memory::freed("projection_buffers", buffers);
+        drop(projection_span);
+        if abandon() { return false }
 
         // -------- Correct for attenuation and detector sensitivity ------------
-        apply_sensitivity_image(&mut self.data, &backprojection, sensitivity);
+        let _span = info_span!("sensitivity_correction").entered();
+        match focus {
+            None       => apply_sensitivity_image        (&mut self.data, &backprojection, sensitivity),
+            Some(mask) => apply_sensitivity_image_focused(&mut self.data, &backprojection, sensitivity, mask),
+        }
+        true
     }
 
     pub fn ones(fov: FOV) -> Self {
         let [x,y,z] = fov.n;
         let size = x * y * z;
-        Self { data: vec![1.0; size], fov}
+        Self::new(fov, vec![1.0; size])
     }
 
     pub fn new(fov: FOV, data: ImageData) -> Self {
@@ -150,7 +518,7 @@ impl Image {
             // TODO change panic to Option or Result
             panic!("Image data does not match dimensions {:?}", fov.n);
         };
-        Image { fov, data }
+        Image { fov, data, frame: Frame::standard(&fov) }
     }
 
     pub fn empty(fov: FOV) -> Self {
@@ -189,7 +557,7 @@ fn zeros_buffer(fov: FOV) -> ImageData { let [x,y,z] = fov.n; vec![0.0; x*y*z] }
 
 type FoldState<'r, 'i, 'g, G> = (ImageData , Vec<Lengthf32>, Vec<Index1_u> , &'r &'i Image, &'g Option<G>);
 
-fn project_one_lor<'r, 'i, 'g, G>(state: FoldState<'r, 'i, 'g, G>, lor: &LOR) -> FoldState<'r, 'i, 'g, G>
+fn project_one_lor<'r, 'i, 'g, G>(state: FoldState<'r, 'i, 'g, G>, i: usize, lor: &LOR, tube: Option<Tube>, bore: Option<Length>, rows: &dyn RowSource) -> FoldState<'r, 'i, 'g, G>
 where
     G: Fn(Length) -> PerLength
 {
@@ -198,92 +566,197 @@ where
     // Need to return the state from various match arms
     macro_rules! return_state { () => (return  (backprojection, weights, indices, image, tof)); }
 
-    // Analyse point where LOR hits FOV
-    match lor_fov_hit(lor, image.fov) {
-
-        // LOR missed FOV: nothing to be done
-        None => return_state!(),
+    // Find active voxels and their weights. LOR missed FOV: nothing to be done
+    let hit = rows.row(i, lor, tof.is_some(), &mut indices, &mut weights,
+                       &mut |indices, weights| system_matrix_row(lor, image.fov, tof, tube, indices, weights));
+    if !hit { return_state!() }
+    if let Some(radius) = bore { clamp_to_bore(&mut indices, &mut weights, image.fov, radius) }
 
-        // Data needed by `system_matrix_elements`
-        Some(FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak}) => {
+    // Skip problematic LORs TODO: Is the cause more interesting than 'effiing floats'?
+    for i in &indices {
+        if *i >= backprojection.len() { return_state!(); }
+    }
+    debug_assert_in_bounds(&indices, image.fov.n);
 
-            // Throw away previous LOR's values
-            weights.clear();
-            indices.clear();
+    // Forward projection of current image into this LOR, including corrections
+    let projection = lor.corrections.forward(forward_project(&weights, &indices, image));
 
-            // Find active voxels and their weights
-            system_matrix_elements(
-                &mut indices, &mut weights,
-                next_boundary, voxel_size,
-                index, delta_index, remaining,
-                tof_peak, tof
-            );
+    // Backprojection of LOR onto image: the multiplicative correction also
+    // scales the system matrix elements used here
+    let multiplicative = ratio_(lor.corrections.multiplicative);
+    back_project(&mut backprojection, &weights, &indices, projection / multiplicative);
+    return_state!();
+}
 
-            // Skip problematic LORs TODO: Is the cause more interesting than 'effiing floats'?
-            for i in &indices {
-                if *i >= backprojection.len() { return_state!(); }
+/// Replace the contents of `indices` and `weights` with the system matrix
+/// elements of `lor`. With a tube of more than one sample, the elements of all
+/// its sub-LORs are averaged. With `normalize_chord`, the weights of each
+/// sub-LOR are first divided by its chord length, so the averaged weights sum
+/// to the fraction of sub-LORs which hit the FOV. Returns `false` if the LOR
+/// misses the FOV.
+pub fn system_matrix_row<G>(
+    lor: &LOR, fov: FOV, tof: &Option<G>, tube: Option<Tube>,
+    indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+) -> bool
+where
+    G: Fn(Length) -> PerLength
+{
+    let normalize_chord = matches!(tube, Some(Tube { normalize_chord: true, .. }));
+    let thin = |lor: &LOR, indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>| {
+        // Throw away previous LOR's values
+        indices.clear();
+        weights.clear();
+        match lor_fov_hit(lor, fov) {
+            None => false,
+            Some(FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak, end}) => {
+                let chord = system_matrix_elements(
+                    indices, weights,
+                    next_boundary, voxel_size,
+                    index, delta_index, remaining,
+                    tof_peak, end, tof
+                );
+                if normalize_chord { normalize(weights, chord) }
+                true
             }
+        }
+    };
+
+    let tube = match tube {
+        Some(tube) if tube.samples > 1 => tube,
+        _ => return thin(lor, indices, weights),
+    };
+
+    let (mut sub_indices, mut sub_weights) = (vec![], vec![]);
+    let mut elements: Vec<(Index1_u, Lengthf32)> = vec![];
+    for sub_lor in tube.sub_lors(lor) {
+        if thin(&sub_lor, &mut sub_indices, &mut sub_weights) {
+            elements.extend(sub_indices.iter().copied().zip(sub_weights.iter().copied()));
+        }
+    }
+    indices.clear();
+    weights.clear();
+    if elements.is_empty() { return false }
+
+    // Sum the weights of voxels crossed by more than one sub-LOR
+    elements.sort_unstable_by_key(|&(i, _)| i);
+    let n = tube.samples as Lengthf32;
+    for (i, w) in elements {
+        if indices.last() == Some(&i) { *weights.last_mut().unwrap() += w / n }
+        else                          { indices.push(i); weights.push(w / n) }
+    }
+    true
+}
 
-            // Forward projection of current image into this LOR
-            let projection = forward_project(&weights, &indices, image) * lor.additive_correction;
+/// Divide `weights` by the `chord` length of their LOR through the FOV
+fn normalize(weights: &mut [Lengthf32], chord: Lengthf32) {
+    if chord > 0.0 {
+        for w in weights { *w /= chord }
+    }
+}
 
-            // Backprojection of LOR onto image
-            back_project(&mut backprojection, &weights, &indices, ratio_(projection));
-            return_state!();
-        }
+/// Attenuation coefficients in mm^-1, from `density` in kg/m^3
+fn attenuation_map(density: Image, rho_to_mu: AreaPerMass) -> Image {
+    let rho_to_mu: f32 = ratio_({
+        let kg = kg(1.0);
+        let  m = mm(1000.0);
+        let rho_unit = kg / (m * m * m);
+        let  mu_unit = 1.0 / mm(1.0);
+        rho_to_mu / (mu_unit / rho_unit)
+    });
+    let mut attenuation = density;
+    for voxel in &mut attenuation.data {
+        *voxel *= rho_to_mu;
     }
+    attenuation
 }
 
-fn sensitivity_one_lor<'r, 'i, 'g, G>(state: FoldState<'r, 'i, 'g, G>, lor: LOR) -> FoldState<'r, 'i, 'g, G>
+fn sensitivity_one_lor<'r, 'i, 'g, G>(state: FoldState<'r, 'i, 'g, G>, lor: LOR, normalize_chord: bool) -> FoldState<'r, 'i, 'g, G>
 where
     G: Fn(Length) -> PerLength
 {
     let (mut backprojection, mut weights, mut indices, attenuation, tof) = state;
+    if let Some(attenuation_factor) = sensitivity_row(&lor, attenuation, tof, normalize_chord, &mut weights, &mut indices) {
+        // Backprojection of LOR onto sensitivity image, with the system matrix
+        // elements scaled by the multiplicative correction, as in `project_one_lor`
+        let multiplicative = ratio_(lor.corrections.multiplicative);
+        back_project(&mut backprojection, &weights, &indices, attenuation_factor * multiplicative);
+    }
+    (backprojection, weights, indices, attenuation, tof)
+}
 
-    // Need to return the state from various match arms
-    macro_rules! return_state { () => (return (backprojection, weights, indices, attenuation, tof)); }
-
-    // Find active voxels (slice of system matrix) WITHOUT TOF
-    // Analyse point where LOR hits FOV
-    match lor_fov_hit(&lor, attenuation.fov) {
-
-        // LOR missed FOV: nothing to be done
-        None => return_state!(),
-
-        // Data needed by `system_matrix_elements`
-        Some(FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak}) => {
-
-            // Throw away previous LOR's values
-            weights.clear();
-            indices.clear();
+/// `lor` without its multiplicative correction, for backprojecting with unit weight
+fn unweighted(lor: LOR) -> LOR {
+    LOR { corrections: Corrections { multiplicative: Corrections::NONE.multiplicative, ..lor.corrections }, ..lor }
+}
 
-            // Find active voxels and their weights
-            system_matrix_elements(
-                &mut indices, &mut weights,
-                next_boundary, voxel_size,
-                index, delta_index, remaining,
-                tof_peak, tof
-            );
+/// Replace `weights` and `indices` with the active voxels of `lor` (slice of
+/// system matrix) WITHOUT TOF, returning the fraction of its photon pairs which
+/// survive `attenuation`. `None` if `lor` misses the FOV.
+fn sensitivity_row<G>(lor: &LOR, attenuation: &Image, tof: &Option<G>, normalize_chord: bool,
+                      weights: &mut Vec<Lengthf32>, indices: &mut Vec<Index1_u>) -> Option<Lengthf32>
+where
+    G: Fn(Length) -> PerLength
+{
+    // Analyse point where LOR hits FOV: `None` if LOR missed FOV
+    let FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak, end} = lor_fov_hit(lor, attenuation.fov)?;
+
+    // Throw away previous LOR's values
+    weights.clear();
+    indices.clear();
+
+    // Find active voxels and their weights
+    let chord = system_matrix_elements(
+        indices, weights,
+        next_boundary, voxel_size,
+        index, delta_index, remaining,
+        tof_peak, end, tof
+    );
+
+    // Skip problematic LORs TODO: Is the cause more interesting than 'effiing floats'?
+    if indices.iter().any(|&i| i >= attenuation.data.len()) { return None }
+    debug_assert_in_bounds(indices, attenuation.fov.n);
+
+    let integral = forward_project(weights, indices, attenuation);
+    // Attenuation depends on the true path lengths, so normalize only now
+    if normalize_chord { normalize(weights, chord) }
+    Some((-integral).exp())
+}
 
-            // Skip problematic LORs TODO: Is the cause more interesting than 'effiing floats'?
-            for i in &indices {
-                if *i >= backprojection.len() { return_state!(); }
-            }
+/// Forward projection of `image` into each of `lors`, including corrections.
+/// `None` for LORs which miss the FOV.
+pub fn forward_projections(image: &Image, lors: &[LOR], sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>) -> Vec<Option<Lengthf32>> {
+    let tof = make_gauss_option(sigma, cutoff);
+    let bore = cutoff.and_then(|c| c.bore_radius);
+    lors.par_iter()
+        .map_init(|| (vec![], vec![]), |(indices, weights), lor| {
+            if !system_matrix_row(lor, image.fov, &tof, tube, indices, weights) { return None }
+            if let Some(radius) = bore { clamp_to_bore(indices, weights, image.fov, radius) }
+            if indices.iter().any(|&i| i >= image.data.len()) { return None }
+            Some(lor.corrections.forward(forward_project(weights, indices, image)))
+        })
+        .collect()
+}
 
-            let integral = forward_project(&weights, &indices, attenuation);
-            let attenuation_factor = (-integral).exp();
-            // Backprojection of LOR onto sensitivity image
-            back_project(&mut backprojection, &weights, &indices, attenuation_factor);
-            return_state!();
+/// Drop voxels whose centres lie farther than `radius` from the z-axis
+fn clamp_to_bore(indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>, fov: FOV, radius: Length) {
+    let mut kept = 0;
+    for k in 0..indices.len() {
+        let centre = fov.voxel_centre1(indices[k]);
+        if centre.x.hypot(centre.y) <= radius {
+            indices[kept] = indices[k];
+            weights[kept] = weights[k];
+            kept += 1;
         }
     }
+    indices.truncate(kept);
+    weights.truncate(kept);
 }
 
 #[inline]
 fn forward_project(weights: &[Lengthf32], indices: &[usize], image: &Image) -> Lengthf32 {
     let mut projection = 0.0;
     for (w, &j) in weights.iter().zip(indices.iter()) {
-        projection += w * image[j]
+        projection += w * image[checked_index(j, image.fov.n)]
     }
     projection
 }
@@ -296,6 +769,15 @@ fn back_project(backprojection: &mut Vec<Lengthf32>, weights: &[Lengthf32], indi
     }
 }
 
+/// As `apply_sensitivity_image`, leaving voxels outside `focus` untouched
+fn apply_sensitivity_image_focused(image: &mut ImageData, backprojection: &[Lengthf32], sensitivity: &[Intensityf32], focus: &[bool]) {
+    for (((voxel, &b), &s), &inside) in image.iter_mut().zip(backprojection).zip(sensitivity).zip(focus) {
+        if !inside { continue }
+        if s > 0.0 { *voxel *= b * s }
+        else       { *voxel  = 0.0   }
+    }
+}
+
 fn apply_sensitivity_image(image: &mut ImageData, backprojection: &[Lengthf32], sensitivity: &[Intensityf32]) {
     //  TODO express with Option<matrix> and mul reciprocal
     // Apply Sensitivity matrix
@@ -305,9 +787,804 @@ fn apply_sensitivity_image(image: &mut ImageData, backprojection: &[Lengthf32],
     })
 }
 
+/// Floor of `axial_sensitivity_profile`, which keeps its reciprocal finite at
+/// the axial ends of the detector and beyond
+pub const AXIAL_PROFILE_FLOOR: Intensityf32 = 1e-3;
+
+/// Sensitivity of a cylindrical detector of axial half-length
+/// `detector_half_length`, as a function of z alone: a cheap stand-in for a
+/// full sensitivity image.
+///
+/// The LORs through a point at `z` which hit the detector at both ends span an
+/// axial acceptance proportional to `detector_half_length - |z|`, for
+/// acceptance angles small enough that their tangents are proportional to the
+/// angles, so the profile falls linearly from the centre to 0 at the ends of
+/// the detector. It is normalized to 1 in the central slice of `fov`, and
+/// clamped below at `AXIAL_PROFILE_FLOOR`.
+pub fn axial_sensitivity_profile(detector_half_length: Length, fov: FOV) -> Image {
+    let acceptance = |z: Length| ratio_((detector_half_length - z.abs()) / detector_half_length).max(0.0);
+    let z = fov.axis_coordinates(2);
+    let centre = acceptance(z[z.len() / 2]);
+    let [nx, ny, _] = fov.n;
+    let data = z.iter()
+        .map(|&z| (acceptance(z) / centre).max(AXIAL_PROFILE_FLOOR))
+        .flat_map(|value| std::iter::repeat(value).take(nx * ny))
+        .collect();
+    Image::new(fov, data)
+}
+
+#[cfg(test)]
+mod test_axial_profile {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    /// 3×2×10 voxels, 10 mm long in z: slice centres at z = -45, -35, ..., 45 mm
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(30.0), mm(20.0), mm(100.0)), (3, 2, 10)) }
+
+    #[rstest(/**/ half_length, iz, z,
+             case(60.0, 5,  5.0),
+             case(60.0, 4, -5.0),
+             case(60.0, 7, 25.0),
+             case(60.0, 0,-45.0),
+             case(60.0, 9, 45.0),
+             case(48.0, 9, 45.0),
+    )]
+    fn profile_matches_closed_form(half_length: f32, iz: usize, z: f32) {
+        let profile = axial_sensitivity_profile(mm(half_length), fov());
+        // Normalized to the central slices, at |z| = 5 mm
+        let expected = (half_length - z.abs()) / (half_length - 5.0);
+        for ix in 0..3 { for iy in 0..2 {
+            assert_float_eq!(profile[[ix, iy, iz]], expected, rmax <= 1e-5);
+        }}
+    }
+
+    #[test]
+    fn profile_is_clamped_beyond_the_detector() {
+        let profile = axial_sensitivity_profile(mm(30.0), fov());
+        assert_eq!(profile[[0, 0, 4]], 1.0);
+        assert_float_eq!(profile[[0, 0, 6]], 15.0 / 25.0, rmax <= 1e-5);
+        for iz in [0, 1, 8, 9] {
+            assert_eq!(profile[[1, 1, iz]], AXIAL_PROFILE_FLOOR, "slice {iz}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tube {
+    use super::*;
+    use crate::Point;
+    use crate::index::index1_to_3;
+    use geometry::units::{mm, ns};
+    use rstest::rstest;
+
+    fn row(lor: &LOR, fov: FOV, tube: Option<Tube>) -> (Vec<Index1_u>, Vec<Lengthf32>) {
+        let (mut indices, mut weights) = (vec![], vec![]);
+        let notof = make_gauss_option(None, None);
+        system_matrix_row(lor, fov, &notof, tube, &mut indices, &mut weights);
+        (indices, weights)
+    }
+
+    fn lor(p1: (f32, f32, f32), p2: (f32, f32, f32)) -> LOR {
+        let p = |(x, y, z)| Point::new(mm(x), mm(y), mm(z));
+        LOR::new(ns(0.0), ns(0.0), p(p1), p(p2))
+    }
+
+    #[rstest(/**/ p1                  , p2,
+             case((-100.0,  3.0,  1.0), (100.0, -7.0,  2.0)),
+             case((  20.0, 90.0, -4.0), (-15.0,-90.0, 30.0)),
+             case((   0.5,  0.5,-80.0), (  0.5,  0.5, 80.0)),
+    )]
+    fn single_sample_is_thin_lor(p1: (f32, f32, f32), p2: (f32, f32, f32)) {
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(30.0), mm(30.0)), (15, 15, 15));
+        let lor = lor(p1, p2);
+        let thin = row(&lor, fov, None);
+        let tube = row(&lor, fov, Some(Tube { radius: mm(3.0), samples: 1, normalize_chord: false }));
+        assert_eq!(thin, tube);
+    }
+
+    #[test]
+    fn tube_weights_sum_to_chord_length() {
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(30.0), mm(30.0)), (15, 15, 15));
+        // Parallel to x: every sub-LOR has a 30 mm chord
+        let lor = lor((-100.0, 0.3, 0.2), (100.0, 0.3, 0.2));
+        let (indices, weights) = row(&lor, fov, Some(Tube { radius: mm(4.0), samples: 16, normalize_chord: false }));
+        let total: f32 = weights.iter().sum();
+        assert!((total - 30.0).abs() < 0.01, "{total}");
+        // Averaging merges repeated voxels
+        let mut unique = indices.clone();
+        unique.dedup();
+        assert_eq!(unique, indices);
+    }
+
+    #[rstest(/**/ p2,
+             case((10.0    , 0.3, 0.2)),
+             case((f32::NAN, 0.3, 0.2)),
+             case((f32::INFINITY, 0.3, 0.2)),
+    )]
+    fn tube_of_lor_without_direction_misses(p2: (f32, f32, f32)) {
+        let fov = FOV::new_from_full_widths((mm(30.0), mm(30.0), mm(30.0)), (15, 15, 15));
+        let lor = lor((10.0, 0.3, 0.2), p2);
+        let (mut indices, mut weights) = (vec![1], vec![1.0]);
+        let notof = make_gauss_option(None, None);
+        let tube = Some(Tube { radius: mm(3.0), samples: 16, normalize_chord: false });
+        assert!(!system_matrix_row(&lor, fov, &notof, tube, &mut indices, &mut weights));
+        assert!(indices.is_empty() && weights.is_empty());
+    }
+
+    #[test]
+    fn axial_tube_reaches_neighbouring_columns() {
+        // Central column of voxels spans -1 to 1 mm in x and y
+        let fov = FOV::new_from_full_widths((mm(22.0), mm(22.0), mm(22.0)), (11, 11, 11));
+        let lor = lor((0.0, 0.0, -100.0), (0.0, 0.0, 100.0));
+        let columns = |tube| {
+            let (indices, _) = row(&lor, fov, tube);
+            let mut columns: Vec<_> = indices.into_iter()
+                .map(|i| { let [x, y, _] = index1_to_3(i, fov.n); (x, y) })
+                .collect();
+            columns.sort_unstable();
+            columns.dedup();
+            columns
+        };
+        assert_eq!(columns(None), vec![(5, 5)]);
+        let wide = columns(Some(Tube { radius: mm(3.0), samples: 16, normalize_chord: false }));
+        for neighbour in [(4, 5), (6, 5), (5, 4), (5, 6)] {
+            assert!(wide.contains(&neighbour), "{neighbour:?} not in {wide:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tof_cutoff {
+    use super::*;
+    use crate::Point;
+    use geometry::units::{mm, mm_, ns, ps, ratio};
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(200.0), mm(2.0), mm(2.0)), (100, 1, 1)) }
+
+    /// LOR along x, from p1 at -100 mm to p2 at +100 mm, with its TOF peak at the origin
+    fn lor() -> LOR {
+        LOR::new(ns(0.0), ns(0.0), Point::new(mm(-100.0), mm(0.0), mm(0.0)), Point::new(mm(100.0), mm(0.0), mm(0.0)))
+    }
+
+    /// (x of voxel centre, weight) along the LOR
+    fn row(cutoff: TofCutoff) -> Vec<(f32, f32)> {
+        let (mut indices, mut weights) = (vec![], vec![]);
+        // sigma = c * 100 ps ~ 30 mm
+        let tof = make_gauss_option(Some(ps(100.0)), Some(cutoff));
+        assert!(system_matrix_row(&lor(), fov(), &tof, None, &mut indices, &mut weights));
+        if let Some(radius) = cutoff.bore_radius { clamp_to_bore(&mut indices, &mut weights, fov(), radius) }
+        indices.iter().zip(weights).map(|(&i, w)| (mm_(fov().voxel_centre1(i).x), w)).collect()
+    }
+
+    #[test]
+    fn asymmetric_cutoff_truncates_towards_p1() {
+        // 1 sigma towards p1, 3 sigma towards p2
+        let row = row(TofCutoff::asymmetric(ratio(1.0), ratio(3.0)));
+        let nonzero = |lo: f32, hi: f32| row.iter().filter(|(x, _)| lo < *x && *x < hi).all(|&(_, w)| w > 0.0);
+        let zero    = |lo: f32, hi: f32| row.iter().filter(|(x, _)| lo < *x && *x < hi).all(|&(_, w)| w == 0.0);
+        assert!(nonzero(-25.0, 85.0));
+        assert!(zero(-100.0, -35.0));
+        assert!(zero(  95.0, 100.0));
+    }
+
+    #[test]
+    fn bore_radius_drops_outer_voxels() {
+        let row = row(TofCutoff::symmetric(ratio(10.0)).with_bore_radius(mm(50.0)));
+        assert_eq!(row.len(), 50);
+        assert!(row.iter().all(|(x, _)| x.abs() <= 50.0));
+    }
+}
+
+#[cfg(test)]
+mod test_corrections {
+    use super::*;
+    use crate::Point;
+    use crate::system_matrix::Corrections;
+    use geometry::units::{mm, ns, ratio};
+    use float_eq::assert_float_eq;
+
+    /// A row of 3 voxels of 1 mm, and a LOR running along it: the forward
+    /// projection of an image of ones is 3 (mm).
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(3.0), mm(1.0), mm(1.0)), (3, 1, 1)) }
+
+    fn lor(corrections: Corrections) -> LOR {
+        LOR::new(ns(0.0), ns(0.0), Point::new(mm(-10.0), mm(0.0), mm(0.0)), Point::new(mm(10.0), mm(0.0), mm(0.0)))
+            .with_corrections(corrections)
+    }
+
+    fn forward_projection(lor: &LOR) -> f32 {
+        let notof = make_gauss_option(None, None);
+        let (mut indices, mut weights) = (vec![], vec![]);
+        assert!(system_matrix_row(lor, fov(), &notof, None, &mut indices, &mut weights));
+        lor.corrections.forward(forward_project(&weights, &indices, &Image::ones(fov())))
+    }
+
+    fn one_iteration(lor: LOR) -> Vec<f32> {
+        Image::mlem(fov(), &[lor], None, None, None, None, 1).next().unwrap().0.data
+    }
+
+    #[test]
+    fn identity_reproduces_uncorrected_projection() {
+        assert_float_eq!(forward_projection(&lor(Corrections::NONE)), 3.0, abs <= 1e-5);
+    }
+
+    #[test]
+    fn multiplicative_scales_forward_projection() {
+        let half = Corrections::new(ratio(0.5), 0.0).unwrap();
+        assert_float_eq!(forward_projection(&lor(half)), 1.5, abs <= 1e-5);
+        // ... but, in a single LOR, cancels between forward and back projection
+        assert_float_eq!(one_iteration(lor(half)), one_iteration(lor(Corrections::NONE)), abs_all <= 1e-5);
+    }
+
+    #[test]
+    fn additive_increases_denominator() {
+        let two = Corrections::new(ratio(1.0), 2.0).unwrap();
+        assert_float_eq!(forward_projection(&lor(two)), 3.0 + 2.0, abs <= 1e-5);
+        // Each voxel: 1 (image) * 1 mm (weight) / 5 (projection) * 1 (sensitivity)
+        assert_float_eq!(one_iteration(lor(two)), vec![0.2; 3], abs_all <= 1e-5);
+        assert_float_eq!(one_iteration(lor(Corrections::NONE)), vec![1.0 / 3.0; 3], abs_all <= 1e-5);
+    }
+
+    #[test]
+    fn invalid_corrections_are_rejected() {
+        assert!(Corrections::new(ratio( 0.0     ), 0.0).is_err());
+        assert!(Corrections::new(ratio(-1.0     ), 0.0).is_err());
+        assert!(Corrections::new(ratio(f32::NAN ), 0.0).is_err());
+        assert!(Corrections::new(ratio(f32::INFINITY), 0.0).is_err());
+        assert!(Corrections::new(ratio( 1.0     ), -1.0).is_err());
+        assert!(Corrections::new(ratio( 1.0     ), f32::NAN).is_err());
+        assert!(Corrections::NONE.validate().is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test_focus {
+    use super::*;
+    use crate::Point;
+    use crate::utils::Region;
+    use geometry::units::{mm, ns};
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (5, 5, 5)) }
+
+    /// LORs parallel to each axis, through the centre of every row of voxels,
+    /// and a few diagonals, so that the image is not uniform after an iteration
+    fn lors() -> Vec<LOR> {
+        let (far, c) = (50.0, |i: usize| -4.0 + 2.0 * i as f32);
+        let lor = |(x1, y1, z1), (x2, y2, z2)| LOR::new(ns(0.0), ns(0.0), Point::new(mm(x1), mm(y1), mm(z1)), Point::new(mm(x2), mm(y2), mm(z2)));
+        let mut lors = vec![];
+        for i in 0..5 {
+            for j in 0..5 {
+                let (a, b) = (c(i), c(j));
+                lors.push(lor((-far, a, b), (far, a, b)));
+                lors.push(lor((a, -far, b), (a, far, b)));
+                lors.push(lor((a, b, -far), (a, b, far)));
+            }
+            lors.push(lor((-far, -far, c(i)), (far, far, c(i))));
+        }
+        lors
+    }
+
+    fn initial() -> Image {
+        let mut image = Image::ones(fov());
+        for (i, v) in image.data.iter_mut().enumerate() { *v = 1.0 + (i % 7) as f32 / 10.0 }
+        image
+    }
+
+    fn fifth(initial: Image, focus: Option<Vec<bool>>) -> Image {
+        let lors = lors();
+        Image::mlem_with(initial, &lors, None, None, None, None, 1, MlemOptions { focus, ..Default::default() }).nth(4).unwrap().image
+    }
+
+    #[test]
+    fn focus_on_whole_fov_matches_normal_mlem() {
+        let everything: Region = "box:-5,-5,-5,5,5,5".parse().unwrap();
+        let lors = lors();
+        let normal = Image::mlem(fov(), &lors, None, None, None, None, 1).nth(4).unwrap().0;
+        let focused = fifth(Image::ones(fov()), Some(everything.mask(fov())));
+        assert_eq!(focused.data, normal.data);
+    }
+
+    #[test]
+    fn voxels_outside_focus_are_fixed() {
+        let focus = "sphere:0,0,0,2.5".parse::<Region>().unwrap().mask(fov());
+        let initial = initial();
+        let image = fifth(initial.clone(), Some(focus.clone()));
+        let mut changed_inside = 0;
+        for ((&inside, &before), &after) in focus.iter().zip(&initial.data).zip(&image.data) {
+            if inside { if after != before { changed_inside += 1 } }
+            else      { assert_eq!(after.to_bits(), before.to_bits()) }
+        }
+        let n_inside = focus.iter().filter(|&&inside| inside).count();
+        assert!(n_inside > 1 && n_inside < focus.len());
+        assert_eq!(changed_inside, n_inside);
+    }
+}
+
+#[cfg(test)]
+mod test_data_sensitivity {
+    use super::*;
+    use crate::Point;
+    use geometry::units::{mm, mm_, ns, ratio};
+    use crate::cylindrical::ASYMMETRY_WARNING;
+    use float_eq::assert_float_eq;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::f32::consts::TAU;
+
+    const DETECTOR_RADIUS: f32 = 50.0;
+    const DETECTOR_LENGTH: f32 = 100.0;
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(20.0), mm(20.0), mm(60.0)), (5, 5, 12)) }
+
+    /// LOR with endpoints on the detector cylinder, passing through `(x,y,z)`
+    /// in direction `(dx,dy,dz)`, if both endpoints lie within its length.
+    fn detected(x: f32, y: f32, z: f32, dx: f32, dy: f32, dz: f32) -> Option<LOR> {
+        // Solve |(x,y) + t (dx,dy)| = R for t
+        let a = dx*dx + dy*dy;
+        let b = 2.0 * (x*dx + y*dy);
+        let c = x*x + y*y - DETECTOR_RADIUS * DETECTOR_RADIUS;
+        let root = (b*b - 4.0*a*c).sqrt();
+        let (t1, t2) = ((-b - root) / (2.0*a), (-b + root) / (2.0*a));
+        let end = |t: f32| (x + t*dx, y + t*dy, z + t*dz);
+        let ((x1, y1, z1), (x2, y2, z2)) = (end(t1), end(t2));
+        let half = DETECTOR_LENGTH / 2.0;
+        if z1.abs() > half || z2.abs() > half { return None }
+        let p = |x, y, z| Point::new(mm(x), mm(y), mm(z));
+        Some(LOR::new(ns(0.0), ns(0.0), p(x1, y1, z1), p(x2, y2, z2)))
+    }
+
+    fn isotropic_direction(rng: &mut StdRng) -> (f32, f32, f32) {
+        let cos_theta: f32 = rng.gen_range(-1.0..1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = rng.gen_range(0.0..TAU);
+        (sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    /// Detected LORs from `n` decays uniformly distributed throughout the FOV
+    fn uniform_source_lors(n: usize, seed: u64) -> Vec<LOR> {
+        let hx = mm_(fov().half_width.x);
+        box_source_lors(n, seed, -hx..hx)
+    }
+
+    /// Detected LORs from `n` decays uniformly distributed throughout the part
+    /// of the FOV with `x` in `xs`
+    fn box_source_lors(n: usize, seed: u64, xs: std::ops::Range<f32>) -> Vec<LOR> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let h = fov().half_width;
+        let (hy, hz) = (mm_(h.y), mm_(h.z));
+        (0..n).filter_map(|_| {
+            let (x, y, z) = (rng.gen_range(xs.clone()), rng.gen_range(-hy..hy), rng.gen_range(-hz..hz));
+            let (dx, dy, dz) = isotropic_direction(&mut rng);
+            detected(x, y, z, dx, dy, dz)
+        }).collect()
+    }
+
+    /// Detector-only LORs sampled as in `make_sensitivity_image`: both
+    /// endpoints uniformly on the cylinder, keeping those that cross the FOV
+    fn analytic_lors(n: usize, seed: u64) -> Vec<LOR> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let fov = fov();
+        let mut point = || {
+            let theta = rng.gen_range(0.0..TAU);
+            let z = rng.gen_range(-DETECTOR_LENGTH / 2.0..DETECTOR_LENGTH / 2.0);
+            Point::new(mm(DETECTOR_RADIUS * theta.cos()), mm(DETECTOR_RADIUS * theta.sin()), mm(z))
+        };
+        let mut lors = vec![];
+        while lors.len() < n {
+            let (p1, p2) = (point(), point());
+            if fov.entry(p1, p2).is_some() { lors.push(LOR::new(ns(0.0), ns(0.0), p1, p2)) }
+        }
+        lors
+    }
+
+    /// Mean value in each z-slice
+    fn axial_profile(image: &Image) -> Vec<f32> {
+        let [nx, ny, nz] = image.fov.n;
+        (0..nz).map(|z| {
+            let slice = &image.data[z*nx*ny..(z+1)*nx*ny];
+            slice.iter().sum::<f32>() / slice.len() as f32
+        }).collect()
+    }
+
+    #[test]
+    fn data_sensitivity_matches_analytic() {
+        // For a uniform source filling the FOV, the backprojection of the
+        // measured data has the same shape as the scanner sensitivity.
+        let data = Image::data_sensitivity_image(fov(), &uniform_source_lors(200_000, 1), None, false, false);
+        let lors = analytic_lors(50_000, 2);
+        let analytic = Image::sensitivity_image(Image::empty(fov()), lors.par_iter().copied(), lors.len(), AreaPerMass::ZERO)
+            .sensitivity_correction(None);
+        let (d, a) = (axial_profile(&data), axial_profile(&analytic));
+        for (d, a) in d.iter().zip(a.iter()) {
+            assert!((d - a).abs() / a < 0.1, "data {d} vs analytic {a}");
+        }
+    }
+
+    #[test]
+    fn data_sensitivity_removes_axial_bias() {
+        let lors = uniform_source_lors(100_000, 3);
+        let reconstruct = |sensitivity: Option<Image>| {
+            let image = Image::mlem(fov(), &lors, None, None, None, sensitivity, 1).nth(9).unwrap().0;
+            let profile = axial_profile(&image);
+            // Ratio of outermost to central slices: 1 if unbiased
+            let n = profile.len();
+            (profile[0] + profile[n-1]) / (profile[n/2 - 1] + profile[n/2])
+        };
+        let ones = reconstruct(None);
+        let data = reconstruct(Some(Image::data_sensitivity_image(fov(), &lors, Some(mm(5.0)), false, false)));
+        assert!(ones < 0.8, "ones sensitivity should show axial bias: {ones}");
+        assert!((data - 1.0).abs() < (ones - 1.0).abs() / 2.0, "data {data} vs ones {ones}");
+    }
+
+    /// Ratio of the mean of the voxels on the faces of the FOV, to the mean of
+    /// the voxels inside it: 1 if unbiased
+    fn edge_to_centre(image: &Image) -> f32 {
+        let [nx, ny, nz] = image.fov.n;
+        let (mut edge, mut centre) = ((0.0, 0), (0.0, 0));
+        for (i, &v) in image.data.iter().enumerate() {
+            let [x, y, z] = crate::index::index1_to_3(i, image.fov.n);
+            let on_face = x == 0 || y == 0 || z == 0 || x == nx-1 || y == ny-1 || z == nz-1;
+            let roi = if on_face { &mut edge } else { &mut centre };
+            roi.0 += v;
+            roi.1 += 1;
+        }
+        (edge.0 / edge.1 as f32) / (centre.0 / centre.1 as f32)
+    }
+
+    /// Backprojections of `lors`, voxel by voxel and assuming rotational symmetry
+    fn backprojections(lors: &[LOR]) -> (Image, Image, f32) {
+        let project = || lors.par_iter().copied();
+        let full = Image::sensitivity_image(Image::empty(fov()), project(), lors.len(), AreaPerMass::ZERO);
+        let (symmetric, asymmetry) = Image::sensitivity_image_symmetric(Image::empty(fov()), project(), lors.len(), AreaPerMass::ZERO, false);
+        (full, symmetric, asymmetry)
+    }
+
+    fn variance(values: impl Iterator<Item = f32> + Clone) -> f32 {
+        let n = values.clone().count() as f32;
+        let mean = values.clone().sum::<f32>() / n;
+        values.map(|v| (v - mean).powi(2)).sum::<f32>() / n
+    }
+
+    #[test]
+    fn symmetric_sensitivity_is_the_azimuthal_average_with_less_noise() {
+        let (full_a, symmetric_a, asymmetry) = backprojections(&analytic_lors(50_000, 5));
+        let (full_b, symmetric_b, _)         = backprojections(&analytic_lors(50_000, 6));
+        assert!(asymmetry < ASYMMETRY_WARNING, "{asymmetry}");
+        // Same LORs: exactly the ring averages of the full computation
+        let averaged = CylindricalImage::from_image(&full_a).broadcast(fov());
+        assert_float_eq!(symmetric_a.data, averaged.data, rmax_all <= 1e-4);
+        // Independent LORs: the differences are pure noise
+        let noise = |a: &Image, b: &Image| variance(a.data.iter().zip(&b.data).map(|(a, b)| a - b));
+        let ratio = noise(&full_a, &full_b) / noise(&symmetric_a, &symmetric_b);
+        assert!(ratio > 2.5, "variance ratio {ratio}");
+    }
+
+    #[test]
+    fn asymmetric_source_is_flagged() {
+        let hx = mm_(fov().half_width.x);
+        let (_, asymmetry) = Image::data_sensitivity_image_symmetric(fov(), &box_source_lors(50_000, 7, 0.0..hx), None, false, false);
+        assert!(asymmetry > ASYMMETRY_WARNING, "{asymmetry}");
+    }
+
+    #[test]
+    fn chord_normalization_reduces_edge_bias() {
+        let lors = uniform_source_lors(50_000, 4);
+        let reconstruct = |normalize_chord| {
+            let tube = Tube { radius: mm(0.0), samples: 1, normalize_chord };
+            let sensitivity = Image::data_sensitivity_image(fov(), &lors, None, normalize_chord, false);
+            let image = Image::mlem(fov(), &lors, None, None, Some(tube), Some(sensitivity), 1).nth(4).unwrap().0;
+            edge_to_centre(&image)
+        };
+        let (off, on) = (reconstruct(false), reconstruct(true));
+        assert!((on - 1.0).abs() < (off - 1.0).abs(), "normalized {on} vs unnormalized {off}");
+        assert!((on - 1.0).abs() < 0.01, "normalized {on}");
+    }
+
+    #[test]
+    fn weighting_counts_lors_in_proportion_to_their_normalization() {
+        let lors = uniform_source_lors(20_000, 8);
+        // Doubling the normalization of a LOR is the same as detecting it twice
+        let doubled = |lor: &LOR| mm_(lor.p1.x) > 0.0;
+        let weighted = lors.iter().map(|&lor| if doubled(&lor) {
+            LOR { corrections: Corrections { multiplicative: ratio(2.0), ..lor.corrections }, ..lor }
+        } else { lor }).collect::<Vec<_>>();
+        let repeated = lors.iter().chain(lors.iter().filter(|lor| doubled(lor))).copied().collect::<Vec<_>>();
+        let expected = Image::data_sensitivity_image(fov(), &repeated, None, false, false);
+        assert_float_eq!(Image::data_sensitivity_image(fov(), &weighted, None, false, true ).data, expected.data, rmax_all <= 1e-3);
+        // Unweighted, the normalization is ignored
+        let plain = Image::data_sensitivity_image(fov(), &lors, None, false, false);
+        assert_float_eq!(Image::data_sensitivity_image(fov(), &weighted, None, false, false).data, plain.data, rmax_all <= 1e-6);
+    }
+}
+
+#[cfg(test)]
+mod test_coverage {
+    use super::*;
+    use crate::Point;
+    use geometry::units::{mm, ns};
+    use float_eq::assert_float_eq;
+
+    /// 2×2×5 voxels of 2 mm
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(4.0), mm(4.0), mm(10.0)), (2, 2, 5)) }
+
+    /// Along z, through the centres of the voxels with `(ix, iy) = (0, 1)`
+    fn axial_lor() -> LOR {
+        LOR::new(ns(0.0), ns(0.0), Point::new(mm(-1.0), mm(1.0), mm(-30.0)), Point::new(mm(-1.0), mm(1.0), mm(30.0)))
+    }
+
+    #[test]
+    fn axial_lor_covers_its_column() {
+        let image = Image::coverage_image(fov(), &[axial_lor(), axial_lor()], CoverageWeight::Count);
+        for ix in 0..2 { for iy in 0..2 { for iz in 0..5 {
+            let expected = if (ix, iy) == (0, 1) { 2.0 } else { 0.0 };
+            assert_eq!(image[[ix, iy, iz]], expected, "voxel {:?}", [ix, iy, iz]);
+        }}}
+    }
+
+    #[test]
+    fn chords_add_up_to_the_length_in_the_fov() {
+        let image = Image::coverage_image(fov(), &[axial_lor()], CoverageWeight::Chord);
+        assert_float_eq!(image.data.iter().sum::<f32>(), 10.0, abs <= 1e-4);
+        let diagonal = LOR::new(ns(0.0), ns(0.0), Point::new(mm(-20.0), mm(-20.0), mm(0.3)), Point::new(mm(20.0), mm(20.0), mm(0.3)));
+        let image = Image::coverage_image(fov(), &[diagonal], CoverageWeight::Chord);
+        assert_float_eq!(image.data.iter().sum::<f32>(), 4.0 * 2.0_f32.sqrt(), abs <= 1e-4);
+    }
+}
+
+#[cfg(test)]
+mod test_analytic {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use crate::system_matrix::Corrections;
+    use geometry::units::ratio;
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    /// `system` with a `(multiplicative, additive)` correction on each bin
+    fn corrected(system: AnalyticSystem, terms: &[(f32, f32)]) -> AnalyticSystem {
+        let corrections: Vec<_> = terms.iter()
+            .map(|&(m, a)| Corrections::new(ratio(m), a).unwrap())
+            .collect();
+        system.with_corrections(&corrections)
+    }
+
+    fn one_d_additive() -> AnalyticSystem {
+        corrected(AnalyticSystem::one_d(), &[(1.0, 1.0), (1.0, 1.0), (1.0, 1.0), (1.0, 2.0)])
+    }
+
+    fn one_d_normalized() -> AnalyticSystem {
+        corrected(AnalyticSystem::one_d(), &[(2.0, 0.0), (2.0, 0.0), (2.0, 0.0), (0.5, 0.0)])
+    }
+
+    fn two_d_both() -> AnalyticSystem {
+        corrected(AnalyticSystem::two_d(), &[(2.0, 0.0), (1.0, 1.0), (0.5, 0.0), (0.5, 2.0), (3.0, 1.0)])
+    }
+
+    fn iterations(system: &AnalyticSystem, lors: &[LOR], n: usize) -> Vec<(Image, usize, usize)> {
+        Image::mlem(system.fov, lors, None, None, None, Some(system.sensitivity_image()), 1)
+            .take(n)
+            .collect()
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::additive  (one_d_additive()),
+             case::normalized(one_d_normalized()),
+             case::two_d_both(two_d_both()),
+             case::two_d_hot (AnalyticSystem::two_d_hot()),
+    )]
+    fn fixture_matrix_matches_projector(system: AnalyticSystem) {
+        let notof = make_gauss_option(None, None);
+        let (mut indices, mut weights) = (vec![], vec![]);
+        for (lor, expected) in system.bins.iter().zip(&system.matrix) {
+            assert!(system_matrix_row(lor, system.fov, &notof, None, &mut indices, &mut weights));
+            let mut row = vec![0.0; expected.len()];
+            for (&i, &w) in indices.iter().zip(&weights) { row[i] += w }
+            assert_float_eq!(row, *expected, abs_all <= 1e-5);
+        }
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::additive  (one_d_additive()),
+             case::normalized(one_d_normalized()),
+             case::two_d_both(two_d_both()),
+    )]
+    fn solution_is_a_fixed_point(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let solution = Image::new(system.fov, system.solution.clone());
+        let next = Image::mlem_with(solution, &lors, None, None, None, Some(system.sensitivity_image()), 1, MlemOptions::default())
+            .next().unwrap().image;
+        assert_float_eq!(next.data, system.solution, rmax_all <= 1e-5);
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::additive  (one_d_additive()),
+             case::normalized(one_d_normalized()),
+             case::two_d_both(two_d_both()),
+             case::two_d_hot (AnalyticSystem::two_d_hot()),
+    )]
+    fn mlem_converges_to_solution(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let (image, _, _) = iterations(&system, &lors, 200).pop().unwrap();
+        assert!(system.max_relative_error(&image.data) < 1e-4,
+                "{:?} != {:?}", image.data, system.solution);
+    }
+
+    #[test]
+    fn one_d_first_iteration_by_hand() {
+        let system = AnalyticSystem::one_d();
+        let lors = system.measured_lors();
+        let (image, _, _) = iterations(&system, &lors, 1).pop().unwrap();
+        assert_float_eq!(image.data, vec![1.5, 2.0, 2.5], abs_all <= 1e-5);
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::additive  (one_d_additive()),
+             case::normalized(one_d_normalized()),
+             case::two_d_both(two_d_both()),
+    )]
+    fn osem_with_one_subset_follows_reference(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let osem = iterations(&system, &lors, 30);
+        for (k, ((image, iteration, subset), reference)) in osem.into_iter().zip(system.reference_mlem()).enumerate() {
+            assert_eq!((iteration, subset), (k + 1, 1));
+            let reference: Vec<f32> = reference.into_iter().map(|x| x as f32).collect();
+            assert_float_eq!(image.data, reference, rmax_all <= 1e-4);
+        }
+    }
+
+    #[test]
+    fn osem_with_one_subset_is_mlem() {
+        let system = AnalyticSystem::two_d_hot();
+        let lors = system.measured_lors();
+        let osem: Vec<_> = Image::osem(system.fov, &lors, Some(system.sensitivity_image()), 1).take(5).collect();
+        let mlem: Vec<_> = iterations(&system, &lors, 5);
+        for ((o, o_iteration, o_subset), (m, m_iteration, m_subset)) in osem.into_iter().zip(mlem) {
+            assert_eq!((o_iteration, o_subset), (m_iteration, m_subset));
+            assert_eq!(o.data, m.data);
+        }
+    }
+
+    #[test]
+    fn more_subsets_converge_in_fewer_passes() {
+        let system = AnalyticSystem::two_d_hot();
+        // Every event twice in a row, so that each of two interleaved subsets
+        // holds exactly half of every bin, and the solution is doubled
+        let lors: Vec<LOR> = system.measured_lors().into_iter().flat_map(|lor| [lor, lor]).collect();
+        let solution: Vec<f32> = system.solution.iter().map(|x| 2.0 * x).collect();
+        let error = |image: &Image| image.data.iter().zip(&solution)
+            .map(|(x, s)| ((x - s) / s).abs())
+            .fold(0.0, f32::max);
+        let passes = 5;
+        let mlem: Vec<_> = Image::osem(system.fov, &lors, Some(system.sensitivity_image()), 1).take(2 * passes).collect();
+        let osem: Vec<_> = Image::osem(system.fov, &lors, Some(system.sensitivity_image()), 2).take(2 * passes).collect();
+        for pass in 1..=passes {
+            let (after_pass, iteration, subset) = &osem[2 * pass - 1];
+            assert_eq!((*iteration, *subset), (pass, 2));
+            // Each subset update is a full MLEM update ...
+            assert_float_eq!(after_pass.data, mlem[2 * pass - 1].0.data, rmax_all <= 1e-4);
+            // ... so OSEM gets closer to the solution in the same number of passes
+            assert!(error(after_pass) < error(&mlem[pass - 1].0), "pass {pass}");
+        }
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::additive  (one_d_additive()),
+             case::normalized(one_d_normalized()),
+             case::two_d_both(two_d_both()),
+    )]
+    fn likelihood_never_decreases(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let mut previous = system.log_likelihood(&Image::ones(system.fov).data);
+        for (image, _, _) in iterations(&system, &lors, 50) {
+            let current = system.log_likelihood(&image.data);
+            assert!(current >= previous - 1e-9 * previous.abs(), "{current} < {previous}");
+            previous = current;
+        }
+    }
+
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::two_d_hot (AnalyticSystem::two_d_hot()),
+    )]
+    fn convergence_is_reported_with_every_image(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let options = MlemOptions { convergence: true, ..Default::default() };
+        let images: Vec<_> = Image::mlem_with(Image::ones(system.fov), &lors, None, None, None, Some(system.sensitivity_image()), 1, options)
+            .take(200)
+            .map(|step| (step.image, step.convergence.unwrap()))
+            .collect();
+        for (k, pair) in images.windows(2).enumerate() {
+            let [(_, before), (image, after)] = [&pair[0], &pair[1]];
+            assert_eq!((after.iteration, after.subset), (k + 2, 1));
+            assert!(after.log_likelihood >= before.log_likelihood - 1e-9 * before.log_likelihood.abs(),
+                    "iteration {}: {} < {}", after.iteration, after.log_likelihood, before.log_likelihood);
+            assert_eq!(after.max_change, max_relative_change(&pair[0].0.data, &image.data));
+        }
+        let (first, last) = (images[0].1, images[199].1);
+        assert!(first.max_change > 0.1, "{first:?}");
+        assert!(last.max_change < 1e-4, "{last:?}");
+    }
+
+    // Without additive terms, every iteration matches the sensitivity-weighted
+    // total activity to the number of events
+    #[rstest(system,
+             case::one_d     (AnalyticSystem::one_d()),
+             case::two_d     (AnalyticSystem::two_d()),
+             case::normalized(one_d_normalized()),
+    )]
+    fn iterations_conserve_counts(system: AnalyticSystem) {
+        let lors = system.measured_lors();
+        let sensitivity = system.sensitivity();
+        for (image, _, _) in iterations(&system, &lors, 10) {
+            let total: f64 = image.data.iter().zip(&sensitivity).map(|(&x, &s)| x as f64 * s).sum();
+            assert_float_eq!(total, lors.len() as f64, rmax <= 1e-5);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_variance {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use float_eq::assert_float_eq;
+
+    fn variance_at_solution(system: &AnalyticSystem, sensitivity: Option<&Image>) -> Vec<f32> {
+        let solution = Image::new(system.fov, system.solution.clone());
+        solution.variance_estimate(&system.measured_lors(), sensitivity, None, None, None).data
+    }
+
+    #[test]
+    fn one_d_matches_fisher_diagonal_by_hand() {
+        // Voxel j is seen by its own bin (y_j = x_j) and the bin through all
+        // three (y = 6): F_jj = 1 / x_j + 1 / 6
+        let system = AnalyticSystem::one_d();
+        let expected = vec![1.0 / (1.0 + 1.0 / 6.0), 1.0 / (0.5 + 1.0 / 6.0), 1.0 / (1.0 / 3.0 + 1.0 / 6.0)];
+        assert_float_eq!(variance_at_solution(&system, None), expected, rmax_all <= 1e-5);
+    }
+
+    #[test]
+    fn hot_voxels_have_larger_absolute_smaller_relative_variance() {
+        let system = AnalyticSystem::two_d_hot();
+        let variance = variance_at_solution(&system, None);
+        let relative: Vec<_> = variance.iter().zip(&system.solution).map(|(v, x)| v / x).collect();
+        // Hot voxel: F = 1/17 + 1/17 + 1/16
+        assert_float_eq!(variance[0], 1.0 / (2.0 / 17.0 + 1.0 / 16.0), rmax <= 1e-5);
+        for cold in 1..4 {
+            assert!(variance[0] > variance[cold], "{variance:?}");
+            assert!(relative[0] < relative[cold], "{relative:?}");
+        }
+    }
+
+    #[test]
+    fn zero_expectations_and_insensitive_voxels_give_zero() {
+        let system = AnalyticSystem::one_d();
+        let lors = system.measured_lors();
+        let empty = Image::empty(system.fov);
+        assert_eq!(empty.variance_estimate(&lors, None, None, None, None).data, vec![0.0; 3]);
+
+        let mut sensitivity = system.sensitivity_image();
+        sensitivity.data[1] = 0.0;
+        let variance = variance_at_solution(&system, Some(&sensitivity));
+        assert_eq!(variance[1], 0.0);
+        assert!(variance[0] > 0.0 && variance[2] > 0.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Ratio;
     use geometry::{units::{mm, mm_, ns, ratio, turn, turn_}, Angle};
     use rstest::{rstest, fixture};
     use float_eq::assert_float_eq;
@@ -470,8 +1747,7 @@ mod tests {
                 Points::Two { x1, y1, x2, y2 } => {
                     lors.push(LOR::from_components((ns(0.0), ns(0.0)),
                                                    (x1, y1, mm(0.0)),
-                                                   (x2, y2, mm(0.0)),
-                                                   ratio(1.0)))
+                                                   (x2, y2, mm(0.0))))
                 },
                 _ => panic!("LOR does not cross detector at two points.")
             }
@@ -530,7 +1806,7 @@ mod tests {
         std::fs::create_dir_all(PathBuf::from(&directory)).unwrap();
         move |(image, iteration, subset)| {
             let image_path = PathBuf::from(format!("{directory}/{iteration:02}-{subset:02}.raw"));
-            crate::io::raw::Image3D::from(image).write_to_file(&image_path).unwrap();
+            image.write_to_raw_file(&image_path).unwrap();
         }
     }
 
@@ -628,7 +1904,7 @@ mod tests {
     fn fov() -> FOV {
         let n = 51;
         let l = mm(n as f32);
-        FOV::new((l, l, mm(1.0)),
+        FOV::new_from_full_widths((l, l, mm(1.0)),
                  (n, n,    1   ))
     }
 
@@ -644,7 +1920,7 @@ mod tests {
         // {
         //     let mklor = | &LOR { p1, p2, .. }, prompt | {
         //         let (E1, E2) = match prompt {
-        //             Prompt::True    => (511.0, 511.0),
+        //             Prompt::True    => (ELECTRON_REST_ENERGY, ELECTRON_REST_ENERGY),
         //             Prompt::Scatter => (450.0, 450.0),
         //             _ => panic!("Not expecting randoms"),
         //         };
@@ -699,14 +1975,14 @@ mod tests {
         // Annotate each LOR with additive correction taken from scattergam
         if let Some(sgram) = sgram {
             for mut lor in &mut lors {
-                lor.additive_correction = sgram.value(lor);
+                lor.corrections.scatter = sgram.value(lor);
             }
         }
 
         // Perform MLEM reconstruction, saving images to disk
         let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
         let _ = pool.install(|| {
-            Image::mlem(fov, &lors, None, None, None, 1)
+            Image::mlem(fov, &lors, None, None, None, None, 1)
                 .take(10)
                 .inspect(save_each_image_in(format!("test-mlem-images/{name}/")))
                 .for_each(|_| {
@@ -715,3 +1991,90 @@ mod tests {
         //assert!(false);
     }
 }
+
+#[cfg(test)]
+mod test_cancel {
+    use super::*;
+    use crate::cancel::OnCancel;
+    use crate::testing::AnalyticSystem;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    fn images<'a>(system: &AnalyticSystem, lors: &'a [LOR], cancel: Option<Cancel>) -> impl Iterator<Item = (Image, usize, usize)> + 'a {
+        Image::mlem_with(Image::ones(system.fov), lors, None, None, None, None, 1, MlemOptions { cancel, ..Default::default() })
+            .map(|MlemStep { image, iteration, subset, .. }| (image, iteration, subset))
+    }
+
+    #[test]
+    fn uncancelled_run_matches_mlem() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let cancel = Cancel::new(Arc::new(AtomicBool::new(false)));
+        let cancellable: Vec<_> = images(&system, &lors, Some(cancel)).take(3).map(|(image, _, _)| image.data).collect();
+        let plain      : Vec<_> = Image::mlem(system.fov, &lors, None, None, None, None, 1).take(3).map(|(image, _, _)| image.data).collect();
+        assert_eq!(cancellable, plain);
+    }
+
+    #[test]
+    fn no_images_after_cancellation() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let cancel = Cancel::new(Arc::new(AtomicBool::new(false)));
+        let mut images = images(&system, &lors, Some(cancel.clone()));
+        assert!(images.next().is_some());
+        cancel.request();
+        assert!(images.next().is_none());
+        assert!(images.next().is_none());
+    }
+
+    #[test]
+    fn abandoned_iteration_leaves_image_untouched() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let mut image = Image::ones(system.fov);
+        let sensitivity = vec![1.0; image.data.len()];
+        let finish = Cancel::new(Arc::new(AtomicBool::new(true)));
+        let abandon = finish.clone().on_cancel(OnCancel::Abandon);
+        assert!(!image.one_iteration(&lors, (0, 1), &sensitivity, None, None, None, None, None, Some(&abandon), Reduction::Fast));
+        assert_eq!(image.data, Image::ones(system.fov).data);
+        // Finishing ignores the flag
+        assert!(image.one_iteration(&lors, (0, 1), &sensitivity, None, None, None, None, None, Some(&finish), Reduction::Fast));
+        assert_ne!(image.data, Image::ones(system.fov).data);
+    }
+}
+
+#[cfg(test)]
+mod test_reduction {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use float_eq::assert_float_eq;
+
+    /// One iteration over several blocks of LORs, with `n_threads` threads
+    fn iterate(n_threads: usize, reduction: Reduction) -> ImageData {
+        let system = AnalyticSystem::two_d_hot();
+        let lors: Vec<LOR> = system.measured_lors().iter().cycle().take(3 * DETERMINISTIC_BLOCK + 123).copied().collect();
+        let sensitivity = system.sensitivity_image();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads).build().unwrap();
+        pool.install(|| {
+            let mut image = Image::ones(system.fov);
+            assert!(image.one_iteration(&lors, (0, 1), &sensitivity.data, None, None, None, None, None, None, reduction));
+            image.data
+        })
+    }
+
+    #[test]
+    fn deterministic_reduction_does_not_depend_on_threads() {
+        let serial = iterate(1, Reduction::Deterministic);
+        for n_threads in [2, 3, 8] {
+            assert_eq!(iterate(n_threads, Reduction::Deterministic), serial, "{n_threads} threads");
+        }
+        assert_float_eq!(iterate(4, Reduction::Fast), serial, rmax_all <= 1e-5);
+    }
+
+    #[test]
+    fn reduction_parses() {
+        assert_eq!("fast".parse(), Ok(Reduction::Fast));
+        assert_eq!("Deterministic".parse(), Ok(Reduction::Deterministic));
+        assert!("serial".parse::<Reduction>().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Normalization: the relative detection efficiencies of LORs, estimated from
+//! a scan of a uniform source, such as a cylinder filling the FOV, and applied
+//! as multiplicative corrections.
+//!
+//! The normalization is a product of components, each of which bins the LORs
+//! of the scan with a `Lorogram` and attributes the variation between its bins
+//! to the detector. A component is only meaningful along LOR coordinates in
+//! which the geometric response to the uniform source is flat, such as
+//! `lorogram::phi_within_module` for crystal interference.
+
+use crate::lorogram::{axis_phi_within_module, Lorogram};
+use crate::scanner::Scanner;
+use crate::system_matrix::LOR;
+use crate::Ratio;
+use geometry::units::ratio;
+
+/// Relative efficiencies of the bins of a `Lorogram`
+pub struct NormalizationComponent {
+    binning: Box<dyn Lorogram>,
+    /// Efficiency of each bin: its counts relative to the mean of the bins
+    /// which received any. 1 for the others.
+    efficiencies: Vec<f32>,
+}
+
+impl NormalizationComponent {
+    /// Derive the efficiencies of the bins of `binning`, which must be empty,
+    /// from the LORs of a uniform-source normalization scan
+    pub fn from_scan(binning: Box<dyn Lorogram>, lors: &[LOR]) -> Result<Self, String> {
+        let mut counts = vec![0_usize; binning.n_bins()];
+        for lor in lors {
+            if let Some(i) = binning.index(lor) { counts[i] += 1 }
+        }
+        let (total, filled) = counts.iter().filter(|&&c| c > 0).fold((0, 0), |(t, n), &c| (t + c, n + 1));
+        if filled == 0 { return Err("No normalization-scan LORs fall in any bin".into()) }
+        let mean = total as f32 / filled as f32;
+        let efficiencies = counts.iter().map(|&c| if c > 0 { c as f32 / mean } else { 1.0 }).collect();
+        Ok(Self { binning, efficiencies })
+    }
+
+    /// Crystal-interference component: `n_bins` bins of `phi_within_module`
+    pub fn crystal_interference(n_bins: usize, scanner: &Scanner, lors: &[LOR]) -> Result<Self, String> {
+        Self::from_scan(Box::new(ndhistogram::ndhistogram!(axis_phi_within_module(n_bins, scanner); usize)), lors)
+    }
+
+    /// Relative efficiency of `lor`: 1 outside the binning
+    pub fn efficiency(&self, lor: &LOR) -> Ratio {
+        ratio(self.binning.index(lor).map_or(1.0, |i| self.efficiencies[i]))
+    }
+}
+
+#[derive(Default)]
+pub struct Normalization {
+    pub components: Vec<NormalizationComponent>,
+}
+
+impl Normalization {
+    pub fn with(mut self, component: NormalizationComponent) -> Self {
+        self.components.push(component);
+        self
+    }
+
+    /// Product of the efficiencies of `lor` in all components
+    pub fn efficiency(&self, lor: &LOR) -> Ratio {
+        self.components.iter().fold(ratio(1.0), |e, c| e * c.efficiency(lor))
+    }
+
+    /// Scale the multiplicative correction of each LOR by its efficiency
+    pub fn apply(&self, lors: &mut [LOR]) {
+        for lor in lors {
+            lor.corrections.multiplicative *= self.efficiency(lor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_normalization {
+    use super::*;
+    use crate::lorogram::{mk_lor, phi_within_module};
+    use geometry::units::{radian_, ratio_};
+    use rand::{Rng, SeedableRng};
+    use std::f32::consts::TAU;
+
+    fn scanner() -> Scanner {
+        "radius = 350.0\nlength = 1000.0\n[modules]\nazimuthal = 12\naxial = 1".parse().unwrap()
+    }
+
+    /// Efficiency, repeating with every module, of LORs at `phi_within_module`
+    fn pattern(phi: f32, pitch: f32) -> f32 { 1.0 + 0.3 * (TAU * phi / pitch).sin() + 0.1 * (3.0 * TAU * phi / pitch).cos() }
+
+    /// LORs of a uniform cylinder of radius 100 mm, kept with the probability
+    /// given by the efficiency `pattern`
+    fn scan(n: usize, seed: u64, scanner: &Scanner) -> Vec<LOR> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let pitch = radian_(scanner.module_pitch());
+        let mut lors = vec![];
+        while lors.len() < n {
+            let (r, a, direction) = (100.0 * rng.gen::<f32>().sqrt(), TAU * rng.gen::<f32>(), TAU * rng.gen::<f32>());
+            let (x, y) = (r * a.cos(), r * a.sin());
+            let (dx, dy) = (300.0 * direction.cos(), 300.0 * direction.sin());
+            let lor = mk_lor(((x - dx, y - dy, 0.0), (x + dx, y + dy, 0.0)));
+            if rng.gen::<f32>() * 1.5 < pattern(phi_within_module(&lor, scanner), pitch) { lors.push(lor) }
+        }
+        lors
+    }
+
+    /// Variance, relative to the squared mean, of the weighted counts in the
+    /// bins of `phi_within_module`
+    fn relative_variance(lors: &[LOR], weight: impl Fn(&LOR) -> f32, n_bins: usize, scanner: &Scanner) -> f32 {
+        let pitch = radian_(scanner.module_pitch());
+        let mut bins = vec![0.0; n_bins];
+        for lor in lors {
+            let bin = ((phi_within_module(lor, scanner) / pitch * n_bins as f32) as usize).min(n_bins - 1);
+            bins[bin] += weight(lor);
+        }
+        let mean = bins.iter().sum::<f32>() / n_bins as f32;
+        bins.iter().map(|b| (b - mean).powi(2)).sum::<f32>() / n_bins as f32 / (mean * mean)
+    }
+
+    #[test]
+    fn crystal_interference_is_flattened() {
+        let scanner = scanner();
+        let n_bins = 16;
+        let calibration = scan(400_000, 1, &scanner);
+        let component = NormalizationComponent::crystal_interference(n_bins, &scanner, &calibration).unwrap();
+        let normalization = Normalization::default().with(component);
+
+        // An independent acquisition with the same detector
+        let mut data = scan(400_000, 2, &scanner);
+        let before = relative_variance(&data, |_| 1.0, n_bins, &scanner);
+        normalization.apply(&mut data);
+        let after = relative_variance(&data, |lor| 1.0 / ratio_(lor.corrections.multiplicative), n_bins, &scanner);
+        assert!(after * 10.0 < before, "before {before}, after {after}");
+    }
+
+    #[test]
+    fn efficiencies_average_to_one() {
+        let scanner = scanner();
+        let lors = scan(10_000, 3, &scanner);
+        let component = NormalizationComponent::crystal_interference(8, &scanner, &lors).unwrap();
+        // The cyclic axis has no overflow bins, and all 8 bins are filled
+        assert_eq!(component.efficiencies.len(), 8);
+        let mean = component.efficiencies.iter().sum::<f32>() / 8.0;
+        float_eq::assert_float_eq!(mean, 1.0, abs <= 1e-5);
+        assert!(NormalizationComponent::crystal_interference(8, &scanner, &[]).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Placement of image arrays in world coordinates, restricted to axis
+//! permutations and flips, so that images can be expressed in the frame of a
+//! reference image without interpolation.
+//!
+//! Array axis `a` of an image runs along world axis `axes[a]`, in its negative
+//! direction if `flips[a]`. The world position of voxel `i` is
+//!
+//! `origin + Σₐ direction[a] iₐ voxel_size[a]`
+//!
+//! where `origin` is the centre of voxel `[0, 0, 0]` (as in the `Offset` of
+//! MetaImage and the translation of NIfTI's sform).
+
+use crate::{Index3_u, Point};
+use crate::fov::FOV;
+use crate::image::Image;
+use crate::index::{index1_to_3, index3_to_1};
+use geometry::units::{mm, mm_};
+
+/// Axis permutation and flips relating array axes to world axes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Orientation {
+    /// World axis (0, 1, 2 for x, y, z) along which each array axis runs
+    pub axes: [usize; 3],
+    /// Whether each array axis runs against its world axis
+    pub flips: [bool; 3],
+}
+
+impl Default for Orientation {
+    fn default() -> Self { Self::IDENTITY }
+}
+
+impl Orientation {
+    pub const IDENTITY: Self = Self { axes: [0, 1, 2], flips: [false; 3] };
+
+    pub fn new(axes: [usize; 3], flips: [bool; 3]) -> Result<Self, String> {
+        let mut sorted = axes;
+        sorted.sort_unstable();
+        if sorted != [0, 1, 2] { return Err(format!("Orientation axes must be a permutation of 0, 1, 2, got {axes:?}")) }
+        Ok(Self { axes, flips })
+    }
+
+    /// Unit vector, in world coordinates, along each array axis
+    pub fn direction(&self) -> [[f32; 3]; 3] {
+        let mut direction = [[0.0; 3]; 3];
+        for a in 0..3 {
+            direction[a][self.axes[a]] = if self.flips[a] { -1.0 } else { 1.0 };
+        }
+        direction
+    }
+
+    /// The orientation whose `direction` is `direction`, which must be a
+    /// signed permutation to within `1e-3`
+    pub fn from_direction(direction: [[f32; 3]; 3]) -> Result<Self, String> {
+        let mut axes = [0; 3];
+        let mut flips = [false; 3];
+        for a in 0..3 {
+            let v = direction[a];
+            let w = (0..3).max_by(|&i, &j| v[i].abs().total_cmp(&v[j].abs())).unwrap();
+            let rest = (0..3).filter(|&i| i != w).map(|i| v[i].abs()).fold(0.0, f32::max);
+            if (v[w].abs() - 1.0).abs() > 1e-3 || rest > 1e-3 {
+                return Err(format!("Direction {v:?} of array axis {a} is not along a world axis"))
+            }
+            axes[a] = w;
+            flips[a] = v[w] < 0.0;
+        }
+        Self::new(axes, flips)
+    }
+
+    /// Array axis running along world axis `w`
+    fn array_axis(&self, w: usize) -> usize { self.axes.iter().position(|&a| a == w).unwrap() }
+}
+
+/// Comma-separated signed world axes of the array axes: `x,y,z` is the
+/// identity, `-y,x,z` a 90° rotation about z
+impl std::str::FromStr for Orientation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        if parts.len() != 3 { return Err(format!("Orientation '{s}' should have three comma-separated axes, such as x,-z,y")) }
+        let mut axes = [0; 3];
+        let mut flips = [false; 3];
+        for (a, part) in parts.iter().enumerate() {
+            let (flip, name) = match part.strip_prefix('-') { Some(name) => (true, name), None => (false, *part) };
+            axes[a] = match name { "x" => 0, "y" => 1, "z" => 2, _ => return Err(format!("Unknown axis '{part}' in orientation '{s}'")) };
+            flips[a] = flip;
+        }
+        Self::new(axes, flips)
+    }
+}
+
+impl std::fmt::Display for Orientation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let axis = |a: usize| format!("{}{}", if self.flips[a] { "-" } else { "" }, ["x", "y", "z"][self.axes[a]]);
+        write!(f, "{},{},{}", axis(0), axis(1), axis(2))
+    }
+}
+
+/// Orientation of an image's array and world position of its first voxel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub orientation: Orientation,
+    /// Centre of voxel `[0, 0, 0]`
+    pub origin: Point,
+}
+
+impl Frame {
+    /// The frame implied by `fov` alone: identity orientation, centred on the
+    /// world origin
+    pub fn standard(fov: &FOV) -> Self {
+        Self { orientation: Orientation::IDENTITY, origin: fov.voxel_centre([0, 0, 0]) }
+    }
+
+    /// World position of the centre of voxel `i` of an array with voxels of
+    /// `fov.voxel_size`
+    pub fn world(&self, fov: &FOV, i: Index3_u) -> Point {
+        let direction = self.orientation.direction();
+        let mut p = [mm_(self.origin.x), mm_(self.origin.y), mm_(self.origin.z)];
+        for a in 0..3 {
+            let step = i[a] as f32 * mm_(fov.voxel_size[a]);
+            for (w, coordinate) in p.iter_mut().enumerate() { *coordinate += direction[a][w] * step }
+        }
+        Point::new(mm(p[0]), mm(p[1]), mm(p[2]))
+    }
+}
+
+impl Image {
+    /// Attach `frame` to the image, without changing its voxels
+    pub fn with_frame(mut self, frame: Frame) -> Self {
+        self.frame = frame;
+        self
+    }
+
+    /// World position of the centre of voxel `i`, according to `self.frame`
+    pub fn world(&self, i: Index3_u) -> Point { self.frame.world(&self.fov, i) }
+
+    /// The same image, with its array permuted and flipped so that it has
+    /// `target` orientation. Every voxel keeps its value and world position:
+    /// no interpolation takes place.
+    pub fn reorient(&self, target: Orientation) -> Image {
+        let source = self.frame.orientation;
+        // Source array axis providing each target array axis, and whether it is reversed
+        let from: [usize; 3] = [0, 1, 2].map(|a| source.array_axis(target.axes[a]));
+        let reversed: [bool; 3] = [0, 1, 2].map(|a| target.flips[a] != source.flips[from[a]]);
+        let n = self.fov.n;
+        let new_n = from.map(|b| n[b]);
+        let widths = from.map(|b| self.fov.half_width[b] * 2.0);
+        let fov = FOV::new_from_full_widths((widths[0], widths[1], widths[2]), (new_n[0], new_n[1], new_n[2]));
+
+        let source_index = |j: Index3_u| {
+            let mut i = [0; 3];
+            for a in 0..3 {
+                let b = from[a];
+                i[b] = if reversed[a] { n[b] - 1 - j[a] } else { j[a] };
+            }
+            i
+        };
+        let data = (0..self.data.len())
+            .map(|j| self.data[index3_to_1(source_index(index1_to_3(j, new_n)), n)])
+            .collect();
+        let origin = self.world(source_index([0, 0, 0]));
+        Image::new(fov, data).with_frame(Frame { orientation: target, origin })
+    }
+}
+
+#[cfg(test)]
+mod test_reorient {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    fn image() -> Image {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(9.0), mm(20.0)), (2, 3, 4));
+        Image::new(fov, (0..24).map(|i| i as f32).collect())
+    }
+
+    fn coordinates(p: Point) -> [f32; 3] { [mm_(p.x), mm_(p.y), mm_(p.z)] }
+
+    #[test]
+    fn standard_frame_matches_fov() {
+        let image = image();
+        for i in 0..24 {
+            let i3 = index1_to_3(i, image.fov.n);
+            assert_float_eq!(coordinates(image.world(i3)), coordinates(image.fov.voxel_centre(i3)), abs_all <= 1e-5);
+        }
+    }
+
+    #[rstest]
+    #[case("x,y,z")]
+    #[case("-x,y,z")]
+    #[case("y,x,z")]
+    #[case("-y,x,z")]
+    #[case("z,-x,-y")]
+    #[case("-z,-y,-x")]
+    fn voxels_keep_their_world_positions(#[case] target: Orientation) {
+        let original = image();
+        let reoriented = original.reorient(target);
+        assert_eq!(reoriented.frame.orientation, target);
+        for j in 0..24 {
+            let j3 = index1_to_3(j, reoriented.fov.n);
+            let value = reoriented.data[j];
+            let i3 = index1_to_3(value as usize, original.fov.n);
+            assert_float_eq!(coordinates(reoriented.world(j3)), coordinates(original.world(i3)), abs_all <= 1e-5);
+        }
+    }
+
+    #[rstest]
+    #[case("-y,x,z", "x,y,z")]
+    #[case("z,-x,-y", "-y,z,x")]
+    #[case("-x,-y,-z", "-x,-y,-z")]
+    fn double_reorientation_is_identity(#[case] first: Orientation, #[case] second: Orientation) {
+        let original = image().reorient(second);
+        let back = original.reorient(first).reorient(second);
+        assert_eq!(back.fov.n, original.fov.n);
+        assert_eq!(back.data, original.data);
+        assert_eq!(back.frame.orientation, original.frame.orientation);
+        assert_float_eq!(coordinates(back.frame.origin), coordinates(original.frame.origin), abs_all <= 1e-5);
+    }
+
+    #[test]
+    fn known_voxel_after_rotation() {
+        // Voxel [1, 2, 3] of the standard image is at (1, 3, 7.5) mm
+        let original = image();
+        assert_float_eq!(coordinates(original.world([1, 2, 3])), [1.0, 3.0, 7.5], abs_all <= 1e-5);
+        let value = original[[1, 2, 3]];
+        // After a 90° rotation about z, array x runs along -y and array y along x
+        let rotated = original.reorient("-y,x,z".parse().unwrap());
+        assert_eq!(rotated.fov.n, [3, 2, 4]);
+        let j = rotated.data.iter().position(|&v| v == value).unwrap();
+        let j3 = index1_to_3(j, rotated.fov.n);
+        assert_eq!(j3, [0, 1, 3]);
+        assert_float_eq!(coordinates(rotated.world(j3)), [1.0, 3.0, 7.5], abs_all <= 1e-5);
+    }
+
+    #[test]
+    fn orientation_parsing() {
+        assert_eq!("x,y,z".parse::<Orientation>(), Ok(Orientation::IDENTITY));
+        let o: Orientation = "-y, x, -z".parse().unwrap();
+        assert_eq!(o, Orientation { axes: [1, 0, 2], flips: [true, false, true] });
+        assert_eq!(o.to_string(), "-y,x,-z");
+        assert_eq!(Orientation::from_direction(o.direction()), Ok(o));
+        assert!("x,x,z".parse::<Orientation>().is_err());
+        assert!("x,y".parse::<Orientation>().is_err());
+        assert!(Orientation::from_direction([[0.7, 0.7, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]]).is_err());
+    }
+}
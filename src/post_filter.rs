@@ -0,0 +1,89 @@
+//! Filters applied to reconstructed images as they are written out. The
+//! images on which MLEM iterates, and any checkpoints, are never filtered.
+//!
+//! + `Gauss(σ)`: separable Gaussian smoothing (see `Image::gaussian_smoothed`)
+//! + `Tv`: edge-preserving total-variation denoising (see `Image::tv_denoise`)
+
+use crate::Length;
+use crate::image::Image;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostFilter {
+    Gauss(Length),
+    Tv {
+        /// Weight of the total variation, in intensity × mm
+        lambda: f32,
+        iterations: usize,
+        /// Print the energy after every iteration, to check convergence
+        verbose: bool,
+    },
+}
+
+impl std::str::FromStr for PostFilter {
+    type Err = String;
+    /// `gauss:<σ>`, such as `gauss:2 mm`, or `tv:lambda=<λ>,iters=<n>`,
+    /// optionally followed by `,verbose`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, parameters) = s.split_once(':')
+            .ok_or_else(|| format!("Expected gauss:<σ> or tv:lambda=<λ>,iters=<n>, got '{s}'"))?;
+        match kind {
+            "gauss" => {
+                let sigma: Length = parameters.parse().map_err(|e| format!("Invalid σ '{parameters}': {e:?}"))?;
+                if !(sigma.value.is_finite() && sigma.value > 0.0) { return Err(format!("σ must be positive, got '{parameters}'")) }
+                Ok(Self::Gauss(sigma))
+            }
+            "tv" => {
+                let (mut lambda, mut iterations, mut verbose) = (None, None, false);
+                for parameter in parameters.split(',').map(str::trim) {
+                    match parameter.split_once('=') {
+                        Some(("lambda", v)) => lambda = Some(v.parse::<f32>().map_err(|e| format!("Invalid lambda '{v}': {e}"))?),
+                        Some(("iters" , v)) => iterations = Some(v.parse::<usize>().map_err(|e| format!("Invalid iters '{v}': {e}"))?),
+                        None if parameter == "verbose" => verbose = true,
+                        _ => return Err(format!("Unknown TV parameter '{parameter}': use lambda=<λ>, iters=<n> or verbose")),
+                    }
+                }
+                let lambda = lambda.ok_or("TV filter needs lambda=<λ>")?;
+                let iterations = iterations.ok_or("TV filter needs iters=<n>")?;
+                if !(lambda.is_finite() && lambda >= 0.0) { return Err(format!("lambda must be finite and non-negative, got {lambda}")) }
+                Ok(Self::Tv { lambda, iterations, verbose })
+            }
+            _ => Err(format!("Unknown post-filter '{kind}': use gauss or tv")),
+        }
+    }
+}
+
+impl PostFilter {
+    pub fn apply(&self, image: &Image) -> Image {
+        match *self {
+            Self::Gauss(sigma) => image.gaussian_smoothed(sigma),
+            Self::Tv { lambda, iterations, verbose: false } => image.tv_denoise(lambda, iterations),
+            Self::Tv { lambda, iterations, verbose: true  } => {
+                let mut print = |iteration: usize, energy: f64| println!("TV iteration {iteration:3}: energy {energy:.6e}");
+                image.tv_denoise_monitored(lambda, iterations, Some(&mut print))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_post_filter {
+    use super::*;
+    use geometry::units::mm;
+    use rstest::rstest;
+
+    #[rstest(/**/ text                              , expected,
+             case("gauss:2 mm"                     , Ok(PostFilter::Gauss(mm(2.0)))),
+             case("tv:lambda=0.1,iters=50"         , Ok(PostFilter::Tv { lambda: 0.1, iterations: 50, verbose: false })),
+             case("tv:iters=5, lambda=0, verbose"  , Ok(PostFilter::Tv { lambda: 0.0, iterations: 5, verbose: true })),
+             case("gauss:2"                        , Err(())),
+             case("gauss:-1 mm"                    , Err(())),
+             case("tv:lambda=0.1"                  , Err(())),
+             case("tv:lambda=-1,iters=5"           , Err(())),
+             case("tv:lambda=0.1,iters=5,weight=2" , Err(())),
+             case("median:3"                       , Err(())),
+             case("gauss"                          , Err(())),
+    )]
+    fn parse(text: &str, expected: Result<PostFilter, ()>) {
+        assert_eq!(text.parse::<PostFilter>().map_err(|_| ()), expected);
+    }
+}
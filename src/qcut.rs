@@ -0,0 +1,104 @@
+//! Charge cuts derived from the charge distributions themselves.
+//!
+//! Unlike the gamma energies, the charges have no physical peak to cut
+//! around, but their lowest values are dominated by noise and partial
+//! deposits. `suggest_qcut` puts the lower bound of each side at a quantile of
+//! that side's charges, as the sides of asymmetric scanners see different
+//! distributions. NaN and non-positive charges carry no information about the
+//! distribution, so they are left out of the quantiles (but are still removed
+//! by any cut with a positive lower bound).
+
+use std::ops::Bound;
+
+use crate::{BoundPair, Chargef32};
+use crate::robust::quantile_in_place;
+
+/// Lower charge bound of one side of the LORs, and the charges it is based on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SideCut {
+    pub lower: Chargef32,
+    /// Charges from which the quantile was taken
+    pub n_used: usize,
+    /// NaN or non-positive charges, which were left out
+    pub n_ignored: usize,
+}
+
+impl SideCut {
+    /// Keep charges of at least `lower`
+    pub fn bounds(&self) -> BoundPair<Chargef32> { (Bound::Included(self.lower), Bound::Unbounded) }
+}
+
+/// Suggested cuts on `q1` and `q2`, each removing `fraction` of the valid
+/// charges of its side
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QcutSuggestion {
+    pub fraction: f32,
+    pub q1: SideCut,
+    pub q2: SideCut,
+}
+
+impl std::fmt::Display for QcutSuggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let side = |f: &mut std::fmt::Formatter, name: &str, cut: &SideCut| writeln!(
+            f, "    {name} >= {:10.2}   ({} charges, ignoring {} NaN or non-positive)", cut.lower, cut.n_used, cut.n_ignored);
+        writeln!(f, "Charge cuts removing the lowest {:.1}% of each side:", 100.0 * self.fraction)?;
+        side(f, "q1", &self.q1)?;
+        side(f, "q2", &self.q2)
+    }
+}
+
+/// Lower bound below which lie `fraction` of the positive, non-NaN `charges`
+pub fn suggest_side(charges: impl IntoIterator<Item = Chargef32>, fraction: f32) -> Result<SideCut, String> {
+    if !(fraction > 0.0 && fraction < 1.0) { return Err(format!("The charge cut fraction must be in (0, 1), got {fraction}")) }
+    let mut n_ignored = 0;
+    let mut valid: Vec<Chargef32> = charges.into_iter()
+        .filter(|&q| { let ok = q > 0.0; if !ok { n_ignored += 1 } ok })
+        .collect();
+    if valid.is_empty() { return Err(format!("No positive charges among {n_ignored}")) }
+    let lower = quantile_in_place(&mut valid, fraction);
+    Ok(SideCut { lower, n_used: valid.len(), n_ignored })
+}
+
+/// Cuts removing `fraction` of the valid charges of each side
+pub fn suggest_qcut(q1: impl IntoIterator<Item = Chargef32>, q2: impl IntoIterator<Item = Chargef32>, fraction: f32)
+                    -> Result<QcutSuggestion, String> {
+    let q1 = suggest_side(q1, fraction).map_err(|e| format!("q1: {e}"))?;
+    let q2 = suggest_side(q2, fraction).map_err(|e| format!("q2: {e}"))?;
+    Ok(QcutSuggestion { fraction, q1, q2 })
+}
+
+#[cfg(test)]
+mod test_qcut {
+    use super::*;
+    use float_eq::assert_float_eq;
+
+    #[test]
+    fn bounds_are_quantiles_of_each_side() -> Result<(), String> {
+        // 101 evenly spaced charges: the 5% quantile is the sixth
+        let q1: Vec<f32> = (1..=101).map(|q| q as f32).collect();
+        let q2: Vec<f32> = q1.iter().map(|q| 3.0 * q).collect();
+        let suggestion = suggest_qcut(q1, q2, 0.05)?;
+        assert_float_eq!(suggestion.q1.lower,  6.0, ulps <= 1);
+        assert_float_eq!(suggestion.q2.lower, 18.0, ulps <= 1);
+        assert_eq!(suggestion.q1.bounds(), (Bound::Included(6.0), Bound::Unbounded));
+        Ok(())
+    }
+
+    #[test]
+    fn nan_and_zero_charges_are_ignored_and_counted() -> Result<(), String> {
+        let mut charges: Vec<f32> = (1..=101).map(|q| q as f32).collect();
+        charges.extend([f32::NAN, 0.0, 0.0, -1.0]);
+        let cut = suggest_side(charges, 0.05)?;
+        assert_eq!((cut.n_used, cut.n_ignored), (101, 4));
+        assert_float_eq!(cut.lower, 6.0, ulps <= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn degenerate_requests_are_rejected() {
+        assert!(suggest_side([1.0, 2.0], 0.0).is_err());
+        assert!(suggest_side([1.0, 2.0], 1.0).is_err());
+        assert!(suggest_side([1.0, 2.0], f32::NAN).is_err());
+        assert!(suggest_side([0.0, f32::NAN], 0.5).is_err());
+    }
+}
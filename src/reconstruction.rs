@@ -0,0 +1,1311 @@
+//! High-level reconstruction: the whole pipeline of the `mlem` binary, from
+//! LORs on disk to images, configured with a builder.
+//!
+//! ```no_run
+//! # use petalo::reconstruction::{Reconstruction, SensitivityMode};
+//! # use petalo::fov::FovBuilder;
+//! # use petalo::sink::NoopSink;
+//! # use geometry::units::{mm, ps};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let fov = FovBuilder::full_widths(mm(300.0), mm(300.0), mm(300.0)).voxels(151, 151, 151).build()?;
+//! let summary = Reconstruction::new()
+//!     .input("MC.h5")
+//!     .fov(fov)
+//!     .tof(ps(200.0))
+//!     .sensitivity(SensitivityMode::Analytic("sensitivity.raw".into()))
+//!     .iterations(5)
+//!     .sink(NoopSink)
+//!     .run()?;
+//! println!("Reconstructed {} LORs", summary.n_lors);
+//! # Ok(()) }
+//! ```
+//!
+//! Each builder method checks its own arguments as it is called. Problems which
+//! depend on several settings, or on the contents of the input, are found by
+//! `validate`, which `run` calls before doing any work. Either way, the first
+//! problem is reported.
+
+use std::error::Error;
+use std::fs::create_dir_all;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tracing::info_span;
+
+use crate::{Angle, BoundPair, Chargef32, Energyf32, Length, Time};
+use crate::acceleration::Acceleration;
+use crate::cancel::{Cancel, RunStatus};
+use crate::checkpoint::{Checkpoint, CheckpointSink};
+use crate::cost::{extrapolate, sample_projection, CostEstimate, MemoryEstimate, ProjectionSample};
+use crate::cylindrical::ASYMMETRY_WARNING;
+use crate::divergence::{IterationStats, Monitor, Thresholds};
+use crate::error::{internal_error, Context};
+use crate::fov::{clip_lors_to_fov, ClipStats, FOV};
+use crate::geometry_cache::{CacheStats, GeometryCache};
+use crate::gauss::TofCutoff;
+use crate::image::Image;
+use crate::io;
+use crate::io::columns::ColumnMap;
+use crate::io::hdf5::{Compression, DeadTimeArgs, ScatterWindowArgs, DEFAULT_LOR_DATASET};
+use crate::lorogram::{BuildScattergram, OccupancyReport};
+use crate::mlem::{axial_sensitivity_profile, MlemOptions, MlemStep, Reduction};
+use crate::normalization::{Normalization, NormalizationComponent};
+use crate::post_filter::PostFilter;
+use crate::ecut::{suggest_ecut, AutoEcut};
+use crate::qcut::suggest_qcut;
+use crate::scanner::Scanner;
+use crate::sink::{self, write_output, Hdf5SeriesSink, ImageFormat, IterationSink, Manifest, PostFiltered, RawFileSink, StatsSink};
+use crate::system_matrix::{DegeneratePolicy, RowSource, SystemMatrix, Tube, LOR};
+use crate::thinning::Split;
+use crate::transform::RigidTransform;
+use crate::utils::{group_digits, resolve_file_and_dataset, split_file_and_dataset, Region};
+use geometry::units::mm_;
+
+/// Number of input rows inspected by `Reconstruction::validate`
+const VALIDATION_SAMPLE: usize = 1000;
+
+/// Rows of the input whose energy spectra locate the photopeak for `auto_ecut`
+pub const AUTO_ECUT_SAMPLE: usize = 100_000;
+
+/// Source of the sensitivity correction
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensitivityMode {
+    /// No correction
+    Ones,
+    /// Precomputed sensitivity image, which must match the FOV exactly
+    Analytic(PathBuf),
+    /// Backprojection of the measured LORs themselves, smoothed with this
+    /// Gaussian sigma: see `Image::data_sensitivity_image`. With
+    /// `assume_rotational_symmetry`, accumulated in (r, z): see
+    /// `Image::data_sensitivity_image_symmetric`. With
+    /// `weight_by_normalization`, each LOR is weighted by its multiplicative
+    /// correction.
+    Data { smoothing: Length, assume_rotational_symmetry: bool, weight_by_normalization: bool },
+    /// Linear fall-off of the sensitivity of a cylindrical detector towards
+    /// its axial ends: see `mlem::axial_sensitivity_profile`
+    AxialProfile { detector_half_length: Length },
+}
+
+/// Cuts on the events which are read
+#[derive(Clone, Debug)]
+pub struct Cuts {
+    /// Gamma energy / keV
+    pub energy: BoundPair<Energyf32>,
+    /// Detected charge / pes
+    pub charge: BoundPair<Chargef32>,
+    /// Polar angle from the transverse plane: see `io::hdf5::theta_bounds`
+    pub theta: BoundPair<Angle>,
+}
+
+impl Default for Cuts {
+    fn default() -> Self {
+        use std::ops::Bound::Unbounded;
+        Self { energy: (Unbounded, Unbounded), charge: (Unbounded, Unbounded), theta: (Unbounded, Unbounded) }
+    }
+}
+
+/// Files written by the reconstruction, besides those of any user sinks
+#[derive(Clone, Debug, Default)]
+pub struct Outputs {
+    /// Prefix of every file name: images go to `<pattern><iteration>-<subset>.<extension>`
+    pub pattern: String,
+    /// Format of the images, which also sets their extension
+    pub format: ImageFormat,
+    /// Write the voxel-centre coordinates of each image to `<image>.axes.json`
+    pub write_axes: bool,
+    /// Also write every image to this HDF5 file
+    pub hdf5_series: Option<PathBuf>,
+    /// Compression of the images in `hdf5_series`
+    pub compression: Compression,
+    /// Filter the images written to files, but not those on which MLEM
+    /// iterates, nor checkpoints or the variance image
+    pub post_filter: Option<PostFilter>,
+    /// Estimate the variance of the final image, and write it to `<pattern>variance.<extension>`
+    pub variance_image: bool,
+    /// Written by the caller, but recorded in the manifest with the other outputs
+    pub summary_json: Option<PathBuf>,
+    /// Skip outputs which the manifest records as already written
+    pub resume: bool,
+}
+
+impl Outputs {
+    pub fn variance_path(&self) -> PathBuf { PathBuf::from(format!("{}variance.{}", self.pattern, self.format.extension())) }
+
+    pub fn manifest_path(&self) -> PathBuf { PathBuf::from(format!("{}manifest.json", self.pattern)) }
+
+    /// Every file which a reconstruction of `iterations`, of `subsets` images
+    /// each, will write
+    pub fn planned(&self, iterations: RangeInclusive<usize>, subsets: usize) -> Vec<PathBuf> {
+        let raw = RawFileSink { pattern: self.pattern.clone(), format: self.format, write_axes: self.write_axes, manifest: None };
+        let mut images: Vec<PathBuf> = iterations
+            .flat_map(|iteration| (1..=subsets).map(move |subset| (iteration, subset)))
+            .map(|(iteration, subset)| raw.path(iteration, subset))
+            .collect();
+        if self.variance_image { images.push(self.variance_path()) }
+        let mut planned = vec![];
+        for image in images {
+            if self.write_axes { planned.push(io::raw::axes_path(&image)) }
+            planned.push(image);
+        }
+        planned.extend(self.hdf5_series.clone());
+        planned.extend(self.summary_json.clone());
+        planned
+    }
+}
+
+/// Result of a completed, converged or cancelled reconstruction
+#[derive(Serialize)]
+pub struct Summary {
+    pub status: RunStatus,
+    /// Number of LORs reconstructed, after all cuts
+    pub n_lors: usize,
+    /// LORs dropped, or truncated, by `clip_lors_to_fov`
+    pub fov_clip: ClipStats,
+    pub iterations: usize,
+    pub subsets: usize,
+    /// Files written, including any skipped because they were already
+    /// complete. After cancellation or convergence, only those which were
+    /// completed.
+    pub outputs: Vec<PathBuf>,
+    #[serde(skip)]
+    pub final_image: Option<Image>,
+    /// Hits and misses of the geometry cache, if one was used
+    pub geometry_cache: Option<CacheStats>,
+    /// Statistics of every image, as written by `Reconstruction::stats_out`
+    pub iteration_stats: Vec<IterationStats>,
+    /// Statistical health of the scattergram, if scatter corrections were
+    /// used, weighted by a sample of the reconstructed LORs
+    pub scattergram_occupancy: Option<OccupancyReport>,
+    /// Through which the caller should write any further outputs
+    #[serde(skip)]
+    pub manifest: Option<Manifest>,
+}
+
+/// Estimated cost of a reconstruction: see `Reconstruction::estimate_cost`
+#[derive(Serialize)]
+pub struct CostReport {
+    /// Rows which would be read
+    pub total_rows: usize,
+    pub sample: ProjectionSample,
+    pub estimate: CostEstimate,
+}
+
+/// What a reconstruction would read and write, and the memory it would need:
+/// see `Reconstruction::plan`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Plan {
+    /// Each input file, with the number of rows in its LOR table
+    pub inputs: Vec<(String, usize)>,
+    /// Rows which would be read, in the event range if one is given
+    pub total_rows: usize,
+    /// Bytes per row of the LOR table, as stored in the (first) file
+    pub row_bytes: usize,
+    pub fov: FOV,
+    /// Directory in which the outputs would be written, if any
+    pub output_dir: Option<PathBuf>,
+    /// Assuming that every row read survives the cuts
+    pub memory: MemoryEstimate,
+}
+
+struct CrystalInterference {
+    n_bins: usize,
+    scanner: Scanner,
+    /// `file.h5` or `file.h5:group/dataset`
+    scan: String,
+}
+
+struct Divergence {
+    thresholds: Thresholds,
+    sample: usize,
+}
+
+pub struct Reconstruction {
+    io: io::hdf5::Args,
+    prefetch: bool,
+    scatter: Option<BuildScattergram>,
+    crystal_interference: Option<CrystalInterference>,
+    /// Fraction of the charges of each side removed by automatic charge cuts
+    auto_qcut: Option<f32>,
+    /// Energy window found around the photopeak of a sample of the input
+    auto_ecut: Option<AutoEcut>,
+    fov: Option<FOV>,
+    /// Move the endpoints of the LORs to where they enter and leave the FOV
+    truncate_lors: bool,
+    tof: Option<Time>,
+    cutoff: Option<TofCutoff>,
+    tube: Option<Tube>,
+    sensitivity: SensitivityMode,
+    iterations: usize,
+    subsets: usize,
+    acceleration: Acceleration,
+    reduction: Reduction,
+    geometry_cache: Option<GeometryCache>,
+    system_matrix_memory: Option<usize>,
+    initial_image: Option<PathBuf>,
+    focus: Option<Region>,
+    divergence: Option<Divergence>,
+    cancel: Option<Cancel>,
+    outputs: Option<Outputs>,
+    stats_out: Option<PathBuf>,
+    likelihood_sample: Option<usize>,
+    stop_when_delta: Option<f32>,
+    checkpoint: Option<PathBuf>,
+    resume_from: Option<PathBuf>,
+    sinks: Vec<Box<dyn IterationSink>>,
+    /// Problems found by the builder methods, reported by `validate`
+    problems: Vec<String>,
+}
+
+impl Default for Reconstruction {
+    fn default() -> Self { Self::new() }
+}
+
+impl Reconstruction {
+
+    /// Five iterations of plain MLEM, without TOF or corrections. An input and
+    /// a FOV must be given.
+    pub fn new() -> Self {
+        let Cuts { energy, charge, theta } = Cuts::default();
+        Self {
+            io: io::hdf5::Args { ecut: energy, qcut: charge, theta_cut: theta, ..Default::default() },
+            prefetch: true,
+            scatter: None,
+            crystal_interference: None,
+            auto_qcut: None,
+            auto_ecut: None,
+            fov: None,
+            truncate_lors: false,
+            tof: None,
+            cutoff: None,
+            tube: None,
+            sensitivity: SensitivityMode::Ones,
+            iterations: 5,
+            subsets: 1,
+            acceleration: Acceleration::Plain,
+            reduction: Reduction::Fast,
+            geometry_cache: None,
+            system_matrix_memory: None,
+            initial_image: None,
+            focus: None,
+            divergence: None,
+            cancel: None,
+            outputs: None,
+            stats_out: None,
+            likelihood_sample: None,
+            stop_when_delta: None,
+            checkpoint: None,
+            resume_from: None,
+            sinks: vec![],
+            problems: vec![],
+        }
+    }
+
+    fn problem(mut self, problem: impl Into<String>) -> Self {
+        self.problems.push(problem.into());
+        self
+    }
+
+    /// LORs to read: `file.h5`, `file.h5:group/dataset`, or a memory-mapped LOR
+    /// file written by `makelor -o file.lors`
+    pub fn input(self, spec: &str) -> Self { self.inputs(&[spec]) }
+
+    /// LORs to read from several files, as a single table concatenated in this
+    /// order: each as in `input`, or a glob pattern such as `jobs/*.h5`. Any
+    /// datasets named in the specifications must agree. The `event_range`
+    /// counts rows across all the files.
+    pub fn inputs<S: AsRef<str>>(mut self, specs: &[S]) -> Self {
+        let (mut files, mut embedded) = (vec![], None);
+        for spec in specs {
+            let (file, dataset) = split_file_and_dataset(spec.as_ref());
+            match (embedded, dataset) {
+                (Some(first), Some(dataset)) if first != dataset => return self.problem(format!(
+                    "Input files name different datasets: '{first}' and '{dataset}'")),
+                (None, Some(dataset)) => embedded = Some(dataset),
+                _ => {},
+            }
+            let matches = match io::hdf5::expand_input_files(&[file.to_string()]) {
+                Ok(matches) => matches,
+                Err(e)      => return self.problem(e),
+            };
+            if let Some(file) = matches.iter().find(|file| !Path::new(file).is_file()) {
+                return self.problem(format!("Input file '{file}' not found"))
+            }
+            files.extend(matches);
+        }
+        self.io.input_files = files;
+        self.io.dataset = embedded.unwrap_or(DEFAULT_LOR_DATASET).into();
+        self
+    }
+
+    /// Override the dataset given in, or implied by, `input`
+    pub fn dataset(mut self, dataset: &str) -> Self { self.io.dataset = dataset.into(); self }
+
+    /// Which rows of the input to read
+    pub fn event_range(mut self, range: std::ops::Range<usize>) -> Self {
+        if range.is_empty() { return self.problem(format!("Empty event range {range:?}")) }
+        self.io.event_range = Some(range);
+        self
+    }
+
+    /// Use true rather than reconstructed LORs
+    pub fn use_true(mut self, use_true: bool) -> Self { self.io.use_true = use_true; self }
+
+    pub fn cuts(mut self, Cuts { energy, charge, theta }: Cuts) -> Self {
+        self.io.ecut = energy;
+        self.io.qcut = charge;
+        self.io.theta_cut = theta;
+        self
+    }
+
+    /// Replace the charge cuts by lower bounds removing the lowest `fraction`
+    /// of the positive charges of each side, read from the input before the
+    /// run (see `qcut`). Not applied by `estimate_cost`.
+    pub fn auto_qcut(mut self, fraction: f32) -> Self {
+        if !(fraction > 0.0 && fraction < 1.0) {
+            return self.problem(format!("The automatic charge cut fraction must be in (0, 1), got {fraction}"))
+        }
+        self.auto_qcut = Some(fraction);
+        self
+    }
+
+    /// Replace the energy cut by a window of `auto.n_sigma` standard deviations
+    /// either side of the photopeak, found in the spectra of the first
+    /// `AUTO_ECUT_SAMPLE` rows of the input before the run (see `ecut`). Not
+    /// applied by `estimate_cost`.
+    pub fn auto_ecut(mut self, auto: AutoEcut) -> Self {
+        if let Err(e) = auto.validate() { return self.problem(e) }
+        self.auto_ecut = Some(auto);
+        self
+    }
+
+    /// What to do with LORs whose endpoints coincide
+    pub fn degenerate(mut self, policy: DegeneratePolicy) -> Self { self.io.degenerate = policy; self }
+
+    pub fn canonicalize_endpoints(mut self, canonicalize: bool) -> Self { self.io.canonicalize_endpoints = canonicalize; self }
+
+    /// Read the LOR table column by column, from columns named as in `columns`
+    pub fn column_map(mut self, columns: ColumnMap) -> Self { self.io.column_map = Some(columns); self }
+
+    /// Move every LOR by `transform` as it is read, to align the data with the
+    /// FOV. Cuts, scatter corrections and normalization scans see the moved LORs.
+    pub fn transform(mut self, transform: RigidTransform) -> Self { self.io.transform = Some(transform); self }
+
+    /// Reconstruct only one replicate of the data
+    pub fn split(mut self, split: Split) -> Self { self.io.split = Some(split); self }
+
+    /// 1D datasets in the input file, aligned with the LOR table, of
+    /// multiplicative and additive corrections
+    pub fn corrections(mut self, multiplicative: Vec<String>, additive: Vec<String>) -> Self {
+        self.io.mult_corrections = multiplicative;
+        self.io.add_corrections = additive;
+        self
+    }
+
+    pub fn dead_time(mut self, dead_time: DeadTimeArgs) -> Self {
+        if dead_time.tau <= Time::ZERO || dead_time.tau.is_nan() { return self.problem(format!("Dead time must be positive, not {:?}", dead_time.tau)) }
+        self.io.dead_time = Some(dead_time);
+        self
+    }
+
+    /// Scatter corrections, gathered while reading the LORs. Requires energies
+    /// in the input, to tell scatters from trues.
+    pub fn scatter(mut self, config: BuildScattergram) -> Self {
+        if config.clone().build().is_none() { return self.problem("Scatter configuration has no axes") }
+        self.scatter = Some(config);
+        self
+    }
+
+    /// Gather scatter corrections separately in windows of acquisition time
+    pub fn scatter_time_windows(mut self, windows: ScatterWindowArgs) -> Self {
+        if windows.n_windows == 0 { return self.problem("Need at least one scatter time window") }
+        self.io.scatter_windows = Some(windows);
+        self
+    }
+
+    /// Correct for crystal interference, derived from a normalization scan
+    /// (`file.h5` or `file.h5:group/dataset`, read with the cuts of the data)
+    /// in `n_bins` bins of the LOR angle within each module of `scanner`
+    pub fn crystal_interference(mut self, n_bins: usize, scanner: &Path, scan: &str) -> Self {
+        if n_bins == 0 { return self.problem("Need at least one crystal-interference bin") }
+        let scanner = match Scanner::load(scanner) {
+            Ok(scanner) => scanner,
+            Err(e)      => return self.problem(e.to_string()),
+        };
+        self.crystal_interference = Some(CrystalInterference { n_bins, scanner, scan: scan.into() });
+        self
+    }
+
+    /// Read the input serially, rather than overlapping it with other work
+    pub fn prefetch(mut self, prefetch: bool) -> Self { self.prefetch = prefetch; self }
+
+    pub fn fov(mut self, fov: FOV) -> Self { self.fov = Some(fov); self }
+
+    /// Move the endpoints of the LORs to where they enter and leave the FOV,
+    /// preserving their TOF peaks, so that the geometry cache and system matrix
+    /// see only the parts which matter. LORs which miss the FOV are dropped
+    /// regardless. Ignored with a tube of several samples, whose sub-LORs enter
+    /// elsewhere.
+    pub fn truncate_lors(mut self, truncate: bool) -> Self { self.truncate_lors = truncate; self }
+
+    /// TOF time-resolution sigma. Requires dt in the input
+    pub fn tof(mut self, sigma: Time) -> Self {
+        if sigma <= Time::ZERO || sigma.is_nan() { return self.problem(format!("TOF sigma must be positive, not {sigma:?}")) }
+        self.tof = Some(sigma);
+        self
+    }
+
+    pub fn tof_cutoff(mut self, cutoff: Option<TofCutoff>) -> Self { self.cutoff = cutoff; self }
+
+    pub fn tube(mut self, tube: Tube) -> Self {
+        if tube.samples == 0 { return self.problem("Tube of response needs at least one sample") }
+        self.tube = Some(tube);
+        self
+    }
+
+    pub fn sensitivity(mut self, mode: SensitivityMode) -> Self {
+        if let SensitivityMode::Analytic(path) = &mode {
+            if !path.is_file() { return self.problem(format!("Sensitivity image '{}' not found", path.display())) }
+        }
+        if let SensitivityMode::AxialProfile { detector_half_length: half } = mode {
+            if half <= Length::ZERO || half.is_nan() { return self.problem(format!("Detector half-length must be positive, not {half:?}")) }
+        }
+        self.sensitivity = mode;
+        self
+    }
+
+    /// Number of iterations, each over all subsets
+    pub fn iterations(mut self, n: usize) -> Self {
+        if n == 0 { return self.problem("Need at least one iteration") }
+        self.iterations = n;
+        self
+    }
+
+    /// Number of OSEM subsets
+    pub fn subsets(mut self, n: usize) -> Self {
+        if n == 0 { return self.problem("Need at least one subset") }
+        self.subsets = n;
+        self
+    }
+
+    pub fn acceleration(mut self, acceleration: Acceleration) -> Self { self.acceleration = acceleration; self }
+
+    /// How the backprojections are added up: see `Reduction`
+    pub fn reduction(mut self, reduction: Reduction) -> Self { self.reduction = reduction; self }
+
+    /// Memoize the system matrix rows of up to `max_entries` LORs, keyed by
+    /// their endpoints rounded to `granularity`: see `geometry_cache`
+    pub fn geometry_cache(mut self, max_entries: usize, granularity: Length) -> Self {
+        match GeometryCache::new(max_entries, granularity) {
+            Ok(cache) => { self.geometry_cache = Some(cache); self },
+            Err(e)    => self.problem(e),
+        }
+    }
+
+    /// Precompute the system matrix rows of the LORs, up to `max_bytes` of
+    /// them, rather than recomputing them in every iteration: see
+    /// `system_matrix::SystemMatrix`
+    pub fn system_matrix_memory(mut self, max_bytes: usize) -> Self { self.system_matrix_memory = Some(max_bytes); self }
+
+    /// Image from which to start iterating, instead of a uniform one
+    pub fn initial_image(mut self, path: &Path) -> Self {
+        if !path.is_file() { return self.problem(format!("Initial image '{}' not found", path.display())) }
+        self.initial_image = Some(path.into());
+        self
+    }
+
+    /// Only update voxels inside `region`. Requires an initial image
+    pub fn focus(mut self, region: Region) -> Self { self.focus = Some(region); self }
+
+    /// Abort when the reconstruction appears to diverge, judging the data
+    /// mismatch on the first `sample` LORs. Requires outputs, where the last
+    /// good image and diagnostics are written.
+    pub fn divergence_check(mut self, thresholds: Thresholds, sample: usize) -> Self {
+        self.divergence = Some(Divergence { thresholds, sample });
+        self
+    }
+
+    /// Stop iterating once `cancel` is requested. The run still succeeds: the
+    /// last image produced is the final image, no variance is estimated, and
+    /// the summary's status is `Cancelled`.
+    pub fn cancellation(mut self, cancel: Cancel) -> Self { self.cancel = Some(cancel); self }
+
+    pub fn outputs(mut self, outputs: Outputs) -> Self { self.outputs = Some(outputs); self }
+
+    /// Write the statistics of every image to `path`, as they arrive: see
+    /// `sink::STATS_COLUMNS`
+    pub fn stats_out(mut self, path: impl Into<PathBuf>) -> Self { self.stats_out = Some(path.into()); self }
+
+    /// Include in the statistics of every image the data mismatch on the first
+    /// `sample` LORs
+    pub fn likelihood_sample(mut self, sample: usize) -> Self {
+        if sample == 0 { return self.problem("The likelihood sample must contain at least one LOR") }
+        self.likelihood_sample = Some(sample);
+        self
+    }
+
+    /// Stop iterating, before all iterations are done, once no voxel changes
+    /// by more than the fraction `delta` of its value from one image to the
+    /// next. The summary's status is then `Converged`.
+    pub fn stop_when_delta(mut self, delta: f32) -> Self {
+        if delta.is_nan() || delta <= 0.0 { return self.problem(format!("The convergence threshold must be positive, got {delta}")) }
+        self.stop_when_delta = Some(delta);
+        self
+    }
+
+    /// Write a checkpoint to `path` after every iteration: see `checkpoint`
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self { self.checkpoint = Some(path.into()); self }
+
+    /// Continue from the checkpoint in `path`, which must match the FOV, up to
+    /// the total number of iterations. Any acceleration restarts from the
+    /// checkpointed image.
+    pub fn resume_from(mut self, path: &Path) -> Self {
+        if !path.is_file() { return self.problem(format!("Checkpoint '{}' not found", path.display())) }
+        self.resume_from = Some(path.into());
+        self
+    }
+
+    /// Also hand every image to `sink`, after the built-in ones
+    pub fn sink(mut self, sink: impl IterationSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Report the first problem with the configuration, including those which
+    /// depend on the contents of the input
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(problem) = self.problems.first() { return Err(problem.clone()) }
+        if self.io.input_files.is_empty() { return Err("No input given".into()) }
+        if self.fov.is_none() { return Err("No FOV given".into()) }
+        if self.focus.is_some() && self.initial_image.is_none() && self.resume_from.is_none() {
+            return Err("A focus region requires an initial image".into())
+        }
+        if self.geometry_cache.is_some() && self.system_matrix_memory.is_some() {
+            return Err("Cannot use both a geometry cache and a precomputed system matrix".into())
+        }
+        if self.initial_image.is_some() && self.resume_from.is_some() {
+            return Err("Cannot start from both an initial image and a checkpoint".into())
+        }
+        if self.divergence.is_some() && self.outputs.is_none() {
+            return Err("The divergence check requires outputs, for the last good image".into())
+        }
+        if self.io.scatter_windows.is_some() && self.scatter.is_none() {
+            return Err("Scatter time windows given, but no scatter configuration".into())
+        }
+        if self.io.scatter_windows.is_some() && self.scatter.as_ref().map_or(false, BuildScattergram::is_adaptive) {
+            return Err("Adaptive scattergram binning cannot be combined with scatter windows".into())
+        }
+        if self.tof.is_none() && self.scatter.is_none() { return Ok(()) }
+
+        let file = self.io.describe_input();
+        let rows = io::hdf5::sample_rows(&self.io, VALIDATION_SAMPLE)
+            .map_err(|e| format!("Cannot inspect {file}: {e}"))?;
+        let missing = |x: f32| x == 0.0 || x.is_nan();
+        if self.tof.is_some() && rows.iter().all(|row| missing(row.dt)) {
+            return Err(format!(
+                "TOF sigma given, but the input has no TOF data: dt is 0 or NaN in all of the first {} rows of {file}",
+                rows.len()))
+        }
+        if self.scatter.is_some() && rows.iter().all(|row| missing(row.E1) && missing(row.E2)) {
+            return Err(format!(
+                "Scatter correction requires gamma energies, but E1 and E2 are 0 or NaN in all of the first {} rows of {file}",
+                rows.len()))
+        }
+        Ok(())
+    }
+
+    /// Estimate the time and memory needed, by projecting the first (up to)
+    /// `n_sample` rows of the input, with `n_threads` threads
+    pub fn estimate_cost(&self, n_sample: usize, n_threads: usize) -> Result<CostReport, Box<dyn Error>> {
+        self.validate()?;
+        let fov = self.fov.unwrap();
+        let (start, total_rows) = match &self.io.event_range {
+            Some(range) => (range.start, range.len()),
+            None        => (0, io::hdf5::concatenated_len(&self.io.files()?, &self.io.dataset)?),
+        };
+        let n_read = n_sample.min(total_rows);
+        let sample_args = io::hdf5::Args { event_range: Some(start..start + n_read), ..self.io.clone() };
+        let lors = io::hdf5::read_lors(sample_args, self.scatter.clone().and_then(BuildScattergram::build))?;
+
+        let sample = sample_projection(&lors, fov, self.tof, self.cutoff, self.tube);
+        // Assume that the same fraction of the full dataset survives the cuts
+        let n_events = (total_rows as f64 * lors.len() as f64 / n_read.max(1) as f64).round() as usize;
+        let estimate = extrapolate(&sample, n_events, fov, n_threads);
+        Ok(CostReport { total_rows, sample, estimate })
+    }
+
+    /// Check everything which can be checked without reading the LORs: the
+    /// configuration (see `validate`), that every input has a LOR table with
+    /// the expected columns, and that the output directory is writable. Plan
+    /// the reconstruction with `n_threads` threads.
+    pub fn plan(&self, n_threads: usize) -> Result<Plan, Box<dyn Error>> {
+        self.validate()?;
+        let fov = self.fov.unwrap();
+        let mut inputs = vec![];
+        let mut row_bytes = None;
+        for file in self.io.files()? {
+            let (rows, bytes) = io::hdf5::inspect_lor_table(&file, &self.io.dataset, self.io.column_map.as_ref())
+                .map_err(|e| format!("Cannot read LORs from '{file}': {e}"))?;
+            row_bytes.get_or_insert(bytes);
+            inputs.push((file, rows));
+        }
+        let n_rows: usize = inputs.iter().map(|(_, rows)| rows).sum();
+        let total_rows = match &self.io.event_range {
+            Some(range) => range.end.min(n_rows).saturating_sub(range.start),
+            None        => n_rows,
+        };
+        if total_rows == 0 {
+            return Err(match &self.io.event_range {
+                Some(range) => format!("Event range {range:?} selects no rows of the {n_rows} in {}", self.io.describe_input()),
+                None        => format!("No LORs in {}", self.io.describe_input()),
+            }.into())
+        }
+        let output_dir = self.outputs.as_ref().map(|outputs| output_dir(&outputs.pattern));
+        if let Some(dir) = &output_dir { check_writable(dir)? }
+
+        let row_bytes = row_bytes.unwrap_or(0);
+        // The chunk being processed, and the next one when prefetching
+        let chunks = if self.prefetch { 2 } else { 1 };
+        let memory = MemoryEstimate::new(total_rows, fov, n_threads)
+            .reading(chunks * total_rows.min(io::hdf5::LOR_CHUNK_SIZE), row_bytes + std::mem::size_of::<io::hdf5::Hdf5Lor>());
+        Ok(Plan { inputs, total_rows, row_bytes, fov, output_dir, memory })
+    }
+
+    /// Read the LORs, apply the corrections and iterate, handing each image to
+    /// the built-in sinks (divergence check, raw files, HDF5 series) and then
+    /// to those added with `sink`
+    pub fn run(self) -> Result<Summary, Box<dyn Error>> {
+        self.validate()?;
+        let Self { io: mut io_args, prefetch, scatter, crystal_interference, auto_qcut, auto_ecut, fov, truncate_lors, tof, cutoff, tube, sensitivity,
+                   iterations, subsets, acceleration, reduction, geometry_cache, system_matrix_memory, initial_image, focus, divergence, cancel,
+                   outputs, stats_out, likelihood_sample, stop_when_delta, checkpoint,
+                   resume_from, mut sinks, .. } = self;
+        let fov = fov.unwrap();
+
+        if let Some(fraction) = auto_qcut {
+            let _span = info_span!("auto_qcut").entered();
+            let (q1, q2) = io::hdf5::read_charges(&io_args)
+                .context(|| format!("reading charges from {}, dataset '{}'", io_args.describe_input(), io_args.dataset))?;
+            let suggestion = suggest_qcut(q1, q2, fraction)?;
+            tracing::info!("{suggestion}");
+            io_args.qcut  = suggestion.q1.bounds();
+            io_args.q2cut = Some(suggestion.q2.bounds());
+        }
+
+        if let Some(auto) = auto_ecut {
+            let _span = info_span!("auto_ecut").entered();
+            let rows = io::hdf5::sample_rows(&io_args, AUTO_ECUT_SAMPLE)
+                .context(|| format!("reading energies from {}, dataset '{}'", io_args.describe_input(), io_args.dataset))?;
+            let (e1, e2) = io::hdf5::energy_histograms(&rows, auto.bins, auto.max);
+            let suggestion = suggest_ecut(&e1, &e2, auto.n_sigma)?;
+            tracing::info!("{suggestion}");
+            io_args.ecut = suggestion.bounds();
+        }
+
+        // The normalization scan is read with the cuts of the data, but none of
+        // the corrections aligned with the data
+        let normalization_args = io::hdf5::Args { event_range: None, split: None, mult_corrections: vec![], add_corrections: vec![],
+                                                  dead_time: None, scatter_windows: None, ..io_args.clone() };
+
+        let lors_context = format!("reading LORs from {}, dataset '{}'", io_args.describe_input(), io_args.dataset);
+        let load_lors = move || -> Result<(Vec<LOR>, Option<OccupancyReport>), String> {
+            // Scatter corrections are gathered in the same pass over the file
+            // which collects the LORs to be reconstructed
+            let scattergram = scatter.and_then(BuildScattergram::build);
+            io::hdf5::read_lors_and_scattergram(io_args, scattergram, prefetch)
+                .map(|(lors, scattergram)| {
+                    let occupancy = scattergram.map(|s| s.occupancy_report(Some(&io::hdf5::occupancy_sample(&lors))));
+                    (lors, occupancy)
+                })
+                .map_err(|e| e.to_string())
+        };
+
+        // A checkpoint is checked before any LORs are read. The iterations
+        // which it records are not repeated.
+        let resumed = match &resume_from {
+            Some(path) => {
+                let Checkpoint { image, iterations: done } = Checkpoint::read(path)?;
+                let image = matching(image, path, fov, "Checkpoint")?;
+                if done >= iterations {
+                    return Err(format!("Checkpoint '{}' is at iteration {done}, but only {iterations} were requested", path.display()).into())
+                }
+                tracing::info!("Resuming after iteration {done} from {}", path.display());
+                Some((image, done))
+            },
+            None => None,
+        };
+
+        // Unless prefetching is disabled, read the LORs in the background while
+        // preparing everything else
+        tracing::info!("Reading LOR data from disk ...");
+        let background_load = if prefetch {
+            std::thread::Builder::new().name("LOR loader".into()).spawn(load_lors.clone()).ok()
+        } else { None };
+
+        // If the directory where results will be written does not exist yet, make it
+        if let Some(outputs) = &outputs {
+            let dir = output_dir(&outputs.pattern);
+            create_dir_all(&dir).context(|| format!("creating output directory '{}'", dir.display()))?
+        }
+
+        let analytic_sensitivity = match &sensitivity {
+            SensitivityMode::Analytic(path) => {
+                let _span = info_span!("load_sensitivity_image").entered();
+                Some(load_matching(path, fov, "Sensitivity").context(|| format!("loading sensitivity image '{}'", path.display()))?)
+            },
+            _ => None,
+        };
+
+        let (mut measured_lors, scattergram_occupancy) = info_span!("wait_for_lors").in_scope(|| match background_load {
+            Some(handle) => handle.join().map_err(|_| internal_error("LOR loading thread panicked"))?.context(|| lors_context),
+            None         => load_lors().context(|| lors_context),
+        })?;
+        if let Some(component) = crystal_interference {
+            normalization(component, normalization_args)?.apply(&mut measured_lors);
+        }
+        // A sub-LOR of a tube may hit the FOV where its central LOR misses
+        let (measured_lors, fov_clip) = info_span!("clip_lors").in_scope(|| match tube {
+            Some(tube) if tube.samples > 1 => clip_lors_to_fov(measured_lors, &fov.expanded_by(tube.radius), false),
+            _                              => clip_lors_to_fov(measured_lors, &fov, truncate_lors),
+        });
+        tracing::info!("Clipping to FOV: {fov_clip}");
+
+        let sensitivity_image = match sensitivity {
+            SensitivityMode::Ones                => None,
+            SensitivityMode::Analytic(_)         => analytic_sensitivity,
+            SensitivityMode::Data { smoothing, assume_rotational_symmetry, weight_by_normalization } => {
+                let normalize_chord = tube.map_or(false, |t| t.normalize_chord);
+                if assume_rotational_symmetry {
+                    let (image, asymmetry) = Image::data_sensitivity_image_symmetric(fov, &measured_lors, Some(smoothing), normalize_chord, weight_by_normalization);
+                    if asymmetry > ASYMMETRY_WARNING {
+                        tracing::warn!("Rotational symmetry assumed, but the azimuthal sectors of the data sensitivity \
+                                        differ by up to {:.0}%: it is the azimuthal average of the sensitivity", 100.0 * asymmetry);
+                    }
+                    Some(image)
+                } else {
+                    Some(Image::data_sensitivity_image(fov, &measured_lors, Some(smoothing), normalize_chord, weight_by_normalization))
+                }
+            },
+            SensitivityMode::AxialProfile { detector_half_length } => {
+                // The correction is the reciprocal of the sensitivity
+                let mut correction = axial_sensitivity_profile(detector_half_length, fov);
+                correction.data.iter_mut().for_each(|s| *s = 1.0 / *s);
+                Some(correction)
+            },
+        };
+
+        let (initial_image, done) = match (&initial_image, resumed) {
+            (_, Some((image, done))) => (image, done),
+            (Some(path), None)       => (load_matching(path, fov, "Initial").context(|| format!("loading initial image '{}'", path.display()))?, 0),
+            (None, None)             => (Image::ones(fov), 0),
+        };
+        let n_planned = (iterations - done) * subsets;
+        let focus = focus.map(|region| region.mask(fov));
+
+        // On divergence, the last good image and diagnostics are written next to
+        // the images, and the run is aborted
+        let mut monitor = match (&divergence, &outputs) {
+            (Some(Divergence { thresholds, sample }), Some(outputs)) => {
+                let sample = &measured_lors[..(*sample).min(measured_lors.len())];
+                Some(Monitor::new(*thresholds, sample, tof, cutoff, tube, &outputs.pattern))
+            },
+            _ => None,
+        };
+
+        // Kept for the variance estimate, if requested
+        let variance_image = outputs.as_ref().map_or(false, |o| o.variance_image);
+        let variance_sensitivity = if variance_image { sensitivity_image.clone() } else { None };
+
+        let planned = outputs.as_ref().map_or(vec![], |o| o.planned(done + 1..=iterations, subsets));
+        let manifest = match &outputs {
+            Some(outputs) => Some(Manifest::start(outputs.manifest_path(), planned.clone(), outputs.resume)?),
+            None          => None,
+        };
+        let mut files = PostFiltered { filter: outputs.as_ref().and_then(|o| o.post_filter), sinks: vec![] };
+        if let Some(o) = &outputs {
+            files.sinks.push(Box::new(RawFileSink {
+                pattern: o.pattern.clone(), format: o.format, write_axes: o.write_axes, manifest: manifest.clone(),
+            }));
+            if let Some(path) = &o.hdf5_series {
+                files.sinks.push(Box::new(Hdf5SeriesSink::new(path).with_manifest(manifest.clone().unwrap()).with_compression(o.compression)));
+            }
+        }
+        let mut stats = StatsSink::new();
+        if let Some(path) = &stats_out { stats = stats.writing_to(path)? }
+        if let Some(sample) = likelihood_sample {
+            stats = stats.with_likelihood(&measured_lors[..sample.min(measured_lors.len())], tof, cutoff, tube);
+        }
+        let diagnostics = monitor.as_ref().map(|m| (m.last_good_path(), m.diagnostics_path()));
+        let mut all_sinks: Vec<&mut dyn IterationSink> = vec![&mut stats];
+        if let Some(monitor) = &mut monitor { all_sinks.push(monitor) }
+        if !files.sinks.is_empty() { all_sinks.push(&mut files) }
+        let mut checkpoints = checkpoint.map(|path| CheckpointSink { path, subsets });
+        if let Some(checkpoints) = &mut checkpoints { all_sinks.push(checkpoints) }
+        for sink in &mut sinks { all_sinks.push(sink.as_mut()) }
+
+        let system_matrix = system_matrix_memory.map(|max_bytes| {
+            let _span = info_span!("precompute_system_matrix").entered();
+            let matrix = SystemMatrix::new(&measured_lors, fov, tof, cutoff, tube, max_bytes);
+            tracing::info!("System matrix: {} of {} rows precomputed, {} MB",
+                           group_digits(matrix.n_stored()), group_digits(measured_lors.len()), matrix.bytes() >> 20);
+            matrix
+        });
+        let rows: Option<&dyn RowSource> = match (&system_matrix, &geometry_cache) {
+            (Some(matrix), _) => Some(matrix),
+            (_, Some(cache))  => Some(cache),
+            (None, None)      => None,
+        };
+        let options = MlemOptions { focus, acceleration, rows, cancel: cancel.clone(), reduction, ..Default::default() };
+        let images = Image::mlem_with(initial_image, &measured_lors, tof, cutoff, tube, sensitivity_image, subsets, options);
+        let converged = |stats: &IterationStats| match (stop_when_delta, stats.max_change) {
+            (Some(delta), Some(change)) => change < delta,
+            _                           => false,
+        };
+        let images = images.map(move |MlemStep { image, iteration, subset, .. }| (image, done + iteration, subset));
+        let final_image = match sink::drive_until(images, n_planned, &mut all_sinks, converged) {
+            Ok(image) => image,
+            Err(e) => {
+                if let Some((last_good, diagnostics)) = diagnostics {
+                    if last_good.exists() { tracing::info!("Last good image written to {}", last_good.display()) }
+                    if diagnostics.exists() { tracing::info!("Diagnostics written to {}", diagnostics.display()) }
+                }
+                return Err(e)
+            }
+        };
+
+        let n_images = stats.history().len();
+        let status = if cancel.as_ref().map_or(false, Cancel::is_requested) && n_images < n_planned {
+            tracing::info!("Cancelled after {n_images} of {n_planned} images");
+            RunStatus::Cancelled
+        } else if n_images < n_planned {
+            tracing::info!("Converged after {n_images} of {n_planned} images");
+            RunStatus::Converged
+        } else { RunStatus::Completed };
+
+        let cache_stats = geometry_cache.as_ref().map(GeometryCache::stats);
+        if let Some(stats) = cache_stats { tracing::info!("Geometry cache: {stats}") }
+
+        let completed = status == RunStatus::Completed;
+        let good_image = status != RunStatus::Cancelled;
+        if let (Some(outputs), true, true, Some(image)) = (&outputs, variance_image, good_image, &final_image) {
+            let variance = image.variance_estimate(&measured_lors, variance_sensitivity.as_ref(), tof, cutoff, tube);
+            let path = outputs.variance_path();
+            write_output(manifest.as_ref(), &path, |tmp| outputs.format.write(&variance, tmp))?;
+            if outputs.write_axes {
+                write_output(manifest.as_ref(), &io::raw::axes_path(&path), |tmp| Ok(io::raw::write_axes(variance.fov, tmp)?))?;
+            }
+            tracing::info!("Variance estimate written to {}", path.display());
+        }
+
+        // The summary is written by the caller, after this
+        let summary_json = outputs.as_ref().and_then(|o| o.summary_json.clone());
+        let outputs = match (&manifest, completed) {
+            (Some(manifest), false) => planned.into_iter()
+                .filter(|path| Some(path) == summary_json.as_ref() || manifest.is_done(path).unwrap_or(false))
+                .collect(),
+            _ => planned,
+        };
+
+        Ok(Summary { status, n_lors: measured_lors.len(), fov_clip, iterations, subsets, outputs, final_image,
+                     geometry_cache: cache_stats, iteration_stats: stats.into_history(), scattergram_occupancy, manifest })
+    }
+}
+
+/// The crystal-interference normalization, derived from the scan read
+/// according to `io_args`
+fn normalization(component: CrystalInterference, io_args: io::hdf5::Args) -> Result<Normalization, Box<dyn Error>> {
+    let CrystalInterference { n_bins, scanner, scan } = component;
+    let _span = info_span!("normalization").entered();
+    let (input_file, dataset) = resolve_file_and_dataset(&scan, None, DEFAULT_LOR_DATASET);
+    let lors = io::hdf5::read_lors(io::hdf5::Args { input_files: vec![input_file], dataset, ..io_args }, None)?;
+    let component = NormalizationComponent::crystal_interference(n_bins, &scanner, &lors)
+        .map_err(|e| format!("Crystal interference from '{scan}': {e}"))?;
+    Ok(Normalization::default().with(component))
+}
+
+/// Directory in which the outputs named by `pattern` are written
+fn output_dir(pattern: &str) -> PathBuf {
+    PathBuf::from(format!("{pattern}00.raw")).parent().map_or_else(PathBuf::new, Path::to_path_buf)
+}
+
+/// Check that files can be written in `dir`, or in the directory in which it
+/// would be created, by creating (and removing) an empty file there
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let existing = dir.ancestors()
+        .map(|d| if d.as_os_str().is_empty() { Path::new(".") } else { d })
+        .find(|d| d.exists())
+        .unwrap_or(Path::new("."));
+    if !existing.is_dir() {
+        return Err(format!("Cannot write outputs to '{}': '{}' is not a directory", dir.display(), existing.display()))
+    }
+    let probe = existing.join(format!(".petalo-write-check-{}", std::process::id()));
+    std::fs::OpenOptions::new().write(true).create_new(true).open(&probe)
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("Cannot write outputs to '{}': {e}", dir.display()))
+}
+
+/// Read the image in `path`, which must match `fov` exactly
+fn load_matching(path: &Path, fov: FOV, what: &str) -> Result<Image, Box<dyn Error>> {
+    matching(Image::from_raw_file(path)?, path, fov, what)
+}
+
+/// `image`, read from `path`, if it matches `fov` exactly
+fn matching(image: Image, path: &Path, fov: FOV, what: &str) -> Result<Image, Box<dyn Error>> {
+    use float_eq::float_eq;
+    let widths = |fov: FOV| { let w = fov.half_width; [mm_(w[0]) * 2.0, mm_(w[1]) * 2.0, mm_(w[2]) * 2.0] };
+    let (actual, expected) = (widths(image.fov), widths(fov));
+    if image.fov.n != fov.n || !float_eq!(actual, expected, ulps_all <= 1) {
+        // TODO enable use of images with different pixelizations, as long as
+        // they cover the whole FOV
+        let [inx, iny, inz] = image.fov.n;
+        let [enx, eny, enz] = fov.n;
+        let ([idx, idy, idz], [edx, edy, edz]) = (actual, expected);
+        return Err(format!(
+            "{what} image '{}' does not match the FOV:\n\
+             {what} image: {inx:3} x {iny:3} x {inz:3} voxels, {idx:3} x {idy:3} x {idz:3} mm\n\
+             FOV: {enx:3} x {eny:3} x {enz:3} voxels, {edx:3} x {edy:3} x {edz:3} mm",
+            path.display()).into())
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test_reconstruction {
+    use super::*;
+    use crate::constants::ELECTRON_REST_ENERGY;
+    use crate::io::hdf5::{write_table, Hdf5Lor};
+    use crate::lorogram::AdaptiveBinning;
+    use crate::sink::STATS_COLUMNS;
+    use crate::testing::AnalyticSystem;
+    use float_eq::assert_float_eq;
+    use geometry::units::{mm, ps};
+
+    /// Write the LORs of `system` to a table in `dir`, with energies `energy`
+    /// and dt 0, returning its path
+    fn write_lors(dir: &Path, system: &AnalyticSystem, energy: f32) -> String {
+        let path = dir.join("lors.h5").to_str().unwrap().to_string();
+        let rows: Vec<Hdf5Lor> = system.measured_lors().iter()
+            .map(|lor| Hdf5Lor { E1: energy, E2: energy, q1: 1000.0, q2: 1000.0, ..Hdf5Lor::from(lor) })
+            .collect();
+        write_table(&path, DEFAULT_LOR_DATASET, &rows).unwrap();
+        path
+    }
+
+    #[test]
+    fn builder_matches_hand_assembled_pipeline() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+
+        let summary = Reconstruction::new()
+            .input(&path)
+            .fov(system.fov)
+            .iterations(4)
+            .prefetch(false)
+            .run()?;
+
+        let args = Reconstruction::new().input(&path).io;
+        let lors = io::hdf5::read_lors(args, None)?;
+        let (expected, _, _) = Image::mlem(system.fov, &lors, None, None, None, None, 1).nth(3).unwrap();
+
+        assert_eq!(summary.n_lors, lors.len());
+        assert_eq!(summary.final_image.unwrap().data, expected.data);
+        assert!(summary.outputs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn user_sinks_see_every_image() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let record = seen.clone();
+        Reconstruction::new()
+            .input(&path).fov(system.fov).iterations(3).subsets(2)
+            .sink(move |n: usize, _: &Image, _: &IterationStats| -> Result<(), Box<dyn Error>> {
+                record.borrow_mut().push(n);
+                Ok(())
+            })
+            .run()?;
+        assert_eq!(*seen.borrow(), vec![1, 2, 3, 4, 5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn iteration_stats_are_streamed_to_csv() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let csv = dir.path().join("stats.csv");
+        let summary = Reconstruction::new()
+            .input(&path).fov(system.fov).iterations(3)
+            .stats_out(&csv).likelihood_sample(100)
+            .run()?;
+
+        let text = std::fs::read_to_string(&csv)?;
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some(STATS_COLUMNS.join(",").as_str()));
+        let rows = lines.map(|line| line.split(',').map(String::from).collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(summary.iteration_stats.len(), 3);
+        for (row, stats) in rows.iter().zip(&summary.iteration_stats) {
+            let field = |name: &str| &row[STATS_COLUMNS.iter().position(|c| *c == name).unwrap()];
+            let number = |name: &str| field(name).parse::<f32>().unwrap();
+            assert_eq!(row.len(), STATS_COLUMNS.len());
+            assert_eq!(field("iteration").parse::<usize>()?, stats.iteration);
+            assert_eq!(field("subset").parse::<usize>()?, stats.subset);
+            assert_eq!(number("total"), stats.total);
+            assert_eq!(number("max"), stats.max);
+            assert_eq!(field("non_finite").parse::<usize>()?, stats.non_finite);
+            assert_eq!(Some(number("seconds")), stats.seconds);
+            assert_eq!(Some(number("mismatch")), stats.mismatch);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn statistics_not_calculated_are_empty_fields() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let csv = dir.path().join("stats.csv");
+        let summary = Reconstruction::new().input(&path).fov(system.fov).iterations(2).stats_out(&csv).run()?;
+        let text = std::fs::read_to_string(&csv)?;
+        for line in text.lines().skip(1) {
+            assert_eq!(line.split(',').count(), STATS_COLUMNS.len());
+            assert!(line.ends_with(','), "{line}");
+        }
+        assert!(summary.iteration_stats.iter().all(|s| s.mismatch.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn cancellation_after_first_iteration_keeps_its_outputs() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let cancel = Cancel::new(std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)));
+        let pattern = dir.path().join("out/image_").to_str().unwrap().to_string();
+        let summary = Reconstruction::new()
+            .input(&path).fov(system.fov).iterations(4)
+            .outputs(Outputs { pattern: pattern.clone(), variance_image: true, ..Outputs::default() })
+            .cancellation(cancel.clone())
+            .sink(move |n: usize, _: &Image, _: &IterationStats| -> Result<(), Box<dyn Error>> {
+                if n == 1 { cancel.request() }
+                Ok(())
+            })
+            .run()?;
+
+        assert_eq!(summary.status, RunStatus::Cancelled);
+        assert_eq!(serde_json::to_value(&summary)?["status"], "cancelled");
+        assert_eq!(summary.iteration_stats.len(), 1);
+        let first = PathBuf::from(format!("{pattern}01-01.raw"));
+        assert_eq!(summary.outputs, vec![first.clone()]);
+        let written: Vec<_> = std::fs::read_dir(dir.path().join("out"))?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_, _>>()?;
+        let mut images: Vec<_> = written.iter().filter(|name| name.ends_with(".raw")).collect();
+        images.sort();
+        assert_eq!(images, vec!["image_01-01.raw"]);
+        assert_eq!(Image::from_raw_file(&first)?.data, summary.final_image.unwrap().data);
+        Ok(())
+    }
+
+    #[test]
+    fn iterations_stop_once_images_stop_changing() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let summary = Reconstruction::new().input(&path).fov(system.fov).iterations(500).stop_when_delta(1e-3).run()?;
+        assert_eq!(summary.status, RunStatus::Converged);
+        assert_eq!(serde_json::to_value(&summary)?["status"], "converged");
+        let stats = &summary.iteration_stats;
+        assert!(stats.len() < 500);
+        assert!(stats.last().unwrap().max_change.unwrap() < 1e-3);
+        assert!(stats[1..stats.len() - 1].iter().all(|s| s.max_change.unwrap() >= 1e-3));
+
+        assert!(Reconstruction::new().input(&path).fov(system.fov).stop_when_delta(0.0).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resuming_from_a_checkpoint_matches_an_uninterrupted_run() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let checkpoint = dir.path().join("checkpoint.h5");
+        let run = |iterations| Reconstruction::new().input(&path).fov(system.fov).iterations(iterations);
+
+        let uninterrupted = run(5).run()?;
+        run(3).checkpoint(&checkpoint).run()?;
+        let resumed = run(5).resume_from(&checkpoint).run()?;
+
+        assert_eq!(resumed.status, RunStatus::Completed);
+        let numbers: Vec<_> = resumed.iteration_stats.iter().map(|s| s.iteration).collect();
+        assert_eq!(numbers, vec![4, 5]);
+        let (expected, actual) = (uninterrupted.final_image.unwrap().data, resumed.final_image.unwrap().data);
+        assert_float_eq!(actual, expected, rmax_all <= 1e-6);
+
+        // The checkpoint must match the FOV of the run
+        let other = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (3, 3, 3));
+        let err = Reconstruction::new().input(&path).fov(other).iterations(5).resume_from(&checkpoint).run().unwrap_err();
+        assert!(err.to_string().contains("does not match the FOV"), "{err}");
+        // ... and must not have done all the iterations already
+        assert!(run(3).resume_from(&checkpoint).run().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn auto_qcut_removes_the_lowest_charges_of_each_side() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = dir.path().join("lors.h5").to_str().unwrap().to_string();
+        // The lowest q1 and the lowest q2 are in different rows
+        let lor = Hdf5Lor::from(&system.measured_lors()[0]);
+        let rows: Vec<Hdf5Lor> = (0..1000)
+            .map(|i| Hdf5Lor { q1: (i + 1) as f32, q2: 2.0 * (1000 - i) as f32, ..lor.clone() })
+            .collect();
+        write_table(&path, DEFAULT_LOR_DATASET, &rows)?;
+
+        let run = |r: Reconstruction| r.input(&path).fov(system.fov).iterations(1).prefetch(false).run();
+        assert_eq!(run(Reconstruction::new())?.n_lors, 1000);
+        assert_eq!(run(Reconstruction::new().auto_qcut(0.05))?.n_lors, 900);
+        assert!(Reconstruction::new().auto_qcut(1.5).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn auto_ecut_keeps_the_photopeak() -> Result<(), Box<dyn Error>> {
+        use rand::{Rng, SeedableRng};
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = dir.path().join("lors.h5").to_str().unwrap().to_string();
+        // 4000 energies in a photopeak of σ 20 keV, truncated at 2σ, and 1000
+        // well below it
+        let lor = Hdf5Lor::from(&system.measured_lors()[0]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut rows = vec![Hdf5Lor { E1: 200.0, E2: 200.0, ..lor.clone() }; 1000];
+        while rows.len() < 5000 {
+            let (u, v): (f32, f32) = (1.0 - rng.gen::<f32>(), rng.gen());
+            let z = (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos();
+            if z.abs() < 2.0 { rows.push(Hdf5Lor { E1: 511.0 + 20.0 * z, E2: 511.0 - 20.0 * z, ..lor.clone() }) }
+        }
+        write_table(&path, DEFAULT_LOR_DATASET, &rows)?;
+
+        let run = |r: Reconstruction| r.input(&path).fov(system.fov).iterations(1).prefetch(false).run();
+        assert_eq!(run(Reconstruction::new())?.n_lors, 5000);
+        assert_eq!(run(Reconstruction::new().auto_ecut(AutoEcut::new(2.5)))?.n_lors, 4000);
+        assert!(Reconstruction::new().auto_ecut(AutoEcut::new(0.0)).validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_binning_with_scatter_windows_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let windows = || ScatterWindowArgs { time_dataset: "reco_info/time".into(), n_windows: 2 };
+        let scatter = BuildScattergram::new().phi_bins(10);
+        let adaptive = scatter.clone().adaptive(AdaptiveBinning { min_count: 100 });
+        let r = || Reconstruction::new().input(&path).fov(system.fov).scatter_time_windows(windows());
+        let err = r().scatter(adaptive).validate().unwrap_err();
+        assert_eq!(err, "Adaptive scattergram binning cannot be combined with scatter windows");
+        assert_eq!(r().scatter(scatter).validate(), Ok(()));
+    }
+
+    #[test]
+    fn tof_without_dt_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let err = Reconstruction::new().input(&path).fov(system.fov).tof(ps(200.0)).validate().unwrap_err();
+        assert!(err.starts_with("TOF sigma given, but the input has no TOF data"), "{err}");
+    }
+
+    #[test]
+    fn scatter_without_energies_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, f32::NAN);
+        let scatter = BuildScattergram::new().phi_bins(10);
+        let err = Reconstruction::new().input(&path).fov(system.fov).scatter(scatter.clone()).validate().unwrap_err();
+        assert!(err.starts_with("Scatter correction requires gamma energies"), "{err}");
+
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        assert_eq!(Reconstruction::new().input(&path).fov(system.fov).scatter(scatter).validate(), Ok(()));
+    }
+
+    #[test]
+    fn first_problem_is_reported() {
+        let err = Reconstruction::new().input("no/such/file.h5").iterations(0).validate().unwrap_err();
+        assert_eq!(err, "Input file 'no/such/file.h5' not found");
+        let err = Reconstruction::new().iterations(0).tof(ps(-1.0)).validate().unwrap_err();
+        assert_eq!(err, "Need at least one iteration");
+        let fov = FOV::new_from_full_widths((mm(2.0), mm(2.0), mm(1.0)), (2, 2, 1));
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_lors(dir.path(), &AnalyticSystem::two_d(), ELECTRON_REST_ENERGY);
+        let err = Reconstruction::new().input(&path).fov(fov).focus("sphere:0,0,0,1".parse().unwrap()).validate().unwrap_err();
+        assert_eq!(err, "A focus region requires an initial image");
+    }
+
+    #[test]
+    fn plan_counts_rows_and_memory() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let n_rows = system.measured_lors().len();
+        let pattern = dir.path().join("out/image_").to_str().unwrap().to_string();
+        let reconstruction = Reconstruction::new().input(&path).fov(system.fov).event_range(10..n_rows + 100)
+            .outputs(Outputs { pattern, ..Outputs::default() });
+
+        let plan = reconstruction.plan(1)?;
+        assert_eq!(plan.inputs, vec![(path, n_rows)]);
+        assert_eq!(plan.total_rows, n_rows - 10);
+        assert_eq!(plan.row_bytes, 11 * std::mem::size_of::<f32>());
+        assert_eq!(plan.output_dir, Some(dir.path().join("out")));
+        assert_eq!(plan.memory.lors, (n_rows - 10) * std::mem::size_of::<LOR>());
+        // The output directory is only created by `run`
+        assert!(!dir.path().join("out").exists());
+
+        let [nx, ny, nz] = system.fov.n;
+        let extra = reconstruction.plan(4)?.memory.total() - plan.memory.total();
+        assert_eq!(extra, 3 * nx * ny * nz * std::mem::size_of::<f32>());
+
+        // Prefetching holds a second chunk
+        let serial = reconstruction.prefetch(false).plan(1)?;
+        assert_eq!(plan.memory.read_buffer, 2 * serial.memory.read_buffer);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_rejects_unusable_inputs_and_outputs() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let path = write_lors(dir.path(), &system, ELECTRON_REST_ENERGY);
+        let not_lors = dir.path().join("not_lors.h5").to_str().unwrap().to_string();
+        write_table(&not_lors, DEFAULT_LOR_DATASET, &[1.0_f32, 2.0, 3.0])?;
+        let plan_error = |r: Reconstruction| r.fov(system.fov).plan(1).unwrap_err().to_string();
+
+        let err = plan_error(Reconstruction::new().input(&path).dataset("reco_info/nope"));
+        assert!(err.contains("Dataset 'reco_info/nope' not found"), "{err}");
+        let err = plan_error(Reconstruction::new().input(&not_lors));
+        assert!(err.contains("LOR table has no columns"), "{err}");
+        let err = plan_error(Reconstruction::new().input(&path).event_range(1_000_000..1_000_010));
+        assert!(err.contains("selects no rows"), "{err}");
+
+        // Outputs in a directory below a plain file
+        let pattern = dir.path().join("lors.h5/out/image_").to_str().unwrap().to_string();
+        let err = plan_error(Reconstruction::new().input(&path).outputs(Outputs { pattern, ..Outputs::default() }));
+        assert!(err.contains("is not a directory"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn axial_profile_needs_a_positive_half_length() {
+        let mode = |half| SensitivityMode::AxialProfile { detector_half_length: mm(half) };
+        let err = Reconstruction::new().sensitivity(mode(-1.0)).validate().unwrap_err();
+        assert!(err.starts_with("Detector half-length must be positive"), "{err}");
+        assert_eq!(Reconstruction::new().sensitivity(mode(100.0)).sensitivity, mode(100.0));
+    }
+}
@@ -0,0 +1,262 @@
+//! Detection and correction of translations between images of one object,
+//! such as a phantom repositioned between scans.
+//!
+//! Shifts are in voxels, and describe `moving` as `reference` translated by
+//! the shift: a feature at voxel `i` of `reference` appears at `i + shift` in
+//! `moving`.
+
+use rayon::prelude::*;
+
+use crate::Length;
+use crate::fov::FOV;
+use crate::image::Image;
+use crate::index::index3_to_1;
+
+/// Largest shift, in voxels along each axis, considered by `estimate_shift`
+pub const DEFAULT_SEARCH_RADIUS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Nearest,
+    Trilinear,
+}
+
+/// Translation of `moving` relative to `reference`, in voxels, searched within
+/// `DEFAULT_SEARCH_RADIUS`. See `estimate_shift_within`.
+pub fn estimate_shift(reference: &Image, moving: &Image) -> (f32, f32, f32) {
+    estimate_shift_within(reference, moving, DEFAULT_SEARCH_RADIUS)
+}
+
+/// Translation of `moving` relative to `reference`, in voxels, up to `radius`
+/// voxels along each axis.
+///
+/// The normalized cross-correlation of the images, over the window where they
+/// overlap, is evaluated at every whole-voxel shift within `radius`. The best
+/// one is refined to sub-voxel precision by maximizing the correlation with a
+/// trilinearly interpolated `moving` image along each axis in turn (a parabolic
+/// fit to the whole-voxel correlations is biased by up to ~0.2 voxels near
+/// half-voxel shifts). No shift is found for images without contrast.
+///
+/// Panics if the images have different numbers of voxels.
+pub fn estimate_shift_within(reference: &Image, moving: &Image, radius: usize) -> (f32, f32, f32) {
+    assert_eq!(reference.fov.n, moving.fov.n, "Cannot register images with different numbers of voxels");
+    let n = reference.fov.n;
+    let r = |axis: usize| radius.min(n[axis] - 1) as isize;
+    let (rx, ry, rz) = (r(0), r(1), r(2));
+    let mut shifts = vec![];
+    for z in -rz..=rz { for y in -ry..=ry { for x in -rx..=rx { shifts.push([x as f32, y as f32, z as f32]) } } }
+    let best = shifts.par_iter()
+        .filter_map(|&shift| correlation(reference, moving, shift).map(|c| (c, shift)))
+        .reduce_with(|a, b| if b.0 > a.0 { b } else { a });
+    let mut shift = match best {
+        Some((_, shift)) => shift,
+        None             => return (0.0, 0.0, 0.0),
+    };
+    // Coarse, then fine, golden-section search along each axis
+    for width in [1.0, 0.25] {
+        for axis in 0..3 {
+            if n[axis] < 2 { continue }
+            let score = move |u: f32| {
+                let mut s = shift;
+                s[axis] = u;
+                correlation(reference, moving, s).unwrap_or(f64::NEG_INFINITY)
+            };
+            shift[axis] = golden_section_max(score, shift[axis] - width, shift[axis] + width, 12);
+        }
+    }
+    (shift[0], shift[1], shift[2])
+}
+
+/// Shift in voxels converted to a physical offset in `fov`
+pub fn shift_to_offset(fov: FOV, (x, y, z): (f32, f32, f32)) -> (Length, Length, Length) {
+    let v = fov.voxel_size;
+    (v[0] * x, v[1] * y, v[2] * z)
+}
+
+/// Location of the maximum of unimodal `f` in `[lo, hi]`
+fn golden_section_max(f: impl Fn(f32) -> f64, mut lo: f32, mut hi: f32, iterations: usize) -> f32 {
+    let g = (5.0_f32.sqrt() - 1.0) / 2.0;
+    let (mut c, mut d) = (hi - g * (hi - lo), lo + g * (hi - lo));
+    let (mut fc, mut fd) = (f(c), f(d));
+    for _ in 0..iterations {
+        if fc > fd { hi = d; d = c; fd = fc; c = hi - g * (hi - lo); fc = f(c); }
+        else       { lo = c; c = d; fc = fd; d = lo + g * (hi - lo); fd = f(d); }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Indices and weights of the voxels between which position `u` (in voxels,
+/// centres at whole numbers) along an axis of `n` voxels is interpolated.
+/// `None` unless all of them lie inside the image.
+fn inner_taps(u: f32, n: usize) -> Option<([usize; 2], [f32; 2])> {
+    let k = u.floor();
+    if k < 0.0 || k > (n - 1) as f32 { return None }
+    let (k, f) = (k as usize, u - k);
+    if f == 0.0 { return Some(([k, k], [1.0, 0.0])) }
+    if k + 1 >= n { return None }
+    Some(([k, k + 1], [1.0 - f, f]))
+}
+
+/// Normalized cross-correlation of `reference` with `moving` sampled at `shift`
+/// (trilinearly, for fractional shifts), over the voxels of `reference` whose
+/// shifted counterparts lie inside `moving`. `None` if either image is
+/// constant over that window.
+fn correlation(reference: &Image, moving: &Image, shift: [f32; 3]) -> Option<f64> {
+    let n = reference.fov.n;
+    let taps: Vec<Vec<_>> = (0..3)
+        .map(|axis| (0..n[axis]).map(|i| inner_taps(i as f32 + shift[axis], n[axis])).collect())
+        .collect();
+    let (mut sr, mut sm, mut srr, mut smm, mut srm, mut count) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    for (iz, tz) in taps[2].iter().enumerate() {
+        let (kz, wz) = match tz { Some(t) => t, None => continue };
+        for (iy, ty) in taps[1].iter().enumerate() {
+            let (ky, wy) = match ty { Some(t) => t, None => continue };
+            for (ix, tx) in taps[0].iter().enumerate() {
+                let (kx, wx) = match tx { Some(t) => t, None => continue };
+                let mut m = 0.0;
+                for (&kz, &wz) in kz.iter().zip(wz) {
+                    for (&ky, &wy) in ky.iter().zip(wy) {
+                        for (&kx, &wx) in kx.iter().zip(wx) {
+                            let w = wx * wy * wz;
+                            if w > 0.0 { m += w * moving[index3_to_1([kx, ky, kz], n)] }
+                        }
+                    }
+                }
+                let (r, m) = (reference[[ix, iy, iz]] as f64, m as f64);
+                sr += r; sm += m; srr += r * r; smm += m * m; srm += r * m; count += 1.0;
+            }
+        }
+    }
+    if count < 2.0 { return None }
+    let (vr, vm) = (srr - sr * sr / count, smm - sm * sm / count);
+    if vr <= 0.0 || vm <= 0.0 { return None }
+    Some((srm - sr * sm / count) / (vr * vm).sqrt())
+}
+
+impl Image {
+    /// This image translated by `shift` voxels, so that `image.translate(s)`
+    /// resembles an image whose `estimate_shift` relative to `image` is `s`.
+    /// Voxels whose source lies outside the image are zero-filled, as are
+    /// the missing neighbours of those near its edges.
+    pub fn translate(&self, shift: (f32, f32, f32), interpolation: Interpolation) -> Self {
+        let n = self.fov.n;
+        let shift = [shift.0, shift.1, shift.2];
+        // Source voxels and weights of each output index along each axis
+        let taps: Vec<Vec<Vec<(usize, f32)>>> = (0..3)
+            .map(|axis| (0..n[axis]).map(|i| {
+                let u = i as f32 - shift[axis];
+                let candidates = match interpolation {
+                    Interpolation::Nearest   => vec![(u.round(), 1.0)],
+                    Interpolation::Trilinear => { let k = u.floor(); vec![(k, 1.0 - (u - k)), (k + 1.0, u - k)] }
+                };
+                candidates.into_iter()
+                    .filter(|&(k, w)| w > 0.0 && k >= 0.0 && k < n[axis] as f32)
+                    .map(|(k, w)| (k as usize, w))
+                    .collect()
+            }).collect())
+            .collect();
+        let mut data = vec![0.0; self.data.len()];
+        for iz in 0..n[2] {
+            for iy in 0..n[1] {
+                for ix in 0..n[0] {
+                    let mut v = 0.0;
+                    for &(kz, wz) in &taps[2][iz] {
+                        for &(ky, wy) in &taps[1][iy] {
+                            for &(kx, wx) in &taps[0][ix] {
+                                v += wx * wy * wz * self[[kx, ky, kz]];
+                            }
+                        }
+                    }
+                    data[index3_to_1([ix, iy, iz], n)] = v;
+                }
+            }
+        }
+        Self::new(self.fov, data)
+    }
+}
+
+#[cfg(test)]
+mod test_registration {
+    use super::*;
+    use geometry::units::{mm, mm_};
+    use float_eq::assert_float_eq;
+    use rstest::rstest;
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(40.0), mm(40.0), mm(40.0)), (20, 20, 20)) }
+
+    /// Two Gaussian blobs of different size and height, displaced by `shift` voxels
+    fn blobs((sx, sy, sz): (f32, f32, f32)) -> Image {
+        let n = fov().n;
+        let blobs = [([9.0, 10.0, 10.0], 2.5, 1.0), ([12.0, 8.0, 11.0], 2.0, 0.6)];
+        let mut data = vec![0.0; n[0] * n[1] * n[2]];
+        for iz in 0..n[2] { for iy in 0..n[1] { for ix in 0..n[0] {
+            let (x, y, z) = (ix as f32 - sx, iy as f32 - sy, iz as f32 - sz);
+            data[index3_to_1([ix, iy, iz], n)] = blobs.iter()
+                .map(|&([cx, cy, cz], s, a)| {
+                    let d2 = (x - cx).powi(2) + (y - cy).powi(2) + (z - cz).powi(2);
+                    a * (-0.5 * d2 / (s * s)).exp()
+                })
+                .sum();
+        }}}
+        Image::new(fov(), data)
+    }
+
+    #[rstest(/**/ shift,
+             case(( 0.0,  0.0, 0.0)),
+             case(( 2.0, -3.0, 1.0)),
+             case(( 1.4, -0.7, 0.3)),
+             case(( 0.5,  0.5, -0.5)),
+             case((-2.6,  0.5, 2.2)),
+    )]
+    fn known_shifts_are_recovered(shift: (f32, f32, f32)) {
+        let (x, y, z) = estimate_shift(&blobs((0.0, 0.0, 0.0)), &blobs(shift));
+        assert_float_eq!([x, y, z], [shift.0, shift.1, shift.2], abs_all <= 0.2);
+    }
+
+    #[test]
+    fn translated_image_is_recovered() {
+        let reference = blobs((0.0, 0.0, 0.0));
+        let shift = (-1.3, 2.0, 0.6);
+        let (x, y, z) = estimate_shift(&reference, &reference.translate(shift, Interpolation::Trilinear));
+        assert_float_eq!([x, y, z], [shift.0, shift.1, shift.2], abs_all <= 0.2);
+    }
+
+    #[test]
+    fn featureless_images_have_no_shift() {
+        let flat = Image::ones(fov());
+        assert_eq!(estimate_shift_within(&flat, &flat, 2), (0.0, 0.0, 0.0));
+    }
+
+    #[rstest(/**/ interpolation,
+             case(Interpolation::Nearest),
+             case(Interpolation::Trilinear),
+    )]
+    fn whole_voxel_translation_moves_values_and_zero_fills(interpolation: Interpolation) {
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(3.0), mm(1.0)), (4, 3, 1));
+        let image = Image::new(fov, (1..=12).map(|v| v as f32).collect());
+        let moved = image.translate((1.0, -1.0, 0.0), interpolation);
+        assert_eq!(moved.data, vec![
+            0.0,  5.0,  6.0,  7.0,
+            0.0,  9.0, 10.0, 11.0,
+            0.0,  0.0,  0.0,  0.0,
+        ]);
+    }
+
+    #[test]
+    fn fractional_trilinear_translation_interpolates() {
+        // A ramp along x is shifted exactly, except at the edge where a
+        // missing neighbour contributes zero
+        let fov = FOV::new_from_full_widths((mm(6.0), mm(1.0), mm(1.0)), (6, 1, 1));
+        let image = Image::new(fov, (1..=6).map(|v| 10.0 * v as f32).collect());
+        let moved = image.translate((0.25, 0.0, 0.0), Interpolation::Trilinear);
+        assert_float_eq!(moved.data, vec![7.5, 17.5, 27.5, 37.5, 47.5, 57.5], abs_all <= 1e-4);
+        let nearest = image.translate((0.25, 0.0, 0.0), Interpolation::Nearest);
+        assert_eq!(nearest.data, image.data);
+    }
+
+    #[test]
+    fn shift_converts_to_offset() {
+        let (x, y, z) = shift_to_offset(fov(), (1.0, -0.5, 2.0));
+        assert_float_eq!([mm_(x), mm_(y), mm_(z)], [2.0, -1.0, 4.0], abs_all <= 1e-6);
+    }
+}
@@ -0,0 +1,363 @@
+//! Self-contained HTML reports comparing several reconstructions.
+//!
+//! Each run is an image file, optionally accompanied by a TOML sidecar with the
+//! same name and a `.toml` extension, whose top-level entries (parameters,
+//! timings, ...) are shown in the table of runs. The FOMs of each image are
+//! calculated for a common set of ROIs, and central slices and maximum
+//! intensity projections are embedded in the page as PNGs.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use ::image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+
+use crate::fom::{FomConfig, FOMS};
+use crate::image::Image;
+use crate::index::index3_to_1;
+use crate::registration::{estimate_shift, shift_to_offset};
+use geometry::units::mm_;
+
+/// Everything shown about a single run
+pub struct Run {
+    pub name: String,
+    /// Sidecar contents, or the reason they are not available
+    pub parameters: Result<BTreeMap<String, String>, String>,
+    /// FOMs and projections, or the reason the image could not be used
+    pub results: Result<(FOMS, Vec<Projection>), String>,
+    /// Shift (voxels) of this image relative to the alignment reference, if any
+    pub shift: Option<(f32, f32, f32)>,
+}
+
+/// 2D view of an image, encoded as PNG
+pub struct Projection {
+    pub caption: String,
+    pub png: Vec<u8>,
+}
+
+/// Sidecar file holding metadata about the image at `image_path`
+pub fn sidecar_path(image_path: &Path) -> PathBuf { image_path.with_extension("toml") }
+
+/// Read sidecar, flattening nested tables into dotted keys
+pub fn read_sidecar(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("no metadata ({}: {e})", path.display()))?;
+    let table: toml::value::Table = toml::from_str(&text)
+        .map_err(|e| format!("unreadable metadata ({}: {e})", path.display()))?;
+    fn flatten(prefix: &str, table: &toml::value::Table, out: &mut BTreeMap<String, String>) {
+        for (key, value) in table {
+            let key = if prefix.is_empty() { key.clone() } else { format!("{prefix}.{key}") };
+            match value {
+                toml::Value::Table(t)  => flatten(&key, t, out),
+                toml::Value::String(s) => { out.insert(key, s.clone()); },
+                other                  => { out.insert(key, other.to_string()); },
+            }
+        }
+    }
+    let mut out = BTreeMap::new();
+    flatten("", &table, &mut out);
+    Ok(out)
+}
+
+impl Run {
+    pub fn new(image_path: &Path, config: &FomConfig) -> Self {
+        Self::analyse(image_path, |image| Ok((image.foms(config, true), None)))
+    }
+
+    /// As `new`, but with the ROIs displaced to follow the shift of the image
+    /// relative to `reference`
+    pub fn aligned(image_path: &Path, config: &FomConfig, reference: &Image) -> Self {
+        Self::analyse(image_path, |image| {
+            if image.fov.n != reference.fov.n {
+                return Err(format!("cannot align: {:?} voxels, reference has {:?}", image.fov.n, reference.fov.n));
+            }
+            let shift = estimate_shift(reference, image);
+            let config = config.translated(shift_to_offset(image.fov, shift));
+            Ok((image.foms(&config, true), Some(shift)))
+        })
+    }
+
+    fn analyse(image_path: &Path, foms: impl FnOnce(&Image) -> Result<(FOMS, Option<(f32, f32, f32)>), String>) -> Self {
+        let name = image_path.display().to_string();
+        let parameters = read_sidecar(&sidecar_path(image_path));
+        let mut shift = None;
+        let results = Image::from_raw_file(image_path)
+            .map_err(|e| format!("cannot read image: {e}"))
+            .and_then(|image| {
+                let (foms, s) = foms(&image)?;
+                shift = s;
+                Ok((foms, projections(&image)?))
+            });
+        Self { name, parameters, results, shift }
+    }
+}
+
+/// Central slices and maximum intensity projections along each axis
+pub fn projections(image: &Image) -> Result<Vec<Projection>, String> {
+    const NAMES: [&str; 3] = ["x", "y", "z"];
+    let coordinates: Vec<Vec<f32>> = (0..3)
+        .map(|axis| image.fov.axis_coordinates(axis).into_iter().map(mm_).collect())
+        .collect();
+    let span = |axis: usize| {
+        let c = &coordinates[axis];
+        format!("{} {:.1} to {:.1} mm", NAMES[axis], c[0], c[c.len() - 1])
+    };
+    let mut out = vec![];
+    for (axis, name) in NAMES.iter().enumerate() {
+        let (u, v) = plane_axes(axis);
+        let extent = format!("{} across, {} up", span(u), span(v));
+        for mip in [false, true] {
+            let caption = if mip { format!("MIP along {name}: {extent}") }
+                          else   { format!("central {name} slice ({name} = {:.1} mm): {extent}", coordinates[axis][image.fov.n[axis] / 2]) };
+            let png = png(&plane(image, axis, mip)).map_err(|e| e.to_string())?;
+            out.push(Projection { caption, png });
+        }
+    }
+    Ok(out)
+}
+
+/// Horizontal and vertical axes of pictures of the plane perpendicular to `axis`
+fn plane_axes(axis: usize) -> (usize, usize) {
+    match axis { 0 => (1, 2), 1 => (0, 2), _ => (0, 1) }
+}
+
+/// Greyscale picture of the plane perpendicular to `axis`: either the central
+/// slice or, if `mip`, the maximum along `axis`. Normalized to its own maximum.
+fn plane(image: &Image, axis: usize, mip: bool) -> GrayImage {
+    let n = image.fov.n;
+    let (u, v) = plane_axes(axis);
+    let layers = if mip { 0..n[axis] } else { n[axis] / 2 .. n[axis] / 2 + 1 };
+    let value = |i: usize, j: usize| {
+        layers.clone().map(|k| {
+            let mut index = [0; 3];
+            index[u] = i; index[v] = j; index[axis] = k;
+            image.data[index3_to_1(index, n)]
+        }).fold(f32::NEG_INFINITY, f32::max)
+    };
+    let values: Vec<f32> = (0..n[v]).rev()
+        .flat_map(|j| (0..n[u]).map(move |i| (i, j)))
+        .map(|(i, j)| value(i, j))
+        .collect();
+    let max = values.iter().copied().fold(0.0, f32::max);
+    let scale = if max > 0.0 { 255.0 / max } else { 0.0 };
+    GrayImage::from_fn(n[u] as u32, n[v] as u32, |x, y| {
+        let value = values[y as usize * n[u] + x as usize];
+        Luma([(value * scale).clamp(0.0, 255.0).round() as u8])
+    })
+}
+
+fn png(picture: &GrayImage) -> ::image::ImageResult<Vec<u8>> {
+    let mut bytes = vec![];
+    DynamicImage::ImageLuma8(picture.clone()).write_to(&mut bytes, ImageOutputFormat::Png)?;
+    Ok(bytes)
+}
+
+/// Standard base64 encoding with padding
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() { out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char) }
+            else                { out.push('=') }
+        }
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Render the runs as a single self-contained HTML page
+pub fn html_report(title: &str, runs: &[Run]) -> String {
+    // Union of all parameter names, and the largest number of ROIs
+    let keys: BTreeSet<&String> = runs.iter()
+        .filter_map(|run| run.parameters.as_ref().ok())
+        .flat_map(BTreeMap::keys)
+        .collect();
+    let n_rois = runs.iter()
+        .filter_map(|run| run.results.as_ref().ok())
+        .map(|(foms, _)| foms.crcs.len())
+        .max().unwrap_or(0);
+    let any_shift = runs.iter().any(|run| run.shift.is_some());
+
+    let mut h = String::new();
+    // Writing to a String cannot fail
+    let _ = writeln!(h, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>", escape(title));
+    h.push_str("<style>\n\
+                body { font-family: sans-serif; }\n\
+                table { border-collapse: collapse; }\n\
+                td, th { border: 1px solid #aaa; padding: 2px 6px; text-align: right; }\n\
+                .problem { color: #b00; }\n\
+                figure { display: inline-block; margin: 4px; }\n\
+                img { width: 160px; image-rendering: pixelated; }\n\
+                </style>\n</head>\n<body>\n");
+    let _ = writeln!(h, "<h1>{}</h1>", escape(title));
+
+    // Table of runs
+    h.push_str("<table>\n<tr><th>run</th>");
+    for key in &keys { let _ = write!(h, "<th>{}</th>", escape(key)); }
+    if any_shift { h.push_str("<th>shift / voxels</th>"); }
+    for i in 0..n_rois { let _ = write!(h, "<th>CRC {i}</th><th>SNR {i}</th>"); }
+    h.push_str("<th>problems</th></tr>\n");
+    for run in runs {
+        let _ = write!(h, "<tr><td>{}</td>", escape(&run.name));
+        let no_parameters = BTreeMap::new();
+        let parameters = run.parameters.as_ref().unwrap_or(&no_parameters);
+        for key in &keys {
+            let _ = write!(h, "<td>{}</td>", escape(parameters.get(*key).map_or("", String::as_str)));
+        }
+        if any_shift {
+            let shift = run.shift.map_or(String::new(), |(x, y, z)| format!("({x:.2}, {y:.2}, {z:.2})"));
+            let _ = write!(h, "<td>{shift}</td>");
+        }
+        for i in 0..n_rois {
+            let fom = |v: &[f32]| v.get(i).map_or(String::new(), |x| format!("{x:.2}"));
+            let (crc, snr) = match &run.results {
+                Ok((foms, _)) => (fom(&foms.crcs), fom(&foms.snrs)),
+                Err(_)        => (String::new(), String::new()),
+            };
+            let _ = write!(h, "<td>{crc}</td><td>{snr}</td>");
+        }
+        let problems: Vec<&str> = [run.parameters.as_ref().err(), run.results.as_ref().err()]
+            .into_iter().flatten().map(String::as_str).collect();
+        let _ = writeln!(h, "<td class=\"problem\">{}</td></tr>", escape(&problems.join("; ")));
+    }
+    h.push_str("</table>\n");
+
+    // Image grid
+    for run in runs {
+        let _ = writeln!(h, "<h2>{}</h2>", escape(&run.name));
+        match &run.results {
+            Ok((_, projections)) => {
+                h.push_str("<div>\n");
+                for Projection { caption, png } in projections {
+                    let _ = writeln!(h, "<figure><img src=\"data:image/png;base64,{}\" alt=\"{caption}\"><figcaption>{caption}</figcaption></figure>",
+                                     base64(png));
+                }
+                h.push_str("</div>\n");
+            },
+            Err(e) => { let _ = writeln!(h, "<p class=\"problem\">{}</p>", escape(e)); },
+        }
+    }
+    h.push_str("</body>\n</html>\n");
+    h
+}
+
+/// Analyse the images at `image_paths` and render the report. Problems with
+/// individual runs are shown in the report rather than aborting it.
+pub fn report(title: &str, image_paths: &[PathBuf], config: &FomConfig) -> String {
+    report_aligned(title, image_paths, config, None)
+}
+
+/// As `report`, but if a `reference` image is given, each run's ROIs are
+/// displaced to follow its shift relative to the reference
+pub fn report_aligned(title: &str, image_paths: &[PathBuf], config: &FomConfig, reference: Option<&Image>) -> String {
+    let runs: Vec<Run> = image_paths.iter()
+        .map(|path| match reference {
+            Some(reference) => Run::aligned(path, config, reference),
+            None            => Run::new    (path, config),
+        })
+        .collect();
+    html_report(title, &runs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fom::ROI;
+    use crate::fov::FOV;
+    use geometry::units::mm;
+
+    #[test]
+    fn base64_matches_reference() {
+        assert_eq!(base64(b""      ), "");
+        assert_eq!(base64(b"f"     ), "Zg==");
+        assert_eq!(base64(b"fo"    ), "Zm8=");
+        assert_eq!(base64(b"foo"   ), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    /// Uniform image of ones, with a sphere of radius 4 mm at `(x, 0, 0)`
+    fn hot_sphere(x: f32, hot: f32) -> Image {
+        let fov = FOV::new_from_full_widths((mm(20.0), mm(20.0), mm(20.0)), (10, 10, 10));
+        let mut image = Image::ones(fov);
+        for i in 0..image.data.len() {
+            let p = fov.voxel_centre1(i);
+            let dx = p.x - mm(x);
+            if dx * dx + p.y * p.y + p.z * p.z < mm(4.0) * mm(4.0) { image[i] = hot; }
+        }
+        image
+    }
+
+    fn synthetic_run(dir: &Path, name: &str, hot: f32, sidecar: Option<&str>) -> PathBuf {
+        let path = dir.join(format!("{name}.raw"));
+        hot_sphere(0.0, hot).write_to_raw_file(&path).unwrap();
+        if let Some(text) = sidecar { std::fs::write(sidecar_path(&path), text).unwrap(); }
+        path
+    }
+
+    #[test]
+    fn report_for_two_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FomConfig::new(
+            vec![(ROI::Sphere((mm(0.0), mm(0.0), mm(0.0)), mm(3.0)), 4.0)],
+            vec![ROI::CylinderZ((mm(7.0), mm(7.0)), mm(2.0))],
+            1.0);
+        let paths = [
+            synthetic_run(dir.path(), "run-a", 3.0, Some("iterations = 17\nsubsets = 2\n[timing]\ntotal_s = 12.5\n")),
+            synthetic_run(dir.path(), "run-b", 3.5, None),
+        ];
+        let html = report("sweep", &paths, &config);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.trim_end().ends_with("</html>"));
+        assert!(html.contains("run-a.raw") && html.contains("run-b.raw"));
+        // Parameters of the run with a sidecar
+        assert!(html.contains("<th>iterations</th>") && html.contains("<td>17</td>"));
+        assert!(html.contains("<th>timing.total_s</th>") && html.contains("<td>12.5</td>"));
+        // Missing sidecar reported for that run only, and its images still shown
+        assert_eq!(html.matches("no metadata").count(), 1);
+        assert!(html.contains("run-b.toml"));
+        assert_eq!(html.matches("<img ").count(), 2 * 6);
+        // Axis labels from voxel-centre coordinates
+        assert!(html.contains("central z slice (z = 1.0 mm): x -9.5 to 9.5 mm across, y -9.5 to 9.5 mm up"));
+        // CRC: (3/1 - 1) / (4/1 - 1) = 66.67%
+        assert!(html.contains("<td>66.67</td>"), "{html}");
+        // No alignment requested
+        assert!(!html.contains("shift / voxels"));
+    }
+
+    #[test]
+    fn aligned_report_follows_shifted_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FomConfig::new(
+            vec![(ROI::Sphere((mm(0.0), mm(0.0), mm(0.0)), mm(3.0)), 4.0)],
+            vec![ROI::CylinderZ((mm(-7.0), mm(-7.0)), mm(2.0))],
+            1.0);
+        // Hot sphere displaced by 2 voxels along x
+        let path = dir.path().join("shifted.raw");
+        hot_sphere(4.0, 3.0).write_to_raw_file(&path).unwrap();
+        let reference = hot_sphere(0.0, 3.0);
+        let unaligned = report        ("shifted", &[path.clone()], &config);
+        let aligned   = report_aligned("shifted", &[path        ], &config, Some(&reference));
+        assert!(!unaligned.contains("<td>66.67</td>"));
+        assert!(  aligned .contains("<td>66.67</td>"), "{aligned}");
+        assert!(  aligned .contains("<th>shift / voxels</th>"));
+    }
+
+    #[test]
+    fn unreadable_image_does_not_abort_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let config: FomConfig = "background_activity = 1.0\n[[background]]\nshape = \"cylinderz\"\ncentre = [7.0, 7.0]\nr = 2.0\n"
+            .parse().unwrap();
+        let good = synthetic_run(dir.path(), "good", 2.0, Some("tof = \"200 ps\"\n"));
+        let missing = dir.path().join("missing.raw");
+        let html = report("partial", &[good, missing], &config);
+        assert!(html.contains("cannot read image"));
+        assert!(html.contains("<td>200 ps</td>"));
+        assert_eq!(html.matches("<img ").count(), 6);
+    }
+}
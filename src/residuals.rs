@@ -0,0 +1,197 @@
+//! Projection-space residuals of a reconstruction: the measured events, and
+//! the forward projection of the final image, summed over the bins of a
+//! lorogram, to show where (in z, r, φ, Δz, ...) the model fails to explain
+//! the data.
+//!
+//! Identical LORs (same endpoints and `dt`) are taken to be repeated events in
+//! a single, binned LOR: each of them is a measured event, but the forward
+//! projection, which is the expected number of events in the LOR, is counted
+//! once. In list-mode data every LOR is distinct, so each contributes one
+//! measured event and its own forward projection.
+//!
+//! Ratios near 1 are only expected of images reconstructed with the
+//! sensitivity of the scanner; otherwise compare them with
+//! `Residuals::overall_ratio`.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::Write;
+
+use crate::Time;
+use crate::gauss::TofCutoff;
+use crate::image::Image;
+use crate::lorogram::Lorogram;
+use crate::mlem::forward_projections;
+use crate::system_matrix::{LOR, Tube};
+use geometry::units::{mm_, ns_};
+
+/// Measured and modelled events in one bin of the lorogram
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResidualBin {
+    pub index: usize,
+    /// The intervals of the bin along each axis (see `Lorogram::bin_at`)
+    pub bin: String,
+    pub measured: usize,
+    /// Sum of the forward projections of the distinct LORs in the bin
+    pub modelled: f64,
+}
+
+impl ResidualBin {
+    /// `measured / modelled`: infinite in bins with events but no model, NaN
+    /// in bins with neither
+    pub fn ratio(&self) -> f64 { self.measured as f64 / self.modelled }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Residuals {
+    /// Every bin of the lorogram, overflow bins included, in order of index
+    pub bins: Vec<ResidualBin>,
+    /// Events whose LORs miss the FOV or lie in no bin
+    pub unbinned: usize,
+}
+
+impl Residuals {
+    pub fn measured(&self) -> usize { self.bins.iter().map(|b| b.measured).sum() }
+    pub fn modelled(&self) -> f64   { self.bins.iter().map(|b| b.modelled).sum() }
+
+    /// Ratio of all measured to all modelled events
+    pub fn overall_ratio(&self) -> f64 { self.measured() as f64 / self.modelled() }
+
+    /// One row per bin, with columns `index,bin,measured,modelled,ratio`
+    pub fn write_csv(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "index,bin,measured,modelled,ratio")?;
+        for b in &self.bins {
+            // The bin description contains commas, but never quotes
+            writeln!(out, "{},\"{}\",{},{},{}", b.index, b.bin, b.measured, b.modelled, b.ratio())?;
+        }
+        Ok(())
+    }
+}
+
+/// Residuals between the events `lors` and the forward projection of `image`
+/// (including the corrections of the LORs) in each bin of the lorograms made
+/// by `make_empty_lorogram`. The measured events are filled into such a
+/// lorogram; the modelled ones, which are not whole numbers, are summed
+/// alongside, at the same indices.
+pub fn residuals(
+    image: &Image,
+    lors: &[LOR],
+    make_empty_lorogram: &(dyn Fn() -> Box<dyn Lorogram>),
+    sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>,
+) -> Residuals {
+    let mut position = HashMap::new();
+    let mut distinct: Vec<LOR> = vec![];
+    let mut events: Vec<usize> = vec![];
+    for lor in lors {
+        match position.entry(key(lor)) {
+            Entry::Occupied(e) => events[*e.get()] += 1,
+            Entry::Vacant(e) => {
+                e.insert(distinct.len());
+                distinct.push(*lor);
+                events.push(1);
+            }
+        }
+    }
+
+    let mut measured = make_empty_lorogram();
+    let mut modelled = vec![0.0; measured.n_bins()];
+    let mut unbinned = 0;
+    let projections = forward_projections(image, &distinct, sigma, cutoff, tube);
+    for ((lor, n), projection) in distinct.iter().zip(events).zip(projections) {
+        match (measured.index(lor), projection) {
+            (Some(i), Some(p)) => {
+                measured.add_at(i, n);
+                modelled[i] += p as f64;
+            }
+            _ => unbinned += n,
+        }
+    }
+
+    let bins = modelled.into_iter().enumerate()
+        .map(|(index, modelled)| ResidualBin {
+            index,
+            bin: measured.bin_at(index).unwrap_or_default(),
+            measured: measured.value_at(index),
+            modelled,
+        })
+        .collect();
+    Residuals { bins, unbinned }
+}
+
+/// Endpoints and `dt`, exactly; `+ 0.0` makes -0 and 0 the same
+fn key(lor: &LOR) -> [u32; 7] {
+    let [x1, y1, z1, x2, y2, z2] = [lor.p1.x, lor.p1.y, lor.p1.z, lor.p2.x, lor.p2.y, lor.p2.z].map(|c| (mm_(c) + 0.0).to_bits());
+    [x1, y1, z1, x2, y2, z2, (ns_(lor.dt) + 0.0).to_bits()]
+}
+
+impl std::fmt::Display for Residuals {
+    /// The bins with measured or modelled events
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Residuals: {} measured, {:.1} modelled events (ratio {:.3}); {} unbinned",
+                 self.measured(), self.modelled(), self.overall_ratio(), self.unbinned)?;
+        writeln!(f, "  {:>6} {:>10} {:>12} {:>8}  bin", "index", "measured", "modelled", "ratio")?;
+        for b in self.bins.iter().filter(|b| b.measured > 0 || b.modelled > 0.0) {
+            writeln!(f, "  {:>6} {:>10} {:>12.1} {:>8.3}  {}", b.index, b.measured, b.modelled, b.ratio(), b.bin)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_residuals {
+    use super::*;
+    use crate::lorogram::axis_dz;
+    use crate::testing::AnalyticSystem;
+    use float_eq::assert_float_eq;
+    use geometry::units::mm;
+    use ndhistogram::ndhistogram;
+
+    /// Transverse LORs (Δz = 0) in bin 1 and axial ones (Δz = 100 mm) in bin
+    /// 2; bins 0 and 3 are the underflow and overflow bins
+    fn by_dz() -> Box<dyn Lorogram> { Box::new(ndhistogram!(axis_dz(2, mm(150.0)); usize)) }
+
+    /// Point source: a hot voxel in a cold background
+    fn reconstructed(system: &AnalyticSystem, lors: &[LOR]) -> Image {
+        let (image, _, _) = Image::mlem(system.fov, lors, None, None, None, Some(system.sensitivity_image()), 1)
+            .nth(199).unwrap();
+        image
+    }
+
+    #[test]
+    fn reconstructed_point_source_explains_the_data() {
+        let system = AnalyticSystem::two_d_hot();
+        let lors = system.measured_lors();
+        let residuals = residuals(&reconstructed(&system, &lors), &lors, &by_dz, None, None, None);
+        assert_eq!(residuals.unbinned, 0);
+        let measured: Vec<usize> = residuals.bins.iter().map(|b| b.measured).collect();
+        assert_eq!(measured, vec![0, 17 + 2 + 17 + 2, 16 + 1 + 1 + 1, 0]);
+        for b in &residuals.bins[1..3] {
+            assert_float_eq!(b.ratio(), 1.0, abs <= 1e-3);
+        }
+        assert_float_eq!(residuals.overall_ratio(), 1.0, abs <= 1e-3);
+    }
+
+    #[test]
+    fn scale_mismatch_shows_in_every_bin_and_csv() -> Result<(), Box<dyn std::error::Error>> {
+        let system = AnalyticSystem::two_d_hot();
+        let lors = system.measured_lors();
+        // Four transverse LORs of 2 voxels and four axial ones of 1, each 1 mm
+        let residuals = residuals(&Image::ones(system.fov), &lors, &by_dz, None, None, None);
+        assert_float_eq!(residuals.bins[1].modelled, 8.0, rmax <= 1e-5);
+        assert_float_eq!(residuals.bins[2].modelled, 4.0, rmax <= 1e-5);
+        assert_float_eq!(residuals.overall_ratio(), 57.0 / 12.0, rmax <= 1e-5);
+        for b in &residuals.bins[1..3] {
+            assert_float_eq!(b.ratio(), residuals.overall_ratio(), rmax <= 1e-5);
+        }
+        assert!(residuals.bins[0].ratio().is_nan());
+
+        let mut csv = vec![];
+        residuals.write_csv(&mut csv)?;
+        let csv = String::from_utf8(csv)?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 1 + 4);
+        assert_eq!(lines[0], "index,bin,measured,modelled,ratio");
+        assert!(lines[3].starts_with("2,\"") && lines[3].ends_with("\",19,4,4.75"), "{}", lines[3]);
+        Ok(())
+    }
+}
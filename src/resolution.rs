@@ -0,0 +1,314 @@
+//! Intrinsic spatial resolution from list-mode data of a line source parallel
+//! to the z-axis, without reconstruction.
+//!
+//! Each LOR is placed in the sinogram by its `view_angle` and its signed
+//! distance `s` from the z-axis (see `lorogram::sinogram_s`). A line source at
+//! transverse position `(x, y)` lies at `s0 = y cos φ - x sin φ`, so the
+//! distribution of `s - s0` within each view is the resolution. A Gaussian is
+//! fitted to it in every view with enough counts.
+
+use std::f32::consts::PI;
+use std::io::Write;
+use std::str::FromStr;
+
+use ndarray::Array2;
+
+use crate::{Anglef32, Length, Lengthf32};
+use crate::lorogram::{sinogram_s, view_angle};
+use crate::system_matrix::LOR;
+use geometry::units::{mm, mm_};
+
+/// Ratio of the FWHM of a Gaussian to its sigma: `2 sqrt(2 ln 2)`
+pub const FWHM_PER_SIGMA: f32 = 2.354_820;
+
+/// How to fit a Gaussian to a histogram
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FitMethod {
+    /// Mean and standard deviation of the whole histogram
+    Moments,
+    /// Parabola fitted to the logarithm of the counts in the bins with at least
+    /// 1/16 of the peak's counts (within about 2.35 sigma of the centroid),
+    /// weighted by the counts
+    LeastSquares,
+}
+
+impl FromStr for FitMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "moments" => Ok(Self::Moments),
+            "lsq"     => Ok(Self::LeastSquares),
+            _ => Err(format!("Unknown fit method `{s}`: expected `moments` or `lsq`")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GaussianFit {
+    /// Offset of the peak from the source
+    pub centroid: Length,
+    pub fwhm: Length,
+    /// Height of the peak, in counts per bin
+    pub amplitude: f32,
+}
+
+/// Fit to the distribution of `s - s0` in one view
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewFit {
+    /// Centre of the view, in radians
+    pub phi: Anglef32,
+    /// LORs in the view, within `s_max` of the source
+    pub counts: usize,
+    /// `None` if the view has fewer than `min_counts` LORs, or the fit failed
+    pub fit: Option<GaussianFit>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineSourceFit {
+    pub views: Vec<ViewFit>,
+    /// Count-weighted mean of the fits of all fitted views
+    pub average: Option<GaussianFit>,
+}
+
+impl LineSourceFit {
+    /// Write one row per view, and a final row, with `phi` = `all`, of the
+    /// average. Views which were not fitted have empty fields.
+    pub fn write_csv(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "phi_deg,counts,centroid_mm,fwhm_mm,amplitude")?;
+        let fields = |fit: Option<GaussianFit>| fit.map_or(",,".into(), |GaussianFit { centroid, fwhm, amplitude }| {
+            format!("{:.4},{:.4},{amplitude:.2}", mm_(centroid), mm_(fwhm))
+        });
+        for ViewFit { phi, counts, fit } in &self.views {
+            writeln!(out, "{:.3},{counts},{}", phi.to_degrees(), fields(*fit))?;
+        }
+        let total: usize = self.views.iter().map(|v| v.counts).sum();
+        writeln!(out, "all,{total},{}", fields(self.average))
+    }
+}
+
+/// Fit of the resolution in `n_phi` views covering `[0, π)`, with the
+/// distribution of `s - s0` in `n_s` bins in `[-s_max, s_max]`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineSourceAnalysis {
+    /// Transverse position of the source
+    pub source: (Length, Length),
+    pub n_phi: usize,
+    pub n_s: usize,
+    pub s_max: Length,
+    /// Views with fewer LORs are not fitted
+    pub min_counts: usize,
+    pub method: FitMethod,
+}
+
+impl LineSourceAnalysis {
+    pub fn new(x: Length, y: Length) -> Self {
+        Self { source: (x, y), n_phi: 36, n_s: 101, s_max: mm(10.0), min_counts: 100, method: FitMethod::LeastSquares }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.n_phi == 0 { return Err("Need at least one view".into()) }
+        if self.n_s < 3    { return Err(format!("Need at least 3 bins in s, not {}", self.n_s)) }
+        if mm_(self.s_max) <= 0.0 || self.s_max.is_nan() { return Err(format!("s_max must be positive, not {:?}", self.s_max)) }
+        Ok(())
+    }
+
+    /// View angle of `lor` and its distance from the source in the sinogram
+    pub fn residual(&self, lor: &LOR) -> (Anglef32, Length) {
+        let phi = view_angle(lor);
+        let (x, y) = self.source;
+        (phi, sinogram_s(lor) - (y * phi.cos() - x * phi.sin()))
+    }
+
+    fn bin_width(&self) -> Lengthf32 { 2.0 * mm_(self.s_max) / self.n_s as f32 }
+
+    /// Counts of `lors` indexed by `[view, s bin]`. LORs further than `s_max`
+    /// from the source are ignored.
+    pub fn histogram(&self, lors: &[LOR]) -> Array2<usize> {
+        let mut counts = Array2::zeros((self.n_phi, self.n_s));
+        let (s_max, width) = (mm_(self.s_max), self.bin_width());
+        for lor in lors {
+            let (phi, ds) = self.residual(lor);
+            let ds = mm_(ds);
+            if ds.is_nan() || ds < -s_max || ds >= s_max { continue }
+            let view = ((phi / PI * self.n_phi as f32) as usize).min(self.n_phi - 1);
+            let bin  = (((ds + s_max) / width) as usize).min(self.n_s - 1);
+            counts[[view, bin]] += 1;
+        }
+        counts
+    }
+
+    pub fn fit(&self, lors: &[LOR]) -> Result<LineSourceFit, String> {
+        self.validate()?;
+        let counts = self.histogram(lors);
+        let width = self.bin_width();
+        let centres = (0..self.n_s).map(|i| -mm_(self.s_max) + (i as f32 + 0.5) * width).collect::<Vec<_>>();
+        let views = counts.outer_iter().enumerate().map(|(view, row)| {
+            let row = row.to_vec();
+            let n = row.iter().sum::<usize>();
+            let fit = if n < self.min_counts.max(1) { None } else {
+                match self.method {
+                    FitMethod::Moments      => fit_moments(&centres, &row, width),
+                    FitMethod::LeastSquares => fit_least_squares(&centres, &row),
+                }
+            };
+            ViewFit { phi: (view as f32 + 0.5) * PI / self.n_phi as f32, counts: n, fit }
+        }).collect::<Vec<_>>();
+        let average = average(&views);
+        Ok(LineSourceFit { views, average })
+    }
+}
+
+/// Gaussian with the mean and (Sheppard-corrected) variance of the histogram
+fn fit_moments(centres: &[Lengthf32], counts: &[usize], width: Lengthf32) -> Option<GaussianFit> {
+    let n = counts.iter().sum::<usize>() as f64;
+    let moment = |k| centres.iter().zip(counts).map(|(&x, &c)| (x as f64).powi(k) * c as f64).sum::<f64>() / n;
+    let mean = moment(1);
+    let variance = moment(2) - mean * mean - (width as f64).powi(2) / 12.0;
+    if variance <= 0.0 || variance.is_nan() { return None }
+    let sigma = variance.sqrt();
+    let amplitude = n * width as f64 / (sigma * (2.0 * std::f64::consts::PI).sqrt());
+    Some(gaussian(mean, sigma, amplitude))
+}
+
+/// Gaussian whose logarithm is the parabola `a + b x + c x²` fitted to the
+/// logarithm of the counts near the peak, weighted by the counts: the
+/// reciprocal of the variance of their logarithms
+fn fit_least_squares(centres: &[Lengthf32], counts: &[usize]) -> Option<GaussianFit> {
+    let peak = *counts.iter().max()?;
+    let mut normal = [[0.0_f64; 3]; 3];
+    let mut rhs = [0.0_f64; 3];
+    for (&x, &c) in centres.iter().zip(counts) {
+        if c == 0 || c * 16 < peak { continue }
+        let (x, w) = (x as f64, c as f64);
+        let powers = [1.0, x, x * x];
+        for ((row, r), &pi) in normal.iter_mut().zip(&mut rhs).zip(&powers) {
+            for (m, &pj) in row.iter_mut().zip(&powers) { *m += w * pi * pj }
+            *r += w * w.ln() * pi;
+        }
+    }
+    let [a, b, c] = solve3(normal, rhs)?;
+    if c >= 0.0 || c.is_nan() { return None }
+    let mean = -b / (2.0 * c);
+    let sigma = (-1.0 / (2.0 * c)).sqrt();
+    Some(gaussian(mean, sigma, (a - b * b / (4.0 * c)).exp()))
+}
+
+fn gaussian(mean: f64, sigma: f64, amplitude: f64) -> GaussianFit {
+    GaussianFit { centroid: mm(mean as f32), fwhm: mm(sigma as f32 * FWHM_PER_SIGMA), amplitude: amplitude as f32 }
+}
+
+/// Solution of `m x = v` by Cramer's rule; `None` if `m` is singular
+fn solve3(m: [[f64; 3]; 3], v: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+      - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+      + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d == 0.0 || !d.is_finite() { return None }
+    let column = |k: usize| {
+        let mut mk = m;
+        for (row, &vi) in mk.iter_mut().zip(&v) { row[k] = vi }
+        det(mk) / d
+    };
+    Some([column(0), column(1), column(2)])
+}
+
+fn average(views: &[ViewFit]) -> Option<GaussianFit> {
+    let fitted = views.iter().filter_map(|v| v.fit.map(|fit| (v.counts as f32, fit))).collect::<Vec<_>>();
+    if fitted.is_empty() { return None }
+    let total: f32 = fitted.iter().map(|(n, _)| n).sum();
+    let mean = |f: &dyn Fn(&GaussianFit) -> f32| fitted.iter().map(|(n, fit)| n * f(fit)).sum::<f32>() / total;
+    Some(GaussianFit {
+        centroid:  mm(mean(&|fit| mm_(fit.centroid))),
+        fwhm:      mm(mean(&|fit| mm_(fit.fwhm))),
+        amplitude: mean(&|fit| fit.amplitude),
+    })
+}
+
+#[cfg(test)]
+mod test_line_source {
+    use super::*;
+    use crate::{Point, Time};
+    use float_eq::assert_float_eq;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use rstest::rstest;
+
+    /// `n` LORs from a line source at `(x, y)`, displaced perpendicular to
+    /// their direction by a Gaussian of width `sigma`, with view angles in
+    /// `[0, max_phi)`
+    fn line_source(x: f32, y: f32, sigma: f32, n: usize, max_phi: f32, seed: u64) -> Vec<LOR> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| {
+            let phi: f32 = rng.gen::<f32>() * max_phi;
+            // Box-Muller
+            let (u1, u2): (f32, f32) = (1.0 - rng.gen::<f32>(), rng.gen());
+            let d = sigma * (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+            let (cx, cy) = (x - d * phi.sin(), y + d * phi.cos());
+            let (ux, uy) = (400.0 * phi.cos(), 400.0 * phi.sin());
+            let (z1, z2) = (rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0));
+            // Alternate the endpoint order
+            let (a, b) = if rng.gen() { (1.0, -1.0) } else { (-1.0, 1.0) };
+            LOR::new(Time::ZERO, Time::ZERO,
+                     Point::new(mm(cx + a * ux), mm(cy + a * uy), mm(z1)),
+                     Point::new(mm(cx + b * ux), mm(cy + b * uy), mm(z2)))
+        }).collect()
+    }
+
+    fn analysis(method: FitMethod) -> LineSourceAnalysis {
+        LineSourceAnalysis { n_phi: 18, n_s: 81, s_max: mm(8.0), method, ..LineSourceAnalysis::new(mm(20.0), mm(-10.0)) }
+    }
+
+    #[rstest]
+    #[case(FitMethod::Moments)]
+    #[case(FitMethod::LeastSquares)]
+    fn recovers_fwhm_in_every_view(#[case] method: FitMethod) {
+        let sigma = 1.5;
+        let lors = line_source(20.0, -10.0, sigma, 200_000, PI, 1);
+        let result = analysis(method).fit(&lors).unwrap();
+        assert_eq!(result.views.len(), 18);
+        let expected = FWHM_PER_SIGMA * sigma;
+        for view in &result.views {
+            let fit = view.fit.unwrap_or_else(|| panic!("View at {} not fitted", view.phi));
+            assert_float_eq!(mm_(fit.fwhm), expected, rmax <= 0.04);
+            assert_float_eq!(mm_(fit.centroid), 0.0, abs <= 0.1);
+        }
+        let average = result.average.unwrap();
+        assert_float_eq!(mm_(average.fwhm), expected, rmax <= 0.02);
+        assert_float_eq!(mm_(average.centroid), 0.0, abs <= 0.03);
+    }
+
+    #[test]
+    fn sparse_views_are_skipped() {
+        // Only the first half of the views contain any LORs
+        let lors = line_source(20.0, -10.0, 1.5, 20_000, PI / 2.0, 2);
+        let analysis = LineSourceAnalysis { min_counts: 500, ..analysis(FitMethod::LeastSquares) };
+        let result = analysis.fit(&lors).unwrap();
+        let (full, empty) = result.views.split_at(9);
+        assert!(full .iter().all(|v| v.fit.is_some() && v.counts >= 500));
+        assert!(empty.iter().all(|v| v.fit.is_none() && v.counts == 0));
+        assert!(result.average.is_some());
+    }
+
+    #[test]
+    fn no_fitted_views_means_no_average() {
+        let result = analysis(FitMethod::Moments).fit(&[]).unwrap();
+        assert!(result.views.iter().all(|v| v.fit.is_none()));
+        assert_eq!(result.average, None);
+        let mut csv = vec![];
+        result.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 18 + 1);
+        assert_eq!(csv.lines().last(), Some("all,0,,,"));
+    }
+
+    #[rstest]
+    #[case(LineSourceAnalysis { n_phi: 0,         ..LineSourceAnalysis::new(mm(0.0), mm(0.0)) })]
+    #[case(LineSourceAnalysis { n_s:   2,         ..LineSourceAnalysis::new(mm(0.0), mm(0.0)) })]
+    #[case(LineSourceAnalysis { s_max: mm(-1.0),  ..LineSourceAnalysis::new(mm(0.0), mm(0.0)) })]
+    fn invalid_analyses_are_rejected(#[case] analysis: LineSourceAnalysis) {
+        assert!(analysis.fit(&[]).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Description of the detector: cylindrical envelope, module layout and,
+//! optionally, the positions of individual sensors.
+//!
+//! Scanners are described in TOML files, with all lengths in mm:
+//!
+//! ```toml
+//! radius = 350.0
+//! depth  =  30.0
+//! length = 1000.0
+//!
+//! [modules]
+//! azimuthal     = 48
+//! axial         = 4
+//! azimuthal_gap = 2.0
+//! axial_gap     = 5.0
+//!
+//! [sensors]
+//! file    = "sensors.h5"        # relative to the TOML file
+//! dataset = "MC/sensor_xyz"
+//! ```
+//!
+//! The `modules` and `sensors` sections are optional.
+
+use std::error::Error;
+use std::f32::consts::TAU;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Angle, Length, Lengthf32, Point, Ratio};
+use crate::sensors::{read_sensors, SensorXYZ};
+use crate::system_matrix::{DegeneratePolicy, LOR};
+use geometry::units::{mm, mm_, radian, ratio};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Scanner {
+    /// Inner radius of the detector
+    pub radius: Lengthf32,
+    /// Radial thickness of the detector
+    #[serde(default)]
+    pub depth: Lengthf32,
+    /// Axial length of the detector
+    pub length: Lengthf32,
+    /// Layout of modules covering the envelope. Full coverage if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modules: Option<ModuleLayout>,
+    /// HDF5 table of sensor positions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensors: Option<SensorTable>,
+    /// Positions read from `sensors`
+    #[serde(skip)]
+    pub sensor_positions: Vec<(u32, Point)>,
+}
+
+/// Modules arranged in a regular grid on the inner surface of the envelope
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModuleLayout {
+    /// Number of modules around the circumference
+    pub azimuthal: usize,
+    /// Number of modules along the axis
+    pub axial: usize,
+    /// Arc length between azimuthally-adjacent modules
+    #[serde(default)]
+    pub azimuthal_gap: Lengthf32,
+    /// Distance between axially-adjacent modules
+    #[serde(default)]
+    pub axial_gap: Lengthf32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SensorTable {
+    pub file: PathBuf,
+    #[serde(default = "default_sensor_dataset")]
+    pub dataset: String,
+}
+
+fn default_sensor_dataset() -> String { "MC/sensor_xyz".into() }
+
+/// Tolerance used when checking that sensors lie within the envelope
+const TOLERANCE: Lengthf32 = 1e-3;
+
+impl Scanner {
+    /// Read scanner description from TOML file, load the sensor positions (if
+    /// any) and validate the result
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read scanner file {path:?}: {e}"))?;
+        let mut scanner: Self = text.parse()?;
+        if let Some(SensorTable { file, dataset }) = &scanner.sensors {
+            let file = path.parent().unwrap_or_else(|| Path::new("")).join(file);
+            let file = file.to_str().ok_or("Non-UTF-8 sensor file path")?;
+            scanner = scanner.with_sensors(read_sensors(file, dataset)?.iter())?;
+        }
+        Ok(scanner)
+    }
+
+    /// Check that the envelope and module layout are physically sensible
+    pub fn validate(&self) -> Result<(), String> {
+        if self.radius <= 0.0 { return Err(format!("Scanner radius must be positive, got {}", self.radius)) }
+        if self.length <= 0.0 { return Err(format!("Scanner length must be positive, got {}", self.length)) }
+        if self.depth  <  0.0 { return Err(format!("Scanner depth must not be negative, got {}", self.depth)) }
+        if let Some(m) = self.modules {
+            if m.azimuthal == 0 || m.axial == 0 {
+                return Err("Scanner must have at least one module in each direction".into())
+            }
+            if m.azimuthal_gap < 0.0 || m.axial_gap < 0.0 {
+                return Err("Negative gaps between modules: modules overlap".into())
+            }
+            if m.azimuthal_gap * m.azimuthal as f32 >= TAU * self.radius {
+                return Err("Azimuthal gaps leave no room for modules".into())
+            }
+            if m.axial_gap * (m.axial - 1) as f32 >= self.length {
+                return Err("Axial gaps leave no room for modules".into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Attach sensor positions, checking that they lie within the envelope
+    pub fn with_sensors<'a>(mut self, sensors: impl IntoIterator<Item = &'a SensorXYZ>) -> Result<Self, String> {
+        let (r_min, r_max) = (self.radius - TOLERANCE, self.radius + self.depth + TOLERANCE);
+        let z_max = self.length / 2.0 + TOLERANCE;
+        self.sensor_positions = sensors.into_iter()
+            .map(|&SensorXYZ { sensor_id, x, y, z }| {
+                let r = x.hypot(y);
+                if r < r_min || r > r_max || z.abs() > z_max {
+                    return Err(format!("Sensor {sensor_id} at ({x}, {y}, {z}) mm lies outside the scanner envelope"))
+                }
+                Ok((sensor_id, Point::new(mm(x), mm(y), mm(z))))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
+    pub fn radius  (&self) -> Length { mm(self.radius) }
+    pub fn diameter(&self) -> Length { mm(self.radius * 2.0) }
+    pub fn length  (&self) -> Length { mm(self.length) }
+    pub fn depth   (&self) -> Length { mm(self.depth) }
+
+    /// Axial extents `(z_min, z_max)` of the rings of modules
+    pub fn axial_module_extents(&self) -> Vec<(Length, Length)> {
+        let half = self.length / 2.0;
+        match self.modules {
+            None => vec![(mm(-half), mm(half))],
+            Some(ModuleLayout { axial, axial_gap, .. }) => {
+                let width = (self.length - axial_gap * (axial - 1) as f32) / axial as f32;
+                (0..axial).map(|i| {
+                    let lo = -half + i as f32 * (width + axial_gap);
+                    (mm(lo), mm(lo + width))
+                }).collect()
+            }
+        }
+    }
+
+    /// Azimuthal period of the module layout: a full turn if there are no modules
+    pub fn module_pitch(&self) -> Angle {
+        radian(TAU / self.modules.map_or(1, |m| m.azimuthal) as f32)
+    }
+
+    /// Fraction of the circumference covered by modules
+    pub fn azimuthal_coverage(&self) -> Ratio {
+        ratio(match self.modules {
+            None => 1.0,
+            Some(m) => 1.0 - m.azimuthal_gap * m.azimuthal as f32 / (TAU * self.radius),
+        })
+    }
+
+    /// Fraction of the full solid angle, seen from the centre of the scanner,
+    /// covered by the inner surface of the envelope
+    pub fn envelope_solid_angle_fraction(&self) -> Ratio {
+        ratio(self.band_solid_angle_fraction(-self.length / 2.0, self.length / 2.0))
+    }
+
+    /// Fraction of the full solid angle, seen from the centre of the scanner,
+    /// covered by modules
+    pub fn module_solid_angle_fraction(&self) -> Ratio {
+        let axial: f32 = self.axial_module_extents().into_iter()
+            .map(|(lo, hi)| self.band_solid_angle_fraction(mm_(lo), mm_(hi)))
+            .sum();
+        self.azimuthal_coverage() * axial
+    }
+
+    /// Fraction of the full solid angle subtended at the origin by the band of
+    /// the inner surface lying between `z1` and `z2`
+    fn band_solid_angle_fraction(&self, z1: Lengthf32, z2: Lengthf32) -> f32 {
+        let sin = |z: Lengthf32| z / z.hypot(self.radius);
+        (sin(z2) - sin(z1)) / 2.0
+    }
+
+    /// Move `p` onto the detector: to the nearest sensor if sensor positions
+    /// are known, otherwise radially onto the inner surface of the envelope,
+    /// with z clamped to its length. A `p` with NaN coordinates has no
+    /// meaningful position on the detector: see `snap_lor`.
+    pub fn snap(&self, p: Point) -> Point {
+        if !self.sensor_positions.is_empty() {
+            return self.sensor_positions.iter()
+                .map(|&(_, s)| s)
+                .min_by(|&a, &b| (a - p).norm().total_cmp(&(b - p).norm()))
+                .unwrap()
+        }
+        let (x, y, z) = (mm_(p.x), mm_(p.y), mm_(p.z));
+        let r = x.hypot(y);
+        let (x, y) = if r > 0.0 { (x * self.radius / r, y * self.radius / r) }
+                     else       { (self.radius, 0.0) };
+        let half = self.length / 2.0;
+        Point::new(mm(x), mm(y), mm(z.clamp(-half, half)))
+    }
+
+    /// `lor` with both endpoints snapped onto the detector. `None` if they
+    /// end up coinciding and `policy` drops such LORs. LORs with NaN endpoint
+    /// coordinates are rejected.
+    pub fn snap_lor(&self, lor: &LOR, policy: DegeneratePolicy) -> Result<Option<LOR>, String> {
+        let nan = |p: Point| [p.x, p.y, p.z].iter().any(|c| c.is_nan());
+        if nan(lor.p1) || nan(lor.p2) { return Err(format!("LOR with NaN endpoint: {lor}")) }
+        let snapped = LOR { p1: self.snap(lor.p1), p2: self.snap(lor.p2), ..*lor };
+        Ok(if policy.admit(&snapped)? { Some(snapped) } else { None })
+    }
+
+    /// Uniformly distributed random point on the inner surface of the envelope
+    pub fn random_point_on_envelope(&self, rng: &mut impl rand::Rng) -> Point {
+        let z     = self.length * (rng.gen::<Lengthf32>() - 0.5);
+        let theta = TAU * rng.gen::<Lengthf32>();
+        Point::new(mm(self.radius * theta.cos()), mm(self.radius * theta.sin()), mm(z))
+    }
+}
+
+impl std::str::FromStr for Scanner {
+    type Err = String;
+
+    /// Parse and validate TOML description. Sensor positions are not loaded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let scanner: Self = toml::from_str(s).map_err(|e| format!("Invalid scanner description: {e}"))?;
+        scanner.validate()?;
+        Ok(scanner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::{ns, radian_, ratio_};
+    use crate::system_matrix::DEGENERATE_TOLERANCE;
+
+    const SAMPLE: &str = r#"
+radius = 350.0
+depth  =  30.0
+length = 1000.0
+
+[modules]
+azimuthal     = 10
+axial         = 2
+azimuthal_gap = 21.99114857512855
+axial_gap     = 100.0
+"#;
+
+    fn sensor(sensor_id: u32, x: f32, y: f32, z: f32) -> SensorXYZ { SensorXYZ { sensor_id, x, y, z } }
+
+    #[test]
+    fn roundtrip() {
+        let scanner: Scanner = SAMPLE.parse().unwrap();
+        let text = toml::to_string(&scanner).unwrap();
+        let reparsed: Scanner = text.parse().unwrap();
+        assert_eq!(scanner, reparsed);
+        assert_eq!(scanner.modules.unwrap().axial, 2);
+        assert_eq!(scanner.sensors, None);
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn load_with_sensors() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        hdf5::File::create(dir.path().join("sensors.h5"))?
+            .create_group("MC")?
+            .new_dataset_builder()
+            .with_data(&[sensor(1, 360.0, 0.0, 0.0), sensor(2, 0.0, -355.0, 400.0)])
+            .create("sensor_xyz")?;
+        let toml_path = dir.path().join("scanner.toml");
+        std::fs::write(&toml_path, format!("{SAMPLE}\n[sensors]\nfile = \"sensors.h5\"\n"))?;
+        let scanner = Scanner::load(&toml_path)?;
+        assert_eq!(scanner.sensor_positions.len(), 2);
+        // Snapping picks the nearest sensor
+        let snapped = scanner.snap(Point::new(mm(10.0), mm(-300.0), mm(350.0)));
+        assert_eq!(snapped, scanner.sensor_positions[1].1);
+        Ok(())
+    }
+
+    #[test]
+    fn sensors_outside_envelope_are_rejected() {
+        let scanner: Scanner = SAMPLE.parse().unwrap();
+        assert!(scanner.clone().with_sensors(&[sensor(1, 365.0, 0.0, 499.0)]).is_ok());
+        assert!(scanner.clone().with_sensors(&[sensor(1, 300.0, 0.0,   0.0)]).is_err()); // inside bore
+        assert!(scanner.clone().with_sensors(&[sensor(1, 390.0, 0.0,   0.0)]).is_err()); // beyond depth
+        assert!(scanner.clone().with_sensors(&[sensor(1, 0.0, 350.0, 501.0)]).is_err()); // beyond end
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        let with = |extra: &str| format!("radius = 100.0\nlength = 200.0\n[modules]\n{extra}").parse::<Scanner>();
+        assert!(with("azimuthal = 4\naxial = 2").is_ok());
+        assert!(with("azimuthal = 4\naxial = 2\naxial_gap = -1.0").is_err());
+        assert!(with("azimuthal = 4\naxial = 3\naxial_gap = 100.0").is_err());
+        assert!(with("azimuthal = 0\naxial = 1").is_err());
+        assert!("radius = -1.0\nlength = 200.0".parse::<Scanner>().is_err());
+    }
+
+    #[test]
+    fn derived_quantities() {
+        let scanner: Scanner = SAMPLE.parse().unwrap();
+        assert_float_eq!(mm_(scanner.diameter()), 700.0, ulps <= 1);
+        // 10 gaps of 22 mm in a circumference of 2200 mm
+        assert_float_eq!(ratio_(scanner.azimuthal_coverage()), 0.9, abs <= 1e-6);
+        assert_float_eq!(radian_(scanner.module_pitch()), TAU / 10.0, ulps <= 1);
+        let extents: Vec<_> = scanner.axial_module_extents().into_iter().map(|(a, b)| (mm_(a), mm_(b))).collect();
+        assert_eq!(extents, vec![(-500.0, -50.0), (50.0, 500.0)]);
+        // Envelope: 2 * (L/2) / sqrt((L/2)^2 + R^2) / 2
+        let envelope = 500.0 / 500.0_f32.hypot(350.0);
+        assert_float_eq!(ratio_(scanner.envelope_solid_angle_fraction()), envelope, abs <= 1e-6);
+        // Modules: the envelope minus the central band, times the azimuthal coverage
+        let central = 50.0 / 50.0_f32.hypot(350.0);
+        assert_float_eq!(ratio_(scanner.module_solid_angle_fraction()), 0.9 * (envelope - central), abs <= 1e-6);
+    }
+
+    #[test]
+    fn snap_without_sensors_projects_radially() {
+        let scanner: Scanner = SAMPLE.parse().unwrap();
+        let p = scanner.snap(Point::new(mm(30.0), mm(40.0), mm(600.0)));
+        assert_float_eq!([mm_(p.x), mm_(p.y), mm_(p.z)], [210.0, 280.0, 500.0], abs_all <= 1e-3);
+    }
+
+    #[test]
+    fn snapping_lors_respects_degenerate_policy() {
+        let scanner = SAMPLE.parse::<Scanner>().unwrap()
+            .with_sensors(&[sensor(1, 360.0, 0.0, 0.0), sensor(2, -360.0, 0.0, 0.0)]).unwrap();
+        let p = |x: f32, y: f32| Point::new(mm(x), mm(y), mm(0.0));
+        let fine = LOR::new(ns(0.0), ns(0.0), p(300.0, 10.0), p(-300.0, -10.0));
+        // Both endpoints nearest to sensor 1
+        let collapsing = LOR::new(ns(0.0), ns(0.0), p(300.0, 10.0), p(300.0, -10.0));
+        for policy in [DegeneratePolicy::Drop, DegeneratePolicy::Error, DegeneratePolicy::Keep] {
+            let snapped = scanner.snap_lor(&fine, policy).unwrap().unwrap();
+            assert_eq!((snapped.p1, snapped.p2), (scanner.sensor_positions[0].1, scanner.sensor_positions[1].1));
+        }
+        assert!(matches!(scanner.snap_lor(&collapsing, DegeneratePolicy::Drop), Ok(None)));
+        assert!(scanner.snap_lor(&collapsing, DegeneratePolicy::Error).is_err());
+        assert!(scanner.snap_lor(&collapsing, DegeneratePolicy::Keep).unwrap().unwrap().is_degenerate(DEGENERATE_TOLERANCE));
+    }
+
+    #[test]
+    fn lors_with_nan_endpoints_are_rejected() {
+        let scanner = SAMPLE.parse::<Scanner>().unwrap()
+            .with_sensors(&[sensor(1, 360.0, 0.0, 0.0), sensor(2, -360.0, 0.0, 0.0)]).unwrap();
+        let p = |x: f32| Point::new(mm(x), mm(0.0), mm(0.0));
+        let lor = LOR::new(ns(0.0), ns(0.0), p(300.0), p(f32::NAN));
+        for policy in [DegeneratePolicy::Drop, DegeneratePolicy::Error, DegeneratePolicy::Keep] {
+            assert!(scanner.snap_lor(&lor, policy).is_err());
+        }
+    }
+}
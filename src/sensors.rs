@@ -0,0 +1,300 @@
+//! Fast lookup of the sensors nearest to arbitrary points.
+//!
+//! Sensors of cylindrical scanners are binned in a regular grid in `(phi, z)`,
+//! stored contiguously cell by cell. A query visits rings of cells of
+//! increasing size around the cell containing the query point (wrapping around
+//! in phi), until a lower bound on the distance to any sensor in the remaining
+//! rings exceeds the best distance found so far. The results are exact: the
+//! grid only determines how much work is needed to find them.
+
+use std::cell::Cell;
+use std::error::Error;
+use std::f32::consts::{FRAC_PI_2, TAU};
+
+use crate::{Length, Lengthf32, Point};
+use geometry::units::{mm, mm_};
+
+pub mod tables;
+pub use tables::{Charge, DuplicateSensors, SensorHit, SensorTables};
+
+#[cfg(feature = "hdf5")]
+pub mod coincidences;
+
+#[cfg(feature = "hdf5")]
+pub mod event;
+
+/// Row of the sensor-position table
+#[cfg_attr(feature = "hdf5", derive(hdf5::H5Type))]
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct SensorXYZ {
+    pub sensor_id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Read the `SensorXYZ` table `dataset` from HDF5 `file`
+#[cfg(feature = "hdf5")]
+pub fn read_sensors(file: &str, dataset: &str) -> Result<Vec<SensorXYZ>, Box<dyn Error>> {
+    Ok(crate::io::hdf5::read_table::<SensorXYZ>(file, dataset, None)?.to_vec())
+}
+
+#[cfg(not(feature = "hdf5"))]
+pub fn read_sensors(file: &str, _dataset: &str) -> Result<Vec<SensorXYZ>, Box<dyn Error>> {
+    Err(format!("Cannot read sensors from {file}: petalo was built without the `hdf5` feature").into())
+}
+
+pub struct SensorIndex {
+    n_phi: usize,
+    n_z: usize,
+    z_min: Lengthf32,
+    dz: Lengthf32,
+    dphi: f32,
+    /// Sensors in cell `c` are `ids[start[c]..start[c+1]]`
+    start: Vec<usize>,
+    ids: Vec<u32>,
+    positions: Vec<[Lengthf32; 3]>,
+}
+
+impl SensorIndex {
+    /// Index `sensors` in a grid of `n_phi` x `n_z` cells
+    pub fn new(sensors: &[SensorXYZ], n_phi: usize, n_z: usize) -> Result<Self, String> {
+        if sensors.is_empty()       { return Err("Cannot index an empty set of sensors".into()) }
+        if n_phi == 0 || n_z == 0   { return Err(format!("Sensor grid needs at least one cell, got {n_phi} x {n_z}")) }
+        let (z_min, z_max) = sensors.iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), s| (lo.min(s.z), hi.max(s.z)));
+        if !(z_min.is_finite() && z_max.is_finite()) { return Err("Sensor positions must be finite".into()) }
+        // Avoid zero-width cells when all sensors lie in one plane
+        let dz = ((z_max - z_min) / n_z as f32).max(f32::EPSILON * z_max.abs().max(1.0));
+        let mut index = Self {
+            n_phi, n_z, z_min, dz, dphi: TAU / n_phi as f32,
+            start: vec![0; n_phi * n_z + 1], ids: vec![], positions: vec![],
+        };
+
+        // Counting sort of the sensors by cell
+        let cells = sensors.iter().map(|s| index.cell_of(s.x, s.y, s.z)).collect::<Vec<_>>();
+        for &c in &cells { index.start[c + 1] += 1 }
+        for c in 0..n_phi * n_z { index.start[c + 1] += index.start[c] }
+        let mut next = index.start.clone();
+        index.ids.resize(sensors.len(), 0);
+        index.positions.resize(sensors.len(), [0.0; 3]);
+        for (s, c) in sensors.iter().zip(cells) {
+            index.ids      [next[c]] = s.sensor_id;
+            index.positions[next[c]] = [s.x, s.y, s.z];
+            next[c] += 1;
+        }
+        Ok(index)
+    }
+
+    /// Index `sensors` with a grid of about `per_cell` sensors per cell, with
+    /// cells of similar size in the azimuthal and axial directions
+    pub fn with_occupancy(sensors: &[SensorXYZ], per_cell: usize) -> Result<Self, String> {
+        let r = sensors.iter().map(|s| s.x.hypot(s.y)).fold(0.0, f32::max);
+        let (lo, hi) = sensors.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), s| (lo.min(s.z), hi.max(s.z)));
+        let n_cells = (sensors.len() / per_cell.max(1)).max(1) as f32;
+        // n_phi / n_z = circumference / length, n_phi * n_z = n_cells
+        let aspect = (TAU * r / (hi - lo).max(1.0)).max(f32::EPSILON);
+        let n_phi = (n_cells * aspect).sqrt().round().max(1.0) as usize;
+        let n_z   = (n_cells / n_phi as f32).round().max(1.0) as usize;
+        Self::new(sensors, n_phi, n_z)
+    }
+
+    /// Read the sensors from an HDF5 `SensorXYZ` table and index them
+    pub fn load(file: &str, dataset: &str, per_cell: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::with_occupancy(&read_sensors(file, dataset)?, per_cell)?)
+    }
+
+    pub fn len(&self) -> usize { self.ids.len() }
+    pub fn is_empty(&self) -> bool { self.ids.is_empty() }
+
+    /// The sensor nearest to `p`, and its distance from `p`
+    pub fn nearest(&self, p: Point) -> (u32, Length) {
+        let q = [mm_(p.x), mm_(p.y), mm_(p.z)];
+        let best = Cell::new((u32::MAX, f32::INFINITY));
+        self.visit_rings(q,
+                         |bound| bound > best.get().1,
+                         |i, d| if d < best.get().1 { best.set((self.ids[i], d)) });
+        let (id, d) = best.get();
+        (id, mm(d))
+    }
+
+    /// All sensors within `radius` of `p`
+    pub fn within(&self, p: Point, radius: Length) -> Vec<u32> {
+        let q = [mm_(p.x), mm_(p.y), mm_(p.z)];
+        let radius = mm_(radius);
+        let mut found = vec![];
+        self.visit_rings(q, |bound| bound > radius, |i, d| if d <= radius { found.push(self.ids[i]) });
+        found
+    }
+
+    /// `nearest` for each of `points`. The queries are processed in the order
+    /// of the grid cells containing them, so that nearby queries reuse the
+    /// same sensors while they are in cache.
+    pub fn nearest_many(&self, points: &[Point]) -> Vec<(u32, Length)> {
+        let mut order = (0..points.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| { let p = points[i]; self.cell_of(mm_(p.x), mm_(p.y), mm_(p.z)) });
+        let mut result = vec![(u32::MAX, mm(f32::INFINITY)); points.len()];
+        for i in order { result[i] = self.nearest(points[i]) }
+        result
+    }
+
+    /// `within` for each of `points`
+    pub fn within_many(&self, points: &[Point], radius: Length) -> Vec<Vec<u32>> {
+        points.iter().map(|&p| self.within(p, radius)).collect()
+    }
+
+    // ----- Grid geometry ------------------------------------------------------------
+    fn phi_cell(&self, x: f32, y: f32) -> usize {
+        let phi = y.atan2(x).rem_euclid(TAU);
+        ((phi / self.dphi) as usize).min(self.n_phi - 1)
+    }
+
+    /// Cells beyond either end of the grid are clamped to it
+    fn z_cell(&self, z: f32) -> usize {
+        (((z - self.z_min) / self.dz).max(0.0) as usize).min(self.n_z - 1)
+    }
+
+    fn cell_of(&self, x: f32, y: f32, z: f32) -> usize { self.z_cell(z) * self.n_phi + self.phi_cell(x, y) }
+
+    /// Lower bound on the distance from `q` to any sensor in a cell which is
+    /// `k` cells away from the cell containing `q`, in phi or z
+    fn ring_bound(&self, q: [f32; 3], k: usize) -> f32 {
+        if k < 2 { return 0.0 }
+        let steps = (k - 1) as f32;
+        let r = q[0].hypot(q[1]);
+        let axial = steps * self.dz;
+        let azimuthal = r * (steps * self.dphi).min(FRAC_PI_2).sin();
+        axial.min(azimuthal)
+    }
+
+    /// Call `visit` with the position in storage and distance from `q` of the
+    /// sensors, ring by ring, until `done` accepts the lower bound for the next ring
+    fn visit_rings(&self, q: [f32; 3], done: impl Fn(f32) -> bool, mut visit: impl FnMut(usize, f32)) {
+        let (ip, iz) = (self.phi_cell(q[0], q[1]) as isize, self.z_cell(q[2]) as isize);
+        let (n_phi, n_z) = (self.n_phi as isize, self.n_z as isize);
+        let k_max = (n_phi / 2).max(n_z);
+        let mut visit_cell = |p: isize, z: isize| {
+            let c = (z * n_phi + p.rem_euclid(n_phi)) as usize;
+            for i in self.start[c]..self.start[c + 1] {
+                let [x, y, sz] = self.positions[i];
+                let d = ((x - q[0]).powi(2) + (y - q[1]).powi(2) + (sz - q[2]).powi(2)).sqrt();
+                visit(i, d);
+            }
+        };
+        for k in 0..=k_max {
+            if done(self.ring_bound(q, k)) { break }
+            // Distinct phi offsets at exactly `k` and at most `k` cells, after wrapping
+            let exactly: Vec<isize> = match k {
+                0                            => vec![0],
+                k if 2 * k < n_phi           => vec![-k, k],
+                k if 2 * k == n_phi          => vec![k],
+                _                            => vec![],
+            };
+            let at_most: Vec<isize> = if 2 * k + 1 >= n_phi { (0..n_phi).collect() } else { (-k..=k).collect() };
+            for z in (iz - k).max(0)..=(iz + k).min(n_z - 1) {
+                let offsets = if (z - iz).abs() == k { &at_most } else { &exactly };
+                for &dp in offsets { visit_cell(ip + dp, z) }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use rstest::rstest;
+
+    fn sensor(sensor_id: u32, x: f32, y: f32, z: f32) -> SensorXYZ { SensorXYZ { sensor_id, x, y, z } }
+
+    fn random_cylinder(n: usize, rng: &mut StdRng) -> Vec<SensorXYZ> {
+        (0..n).map(|i| {
+            let phi = rng.gen_range(0.0..TAU);
+            let r = rng.gen_range(350.0..380.0);
+            sensor(i as u32, r * phi.cos(), r * phi.sin(), rng.gen_range(-500.0..500.0))
+        }).collect()
+    }
+
+    fn random_point(rng: &mut StdRng) -> Point {
+        Point::new(mm(rng.gen_range(-450.0..450.0)), mm(rng.gen_range(-450.0..450.0)), mm(rng.gen_range(-600.0..600.0)))
+    }
+
+    fn distance(s: &SensorXYZ, p: Point) -> f32 {
+        ((s.x - mm_(p.x)).powi(2) + (s.y - mm_(p.y)).powi(2) + (s.z - mm_(p.z)).powi(2)).sqrt()
+    }
+
+    fn brute_force(sensors: &[SensorXYZ], p: Point) -> (u32, f32) {
+        sensors.iter()
+            .map(|s| (s.sensor_id, distance(s, p)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .unwrap()
+    }
+
+    #[rstest(/**/ n_phi, n_z, case(1, 1), case(7, 3), case(48, 20), case(200, 100))]
+    fn nearest_agrees_with_brute_force(n_phi: usize, n_z: usize) {
+        let mut rng = StdRng::seed_from_u64(1234);
+        let sensors = random_cylinder(2000, &mut rng);
+        let index = SensorIndex::new(&sensors, n_phi, n_z).unwrap();
+        for _ in 0..2000 {
+            let p = random_point(&mut rng);
+            let (id, d) = index.nearest(p);
+            let (expected_id, expected_d) = brute_force(&sensors, p);
+            assert_eq!(mm_(d), expected_d);
+            assert!(id == expected_id || distance(&sensors[id as usize], p) == expected_d);
+        }
+    }
+
+    #[test]
+    fn within_agrees_with_brute_force() {
+        let mut rng = StdRng::seed_from_u64(99);
+        let sensors = random_cylinder(3000, &mut rng);
+        let index = SensorIndex::with_occupancy(&sensors, 4).unwrap();
+        for _ in 0..500 {
+            let p = random_point(&mut rng);
+            let mut found = index.within(p, mm(60.0));
+            found.sort_unstable();
+            let expected = sensors.iter().filter(|s| distance(s, p) <= 60.0).map(|s| s.sensor_id).collect::<Vec<_>>();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn phi_wrap_around() {
+        // Sensors just either side of phi = 0 (the +x axis), where the grid wraps
+        let r = 350.0;
+        let at = |deg: f32| (r * deg.to_radians().cos(), r * deg.to_radians().sin());
+        let (x1, y1) = at(359.5);
+        let (x2, y2) = at(1.5);
+        let (x3, y3) = at(180.0);
+        let sensors = [sensor(1, x1, y1, 0.0), sensor(2, x2, y2, 0.0), sensor(3, x3, y3, 0.0)];
+        let index = SensorIndex::new(&sensors, 36, 1).unwrap();
+        let (qx, qy) = at(0.2);
+        assert_eq!(index.nearest(Point::new(mm(qx), mm(qy), mm(0.0))).0, 1);
+        let (qx, qy) = at(359.9);
+        let mut near = index.within(Point::new(mm(qx), mm(qy), mm(0.0)), mm(20.0));
+        near.sort_unstable();
+        assert_eq!(near, vec![1, 2]);
+    }
+
+    #[test]
+    fn bulk_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let sensors = random_cylinder(1000, &mut rng);
+        let index = SensorIndex::with_occupancy(&sensors, 8).unwrap();
+        let points = (0..1000).map(|_| random_point(&mut rng)).collect::<Vec<_>>();
+        let bulk = index.nearest_many(&points);
+        let scalar = points.iter().map(|&p| index.nearest(p)).collect::<Vec<_>>();
+        assert_eq!(bulk, scalar);
+        assert_eq!(index.within_many(&points[..50], mm(40.0)),
+                   points[..50].iter().map(|&p| index.within(p, mm(40.0))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn invalid_grids_are_rejected() {
+        assert!(SensorIndex::new(&[], 4, 4).is_err());
+        assert!(SensorIndex::new(&[sensor(0, 1.0, 0.0, 0.0)], 0, 4).is_err());
+        assert!(SensorIndex::new(&[sensor(0, 1.0, 0.0, 0.0)], 4, 4).is_ok());
+    }
+}
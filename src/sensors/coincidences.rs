@@ -0,0 +1,218 @@
+//! Coincidence sorting: LORs from the charges and signal times collected by the
+//! sensors in each event.
+//!
+//! The sensors of an event are split into two sides by the plane through the
+//! z-axis which is perpendicular to the transverse direction of the sensor with
+//! the most charge. Each side becomes an endpoint of the LOR: its position is
+//! the charge-weighted centroid of the side's sensors, its time the earliest
+//! signal on any of them, and its charge (`q1` or `q2`) their total charge.
+//! Energies are not measured, so `E1` and `E2` are NaN.
+//!
+//! Events are dropped, and counted in `CoincidenceCounts`, if all their charge
+//! lies on one side, if either side collects less charge than the threshold, if
+//! either side has no signal time, or if the times of the sides are further
+//! apart than the coincidence window.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::Time;
+use crate::io::hdf5::Hdf5Lor;
+use crate::io::units::ns_from_file;
+use crate::utils::group_digits;
+use super::{Charge, SensorHit, SensorTables, SensorXYZ};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoincidenceCuts {
+    /// Least total charge (pes) on each side of an event
+    pub charge_threshold: u64,
+    /// Largest difference between the times of the two sides
+    pub time_window: Time,
+    /// Largest fraction of an event's charge which may have been lost to
+    /// sensors absent from the sensor table (see `SensorTables`)
+    pub max_orphan_charge: f32,
+}
+
+/// What became of the events given to `sort_coincidences`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoincidenceCounts {
+    pub events: usize,
+    pub lors: usize,
+    /// Too much charge lost to unknown sensors
+    pub incomplete: usize,
+    /// All charge on one side
+    pub single_cluster: usize,
+    /// Less charge than the threshold on a side
+    pub too_little_charge: usize,
+    /// No signal time on a side
+    pub no_time: usize,
+    /// Sides further apart in time than the coincidence window
+    pub outside_window: usize,
+}
+
+impl fmt::Display for CoincidenceCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = group_digits;
+        write!(f, "{} LORs from {} events; dropped: {} incomplete, {} single cluster, {} too little charge, {} without time, {} outside time window",
+               g(self.lors), g(self.events), g(self.incomplete), g(self.single_cluster),
+               g(self.too_little_charge), g(self.no_time), g(self.outside_window))
+    }
+}
+
+/// Charges and earliest signal times of the sensors of one event
+#[derive(Default)]
+struct Event {
+    charges: Vec<(u64, u64)>,
+    times: HashMap<u64, f64>,
+}
+
+/// One LOR for each event in `tables` which passes `cuts`, in order of
+/// `event_id`
+pub fn sort_coincidences(tables: &SensorTables, cuts: CoincidenceCuts) -> (Vec<Hdf5Lor>, CoincidenceCounts) {
+    let positions: HashMap<u64, [f32; 3]> = tables.sensors.iter()
+        .map(|&SensorXYZ { sensor_id, x, y, z }| (sensor_id as u64, [x, y, z]))
+        .collect();
+    let mut events = BTreeMap::<u64, Event>::new();
+    for &Charge { event_id, sensor_id, charge } in &tables.charges {
+        events.entry(event_id).or_default().charges.push((sensor_id, charge));
+    }
+    for &SensorHit { event_id, sensor_id, time } in &tables.hits {
+        if let Some(event) = events.get_mut(&event_id) {
+            let earliest = event.times.entry(sensor_id).or_insert(time);
+            *earliest = earliest.min(time);
+        }
+    }
+
+    let mut counts = CoincidenceCounts { events: events.len(), ..CoincidenceCounts::default() };
+    let mut lors = vec![];
+    for (event_id, event) in events {
+        if !tables.is_complete(event_id, cuts.max_orphan_charge) { counts.incomplete += 1; continue }
+        match coincidence(&event, &positions, cuts) {
+            Ok(lor) => lors.push(lor),
+            Err(reason) => *reason.count(&mut counts) += 1,
+        }
+    }
+    counts.lors = lors.len();
+    (lors, counts)
+}
+
+/// Why an event produced no LOR
+enum Rejection { SingleCluster, TooLittleCharge, NoTime, OutsideWindow }
+
+impl Rejection {
+    fn count(self, counts: &mut CoincidenceCounts) -> &mut usize {
+        match self {
+            Self::SingleCluster   => &mut counts.single_cluster,
+            Self::TooLittleCharge => &mut counts.too_little_charge,
+            Self::NoTime          => &mut counts.no_time,
+            Self::OutsideWindow   => &mut counts.outside_window,
+        }
+    }
+}
+
+/// Charge, charge-weighted position and earliest time of one side of an event
+struct Side { q: u64, weighted: [f64; 3], t: Option<f64> }
+
+impl Side {
+    fn new() -> Self { Self { q: 0, weighted: [0.0; 3], t: None } }
+
+    fn add(&mut self, q: u64, [x, y, z]: [f32; 3], t: Option<f64>) {
+        self.q += q;
+        for (w, c) in self.weighted.iter_mut().zip([x, y, z]) { *w += q as f64 * c as f64 }
+        self.t = match (self.t, t) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b)             => a.or(b),
+        };
+    }
+
+    fn centroid(&self) -> [f32; 3] { self.weighted.map(|w| (w / self.q as f64) as f32) }
+}
+
+fn coincidence(event: &Event, positions: &HashMap<u64, [f32; 3]>, cuts: CoincidenceCuts) -> Result<Hdf5Lor, Rejection> {
+    // `SensorTables` has removed charges on unknown sensors
+    let brightest = event.charges.iter().max_by_key(|&&(_, q)| q).map(|&(sensor, _)| positions[&sensor]);
+    let [xb, yb, _] = brightest.ok_or(Rejection::SingleCluster)?;
+    let (mut a, mut b) = (Side::new(), Side::new());
+    for &(sensor, q) in &event.charges {
+        let position = positions[&sensor];
+        let [x, y, _] = position;
+        let side = if x * xb + y * yb > 0.0 { &mut a } else { &mut b };
+        side.add(q, position, event.times.get(&sensor).copied());
+    }
+    if a.q == 0 || b.q == 0 { return Err(Rejection::SingleCluster) }
+    if a.q < cuts.charge_threshold || b.q < cuts.charge_threshold { return Err(Rejection::TooLittleCharge) }
+    let (t1, t2) = match (a.t, b.t) {
+        (Some(t1), Some(t2)) => (t1, t2),
+        _                    => return Err(Rejection::NoTime),
+    };
+    let dt = (t2 - t1) as f32;
+    if ns_from_file(dt.abs()) > cuts.time_window { return Err(Rejection::OutsideWindow) }
+    let ([x1, y1, z1], [x2, y2, z2]) = (a.centroid(), b.centroid());
+    Ok(Hdf5Lor { dt, x1, y1, z1, x2, y2, z2, q1: a.q as f32, q2: b.q as f32, E1: f32::NAN, E2: f32::NAN })
+}
+
+#[cfg(test)]
+mod test_coincidences {
+    use super::*;
+    use crate::sensors::DuplicateSensors;
+    use float_eq::assert_float_eq;
+    use geometry::units::ns;
+
+    /// Four sensors at ±x and two at ±y, on a ring of radius 100 mm
+    fn sensors() -> Vec<SensorXYZ> {
+        [(1, 100.0, 0.0, 10.0), (2, 100.0, 0.0, -10.0), (3, -100.0, 0.0, 10.0), (4, -100.0, 0.0, -10.0),
+         (5, 0.0, 100.0, 0.0), (6, 0.0, -100.0, 0.0)]
+            .map(|(sensor_id, x, y, z)| SensorXYZ { sensor_id, x, y, z })
+            .to_vec()
+    }
+
+    fn cuts() -> CoincidenceCuts {
+        CoincidenceCuts { charge_threshold: 10, time_window: ns(1.0), max_orphan_charge: 0.5 }
+    }
+
+    fn sort(charges: &[(u64, u64, u64)], hits: &[(u64, u64, f64)]) -> (Vec<Hdf5Lor>, CoincidenceCounts) {
+        let charges = charges.iter().map(|&(event_id, sensor_id, charge)| Charge { event_id, sensor_id, charge }).collect();
+        let hits    = hits   .iter().map(|&(event_id, sensor_id, time  )| SensorHit { event_id, sensor_id, time }).collect();
+        let tables = SensorTables::new(sensors(), charges, hits, DuplicateSensors::Error).unwrap();
+        sort_coincidences(&tables, cuts())
+    }
+
+    #[test]
+    fn sides_become_endpoints() {
+        let (lors, counts) = sort(
+            &[(7, 1, 30), (7, 2, 10), (7, 3, 15), (7, 4, 15)],
+            &[(7, 1, 2.5), (7, 2, 2.2), (7, 2, 2.9), (7, 3, 2.7), (7, 4, 3.0)],
+        );
+        assert_eq!(counts, CoincidenceCounts { events: 1, lors: 1, ..CoincidenceCounts::default() });
+        let Hdf5Lor { dt, x1, y1, z1, x2, y2, z2, q1, q2, E1, .. } = lors[0].clone();
+        // The side of the brightest sensor comes first
+        assert_float_eq!([x1, y1, z1], [ 100.0, 0.0, 5.0], abs <= [1e-5; 3]);
+        assert_float_eq!([x2, y2, z2], [-100.0, 0.0, 0.0], abs <= [1e-5; 3]);
+        assert_eq!((q1, q2), (40.0, 30.0));
+        // Earliest times: 2.2 and 2.7
+        assert_float_eq!(dt, 0.5, abs <= 1e-6);
+        assert!(E1.is_nan());
+    }
+
+    #[test]
+    fn failing_events_are_counted() {
+        let (lors, counts) = sort(
+            &[
+                (0, 1, 20), (0, 3, 20),                // good
+                (1, 1, 20), (1, 2, 20),                // single cluster
+                (2, 1, 20), (2, 3,  5),                // too little charge
+                (3, 1, 20), (3, 3, 20),                // no time on one side
+                (4, 1, 20), (4, 3, 20),                // 1.5 ns apart
+                (5, 1, 20), (5, 9, 30),                // mostly on an unknown sensor
+                (6, 5, 25), (6, 6, 20),                // good, split along y
+            ],
+            &[(0, 1, 0.0), (0, 3, 0.5), (1, 1, 0.0), (1, 2, 0.0), (2, 1, 0.0), (2, 3, 0.0),
+              (3, 1, 0.0), (4, 1, 0.0), (4, 3, 1.5), (5, 1, 0.0), (6, 5, 0.0), (6, 6, 0.0)],
+        );
+        assert_eq!(counts, CoincidenceCounts {
+            events: 7, lors: 2, incomplete: 1, single_cluster: 1, too_little_charge: 1, no_time: 1, outside_window: 1,
+        });
+        assert_eq!(lors.len(), 2);
+        assert_eq!((lors[1].y1, lors[1].y2), (100.0, -100.0));
+    }
+}
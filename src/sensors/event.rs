@@ -0,0 +1,171 @@
+//! Everything recorded about a single event, for debugging its reconstruction:
+//! the charge and signal times on each sensor, where the sensors are, and the
+//! LOR reconstructed from them.
+//!
+//! LOR tables do not record the event from which each LOR came, so the LOR is
+//! identified by its row.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::Write;
+
+use crate::io::hdf5::{read_table, read_table_chunked, Hdf5Lor, Qtot, Waveform};
+use super::{read_sensors, SensorXYZ};
+
+/// Rows read at a time when scanning the charge and waveform tables
+const CHUNK: usize = 1 << 20;
+
+/// What one sensor saw in one event
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorDetail {
+    pub sensor_id: u64,
+    /// `None` if the sensor is absent from the sensor table
+    pub position: Option<[f32; 3]>,
+    /// Zero if the sensor has hits but no charge row
+    pub charge: u64,
+    /// Number of `Waveform` rows
+    pub hits: usize,
+    /// Time of the earliest of them, in ns
+    pub first_hit: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventDetail {
+    pub event_id: u64,
+    /// Every sensor with charge or hits in the event, in order of `sensor_id`
+    pub sensors: Vec<SensorDetail>,
+    pub lor: Option<Hdf5Lor>,
+}
+
+/// Row `row` of LOR table `dataset` in `file`
+#[derive(Clone, Copy, Debug)]
+pub struct LorRow<'a> {
+    pub file: &'a str,
+    pub dataset: &'a str,
+    pub row: usize,
+}
+
+impl EventDetail {
+    /// Gather `event_id` from the `MC/total_charge`, `MC/waveform` and
+    /// `MC/sensor_xyz` tables of `mc_file`, in one pass over each, and the LOR
+    /// at `lor`, if given
+    pub fn read(mc_file: &str, event_id: u64, lor: Option<LorRow>) -> Result<Self, Box<dyn Error>> {
+        let mut sensors = BTreeMap::<u64, SensorDetail>::new();
+        let mut detail = |sensor_id: u64| sensors.entry(sensor_id).or_insert(SensorDetail {
+            sensor_id, position: None, charge: 0, hits: 0, first_hit: None,
+        });
+        for chunk in read_table_chunked::<Qtot>(mc_file, "MC/total_charge", None, CHUNK)? {
+            for q in chunk?.iter().filter(|q| q.event_id as u64 == event_id) {
+                detail(q.sensor_id as u64).charge += q.charge as u64;
+            }
+        }
+        for chunk in read_table_chunked::<Waveform>(mc_file, "MC/waveform", None, CHUNK)? {
+            for w in chunk?.iter().filter(|w| w.event_id as u64 == event_id) {
+                let sensor = detail(w.sensor_id as u64);
+                sensor.hits += 1;
+                sensor.first_hit = Some(sensor.first_hit.map_or(w.time, |t| t.min(w.time)));
+            }
+        }
+        if sensors.is_empty() {
+            return Err(format!("Event {event_id} has neither charges nor hits in '{mc_file}'").into())
+        }
+        let positions: HashMap<u64, [f32; 3]> = read_sensors(mc_file, "MC/sensor_xyz")?.into_iter()
+            .map(|SensorXYZ { sensor_id, x, y, z }| (sensor_id as u64, [x, y, z]))
+            .collect();
+        for sensor in sensors.values_mut() { sensor.position = positions.get(&sensor.sensor_id).copied() }
+
+        let lor = match lor {
+            Some(LorRow { file, dataset, row }) => Some(read_table::<Hdf5Lor>(file, dataset, Some(row..row+1))?[0].clone()),
+            None => None,
+        };
+        Ok(Self { event_id, sensors: sensors.into_values().collect(), lor })
+    }
+
+    pub fn total_charge(&self) -> u64 { self.sensors.iter().map(|s| s.charge).sum() }
+
+    pub fn n_hits(&self) -> usize { self.sensors.iter().map(|s| s.hits).sum() }
+
+    /// One row per sensor, then one per LOR endpoint, with columns
+    /// `kind,sensor_id,x_mm,y_mm,z_mm,charge,hits,first_hit_ns`. Unknown
+    /// values are left empty.
+    pub fn write_csv(&self, mut out: impl Write) -> std::io::Result<()> {
+        let opt = |x: Option<f32>| x.map_or(String::new(), |x| x.to_string());
+        writeln!(out, "kind,sensor_id,x_mm,y_mm,z_mm,charge,hits,first_hit_ns")?;
+        for SensorDetail { sensor_id, position, charge, hits, first_hit } in &self.sensors {
+            let [x, y, z] = match position {
+                Some(p) => p.map(|c| c.to_string()),
+                None    => [(); 3].map(|_| String::new()),
+            };
+            writeln!(out, "sensor,{sensor_id},{x},{y},{z},{charge},{hits},{}", opt(*first_hit))?;
+        }
+        if let Some(Hdf5Lor { x1, y1, z1, x2, y2, z2, q1, q2, .. }) = &self.lor {
+            writeln!(out, "lor1,,{x1},{y1},{z1},{q1},,")?;
+            writeln!(out, "lor2,,{x2},{y2},{z2},{q2},,")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_event_detail {
+    use super::*;
+
+    /// MC file with two events, seen by sensors 1-3, and a sensor table which
+    /// lacks sensor 3
+    fn mc_file(dir: &std::path::Path) -> Result<String, Box<dyn Error>> {
+        let path = dir.join("mc.h5");
+        let file = hdf5::File::create(&path)?;
+        let mc = file.create_group("MC")?;
+        let sensors = [(1, 100.0, 0.0, 0.0), (2, -100.0, 0.0, 0.0)]
+            .map(|(sensor_id, x, y, z)| SensorXYZ { sensor_id, x, y, z });
+        let charges = [(0, 1, 10), (0, 2, 5), (1, 1, 7), (1, 2, 8), (1, 3, 2)]
+            .map(|(event_id, sensor_id, charge)| Qtot { event_id, sensor_id, charge });
+        let hits = [(0, 1, 0.5), (0, 1, 0.3), (0, 2, 0.9), (1, 1, 1.0), (1, 3, 1.2), (1, 3, 1.1), (1, 3, 1.4)]
+            .map(|(event_id, sensor_id, time)| Waveform { event_id, sensor_id, time });
+        mc.new_dataset_builder().with_data(&sensors).create("sensor_xyz"  )?;
+        mc.new_dataset_builder().with_data(&charges).create("total_charge")?;
+        mc.new_dataset_builder().with_data(&hits   ).create("waveform"    )?;
+        let lors = [1.0, 2.0].map(|dt| Hdf5Lor { dt, x1: 100.0, y1: 0.0, z1: 0.0, x2: -100.0, y2: 0.0, z2: 0.0,
+                                                 q1: 10.0, q2: 5.0, E1: f32::NAN, E2: f32::NAN });
+        file.create_group("reco_info")?.new_dataset_builder().with_data(&lors).create("lors")?;
+        Ok(path.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn events_are_gathered_from_every_table() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let file = mc_file(dir.path())?;
+
+        let event = EventDetail::read(&file, 0, None)?;
+        assert_eq!((event.total_charge(), event.n_hits()), (15, 3));
+        assert_eq!(event.sensors[0], SensorDetail { sensor_id: 1, position: Some([100.0, 0.0, 0.0]),
+                                                    charge: 10, hits: 2, first_hit: Some(0.3) });
+        assert_eq!(event.lor, None);
+
+        let row = LorRow { file: &file, dataset: "reco_info/lors", row: 1 };
+        let event = EventDetail::read(&file, 1, Some(row))?;
+        assert_eq!((event.total_charge(), event.n_hits()), (17, 4));
+        assert_eq!(event.sensors.iter().map(|s| s.sensor_id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(event.sensors[2].position, None);
+        assert_eq!(event.sensors[2].first_hit, Some(1.1));
+        assert_eq!(event.lor.map(|lor| lor.dt), Some(2.0));
+
+        assert!(EventDetail::read(&file, 2, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_has_a_row_per_sensor_and_endpoint() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let file = mc_file(dir.path())?;
+        let row = LorRow { file: &file, dataset: "reco_info/lors", row: 0 };
+        let mut csv = vec![];
+        EventDetail::read(&file, 1, Some(row))?.write_csv(&mut csv)?;
+        let csv = String::from_utf8(csv)?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 1 + 3 + 2);
+        assert_eq!(lines[3], "sensor,3,,,,2,3,1.1");
+        assert_eq!(lines[5], "lor2,,-100,0,0,5,,");
+        Ok(())
+    }
+}
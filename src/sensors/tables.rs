@@ -0,0 +1,211 @@
+//! Validation of the sensor-position and per-sensor signal tables.
+//!
+//! Calibration files may contain several `SensorXYZ` rows with the same
+//! `sensor_id`, and `Charge` or `SensorHit` rows referring to sensors which do
+//! not appear in `SensorXYZ` at all. `SensorTables` resolves the former
+//! according to a `DuplicateSensors` policy and removes the latter (orphans),
+//! remembering what fraction of each event's charge was lost, so that
+//! incomplete events can be cut.
+
+use std::collections::{HashMap, HashSet};
+
+use super::SensorXYZ;
+
+/// Charge collected by one sensor in one event
+#[cfg_attr(feature = "hdf5", derive(hdf5::H5Type))]
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct Charge {
+    pub event_id: u64,
+    pub sensor_id: u64,
+    pub charge: u64,
+}
+
+/// Time of a sensor's signal in one event
+#[cfg_attr(feature = "hdf5", derive(hdf5::H5Type))]
+#[derive(Clone, PartialEq, Debug)]
+#[repr(C)]
+pub struct SensorHit {
+    pub event_id: u64,
+    pub sensor_id: u64,
+    pub time: f64,
+}
+
+/// What to do with `SensorXYZ` rows whose `sensor_id` has already been seen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateSensors {
+    /// Refuse to load the tables
+    Error,
+    /// Use the first row with each id, warning about the others
+    KeepFirst,
+}
+
+impl Default for DuplicateSensors {
+    fn default() -> Self { Self::KeepFirst }
+}
+
+impl std::str::FromStr for DuplicateSensors {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error"      => Ok(Self::Error),
+            "keep-first" => Ok(Self::KeepFirst),
+            _ => Err(format!("Unknown duplicate sensor policy '{s}': use error or keep-first")),
+        }
+    }
+}
+
+/// Sensor positions, charges and hits, with duplicate sensors and orphan rows
+/// removed
+#[derive(Clone, Debug, Default)]
+pub struct SensorTables {
+    pub sensors: Vec<SensorXYZ>,
+    pub charges: Vec<Charge>,
+    pub hits: Vec<SensorHit>,
+    /// Number of `SensorXYZ` rows discarded as duplicates
+    pub n_duplicates: usize,
+    /// Number of `Charge` rows discarded because their sensor is unknown
+    pub n_orphan_charges: usize,
+    /// Number of `SensorHit` rows discarded because their sensor is unknown
+    pub n_orphan_hits: usize,
+    /// Fraction of the charge on unknown sensors, for events with any
+    orphan_fraction: HashMap<u64, f32>,
+}
+
+impl SensorTables {
+    pub fn new(
+        sensors: Vec<SensorXYZ>,
+        charges: Vec<Charge>,
+        hits: Vec<SensorHit>,
+        duplicates: DuplicateSensors,
+    ) -> Result<Self, String> {
+        let (sensors, n_duplicates) = deduplicate(sensors, duplicates)?;
+        let known: HashSet<u64> = sensors.iter().map(|s| s.sensor_id as u64).collect();
+
+        let mut total  = HashMap::<u64, u64>::new();
+        let mut orphan = HashMap::<u64, u64>::new();
+        for c in &charges {
+            *total.entry(c.event_id).or_default() += c.charge;
+            if !known.contains(&c.sensor_id) { *orphan.entry(c.event_id).or_default() += c.charge }
+        }
+        let orphan_fraction = orphan.into_iter()
+            .map(|(event, q)| (event, if q == 0 { 0.0 } else { q as f32 / total[&event] as f32 }))
+            .collect();
+
+        let n_charges = charges.len();
+        let n_hits    = hits   .len();
+        let charges: Vec<_> = charges.into_iter().filter(|c| known.contains(&c.sensor_id)).collect();
+        let hits   : Vec<_> = hits   .into_iter().filter(|h| known.contains(&h.sensor_id)).collect();
+        Ok(Self {
+            n_duplicates,
+            n_orphan_charges: n_charges - charges.len(),
+            n_orphan_hits   : n_hits    - hits   .len(),
+            sensors, charges, hits, orphan_fraction,
+        })
+    }
+
+    /// Fraction of the charge of `event_id` which was collected by sensors
+    /// absent from `SensorXYZ`
+    pub fn orphan_charge_fraction(&self, event_id: u64) -> f32 {
+        self.orphan_fraction.get(&event_id).copied().unwrap_or(0.0)
+    }
+
+    /// Whether no more than `max_orphan_fraction` of the charge of `event_id`
+    /// was lost to unknown sensors
+    pub fn is_complete(&self, event_id: u64, max_orphan_fraction: f32) -> bool {
+        self.orphan_charge_fraction(event_id) <= max_orphan_fraction
+    }
+
+    /// Number of events which lost some charge to unknown sensors
+    pub fn n_incomplete_events(&self) -> usize { self.orphan_fraction.len() }
+
+    /// One-line account of what was removed, for warnings
+    pub fn report(&self) -> String {
+        format!("{} duplicate sensors, {} orphan charges and {} orphan hits removed; {} events lost charge to unknown sensors",
+                self.n_duplicates, self.n_orphan_charges, self.n_orphan_hits, self.n_incomplete_events())
+    }
+}
+
+/// `sensors` without rows repeating an earlier `sensor_id`, and the number of
+/// rows removed
+fn deduplicate(sensors: Vec<SensorXYZ>, policy: DuplicateSensors) -> Result<(Vec<SensorXYZ>, usize), String> {
+    let mut first = HashMap::<u32, usize>::new();
+    let mut kept = Vec::with_capacity(sensors.len());
+    let mut n_duplicates = 0;
+    for s in sensors {
+        if let Some(&i) = first.get(&s.sensor_id) {
+            let SensorXYZ { x, y, z, .. } = kept[i];
+            let conflict = if (x, y, z) == (s.x, s.y, s.z) { String::new() }
+                           else { format!(": ({}, {}, {}) conflicts with ({x}, {y}, {z})", s.x, s.y, s.z) };
+            match policy {
+                DuplicateSensors::Error     => return Err(format!("Duplicate sensor id {}{conflict}", s.sensor_id)),
+                DuplicateSensors::KeepFirst => tracing::warn!("Ignoring duplicate sensor id {}{conflict}", s.sensor_id),
+            }
+            n_duplicates += 1;
+        } else {
+            first.insert(s.sensor_id, kept.len());
+            kept.push(s);
+        }
+    }
+    Ok((kept, n_duplicates))
+}
+
+#[cfg(test)]
+mod test_sensor_tables {
+    use super::*;
+
+    fn sensor(sensor_id: u32, x: f32) -> SensorXYZ { SensorXYZ { sensor_id, x, y: 0.0, z: 0.0 } }
+    fn charge(event_id: u64, sensor_id: u64, charge: u64) -> Charge { Charge { event_id, sensor_id, charge } }
+    fn hit   (event_id: u64, sensor_id: u64) -> SensorHit { SensorHit { event_id, sensor_id, time: 1.0 } }
+
+    #[test]
+    fn duplicates_are_rejected_under_error() {
+        let sensors = vec![sensor(1, 10.0), sensor(2, 20.0), sensor(1, 30.0)];
+        let err = SensorTables::new(sensors, vec![], vec![], DuplicateSensors::Error).unwrap_err();
+        assert!(err.contains("Duplicate sensor id 1"), "{err}");
+    }
+
+    #[test]
+    fn first_duplicate_is_kept() {
+        let sensors = vec![sensor(1, 10.0), sensor(2, 20.0), sensor(1, 30.0), sensor(2, 20.0)];
+        let tables = SensorTables::new(sensors, vec![], vec![], DuplicateSensors::KeepFirst).unwrap();
+        assert_eq!(tables.sensors, vec![sensor(1, 10.0), sensor(2, 20.0)]);
+        assert_eq!(tables.n_duplicates, 2);
+    }
+
+    #[test]
+    fn orphans_are_counted_and_removed() {
+        let sensors = vec![sensor(1, 10.0), sensor(2, 20.0)];
+        let charges = vec![charge(0, 1, 5), charge(0, 9, 5), charge(1, 2, 4), charge(1, 7, 1), charge(1, 8, 1)];
+        let hits    = vec![hit(0, 1), hit(0, 9), hit(1, 2)];
+        let tables = SensorTables::new(sensors, charges, hits, DuplicateSensors::Error).unwrap();
+        assert_eq!((tables.n_orphan_charges, tables.n_orphan_hits), (3, 1));
+        assert_eq!(tables.charges, vec![charge(0, 1, 5), charge(1, 2, 4)]);
+        assert_eq!(tables.hits, vec![hit(0, 1), hit(1, 2)]);
+        assert_eq!(tables.orphan_charge_fraction(0), 0.5);
+        assert_eq!(tables.orphan_charge_fraction(1), 2.0 / 6.0);
+        assert_eq!(tables.n_incomplete_events(), 2);
+    }
+
+    #[test]
+    fn mostly_orphaned_event_fails_completeness_cut() {
+        let sensors = vec![sensor(1, 10.0), sensor(2, 20.0)];
+        let charges = vec![
+            charge(0, 1, 8), charge(0, 2, 8),                  // complete
+            charge(1, 1, 3), charge(1, 5, 6), charge(1, 6, 2), // majority orphaned
+        ];
+        let tables = SensorTables::new(sensors, charges, vec![], DuplicateSensors::KeepFirst).unwrap();
+        assert!( tables.is_complete(0, 0.5));
+        assert!(!tables.is_complete(1, 0.5));
+        assert!( tables.is_complete(1, 1.0));
+        // Events without any charge are not penalized
+        assert!( tables.is_complete(42, 0.0));
+    }
+
+    #[test]
+    fn policy_from_str() {
+        assert_eq!("keep-first".parse::<DuplicateSensors>(), Ok(DuplicateSensors::KeepFirst));
+        assert_eq!("Error"     .parse::<DuplicateSensors>(), Ok(DuplicateSensors::Error));
+        assert!("drop".parse::<DuplicateSensors>().is_err());
+    }
+}
@@ -0,0 +1,751 @@
+//! Destinations for the images produced by each MLEM (sub)iteration.
+//!
+//! `drive` pulls images from a reconstruction (such as `Image::mlem`) and hands
+//! each one to every `IterationSink`, so that library users can send the
+//! images wherever they like. The first error from a sink aborts the run.
+//!
+//! File outputs may be recorded in a `Manifest`, so that an interrupted run
+//! can be resumed without rewriting the outputs which were completed.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Time;
+use crate::divergence::{max_relative_change, mismatch, IterationStats, Monitor};
+use crate::gauss::TofCutoff;
+use crate::image::Image;
+use crate::io;
+use crate::mlem::forward_projections;
+use crate::post_filter::PostFilter;
+use crate::system_matrix::{Tube, LOR};
+#[cfg(feature = "hdf5")]
+use geometry::units::mm_;
+#[cfg(feature = "hdf5")]
+use crate::io::hdf5::{Compression, Writer};
+
+pub trait IterationSink {
+    /// Receive the `n`th image of the run, counting from 1 over all iterations
+    /// and subsets
+    fn on_iteration(&mut self, n: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>>;
+
+    /// Add to the statistics of `image`, before any sink receives them
+    fn annotate(&mut self, _image: &Image, _stats: &mut IterationStats) -> Result<(), Box<dyn Error>> { Ok(()) }
+
+    /// Called once after the last image of a run which was not aborted
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> { Ok(()) }
+}
+
+/// Any suitable closure is a sink
+impl<F> IterationSink for F
+where
+    F: FnMut(usize, &Image, &IterationStats) -> Result<(), Box<dyn Error>>
+{
+    fn on_iteration(&mut self, n: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        self(n, image, stats)
+    }
+}
+
+/// Discards every image
+pub struct NoopSink;
+
+impl IterationSink for NoopSink {
+    fn on_iteration(&mut self, _: usize, _: &Image, _: &IterationStats) -> Result<(), Box<dyn Error>> { Ok(()) }
+}
+
+/// File format of the images written by `RawFileSink`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Versioned raw format (see `io::raw`)
+    Raw,
+    /// NIfTI-1, with the voxel size and position of the FOV (see `io::nifti`)
+    Nifti,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self { Self::Raw }
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Raw   => "raw",
+            Self::Nifti => "nii",
+        }
+    }
+
+    pub fn write(self, image: &Image, path: &Path) -> Result<(), Box<dyn Error>> {
+        match self {
+            Self::Raw   => image.write_to_raw_file(path),
+            Self::Nifti => Ok(io::nifti::write(image, path)?),
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw"   => Ok(Self::Raw),
+            "nifti" => Ok(Self::Nifti),
+            _ => Err(format!("Unknown image format '{s}': use raw or nifti")),
+        }
+    }
+}
+
+/// Writes each image to `{pattern}{iteration:02}-{subset:02}.raw` (or `.nii`,
+/// according to `format`), and optionally its voxel-centre axes alongside
+pub struct RawFileSink {
+    pub pattern: String,
+    pub format: ImageFormat,
+    pub write_axes: bool,
+    /// Record of the files, which skips those already written
+    pub manifest: Option<Manifest>,
+}
+
+impl RawFileSink {
+    pub fn path(&self, iteration: usize, subset: usize) -> PathBuf {
+        PathBuf::from(format!("{}{iteration:02}-{subset:02}.{}", self.pattern, self.format.extension()))
+    }
+}
+
+impl IterationSink for RawFileSink {
+    fn on_iteration(&mut self, _: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        let path = self.path(stats.iteration, stats.subset);
+        write_output(self.manifest.as_ref(), &path, |tmp| self.format.write(image, tmp))?;
+        if self.write_axes {
+            write_output(self.manifest.as_ref(), &io::raw::axes_path(&path), |tmp| Ok(io::raw::write_axes(image.fov, tmp)?))?;
+        }
+        Ok(())
+    }
+}
+
+/// Hands every image to `sinks` after applying `filter`, once however many
+/// sinks there are. Without a filter, the images are passed on unchanged.
+pub struct PostFiltered {
+    pub filter: Option<PostFilter>,
+    pub sinks: Vec<Box<dyn IterationSink>>,
+}
+
+impl IterationSink for PostFiltered {
+    fn on_iteration(&mut self, n: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        let filtered = self.filter.map(|filter| filter.apply(image));
+        let image = filtered.as_ref().unwrap_or(image);
+        for sink in &mut self.sinks { sink.on_iteration(n, image, stats)? }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        for sink in &mut self.sinks { sink.finish()? }
+        Ok(())
+    }
+}
+
+/// Writes all images to a single HDF5 file: image `{iteration:02}-{subset:02}`
+/// in dataset `images/{iteration:02}-{subset:02}`, with shape `[nz, ny, nx]`.
+/// The full width (mm) of the FOV along x, y and z is in dataset
+/// `full_width_mm`. Any existing file is replaced.
+///
+/// With a `Manifest`, the images are written to a temporary file, which
+/// replaces the series when the run finishes, and a completed series is not
+/// written again.
+#[cfg(feature = "hdf5")]
+pub struct Hdf5SeriesSink {
+    path: PathBuf,
+    created: bool,
+    manifest: Option<Manifest>,
+    compression: Compression,
+}
+
+#[cfg(feature = "hdf5")]
+impl Hdf5SeriesSink {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self { path: path.as_ref().into(), created: false, manifest: None, compression: Compression::None }
+    }
+
+    pub fn with_manifest(self, manifest: Manifest) -> Self { Self { manifest: Some(manifest), ..self } }
+
+    pub fn with_compression(self, compression: Compression) -> Self { Self { compression, ..self } }
+
+    /// Where the images are being written
+    fn writing(&self) -> PathBuf {
+        if self.manifest.is_some() { Manifest::temp_path(&self.path) } else { self.path.clone() }
+    }
+
+    pub fn dataset(iteration: usize, subset: usize) -> String { format!("images/{iteration:02}-{subset:02}") }
+}
+
+#[cfg(feature = "hdf5")]
+impl IterationSink for Hdf5SeriesSink {
+    fn on_iteration(&mut self, _: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        if let Some(manifest) = &self.manifest {
+            if manifest.is_done(&self.path)? { return Ok(()) }
+        }
+        let path = self.writing();
+        let writer = if self.created {
+            Writer::append(&path)?
+        } else {
+            let writer = Writer::create(&path)?;
+            let h = image.fov.half_width;
+            let full_width = [h.x, h.y, h.z].map(|h| mm_(h) * 2.0);
+            writer.file().new_dataset_builder().with_data(&full_width).create("full_width_mm")?;
+            self.created = true;
+            writer
+        };
+        let [nx, ny, nz] = image.fov.n;
+        let data = ndarray::Array3::from_shape_vec((nz, ny, nx), image.data.clone())?;
+        writer.compression(self.compression).write(&Self::dataset(stats.iteration, stats.subset), data.view())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        match &self.manifest {
+            Some(manifest) if self.created => manifest.complete(&self.path),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A diverging image is an error
+impl IterationSink for Monitor<'_> {
+    fn on_iteration(&mut self, _: usize, image: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        self.observe(image, stats.iteration, stats.subset)
+    }
+}
+
+/// Columns of the statistics written by `StatsSink` as CSV, in order:
+///
+/// + `iteration`, `subset`: counting from 1
+/// + `seconds`: wall-clock time taken to compute the image
+/// + `total`, `max`: sum and maximum of the finite voxels
+/// + `non_finite`: number of NaN or infinite voxels
+/// + `max_change`: largest relative change of any voxel from the previous
+///   image (see `divergence::max_relative_change`)
+/// + `mismatch`: negative mean log-likelihood of a sample of the LORs (see
+///   `divergence::mismatch`)
+///
+/// Statistics which were not calculated are empty fields. JSON lines have the
+/// same fields, with `null` for those not calculated.
+pub const STATS_COLUMNS: [&str; 8] = ["iteration", "subset", "seconds", "total", "max", "non_finite", "max_change", "mismatch"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat { Csv, JsonLines }
+
+impl StatsFormat {
+    /// JSON lines for `.json` and `.jsonl` files, otherwise CSV
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json" | "jsonl") => Self::JsonLines,
+            _                      => Self::Csv,
+        }
+    }
+}
+
+/// Records the statistics of every image, including the data mismatch on a
+/// sample of LORs if requested, and optionally appends them to a file as they
+/// arrive, flushing it after every image, so that a run can be followed while
+/// in progress.
+#[derive(Default)]
+pub struct StatsSink<'a> {
+    file: Option<(BufWriter<File>, StatsFormat)>,
+    likelihood: Option<Likelihood<'a>>,
+    history: Vec<IterationStats>,
+}
+
+struct Likelihood<'a> {
+    sample: &'a [LOR],
+    sigma: Option<Time>,
+    cutoff: Option<TofCutoff>,
+    tube: Option<Tube>,
+}
+
+impl<'a> StatsSink<'a> {
+    pub fn new() -> Self { Self::default() }
+
+    /// Write the statistics to `path`, replacing any existing file, in the
+    /// format chosen by `StatsFormat::of_path`
+    pub fn writing_to(self, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let format = StatsFormat::of_path(path);
+        let mut file = BufWriter::new(File::create(path).map_err(|e| format!("{}: {e}", path.display()))?);
+        if format == StatsFormat::Csv {
+            writeln!(file, "{}", STATS_COLUMNS.join(","))?;
+            file.flush()?;
+        }
+        Ok(Self { file: Some((file, format)), ..self })
+    }
+
+    /// Calculate the data mismatch of every image on `sample`
+    pub fn with_likelihood(self, sample: &'a [LOR], sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>) -> Self {
+        Self { likelihood: Some(Likelihood { sample, sigma, cutoff, tube }), ..self }
+    }
+
+    /// Statistics of all the images received so far
+    pub fn history(&self) -> &[IterationStats] { &self.history }
+
+    pub fn into_history(self) -> Vec<IterationStats> { self.history }
+}
+
+impl IterationSink for StatsSink<'_> {
+    fn annotate(&mut self, image: &Image, stats: &mut IterationStats) -> Result<(), Box<dyn Error>> {
+        if let Some(Likelihood { sample, sigma, cutoff, tube }) = &self.likelihood {
+            let projections = forward_projections(image, sample, *sigma, *cutoff, *tube);
+            stats.mismatch = Some(mismatch(&projections, stats.total));
+        }
+        Ok(())
+    }
+
+    fn on_iteration(&mut self, _: usize, _: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+        self.history.push(*stats);
+        if let Some((file, format)) = &mut self.file {
+            match format {
+                StatsFormat::Csv       => writeln!(file, "{}", csv_row(stats))?,
+                StatsFormat::JsonLines => writeln!(file, "{}", serde_json::to_string(stats)?)?,
+            }
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// `stats` in the order of `STATS_COLUMNS`
+fn csv_row(stats: &IterationStats) -> String {
+    let optional = |x: Option<f32>| x.map_or(String::new(), |x| x.to_string());
+    let IterationStats { iteration, subset, max, total, non_finite, mismatch, seconds, max_change } = *stats;
+    format!("{iteration},{subset},{},{total},{max},{non_finite},{},{}", optional(seconds), optional(max_change), optional(mismatch))
+}
+
+/// Pass the first `n_images` of `images` to each of the `sinks`, in order.
+/// Returns the last image, or the first error of any sink, which stops the run.
+pub fn drive(
+    images: impl Iterator<Item = (Image, usize, usize)>,
+    n_images: usize,
+    sinks: &mut [&mut dyn IterationSink],
+) -> Result<Option<Image>, Box<dyn Error>> {
+    drive_until(images, n_images, sinks, |_| false)
+}
+
+/// As `drive`, but stopping early, after the sinks have received it, at the
+/// first image whose statistics satisfy `stop`
+pub fn drive_until(
+    images: impl Iterator<Item = (Image, usize, usize)>,
+    n_images: usize,
+    sinks: &mut [&mut dyn IterationSink],
+    stop: impl Fn(&IterationStats) -> bool,
+) -> Result<Option<Image>, Box<dyn Error>> {
+    let mut last: Option<Image> = None;
+    let mut start = Instant::now();
+    for (n, (image, iteration, subset)) in images.take(n_images).enumerate() {
+        let n = n + 1;
+        let seconds = Some(start.elapsed().as_secs_f32());
+        let max_change = last.as_ref().map(|last| max_relative_change(&last.data, &image.data));
+        let mut stats = IterationStats { seconds, max_change, ..IterationStats::of(&image, iteration, subset, None) };
+        let context = |e: Box<dyn Error>| format!("Iteration {iteration}, subset {subset} (image {n}): {e}");
+        for sink in sinks.iter_mut() {
+            sink.annotate(&image, &mut stats).map_err(context)?;
+        }
+        for sink in sinks.iter_mut() {
+            sink.on_iteration(n, &image, &stats).map_err(context)?;
+        }
+        last = Some(image);
+        if stop(&stats) { break }
+        start = Instant::now();
+    }
+    for sink in sinks.iter_mut() {
+        sink.finish().map_err(|e| format!("Finishing outputs: {e}"))?;
+    }
+    Ok(last)
+}
+
+/// Whether a planned output has been completely written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status { Pending, Done }
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub status: Status,
+    /// `checksum` of the file, once it is done
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// The entries of a manifest, by output path
+pub type ManifestEntries = BTreeMap<String, ManifestEntry>;
+
+/// JSON file listing the planned outputs of a run, each `pending` until it has
+/// been completely written, then `done` with a checksum of its contents.
+/// Outputs are written under a temporary name and renamed into place, as is
+/// the manifest itself, so an interrupted run never leaves a partial file
+/// under an output's name, nor marks one as done.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    path: PathBuf,
+}
+
+impl Manifest {
+    /// Record `planned` outputs in the manifest at `path`, all pending. When
+    /// `resume`-ing, outputs which an existing manifest marks as done, and
+    /// whose files still match their checksums, stay done.
+    pub fn start(path: impl AsRef<Path>, planned: impl IntoIterator<Item = PathBuf>, resume: bool) -> Result<Self, Box<dyn Error>> {
+        let manifest = Self { path: path.as_ref().into() };
+        let previous = if resume && manifest.path.exists() { manifest.entries()? } else { ManifestEntries::new() };
+        let mut entries = ManifestEntries::new();
+        for output in planned {
+            let key = Self::key(&output);
+            let done = previous.get(&key)
+                .filter(|entry| entry.status == Status::Done)
+                .and_then(|entry| entry.checksum.as_ref())
+                .map_or(false, |expected| checksum(&output).map_or(false, |actual| &actual == expected));
+            let entry = if done { previous[&key].clone() } else { ManifestEntry { status: Status::Pending, checksum: None } };
+            entries.insert(key, entry);
+        }
+        manifest.save(&entries)?;
+        Ok(manifest)
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn entries(&self) -> Result<ManifestEntries, Box<dyn Error>> {
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("Reading manifest {}: {e}", self.path.display()))?;
+        Ok(serde_json::from_str(&text).map_err(|e| format!("Parsing manifest {}: {e}", self.path.display()))?)
+    }
+
+    pub fn is_done(&self, output: &Path) -> Result<bool, Box<dyn Error>> {
+        Ok(self.entries()?.get(&Self::key(output)).map_or(false, |entry| entry.status == Status::Done))
+    }
+
+    /// Unless `output` is done, have `write` write it to the temporary path it
+    /// is given, then `complete` it. Returns whether `output` was written.
+    pub fn produce(&self, output: &Path, write: impl FnOnce(&Path) -> Result<(), Box<dyn Error>>) -> Result<bool, Box<dyn Error>> {
+        if self.is_done(output)? { return Ok(false) }
+        write(&Self::temp_path(output))?;
+        self.complete(output)?;
+        Ok(true)
+    }
+
+    /// Move the temporary file of `output` into place, and mark it as done
+    pub fn complete(&self, output: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::rename(Self::temp_path(output), output)
+            .map_err(|e| format!("Completing {}: {e}", output.display()))?;
+        let mut entries = self.entries()?;
+        entries.insert(Self::key(output), ManifestEntry { status: Status::Done, checksum: Some(checksum(output)?) });
+        self.save(&entries)
+    }
+
+    /// Where `output` is written before it is complete
+    pub fn temp_path(output: &Path) -> PathBuf {
+        let name = output.file_name().map_or_else(Default::default, |n| n.to_string_lossy().into_owned());
+        output.with_file_name(format!("{name}.partial"))
+    }
+
+    fn key(output: &Path) -> String { output.to_string_lossy().into_owned() }
+
+    fn save(&self, entries: &ManifestEntries) -> Result<(), Box<dyn Error>> {
+        let tmp = Self::temp_path(&self.path);
+        std::fs::write(&tmp, serde_json::to_string_pretty(entries)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Write `output` through `manifest`, if any, or directly
+pub fn write_output(manifest: Option<&Manifest>, output: &Path, write: impl FnOnce(&Path) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    match manifest {
+        Some(manifest) => manifest.produce(output, write).map(|_| ()),
+        None           => write(output),
+    }
+}
+
+/// 64-bit FNV-1a hash of the contents of the file at `path`, as
+/// `fnv1a64:<16 hex digits>`
+pub fn checksum(path: &Path) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, &b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3));
+    Ok(format!("fnv1a64:{hash:016x}"))
+}
+
+#[cfg(test)]
+mod test_sinks {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use std::cell::Cell;
+
+    /// MLEM images of the 2D analytic system, counting how many are computed
+    fn images<'a>(system: &'a AnalyticSystem, lors: &'a [crate::system_matrix::LOR], n_subsets: usize, computed: &'a Cell<usize>)
+                  -> impl Iterator<Item = (Image, usize, usize)> + 'a {
+        Image::mlem(system.fov, lors, None, None, None, Some(system.sensitivity_image()), n_subsets)
+            .inspect(move |_| computed.set(computed.get() + 1))
+    }
+
+    #[derive(Default)]
+    struct Recorder { calls: Vec<(usize, usize, usize)> }
+
+    impl IterationSink for Recorder {
+        fn on_iteration(&mut self, n: usize, _: &Image, stats: &IterationStats) -> Result<(), Box<dyn Error>> {
+            self.calls.push((n, stats.iteration, stats.subset));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_image_reaches_every_sink() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let mut recorder = Recorder::default();
+        let last = drive(images(&system, &lors, 1, &computed), 5, &mut [&mut recorder, &mut NoopSink]).unwrap();
+        assert_eq!(recorder.calls, (1..=5).map(|i| (i, i, 1)).collect::<Vec<_>>());
+        assert_eq!(computed.get(), 5);
+        assert!(last.is_some());
+    }
+
+    #[test]
+    fn driving_stops_once_images_stop_changing() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let mut stats = StatsSink::new();
+        let converged = |s: &IterationStats| s.max_change.map_or(false, |change| change < 1e-3);
+        drive_until(images(&system, &lors, 1, &computed), 1000, &mut [&mut stats], converged).unwrap();
+        let history = stats.history();
+        let n = history.len();
+        assert!(1 < n && n < 1000, "{n}");
+        assert_eq!(computed.get(), n);
+        assert_eq!(history[0].max_change, None);
+        assert!(history[1..n - 1].iter().all(|s| !converged(s)));
+        assert!(converged(&history[n - 1]));
+    }
+
+    #[test]
+    fn annotations_reach_every_sink() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let mut stats = StatsSink::new().with_likelihood(&lors, None, None, None);
+        let mut seen = vec![];
+        let mut record = |_: usize, _: &Image, stats: &IterationStats| -> Result<(), Box<dyn Error>> { seen.push(stats.mismatch); Ok(()) };
+        drive(images(&system, &lors, 1, &computed), 3, &mut [&mut record, &mut stats]).unwrap();
+        assert_eq!(seen.len(), 3);
+        assert!(seen.iter().all(Option::is_some));
+        assert_eq!(seen, stats.history().iter().map(|s| s.mismatch).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn failing_sink_stops_the_run() {
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let mut recorder = Recorder::default();
+        let mut fail_on_2 = |n: usize, _: &Image, _: &IterationStats| -> Result<(), Box<dyn Error>> {
+            if n == 2 { Err("storage unavailable".into()) } else { Ok(()) }
+        };
+        let err = drive(images(&system, &lors, 1, &computed), 10, &mut [&mut recorder, &mut fail_on_2])
+            .unwrap_err().to_string();
+        assert_eq!(err, "Iteration 2, subset 1 (image 2): storage unavailable");
+        assert_eq!(recorder.calls.len(), 2);
+        assert_eq!(computed.get(), 2);
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn file_sinks() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let mut raw = RawFileSink { pattern: dir.path().join("run-").to_str().unwrap().into(), format: ImageFormat::Raw, write_axes: false, manifest: None };
+        let h5 = dir.path().join("series.h5");
+        let mut series = Hdf5SeriesSink::new(&h5);
+        let h5_gzip = dir.path().join("series_gzip.h5");
+        let mut compressed = Hdf5SeriesSink::new(&h5_gzip).with_compression(Compression::Gzip(4));
+        let last = drive(images(&system, &lors, 1, &computed), 3, &mut [&mut raw, &mut series, &mut compressed])?.unwrap();
+
+        assert_eq!(Image::from_raw_file(&raw.path(3, 1))?.data, last.data);
+        let file = hdf5::File::open(&h5)?;
+        assert_eq!(file.group("images")?.member_names()?, vec!["01-01", "02-01", "03-01"]);
+        let dataset = file.dataset(&Hdf5SeriesSink::dataset(3, 1))?;
+        assert_eq!(dataset.shape(), vec![1, 2, 2]);
+        assert_eq!(dataset.read_raw::<f32>()?, last.data);
+        assert_eq!(file.dataset("full_width_mm")?.read_raw::<f32>()?, vec![2.0, 2.0, 1.0]);
+        let dataset = hdf5::File::open(&h5_gzip)?.dataset(&Hdf5SeriesSink::dataset(3, 1))?;
+        assert!(!dataset.filters().is_empty());
+        assert_eq!(dataset.read_raw::<f32>()?, last.data);
+        Ok(())
+    }
+
+    #[test]
+    fn nifti_files_match_raw_files() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let computed = Cell::new(0);
+        let sink = |format| RawFileSink { pattern: dir.path().join("run-").to_str().unwrap().into(), format, write_axes: false, manifest: None };
+        let (mut raw, mut nifti) = (sink(ImageFormat::Raw), sink(ImageFormat::Nifti));
+        drive(images(&system, &lors, 1, &computed), 2, &mut [&mut raw, &mut nifti])?;
+
+        let path = nifti.path(2, 1);
+        assert_eq!(path.extension().unwrap(), "nii");
+        let (fov, _) = io::nifti::read_header(&path)?;
+        assert_eq!(fov.n, system.fov.n);
+        let size = |fov: crate::fov::FOV| [0, 1, 2].map(|a| geometry::units::mm_(fov.voxel_size[a]));
+        float_eq::assert_float_eq!(size(fov), size(system.fov), rmax_all <= 1e-6);
+        let bytes = std::fs::read(&path)?;
+        let n = system.fov.n.iter().product::<usize>();
+        let data: Vec<f32> = bytes[bytes.len() - 4 * n..].chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(data, Image::from_raw_file(&raw.path(2, 1))?.data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_stats_sink {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn format_follows_extension() {
+        assert_eq!(StatsFormat::of_path(Path::new("run/stats.csv"  )), StatsFormat::Csv);
+        assert_eq!(StatsFormat::of_path(Path::new("run/stats"      )), StatsFormat::Csv);
+        assert_eq!(StatsFormat::of_path(Path::new("run/stats.jsonl")), StatsFormat::JsonLines);
+        assert_eq!(StatsFormat::of_path(Path::new("run/stats.json" )), StatsFormat::JsonLines);
+    }
+
+    #[test]
+    fn json_lines_have_the_columns_as_fields() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let system = AnalyticSystem::two_d();
+        let lors = system.measured_lors();
+        let path = dir.path().join("stats.jsonl");
+        let mut stats = StatsSink::new().writing_to(&path)?;
+        let images = Image::mlem(system.fov, &lors, None, None, None, Some(system.sensitivity_image()), 1);
+        drive(images, 3, &mut [&mut stats])?;
+
+        let lines = std::fs::read_to_string(&path)?.lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(stats.history().len(), 3);
+        for (line, stats) in lines.iter().zip(stats.history()) {
+            let fields = line.as_object().unwrap().keys().map(String::as_str).collect::<BTreeSet<_>>();
+            assert_eq!(fields, STATS_COLUMNS.iter().copied().collect());
+            assert_eq!(line["iteration"], stats.iteration);
+            assert_eq!(line["total"], stats.total);
+            assert!(line["mismatch"].is_null());
+            assert!(line["seconds"].is_number());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "hdf5"))]
+mod test_manifest {
+    use super::*;
+    use crate::testing::AnalyticSystem;
+    use std::os::unix::fs::MetadataExt;
+
+    struct Run {
+        dir: tempfile::TempDir,
+        system: AnalyticSystem,
+        lors: Vec<crate::system_matrix::LOR>,
+    }
+
+    impl Run {
+        fn new() -> Self {
+            let system = AnalyticSystem::two_d();
+            let lors = system.measured_lors();
+            Self { dir: tempfile::tempdir().unwrap(), system, lors }
+        }
+
+        fn raw(&self, n: usize) -> PathBuf { self.dir.path().join(format!("run-{n:02}-01.raw")) }
+        fn series(&self) -> PathBuf { self.dir.path().join("series.h5") }
+        fn manifest_path(&self) -> PathBuf { self.dir.path().join("manifest.json") }
+
+        fn planned(&self) -> Vec<PathBuf> {
+            (1..=5).map(|n| self.raw(n)).chain([self.series()]).collect()
+        }
+
+        /// Reconstruct 5 images into raw files and an HDF5 series, with a sink
+        /// failing on image `fail_on`, if any
+        fn go(&self, resume: bool, fail_on: Option<usize>) -> Result<Manifest, Box<dyn Error>> {
+            let manifest = Manifest::start(self.manifest_path(), self.planned(), resume)?;
+            let pattern = self.dir.path().join("run-").to_str().unwrap().into();
+            let mut raw = RawFileSink { pattern, format: ImageFormat::Raw, write_axes: false, manifest: Some(manifest.clone()) };
+            let mut series = Hdf5SeriesSink::new(self.series()).with_manifest(manifest.clone());
+            let mut fail = |n: usize, _: &Image, _: &IterationStats| -> Result<(), Box<dyn Error>> {
+                if Some(n) == fail_on { Err("interrupted".into()) } else { Ok(()) }
+            };
+            let images = Image::mlem(self.system.fov, &self.lors, None, None, None, Some(self.system.sensitivity_image()), 1);
+            drive(images, 5, &mut [&mut raw, &mut series, &mut fail])?;
+            Ok(manifest)
+        }
+    }
+
+    fn inode(path: &Path) -> u64 { std::fs::metadata(path).unwrap().ino() }
+
+    #[test]
+    fn interrupted_run_resumes_missing_outputs() -> Result<(), Box<dyn Error>> {
+        let run = Run::new();
+        assert!(run.go(false, Some(3)).is_err());
+
+        let manifest = Manifest { path: run.manifest_path() };
+        let done = |path: &Path| manifest.is_done(path).unwrap();
+        assert!( done(&run.raw(3)));
+        assert!(!done(&run.raw(4)));
+        assert!(!done(&run.series()));
+        // The series was interrupted: only its temporary file exists
+        assert!(!run.series().exists());
+        assert!(Manifest::temp_path(&run.series()).exists());
+
+        let before: Vec<u64> = (1..=3).map(|n| inode(&run.raw(n))).collect();
+        let manifest = run.go(true, None)?;
+        // Completed files were not rewritten, which would have renamed new ones into place
+        let after: Vec<u64> = (1..=3).map(|n| inode(&run.raw(n))).collect();
+        assert_eq!(before, after);
+
+        let entries = manifest.entries()?;
+        assert_eq!(entries.len(), 6);
+        for path in run.planned() {
+            let entry = &entries[&path.to_string_lossy().into_owned()];
+            assert_eq!(entry.status, Status::Done, "{}", path.display());
+            assert_eq!(entry.checksum.as_ref(), Some(&checksum(&path)?));
+        }
+        assert_eq!(hdf5::File::open(run.series())?.group("images")?.member_names()?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_not_matching_their_checksums_are_redone() -> Result<(), Box<dyn Error>> {
+        let run = Run::new();
+        run.go(false, None)?;
+        let original = std::fs::read(run.raw(2))?;
+        std::fs::write(run.raw(2), b"corrupted")?;
+        let untouched = inode(&run.raw(1));
+
+        let manifest = run.go(true, None)?;
+        assert_eq!(std::fs::read(run.raw(2))?, original);
+        assert_eq!(inode(&run.raw(1)), untouched);
+        assert!(manifest.entries()?.values().all(|entry| entry.status == Status::Done));
+        Ok(())
+    }
+
+    #[test]
+    fn without_resume_everything_is_redone() -> Result<(), Box<dyn Error>> {
+        let run = Run::new();
+        run.go(false, None)?;
+        let before = inode(&run.raw(1));
+        let manifest = Manifest::start(run.manifest_path(), run.planned(), false)?;
+        assert!(manifest.entries()?.values().all(|entry| entry.status == Status::Pending));
+        run.go(false, None)?;
+        assert_ne!(inode(&run.raw(1)), before);
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Sinograms: LORs binned by their distance from the z-axis (`r`), transverse
+//! direction (`phi`), axial position (`z`) and obliqueness (`dz`), as consumed
+//! by many PET tools which do not read LOR lists.
+//!
+//! The coordinates are those binned by the scattergram axes (`lorogram::axis_r`,
+//! `axis_phi`, `axis_z` and `axis_dz`), and follow their conventions for values
+//! on bin edges. `r` is never negative, while `phi` covers a whole turn,
+//! distinguishing the sides of the z-axis: together they are equivalent to the
+//! signed `r` and half-turn `phi` of the classic sinogram. `z` is that of the
+//! midpoint of the LOR, and `dz` the difference between the `z` of its
+//! endpoints, regardless of their order.
+//!
+//! When written to HDF5, the file holds
+//!
+//! + `counts`: the counts, indexed by (r, phi, z, dz)
+//! + `r_edges_mm`, `phi_edges_rad`, `z_edges_mm`, `dz_edges_mm`: the edges of
+//!   the bins along each axis, one more than the bins
+//! + `outside`: the number of LORs beyond the binned range of `r`, `z` or `dz`
+
+use ndarray::Array4;
+use ndhistogram::axis::Axis;
+
+use crate::Length;
+use crate::lorogram::{try_axis_dz, try_axis_phi, try_axis_r, try_axis_z, LorAxC, LorAxU};
+use crate::lorogram::axis::AxisError;
+#[cfg(feature = "hdf5")]
+use crate::io::hdf5::{Compression, Writer};
+use crate::system_matrix::LOR;
+use geometry::units::mm_;
+
+/// Number of bins along each axis of a `Sinogram`, and their range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SinogramBins {
+    pub n_r: usize,
+    pub r_max: Length,
+    /// Over a whole turn
+    pub n_phi: usize,
+    pub n_z: usize,
+    pub z_min: Length,
+    pub z_max: Length,
+    pub n_dz: usize,
+    pub dz_max: Length,
+}
+
+impl SinogramBins {
+    /// Edges of the bins along r, phi, z and dz, in mm and radians
+    pub fn edges(&self) -> [Vec<f32>; 4] {
+        let edges = |n: usize, low: f32, high: f32| -> Vec<f32> {
+            (0..=n).map(|i| if i == n { high } else { low + i as f32 * (high - low) / n as f32 }).collect()
+        };
+        [edges(self.n_r  , 0.0              , mm_(self.r_max )),
+         edges(self.n_phi, 0.0              , std::f32::consts::TAU),
+         edges(self.n_z  , mm_(self.z_min)  , mm_(self.z_max )),
+         edges(self.n_dz , 0.0              , mm_(self.dz_max))]
+    }
+}
+
+pub struct Sinogram {
+    pub bins: SinogramBins,
+    /// Counts, indexed by (r, phi, z, dz)
+    pub counts: Array4<u32>,
+    /// LORs beyond the range of `r`, `z` or `dz`, which are not counted
+    pub outside: usize,
+    axes: (LorAxU, LorAxC, LorAxU, LorAxU),
+}
+
+impl Sinogram {
+    pub fn new(bins: SinogramBins) -> Result<Self, AxisError> {
+        let axes = (
+            try_axis_r  (bins.n_r, bins.r_max)?,
+            try_axis_phi(bins.n_phi)?,
+            try_axis_z  (bins.n_z, bins.z_min, bins.z_max)?,
+            try_axis_dz (bins.n_dz, bins.dz_max)?,
+        );
+        let counts = Array4::zeros((bins.n_r, bins.n_phi, bins.n_z, bins.n_dz));
+        Ok(Self { bins, counts, outside: 0, axes })
+    }
+
+    /// Bin every one of `lors`
+    pub fn from_lors<'l>(bins: SinogramBins, lors: impl IntoIterator<Item = &'l LOR>) -> Result<Self, AxisError> {
+        let mut sinogram = Self::new(bins)?;
+        for lor in lors { sinogram.fill(lor) }
+        Ok(sinogram)
+    }
+
+    /// Indices (r, phi, z, dz) of the bin containing `lor`; `None` if it is
+    /// beyond the range of any axis
+    pub fn index(&self, lor: &LOR) -> Option<[usize; 4]> {
+        let (r, phi, z, dz) = &self.axes;
+        // Skipping the underflow and overflow bins, which come first and last
+        let within = |axis: &LorAxU| {
+            let i = axis.index(lor)?;
+            (i > 0 && i < axis.num_bins() - 1).then(|| i - 1)
+        };
+        Some([within(r)?, phi.index(lor)?, within(z)?, within(dz)?])
+    }
+
+    pub fn fill(&mut self, lor: &LOR) {
+        match self.index(lor) {
+            Some(index) => self.counts[index] += 1,
+            None        => self.outside += 1,
+        }
+    }
+
+    /// Number of LORs counted in the bins
+    pub fn total(&self) -> u64 { self.counts.iter().map(|&c| c as u64).sum() }
+
+    /// Write the counts, with `compression`, and the bin edges to a new HDF5
+    /// file at `path`, as described in the module documentation
+    #[cfg(feature = "hdf5")]
+    pub fn write_hdf5(&self, path: &std::path::Path, compression: Compression) -> Result<(), Box<dyn std::error::Error>> {
+        let writer = Writer::create(path)?.compression(compression);
+        writer.write("counts", self.counts.view())?;
+        let file = writer.file();
+        let [r, phi, z, dz] = self.bins.edges();
+        file.new_dataset_builder().with_data(&r  ).create("r_edges_mm")?;
+        file.new_dataset_builder().with_data(&phi).create("phi_edges_rad")?;
+        file.new_dataset_builder().with_data(&z  ).create("z_edges_mm")?;
+        file.new_dataset_builder().with_data(&dz ).create("dz_edges_mm")?;
+        file.new_dataset_builder().with_data(&[self.outside as u64]).create("outside")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_sinogram {
+    use super::*;
+    use crate::lorogram::mk_lor;
+    use geometry::units::mm;
+
+    fn bins() -> SinogramBins {
+        SinogramBins { n_r: 4, r_max: mm(100.0), n_phi: 4, n_z: 2, z_min: mm(-50.0), z_max: mm(50.0), n_dz: 2, dz_max: mm(100.0) }
+    }
+
+    #[test]
+    fn lors_land_in_the_expected_bins() {
+        // Transverse, 10 mm above the axis, in the direction of x
+        let a = mk_lor(((-200.0,  10.0,   0.0), (200.0,  10.0,   0.0)));
+        // Transverse, about 55 mm below the axis, heading slightly above x
+        let b = mk_lor(((-200.0, -60.0, -20.0), (200.0, -50.0, -20.0)));
+        // Oblique, about 35 mm from the axis, heading slightly behind y
+        let c = mk_lor(((  40.0,-200.0, -10.0), ( 30.0, 200.0,  70.0)));
+        // Too far from the axis, and beyond the end of the z range
+        let far  = mk_lor(((-200.0, 150.0,  0.0), (200.0, 150.0,  0.0)));
+        let high = mk_lor(((-200.0,  10.0, 60.0), (200.0,  10.0, 60.0)));
+        let c_reversed = LOR { p1: c.p2, p2: c.p1, ..c };
+        let lors = [a, b, c, c_reversed, far, high];
+
+        let sinogram = Sinogram::from_lors(bins(), &lors).unwrap();
+        assert_eq!(sinogram.index(&a), Some([0, 0, 1, 0]));
+        assert_eq!(sinogram.index(&b), Some([2, 2, 0, 0]));
+        assert_eq!(sinogram.index(&c), Some([1, 3, 1, 1]));
+        assert_eq!(sinogram.index(&far ), None);
+        assert_eq!(sinogram.index(&high), None);
+        assert_eq!(sinogram.counts[[0, 0, 1, 0]], 1);
+        assert_eq!(sinogram.counts[[2, 2, 0, 0]], 1);
+        assert_eq!(sinogram.counts[[1, 3, 1, 1]], 2);
+        assert_eq!((sinogram.total(), sinogram.outside), (4, 2));
+    }
+
+    #[test]
+    fn edges_span_the_binned_ranges() {
+        let [r, phi, z, dz] = bins().edges();
+        assert_eq!(r, vec![0.0, 25.0, 50.0, 75.0, 100.0]);
+        assert_eq!((phi.len(), phi[0], phi[4]), (5, 0.0, std::f32::consts::TAU));
+        assert_eq!(z, vec![-50.0, 0.0, 50.0]);
+        assert_eq!(dz, vec![0.0, 50.0, 100.0]);
+    }
+
+    #[test]
+    fn invalid_binning_is_rejected() {
+        assert!(Sinogram::new(SinogramBins { n_r: 0, ..bins() }).is_err());
+        assert!(Sinogram::new(SinogramBins { z_min: mm(50.0), z_max: mm(-50.0), ..bins() }).is_err());
+    }
+}
@@ -14,13 +14,13 @@
 //!    coordinate system.
 
 use geometry::in_base_unit;
-use crate::{Index3Weightf32, Lengthf32};
+use crate::{memory, Chargef32, Energyf32, Index1_u, Index3Weightf32, Lengthf32};
 use crate::{Length, PerLength, Time, C,
             Point, Vector, Ratio, RatioPoint, RatioVec};
 use crate::fov::FOV;
 
-use geometry::units::{mm, mm_, ns_, ratio_};
-use crate::gauss::make_gauss_option;
+use geometry::units::{mm, mm_, ps_, ratio_};
+use crate::gauss::{make_gauss_option, TofCutoff};
 use crate::index::index1_to_3;
 
 // ------------------------------ TESTS ------------------------------
@@ -33,7 +33,6 @@ mod test {
     #[allow(unused)] use pretty_assertions::{assert_eq, assert_ne};
     use rstest::rstest;
     use crate::TWOPI;
-    use geometry::units::ratio;
 
     // --------------------------------------------------------------------------------
     // This set of hand-picked values should be easy to verify by humans. The
@@ -68,15 +67,14 @@ mod test {
 
         let p1 = Point::new(p1.0, p1.1, mm(0.0));
         let p2 = Point::new(p2.0, p2.1, mm(0.0));
-        let fov = FOV::new((mm(size.0), mm(size.1), mm(1.0)), (n.0, n.1, 1));
+        let fov = FOV::new_from_full_widths((mm(size.0), mm(size.1), mm(1.0)), (n.0, n.1, 1));
 
         // Values to plug in to visualizer:
-        let lor = LOR::new(Time::ZERO, Time::ZERO, p1, p2, ratio(1.0));
-        let command = crate::visualize::vislor_command(&fov, &lor);
-        println!("\nTo visualize this case, run:\n{}\n", command);
+        #[cfg(feature = "vis")]
+        println!("\nTo visualize this case, run:\n{}\n", crate::visualize::vislor_command(&fov, &LOR::new(Time::ZERO, Time::ZERO, p1, p2)));
 
         // Collect hits
-        let hits: Vec<Index3Weightf32> = LOR::new(Time::ZERO, Time::ZERO, p1, p2, ratio(1.0)).active_voxels(&fov, None, None);
+        let hits: Vec<Index3Weightf32> = LOR::new(Time::ZERO, Time::ZERO, p1, p2).active_voxels(&fov, None, None);
 
         // Diagnostic output
         for (is, l) in &hits { println!("  ({} {})   {}", is[0], is[1], l) }
@@ -121,14 +119,13 @@ mod test {
             let p2_theta = p1_theta + (p2_delta * TWOPI);
             let p1 = Point::new(r * p1_theta.cos(), r * p1_theta.sin(), p1_z);
             let p2 = Point::new(r * p2_theta.cos(), r * p2_theta.sin(), p2_z);
-            let fov = FOV::new((mm(dx), mm(dy), mm(dz)), (nx, ny, nz));
+            let fov = FOV::new_from_full_widths((mm(dx), mm(dy), mm(dz)), (nx, ny, nz));
 
             // Values to plug in to visualizer:
-            let lor = LOR::new(Time::ZERO, Time::ZERO, p1, p2, ratio(1.0));
-            let command = crate::visualize::vislor_command(&fov, &lor);
-            println!("\nTo visualize this case, run:\n{}\n", command);
+            #[cfg(feature = "vis")]
+            println!("\nTo visualize this case, run:\n{}\n", crate::visualize::vislor_command(&fov, &LOR::new(Time::ZERO, Time::ZERO, p1, p2)));
 
-            let summed: Lengthf32 = LOR::new(Time::ZERO, Time::ZERO, p1, p2, ratio(1.0))
+            let summed: Lengthf32 = LOR::new(Time::ZERO, Time::ZERO, p1, p2)
                 .active_voxels(&fov, None, None)
                 .into_iter()
                 .inspect(|(i, l)| println!("  ({} {} {}) {}", i[0], i[1], i[2], l))
@@ -156,56 +153,159 @@ mod test {
 /// return values, because this function is called in the inner loop, and
 /// allocating the vectors of results repeatedly, had a noticeable impact on
 /// performance.
+///
+/// LORs parallel to the transverse plane are traversed in 2D (see
+/// `WeightsAlongLor::planar`), with identical results.
+///
+/// Returns the length of the chord of the LOR through the FOV: the sum of the
+/// weights, before any TOF adjustment.
 #[inline]
 #[allow(clippy::too_many_arguments)]
 pub fn system_matrix_elements(
     indices: &mut Vec<usize>,
     weights: &mut Vec<Lengthf32>,
-    mut next_boundary: Vector,
+    next_boundary: Vector,
     voxel_size: Vector,
-    mut index: i32,
+    index: i32,
     delta_index: [i32; 3],
-    mut remaining: [i32; 3],
+    remaining: [i32; 3],
     tof_peak: Length,
-    tof: &Option<impl Fn(Length) -> PerLength>) {
+    end: Length,
+    tof: &Option<impl Fn(Length) -> PerLength>) -> Lengthf32 {
 
-    // How far we have moved since entering the FOV
-    let mut here = Length::ZERO;
+    let lor = WeightsAlongLor3 {
+        next_boundary: [0, 1, 2].map(|d| mm_(next_boundary[d])),
+        voxel_size   : [0, 1, 2].map(|d| mm_(   voxel_size[d])),
+        index, delta_index, remaining, tof_peak,
+        end: mm_(end),
+    };
+    match lor.planar() {
+        Some(planar) => planar.weights(indices, weights, tof),
+        None         =>    lor.weights(indices, weights, tof),
+    }
+}
 
-    loop {
-        // Which voxel boundary will be hit next, and its position
-        let (dimension, boundary_position) = next_boundary.argmin();
+/// Where a LOR stands in its traversal of a `D`-dimensional grid of voxels,
+/// with all distances (mm) measured along the LOR from its entry into the FOV.
+/// The fields are those of `fov::FovHit`, restricted to `D` dimensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightsAlongLor<const D: usize> {
+    pub next_boundary: [Lengthf32; D],
+    pub voxel_size   : [Lengthf32; D],
+    pub index        : i32,
+    pub delta_index  : [i32; D],
+    pub remaining    : [i32; D],
+    pub tof_peak     : Length,
+    pub end          : Lengthf32,
+}
 
-        // The weight is the length of LOR in this voxel
-        let mut weight = boundary_position - here;
+pub type WeightsAlongLor2 = WeightsAlongLor<2>;
+pub type WeightsAlongLor3 = WeightsAlongLor<3>;
 
-        // If TOF enabled, adjust weight
-        if let Some(gauss) = &tof {
-            let g: PerLength = gauss(here - tof_peak);
-            // TODO Normalization
-            let completely_arbitrary_factor = 666.0;
-            let g: f32 = ratio_(mm(completely_arbitrary_factor) * g);
-            weight *= g;
+impl From<crate::fov::FovHit> for WeightsAlongLor3 {
+    fn from(hit: crate::fov::FovHit) -> Self {
+        let crate::fov::FovHit { next_boundary, voxel_size, index, delta_index, remaining, tof_peak, end } = hit;
+        Self {
+            next_boundary: [0, 1, 2].map(|d| mm_(next_boundary[d])),
+            voxel_size   : [0, 1, 2].map(|d| mm_(   voxel_size[d])),
+            index, delta_index, remaining, tof_peak,
+            end: mm_(end),
         }
+    }
+}
 
-        // Store the index and weight of the voxel we have just crossed
-        if weight > Length::ZERO {
-            indices.push(index as usize);
-            weights.push(mm_(weight));
+impl WeightsAlongLor3 {
+    /// The same traversal without the z dimension, if the LOR never crosses a
+    /// z boundary because it is parallel to the transverse plane
+    pub fn planar(&self) -> Option<WeightsAlongLor2> {
+        if self.voxel_size[2] != Lengthf32::INFINITY { return None }
+        let [x, y, _] = self.next_boundary;
+        let [vx, vy, _] = self.voxel_size;
+        let [dx, dy, _] = self.delta_index;
+        let [rx, ry, _] = self.remaining;
+        Some(WeightsAlongLor2 {
+            next_boundary: [x, y], voxel_size: [vx, vy], index: self.index,
+            delta_index: [dx, dy], remaining: [rx, ry], tof_peak: self.tof_peak,
+            end: self.end,
+        })
+    }
+}
+
+impl<const D: usize> WeightsAlongLor<D> {
+    /// Bounds on the number of voxels `weights` appends, in the manner of
+    /// `Iterator::size_hint`. Every voxel boundary crossed uses up one of the
+    /// `remaining` voxels along some axis, and the traversal stops as soon as
+    /// any axis runs out. The exact count is not known in advance: voxels
+    /// whose weight vanishes, or falls outside the TOF window, are skipped.
+    pub fn size_hint(&self) -> (usize, Option<usize>) {
+        let crossings: usize = self.remaining.iter().map(|&r| r.max(1) as usize - 1).sum();
+        (0, Some(crossings + 1))
+    }
+
+    /// Append the indices and weights of the voxels crossed by the LOR to
+    /// `indices` and `weights`, as described in `system_matrix_elements`
+    #[inline]
+    pub fn weights(self,
+                   indices: &mut Vec<usize>,
+                   weights: &mut Vec<Lengthf32>,
+                   tof: &Option<impl Fn(Length) -> PerLength>) -> Lengthf32 {
+        let Self { mut next_boundary, voxel_size, mut index, delta_index, mut remaining, tof_peak, end } = self;
+
+        // Grow the outputs once, rather than repeatedly while pushing
+        if let (_, Some(most)) = self.size_hint() {
+            indices.reserve(most);
+            weights.reserve(most);
         }
 
-        // Move along LOR until it leaves this voxel
-        here = boundary_position;
+        // How far we have moved since entering the FOV
+        let mut here = 0.0;
+        let mut chord = 0.0;
+
+        loop {
+            // Which voxel boundary will be hit next, and its position
+            let mut dimension = 0;
+            for d in 1..D {
+                if next_boundary[d] < next_boundary[dimension] { dimension = d }
+            }
+            let boundary_position = next_boundary[dimension];
+
+            // The weight is the length of LOR in this voxel, which is cut
+            // short if the LOR ends inside it
+            let mut weight = boundary_position.min(end) - here;
+            if weight > 0.0 { chord += weight }
+
+            // If TOF enabled, adjust weight
+            if let Some(gauss) = &tof {
+                let g: PerLength = gauss(mm(here) - tof_peak);
+                // TODO Normalization
+                let completely_arbitrary_factor = 666.0;
+                let g: f32 = ratio_(mm(completely_arbitrary_factor) * g);
+                weight *= g;
+            }
+
+            // Store the index and weight of the voxel we have just crossed
+            if weight > 0.0 {
+                indices.push(index as usize);
+                weights.push(weight);
+            }
+
+            // A LOR which ends inside the FOV stops in this voxel
+            if boundary_position >= end { break; }
 
-        // Find the next boundary in this dimension
-        next_boundary[dimension] += voxel_size[dimension];
+            // Move along LOR until it leaves this voxel
+            here = boundary_position;
 
-        // Move index across the boundary we are crossing
-        index += delta_index[dimension];
-        remaining[dimension] -= 1;
+            // Find the next boundary in this dimension
+            next_boundary[dimension] += voxel_size[dimension];
 
-        // If we have traversed the whole FOV, we're finished
-        if remaining[dimension] == 0 { break; }
+            // Move index across the boundary we are crossing
+            index += delta_index[dimension];
+            remaining[dimension] -= 1;
+
+            // If we have traversed the whole FOV, we're finished
+            if remaining[dimension] == 0 { break; }
+        }
+        chord
     }
 }
 
@@ -233,7 +333,8 @@ pub fn find_entry_point(entry_point: Point, fov: FOV) -> RatioPoint {
 }
 
 
-/// Distance from entry point to the LOR's TOF peak
+/// Distance from entry point to the LOR's TOF peak. The peak does not move if
+/// the endpoints are exchanged and the sign of `dt` is flipped (`LOR::swapped`).
 #[inline]
 pub fn find_tof_peak(entry_point: Point, p1: Point, p2: Point, dt: Time) -> Length {
     let half_lor_length = (p1 - p2).norm() / 2.0;
@@ -276,40 +377,170 @@ pub struct LOR {
     pub p1: Point,
     pub p2: Point,
     pub dt: Time,
-    /// Scatter and random corrections, which appear as an additive contribution
-    /// to the sinogram bin in the MLEM forward projection. In order to make it
-    /// compatible with a single LOR (rather than many LORs in a sinogram bin)
-    /// it is expressed here as a *multiplicative* factor.
-    pub additive_correction: Ratio,
+    pub corrections: Corrections,
+}
+
+/// Per-LOR terms of the MLEM forward model.
+///
+/// The expected number of counts in a LOR is
+///
+/// `multiplicative * scatter * Σ_j a_ij x_j + additive`
+///
+/// where `a_ij` are the system matrix elements of the LOR and `x_j` the
+/// current image. The identity, `Corrections::NONE`, reproduces the
+/// uncorrected reconstruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Corrections {
+    /// Normalization and attenuation: scales the system matrix elements in
+    /// both the forward and the back projection
+    pub multiplicative: Ratio,
+    /// Scatter and randoms expected in this LOR, in the units of the forward
+    /// projection
+    pub additive: f32,
+    /// Scatter and random contribution estimated relative to the trues, as
+    /// `(scatters + trues) / trues` (see `Scattergram::value`). Scales the
+    /// forward projection only.
+    pub scatter: Ratio,
+}
+
+impl Corrections {
+    pub const NONE: Self = Self {
+        multiplicative: Ratio { dimension: std::marker::PhantomData, units: std::marker::PhantomData, value: 1.0 },
+        additive      : 0.0,
+        scatter       : Ratio { dimension: std::marker::PhantomData, units: std::marker::PhantomData, value: 1.0 },
+    };
+
+    /// Corrections with the given multiplicative and additive terms, which
+    /// must be positive and finite, and non-negative and finite, respectively.
+    pub fn new(multiplicative: Ratio, additive: f32) -> Result<Self, String> {
+        let corrections = Self { multiplicative, additive, ..Self::NONE };
+        corrections.validate()?;
+        Ok(corrections)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let m = ratio_(self.multiplicative);
+        if !m.is_finite() || m <= 0.0 {
+            return Err(format!("Multiplicative correction must be positive and finite, got {m}"))
+        }
+        if !self.additive.is_finite() || self.additive < 0.0 {
+            return Err(format!("Additive correction must be non-negative and finite, got {}", self.additive))
+        }
+        let s = ratio_(self.scatter);
+        if s.is_nan() || s <= 0.0 {
+            return Err(format!("Scatter correction must be positive, got {s}"))
+        }
+        Ok(())
+    }
+
+    /// Expected counts in a LOR whose uncorrected forward projection is `projection`
+    pub fn forward(&self, projection: Lengthf32) -> Lengthf32 {
+        ratio_(self.multiplicative * self.scatter) * projection + self.additive
+    }
+}
+
+impl Default for Corrections {
+    fn default() -> Self { Self::NONE }
+}
+
+/// LORs whose endpoints are closer than this have no well-defined direction
+pub const DEGENERATE_TOLERANCE: Length = in_base_unit!(1e-3);
+
+/// What to do with degenerate LORs (see `LOR::is_degenerate`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DegeneratePolicy {
+    /// Discard them, counting how many were discarded
+    Drop,
+    /// Abort processing
+    Error,
+    /// Pass them on unchanged
+    Keep,
+}
+
+impl Default for DegeneratePolicy {
+    fn default() -> Self { Self::Drop }
+}
+
+impl DegeneratePolicy {
+    /// Whether `lor` should be kept, or an error if the policy forbids it
+    pub fn admit(self, lor: &LOR) -> Result<bool, String> {
+        if !lor.is_degenerate(DEGENERATE_TOLERANCE) { return Ok(true) }
+        match self {
+            Self::Drop  => Ok(false),
+            Self::Keep  => Ok(true),
+            Self::Error => {
+                let p = |p: Point| (mm_(p.x), mm_(p.y), mm_(p.z));
+                Err(format!("Degenerate LOR: endpoints {:?} and {:?} (mm) coincide", p(lor.p1), p(lor.p2)))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for DegeneratePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop"  => Ok(Self::Drop),
+            "error" => Ok(Self::Error),
+            "keep"  => Ok(Self::Keep),
+            _ => Err(format!("Unknown degenerate LOR policy '{s}': use drop, error or keep")),
+        }
+    }
 }
 
 impl LOR {
-    pub fn new(t1: Time, t2: Time, p1: Point, p2: Point, additive_correction: Ratio) -> Self {
-        Self { p1, p2, dt: t2 - t1, additive_correction }
+    pub fn new(t1: Time, t2: Time, p1: Point, p2: Point) -> Self {
+        Self { p1, p2, dt: t2 - t1, corrections: Corrections::NONE }
+    }
+
+    /// Whether the endpoints lie within `tolerance` of each other, leaving the
+    /// LOR without a well-defined direction
+    pub fn is_degenerate(&self, tolerance: Length) -> bool {
+        (self.p2 - self.p1).norm() <= tolerance
+    }
+
+    /// The same LOR with its endpoints exchanged, and the sign of `dt` flipped
+    /// to match, so that the TOF peak stays in place
+    pub fn swapped(self) -> Self {
+        Self { p1: self.p2, p2: self.p1, dt: -self.dt, ..self }
+    }
+
+    /// Whether the endpoints are in the order given by `endpoint_order`
+    pub fn is_canonical(&self) -> bool {
+        let (p, q) = (self.p1, self.p2);
+        canonical(endpoint_order((mm_(p.x), mm_(p.y), mm_(p.z)), (mm_(q.x), mm_(q.y), mm_(q.z))), ps_(self.dt))
+    }
+
+    /// The same LOR, with its endpoints in canonical order
+    pub fn canonicalized(self) -> Self {
+        if self.is_canonical() { self } else { self.swapped() }
     }
 
     pub fn from_components((t1, t2): (Time, Time),
                            (x1, y1, z1): (Length, Length, Length),
                            (x2, y2, z2): (Length, Length, Length),
-                           additive_correction: Ratio
                           ) -> Self
     {
-        Self::new(t1, t2, Point::new(x1,y1,z1), Point::new(x2,y2,z2), additive_correction)
+        Self::new(t1, t2, Point::new(x1,y1,z1), Point::new(x2,y2,z2))
+    }
+
+    pub fn with_corrections(self, corrections: Corrections) -> Self {
+        Self { corrections, ..self }
     }
 
-    pub fn active_voxels(&self, fov: &FOV, cutoff: Option<Ratio>, sigma: Option<Time>) -> Vec<Index3Weightf32> {
+    pub fn active_voxels(&self, fov: &FOV, cutoff: Option<TofCutoff>, sigma: Option<Time>) -> Vec<Index3Weightf32> {
         use crate::fov::{lor_fov_hit, FovHit};
         let tof = make_gauss_option(sigma, cutoff);
         let mut weights = vec![];
         let mut indices = vec![];
         match lor_fov_hit(self, *fov) {
             None => (),
-            Some(FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak}) => {
+            Some(FovHit {next_boundary, voxel_size, index, delta_index, remaining, tof_peak, end}) => {
                 system_matrix_elements(
                     &mut indices, &mut weights,
                     next_boundary, voxel_size,
                     index, delta_index, remaining,
-                    tof_peak, &tof
+                    tof_peak, end, &tof
                 );
 
             }
@@ -321,15 +552,575 @@ impl LOR {
     }
 }
 
+/// A `LOR` with the energies (keV) and charges of its gammas, which select and
+/// classify coincidences but play no part in MLEM. The `LOR` is stored whole,
+/// so it can be borrowed (`as_ref`) or moved out (`LOR::from`) without
+/// conversion.
+#[derive(Clone, Copy, Debug)]
+pub struct LorWithMeta {
+    pub lor: LOR,
+    pub e1: Energyf32,
+    pub e2: Energyf32,
+    pub q1: Chargef32,
+    pub q2: Chargef32,
+}
+
+impl LorWithMeta {
+    /// `lor` with the energies of its gammas, and unknown (NaN) charges
+    pub fn with_energies(lor: LOR, e1: Energyf32, e2: Energyf32) -> Self {
+        Self { lor, e1, e2, q1: f32::NAN, q2: f32::NAN }
+    }
+}
+
+impl From<LorWithMeta> for LOR {
+    fn from(meta: LorWithMeta) -> Self { meta.lor }
+}
+
+impl AsRef<LOR> for LorWithMeta {
+    fn as_ref(&self) -> &LOR { &self.lor }
+}
+
+/// Order of two LOR endpoints, with coordinates in mm: by `z`, then by azimuth
+/// `atan2(y, x)`, then by `x`, then by `y`. Endpoints which are `Less` come
+/// first in canonical order. Incomparable (NaN) coordinates count as equal.
+///
+/// Input files order the endpoints arbitrarily. `io::hdf5::Args` can put them
+/// in this order on reading, after which downstream code may rely on it: `p1`
+/// is then the endpoint with the smaller `z`.
+pub fn endpoint_order((x1, y1, z1): (f32, f32, f32), (x2, y2, z2): (f32, f32, f32)) -> std::cmp::Ordering {
+    use std::cmp::Ordering::Equal;
+    let cmp = |a: f32, b: f32| a.partial_cmp(&b).unwrap_or(Equal);
+    cmp(z1, z2)
+        .then_with(|| cmp(y1.atan2(x1), y2.atan2(x2)))
+        .then_with(|| cmp(x1, x2))
+        .then_with(|| cmp(y1, y2))
+}
+
+/// Whether endpoints of relative `order` and time difference `dt` (of either
+/// unit) are in canonical order. Coincident endpoints are ordered by `dt >= 0`.
+pub(crate) fn canonical(order: std::cmp::Ordering, dt: f32) -> bool {
+    use std::cmp::Ordering::*;
+    match order {
+        Less    => true,
+        Greater => false,
+        Equal   => dt >= 0.0 || dt.is_nan(),
+    }
+}
+
 use core::fmt;
+/// `LOR (-300, 0, 0) -> (300, 0, 0) mm, dt 200 ps`, which `utils::parse_lor`
+/// reads back exactly. Corrections are not shown.
 impl fmt::Display for LOR {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let (p, q) = (self.p1, self.p2);
-        write!(f, "<LOR ({:8.2} {:8.2} {:8.2}) ({:8.2} {:8.2} {:8.2}) {:7.2}ns {:7.2}mm /{:7.2} >",
+        write!(f, "LOR ({}, {}, {}) -> ({}, {}, {}) mm, dt {} ps",
                mm_(p.x), mm_(p.y), mm_(p.z),
                mm_(q.x), mm_(q.y), mm_(q.z),
-               ns_(self.dt), mm_(self.dt * C) / 2.0,
-               mm_((p-q).norm())
+               ps_(self.dt),
         )
     }
 }
+
+//--------------------------------------------------------------------------------
+/// Tube-of-response model of a LOR: `samples` lines parallel to the LOR, spread
+/// over a disc of `radius` perpendicular to it, whose system matrix elements
+/// are averaged. A single sample is the usual, infinitely thin, LOR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tube {
+    pub radius: Length,
+    pub samples: usize,
+    /// Divide the weights of each (sub-)LOR by the length of its chord through
+    /// the FOV, so that every thin LOR hitting the FOV has total weight 1
+    pub normalize_chord: bool,
+}
+
+impl Tube {
+    /// Offsets of the sub-LORs from the central LOR, in units of `radius`,
+    /// along two axes perpendicular to the LOR. Deterministic and stratified
+    /// (a Vogel spiral): each sample represents an equal area of the disc.
+    pub fn offsets(&self) -> Vec<(f32, f32)> {
+        if self.samples <= 1 { return vec![(0.0, 0.0)] }
+        let golden_angle = std::f32::consts::PI * (3.0 - 5.0_f32.sqrt());
+        let n = self.samples as f32;
+        (0..self.samples).map(|k| {
+            let r = ((k as f32 + 0.5) / n).sqrt();
+            let theta = k as f32 * golden_angle;
+            (r * theta.cos(), r * theta.sin())
+        }).collect()
+    }
+
+    /// LORs parallel to `lor`, displaced according to `offsets`. Empty if `lor`
+    /// has no direction: coincident or non-finite endpoints.
+    pub fn sub_lors(&self, lor: &LOR) -> Vec<LOR> {
+        if self.samples <= 1 { return vec![*lor] }
+        let (u, v) = match perpendicular_basis(lor.p2 - lor.p1) {
+            Some(basis) => basis,
+            None        => return vec![],
+        };
+        let r = mm_(self.radius);
+        self.offsets().into_iter().map(|(a, b)| {
+            let [dx, dy, dz] = [0, 1, 2].map(|i| mm(r * (a * u[i] + b * v[i])));
+            let shift = Vector::new(dx, dy, dz);
+            LOR { p1: lor.p1 + shift, p2: lor.p2 + shift, ..*lor }
+        }).collect()
+    }
+}
+
+/// Two unit vectors perpendicular to `direction` and to each other. Robust for
+/// any direction, including ones parallel to the coordinate axes. None if
+/// `direction` has zero length or non-finite components.
+pub fn perpendicular_basis(direction: Vector) -> Option<([f32; 3], [f32; 3])> {
+    let d = [mm_(direction.x), mm_(direction.y), mm_(direction.z)];
+    let norm = |a: [f32; 3]| (a[0]*a[0] + a[1]*a[1] + a[2]*a[2]).sqrt();
+    let unit = |a: [f32; 3]| { let n = norm(a); a.map(|c| c / n) };
+    let cross = |a: [f32; 3], b: [f32; 3]| [a[1]*b[2] - a[2]*b[1],
+                                             a[2]*b[0] - a[0]*b[2],
+                                             a[0]*b[1] - a[1]*b[0]];
+    let length = norm(d);
+    if !length.is_finite() || length == 0.0 { return None }
+    let d = unit(d);
+    // Cross with the axis least aligned with the direction, which cannot be
+    // (nearly) parallel to it
+    let smallest = (0..3).min_by(|&i, &j| d[i].abs().total_cmp(&d[j].abs())).unwrap();
+    let mut axis = [0.0; 3];
+    axis[smallest] = 1.0;
+    let u = unit(cross(d, axis));
+    let v = cross(d, u);
+    Some((u, v))
+}
+
+//--------------------------------------------------------------------------------
+/// Where the projector gets the system matrix rows of the LORs it projects:
+/// computed afresh each time (`OnTheFly`), memoized by geometry
+/// (`geometry_cache::GeometryCache`) or precomputed for a fixed list of LORs
+/// (`SystemMatrix`).
+pub trait RowSource: Sync {
+    /// Replace the contents of `indices` and `weights` with the row of `lor`,
+    /// which is the `i`th of the LORs being reconstructed. Rows which are not
+    /// stored come from `compute`, which has the signature and meaning of
+    /// `mlem::system_matrix_row`. `with_dt` must be set when the row depends
+    /// on dt, as it does with TOF. Returns `false` if the LOR misses the FOV.
+    fn row(&self, i: usize, lor: &LOR, with_dt: bool,
+           indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+           compute: &mut dyn FnMut(&mut Vec<Index1_u>, &mut Vec<Lengthf32>) -> bool,
+    ) -> bool;
+}
+
+/// Every row is computed when it is needed
+pub struct OnTheFly;
+
+impl RowSource for OnTheFly {
+    fn row(&self, _: usize, _: &LOR, _: bool,
+           indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+           compute: &mut dyn FnMut(&mut Vec<Index1_u>, &mut Vec<Lengthf32>) -> bool,
+    ) -> bool {
+        compute(indices, weights)
+    }
+}
+
+/// The voxels crossed by a LOR, and the system matrix elements (weights) of
+/// each
+#[derive(Clone, Debug, PartialEq)]
+pub struct SystemMatrixRow {
+    pub indices: Box<[Index1_u]>,
+    pub weights: Box<[Lengthf32]>,
+}
+
+/// Number of LORs whose rows are computed in parallel while filling a
+/// `SystemMatrix`, bounding how far the computation overshoots its memory cap
+const ROW_BLOCK: usize = 1 << 16;
+
+/// The rows of the system matrix of a fixed list of LORs, computed once and
+/// reused in every iteration. Worthwhile for small FOVs, whose rows are short,
+/// and for LOR lists which are reconstructed repeatedly.
+///
+/// Rows are stored in the order of the LORs until they reach a memory cap;
+/// those of later LORs are computed on the fly whenever they are projected.
+/// A `SystemMatrix` is only valid for the LORs, FOV, TOF and tube settings with
+/// which it was made.
+pub struct SystemMatrix {
+    /// The rows of the first LORs, `None` for those which miss the FOV
+    rows: Vec<Option<SystemMatrixRow>>,
+    bytes: usize,
+}
+
+impl SystemMatrix {
+    /// Precompute the rows of `lors` until they take up `max_bytes`
+    pub fn new(lors: &[LOR], fov: FOV, sigma: Option<Time>, cutoff: Option<TofCutoff>, tube: Option<Tube>, max_bytes: usize) -> Self {
+        use rayon::prelude::*;
+        let tof = make_gauss_option(sigma, cutoff);
+        let (mut rows, mut bytes) = (vec![], 0);
+        'blocks: for block in lors.chunks(ROW_BLOCK) {
+            let block: Vec<_> = block.par_iter()
+                .map_init(|| (vec![], vec![]), |(indices, weights), lor| {
+                    crate::mlem::system_matrix_row(lor, fov, &tof, tube, indices, weights)
+                        .then(|| SystemMatrixRow { indices: indices.as_slice().into(), weights: weights.as_slice().into() })
+                })
+                .collect();
+            for row in block {
+                let size = row_size(&row);
+                if bytes + size > max_bytes { break 'blocks }
+                bytes += size;
+                rows.push(row);
+            }
+        }
+        rows.shrink_to_fit();
+        memory::allocated("system_matrix", bytes);
+        Self { rows, bytes }
+    }
+
+    /// Number of LORs whose rows are stored
+    pub fn n_stored(&self) -> usize { self.rows.len() }
+
+    /// Memory taken by the stored rows
+    pub fn bytes(&self) -> usize { self.bytes }
+}
+
+impl RowSource for SystemMatrix {
+    fn row(&self, i: usize, _: &LOR, _: bool,
+           indices: &mut Vec<Index1_u>, weights: &mut Vec<Lengthf32>,
+           compute: &mut dyn FnMut(&mut Vec<Index1_u>, &mut Vec<Lengthf32>) -> bool,
+    ) -> bool {
+        let row = match self.rows.get(i) {
+            Some(row) => row,
+            None      => return compute(indices, weights),
+        };
+        indices.clear();
+        weights.clear();
+        match row {
+            Some(row) => { indices.extend_from_slice(&row.indices); weights.extend_from_slice(&row.weights); true },
+            None      => false,
+        }
+    }
+}
+
+impl Drop for SystemMatrix {
+    fn drop(&mut self) { memory::freed("system_matrix", self.bytes) }
+}
+
+/// Memory taken by `row`, including its slot in `SystemMatrix::rows`
+fn row_size(row: &Option<SystemMatrixRow>) -> usize {
+    std::mem::size_of_val(row) +
+        row.as_ref().map_or(0, |row| memory::size_of_slice(&row.indices) + memory::size_of_slice(&row.weights))
+}
+
+#[cfg(test)]
+mod test_weights_along_lor {
+    use super::*;
+    use crate::fov::lor_fov_hit;
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    /// Indices, weights and chord of `lor` in `fov`, traversed in 3D and, if
+    /// the LOR is planar, in 2D
+    fn both_ways(lor: &LOR, fov: FOV) -> ((Vec<usize>, Vec<Lengthf32>, Lengthf32), Option<(Vec<usize>, Vec<Lengthf32>, Lengthf32)>) {
+        let tof = make_gauss_option(None, None);
+        let traversal = WeightsAlongLor3::from(lor_fov_hit(lor, fov).unwrap());
+        let (mut indices, mut weights) = (vec![], vec![]);
+        let chord = traversal.weights(&mut indices, &mut weights, &tof);
+        assert!(indices.len() <= traversal.size_hint().1.unwrap());
+        let planar = traversal.planar().map(|planar| {
+            let (mut indices, mut weights) = (vec![], vec![]);
+            let chord = planar.weights(&mut indices, &mut weights, &tof);
+            assert!(indices.len() <= planar.size_hint().1.unwrap());
+            (indices, weights, chord)
+        });
+        ((indices, weights, chord), planar)
+    }
+
+    // The hand-picked cases of `test::hand_picked`
+    #[rstest(/**/      p1       ,      p2      ,    size     ,  n   ,
+             case((-30.0, -30.0), ( 30.0, 30.0), (10.0, 10.0), (3,3)),
+             case(( 30.0, -30.0), (-30.0, 30.0), (10.0, 10.0), (3,3)),
+             case((-30.0,  30.0), ( 30.0,-30.0), (10.0, 10.0), (3,3)),
+             case(( 30.0,  30.0), (-30.0,-30.0), (10.0, 10.0), (3,3)),
+             case((-30.0, -30.0), ( 30.0, 30.0), (10.0, 10.0), (3,2)),
+             case((-30.0, -30.0), ( 30.0, 30.0), (10.0, 10.0), (2,3)),
+             case((  5.4, -20.0), (  5.4, 10.0), (11.0,  9.0), (9,4)),
+             case((-15.0,  -4.0), ( 15.0, -4.0), ( 8.0, 10.0), (4,3)),
+    )]
+    fn two_d_matches_three_d(p1: (Lengthf32, Lengthf32), p2: (Lengthf32, Lengthf32),
+                             size: (Lengthf32, Lengthf32), n: (usize, usize)) {
+        let p1 = Point::new(mm(p1.0), mm(p1.1), mm(0.0));
+        let p2 = Point::new(mm(p2.0), mm(p2.1), mm(0.0));
+        let fov = FOV::new_from_full_widths((mm(size.0), mm(size.1), mm(1.0)), (n.0, n.1, 1));
+        let (three_d, two_d) = both_ways(&LOR::new(Time::ZERO, Time::ZERO, p1, p2), fov);
+        assert_eq!(two_d, Some(three_d));
+    }
+
+    #[test]
+    fn oblique_lors_stay_three_d() {
+        let fov = FOV::new_from_full_widths((mm(100.0), mm(100.0), mm(100.0)), (10, 10, 10));
+        let lor = LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-300.0), mm(10.0), mm(-20.0)),
+                                                   Point::new(mm( 300.0), mm(15.0), mm( 30.0)));
+        assert_eq!(both_ways(&lor, fov).1, None);
+    }
+
+    proptest! {
+        #[test]
+        fn planar_lors_are_traversed_identically(
+            r        in  200.0..(300.0 as Lengthf32),
+            p1_angle in 0.0..(1.0 as Lengthf32),
+            p2_delta in 0.1..(0.9 as Lengthf32),
+            z        in -40.0..(40.0 as Lengthf32),
+            nx in  5..50_usize,
+            ny in  5..50_usize,
+            nz in  1..10_usize,
+        ) {
+            let p1_theta = p1_angle * crate::TWOPI;
+            let p2_theta = p1_theta + (p2_delta * crate::TWOPI);
+            let p1 = Point::new(mm(r * p1_theta.cos()), mm(r * p1_theta.sin()), mm(z));
+            let p2 = Point::new(mm(r * p2_theta.cos()), mm(r * p2_theta.sin()), mm(z));
+            let fov = FOV::new_from_full_widths((mm(120.0), mm(130.0), mm(100.0)), (nx, ny, nz));
+            let lor = LOR::new(Time::ZERO, Time::ZERO, p1, p2);
+            if lor_fov_hit(&lor, fov).is_some() {
+                let (three_d, two_d) = both_ways(&lor, fov);
+                assert_eq!(two_d, Some(three_d));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_inside_endpoints {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Length of the segment `p1 p2` inside `fov`, found by clipping the
+    /// segment to the slab of the FOV along each axis in turn
+    fn length_inside(p1: Point, p2: Point, fov: FOV) -> Lengthf32 {
+        let (mut lo, mut hi) = (0.0, 1.0 as Lengthf32);
+        for d in 0..3 {
+            let (a, b, h) = (mm_(p1[d]), mm_(p2[d]), mm_(fov.half_width[d]));
+            if a == b {
+                if a.abs() > h { return 0.0 }
+                continue
+            }
+            let (t1, t2) = ((-h - a) / (b - a), (h - a) / (b - a));
+            lo = lo.max(t1.min(t2));
+            hi = hi.min(t1.max(t2));
+        }
+        if hi > lo { (hi - lo) * mm_((p2 - p1).norm()) } else { 0.0 }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum Inside { P1, P2, Both }
+
+    proptest! {
+        #[test]
+        fn lors_are_weighted_only_between_their_endpoints(
+            inside in prop_oneof![Just(Inside::P1), Just(Inside::P2), Just(Inside::Both)],
+            a     in prop::array::uniform3(-0.99..(0.99 as Lengthf32)),
+            b     in prop::array::uniform3(-0.99..(0.99 as Lengthf32)),
+            r     in  200.0..(300.0 as Lengthf32),
+            angle in 0.0..(1.0 as Lengthf32),
+            cos   in -1.0..(1.0 as Lengthf32),
+            nx in  5..50_usize,
+            ny in  5..50_usize,
+            nz in  5..50_usize,
+        ) {
+            let fov = FOV::new_from_full_widths((mm(120.0), mm(130.0), mm(100.0)), (nx, ny, nz));
+            let h = fov.half_width;
+            let within = |f: [Lengthf32; 3]| Point::new(h.x * f[0], h.y * f[1], h.z * f[2]);
+            let (theta, sin) = (angle * crate::TWOPI, (1.0 - cos * cos).sqrt());
+            let outside = Point::new(mm(r * sin * theta.cos()), mm(r * sin * theta.sin()), mm(r * cos));
+            let (p1, p2) = match inside {
+                Inside::P1   => (within(a), outside),
+                Inside::P2   => (outside, within(a)),
+                Inside::Both => (within(a), within(b)),
+            };
+            let lor = LOR::new(Time::ZERO, Time::ZERO, p1, p2);
+            let voxels = lor.active_voxels(&fov, None, None);
+
+            let summed: Lengthf32 = voxels.iter().map(|(_, w)| w).sum();
+            assert_float_eq!(summed, length_inside(p1, p2, fov), rmax <= 1e-3, abs <= 1e-4);
+
+            // The traversal starts and ends in the voxels containing the endpoints
+            let voxel = |p: Point| fov.voxel_coordinates(p).unwrap().map(|c| c as usize);
+            if fov.contains(p1) { assert_eq!(voxels.first().unwrap().0, voxel(p1)); }
+            if fov.contains(p2) { assert_eq!(voxels.last ().unwrap().0, voxel(p2)); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_system_matrix {
+    use super::*;
+    use crate::image::Image;
+    use crate::mlem::{system_matrix_row, MlemOptions};
+
+    fn fov() -> FOV { FOV::new_from_full_widths((mm(100.0), mm(100.0), mm(10.0)), (20, 20, 2)) }
+
+    /// `n` LORs at a variety of angles and heights, some of which miss the FOV
+    fn lors(n: usize) -> Vec<LOR> {
+        (0..n).map(|i| {
+            let (y, z, dy) = ((i * 37 % 120) as f32 - 60.0, (i % 7) as f32 - 3.0, (i % 5) as f32 - 2.0);
+            LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-200.0), mm(y), mm(z)), Point::new(mm(200.0), mm(y + dy), mm(-z)))
+        }).collect()
+    }
+
+    /// The row of the `i`th of `lors`, from `rows`
+    fn row(rows: &dyn RowSource, i: usize, lors: &[LOR]) -> (bool, Vec<Index1_u>, Vec<Lengthf32>) {
+        let tof = make_gauss_option(None, None);
+        let (mut indices, mut weights) = (vec![], vec![]);
+        let hit = rows.row(i, &lors[i], false, &mut indices, &mut weights,
+                           &mut |indices, weights| system_matrix_row(&lors[i], fov(), &tof, None, indices, weights));
+        (hit, indices, weights)
+    }
+
+    #[test]
+    fn stored_rows_match_those_computed_on_the_fly() {
+        let lors = lors(200);
+        let all = SystemMatrix::new(&lors, fov(), None, None, None, usize::MAX);
+        assert_eq!(all.n_stored(), lors.len());
+        let half = SystemMatrix::new(&lors, fov(), None, None, None, all.bytes() / 2);
+        assert!(half.n_stored() > 0 && half.n_stored() < lors.len(), "{}", half.n_stored());
+        assert!(half.bytes() <= all.bytes() / 2);
+        let none = SystemMatrix::new(&lors, fov(), None, None, None, 0);
+        assert_eq!((none.n_stored(), none.bytes()), (0, 0));
+
+        let misses = (0..lors.len()).filter(|&i| !row(&OnTheFly, i, &lors).0).count();
+        assert!(misses > 0);
+        for i in 0..lors.len() {
+            let expected = row(&OnTheFly, i, &lors);
+            for matrix in [&all, &half, &none] {
+                assert_eq!(row(matrix, i, &lors), expected, "LOR {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn precomputed_reconstruction_matches_recomputed() {
+        // Enough LORs for several projection chunks, each subset drawing on all
+        // of them
+        let lors = lors(3 * crate::cancel::CANCEL_CHECK_CHUNK);
+        let matrix = SystemMatrix::new(&lors, fov(), None, None, None, usize::MAX);
+        let partial = SystemMatrix::new(&lors, fov(), None, None, None, matrix.bytes() / 3);
+        let run = |rows: Option<&dyn RowSource>| {
+            Image::mlem_with(Image::ones(fov()), &lors, None, None, None, None, 2, MlemOptions { rows, ..Default::default() })
+                .nth(3).unwrap().image
+        };
+        let recomputed = run(None);
+        assert_float_eq!(run(Some(&matrix )).data, recomputed.data, rmax_all <= 1e-5);
+        assert_float_eq!(run(Some(&partial)).data, recomputed.data, rmax_all <= 1e-5);
+    }
+}
+
+#[cfg(test)]
+mod test_degenerate {
+    use super::*;
+    use geometry::units::ns;
+
+    fn lor(p2: (f32, f32, f32)) -> LOR {
+        let (x, y, z) = p2;
+        LOR::new(ns(0.0), ns(0.0), Point::new(mm(100.0), mm(-20.0), mm(5.0)), Point::new(mm(x), mm(y), mm(z)))
+    }
+
+    #[test]
+    fn policies() {
+        let (degenerate, fine) = (lor((100.0, -20.0, 5.0)), lor((-100.0, 20.0, 5.0)));
+        assert!(degenerate.is_degenerate(DEGENERATE_TOLERANCE));
+        assert!(!fine.is_degenerate(DEGENERATE_TOLERANCE));
+        assert!(lor((100.0, -20.0, 5.5)).is_degenerate(mm(1.0)));
+        for policy in [DegeneratePolicy::Drop, DegeneratePolicy::Error, DegeneratePolicy::Keep] {
+            assert_eq!(policy.admit(&fine), Ok(true));
+        }
+        assert_eq!(DegeneratePolicy::Drop.admit(&degenerate), Ok(false));
+        assert_eq!(DegeneratePolicy::Keep.admit(&degenerate), Ok(true));
+        assert!(DegeneratePolicy::Error.admit(&degenerate).is_err());
+        assert_eq!("Keep".parse::<DegeneratePolicy>(), Ok(DegeneratePolicy::Keep));
+        assert!("ignore".parse::<DegeneratePolicy>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_endpoint_order {
+    use super::*;
+    use geometry::units::{ps, ratio};
+    use proptest::prelude::*;
+    use rstest::rstest;
+    use std::collections::BTreeMap;
+
+    fn lor((x1, y1, z1): (f32, f32, f32), (x2, y2, z2): (f32, f32, f32), dt: f32) -> LOR {
+        LOR::new(ps(0.0), ps(dt), Point::new(mm(x1), mm(y1), mm(z1)), Point::new(mm(x2), mm(y2), mm(z2)))
+    }
+
+    fn coordinates(lor: &LOR) -> [f32; 7] {
+        let (p, q) = (lor.p1, lor.p2);
+        [mm_(p.x), mm_(p.y), mm_(p.z), mm_(q.x), mm_(q.y), mm_(q.z), ps_(lor.dt)]
+    }
+
+    proptest! {
+        #[test]
+        fn canonical_order_is_unique(
+            x1 in -400.0..(400.0 as f32), y1 in -400.0..(400.0 as f32), z1 in -200.0..(200.0 as f32),
+            x2 in -400.0..(400.0 as f32), y2 in -400.0..(400.0 as f32), z2 in -200.0..(200.0 as f32),
+            dt in -1000.0..(1000.0 as f32),
+        ) {
+            let lor = lor((x1, y1, z1), (x2, y2, z2), dt);
+            let canonical = lor.canonicalized();
+            prop_assert!(canonical.is_canonical());
+            prop_assert!(canonical.p1.z <= canonical.p2.z);
+            prop_assert_eq!(coordinates(&lor.swapped().canonicalized()), coordinates(&canonical));
+            prop_assert_eq!(coordinates(&lor.swapped().swapped()), coordinates(&lor));
+        }
+    }
+
+    #[rstest(/**/ p1                , p2                , canonical_p1,
+             // z decides
+             case((  0.0, 0.0,  10.0), ( 10.0, 0.0, -10.0), ( 10.0, 0.0, -10.0)),
+             // then azimuth
+             case((-10.0, 0.0,   5.0), ( 10.0, 0.0,   5.0), ( 10.0, 0.0,   5.0)),
+             case((  0.0, 10.0,  5.0), (  0.0,-10.0,   5.0), (  0.0,-10.0,   5.0)),
+             // then x, along a ray from the axis
+             case(( 20.0, 20.0,  5.0), ( 10.0, 10.0,   5.0), ( 10.0, 10.0,   5.0)),
+    )]
+    fn precedence(p1: (f32, f32, f32), p2: (f32, f32, f32), canonical_p1: (f32, f32, f32)) {
+        let canonical = lor(p1, p2, 100.0).canonicalized();
+        let (x, y, z) = canonical_p1;
+        assert_eq!(coordinates(&canonical)[..3], [x, y, z]);
+        let dt = if (x, y, z) == p1 { 100.0 } else { -100.0 };
+        assert_eq!(ps_(canonical.dt), dt);
+    }
+
+    #[test]
+    fn coincident_endpoints_have_non_negative_dt() {
+        let p = (30.0, -40.0, 5.0);
+        assert_eq!(ps_(lor(p, p, -250.0).canonicalized().dt), 250.0);
+        assert_eq!(ps_(lor(p, p,  250.0).canonicalized().dt), 250.0);
+    }
+
+    /// TOF-weighted system matrix elements of `lor`, by voxel
+    fn weights(lor: &LOR, cutoff: Option<TofCutoff>) -> BTreeMap<[usize; 3], f32> {
+        let fov = FOV::new_from_full_widths((mm(200.0), mm(200.0), mm(100.0)), (20, 20, 10));
+        lor.active_voxels(&fov, cutoff, Some(ps(150.0))).into_iter().collect()
+    }
+
+    /// Every voxel's weight agrees, to within a small fraction of the largest
+    fn assert_same_weights(a: &BTreeMap<[usize; 3], f32>, b: &BTreeMap<[usize; 3], f32>) {
+        let largest = a.values().chain(b.values()).fold(0.0_f32, |m, &w| m.max(w));
+        assert!(largest > 0.0);
+        for voxel in a.keys().chain(b.keys()) {
+            let (wa, wb) = (a.get(voxel).unwrap_or(&0.0), b.get(voxel).unwrap_or(&0.0));
+            assert!((wa - wb).abs() <= 1e-4 * largest, "voxel {voxel:?}: {wa} vs {wb}");
+        }
+    }
+
+    #[rstest(/**/ p1                    , p2                  , dt    ,
+             case((-300.0, -50.0, -20.0), (280.0,  70.0, 40.0),    0.0),
+             case((-300.0, -50.0, -20.0), (280.0,  70.0, 40.0),  200.0),
+             case(( 250.0, 260.0,  30.0), (-90.0, -310.0, -5.0), -350.0),
+    )]
+    fn tof_weights_ignore_endpoint_order(p1: (f32, f32, f32), p2: (f32, f32, f32), dt: f32) {
+        let lor = lor(p1, p2, dt);
+        let symmetric = Some(TofCutoff::symmetric(ratio(3.0)));
+        for cutoff in [None, symmetric] {
+            assert_same_weights(&weights(&lor, cutoff), &weights(&lor.swapped(), cutoff));
+        }
+        // Asymmetric cutoffs are defined relative to the direction from p1 to
+        // p2, so swapping the endpoints mirrors them
+        let (before, after) = (ratio(1.0), ratio(3.0));
+        assert_same_weights(&weights(&lor          , Some(TofCutoff::asymmetric(before, after))),
+                            &weights(&lor.swapped(), Some(TofCutoff::asymmetric(after, before))));
+    }
+}
@@ -0,0 +1,207 @@
+//! Seeded generators of synthetic LORs and images, for tests, benches and
+//! examples. The same seed always gives the same data.
+//!
+//! Gammas are emitted isotropically (`cos θ` uniform in `[-1, 1]`, `φ` uniform
+//! in `[0, 2π)`) and back to back. Each LOR's endpoints are where the line of
+//! the gammas crosses a cylinder about the z-axis, and its `dt` places the TOF
+//! peak (see `find_tof_peak`) exactly at the decay.
+//!
+//! Available to downstream crates with the `testing` feature.
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::f32::consts::TAU;
+
+use crate::{Length, Point, Time, C};
+use crate::fov::FOV;
+use crate::image::Image;
+use crate::system_matrix::LOR;
+use geometry::units::{mm, mm_};
+use geometry::uom::ConstZero;
+
+/// Cylindrical detector about the z-axis, centred on the origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Detector {
+    pub radius: Length,
+    pub half_length: Length,
+}
+
+/// `n` LORs from decays distributed uniformly throughout the cylinder of
+/// `radius` and `half_length`: `r = radius √u` and `z` uniform in
+/// `[-half_length, half_length]`. The endpoints lie on the curved surface of
+/// the same cylinder, extended axially as far as necessary.
+pub fn uniform_cylinder_lors(n: usize, radius: Length, half_length: Length, seed: u64) -> Vec<LOR> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (r_max, h) = (mm_(radius), mm_(half_length));
+    let mut lors = Vec::with_capacity(n);
+    while lors.len() < n {
+        let r = r_max * rng.gen::<f32>().sqrt();
+        let phi = rng.gen_range(0.0..TAU);
+        let decay = [r * phi.cos(), r * phi.sin(), rng.gen_range(-h..=h)];
+        let direction = isotropic(&mut rng);
+        if let Some((p1, p2)) = cylinder_crossings(decay, direction, r_max) {
+            lors.push(lor_through(decay, p1, p2));
+        }
+    }
+    lors
+}
+
+/// `n` LORs from decays at `position`, which must lie inside `detector`.
+/// Emissions whose gammas do not both reach `detector` within its length are
+/// discarded, so the directions of the LORs are isotropic within its
+/// acceptance.
+pub fn point_source_lors(n: usize, position: Point, detector: Detector, seed: u64) -> Vec<LOR> {
+    let (r_max, h) = (mm_(detector.radius), mm_(detector.half_length));
+    let decay = [mm_(position.x), mm_(position.y), mm_(position.z)];
+    assert!(decay[0].hypot(decay[1]) < r_max && decay[2].abs() < h, "Point source {decay:?} mm lies outside the detector");
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut lors = Vec::with_capacity(n);
+    while lors.len() < n {
+        match cylinder_crossings(decay, isotropic(&mut rng), r_max) {
+            Some((p1, p2)) if p1[2].abs() <= h && p2[2].abs() <= h => lors.push(lor_through(decay, p1, p2)),
+            _ => (),
+        }
+    }
+    lors
+}
+
+/// Image of `fov` whose voxels are independently `baseline + sigma N(0, 1)`,
+/// with the standard normal variates drawn by the Box-Muller transform
+pub fn noisy_image(fov: FOV, baseline: f32, sigma: f32, seed: u64) -> Image {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let [nx, ny, nz] = fov.n;
+    let data = (0..nx * ny * nz)
+        .map(|_| {
+            // 1 - u lies in (0, 1], so its logarithm is finite
+            let u: f32 = 1.0 - rng.gen::<f32>();
+            let v: f32 = rng.gen();
+            baseline + sigma * (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+        })
+        .collect();
+    Image::new(fov, data)
+}
+
+/// Unit vector in an isotropic direction
+fn isotropic(rng: &mut StdRng) -> [f32; 3] {
+    let cos_theta: f32 = rng.gen_range(-1.0..=1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let phi = rng.gen_range(0.0..TAU);
+    [sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta]
+}
+
+/// Where the line through `point` (inside the cylinder) along `direction`
+/// crosses the cylinder of `radius` about the z-axis, behind and ahead of
+/// `point`. `None` for directions along the z-axis, which never cross it.
+fn cylinder_crossings(point: [f32; 3], direction: [f32; 3], radius: f32) -> Option<([f32; 3], [f32; 3])> {
+    let ([x, y, _], [dx, dy, _]) = (point, direction);
+    // Solve |(x, y) + t (dx, dy)| = radius for t
+    let a = dx * dx + dy * dy;
+    if a < 1e-12 { return None }
+    let b = 2.0 * (x * dx + y * dy);
+    let c = x * x + y * y - radius * radius;
+    let root = (b * b - 4.0 * a * c).sqrt();
+    let at = |t: f32| [0, 1, 2].map(|d| point[d] + t * direction[d]);
+    Some((at((-b - root) / (2.0 * a)), at((-b + root) / (2.0 * a))))
+}
+
+/// LOR from `p1` to `p2`, with the TOF peak at `decay`: the peak lies
+/// `L/2 - c dt/2` from `p1`, which is `d1` when `dt = (d2 - d1) / c`
+fn lor_through(decay: [f32; 3], p1: [f32; 3], p2: [f32; 3]) -> LOR {
+    let point = |[x, y, z]: [f32; 3]| Point::new(mm(x), mm(y), mm(z));
+    let (decay, p1, p2) = (point(decay), point(p1), point(p2));
+    let dt = ((p2 - decay).norm() - (p1 - decay).norm()) / C;
+    LOR { dt, ..LOR::new(Time::ZERO, Time::ZERO, p1, p2) }
+}
+
+#[cfg(test)]
+mod test_testdata {
+    use super::*;
+    use float_eq::assert_float_eq;
+    use geometry::units::ratio_;
+
+    /// Where the TOF peak of `lor` lies
+    fn tof_peak(lor: &LOR) -> [f32; 3] {
+        let length = (lor.p2 - lor.p1).norm();
+        let p1_to_peak = length / 2.0 - C * lor.dt / 2.0;
+        let peak = lor.p1 + (lor.p2 - lor.p1) * ratio_(p1_to_peak / length);
+        [mm_(peak.x), mm_(peak.y), mm_(peak.z)]
+    }
+
+    /// Numbers of `angles` (radians) in each of `n` equal bins of `[0, period)`
+    fn histogram(angles: impl Iterator<Item = f32>, n: usize, period: f32) -> Vec<usize> {
+        let mut counts = vec![0; n];
+        for a in angles {
+            counts[((a.rem_euclid(period) / period * n as f32) as usize).min(n - 1)] += 1;
+        }
+        counts
+    }
+
+    /// Whether each bin of `counts` lies within 5 standard deviations of an
+    /// even share of their total
+    fn is_flat(counts: &[usize]) -> bool {
+        let total = counts.iter().sum::<usize>() as f32;
+        let p = 1.0 / counts.len() as f32;
+        let (expected, sd) = (total * p, (total * p * (1.0 - p)).sqrt());
+        counts.iter().all(|&c| (c as f32 - expected).abs() < 5.0 * sd)
+    }
+
+    fn mean(xs: impl Iterator<Item = f32>) -> f32 {
+        let (n, sum) = xs.fold((0, 0.0), |(n, sum), x| (n + 1, sum + x as f64));
+        (sum / n as f64) as f32
+    }
+
+    #[test]
+    fn uniform_cylinder() {
+        let lors = uniform_cylinder_lors(20_000, mm(100.0), mm(50.0), 1);
+        assert_eq!(lors.len(), 20_000);
+        let radius = |p: Point| mm_(p.x).hypot(mm_(p.y));
+        assert_float_eq!(mean(lors.iter().map(|l| radius(l.p1))), 100.0, abs <= 1e-3);
+        assert_float_eq!(mean(lors.iter().map(|l| radius(l.p2))), 100.0, abs <= 1e-3);
+        assert!(is_flat(&histogram(lors.iter().map(|l| mm_(l.p1.y).atan2(mm_(l.p1.x))), 8, TAU)));
+
+        // Uniform in the cylinder: <r²> = R²/2, <z> = 0 and <z²> = h²/3
+        let peaks: Vec<[f32; 3]> = lors.iter().map(tof_peak).collect();
+        assert_float_eq!(mean(peaks.iter().map(|[x, y, _]| x * x + y * y)), 5000.0, abs <= 100.0);
+        assert_float_eq!(mean(peaks.iter().map(|[_, _, z]| *z)), 0.0, abs <= 1.0);
+        assert_float_eq!(mean(peaks.iter().map(|[_, _, z]| z * z)), 2500.0 / 3.0, abs <= 30.0);
+        assert!(peaks.iter().all(|[_, _, z]| z.abs() <= 50.01));
+    }
+
+    #[test]
+    fn point_source() {
+        let detector = Detector { radius: mm(200.0), half_length: mm(100.0) };
+        let at = |x, y, z| Point::new(mm(x), mm(y), mm(z));
+
+        // Every LOR passes through an off-axis source, which is the TOF peak
+        let lors = point_source_lors(1000, at(30.0, -20.0, 10.0), detector, 2);
+        for lor in &lors {
+            assert_float_eq!(tof_peak(lor), [30.0, -20.0, 10.0], abs <= [0.05; 3]);
+            assert!(mm_(lor.p1.z).abs() <= 100.0 && mm_(lor.p2.z).abs() <= 100.0);
+        }
+
+        // The acceptance of a source on the axis does not depend on φ
+        let lors = point_source_lors(20_000, at(0.0, 0.0, 10.0), detector, 3);
+        let phi = |lor: &LOR| mm_(lor.p2.y - lor.p1.y).atan2(mm_(lor.p2.x - lor.p1.x));
+        assert!(is_flat(&histogram(lors.iter().map(phi), 6, TAU / 2.0)));
+    }
+
+    #[test]
+    fn noisy_image_has_the_requested_mean_and_spread() {
+        let fov = FOV::new_from_full_widths((mm(20.0), mm(20.0), mm(20.0)), (20, 20, 20));
+        let image = noisy_image(fov, 10.0, 2.0, 4);
+        let average = mean(image.data.iter().copied());
+        let variance = mean(image.data.iter().map(|v| (v - average).powi(2)));
+        assert_float_eq!(average, 10.0, abs <= 0.1);
+        assert_float_eq!(variance.sqrt(), 2.0, abs <= 0.1);
+    }
+
+    #[test]
+    fn generators_are_deterministic() {
+        let ends = |lors: Vec<LOR>| lors.iter().map(|l| (l.p1, l.p2, l.dt)).collect::<Vec<_>>();
+        let cylinder = |seed| ends(uniform_cylinder_lors(100, mm(50.0), mm(20.0), seed));
+        assert_eq!(cylinder(7), cylinder(7));
+        assert_ne!(cylinder(7), cylinder(8));
+        let fov = FOV::new_from_full_widths((mm(4.0), mm(4.0), mm(4.0)), (4, 4, 4));
+        assert_eq!(noisy_image(fov, 1.0, 0.5, 9).data, noisy_image(fov, 1.0, 0.5, 9).data);
+    }
+}
@@ -0,0 +1,237 @@
+//! Tiny reconstruction problems whose MLEM fixed points are known exactly.
+//!
+//! Each problem is a handful of distinct LORs (bins) through a FOV of a few
+//! voxels, with integer counts chosen to match a known activity exactly. The
+//! system matrices have full column rank, so the known activity is the unique
+//! maximum-likelihood estimate, and MLEM converges to it.
+//!
+//! Available to downstream crates with the `testing` feature.
+
+use crate::Point;
+use crate::fov::FOV;
+use crate::image::{Image, ImageData};
+use crate::system_matrix::{Corrections, LOR};
+use geometry::units::{mm, ns, ratio_};
+
+/// Distance of LOR endpoints from the FOV centre: far outside every FOV here
+const FAR: f32 = 50.0;
+
+#[derive(Clone)]
+pub struct AnalyticSystem {
+    pub fov: FOV,
+    /// The distinct LORs, including their corrections
+    pub bins: Vec<LOR>,
+    /// Path length (mm) of each bin through each voxel, before corrections
+    pub matrix: Vec<Vec<f32>>,
+    /// Number of events measured in each bin
+    pub counts: Vec<usize>,
+    /// The unique MLEM fixed point
+    pub solution: ImageData,
+}
+
+impl AnalyticSystem {
+
+    /// Three 1 mm voxels along x. One bin along y through each voxel, and one
+    /// along x through all three:
+    ///
+    /// ```text
+    /// A = [1 0 0]    x* = [1 2 3]    y = [1 2 3 6]
+    ///     [0 1 0]
+    ///     [0 0 1]
+    ///     [1 1 1]
+    /// ```
+    ///
+    /// Every voxel has sensitivity 2, so one iteration from a uniform image of
+    /// ones gives `x_j = (y_j / 1 + 6 / 3) / 2 = [1.5 2 2.5]`.
+    pub fn one_d() -> Self {
+        let fov = FOV::new_from_full_widths((mm(3.0), mm(1.0), mm(1.0)), (3, 1, 1));
+        let x = |i: usize| -1.0 + i as f32;
+        let mut bins: Vec<_> = (0..3).map(|i| lor((x(i), -FAR, 0.0), (x(i), FAR, 0.0))).collect();
+        bins.push(lor((-FAR, 0.0, 0.0), (FAR, 0.0, 0.0)));
+        let matrix = vec![
+            vec![1.0, 0.0, 0.0],
+            vec![0.0, 1.0, 0.0],
+            vec![0.0, 0.0, 1.0],
+            vec![1.0, 1.0, 1.0],
+        ];
+        Self::new(fov, bins, matrix, vec![1.0, 2.0, 3.0])
+    }
+
+    /// 2×2 voxels of 1 mm in the xy-plane, voxel `(ix, iy)` at index `ix + 2 iy`.
+    /// One bin along x through each row, one along y through each column, and
+    /// one along z through voxel `(0, 0)`, without which rows and columns alone
+    /// would not determine the solution:
+    ///
+    /// ```text
+    /// A = [1 1 0 0]    x* = [1 2 3 4]    y = [3 7 4 6 1]
+    ///     [0 0 1 1]
+    ///     [1 0 1 0]
+    ///     [0 1 0 1]
+    ///     [1 0 0 0]
+    /// ```
+    pub fn two_d() -> Self {
+        let (fov, bins, mut matrix) = rows_and_columns_2x2(&[(0, 0)]);
+        matrix.push(vec![1.0, 0.0, 0.0, 0.0]);
+        Self::new(fov, bins, matrix, vec![1.0, 2.0, 3.0, 4.0])
+    }
+
+    /// As `two_d`, with a hot anti-diagonal. Every row and column bin sees the
+    /// same counts, so only the z bin distinguishes the solution from a uniform
+    /// image, and plain MLEM approaches it slowly:
+    ///
+    /// ```text
+    /// A = [1 1 0 0]    x* = [1 20 20 1]    y = [21 21 21 21 1]
+    ///     [0 0 1 1]
+    ///     [1 0 1 0]
+    ///     [0 1 0 1]
+    ///     [1 0 0 0]
+    /// ```
+    pub fn two_d_diagonal() -> Self {
+        let (fov, bins, mut matrix) = rows_and_columns_2x2(&[(0, 0)]);
+        matrix.push(vec![1.0, 0.0, 0.0, 0.0]);
+        Self::new(fov, bins, matrix, vec![1.0, 20.0, 20.0, 1.0])
+    }
+
+    /// As `two_d`, with a hot voxel `(0, 0)` in a cold background, and one bin
+    /// along z through every voxel:
+    ///
+    /// ```text
+    /// A = [1 1 0 0]    x* = [16 1 1 1]    y = [17 2 17 2 16 1 1 1]
+    ///     [0 0 1 1]
+    ///     [1 0 1 0]
+    ///     [0 1 0 1]
+    ///     [   I   ]
+    /// ```
+    pub fn two_d_hot() -> Self {
+        let (fov, bins, mut matrix) = rows_and_columns_2x2(&[(0, 0), (1, 0), (0, 1), (1, 1)]);
+        for j in 0..4 {
+            matrix.push((0..4).map(|k| if k == j { 1.0 } else { 0.0 }).collect());
+        }
+        Self::new(fov, bins, matrix, vec![16.0, 1.0, 1.0, 1.0])
+    }
+
+    fn new(fov: FOV, bins: Vec<LOR>, matrix: Vec<Vec<f32>>, solution: ImageData) -> Self {
+        let mut system = Self { fov, bins, matrix, counts: vec![], solution };
+        system.counts = system.consistent_counts();
+        system
+    }
+
+    /// Attach `corrections` to the bins, adjusting the counts to remain
+    /// consistent with the solution. Panics if that would require fractional
+    /// counts.
+    pub fn with_corrections(mut self, corrections: &[Corrections]) -> Self {
+        assert_eq!(corrections.len(), self.bins.len(), "Need one set of corrections per bin");
+        for (lor, &c) in self.bins.iter_mut().zip(corrections) { lor.corrections = c }
+        self.counts = self.consistent_counts();
+        self
+    }
+
+    fn consistent_counts(&self) -> Vec<usize> {
+        self.expected(&self.solution).into_iter()
+            .map(|e| {
+                let n = e.round();
+                assert!((e - n).abs() < 1e-9, "Analytic system expects non-integral counts: {e}");
+                n as usize
+            })
+            .collect()
+    }
+
+    /// The measured events: each bin repeated as many times as its count
+    pub fn measured_lors(&self) -> Vec<LOR> {
+        self.bins.iter().zip(&self.counts)
+            .flat_map(|(&lor, &n)| std::iter::repeat(lor).take(n))
+            .collect()
+    }
+
+    /// Expected counts in each bin, given `image`, including corrections
+    pub fn expected(&self, image: &[f32]) -> Vec<f64> {
+        self.expected_f64(&to_f64(image))
+    }
+
+    fn expected_f64(&self, image: &[f64]) -> Vec<f64> {
+        self.bins.iter().zip(&self.matrix)
+            .map(|(lor, row)| {
+                let projection: f64 = row.iter().zip(image).map(|(&a, &x)| a as f64 * x).sum();
+                let c = lor.corrections;
+                ratio_(c.multiplicative * c.scatter) as f64 * projection + c.additive as f64
+            })
+            .collect()
+    }
+
+    /// Sum over bins of each voxel's system matrix elements, scaled by the
+    /// bins' multiplicative corrections
+    pub fn sensitivity(&self) -> Vec<f64> {
+        let mut sensitivity = vec![0.0; self.solution.len()];
+        for (lor, row) in self.bins.iter().zip(&self.matrix) {
+            let m = ratio_(lor.corrections.multiplicative) as f64;
+            for (s, &a) in sensitivity.iter_mut().zip(row) { *s += m * a as f64 }
+        }
+        sensitivity
+    }
+
+    /// The reciprocal of `sensitivity`, as expected by `Image::mlem`
+    pub fn sensitivity_image(&self) -> Image {
+        let data = self.sensitivity().iter()
+            .map(|&s| if s > 0.0 { (1.0 / s) as f32 } else { 0.0 })
+            .collect();
+        Image::new(self.fov, data)
+    }
+
+    /// Poisson log-likelihood of the counts, given `image`, up to a constant
+    pub fn log_likelihood(&self, image: &[f32]) -> f64 {
+        self.expected(image).iter().zip(&self.counts)
+            .map(|(&e, &n)| n as f64 * e.ln() - e)
+            .sum()
+    }
+
+    /// Largest relative deviation of `image` from the solution
+    pub fn max_relative_error(&self, image: &[f32]) -> f64 {
+        image.iter().zip(&self.solution)
+            .map(|(&x, &s)| ((x as f64 - s as f64) / s as f64).abs())
+            .fold(0.0, f64::max)
+    }
+
+    /// MLEM iterates, starting from ones, computed in `f64` with the dense
+    /// system matrix: a reference for the projector-based implementation
+    pub fn reference_mlem(&self) -> impl Iterator<Item = Vec<f64>> + '_ {
+        let sensitivity = self.sensitivity();
+        let mut image = vec![1.0; self.solution.len()];
+        std::iter::from_fn(move || {
+            let expected = self.expected_f64(&image);
+            let mut backprojection = vec![0.0; image.len()];
+            for (((lor, row), &n), e) in self.bins.iter().zip(&self.matrix).zip(&self.counts).zip(expected) {
+                let m = ratio_(lor.corrections.multiplicative) as f64;
+                for (b, &a) in backprojection.iter_mut().zip(row) { *b += n as f64 * m * a as f64 / e }
+            }
+            for ((x, b), &s) in image.iter_mut().zip(backprojection).zip(&sensitivity) {
+                *x = if s > 0.0 { *x * b / s } else { 0.0 };
+            }
+            Some(image.clone())
+        })
+    }
+}
+
+/// The FOV of the 2×2 systems, bins along x through each row, along y through
+/// each column and along z through each of `z_voxels`, and the system matrix
+/// rows of all but the z bins
+fn rows_and_columns_2x2(z_voxels: &[(usize, usize)]) -> (FOV, Vec<LOR>, Vec<Vec<f32>>) {
+    let fov = FOV::new_from_full_widths((mm(2.0), mm(2.0), mm(1.0)), (2, 2, 1));
+    let c = |i: usize| -0.5 + i as f32;
+    let mut bins = vec![];
+    for iy in 0..2 { bins.push(lor((-FAR, c(iy), 0.0), (FAR, c(iy), 0.0))) }
+    for ix in 0..2 { bins.push(lor((c(ix), -FAR, 0.0), (c(ix), FAR, 0.0))) }
+    for &(ix, iy) in z_voxels { bins.push(lor((c(ix), c(iy), -FAR), (c(ix), c(iy), FAR))) }
+    let matrix = vec![
+        vec![1.0, 1.0, 0.0, 0.0],
+        vec![0.0, 0.0, 1.0, 1.0],
+        vec![1.0, 0.0, 1.0, 0.0],
+        vec![0.0, 1.0, 0.0, 1.0],
+    ];
+    (fov, bins, matrix)
+}
+
+fn lor((x1, y1, z1): (f32, f32, f32), (x2, y2, z2): (f32, f32, f32)) -> LOR {
+    LOR::new(ns(0.0), ns(0.0), Point::new(mm(x1), mm(y1), mm(z1)), Point::new(mm(x2), mm(y2), mm(z2)))
+}
+
+fn to_f64(image: &[f32]) -> Vec<f64> { image.iter().map(|&x| x as f64).collect() }
@@ -0,0 +1,107 @@
+//! Poisson thinning: split one dataset into statistically independent replicates.
+//!
+//! Each event is assigned to one of `k` replicates, independently of all other
+//! events, with equal probability. The assignment depends only on the seed and
+//! on the event's position in the full dataset, so that a replicate can be
+//! selected on the fly while reading (see `Split`), and agrees with the files
+//! written by `thin_lors`, whatever the chunking or cuts applied.
+
+/// Which of `k` replicates event number `event` belongs to
+pub fn replicate_of(event: usize, k: usize, seed: u64) -> usize {
+    (splitmix64(seed ^ splitmix64(event as u64)) % k as u64) as usize
+}
+
+/// Indices of the events belonging to each of `k` replicates of `n` events
+pub fn thin_indices(n: usize, k: usize, seed: u64) -> Vec<Vec<usize>> {
+    let mut replicates = vec![vec![]; k];
+    for event in 0..n {
+        replicates[replicate_of(event, k, seed)].push(event);
+    }
+    replicates
+}
+
+/// Split `lors` into `k` statistically independent replicates
+pub fn thin_lors<T: Clone>(lors: &[T], k: usize, seed: u64) -> Vec<Vec<T>> {
+    thin_indices(lors.len(), k, seed).into_iter()
+        .map(|indices| indices.into_iter().map(|i| lors[i].clone()).collect())
+        .collect()
+}
+
+/// Selection of replicate `index` out of `k`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Split {
+    pub k: usize,
+    pub index: usize,
+    pub seed: u64,
+}
+
+impl Split {
+    pub fn new(k: usize, index: usize, seed: u64) -> Result<Self, String> {
+        if k == 0     { return Err("Cannot split into 0 replicates".into()) }
+        if index >= k { return Err(format!("Replicate index {index} out of range for {k} replicates")) }
+        Ok(Self { k, index, seed })
+    }
+
+    /// Whether event number `event` of the full dataset belongs to this replicate
+    pub fn keeps(&self, event: usize) -> bool { replicate_of(event, self.k, self.seed) == self.index }
+}
+
+/// Mixing function from the SplitMix64 generator: a bijection on u64 with good
+/// avalanche properties.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(k, case(1), case(2), case(7))]
+    fn each_event_in_exactly_one_replicate(k: usize) {
+        let n = 1000;
+        let replicates = thin_indices(n, k, 42);
+        assert_eq!(replicates.len(), k);
+        assert_eq!(replicates.iter().map(Vec::len).sum::<usize>(), n);
+        let mut all = replicates.concat();
+        all.sort_unstable();
+        assert_eq!(all, (0..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn assignment_is_deterministic() {
+        let lors = (0..500).collect::<Vec<usize>>();
+        assert_eq!(thin_lors(&lors, 3, 7), thin_lors(&lors, 3, 7));
+        assert_ne!(thin_lors(&lors, 3, 7), thin_lors(&lors, 3, 8));
+    }
+
+    #[test]
+    fn split_agrees_with_thinning() {
+        let (n, k, seed) = (300, 4, 99);
+        let replicates = thin_indices(n, k, seed);
+        for (index, replicate) in replicates.iter().enumerate() {
+            let split = Split::new(k, index, seed).unwrap();
+            let kept = (0..n).filter(|&e| split.keeps(e)).collect::<Vec<_>>();
+            assert_eq!(&kept, replicate);
+        }
+        assert!(Split::new(4, 4, 0).is_err());
+        assert!(Split::new(0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn replicates_are_uniformly_populated() {
+        let (n, k) = (100_000, 5);
+        let p = 1.0 / k as f64;
+        let expected = n as f64 * p;
+        let sigma = (n as f64 * p * (1.0 - p)).sqrt();
+        for seed in 0..3 {
+            for replicate in thin_indices(n, k, seed) {
+                let deviation = (replicate.len() as f64 - expected).abs();
+                assert!(deviation < 5.0 * sigma, "seed {seed}: {} events, expected {expected}", replicate.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Timing of the phases of a reconstruction, via `tracing` spans.
+//!
+//! Library code opens spans around its phases (reading, filtering, filling
+//! scattergrams, calculating sensitivity images, MLEM iterations and their
+//! projections, writing images). Executables choose how to report them with
+//! [`init`]: by default the duration of each span is printed when it closes,
+//! indented by its depth; optionally a Chrome trace file, loadable in
+//! `about://tracing`, is written as well.
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::Instant;
+
+use tracing::{field::{Field, Visit}, span, Event, Subscriber};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, util::SubscriberInitExt, Layer};
+
+use crate::memory::{self, MemoryAccounting};
+use crate::utils::stats::{format_millis, format_rate};
+
+/// Handles to the reporting installed by [`init`]
+pub struct Telemetry {
+    pub memory: MemoryAccounting,
+    /// Completes the Chrome trace when dropped
+    pub chrome: Option<FlushGuard>,
+}
+
+impl Telemetry {
+    /// Machine-readable summary of the run so far
+    pub fn summary_json(&self) -> serde_json::Value {
+        serde_json::json!({ "memory": self.memory.summary() })
+    }
+}
+
+/// Install the global subscriber: compact durations on stdout, memory
+/// accounting and, if `trace_json` is given, a Chrome trace in that file. The
+/// trace is completed when the returned `Telemetry` is dropped.
+pub fn init(trace_json: Option<&Path>) -> Telemetry {
+    let (chrome, guard) = match trace_json {
+        Some(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+            (Some(layer), Some(guard))
+        },
+        None => (None, None),
+    };
+    let memory = MemoryAccounting::new();
+    tracing_subscriber::registry()
+        .with(CompactTimings)
+        .with(memory.clone())
+        .with(chrome)
+        .init();
+    Telemetry { memory, chrome: guard }
+}
+
+/// Prints the duration of every span as it closes, and every event, in the
+/// style of `Phase: 1,234 ms`, followed by the rate at which it processed LORs
+/// if the span has an `n_lors` field
+pub struct CompactTimings;
+
+struct Started(Instant);
+/// The formatted fields of a span, and its `n_lors` field, from which the
+/// rate at which it processed LORs is reported
+struct Fields { text: String, n_lors: Option<usize> }
+
+impl<S> Layer<S> for CompactTimings
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = FieldsVisitor::default();
+            attrs.record(&mut fields);
+            let mut extensions = span.extensions_mut();
+            extensions.insert(Fields { text: fields.text, n_lors: fields.n_lors });
+            extensions.insert(Started(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let depth = span.scope().skip(1).count();
+            let extensions = span.extensions();
+            if let (Some(Started(start)), Some(Fields { text, n_lors })) = (extensions.get::<Started>(), extensions.get::<Fields>()) {
+                let elapsed = start.elapsed();
+                let rate = n_lors.map_or(String::new(), |n| format!(" ({})", format_rate(n, elapsed, "LORs")));
+                println!("{:indent$}{}{text}: {}{rate}", "", span.name(), format_millis(elapsed), indent = 2 * depth);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() == memory::TARGET { return }
+        let depth = ctx.event_scope(event).map_or(0, |scope| scope.count());
+        let mut fields = FieldsVisitor::default();
+        event.record(&mut fields);
+        println!("{:indent$}{}", "", fields.text.trim_start(), indent = 2 * depth);
+    }
+}
+
+/// Formats fields as ` name=value`, and the message of an event as is
+#[derive(Default)]
+struct FieldsVisitor { text: String, n_lors: Option<usize> }
+
+impl Visit for FieldsVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "n_lors" { self.n_lors = Some(value as usize) }
+        self.record_debug(field, &value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.text, " {value:?}"),
+            name      => write!(self.text, " {name}={value:?}"),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::fov::FOV;
+    use crate::image::Image;
+    use crate::system_matrix::LOR;
+    use crate::Point;
+    use geometry::units::{mm, ns};
+
+    type Spans = Arc<Mutex<Vec<(&'static str, Option<&'static str>)>>>;
+
+    /// Records the name of each span, and that of its parent
+    struct Capture(Spans);
+
+    impl<S> Layer<S> for Capture
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|p| p.name());
+            self.0.lock().unwrap().push((span.name(), parent));
+        }
+    }
+
+    #[test]
+    fn single_iteration_span_hierarchy() {
+        let spans = Spans::default();
+        let subscriber = tracing_subscriber::registry().with(Capture(Arc::clone(&spans)));
+        let dir = tempfile::tempdir().unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (5, 5, 5));
+            let p = |x, y, z| Point::new(mm(x), mm(y), mm(z));
+            let lors = vec![
+                LOR::new(ns(0.0), ns(0.0), p(-50.0, 0.0, 0.0), p(50.0,  0.0, 0.0)),
+                LOR::new(ns(0.0), ns(0.0), p(0.0, -50.0, 1.0), p( 0.0, 50.0, 1.0)),
+            ];
+            let sensitivity = Image::data_sensitivity_image(fov, &lors, None, false, false);
+            let (image, _, _) = Image::mlem(fov, &lors, None, None, None, Some(sensitivity), 1).next().unwrap();
+            image.write_to_raw_file(&dir.path().join("image.raw")).unwrap();
+        });
+
+        let spans = spans.lock().unwrap().clone();
+        let expected = [
+            ("data_sensitivity"      , None),
+            ("sensitivity_image"     , Some("data_sensitivity")),
+            ("mlem_iteration"        , None),
+            ("projection"            , Some("mlem_iteration")),
+            ("sensitivity_correction", Some("mlem_iteration")),
+            ("write_image"           , None),
+        ];
+        assert_eq!(spans, expected);
+    }
+
+    #[test]
+    fn summary_json_has_memory_section() {
+        let telemetry = Telemetry { memory: MemoryAccounting::new(), chrome: None };
+        let subscriber = tracing_subscriber::registry().with(telemetry.memory.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (5, 5, 5));
+            let lors = vec![LOR::new(ns(0.0), ns(0.0), Point::new(mm(-50.0), mm(0.0), mm(0.0)), Point::new(mm(50.0), mm(0.0), mm(0.0)))];
+            Image::mlem(fov, &lors, None, None, None, None, 1).next().unwrap();
+        });
+        let json = telemetry.summary_json();
+        let memory = &json["memory"];
+        assert_eq!(memory["components"]["image"], 3 * 125 * 4);
+        assert!(memory["components"]["projection_buffers"].as_u64().unwrap() > 0);
+        assert!(memory["phases"]["mlem_iteration"].is_object());
+        assert!(memory.get("peak_rss").is_some());
+    }
+}
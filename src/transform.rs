@@ -0,0 +1,185 @@
+//! Rigid transformations of detector coordinates, for aligning data acquired
+//! in one coordinate system with a FOV defined in another.
+//!
+//! A transform is given by a translation `(tx, ty, tz)` and rotations
+//! `(rx, ry, rz)` about the x, y and z axes of the FOV. Points are rotated
+//! first about x, then about y, then about z (all about the fixed FOV axes,
+//! through the origin), and then translated:
+//!
+//! `p' = Rz Ry Rx p + t`
+
+use ncollide3d::math::{Isometry, Rotation, Translation};
+
+use crate::{Angle, Length, Point, Pointf32, LOR};
+use geometry::units::{degree, degree_, mm, mm_, radian, radian_};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RigidTransform {
+    isometry: Isometry<f32>,
+}
+
+impl Default for RigidTransform {
+    fn default() -> Self { Self::identity() }
+}
+
+impl RigidTransform {
+    pub fn identity() -> Self { Self { isometry: Isometry::identity() } }
+
+    /// Rotation by `(rx, ry, rz)` about the FOV axes, in that order, followed
+    /// by translation by `(tx, ty, tz)`
+    pub fn new((tx, ty, tz): (Length, Length, Length), (rx, ry, rz): (Angle, Angle, Angle)) -> Self {
+        let rotation = Rotation::from_euler_angles(radian_(rx), radian_(ry), radian_(rz));
+        let translation = Translation::new(mm_(tx), mm_(ty), mm_(tz));
+        Self { isometry: Isometry::from_parts(translation, rotation) }
+    }
+
+    pub fn translation(t: (Length, Length, Length)) -> Self { Self::new(t, (degree(0.0), degree(0.0), degree(0.0))) }
+
+    /// The transform which undoes `self`
+    pub fn inverse(&self) -> Self { Self { isometry: self.isometry.inverse() } }
+
+    /// `self` followed by `next`
+    pub fn then(&self, next: &Self) -> Self { Self { isometry: next.isometry * self.isometry } }
+
+    pub fn apply(&self, p: Point) -> Point {
+        let p: Pointf32 = p.into();
+        (self.isometry * p).into()
+    }
+
+    /// `lor` with both endpoints transformed. TOF and corrections are kept.
+    pub fn apply_lor(&self, lor: &LOR) -> LOR {
+        LOR { p1: self.apply(lor.p1), p2: self.apply(lor.p2), ..*lor }
+    }
+
+    /// Translation `(tx, ty, tz)` and rotations `(rx, ry, rz)`, as given to `new`
+    pub fn components(&self) -> ((Length, Length, Length), (Angle, Angle, Angle)) {
+        let t = self.isometry.translation.vector;
+        let (rx, ry, rz) = self.isometry.rotation.euler_angles();
+        ((mm(t.x), mm(t.y), mm(t.z)), (radian(rx), radian(ry), radian(rz)))
+    }
+}
+
+/// `tx,ty,tz,rx,ry,rz`: translations in mm and rotations in degrees
+impl std::str::FromStr for RigidTransform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(',')
+            .map(|part| part.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Transform '{s}': {e}"))?;
+        match parts[..] {
+            [tx, ty, tz, rx, ry, rz] => Ok(Self::new((mm(tx), mm(ty), mm(tz)), (degree(rx), degree(ry), degree(rz)))),
+            _ => Err(format!("Transform '{s}' should have six comma-separated numbers: tx,ty,tz (mm),rx,ry,rz (degrees)")),
+        }
+    }
+}
+
+impl std::fmt::Display for RigidTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ((tx, ty, tz), (rx, ry, rz)) = self.components();
+        write!(f, "{},{},{},{},{},{}", mm_(tx), mm_(ty), mm_(tz), degree_(rx), degree_(ry), degree_(rz))
+    }
+}
+
+#[cfg(test)]
+mod test_rigid_transform {
+    use super::*;
+    use crate::fov::FOV;
+    use crate::image::Image;
+    use crate::system_matrix::Corrections;
+    use float_eq::assert_float_eq;
+    use geometry::units::ns;
+
+    fn coordinates(p: Point) -> [f32; 3] { [mm_(p.x), mm_(p.y), mm_(p.z)] }
+
+    fn transform() -> RigidTransform { "12.5,-3,40,10,-25,170".parse().unwrap() }
+
+    fn points() -> Vec<Point> {
+        (0..10).map(|i| {
+            let f = i as f32;
+            Point::new(mm(30.0 * f - 100.0), mm(f * f - 20.0), mm(250.0 - 45.0 * f))
+        }).collect()
+    }
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let t = transform();
+        let round_trip = t.then(&t.inverse());
+        for p in points() {
+            assert_float_eq!(coordinates(round_trip.apply(p)), coordinates(p), abs <= [1e-3; 3]);
+            assert_float_eq!(coordinates(t.inverse().apply(t.apply(p))), coordinates(p), abs <= [1e-3; 3]);
+        }
+    }
+
+    #[test]
+    fn rotations_preserve_lengths_and_angles() {
+        let t: RigidTransform = "0,0,0,33,-71,12".parse().unwrap();
+        let lors: Vec<LOR> = points().windows(2)
+            .map(|w| LOR { p1: w[0], p2: w[1], dt: ns(0.1), corrections: Corrections::NONE })
+            .collect();
+        let direction = |lor: &LOR| { let v = lor.p2 - lor.p1; [mm_(v.x), mm_(v.y), mm_(v.z)] };
+        let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+        for pair in lors.windows(2) {
+            let [a, b] = [&pair[0], &pair[1]];
+            let [ta, tb] = [t.apply_lor(a), t.apply_lor(b)];
+            assert_eq!((ta.dt, ta.corrections), (a.dt, a.corrections));
+            assert_float_eq!(mm_((ta.p2 - ta.p1).norm()), mm_((a.p2 - a.p1).norm()), rmax <= 1e-5);
+            assert_float_eq!(dot(direction(&ta), direction(&tb)), dot(direction(a), direction(b)), rmax <= 1e-4);
+        }
+    }
+
+    #[test]
+    fn rotations_are_applied_about_x_then_y_then_z() {
+        // x → y about z
+        let about_z: RigidTransform = "0,0,0,0,0,90".parse().unwrap();
+        assert_float_eq!(coordinates(about_z.apply(Point::new(mm(1.0), mm(0.0), mm(0.0)))), [0.0, 1.0, 0.0], abs <= [1e-6; 3]);
+        // z → -y about x, then -y → -x about z
+        let both: RigidTransform = "0,0,0,90,0,90".parse().unwrap();
+        assert_float_eq!(coordinates(both.apply(Point::new(mm(0.0), mm(0.0), mm(1.0)))), [1.0, 0.0, 0.0], abs <= [1e-6; 3]);
+        // Translation follows rotation
+        let moved: RigidTransform = "5,0,0,0,0,90".parse().unwrap();
+        assert_float_eq!(coordinates(moved.apply(Point::new(mm(1.0), mm(0.0), mm(0.0)))), [5.0, 1.0, 0.0], abs <= [1e-6; 3]);
+    }
+
+    #[test]
+    fn parses_and_displays_components() {
+        let t = transform();
+        let ((tx, ty, tz), (rx, ry, rz)) = t.components();
+        assert_float_eq!([mm_(tx), mm_(ty), mm_(tz)], [12.5, -3.0, 40.0], abs <= [1e-5; 3]);
+        assert_float_eq!([degree_(rx), degree_(ry), degree_(rz)], [10.0, -25.0, 170.0], abs <= [1e-3; 3]);
+        let reparsed: RigidTransform = t.to_string().parse().unwrap();
+        for p in points() {
+            assert_float_eq!(coordinates(reparsed.apply(p)), coordinates(t.apply(p)), abs <= [1e-3; 3]);
+        }
+        assert!("1,2,3".parse::<RigidTransform>().is_err());
+        assert!("1,2,3,4,5,x".parse::<RigidTransform>().is_err());
+    }
+
+    /// Position of the hottest voxel of the image reconstructed from `lors`
+    fn reconstructed_peak(fov: FOV, lors: &[LOR]) -> [f32; 3] {
+        let (image, _, _) = Image::mlem(fov, lors, None, None, None, None, 1).nth(19).unwrap();
+        let hottest = (0..image.data.len()).max_by(|&i, &j| image.data[i].total_cmp(&image.data[j])).unwrap();
+        coordinates(fov.voxel_centre1(hottest))
+    }
+
+    #[test]
+    fn translation_moves_reconstructed_point_source() {
+        // 5 mm voxels; the source and the translation are aligned with them
+        let fov = FOV::new_from_full_widths((mm(100.0), mm(100.0), mm(25.0)), (20, 20, 5));
+        let source = Point::new(mm(-7.5), mm(2.5), mm(0.0));
+        let lors: Vec<LOR> = (0..180).map(|i| {
+            let phi = (i as f32).to_radians();
+            let dz = 10.0 * ((i % 5) as f32 - 2.0);
+            let (dx, dy) = (300.0 * phi.cos(), 300.0 * phi.sin());
+            let end = |s: f32| Point::new(source.x + mm(s * dx), source.y + mm(s * dy), source.z + mm(s * dz));
+            LOR { p1: end(-1.0), p2: end(1.0), dt: ns(0.0), corrections: Corrections::NONE }
+        }).collect();
+        let shift: RigidTransform = "10,-15,5,0,0,0".parse().unwrap();
+        let moved: Vec<LOR> = lors.iter().map(|lor| shift.apply_lor(lor)).collect();
+
+        let before = reconstructed_peak(fov, &lors);
+        let after  = reconstructed_peak(fov, &moved);
+        assert_float_eq!(before, coordinates(source), abs <= [1e-4; 3]);
+        assert_float_eq!(after, [before[0] + 10.0, before[1] - 15.0, before[2] + 5.0], abs <= [1e-4; 3]);
+    }
+}
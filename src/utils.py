@@ -39,8 +39,26 @@ def show_image(image, *, axis, slice_, extent, shape, ax=plt):
     if axis == 'z': ax.imshow(image[:, :, s].T , extent = [-xe,xe, -ye,ye], origin = 'lower')
 
 
+VERSIONED_MAGIC = b'\x89PETALO-IMAGE\r\n\x1a'
+
+def read_versioned_raw(buffer, header_only=False):
+    """Read an image in the versioned format (see io/raw.rs). The data are
+    returned with x varying fastest, irrespective of the order in the file."""
+    version, header_length, dtype, order = struct.unpack_from('<HIBB', buffer, 16)
+    pixels = struct.unpack_from('<III', buffer, 24)
+    mm     = struct.unpack_from('<fff', buffer, 36)
+    if header_only:
+        return pixels, mm
+    data = np.frombuffer(buffer[header_length:], dtype = ('<f4', '<f8')[dtype])
+    if order == 1:
+        data = data.reshape(pixels).flatten(order='F')
+    return (pixels, mm), tuple(data.astype(np.float32))
+
+
 def read_raw(filename, header_only=False, end='>'):
     buffer = open(filename, 'rb').read()
+    if buffer.startswith(VERSIONED_MAGIC):
+        return read_versioned_raw(buffer, header_only)
     metadata_length = 18
     metadata = buffer[: metadata_length  ]
     data     = buffer[  metadata_length :]
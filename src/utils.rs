@@ -2,9 +2,14 @@ use std::error::Error;
 use std::ops::{Bound, Range};
 
 use crate::{Timef32, Lengthf32, BoundPair};
-use crate::{Point, Ratio};
+use crate::{Length, Point, Time};
+use crate::fov::FOV;
+use crate::gauss::TofCutoff;
 use crate::system_matrix::LOR;
-use geometry::units::{mm, ns, ratio};
+use geometry::units::{cm, mm, ns, ps};
+use geometry::uom::ConstZero;
+
+pub mod stats;
 
 pub fn parse_range<T: std::str::FromStr>(s: &str) -> Result<Range<T>, <T as std::str::FromStr>::Err> {
     let v = s.split("..").collect::<Vec<_>>();
@@ -52,33 +57,160 @@ pub fn parse_triplet<T: std::str::FromStr>(s: &str) -> Result<(T,T,T), <T as std
     Ok((x, y, z))
 }
 
+/// LOR from the `Display` form of `LOR`, or from whitespace-separated values:
+/// either 8, `t1 t2 x1 y1 z1 x2 y2 z2`, or 7, `dt=<dt> x1 y1 z1 x2 y2 z2`,
+/// where `dt` is `t2 - t1`. Each value may carry a unit: `ps` or `ns` for
+/// times, `mm` or `cm` for positions. Without one, times are in ns and
+/// positions in mm. For example `dt=200ps  -10cm 2cm -9cm  10cm 6cm 1cm`.
 pub fn parse_lor(s: &str) -> Result<LOR, Box<dyn Error>> {
+    if let Some(rest) = s.trim().strip_prefix("LOR") {
+        let n = rest.replace("->", " ").replace(|c| matches!(c, '(' | ')' | ','), " ");
+        let n = n.split_whitespace().collect::<Vec<_>>();
+        if n.len() != 10 || n[6] != "mm" || n[7] != "dt" || n[9] != "ps" {
+            return Err(format!("Expected 'LOR (x1, y1, z1) -> (x2, y2, z2) mm, dt t ps', got '{s}'").into())
+        }
+        let xyz = |i: usize| -> Result<Point, Box<dyn Error>> {
+            Ok(Point::new(mm(n[i].parse()?), mm(n[i+1].parse()?), mm(n[i+2].parse()?)))
+        };
+        let dt = ps(n[8].parse::<Timef32>()?);
+        return Ok(LOR { dt, ..LOR::new(Time::ZERO, Time::ZERO, xyz(0)?, xyz(3)?) })
+    }
+
     let n = s.split_whitespace().collect::<Vec<_>>();
-    assert!(n.len() == 8);
+    if let Some(token) = n.iter().skip(1).find(|t| t.starts_with("dt=")) {
+        return Err(format!("'{token}' must come first, in place of t1 and t2").into())
+    }
+    let (dt, xyz) = match n.first().and_then(|t| t.strip_prefix("dt=")) {
+        Some(dt) => {
+            if n.len() != 7 { return Err(format!("Expected dt=<dt> and 6 positions: dt=dt x1 y1 z1 x2 y2 z2, got '{s}'").into()) }
+            (parse_time(dt, "dt")?, &n[1..])
+        }
+        None => {
+            if n.len() != 8 { return Err(format!("Expected 8 values: t1 t2 x1 y1 z1 x2 y2 z2, or 7: dt=dt x1 y1 z1 x2 y2 z2, got '{s}'").into()) }
+            (parse_time(n[1], "t2")? - parse_time(n[0], "t1")?, &n[2..])
+        }
+    };
+
+    let names = ["x1", "y1", "z1", "x2", "y2", "z2"];
+    let mut v = [Length::ZERO; 6];
+    for ((value, &token), name) in v.iter_mut().zip(xyz).zip(names) { *value = parse_length(token, name)? }
+    let p1 = Point::new(v[0], v[1], v[2]);
+    let p2 = Point::new(v[3], v[4], v[5]);
+    Ok(LOR { dt, ..LOR::new(Time::ZERO, Time::ZERO, p1, p2) })
+}
+
+/// Split `token` into its number and its (possibly empty) alphabetic unit suffix
+fn number_and_unit(token: &str) -> (&str, &str) {
+    let number = token.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    (number, &token[number.len()..])
+}
 
-    let t1 = ns(n[0].parse::<Timef32>()?);
-    let t2 = ns(n[1].parse::<Timef32>()?);
+/// Time in `token` (`parse_lor`'s `name`), in ps, ns, or ns when no unit is given
+fn parse_time(token: &str, name: &str) -> Result<Time, String> {
+    let (number, unit) = number_and_unit(token);
+    let unit: fn(Timef32) -> Time = match unit {
+        "ps"        => ps,
+        "ns" | ""   => ns,
+        "mm" | "cm" => return Err(format!("'{token}': {name} is a time, but {unit} is a unit of length")),
+        _           => return Err(format!("'{token}': unknown unit of time '{unit}' for {name}: use ps or ns")),
+    };
+    number.parse().map(unit).map_err(|e| format!("'{token}': cannot read {name}: {e}"))
+}
 
-    let x1 = mm(n[2].parse::<Lengthf32>()?);
-    let y1 = mm(n[3].parse::<Lengthf32>()?);
-    let z1 = mm(n[4].parse::<Lengthf32>()?);
+/// Length in `token` (`parse_lor`'s `name`), in mm, cm, or mm when no unit is given
+fn parse_length(token: &str, name: &str) -> Result<Length, String> {
+    let (number, unit) = number_and_unit(token);
+    let unit: fn(Lengthf32) -> Length = match unit {
+        "mm" | ""   => mm,
+        "cm"        => cm,
+        "ps" | "ns" => return Err(format!("'{token}': {name} is a position, but {unit} is a unit of time")),
+        _           => return Err(format!("'{token}': unknown unit of length '{unit}' for {name}: use mm or cm")),
+    };
+    number.parse().map(unit).map_err(|e| format!("'{token}': cannot read {name}: {e}"))
+}
 
-    let x2 = mm(n[5].parse::<Lengthf32>()?);
-    let y2 = mm(n[6].parse::<Lengthf32>()?);
-    let z2 = mm(n[7].parse::<Lengthf32>()?);
+/// Split `file.h5:group/dataset` into the file path and the dataset location.
+///
+/// The split happens at the last `:`, unless that `:` belongs to a Windows
+/// drive prefix (`C:\...`), in which case there is no dataset.
+pub fn split_file_and_dataset(spec: &str) -> (&str, Option<&str>) {
+    match spec.rfind(':') {
+        Some(1) if spec.as_bytes()[0].is_ascii_alphabetic() => (spec, None),
+        Some(i) if i + 1 < spec.len() => (&spec[..i], Some(&spec[i+1..])),
+        Some(i)                       => (&spec[..i], None),
+        None                          => (spec, None),
+    }
+}
 
-    let p1 = Point::new(x1, y1, z1);
-    let p2 = Point::new(x2, y2, z2);
-    let lor = LOR::new(t1, t2, p1, p2, ratio(1.0));
-    Ok(lor)
+/// File path and dataset to be read, given an input file specification which
+/// may use the `file.h5:group/dataset` syntax, and an explicit dataset (`-d`)
+/// which, if present, takes precedence.
+pub fn resolve_file_and_dataset(spec: &str, explicit: Option<&str>, default: &str) -> (String, String) {
+    let (file, embedded) = split_file_and_dataset(spec);
+    if let (Some(explicit), Some(embedded)) = (explicit, embedded) {
+        if explicit != embedded {
+            tracing::warn!("Dataset '{explicit}' overrides '{embedded}' given in '{spec}'");
+        }
+    }
+    let dataset = explicit.or(embedded).unwrap_or(default);
+    (file.to_string(), dataset.to_string())
 }
 
 // Alias to disable structopt's type magic
 pub type CutoffOption<T> = Option<T>;
 
 
-pub fn parse_maybe_cutoff(s: &str) -> Result<CutoffOption<Ratio>, std::num::ParseFloatError> {
-    Ok(if s == "no" { None } else { Some(geometry::units::ratio(s.parse()?)) })
+/// `no` for an untruncated kernel, otherwise see `TofCutoff`'s `FromStr`
+pub fn parse_maybe_cutoff(s: &str) -> Result<CutoffOption<TofCutoff>, String> {
+    Ok(if s == "no" { None } else { Some(s.parse()?) })
+}
+
+/// Region of the FOV, written as `sphere:x,y,z,r` or `box:x0,y0,z0,x1,y1,z1`,
+/// with all values in mm.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Sphere { centre: Point, r: Length },
+    Box    { min: Point, max: Point },
+}
+
+impl Region {
+    pub fn contains(&self, p: Point) -> bool {
+        match *self {
+            Region::Sphere { centre, r } => (p - centre).norm() <= r,
+            Region::Box    { min, max  } =>
+                min.x <= p.x && p.x <= max.x &&
+                min.y <= p.y && p.y <= max.y &&
+                min.z <= p.z && p.z <= max.z,
+        }
+    }
+
+    /// Which voxels of `fov` have their centres inside the region
+    pub fn mask(&self, fov: FOV) -> Vec<bool> {
+        let [nx, ny, nz] = fov.n;
+        (0..nx * ny * nz).map(|i| self.contains(fov.voxel_centre1(i))).collect()
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (shape, numbers) = s.split_once(':')
+            .ok_or_else(|| format!("Region '{s}' should look like 'sphere:x,y,z,r' or 'box:x0,y0,z0,x1,y1,z1'"))?;
+        let n = numbers.split(',')
+            .map(|n| n.trim().parse::<Lengthf32>().map_err(|e| format!("{e} in region '{s}'")))
+            .collect::<Result<Vec<_>, _>>()?;
+        match (shape.trim(), n.as_slice()) {
+            ("sphere", &[x, y, z, r]) => Ok(Region::Sphere { centre: Point::new(mm(x), mm(y), mm(z)), r: mm(r) }),
+            ("box", &[x0, y0, z0, x1, y1, z1]) => Ok(Region::Box {
+                min: Point::new(mm(x0.min(x1)), mm(y0.min(y1)), mm(z0.min(z1))),
+                max: Point::new(mm(x0.max(x1)), mm(y0.max(y1)), mm(z0.max(z1))),
+            }),
+            ("sphere", _) => Err(format!("Sphere '{s}' needs 4 values: x,y,z,r")),
+            ("box"   , _) => Err(format!("Box '{s}' needs 6 values: x0,y0,z0,x1,y1,z1")),
+            (other   , _) => Err(format!("Unknown region shape '{other}' in '{s}'")),
+        }
+    }
 }
 
 /// Group numeric digits to facilitate reading long numbers
@@ -86,3 +218,150 @@ pub fn group_digits<F: num_format::ToFormattedString>(n: F) -> String {
     use num_format::{Locale};
     n.to_formatted_string(&Locale::en)
 }
+
+#[cfg(test)]
+mod test_file_and_dataset {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(/**/ spec                               , file                 , dataset,
+             case("data/run.h5"                      , "data/run.h5"        , None),
+             case("data/run.h5:reco_info/lors"       , "data/run.h5"        , Some("reco_info/lors")),
+             case("run.h5:lors"                      , "run.h5"             , Some("lors")),
+             case("run.h5:"                          , "run.h5"             , None),
+             case("C:\\data\\run.h5"                 , "C:\\data\\run.h5"   , None),
+             case("C:/data/run.h5"                   , "C:/data/run.h5"     , None),
+             case("C:\\data\\run.h5:reco_info/lors"  , "C:\\data\\run.h5"   , Some("reco_info/lors")),
+             case("d:run.h5:MC/primaries"            , "d:run.h5"           , Some("MC/primaries")),
+    )]
+    fn split(spec: &str, file: &str, dataset: Option<&str>) {
+        assert_eq!(split_file_and_dataset(spec), (file, dataset));
+    }
+
+    #[rstest(/**/ spec                , explicit       , file    , dataset,
+             case("run.h5"            , None           , "run.h5", "reco_info/lors"),
+             case("run.h5"            , Some("x/y")    , "run.h5", "x/y"),
+             case("run.h5:a/b"        , None           , "run.h5", "a/b"),
+             // Explicit -d wins over the combined syntax
+             case("run.h5:a/b"        , Some("x/y")    , "run.h5", "x/y"),
+             case("C:\\run.h5:a/b"    , Some("x/y")    , "C:\\run.h5", "x/y"),
+    )]
+    fn resolve(spec: &str, explicit: Option<&str>, file: &str, dataset: &str) {
+        let resolved = resolve_file_and_dataset(spec, explicit, "reco_info/lors");
+        assert_eq!(resolved, (file.to_string(), dataset.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod test_region {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn parse_sphere() {
+        let region: Region = "sphere:1,2,3,30".parse().unwrap();
+        assert_eq!(region, Region::Sphere { centre: Point::new(mm(1.0), mm(2.0), mm(3.0)), r: mm(30.0) });
+    }
+
+    #[test]
+    fn parse_box_orders_corners() {
+        let region: Region = "box:10,-5,0,-10,5,20".parse().unwrap();
+        let min = Point::new(mm(-10.0), mm(-5.0), mm( 0.0));
+        let max = Point::new(mm( 10.0), mm( 5.0), mm(20.0));
+        assert_eq!(region, Region::Box { min, max });
+    }
+
+    #[rstest(/**/ spec,
+             case("sphere:1,2,3"),
+             case("box:1,2,3,4,5"),
+             case("cone:1,2,3,4"),
+             case("sphere:1,2,three,4"),
+             case("1,2,3,4"),
+    )]
+    fn rejects(spec: &str) {
+        assert!(spec.parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn mask_selects_voxels_inside() {
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (10, 10, 10));
+        let everything: Region = "box:-5,-5,-5,5,5,5".parse().unwrap();
+        assert!(everything.mask(fov).into_iter().all(|inside| inside));
+        // Voxel centres at ±0.5 mm: the 8 central voxels
+        let centre: Region = "sphere:0,0,0,0.9".parse().unwrap();
+        assert_eq!(centre.mask(fov).into_iter().filter(|&inside| inside).count(), 8);
+    }
+}
+
+#[cfg(test)]
+mod test_parse_lor {
+    use super::*;
+    use geometry::units::{mm_, ps_};
+    use float_eq::assert_float_eq;
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    fn fields(lor: &LOR) -> [u32; 7] {
+        let (p, q) = (lor.p1, lor.p2);
+        [mm_(p.x), mm_(p.y), mm_(p.z), mm_(q.x), mm_(q.y), mm_(q.z), ps_(lor.dt)].map(f32::to_bits)
+    }
+
+    #[test]
+    fn display() {
+        let lor = LOR { dt: ps(200.0), ..LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-300.0), mm(0.0), mm(12.5)), Point::new(mm(300.0), mm(-1.25), mm(0.0))) };
+        assert_eq!(lor.to_string(), "LOR (-300, 0, 12.5) -> (300, -1.25, 0) mm, dt 200 ps");
+    }
+
+    #[test]
+    fn numbers() {
+        let lor = parse_lor("0 0.2  -300 0 12.5  300 -1.25 0").unwrap();
+        let expected = LOR::new(ns(0.0), ns(0.2), Point::new(mm(-300.0), mm(0.0), mm(12.5)), Point::new(mm(300.0), mm(-1.25), mm(0.0)));
+        assert_eq!(fields(&lor), fields(&expected));
+        assert!(parse_lor("0 0.2 -300 0 12.5 300 -1.25").is_err());
+        assert!(parse_lor("LOR (1, 2, 3) -> (4, 5, 6) mm, dt 7 ns").is_err());
+    }
+
+    #[rstest(/**/ s,
+             case("dt=200ps  -10cm 2cm -9cm   10cm 6cm 1cm"),
+             case("dt=0.2ns  -100 20 -90   100 60 10"),
+             case("dt=0.2    -100mm 20 -90   100 60 10mm"),
+             case("100ps 300ps  -10cm 20mm -90   100 6cm 10"),
+             case("0.1 300ps  -100 20 -90   100 60 10"),
+    )]
+    fn units(s: &str) {
+        let expected = LOR { dt: ps(200.0), ..LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(-100.0), mm(20.0), mm(-90.0)), Point::new(mm(100.0), mm(60.0), mm(10.0))) };
+        let lor = parse_lor(s).unwrap();
+        assert_float_eq!(ps_(lor.dt), 200.0, abs <= 1e-3);
+        for (got, want) in [(lor.p1, expected.p1), (lor.p2, expected.p2)] {
+            assert_float_eq!([mm_(got.x), mm_(got.y), mm_(got.z)], [mm_(want.x), mm_(want.y), mm_(want.z)], abs <= [1e-4; 3]);
+        }
+    }
+
+    #[rstest(/**/ s                          , named,
+             case("dt=200us  0 0 0  1 1 1"    , "200us"),         // unknown unit of time
+             case("0 200mm  0 0 0  1 1 1"     , "200mm"),         // length for a time
+             case("dt=200ps  0 0 3ns  1 1 1"  , "3ns"),           // time for a length
+             case("0 1  0 0 0  1 1 1m"        , "1m"),            // unknown unit of length
+             case("0 1  0 x0 0  1 1 1"        , "x0"),            // not a number
+             case("0 dt=200ps  0 0 0  1 1 1"  , "dt=200ps"),      // dt mixed with t1 and t2
+             case("dt=200ps 0  0 0 0  1 1 1"  , "dt=dt x1 y1 z1"), // dt with 8 values
+             case("0 1  0 0 0  1 1"           , "t1 t2 x1 y1"),   // t1 and t2 with 7 values
+    )]
+    fn rejected(s: &str, named: &str) {
+        let error = parse_lor(s).unwrap_err().to_string();
+        assert!(error.contains(named), "'{error}' does not mention '{named}'");
+    }
+
+    proptest! {
+        #[test]
+        fn display_round_trips(
+            x1 in -1e4..1e4_f32, y1 in -1e4..1e4_f32, z1 in -1e4..1e4_f32,
+            x2 in -1e4..1e4_f32, y2 in -1e4..1e4_f32, z2 in -1e4..1e4_f32,
+            dt in -1e4..1e4_f32,
+        ) {
+            let lor = LOR { dt: ps(dt), ..LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(x1), mm(y1), mm(z1)), Point::new(mm(x2), mm(y2), mm(z2))) };
+            let parsed = parse_lor(&lor.to_string()).unwrap();
+            prop_assert_eq!(fields(&parsed), fields(&lor));
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Arithmetic and formatting for the statistics which are reported along the
+//! way: fractions kept by cuts, durations of phases and rates of processing.
+//!
+//! Percentages are never rounded to 0% or 100% unless they are exactly that,
+//! so that a cut which keeps 0.4% of the data does not appear to keep none.
+
+use std::time::Duration;
+
+use super::group_digits;
+
+/// `100 * numerator / denominator`; 0 if `denominator` is 0
+pub fn percentage(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 { return 0.0 }
+    100.0 * numerator as f64 / denominator as f64
+}
+
+/// Items processed per second; NaN if `duration` is zero
+pub fn rate(count: usize, duration: Duration) -> f64 {
+    let seconds = duration.as_secs_f64();
+    if seconds == 0.0 { return f64::NAN }
+    count as f64 / seconds
+}
+
+/// `percentage(numerator, denominator)` to one decimal place, such as `12.3%`,
+/// but `<0.1%` or `>99.9%` rather than rounding to 0% or 100%
+pub fn format_percentage(numerator: usize, denominator: usize) -> String {
+    let pct = percentage(numerator, denominator);
+    if numerator == 0 || denominator == 0 { return "0%".into() }
+    if numerator == denominator { return "100%".into() }
+    if pct < 0.1  { return "<0.1%" .into() }
+    if pct > 99.9 { return ">99.9%".into() }
+    format!("{pct:.1}%")
+}
+
+/// `1,234 of 5,678 (21.7%)`
+pub fn format_fraction(numerator: usize, denominator: usize) -> String {
+    format!("{} of {} ({})", group_digits(numerator), group_digits(denominator), format_percentage(numerator, denominator))
+}
+
+/// `1,235 ms`, rounded to the nearest ms, or `0.4 ms` to a tenth of a ms for
+/// durations shorter than 10 ms
+pub fn format_millis(duration: Duration) -> String {
+    let ms = duration.as_secs_f64() * 1e3;
+    if ms < 10.0 { format!("{ms:.1} ms") } else { format!("{} ms", group_digits(ms.round() as u64)) }
+}
+
+/// `12,345 LORs/s`, for `count` `items` processed in `duration`; `? LORs/s`
+/// if `duration` is zero
+pub fn format_rate(count: usize, duration: Duration, items: &str) -> String {
+    let rate = rate(count, duration);
+    if rate.is_finite() { format!("{} {items}/s", group_digits(rate.round() as u64)) } else { format!("? {items}/s") }
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(/**/ numerator, denominator, expected,
+             case(        0,          10, "0%"    ),   // nothing kept
+             case(       10,          10, "100%"  ),   // everything kept
+             case(        0,           0, "0%"    ),   // nothing at all
+             case(        4,        1000, "0.4%"  ),   // not 0%, as integer division would have it
+             case(        1,     100_000, "<0.1%" ),
+             case(   99_999,     100_000, ">99.9%"),
+             case(        1,           3, "33.3%" ),
+    )]
+    fn percentages(numerator: usize, denominator: usize, expected: &str) {
+        assert_eq!(format_percentage(numerator, denominator), expected);
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(format_fraction(1234, 5678), "1,234 of 5,678 (21.7%)");
+        assert_eq!(format_fraction(0, 0), "0 of 0 (0%)");
+    }
+
+    #[rstest(/**/ duration                          , expected,
+             case(Duration::ZERO                   , "0.0 ms"),
+             case(Duration::from_micros(400)       , "0.4 ms"),
+             case(Duration::from_micros(1_234_600) , "1,235 ms"),
+    )]
+    fn millis(duration: Duration, expected: &str) {
+        assert_eq!(format_millis(duration), expected);
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(format_rate(3000, Duration::from_millis(1500), "LORs"), "2,000 LORs/s");
+        assert_eq!(format_rate(0, Duration::from_secs(1), "LORs"), "0 LORs/s");
+        assert_eq!(format_rate(10, Duration::ZERO, "LORs"), "? LORs/s");
+        assert!(rate(10, Duration::ZERO).is_nan());
+        assert_eq!(percentage(1, 0), 0.0);
+    }
+}
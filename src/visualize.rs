@@ -1,3 +1,6 @@
+mod volume;
+pub use volume::*;
+
 use kiss3d::light::Light;
 use kiss3d::window::Window;
 use kiss3d::event::{Action, WindowEvent};
@@ -5,8 +8,12 @@ use kiss3d::scene::SceneNode;
 use kiss3d::camera::ArcBall;
 use kiss3d::nalgebra::{Point3, Translation3};
 
+use std::error::Error;
+use std::ops::Range;
+
 use crate::Vectorf32;
-use crate::{Time, Ratio};
+use crate::Time;
+use crate::gauss::TofCutoff;
 use crate::system_matrix::LOR;
 use crate::fov::FOV;
 
@@ -32,11 +39,30 @@ pub struct Scene {
     // Parameters which define the scene
     lor: LOR,
     fov: FOV,
-    lines: Vec<(Point3<f32>, Point3<f32>, Point3<f32>)>,
+    /// Axes and FOV frame
+    lines: Vec<Line>,
+    lor_lines: Vec<Line>,
+}
+
+/// Endpoints and colour
+type Line = (Point3<f32>, Point3<f32>, Point3<f32>);
+
+/// Line showing `lor` in colour `[r, g, b]`
+fn lor_line((lor, [r, g, b]): &(LOR, [f32; 3])) -> Line {
+    let p1_f32 = Point3::new(mm_(lor.p1.x), mm_(lor.p1.y), mm_(lor.p1.z));
+    let p2_f32 = Point3::new(mm_(lor.p2.x), mm_(lor.p2.y), mm_(lor.p2.z));
+    (p1_f32, p2_f32, Point3::new(*r, *g, *b))
 }
 
 impl Scene {
     pub fn new(lor: LOR, fov: FOV) -> Self {
+        Self::with_coloured_lors(&[(lor, [1.0, 1.0, 0.0])], fov)
+    }
+
+    /// Scene showing several LORs, each in its own colour. Voxels are placed
+    /// along the first one.
+    pub fn with_coloured_lors(lors: &[(LOR, [f32; 3])], fov: FOV) -> Self {
+        let lor = lors[0].0;
         let mut window = Window::new("LOR weights");
         window.set_light(Light::StickToCamera);
 
@@ -53,11 +79,6 @@ impl Scene {
         let y_axis_colour = Point3::new(0.0, 1.0, 0.0);
         let z_axis_colour = Point3::new(0.0, 0.0, 1.0);
 
-        // LOR line
-        let p1_f32 = Point3::new(mm_(lor.p1.x), mm_(lor.p1.y), mm_(lor.p1.z));
-        let p2_f32 = Point3::new(mm_(lor.p2.x), mm_(lor.p2.y), mm_(lor.p2.z));
-        let lor_colour = Point3::new(1.0, 1.0, 0.0);
-
         // FOV frame
         let w = Vectorf32::from(fov.half_width);
         let (bwx, bwy, bwz) = (w.x as f32, w.y as f32, w.z as f32);
@@ -73,21 +94,20 @@ impl Scene {
 
         // Turn the above endpoints into actual lines
         let lines = vec![(x_axis_lo, x_axis_hi, x_axis_colour),
-                         (y_axis_lo, y_axis_hi, y_axis_colour),
-                         (z_axis_lo, z_axis_hi, z_axis_colour),
-                         (p1_f32   , p2_f32  ,     lor_colour),
-                         (box_000  , box_001 ,     box_colour),
-                         (box_001  , box_011 ,     box_colour),
-                         (box_011  , box_010 ,     box_colour),
-                         (box_010  , box_000 ,     box_colour),
-                         (box_100  , box_101 ,     box_colour),
-                         (box_101  , box_111 ,     box_colour),
-                         (box_111  , box_110 ,     box_colour),
-                         (box_110  , box_100 ,     box_colour),
-                         (box_000  , box_100 ,     box_colour),
-                         (box_001  , box_101 ,     box_colour),
-                         (box_011  , box_111 ,     box_colour),
-                         (box_010  , box_110 ,     box_colour),
+                             (y_axis_lo, y_axis_hi, y_axis_colour),
+                             (z_axis_lo, z_axis_hi, z_axis_colour),
+                             (box_000  , box_001 ,     box_colour),
+                             (box_001  , box_011 ,     box_colour),
+                             (box_011  , box_010 ,     box_colour),
+                             (box_010  , box_000 ,     box_colour),
+                             (box_100  , box_101 ,     box_colour),
+                             (box_101  , box_111 ,     box_colour),
+                             (box_111  , box_110 ,     box_colour),
+                             (box_110  , box_100 ,     box_colour),
+                             (box_000  , box_100 ,     box_colour),
+                             (box_001  , box_101 ,     box_colour),
+                             (box_011  , box_111 ,     box_colour),
+                             (box_010  , box_110 ,     box_colour),
 
         ];
 
@@ -99,18 +119,24 @@ impl Scene {
             lor,
             fov,
             lines,
+            lor_lines: lors.iter().map(lor_line).collect(),
         }
     }
 
     fn clear(&mut self) {
-        println!("Clearing ... supposedly");
-        for v in &mut self.voxels {
-            self.window.remove_node(v);
-            v.unlink();
+        for mut v in self.voxels.drain(..) {
+            self.window.remove_node(&mut v);
         }
     }
 
-    pub fn place_voxels(&mut self, shape: Shape, cutoff: Option<Ratio>, sigma: Option<Time>) {
+    /// Replace the LOR, and remove its voxels
+    fn show(&mut self, lor: LOR) {
+        self.clear();
+        self.lor = lor;
+        self.lor_lines = vec![lor_line(&(lor, [1.0, 1.0, 0.0]))];
+    }
+
+    pub fn place_voxels(&mut self, shape: Shape, cutoff: Option<TofCutoff>, sigma: Option<Time>) {
 
         let active_voxels = self.lor.active_voxels(&self.fov, cutoff, sigma);
 
@@ -124,7 +150,6 @@ impl Scene {
         let bsize = Vectorf32::from(self.fov.half_width);
         let (bdx, bdy, bdz) = (bsize.x as f32, bsize.y as f32, bsize.z as f32);
         let (vdx, vdy, vdz) = (vsize.x as f32, vsize.y as f32, vsize.z as f32);
-        let mut voxels = Vec::with_capacity(self.fov.n[0] * self.fov.n[1]);
         let half_fov = Translation3::new(-bdx, -bdy, -bdz);
         let half_voxel = Translation3::new(vdx / 2.0,
                                            vdy / 2.0,
@@ -142,13 +167,13 @@ impl Scene {
             v.append_translation(&half_voxel);
             v.append_translation(&Translation3::new(i[0] as f32 * vdx, i[1] as f32 * vdy, i[2] as f32 * vdz));
             v.set_color(relative_weight, 0.1, 0.0);
-            voxels.push(v);
+            self.voxels.push(v);
             //v.set_material(material);
         }
     }
 
     fn draw_lines(&mut self) {
-        for line in &self.lines {
+        for line in self.lines.iter().chain(&self.lor_lines) {
             self.window.draw_line(&line.0, &line.1, &line.2);
         }
     }
@@ -168,13 +193,18 @@ impl Scene {
         )
     }
 
-    fn main_loop(&mut self) {
+    fn main_loop(&mut self) { self.main_loop_with(|_, _| {}) }
+
+    /// As `main_loop`, passing key presses which it does not handle itself to
+    /// `on_key`
+    fn main_loop_with(&mut self, mut on_key: impl FnMut(&mut Self, kiss3d::event::Key)) {
         while self.window.render_with_camera(&mut self.camera) {
 
             // Draw axes and LOR
             self.draw_lines();
 
             // Deal with events
+            let mut pressed = vec![];
             for event in self.window.events().iter() {
                 use kiss3d::event::Key;
                 match event.value {
@@ -188,31 +218,130 @@ impl Scene {
                     WindowEvent::Key(Key::T, Action::Press, _) => {
                         println!("TODO: Toggle / change cutoff");
                     },
+                    WindowEvent::Key(key, Action::Press, _) => pressed.push(key),
                     _ => {}
                 }
             }
+            for key in pressed { on_key(self, key) }
         }
     }
 }
 
-pub fn lor_weights(lor: LOR, fov: FOV, shape: Shape, cutoff: Option<Ratio>, sigma: Option<Time>) {
+pub fn lor_weights(lor: LOR, fov: FOV, shape: Shape, cutoff: Option<TofCutoff>, sigma: Option<Time>) {
     let mut scene = Scene::new(lor, fov);
     scene.place_voxels(shape, cutoff, sigma);
     scene.main_loop();
 }
 
+/// Show `lors`, each in its own colour, with the voxels of the first one
+pub fn coloured_lors(lors: &[(LOR, [f32; 3])], fov: FOV, shape: Shape, cutoff: Option<TofCutoff>, sigma: Option<Time>) {
+    let mut scene = Scene::with_coloured_lors(lors, fov);
+    scene.place_voxels(shape, cutoff, sigma);
+    scene.main_loop();
+}
+
+/// Show one LOR at a time, starting with `first`, stepping through them with
+/// the `n` and `p` keys. `step` returns the LOR to show next, or `None` to stay
+/// on the current one.
+pub fn browse_lors(first: LOR, fov: FOV, shape: Shape, cutoff: Option<TofCutoff>, sigma: Option<Time>,
+                   mut step: impl FnMut(Step) -> Option<LOR>) {
+    let mut scene = Scene::new(first, fov);
+    scene.place_voxels(shape.clone(), cutoff, sigma);
+    scene.main_loop_with(|scene, key| {
+        use kiss3d::event::Key;
+        let direction = match key {
+            Key::N => Step::Next,
+            Key::P => Step::Previous,
+            _      => return,
+        };
+        if let Some(lor) = step(direction) {
+            scene.show(lor);
+            scene.place_voxels(shape.clone(), cutoff, sigma);
+        }
+    });
+}
+
+/// Direction in which to step through events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step { Next, Previous }
+
+/// The events of a dataset, held in memory a window of consecutive events at a
+/// time, for stepping through them one by one. `load` reads a range of rows of
+/// the dataset, which holds `n_events` in all.
+pub struct EventBrowser<L> {
+    load: L,
+    n_events: usize,
+    window_size: usize,
+    /// Index of the first event in `window`
+    start: usize,
+    window: Vec<LOR>,
+    current: usize,
+}
+
+impl<L> EventBrowser<L>
+where
+    L: FnMut(Range<usize>) -> Result<Vec<LOR>, Box<dyn Error>>
+{
+    /// Load the window of `window_size` events starting at `first`
+    pub fn new(load: L, n_events: usize, window_size: usize, first: usize) -> Result<Self, Box<dyn Error>> {
+        if first >= n_events {
+            return Err(format!("Event {first} is out of range: the dataset has {n_events} events").into())
+        }
+        let mut browser = Self { load, n_events, window_size: window_size.max(1), start: first, window: vec![], current: first };
+        browser.load_from(first)?;
+        Ok(browser)
+    }
+
+    fn load_from(&mut self, start: usize) -> Result<(), Box<dyn Error>> {
+        let end = (start + self.window_size).min(self.n_events);
+        let window = (self.load)(start..end)?;
+        if window.len() != end - start {
+            return Err(format!("Expected {} events from rows {start}..{end}, got {}", end - start, window.len()).into())
+        }
+        self.start = start;
+        self.window = window;
+        Ok(())
+    }
+
+    /// Index, in the dataset, of the current event
+    pub fn index(&self) -> usize { self.current }
+
+    pub fn current(&self) -> LOR { self.window[self.current - self.start] }
+
+    /// Move to the next or previous event, loading its window if necessary.
+    /// `None` at either end of the dataset, where the current event is kept.
+    pub fn step(&mut self, step: Step) -> Result<Option<LOR>, Box<dyn Error>> {
+        let target = match step {
+            Step::Next     => self.current + 1,
+            Step::Previous => match self.current.checked_sub(1) { Some(target) => target, None => return Ok(None) },
+        };
+        if target >= self.n_events { return Ok(None) }
+        // Going backwards, load the window which ends at the target
+        if target < self.start                           { self.load_from((target + 1).saturating_sub(self.window_size))? }
+        else if target >= self.start + self.window.len() { self.load_from(target)? }
+        self.current = target;
+        Ok(Some(self.current()))
+    }
+}
+
+/// Colour of `value` on a scale running from blue at `lo` to red at `hi`,
+/// through green. Values outside the range are clamped.
+pub fn colour_scale(value: f32, lo: f32, hi: f32) -> [f32; 3] {
+    let t = if hi > lo { ((value - lo) / (hi - lo)).clamp(0.0, 1.0) } else { 0.5 };
+    [t, 1.0 - (2.0 * t - 1.0).abs(), 1.0 - t]
+}
+
 pub fn vislor_command(fov: &FOV, lor: &LOR) -> String {
     let fov_half_width = Vectorf32::from(fov.half_width);
     format!(
-        "cargo run --bin vislor -- box --fov-size {vx},{vy},{vz} --nvoxels {nx},{ny},{nz} --lor '{t1} {t2}   {x1} {y1} {z1}    {x2} {y2} {z2}'",
+        "cargo run --bin vislor -- box --fov-size {vx},{vy},{vz} --nvoxels {nx},{ny},{nz} --lor 'dt={dt}ps   {x1} {y1} {z1}    {x2} {y2} {z2}'",
         vx = fov_half_width.x * 2.0,
         vy = fov_half_width.y * 2.0,
         vz = fov_half_width.z * 2.0,
         nx = fov.n[0],
         ny = fov.n[1],
         nz = fov.n[2],
-        t1 = 0.0,
-        t2 = ps_(lor.dt),
+        dt = ps_(lor.dt),
         x1 = mm_(lor.p1.x),
         y1 = mm_(lor.p1.y),
         z1 = mm_(lor.p1.z),
@@ -221,3 +350,89 @@ pub fn vislor_command(fov: &FOV, lor: &LOR) -> String {
         z2 = mm_(lor.p2.z),
     )
 }
+
+#[cfg(test)]
+mod test_colour_scale {
+    use super::*;
+
+    #[test]
+    fn gradient_from_blue_to_red() {
+        assert_eq!(colour_scale(1.0, 1.0, 3.0), [0.0, 0.0, 1.0]);
+        assert_eq!(colour_scale(2.0, 1.0, 3.0), [0.5, 1.0, 0.5]);
+        assert_eq!(colour_scale(3.0, 1.0, 3.0), [1.0, 0.0, 0.0]);
+        assert_eq!(colour_scale(9.0, 1.0, 3.0), colour_scale(3.0, 1.0, 3.0));
+        let reds = (0..=10).map(|i| colour_scale(i as f32, 0.0, 10.0)[0]).collect::<Vec<_>>();
+        assert!(reds.windows(2).all(|w| w[0] < w[1]));
+    }
+}
+
+#[cfg(test)]
+mod test_event_browser {
+    use super::*;
+    use crate::Point;
+    use geometry::units::mm;
+
+    /// A LOR which identifies event `i`
+    fn event(i: usize) -> LOR {
+        LOR::new(Time::ZERO, Time::ZERO, Point::new(mm(i as f32), mm(0.0), mm(0.0)), Point::new(mm(0.0), mm(100.0), mm(0.0)))
+    }
+
+    fn index(lor: LOR) -> usize { mm_(lor.p1.x) as usize }
+
+    /// Browser of `n_events`, recording the ranges loaded in `loads`
+    fn browser(n_events: usize, window: usize, first: usize, loads: &mut Vec<Range<usize>>)
+               -> EventBrowser<impl FnMut(Range<usize>) -> Result<Vec<LOR>, Box<dyn Error>> + '_> {
+        let load = move |range: Range<usize>| {
+            loads.push(range.clone());
+            Ok(range.map(event).collect())
+        };
+        EventBrowser::new(load, n_events, window, first).unwrap()
+    }
+
+    #[test]
+    fn stops_at_start_of_dataset() -> Result<(), Box<dyn Error>> {
+        let mut loads = vec![];
+        let mut b = browser(10, 4, 1, &mut loads);
+        assert_eq!(b.step(Step::Previous)?.map(index), Some(0));
+        assert_eq!(b.step(Step::Previous)?, None);
+        assert_eq!(b.index(), 0);
+        assert_eq!(b.step(Step::Next)?.map(index), Some(1));
+        drop(b);
+        assert_eq!(loads, vec![1..5, 0..1]);
+        Ok(())
+    }
+
+    #[test]
+    fn stops_at_end_of_dataset() -> Result<(), Box<dyn Error>> {
+        let mut loads = vec![];
+        let mut b = browser(10, 4, 0, &mut loads);
+        let seen: Vec<usize> = std::iter::from_fn(|| b.step(Step::Next).unwrap()).map(index).collect();
+        assert_eq!(seen, (1..10).collect::<Vec<_>>());
+        assert_eq!(b.step(Step::Next)?, None);
+        assert_eq!(index(b.current()), 9);
+        drop(b);
+        // The last window is truncated at the end of the dataset
+        assert_eq!(loads, vec![0..4, 4..8, 8..10]);
+        Ok(())
+    }
+
+    #[test]
+    fn stepping_back_loads_window_ending_at_target() -> Result<(), Box<dyn Error>> {
+        let mut loads = vec![];
+        let mut b = browser(20, 5, 10, &mut loads);
+        assert_eq!(b.step(Step::Previous)?.map(index), Some(9));
+        // Within the window just loaded: no further loads
+        for expected in (5..9).rev() { assert_eq!(b.step(Step::Previous)?.map(index), Some(expected)) }
+        drop(b);
+        assert_eq!(loads, vec![10..15, 5..10]);
+        Ok(())
+    }
+
+    #[test]
+    fn first_event_must_exist() {
+        let load = |range: Range<usize>| -> Result<Vec<LOR>, Box<dyn Error>> { Ok(range.map(event).collect()) };
+        assert!(EventBrowser::new(load, 3, 10, 3).is_err());
+        let mut b = EventBrowser::new(load, 3, 10, 2).unwrap();
+        assert_eq!(b.step(Step::Next).unwrap(), None);
+    }
+}
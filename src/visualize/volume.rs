@@ -0,0 +1,261 @@
+//! Offline volume rendering of reconstructed images.
+//!
+//! Rays are cast in parallel (orthographic projection) through the FOV, the
+//! image is sampled trilinearly at regular intervals along each ray, each
+//! sample is mapped to colour and opacity by a [`TransferFunction`], and the
+//! samples are alpha-composited front-to-back.
+
+use std::str::FromStr;
+
+use ::image::{DynamicImage, ImageOutputFormat, ImageResult, Rgba, RgbaImage};
+
+use crate::{Angle, Length, Lengthf32, Point};
+use crate::image::Image;
+use geometry::units::{mm, mm_, radian_};
+
+/// Orthographic camera looking at the image from a given direction.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraParams {
+    /// Point at the centre of the rendered view
+    pub look_at: Point,
+    /// Angle around the z-axis, of the camera position, measured from the x-axis
+    pub azimuth: Angle,
+    /// Angle of the camera position above the x-y plane
+    pub elevation: Angle,
+    /// Full width of the region covered by the rendered view
+    pub width: Length,
+}
+
+type V3 = [Lengthf32; 3];
+
+impl CameraParams {
+    /// Unit vectors: direction of rays, and the horizontal and vertical axes of
+    /// the view plane.
+    fn basis(&self) -> (V3, V3, V3) {
+        let (az, el) = (radian_(self.azimuth), radian_(self.elevation));
+        let forward = [-el.cos() * az.cos(), -el.cos() * az.sin(), -el.sin()];
+        let right   = [-az.sin()           ,  az.cos()           ,  0.0     ];
+        let up      = [-el.sin() * az.cos(), -el.sin() * az.sin(),  el.cos()];
+        (forward, right, up)
+    }
+}
+
+/// Piecewise-linear map from (normalized) voxel value to RGBA.
+///
+/// Voxel values are divided by the maximum value in the image before being
+/// looked up, so the control points should lie in `[0, 1]`. Values below the
+/// first or above the last control point take the colour of that point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferFunction {
+    points: Vec<(f32, [f32; 4])>,
+}
+
+impl TransferFunction {
+    pub fn new(mut points: Vec<(f32, [f32; 4])>) -> Self {
+        if points.is_empty() { panic!("Transfer function needs at least one control point") }
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("NaN in transfer function"));
+        Self { points }
+    }
+
+    /// Grey-scale ramp, transparent at 0 and opaque white at 1
+    pub fn ramp() -> Self {
+        Self::new(vec![(0.0, [0.0, 0.0, 0.0, 0.0]), (1.0, [1.0, 1.0, 1.0, 1.0])])
+    }
+
+    pub fn rgba(&self, value: f32) -> [f32; 4] {
+        let first = self.points[0];
+        if value <= first.0 { return first.1 }
+        for pair in self.points.windows(2) {
+            let ((lo, a), (hi, b)) = (pair[0], pair[1]);
+            if value <= hi {
+                let f = if hi > lo { (value - lo) / (hi - lo) } else { 1.0 };
+                return [0, 1, 2, 3].map(|c| a[c] + (b[c] - a[c]) * f)
+            }
+        }
+        self.points[self.points.len() - 1].1
+    }
+}
+
+/// Parse control points written as `value:r,g,b,a` separated by whitespace,
+/// for example `'0:0,0,0,0  0.3:1,0,0,0.05  1:1,1,0,1'`.
+impl FromStr for TransferFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_point = |p: &str| -> Result<(f32, [f32; 4]), String> {
+            let (value, colour) = p.split_once(':')
+                .ok_or_else(|| format!("Expected 'value:r,g,b,a', got '{p}'"))?;
+            let value = value.parse::<f32>().map_err(|e| format!("{e} in '{p}'"))?;
+            let colour = colour.split(',')
+                .map(|c| c.parse::<f32>().map_err(|e| format!("{e} in '{p}'")))
+                .collect::<Result<Vec<_>, _>>()?;
+            if colour.len() != 4 { return Err(format!("Expected 4 colour components in '{p}'")) }
+            Ok((value, [colour[0], colour[1], colour[2], colour[3]]))
+        };
+        let points = s.split_whitespace()
+            .map(parse_point)
+            .collect::<Result<Vec<_>, _>>()?;
+        if points.is_empty() { return Err("Empty transfer function".into()) }
+        Ok(Self::new(points))
+    }
+}
+
+/// Range of ray parameter `t` for which `origin + t * direction` lies inside
+/// the axis-aligned box with the given half-widths, centred on the origin.
+fn ray_box_overlap(origin: V3, direction: V3, half_width: V3) -> Option<(f32, f32)> {
+    let (mut t_in, mut t_out) = (f32::NEG_INFINITY, f32::INFINITY);
+    for ((&o, &v), &h) in origin.iter().zip(&direction).zip(&half_width) {
+        if v.abs() < 1e-9 {
+            if o.abs() > h { return None }
+        } else {
+            let (t1, t2) = ((-h - o) / v, (h - o) / v);
+            t_in  = t_in .max(t1.min(t2));
+            t_out = t_out.min(t1.max(t2));
+        }
+    }
+    if t_in < t_out { Some((t_in, t_out)) } else { None }
+}
+
+/// Render `image` as seen from `camera`, into an RGBA picture of `out_size`
+/// (width, height) pixels.
+pub fn volume_render(image: &Image, camera: CameraParams, transfer: &TransferFunction, out_size: (u32, u32)) -> RgbaImage {
+    let (w, h) = out_size;
+    let mut out = RgbaImage::new(w, h);
+
+    let fov = image.fov;
+    let half_width = [mm_(fov.half_width.x), mm_(fov.half_width.y), mm_(fov.half_width.z)];
+    let voxel_size = [mm_(fov.voxel_size.x), mm_(fov.voxel_size.y), mm_(fov.voxel_size.z)];
+
+    // Sample twice per voxel; opacities in the transfer function refer to one voxel
+    let reference_step = voxel_size[0].min(voxel_size[1]).min(voxel_size[2]);
+    let step = reference_step / 2.0;
+
+    let max = image.data.iter().copied().fold(0.0, f32::max);
+    let normalize = if max > 0.0 { 1.0 / max } else { 0.0 };
+
+    let (forward, right, up) = camera.basis();
+    let view_w = mm_(camera.width);
+    let view_h = view_w * h as f32 / w as f32;
+    let centre = [mm_(camera.look_at.x), mm_(camera.look_at.y), mm_(camera.look_at.z)];
+
+    for py in 0..h {
+        for px in 0..w {
+            // Position of this pixel in the view plane
+            let s = ((px as f32 + 0.5) / w as f32 - 0.5) * view_w;
+            let t = (0.5 - (py as f32 + 0.5) / h as f32) * view_h;
+            let origin: V3 = [0, 1, 2].map(|d| centre[d] + s * right[d] + t * up[d]);
+
+            let (mut colour, mut alpha) = ([0.0_f32; 3], 0.0_f32);
+            if let Some((t_in, t_out)) = ray_box_overlap(origin, forward, half_width) {
+                let n_steps = ((t_out - t_in) / step).ceil() as usize;
+                for i in 0..n_steps {
+                    let tt = t_in + (i as f32 + 0.5) * step;
+                    let p = Point::new(mm(origin[0] + tt * forward[0]),
+                                       mm(origin[1] + tt * forward[1]),
+                                       mm(origin[2] + tt * forward[2]));
+                    let value = match image.trilinear(p) { Some(v) => v, None => continue };
+                    let [r, g, b, a] = transfer.rgba(value * normalize);
+                    // Correct opacity for the sampling step size
+                    let a = 1.0 - (1.0 - a.clamp(0.0, 1.0)).powf(step / reference_step);
+                    let weight = (1.0 - alpha) * a;
+                    colour[0] += weight * r;
+                    colour[1] += weight * g;
+                    colour[2] += weight * b;
+                    alpha     += weight;
+                    // Early ray termination
+                    if alpha > 0.999 { break }
+                }
+            }
+            let to_u8 = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+            let unpremultiply = |c: f32| if alpha > 0.0 { c / alpha } else { 0.0 };
+            out.put_pixel(px, py, Rgba([to_u8(unpremultiply(colour[0])),
+                                        to_u8(unpremultiply(colour[1])),
+                                        to_u8(unpremultiply(colour[2])),
+                                        to_u8(alpha)]));
+        }
+    }
+    out
+}
+
+/// Encode rendering as PNG
+pub fn png_bytes(rendering: &RgbaImage) -> ImageResult<Vec<u8>> {
+    let mut bytes = vec![];
+    DynamicImage::ImageRgba8(rendering.clone()).write_to(&mut bytes, ImageOutputFormat::Png)?;
+    Ok(bytes)
+}
+
+pub fn write_png(rendering: &RgbaImage, path: &std::path::Path) -> ImageResult<()> {
+    rendering.save(path)
+}
+
+/// Show rendering in a window, until the window is closed
+pub fn display_rendering(rendering: &RgbaImage) -> ImageResult<()> {
+    use kiss3d::window::Window;
+    let png = png_bytes(rendering)?;
+    let (w, h) = rendering.dimensions();
+    let mut window = Window::new_with_size("Volume rendering", w, h);
+    let mut canvas = window.add_rectangle(w as f32, h as f32);
+    canvas.set_texture_from_memory(&png, "volume rendering");
+    while window.render() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fov::FOV;
+    use geometry::units::{mm, radian};
+
+    fn looking_along_x() -> CameraParams {
+        CameraParams {
+            look_at: Point::new(mm(0.0), mm(0.0), mm(0.0)),
+            azimuth: radian(0.0),
+            elevation: radian(0.0),
+            width: mm(20.0),
+        }
+    }
+
+    fn cube(value: f32) -> Image {
+        let fov = FOV::new_from_full_widths((mm(10.0), mm(10.0), mm(10.0)), (5, 5, 5));
+        Image::new(fov, vec![value; 125])
+    }
+
+    #[test]
+    fn opaque_cube_silhouette_matches_box_outline() {
+        let opaque = TransferFunction::new(vec![(0.0, [1.0, 1.0, 1.0, 1.0])]);
+        let rendering = volume_render(&cube(1.0), looking_along_x(), &opaque, (20, 20));
+        // 1 pixel = 1 mm; box spans [-5, 5] mm, i.e. pixels 5 to 14 inclusive
+        for py in 0..20 {
+            for px in 0..20 {
+                let inside = (5..15).contains(&px) && (5..15).contains(&py);
+                let alpha = rendering.get_pixel(px, py).0[3];
+                assert_eq!(alpha == 255, inside, "pixel ({px}, {py}) has alpha {alpha}");
+                if !inside { assert_eq!(alpha, 0) }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_image_is_transparent() {
+        let rendering = volume_render(&cube(0.0), looking_along_x(), &TransferFunction::ramp(), (16, 12));
+        assert!(rendering.pixels().all(|p| p.0 == [0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn png_is_deterministic() {
+        let mut image = cube(1.0);
+        for (i, v) in image.data.iter_mut().enumerate() { *v = (i % 7) as f32; }
+        let camera = CameraParams { azimuth: radian(0.3), elevation: radian(0.4), ..looking_along_x() };
+        let a = png_bytes(&volume_render(&image, camera, &TransferFunction::ramp(), (32, 24))).unwrap();
+        let b = png_bytes(&volume_render(&image, camera, &TransferFunction::ramp(), (32, 24))).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn parse_transfer_function() {
+        let parsed: TransferFunction = "1:1,1,1,1  0:0,0,0,0".parse().unwrap();
+        assert_eq!(parsed, TransferFunction::ramp());
+        assert_eq!(parsed.rgba(0.5), [0.5, 0.5, 0.5, 0.5]);
+        assert!("0:1,1,1".parse::<TransferFunction>().is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! Errors of each class make the executables exit with the class's code, and
+//! report the class, the context and the cause. There is no test data in the
+//! repository, so the inputs are written by the tests.
+#![cfg(all(feature = "hdf5", feature = "cli"))]
+
+use std::path::Path;
+use std::process::Output;
+
+use assert_cmd::Command;
+use petalo::error::{ErrorClass, INJECT_PANIC_VAR};
+use petalo::io::hdf5::{write_table, Hdf5Lor};
+
+/// Reconstruct `input` into `out`, in a tiny FOV
+fn mlem(input: &Path, out: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("mlem").unwrap();
+    cmd.args(["-i", "1", "-n", "3,3,3", "-s", "30 mm,30 mm,30 mm"])
+       .arg("-f").arg(input)
+       .arg("-o").arg(out);
+    cmd
+}
+
+/// A small, valid LOR table
+fn write_lors(path: &Path) {
+    let lor = Hdf5Lor { dt: 0.0, x1: -100.0, y1: 0.0, z1: 0.0, x2: 100.0, y2: 0.0, z2: 0.0, q1: 1.0, q2: 1.0, E1: 511.0, E2: 511.0 };
+    write_table(path.to_str().unwrap(), "reco_info/lors", &vec![lor; 10]).unwrap();
+}
+
+fn stderr(output: &Output) -> String { String::from_utf8_lossy(&output.stderr).into_owned() }
+
+fn assert_failed_with(output: &Output, class: ErrorClass) {
+    assert_eq!(output.status.code(), Some(class.exit_code() as i32), "{}", stderr(output));
+    assert!(stderr(output).contains(&format!("mlem: {class}\n")), "{}", stderr(output));
+}
+
+#[test]
+fn missing_input_is_a_user_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = mlem(&dir.path().join("missing.h5"), &dir.path().join("out/image")).output().unwrap();
+    assert_failed_with(&output, ErrorClass::UserInput);
+    let stderr = stderr(&output);
+    assert!(stderr.contains("  while reconstructing"), "{stderr}");
+    assert!(stderr.contains("  cause: Input file"), "{stderr}");
+    assert!(stderr.contains("mlem --help"), "{stderr}");
+    assert!(!stderr.contains("This is a bug"), "{stderr}");
+}
+
+#[test]
+fn malformed_input_is_a_user_error_in_context() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("garbage.h5");
+    std::fs::write(&input, "not HDF5").unwrap();
+    let output = mlem(&input, &dir.path().join("out/image")).output().unwrap();
+    assert_failed_with(&output, ErrorClass::UserInput);
+    let stderr = stderr(&output);
+    assert!(stderr.contains(&format!("  while reading LORs from '{}', dataset 'reco_info/lors'", input.display())), "{stderr}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unwritable_output_is_an_environment_error() {
+    // sysfs refuses to make directories, even for root
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("lors.h5");
+    write_lors(&input);
+    let output = mlem(&input, Path::new("/sys/petalo-test/image")).output().unwrap();
+    assert_failed_with(&output, ErrorClass::Environment);
+    assert!(stderr(&output).contains("  while creating output directory '/sys/petalo-test'"), "{}", stderr(&output));
+}
+
+#[test]
+fn panics_are_internal_errors_with_a_crash_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("lors.h5");
+    write_lors(&input);
+    let output = mlem(&input, &dir.path().join("out/image"))
+        .env(INJECT_PANIC_VAR, "1")
+        .env("TMPDIR", dir.path())
+        .output().unwrap();
+    assert_failed_with(&output, ErrorClass::Internal);
+    let stderr = stderr(&output);
+    assert!(stderr.contains("  cause: panicked at"), "{stderr}");
+    assert!(stderr.contains("This is a bug in petalo"), "{stderr}");
+
+    let report = std::fs::read_dir(dir.path()).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("mlem-crash-"))
+        .expect("no crash report");
+    let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(report).unwrap()).unwrap();
+    assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+    assert!(report["config"].as_str().unwrap().contains("iterations: 1"));
+    assert!(report["backtrace"].is_string());
+}